@@ -0,0 +1,7 @@
+fn main() {
+    // The target triple, to pick the matching release asset when self-updating
+    println!(
+        "cargo:rustc-env=TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
+}
@@ -0,0 +1,742 @@
+use crate::domains::{self, DomainTarget};
+use crate::mapping::DeviceMapping;
+use crate::progress::Progress;
+#[cfg(feature = "csv-report")]
+use crate::report::csv_report::CsvReport;
+use crate::report::{Action, ActionOutcome, AuditEntry, AuditLog, ReportSummary};
+use crate::rest::api::NetshotApi;
+use crate::rest::helpers::{is_forbidden, ReadOnlyViolation};
+use crate::rest::netshot;
+use crate::rest::snapshot::FetchSnapshot;
+use crate::state;
+use crate::sync::{canonical_ip, AddressChange, PlannedDevice, SyncPlan};
+use anyhow::{anyhow, Error, Result};
+use chrono::Utc;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The delay between two checks of the discovery of a registered device
+const DISCOVERY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How the changes of a plan are applied, the CLI defaults being the default
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ApplyOptions {
+    /// The number of changes of an action applied at once
+    pub parallelism: usize,
+    /// Look up the devices to disable/enable by batches of this size instead of one by one
+    pub batch_size: Option<usize>,
+    /// Wait for the discovery task of each registration, counting the failed ones as failures
+    pub wait_for_tasks: bool,
+    /// How long to wait for the discovery task of a registration
+    pub task_timeout: Duration,
+    /// The static group the registered devices are added to, once discovered
+    pub group_id: Option<u32>,
+    /// Disable the registered devices once discovered, onboarding them without monitoring them yet
+    pub disable_registered: bool,
+    /// The credential sets of the devices planned for a credential update
+    pub credential_set_ids: Vec<u32>,
+    /// The static group the stale devices are added to, when they are not disabled
+    pub stale_group: Option<String>,
+    /// Create the stale group when it is missing, before adding the first devices to it
+    pub create_stale_group: bool,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        ApplyOptions {
+            parallelism: 4,
+            batch_size: None,
+            wait_for_tasks: false,
+            task_timeout: Duration::from_secs(300),
+            group_id: None,
+            disable_registered: false,
+            credential_set_ids: Vec::new(),
+            stale_group: None,
+            create_stale_group: false,
+        }
+    }
+}
+
+/// What a run records along the way, shared by the domains
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Recorders<'a> {
+    pub audit: Option<&'a AuditLog>,
+    pub mapping: Option<&'a Mutex<DeviceMapping>>,
+    pub history: Option<&'a Mutex<state::RunState>>,
+    pub snapshots: Option<&'a Mutex<Vec<FetchSnapshot>>>,
+    /// The outcomes of the actions of every domain, for the final summary
+    pub outcomes: Option<&'a Mutex<Vec<ActionOutcome>>>,
+    #[cfg(feature = "csv-report")]
+    pub csv: Option<&'a CsvReport>,
+    /// The Netshot devices fetched from the domains, for --dump-inventories
+    pub netshot_inventory: Option<&'a Mutex<Vec<netshot::Device>>>,
+}
+
+/// Log the outcome of an action as a structured line, count it, append it to the audit log, the CSV report and the
+/// failure history
+fn record_outcome(
+    summary: &mut ReportSummary,
+    recorders: Recorders,
+    outcome: ActionOutcome,
+) -> Result<(), Error> {
+    summary.record(&outcome);
+    log::log!(
+        outcome.level(),
+        action:% = outcome.action,
+        domain_id = outcome.domain_id,
+        ip = outcome.ip.as_str(),
+        hostname = outcome.name.as_str(),
+        result = outcome.result();
+        "{}",
+        outcome
+    );
+    if let Some(history) = recorders.history {
+        history.lock().unwrap().record_outcome(&outcome);
+    }
+    #[cfg(feature = "csv-report")]
+    if let Some(csv) = recorders.csv {
+        csv.record_outcome(&outcome);
+    }
+    if let Some(outcomes) = recorders.outcomes {
+        outcomes.lock().unwrap().push(outcome.clone());
+    }
+    match recorders.audit {
+        Some(audit) => audit.append(&AuditEntry::Action(outcome)),
+        None => Ok(()),
+    }
+}
+
+/// The action categories of a domain aborted once Netshot refused one of their writes with a 403, as the token lacks
+/// the privilege on the domain and would get the other writes of the category refused the same way. No write of the
+/// category is sent once the refusal is handled, the ones already in flight completing: at most `--parallelism`
+/// writes of a category are refused before it is aborted.
+struct PrivilegeAborts<'a> {
+    target: &'a DomainTarget,
+    aborted: BTreeSet<Action>,
+}
+
+impl<'a> PrivilegeAborts<'a> {
+    fn new(target: &'a DomainTarget) -> Self {
+        PrivilegeAborts {
+            target,
+            aborted: BTreeSet::new(),
+        }
+    }
+
+    /// Abort the category of the action when Netshot refused it for lack of privileges
+    fn check(&mut self, action: Action, device: &PlannedDevice, result: &Result<bool, Error>) {
+        let error = match result {
+            Err(error) if is_forbidden(error) => error,
+            _ => return,
+        };
+        if self.aborted.insert(action) {
+            log::error!(
+                "Netshot refused to {} {} in domain {}: {}, the token lacks the read-write privilege on the domain, aborting the other {} changes",
+                action,
+                device.ip,
+                self.target.label(),
+                error,
+                action
+            );
+        }
+    }
+
+    /// Record the action as aborted when its category is, telling whether it is
+    fn skip(
+        &self,
+        summary: &mut ReportSummary,
+        recorders: Recorders,
+        action: Action,
+        device: &PlannedDevice,
+        netshot_id: Option<u32>,
+    ) -> Result<bool, Error> {
+        if !self.aborted.contains(&action) {
+            return Ok(false);
+        }
+        let outcome = ActionOutcome::aborted(self.target.domain_id, action, device, netshot_id);
+        record_outcome(summary, recorders, outcome)?;
+        Ok(true)
+    }
+}
+
+/// The changes of a domain being applied, with their outcomes so far
+struct DomainChanges<'a> {
+    recorders: Recorders<'a>,
+    aborts: PrivilegeAborts<'a>,
+    netshot_ids: HashMap<String, u32>,
+    applied: ReportSummary,
+}
+
+impl DomainChanges<'_> {
+    fn netshot_id(&self, device: &PlannedDevice) -> Option<u32> {
+        self.netshot_ids.get(device.ip.as_str()).copied()
+    }
+
+    /// The progress of the changes of an action
+    fn progress(&self, action: Action, devices: &[PlannedDevice]) -> Progress {
+        Progress::start(
+            format!("Domain {}: {}", self.aborts.target.label(), action),
+            Some(devices.len()),
+        )
+    }
+
+    /// Record the action as aborted when its category is, telling whether it is
+    fn skip(&mut self, action: Action, device: &PlannedDevice) -> Result<bool, Error> {
+        let netshot_id = self.netshot_id(device);
+        self.aborts.skip(
+            &mut self.applied,
+            self.recorders,
+            action,
+            device,
+            netshot_id,
+        )
+    }
+
+    /// Record the result of an action, aborting its category when Netshot refused it for lack of privileges
+    fn record(
+        &mut self,
+        action: Action,
+        device: &PlannedDevice,
+        duration: Duration,
+        result: Result<bool, Error>,
+    ) -> Result<(), Error> {
+        self.aborts.check(action, device, &result);
+        let outcome = ActionOutcome::new(
+            self.aborts.target.domain_id,
+            action,
+            device,
+            self.netshot_id(device),
+            duration,
+            &result,
+        );
+        record_outcome(&mut self.applied, self.recorders, outcome)
+    }
+
+    /// Apply the action to the devices with at most `parallelism` calls at once, recording the outcomes as they come
+    fn apply<F>(
+        &mut self,
+        action: Action,
+        devices: &[PlannedDevice],
+        parallelism: usize,
+        call: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(&PlannedDevice) -> Result<bool, Error> + Sync,
+    {
+        self.apply_recording(action, devices, parallelism, call, None)
+    }
+
+    /// Apply the action like `apply`, leaving the outcomes of the successful calls to be recorded by the caller, e.g.
+    /// once the tasks they started ended. The time each of them took is given by IP.
+    fn submit<F>(
+        &mut self,
+        action: Action,
+        devices: &[PlannedDevice],
+        parallelism: usize,
+        call: F,
+    ) -> Result<HashMap<String, Duration>, Error>
+    where
+        F: Fn(&PlannedDevice) -> Result<bool, Error> + Sync,
+    {
+        let mut succeeded = HashMap::new();
+        self.apply_recording(action, devices, parallelism, call, Some(&mut succeeded))?;
+        Ok(succeeded)
+    }
+
+    fn apply_recording<F>(
+        &mut self,
+        action: Action,
+        devices: &[PlannedDevice],
+        parallelism: usize,
+        call: F,
+        mut succeeded: Option<&mut HashMap<String, Duration>>,
+    ) -> Result<(), Error>
+    where
+        F: Fn(&PlannedDevice) -> Result<bool, Error> + Sync,
+    {
+        if self.aborts.aborted.contains(&action) {
+            for device in devices {
+                self.skip(action, device)?;
+            }
+            return Ok(());
+        }
+        let progress = self.progress(action, devices);
+        domains::for_each_parallel(
+            devices,
+            parallelism,
+            |device| {
+                let start = Instant::now();
+                let result = call(device);
+                (start.elapsed(), result)
+            },
+            |device, result| {
+                progress.advance(1);
+                match (result, succeeded.as_deref_mut()) {
+                    (Some((duration, Ok(_))), Some(succeeded)) => {
+                        succeeded.insert(device.ip.clone(), duration);
+                    }
+                    (Some((duration, result)), _) => {
+                        self.record(action, device, duration, result)?
+                    }
+                    (None, _) => {
+                        self.skip(action, device)?;
+                    }
+                }
+                Ok(self.aborts.aborted.contains(&action))
+            },
+        )
+    }
+}
+
+/// Apply the changes of the plan of a domain, given the Netshot devices it was computed from, and count their
+/// outcomes. A change failing does not stop the others, only the errors of the recorders do.
+/// The devices are given when they were fetched through `client`, their IDs being only valid on their own Netshot:
+/// without them, the devices are searched by IP on the Netshot of `client`.
+pub fn apply<A: NetshotApi>(
+    plan: &SyncPlan,
+    client: &A,
+    netshot_devices: Option<&[netshot::Device]>,
+    target: &DomainTarget,
+    options: &ApplyOptions,
+    recorders: Recorders,
+) -> Result<ReportSummary, Error> {
+    let domain_id = target.domain_id;
+    let parallelism = options.parallelism;
+    let shared_ids = netshot_devices.is_some();
+    let netshot_devices = netshot_devices.unwrap_or_default();
+    let mut changes = DomainChanges {
+        recorders,
+        aborts: PrivilegeAborts::new(target),
+        netshot_ids: netshot_devices
+            .iter()
+            .map(|device| (canonical_ip(&device.management_address.ip), device.id))
+            .collect(),
+        applied: ReportSummary::default(),
+    };
+    // The ID of a device on the Netshot of `client`, when it is not known from the plan
+    let find_id = |ip: &str| -> Result<u32, Error> {
+        client
+            .find_device(ip)?
+            .map(|device| device.id)
+            .ok_or_else(|| anyhow!("No device with the IP {} on Netshot", ip))
+    };
+
+    register_devices(&mut changes, client, &plan.to_register, options, domain_id)?;
+
+    // The devices of the inventory are updated by ID, the others are searched by IP first
+    let known_devices: HashMap<String, &netshot::Device> = netshot_devices
+        .iter()
+        .map(|device| (canonical_ip(&device.management_address.ip), device))
+        .collect();
+    for (action, devices) in [
+        (Action::Disable, &plan.to_disable),
+        (Action::Enable, &plan.to_enable),
+    ] {
+        let enabled = action == Action::Enable;
+        let (known, unknown): (Vec<PlannedDevice>, Vec<PlannedDevice>) = devices
+            .iter()
+            .cloned()
+            .partition(|device| known_devices.contains_key(device.ip.as_str()));
+        changes.apply(action, &known, parallelism, |device| {
+            let netshot_device = known_devices[device.ip.as_str()];
+            match enabled {
+                true => client.enable_device_by_id(netshot_device),
+                false => client.disable_device_by_id(netshot_device),
+            }
+            .map(|update| update.is_some())
+        })?;
+        match options.batch_size {
+            Some(batch_size) => {
+                let progress = changes.progress(action, &unknown);
+                // A refused batch aborts the following ones, its other members being attempted already
+                for batch in unknown.chunks(batch_size.max(1)) {
+                    progress.advance(batch.len());
+                    let mut skipped = false;
+                    for device in batch {
+                        skipped = changes.skip(action, device)?;
+                    }
+                    if skipped {
+                        continue;
+                    }
+                    let ips: Vec<String> = batch.iter().map(|device| device.ip.clone()).collect();
+                    let updates = client.set_devices_enabled(&ips, enabled, batch_size);
+                    for (device, (_, duration, update)) in batch.iter().zip(updates) {
+                        let update = update.map(|update| update.is_some());
+                        changes.record(action, device, duration, update)?;
+                    }
+                }
+            }
+            None => changes.apply(action, &unknown, parallelism, |device| {
+                match enabled {
+                    true => client.enable_device(device.ip.clone()),
+                    false => client.disable_device(device.ip.clone()),
+                }
+                .map(|update| update.is_some())
+            })?,
+        }
+    }
+    changes.apply(
+        Action::UpdateCredentials,
+        &plan.to_update_credentials,
+        parallelism,
+        |device| {
+            client
+                .set_device_credential_sets(device.ip.clone(), &options.credential_set_ids)
+                .map(|_| true)
+        },
+    )?;
+    changes.apply(
+        Action::FixPrefixLength,
+        &plan.to_fix_prefix_length,
+        parallelism,
+        |device| {
+            client
+                .set_device_prefix_length(
+                    device.ip.clone(),
+                    device.prefix_length.unwrap_or_default(),
+                )
+                .map(|_| true)
+        },
+    )?;
+    let address_changes: HashMap<&str, &AddressChange> = plan
+        .address_changes
+        .iter()
+        .map(|change| (change.netbox_device.ip.as_str(), change))
+        .collect();
+    if shared_ids {
+        changes.netshot_ids.extend(
+            address_changes
+                .iter()
+                .map(|(ip, change)| (ip.to_string(), change.netshot_id)),
+        );
+    }
+    changes.apply(
+        Action::UpdateAddress,
+        &plan.to_update_address,
+        parallelism,
+        |device| {
+            // The device is still at its previous address on Netshot
+            let change = address_changes[device.ip.as_str()];
+            let netshot_id = match shared_ids {
+                true => change.netshot_id,
+                false => find_id(&change.netshot_ip)?,
+            };
+            client
+                .update_device_ip(netshot_id, device.ip.clone())
+                .map(|_| true)
+        },
+    )?;
+    let matched_ids: HashMap<&str, u32> = plan
+        .matched
+        .iter()
+        .filter(|_| shared_ids)
+        .map(|matched| (matched.ip.as_str(), matched.netshot_id))
+        .collect();
+    changes.apply(Action::Rename, &plan.to_rename, parallelism, |device| {
+        let netshot_id = match matched_ids.get(device.ip.as_str()) {
+            Some(netshot_id) => *netshot_id,
+            None => find_id(&device.ip)?,
+        };
+        client
+            .rename_device(netshot_id, device.name.clone())
+            .map(|_| true)
+    })?;
+    changes.apply(
+        Action::UpdateAttributes,
+        &plan.to_update_attributes,
+        parallelism,
+        |device| {
+            client
+                .update_device_comments(
+                    device.ip.clone(),
+                    device.comments.as_deref().unwrap_or_default(),
+                )
+                .map(|_| true)
+        },
+    )?;
+    if let Some(group_name) = options.stale_group.as_deref() {
+        if options.create_stale_group && !plan.to_add_to_group.is_empty() {
+            create_stale_group(client, group_name);
+        }
+        for (action, devices) in [
+            (Action::AddToGroup, &plan.to_add_to_group),
+            (Action::RemoveFromGroup, &plan.to_remove_from_group),
+        ] {
+            // The membership updates rewrite the whole group, one at a time so that none is lost
+            changes.apply(action, devices, 1, |device| {
+                client.set_group_membership(
+                    group_name,
+                    device.ip.clone(),
+                    action == Action::AddToGroup,
+                )
+            })?;
+        }
+    }
+    changes.apply(Action::Purge, &plan.to_purge, parallelism, |device| {
+        client.delete_device(device.ip.clone()).map(|_| true)
+    })?;
+    changes.apply(
+        Action::PurgeDisabled,
+        &plan.to_purge_disabled,
+        parallelism,
+        |device| client.delete_device(device.ip.clone()).map(|_| true),
+    )?;
+
+    let applied = changes.applied;
+    log::info!(
+        "Failed to apply {} changes: {} timeouts, {} errors",
+        applied.failed(),
+        applied.timeouts,
+        applied.failures
+    );
+    Ok(applied)
+}
+
+/// Create the stale group when it is missing on the Netshot of `client`, the group additions reporting the failure
+fn create_stale_group<A: NetshotApi>(client: &A, group_name: &str) {
+    let created = client.get_groups().and_then(|groups| {
+        match groups.iter().any(|group| group.name == group_name) {
+            true => Ok(false),
+            false => client.create_static_group(group_name).map(|_| true),
+        }
+    });
+    match created {
+        Ok(true) => log::info!("Created the Netshot group {}", group_name),
+        Ok(false) => {}
+        Err(error) if error.is::<ReadOnlyViolation>() => {
+            log::info!("The Netshot group {} would be created", group_name)
+        }
+        Err(error) => log::error!(
+            "Unable to create the Netshot group {}: {:#}",
+            group_name,
+            error
+        ),
+    }
+}
+
+/// Wait for the discovery tasks of registrations to end against one deadline, failing the ones which did not succeed
+/// in time
+pub fn wait_for_discoveries<A: NetshotApi>(
+    client: &A,
+    task_ids: &[u32],
+    deadline: Instant,
+) -> HashMap<u32, Result<(), Error>> {
+    let mut discoveries = HashMap::new();
+    let mut pending: Vec<(u32, String)> = task_ids
+        .iter()
+        .map(|&task_id| (task_id, String::new()))
+        .collect();
+    loop {
+        pending.retain_mut(|(task_id, status)| {
+            let task = match client.get_task(*task_id) {
+                Ok(task) => task,
+                Err(error) => {
+                    discoveries.insert(*task_id, Err(error));
+                    return false;
+                }
+            };
+            if !task.is_finished() {
+                *status = task.status;
+                return true;
+            }
+            let discovery = match task.discovery_failure() {
+                Some(failure) => Err(anyhow!(
+                    "The discovery task {} failed on a {} problem: {}",
+                    task_id,
+                    failure,
+                    task.failure_reason().unwrap_or_default()
+                )),
+                None if task.status != "SUCCESS" => Err(anyhow!(
+                    "The discovery task {} ended with the status {}",
+                    task_id,
+                    task.status
+                )),
+                None => Ok(()),
+            };
+            discoveries.insert(*task_id, discovery);
+            false
+        });
+        if pending.is_empty() {
+            return discoveries;
+        }
+        if Instant::now() >= deadline {
+            for (task_id, status) in pending {
+                discoveries.insert(
+                    task_id,
+                    Err(anyhow!(
+                        "The discovery task {} is still {} after --task-timeout",
+                        task_id,
+                        status
+                    )),
+                );
+            }
+            return discoveries;
+        }
+        std::thread::sleep(DISCOVERY_POLL_INTERVAL);
+    }
+}
+
+/// Add a device registered during the run to the group, reporting the failures without failing the run
+fn group_registered_device<A: NetshotApi>(client: &A, ip: &str, group_id: u32) {
+    let grouped = client
+        .find_device(ip)
+        .and_then(|device| {
+            device.ok_or_else(|| anyhow!("It is missing from Netshot after its discovery"))
+        })
+        .and_then(|device| {
+            let added = client.add_device_to_group(device.id, group_id)?;
+            Ok((device, added))
+        });
+    match grouped {
+        Ok((device, true)) => log::info!(
+            "Added the registered device {}({}) to the Netshot group {}",
+            device.name,
+            ip,
+            group_id
+        ),
+        Ok((device, false)) => log::debug!(
+            "The registered device {}({}) already is in the Netshot group {}",
+            device.name,
+            ip,
+            group_id
+        ),
+        Err(error) => log::error!(
+            "Unable to add the registered device {} to the Netshot group {}, to be added manually: {:#}",
+            ip,
+            group_id,
+            error
+        ),
+    }
+}
+
+/// Disable a device registered during the run, remembering it so the next runs leave it disabled, and reporting the
+/// failures without failing the run
+fn disable_registered_device<A: NetshotApi>(
+    client: &A,
+    history: Option<&Mutex<state::RunState>>,
+    ip: &str,
+    domain_id: u32,
+) {
+    match client.disable_device(ip.to_string()) {
+        Ok(_) => {
+            log::info!("Disabled the registered device {}", ip);
+            if let Some(history) = history {
+                history
+                    .lock()
+                    .unwrap()
+                    .record_onboarded(ip, domain_id, Utc::now());
+            }
+        }
+        Err(error) => log::error!(
+            "Unable to disable the registered device {}, to be disabled manually: {:#}",
+            ip,
+            error
+        ),
+    }
+}
+
+/// Register the devices, then wait for their discovery against one deadline when requested or needed to add them to
+/// the group or disable them. With --wait-for-tasks, the outcome of a registration is only recorded once its discovery
+/// ended, a failed discovery failing it.
+fn register_devices<A: NetshotApi>(
+    changes: &mut DomainChanges,
+    client: &A,
+    devices: &[PlannedDevice],
+    options: &ApplyOptions,
+    domain_id: u32,
+) -> Result<(), Error> {
+    let history = changes.recorders.history;
+    let submitted = Mutex::new(Vec::new());
+    let register = |device: &PlannedDevice| -> Result<bool, Error> {
+        let payload = client.register_device(device.ip.clone(), domain_id, &device.name)?;
+        if let Some(history) = history {
+            history.lock().unwrap().record_registration(
+                &device.ip,
+                domain_id,
+                payload.task_id,
+                Utc::now(),
+            );
+        }
+        submitted
+            .lock()
+            .unwrap()
+            .push((device.clone(), payload.task_id));
+        Ok(true)
+    };
+    let submit_durations = match options.wait_for_tasks {
+        true => changes.submit(Action::Register, devices, options.parallelism, register)?,
+        false => {
+            changes.apply(Action::Register, devices, options.parallelism, register)?;
+            HashMap::new()
+        }
+    };
+    if !options.wait_for_tasks && options.group_id.is_none() && !options.disable_registered {
+        return Ok(());
+    }
+
+    let mut submitted = submitted.into_inner().unwrap();
+    submitted.sort_by(|(first, _), (second, _)| first.ip.cmp(&second.ip));
+    let task_ids: Vec<u32> = submitted.iter().map(|(_, task_id)| *task_id).collect();
+    let start = Instant::now();
+    let mut discoveries = wait_for_discoveries(client, &task_ids, start + options.task_timeout);
+    let waited = start.elapsed();
+    let mut discovered = Vec::new();
+    for (device, task_id) in submitted {
+        let discovery = discoveries
+            .remove(&task_id)
+            .unwrap_or_else(|| Err(anyhow!("The discovery task {} was not polled", task_id)));
+        match &discovery {
+            Ok(()) => {
+                log::debug!("The discovery task {} of {} succeeded", task_id, device.ip);
+                discovered.push(device.ip.clone());
+            }
+            Err(_) if options.wait_for_tasks => {}
+            Err(error) => {
+                if let Some(group_id) = options.group_id {
+                    log::error!(
+                        "Unable to add the registered device {} to the Netshot group {}, to be added manually: {:#}",
+                        device.ip,
+                        group_id,
+                        error
+                    );
+                }
+                if options.disable_registered {
+                    log::error!(
+                        "Unable to disable the registered device {}, to be disabled manually: {:#}",
+                        device.ip,
+                        error
+                    );
+                }
+            }
+        }
+        if options.wait_for_tasks {
+            let duration = submit_durations
+                .get(&device.ip)
+                .copied()
+                .unwrap_or_default()
+                + waited;
+            changes.record(
+                Action::Register,
+                &device,
+                duration,
+                discovery.map(|()| true),
+            )?;
+        }
+    }
+
+    domains::for_each_parallel(
+        &discovered,
+        options.parallelism,
+        |ip| {
+            if let Some(group_id) = options.group_id {
+                group_registered_device(client, ip, group_id);
+            }
+            if options.disable_registered {
+                disable_registered_device(client, history, ip, domain_id);
+            }
+        },
+        |_, _| Ok(false),
+    )
+}
@@ -0,0 +1,143 @@
+use crate::rest::netbox::Device;
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// The prefix of the Netshot comment lines written by netbox2netshot, the other lines being left as they are
+const LINE_PREFIX: &str = "Netbox ";
+
+/// A Netbox field copied into the comments of the Netshot device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attribute {
+    Site,
+    Rack,
+    Serial,
+    AssetTag,
+    /// The page of the device on Netbox
+    Url,
+}
+
+impl FromStr for Attribute {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "site" => Ok(Attribute::Site),
+            "rack" => Ok(Attribute::Rack),
+            "serial" => Ok(Attribute::Serial),
+            "asset-tag" => Ok(Attribute::AssetTag),
+            "url" => Ok(Attribute::Url),
+            _ => Err(anyhow!(
+                "Unknown attribute `{}`, expected site, rack, serial, asset-tag or url",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Attribute::Site => "site",
+            Attribute::Rack => "rack",
+            Attribute::Serial => "serial",
+            Attribute::AssetTag => "asset tag",
+            Attribute::Url => "URL",
+        })
+    }
+}
+
+impl Attribute {
+    /// The value of the attribute on the device, `None` when it is not set
+    fn value(self, device: &Device, netbox_url: &str) -> Option<String> {
+        match self {
+            Attribute::Site => device
+                .site()
+                .and_then(|site| site.name.clone().or_else(|| site.slug.clone())),
+            Attribute::Rack => device.rack().and_then(|rack| rack.name.clone()),
+            Attribute::Serial => device.serial().map(str::to_string),
+            Attribute::AssetTag => device.asset_tag().map(str::to_string),
+            Attribute::Url => {
+                let path = match device.virtual_machine {
+                    true => "virtualization/virtual-machines",
+                    false => "dcim/devices",
+                };
+                Some(format!(
+                    "{}/{}/{}/",
+                    netbox_url.trim_end_matches('/'),
+                    path,
+                    device.id
+                ))
+            }
+        }
+    }
+}
+
+/// The comment lines of the attributes set on the device, e.g. `Netbox site: PAR1`, in the given order
+pub fn comment_lines(device: &Device, attributes: &[Attribute], netbox_url: &str) -> Vec<String> {
+    attributes
+        .iter()
+        .filter_map(|attribute| {
+            attribute
+                .value(device, netbox_url)
+                .map(|value| format!("{}{}: {}", LINE_PREFIX, attribute, value))
+        })
+        .collect()
+}
+
+/// The Netshot comments with the lines written by netbox2netshot replaced by the given ones, after the other lines
+pub fn merge_comments(current: &str, lines: &[String]) -> String {
+    current
+        .lines()
+        .filter(|line| !line.starts_with(LINE_PREFIX))
+        .map(str::to_string)
+        .chain(lines.iter().cloned())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn device_comments() {
+        let device: Device = serde_json::from_value(json!({
+            "id": 12,
+            "name": "edge-1",
+            "site": {"id": 2, "name": "PAR1", "slug": "par1"},
+            "rack": {"id": 7, "name": "R12"},
+            "serial": "FOC1234X",
+            "asset_tag": null,
+        }))
+        .unwrap();
+        let attributes: Vec<Attribute> = "site,rack,serial,asset-tag,url"
+            .split(',')
+            .map(|attribute| attribute.parse().unwrap())
+            .collect();
+        let lines = comment_lines(&device, &attributes, "https://netbox.example.org/");
+        assert_eq!(
+            lines,
+            vec![
+                "Netbox site: PAR1",
+                "Netbox rack: R12",
+                "Netbox serial: FOC1234X",
+                "Netbox URL: https://netbox.example.org/dcim/devices/12/",
+            ]
+        );
+        assert!("tenant".parse::<Attribute>().is_err());
+
+        // The comments of the operators are kept, the previous values replaced
+        assert_eq!(
+            merge_comments(
+                "Core router\nNetbox site: PAR2\nNetbox rack: R1",
+                &lines[..2]
+            ),
+            "Core router\nNetbox site: PAR1\nNetbox rack: R12"
+        );
+        assert_eq!(merge_comments("", &lines[..1]), "Netbox site: PAR1");
+        let comments = merge_comments("Core router", &lines);
+        assert_eq!(merge_comments(&comments, &lines), comments);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::rest::{netbox, netshot};
+use crate::sync;
+use anyhow::{Error, Result};
+use serde_json::json;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// The options of the synthetic inventories benchmark
+#[derive(Debug, StructOpt, Clone)]
+pub struct BenchOpt {
+    #[structopt(
+        long,
+        help = "The number of Netbox devices to generate",
+        default_value = "10000"
+    )]
+    pub netbox_devices: u32,
+
+    #[structopt(
+        long,
+        help = "The number of Netshot devices to generate",
+        default_value = "10000"
+    )]
+    pub netshot_devices: u32,
+
+    #[structopt(
+        long,
+        help = "The percentage of Netshot devices also present on Netbox",
+        default_value = "90"
+    )]
+    pub overlap: u8,
+
+    #[structopt(
+        long,
+        help = "The percentage of Netshot devices in the DISABLED status",
+        default_value = "5"
+    )]
+    pub disabled: u8,
+
+    #[structopt(
+        long,
+        help = "The number of planning runs to time",
+        default_value = "10"
+    )]
+    pub iterations: u32,
+}
+
+/// A synthetic but unique management IP for the given index
+fn synthetic_ip(index: u32) -> Ipv4Addr {
+    Ipv4Addr::from(0x0a00_0000 + index)
+}
+
+/// Generate the synthetic Netbox and Netshot inventories
+pub fn generate_inventories(
+    opt: &BenchOpt,
+) -> Result<(Vec<netbox::Device>, Vec<netshot::Device>), Error> {
+    let netbox_devices = (0..opt.netbox_devices)
+        .map(|index| {
+            serde_json::from_value(json!({
+                "id": index,
+                "name": format!("device-{}", index),
+                "primary_ip4": {"id": index, "family": 4, "address": format!("{}/32", synthetic_ip(index))}
+            }))
+        })
+        .collect::<Result<Vec<netbox::Device>, _>>()?;
+
+    let overlapping =
+        opt.netshot_devices.min(opt.netbox_devices) * u32::from(opt.overlap.min(100)) / 100;
+    let disabled_every = match opt.disabled.min(100) {
+        0 => u32::MAX,
+        disabled => 100 / u32::from(disabled),
+    };
+    let netshot_devices = (0..opt.netshot_devices)
+        .map(|index| {
+            // The non-overlapping devices get IPs after the whole Netbox range
+            let number = if index < overlapping {
+                index
+            } else {
+                opt.netbox_devices + index
+            };
+            let status = if index % disabled_every == 0 {
+                "DISABLED"
+            } else {
+                "INPRODUCTION"
+            };
+            serde_json::from_value(json!({
+                "id": index,
+                "name": format!("device-{}", number),
+                "mgmtAddress": {"ip": synthetic_ip(number).to_string()},
+                "status": status
+            }))
+        })
+        .collect::<Result<Vec<netshot::Device>, _>>()?;
+
+    Ok((netbox_devices, netshot_devices))
+}
+
+/// The peak resident memory of the process, when the platform exposes it
+fn peak_memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// Generate the inventories, time the planning runs and print the statistics
+pub fn run(opt: &BenchOpt) -> Result<(), Error> {
+    let start = Instant::now();
+    let (netbox_devices, netshot_devices) = generate_inventories(opt)?;
+    println!(
+        "Generated {} Netbox and {} Netshot devices in {:?}",
+        netbox_devices.len(),
+        netshot_devices.len(),
+        start.elapsed()
+    );
+
+    let plan_options = sync::PlanOptions::default();
+    let mut timings: Vec<Duration> = Vec::new();
+    let mut sync_plan = sync::SyncPlan::default();
+    for _ in 0..opt.iterations.max(1) {
+        let start = Instant::now();
+        sync_plan = sync::plan(&netbox_devices, &netshot_devices, &plan_options);
+        timings.push(start.elapsed());
+    }
+
+    let total: Duration = timings.iter().sum();
+    println!(
+        "Planned {} registrations, {} disables and {} enables",
+        sync_plan.to_register.len(),
+        sync_plan.to_disable.len(),
+        sync_plan.to_enable.len()
+    );
+    println!(
+        "Planning time over {} runs: min {:?}, avg {:?}, max {:?}",
+        timings.len(),
+        timings.iter().min().unwrap(),
+        total / timings.len() as u32,
+        timings.iter().max().unwrap()
+    );
+    match peak_memory_kb() {
+        Some(kb) => println!("Peak memory usage: {} MiB", kb / 1024),
+        None => println!("Peak memory usage: unavailable on this platform"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthetic_inventories() {
+        let opt = BenchOpt {
+            netbox_devices: 100,
+            netshot_devices: 50,
+            overlap: 80,
+            disabled: 10,
+            iterations: 1,
+        };
+
+        let (netbox_devices, netshot_devices) = generate_inventories(&opt).unwrap();
+        let sync_plan = sync::plan(
+            &netbox_devices,
+            &netshot_devices,
+            &sync::PlanOptions::default(),
+        );
+
+        assert_eq!(sync_plan.to_register.len(), 60);
+        assert_eq!(sync_plan.to_disable.len(), 10);
+        assert_eq!(sync_plan.to_enable.len(), 4);
+    }
+}
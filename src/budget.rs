@@ -0,0 +1,113 @@
+use crate::report::Action;
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// The order in which the action categories consume the changes budget, e.g. `register,enable,disable`.
+/// The categories left out consume it last, in the default order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetOrder(pub Vec<Action>);
+
+impl Default for BudgetOrder {
+    fn default() -> Self {
+        BudgetOrder(vec![
+            Action::Register,
+            Action::Enable,
+            Action::Disable,
+            Action::UpdateCredentials,
+            Action::FixPrefixLength,
+            Action::UpdateAddress,
+            Action::Rename,
+            Action::UpdateAttributes,
+            Action::AddToGroup,
+            Action::RemoveFromGroup,
+            Action::Purge,
+            Action::PurgeDisabled,
+        ])
+    }
+}
+
+impl FromStr for BudgetOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow!(
+                "Invalid budget order `{}`, expected register, enable, disable, update_credentials, fix_prefix_length, update_address, rename, update_attributes, add_to_group, remove_from_group, purge or purge_disabled separated by commas",
+                s
+            )
+        };
+        let mut order = Vec::new();
+        for action in s.split(',') {
+            let action = match action.trim() {
+                "register" => Action::Register,
+                "enable" => Action::Enable,
+                "disable" => Action::Disable,
+                "update_credentials" => Action::UpdateCredentials,
+                "fix_prefix_length" => Action::FixPrefixLength,
+                "update_address" => Action::UpdateAddress,
+                "rename" => Action::Rename,
+                "update_attributes" => Action::UpdateAttributes,
+                "add_to_group" => Action::AddToGroup,
+                "remove_from_group" => Action::RemoveFromGroup,
+                "purge" => Action::Purge,
+                "purge_disabled" => Action::PurgeDisabled,
+                _ => return Err(invalid()),
+            };
+            if order.contains(&action) {
+                return Err(invalid());
+            }
+            order.push(action);
+        }
+        for action in BudgetOrder::default().0 {
+            if !order.contains(&action) {
+                order.push(action);
+            }
+        }
+        Ok(BudgetOrder(order))
+    }
+}
+
+impl fmt::Display for BudgetOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let actions: Vec<String> = self.0.iter().map(Action::to_string).collect();
+        f.write_str(&actions.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_orders() {
+        assert_eq!(
+            "disable, register,enable".parse::<BudgetOrder>().unwrap(),
+            BudgetOrder(vec![
+                Action::Disable,
+                Action::Register,
+                Action::Enable,
+                Action::UpdateCredentials,
+                Action::FixPrefixLength,
+                Action::UpdateAddress,
+                Action::Rename,
+                Action::UpdateAttributes,
+                Action::AddToGroup,
+                Action::RemoveFromGroup,
+                Action::Purge,
+                Action::PurgeDisabled
+            ])
+        );
+        assert_eq!(
+            "disable".parse::<BudgetOrder>().unwrap().to_string(),
+            "disable,register,enable,update_credentials,fix_prefix_length,update_address,rename,update_attributes,add_to_group,remove_from_group,purge,purge_disabled"
+        );
+        assert_eq!(
+            BudgetOrder::default().to_string(),
+            "register,enable,disable,update_credentials,fix_prefix_length,update_address,rename,update_attributes,add_to_group,remove_from_group,purge,purge_disabled"
+        );
+
+        assert!("register,enable,enable".parse::<BudgetOrder>().is_err());
+        assert!("register,enable,delete".parse::<BudgetOrder>().is_err());
+    }
+}
@@ -1 +1,109 @@
+use anyhow::{anyhow, Error, Result};
+use chrono::Utc;
+use reqwest::Url;
+
 pub const APP_USER_AGENT: &str = "netbox2netshot";
+
+/// A new identifier of the run, to tell the tasks and reports of different runs apart
+pub fn new_run_id() -> String {
+    format!(
+        "{}-{}",
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        std::process::id()
+    )
+}
+
+/// Parse the base URL of an API, requiring an http(s) scheme and dropping the trailing slashes
+pub fn parse_base_url(s: &str) -> Result<String, Error> {
+    if s.contains(char::is_whitespace) {
+        return Err(anyhow!("`{}` contains whitespace", s));
+    }
+    let lowercase = s.to_lowercase();
+    if !lowercase.starts_with("http://") && !lowercase.starts_with("https://") {
+        return Err(anyhow!("`{}` must start with http:// or https://", s));
+    }
+    let url = Url::parse(s).map_err(|error| anyhow!("`{}` is not a valid URL: {}", s, error))?;
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(anyhow!(
+            "`{}` must not have a query string or a fragment",
+            s
+        ));
+    }
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+/// Parse the prefix of the log file names, refusing anything which could lead out of the log directory
+pub fn parse_log_file_prefix(s: &str) -> Result<String, Error> {
+    if s.is_empty() || s.starts_with('.') {
+        return Err(anyhow!("`{}` must not be empty or start with a dot", s));
+    }
+    if let Some(character) = s
+        .chars()
+        .find(|character| !character.is_ascii_alphanumeric() && !"-_.".contains(*character))
+    {
+        return Err(anyhow!(
+            "`{}` must only contain letters, digits, `-`, `_` and `.`, not `{}`",
+            s,
+            character
+        ));
+    }
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_urls() {
+        assert_eq!(
+            parse_base_url("https://netbox.example.net/").unwrap(),
+            "https://netbox.example.net"
+        );
+        assert_eq!(
+            parse_base_url("http://netshot.example.net:8443/netshot//").unwrap(),
+            "http://netshot.example.net:8443/netshot"
+        );
+
+        for (url, problem) in [
+            ("netshot.example.net", "must start with http:// or https://"),
+            (
+                "netshot.example.net:8443",
+                "must start with http:// or https://",
+            ),
+            (
+                "ftp://netshot.example.net",
+                "must start with http:// or https://",
+            ),
+            ("https://netbox.example.net /", "contains whitespace"),
+            ("https://", "is not a valid URL"),
+            (
+                "https://netbox.example.net/?limit=10",
+                "must not have a query string",
+            ),
+        ] {
+            let error = parse_base_url(url).unwrap_err().to_string();
+            assert!(error.contains(problem), "{}: {}", url, error);
+        }
+    }
+
+    #[test]
+    fn log_file_prefixes() {
+        assert_eq!(parse_log_file_prefix("prod-sync").unwrap(), "prod-sync");
+        assert_eq!(
+            parse_log_file_prefix("staging_2.eu").unwrap(),
+            "staging_2.eu"
+        );
+        for prefix in [
+            "",
+            "..",
+            "../prod",
+            "prod/sync",
+            "prod\\sync",
+            ".hidden",
+            "prod sync",
+        ] {
+            assert!(parse_log_file_prefix(prefix).is_err(), "{}", prefix);
+        }
+    }
+}
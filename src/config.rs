@@ -0,0 +1,229 @@
+use crate::profile::OptionSource;
+use std::collections::HashMap;
+use std::fmt;
+use structopt::clap::{App, ArgMatches, ArgSettings};
+
+/// Where the effective value of an option comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Cli,
+    Env,
+    /// The named profile of the configuration file
+    Profile(String),
+    /// The configuration file, outside of any profile
+    ConfigFile,
+    Default,
+    Unset,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Cli => f.pad("cli"),
+            Source::Env => f.pad("env"),
+            Source::Profile(profile) => f.pad(&format!("profile {}", profile)),
+            Source::ConfigFile => f.pad("config file"),
+            Source::Default => f.pad("default"),
+            Source::Unset => f.pad("unset"),
+        }
+    }
+}
+
+/// The effective value of an option, `None` when unset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectiveOption {
+    pub name: String,
+    pub value: Option<String>,
+    pub source: Source,
+}
+
+/// Whether the value of an option must never be printed
+fn is_secret(name: &str, hide_env_values: bool) -> bool {
+    hide_env_values || name.contains("token") || name.contains("password")
+}
+
+/// The effective value and source of every option and flag of the top-level command, secrets redacted.
+/// The options inserted from the configuration file are given along with the profile they come from.
+pub fn effective_options(
+    app: &App,
+    matches: &ArgMatches,
+    profile_sources: &HashMap<String, OptionSource>,
+) -> Vec<EffectiveOption> {
+    let given = |name: &str| match profile_sources.get(name) {
+        Some(Some(profile)) => Source::Profile(profile.clone()),
+        Some(None) => Source::ConfigFile,
+        None => Source::Cli,
+    };
+    let mut options = Vec::new();
+
+    for flag in &app.p.flags {
+        let name = flag.b.name;
+        if name == "help" || name == "version" {
+            continue;
+        }
+        let set = matches.is_present(name);
+        options.push(EffectiveOption {
+            name: name.to_string(),
+            value: Some(set.to_string()),
+            source: if set { given(name) } else { Source::Default },
+        });
+    }
+
+    for option in &app.p.opts {
+        let name = option.b.name;
+        let value = matches
+            .values_of_lossy(name)
+            .map(|values| match option.v.val_delim {
+                Some(delimiter) => values.join(&delimiter.to_string()),
+                None => values.join(" "),
+            });
+        let from_env = option
+            .v
+            .env
+            .as_ref()
+            .is_some_and(|(_, env_value)| env_value.is_some());
+        let source = if matches.occurrences_of(name) > 0 {
+            given(name)
+        } else if value.is_none() {
+            Source::Unset
+        } else if from_env {
+            Source::Env
+        } else {
+            Source::Default
+        };
+        let value = match value {
+            Some(_) if is_secret(name, option.b.is_set(ArgSettings::HideEnvValues)) => {
+                Some("<redacted>".to_string())
+            }
+            value => value,
+        };
+        options.push(EffectiveOption {
+            name: name.to_string(),
+            value,
+            source,
+        });
+    }
+
+    options.sort_by(|a, b| a.name.cmp(&b.name));
+    options
+}
+
+/// Print the effective configuration, one option per line
+pub fn print_effective_config(
+    app: &App,
+    matches: &ArgMatches,
+    profile_sources: &HashMap<String, OptionSource>,
+) {
+    let options = effective_options(app, matches, profile_sources);
+    let width = options
+        .iter()
+        .map(|option| option.name.len())
+        .max()
+        .unwrap_or_default();
+    let source_width = options
+        .iter()
+        .map(|option| option.source.to_string().len())
+        .max()
+        .unwrap_or_default();
+    for option in options {
+        println!(
+            "{:width$}  {:source_width$}  {}",
+            option.name,
+            option.source,
+            option.value.as_deref().unwrap_or("-"),
+            width = width,
+            source_width = source_width
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::clap::Arg;
+
+    fn app() -> App<'static, 'static> {
+        App::new("netbox2netshot")
+            .arg(Arg::with_name("check").long("check"))
+            .arg(
+                Arg::with_name("netbox-url")
+                    .long("netbox-url")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("netbox-token")
+                    .long("netbox-token")
+                    .takes_value(true)
+                    .env("NETBOX2NETSHOT_TEST_NETBOX_TOKEN")
+                    .hide_env_values(true),
+            )
+            .arg(
+                Arg::with_name("netshot-url")
+                    .long("netshot-url")
+                    .takes_value(true)
+                    .env("NETBOX2NETSHOT_TEST_NETSHOT_URL"),
+            )
+            .arg(
+                Arg::with_name("log-directory")
+                    .long("log-directory")
+                    .takes_value(true)
+                    .default_value("logs"),
+            )
+            .arg(
+                Arg::with_name("ensure-credential-set-ids")
+                    .long("ensure-credential-set-ids")
+                    .takes_value(true)
+                    .use_delimiter(true),
+            )
+    }
+
+    fn option(name: &str, value: Option<&str>, source: Source) -> EffectiveOption {
+        EffectiveOption {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+            source,
+        }
+    }
+
+    #[test]
+    fn effective_sources() {
+        std::env::set_var("NETBOX2NETSHOT_TEST_NETBOX_TOKEN", "secret");
+        std::env::set_var(
+            "NETBOX2NETSHOT_TEST_NETSHOT_URL",
+            "https://netshot.example.net",
+        );
+        let app = app();
+        let matches = app.clone().get_matches_from(vec![
+            "netbox2netshot",
+            "--netbox-url",
+            "https://netbox.example.net",
+            "--ensure-credential-set-ids",
+            "1,2",
+            "--check",
+        ]);
+        let profile_sources = HashMap::from([
+            ("check".to_string(), Some("prod".to_string())),
+            ("ensure-credential-set-ids".to_string(), None),
+        ]);
+
+        assert_eq!(
+            effective_options(&app, &matches, &profile_sources),
+            vec![
+                option("check", Some("true"), Source::Profile("prod".to_string())),
+                option("ensure-credential-set-ids", Some("1,2"), Source::ConfigFile),
+                option("log-directory", Some("logs"), Source::Default),
+                option("netbox-token", Some("<redacted>"), Source::Env),
+                option(
+                    "netbox-url",
+                    Some("https://netbox.example.net"),
+                    Source::Cli
+                ),
+                option(
+                    "netshot-url",
+                    Some("https://netshot.example.net"),
+                    Source::Env
+                ),
+            ]
+        );
+    }
+}
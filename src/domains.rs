@@ -0,0 +1,597 @@
+use crate::rest::{netbox, netshot};
+use anyhow::{anyhow, Error, Result};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+thread_local! {
+    static CURRENT_DOMAIN: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A Netshot domain and the Netbox devices to synchronize into it
+#[derive(Debug)]
+pub struct DomainTarget {
+    pub domain_id: u32,
+    /// Only known when the Netshot domains could be listed
+    pub domain_name: Option<String>,
+    pub netbox_devices: Vec<netbox::Device>,
+}
+
+impl DomainTarget {
+    /// The domain ID, followed by its name when known, e.g. `2 (PAR1)`
+    pub fn label(&self) -> String {
+        match &self.domain_name {
+            Some(name) => format!("{} ({})", self.domain_id, name),
+            None => self.domain_id.to_string(),
+        }
+    }
+}
+
+/// A device left out as the custom field of its site does not name an existing Netshot domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainConflict {
+    pub device: String,
+    pub site_id: u32,
+    pub value: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for DomainConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: its site {} names the domain {}, which {}",
+            self.device, self.site_id, self.value, self.reason
+        )
+    }
+}
+
+/// The domain ID held by a custom field, a number, a numeric string or an object with an ID, `None` when empty
+fn custom_field_domain(value: &Value) -> Result<Option<u32>, &'static str> {
+    let id = match value {
+        Value::Null => return Ok(None),
+        Value::String(s) if s.trim().is_empty() => return Ok(None),
+        Value::String(s) => s.trim().parse().ok(),
+        Value::Object(object) => object.get("id").and_then(Value::as_u64),
+        value => value.as_u64(),
+    };
+    id.and_then(|id| u32::try_from(id).ok())
+        .map(Some)
+        .ok_or("is not a domain ID")
+}
+
+/// Split the devices between the domains named by the custom field of their site, given per site ID, the devices
+/// whose site names none going to the default domain, which is always a target
+pub fn split_by_site(
+    devices: Vec<netbox::Device>,
+    default_domain_id: u32,
+    site_fields: &HashMap<u32, Value>,
+    domains: &[netshot::Domain],
+) -> (Vec<DomainTarget>, Vec<DomainConflict>) {
+    let domain_names: HashMap<u32, &str> = domains
+        .iter()
+        .map(|domain| (domain.id, domain.name.as_str()))
+        .collect();
+    let mut split: BTreeMap<u32, Vec<netbox::Device>> = BTreeMap::new();
+    split.insert(default_domain_id, Vec::new());
+    let mut conflicts = Vec::new();
+
+    for device in devices {
+        let site = device
+            .site()
+            .and_then(|site| Some((site.id, site_fields.get(&site.id)?)));
+        let domain_id = match site {
+            None => default_domain_id,
+            Some((site_id, value)) => match custom_field_domain(value) {
+                Ok(None) => default_domain_id,
+                Ok(Some(domain_id)) if domain_names.contains_key(&domain_id) => domain_id,
+                result => {
+                    conflicts.push(DomainConflict {
+                        device: device.display_name(),
+                        site_id,
+                        value: value.to_string(),
+                        reason: result.err().unwrap_or("does not exist on Netshot"),
+                    });
+                    continue;
+                }
+            },
+        };
+        split.entry(domain_id).or_default().push(device);
+    }
+
+    // The default domain first, as it was synchronized alone before
+    let mut targets: Vec<DomainTarget> = split
+        .into_iter()
+        .map(|(domain_id, netbox_devices)| DomainTarget {
+            domain_id,
+            domain_name: domain_names.get(&domain_id).map(|name| name.to_string()),
+            netbox_devices,
+        })
+        .collect();
+    targets.sort_by_key(|target| target.domain_id != default_domain_id);
+    (targets, conflicts)
+}
+
+/// A Netbox device role (slug, name or ID) and the Netshot domain of its devices, given as `<role>=<domain ID>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoleDomain {
+    pub role: String,
+    pub domain_id: u32,
+}
+
+impl FromStr for RoleDomain {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Expected <role>=<domain ID>, got `{}`", s);
+        let (role, domain_id) = s.split_once('=').ok_or_else(invalid)?;
+        let role = role.trim();
+        if role.is_empty() {
+            return Err(invalid());
+        }
+        Ok(RoleDomain {
+            role: role.to_string(),
+            domain_id: domain_id.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl RoleDomain {
+    fn matches(&self, role: &netbox::Role) -> bool {
+        role.slug.as_deref() == Some(self.role.as_str())
+            || role.name.as_deref() == Some(self.role.as_str())
+            || role.id.to_string() == self.role
+    }
+}
+
+/// A device left out as its role is mapped to no Netshot domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnmappedRole {
+    pub device: String,
+    pub role: Option<String>,
+}
+
+impl fmt::Display for UnmappedRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.role {
+            Some(role) => write!(
+                f,
+                "{}: its role {} is mapped to no domain",
+                self.device, role
+            ),
+            None => write!(f, "{}: it has no role", self.device),
+        }
+    }
+}
+
+/// Split the devices between the domains their role is mapped to, the first mapping of a role winning.
+/// Every mapped domain is a target, even without any device, so that its Netshot devices are compared.
+pub fn split_by_role(
+    devices: Vec<netbox::Device>,
+    role_domains: &[RoleDomain],
+    domains: &[netshot::Domain],
+) -> (Vec<DomainTarget>, Vec<UnmappedRole>) {
+    let mut split: BTreeMap<u32, Vec<netbox::Device>> = role_domains
+        .iter()
+        .map(|role_domain| (role_domain.domain_id, Vec::new()))
+        .collect();
+    let mut unmapped = Vec::new();
+
+    for device in devices {
+        let role_domain = device.role().and_then(|role| {
+            role_domains
+                .iter()
+                .find(|role_domain| role_domain.matches(role))
+        });
+        match role_domain {
+            Some(role_domain) => split.entry(role_domain.domain_id).or_default().push(device),
+            None => unmapped.push(UnmappedRole {
+                role: device
+                    .role()
+                    .map(|role| role.slug.clone().unwrap_or_else(|| role.id.to_string())),
+                device: device.display_name(),
+            }),
+        }
+    }
+
+    let targets = split
+        .into_iter()
+        .map(|(domain_id, netbox_devices)| DomainTarget {
+            domain_id,
+            domain_name: domains
+                .iter()
+                .find(|domain| domain.id == domain_id)
+                .map(|domain| domain.name.clone()),
+            netbox_devices,
+        })
+        .collect();
+    (targets, unmapped)
+}
+
+/// The label of the domain being synchronized by the current thread, if any
+pub fn current_domain() -> Option<String> {
+    CURRENT_DOMAIN.with(|domain| domain.borrow().clone())
+}
+
+/// Run the task on every domain, with at most `concurrency` domains at once.
+/// A failure in one domain does not prevent the others from running, the results are returned in the targets order.
+pub fn run_per_domain<R, F>(
+    targets: &[DomainTarget],
+    concurrency: usize,
+    task: F,
+) -> Vec<Result<R, Error>>
+where
+    R: Send,
+    F: Fn(&DomainTarget) -> Result<R, Error> + Sync,
+{
+    let next_target = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(targets.len()));
+    let workers = concurrency.clamp(1, targets.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next_target.fetch_add(1, Ordering::SeqCst);
+                let target = match targets.get(index) {
+                    Some(target) => target,
+                    None => break,
+                };
+                CURRENT_DOMAIN.with(|domain| *domain.borrow_mut() = Some(target.label()));
+                let result = task(target);
+                CURRENT_DOMAIN.with(|domain| *domain.borrow_mut() = None);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Call `call` on every item with at most `parallelism` calls at once, the workers logging for the domain of the
+/// current thread. Each result is handed to `handle` on the current thread along with its item, in completion order,
+/// the next item being started once it is handled. Once `handle` returns true, the items not started yet are handed
+/// to it without a result.
+pub(crate) fn for_each_parallel<T, R, F, H>(
+    items: &[T],
+    parallelism: usize,
+    call: F,
+    mut handle: H,
+) -> Result<(), Error>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+    H: FnMut(&T, Option<R>) -> Result<bool, Error>,
+{
+    let domain = current_domain();
+    let workers = parallelism.clamp(1, items.len().max(1));
+    let (work_sender, work_receiver) = mpsc::channel::<&T>();
+    let work_receiver = Mutex::new(work_receiver);
+    let (result_sender, result_receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let result_sender = result_sender.clone();
+            let (work_receiver, call, domain) = (&work_receiver, &call, &domain);
+            scope.spawn(move || {
+                CURRENT_DOMAIN.with(|current| *current.borrow_mut() = domain.clone());
+                loop {
+                    let item = match work_receiver.lock().unwrap().recv() {
+                        Ok(item) => item,
+                        Err(_) => break,
+                    };
+                    if result_sender.send((item, call(item))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_sender);
+
+        let mut pending = items.iter();
+        let mut in_flight = 0;
+        for item in pending.by_ref().take(workers) {
+            work_sender.send(item).expect("The workers are running");
+            in_flight += 1;
+        }
+        let mut stopped = false;
+        while in_flight > 0 {
+            let (item, result) = result_receiver
+                .recv()
+                .expect("A worker is calling each item in flight");
+            in_flight -= 1;
+            // An error of `handle` lets the calls in flight complete, their results being dropped
+            stopped |= match handle(item, Some(result)) {
+                Ok(stop) => stop,
+                Err(error) => {
+                    drop(work_sender);
+                    return Err(error);
+                }
+            };
+            if stopped {
+                continue;
+            }
+            if let Some(item) = pending.next() {
+                work_sender.send(item).expect("The workers are running");
+                in_flight += 1;
+            }
+        }
+        drop(work_sender);
+        for item in pending {
+            handle(item, None)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    fn targets(count: u32) -> Vec<DomainTarget> {
+        (1..=count)
+            .map(|domain_id| DomainTarget {
+                domain_id,
+                domain_name: (domain_id % 2 == 0).then(|| format!("site-{}", domain_id)),
+                netbox_devices: Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bounded_concurrency() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        let results = run_per_domain(&targets(8), 3, |target| {
+            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now_running, Ordering::SeqCst);
+            thread::sleep(std::time::Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+            assert_eq!(current_domain(), Some(target.label()));
+            Ok(current_domain().unwrap())
+        });
+
+        assert!(max_running.load(Ordering::SeqCst) <= 3);
+        assert_eq!(
+            results.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![
+                "1",
+                "2 (site-2)",
+                "3",
+                "4 (site-4)",
+                "5",
+                "6 (site-6)",
+                "7",
+                "8 (site-8)"
+            ]
+        );
+        assert_eq!(current_domain(), None);
+    }
+
+    #[test]
+    fn parallel_calls() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let items: Vec<u32> = (1..=12).collect();
+        let started = std::time::Instant::now();
+
+        let mut handled = Vec::new();
+        CURRENT_DOMAIN.with(|domain| *domain.borrow_mut() = Some("2 (PAR1)".to_string()));
+        for_each_parallel(
+            &items,
+            4,
+            |item| {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                thread::sleep(std::time::Duration::from_millis(50));
+                running.fetch_sub(1, Ordering::SeqCst);
+                (*item, current_domain())
+            },
+            |item, result| {
+                assert_eq!(result, Some((*item, Some("2 (PAR1)".to_string()))));
+                handled.push(*item);
+                Ok(false)
+            },
+        )
+        .unwrap();
+        CURRENT_DOMAIN.with(|domain| *domain.borrow_mut() = None);
+
+        // 12 calls of 50ms, 4 at once
+        assert_eq!(max_running.load(Ordering::SeqCst), 4);
+        assert!(started.elapsed() < std::time::Duration::from_millis(500));
+        handled.sort_unstable();
+        assert_eq!(handled, items);
+
+        // Stopping hands the items not started yet without a result
+        let mut called = 0;
+        let mut skipped = 0;
+        for_each_parallel(
+            &items,
+            1,
+            |item| *item,
+            |item, result| {
+                match result {
+                    Some(_) => called += 1,
+                    None => skipped += 1,
+                }
+                Ok(*item == 3)
+            },
+        )
+        .unwrap();
+        assert_eq!((called, skipped), (3, 9));
+
+        let error = for_each_parallel(&items, 2, |item| *item, |_, _| Err(anyhow!("audit")));
+        assert_eq!(error.unwrap_err().to_string(), "audit");
+    }
+
+    #[test]
+    fn site_domains() {
+        let device = |name: &str, site_id: Option<u32>| -> netbox::Device {
+            serde_json::from_value(json!({
+                "id": 1,
+                "name": name,
+                "site": site_id.map(|id| json!({"id": id, "slug": format!("site-{}", id)})),
+            }))
+            .unwrap()
+        };
+        let domains: Vec<netshot::Domain> = serde_json::from_value(json!([
+            {"id": 1, "name": "Default"},
+            {"id": 2, "name": "PAR1"},
+            {"id": 3, "name": "AMS1"},
+        ]))
+        .unwrap();
+        let site_fields = HashMap::from([
+            (10, json!(2)),
+            (11, json!("3")),
+            (12, json!({"id": 3, "name": "AMS1"})),
+            (13, Value::Null),
+            (14, json!(9)),
+            (15, json!("par")),
+        ]);
+        let devices = vec![
+            device("par-1", Some(10)),
+            device("ams-1", Some(11)),
+            device("ams-2", Some(12)),
+            device("empty-field", Some(13)),
+            device("no-site", None),
+            device("unknown-domain", Some(14)),
+            device("not-an-id", Some(15)),
+        ];
+
+        let (targets, conflicts) = split_by_site(devices, 1, &site_fields, &domains);
+        let split: Vec<(String, Vec<String>)> = targets
+            .iter()
+            .map(|target| {
+                (
+                    target.label(),
+                    target
+                        .netbox_devices
+                        .iter()
+                        .map(|device| device.display_name())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            split,
+            vec![
+                (
+                    "1 (Default)".to_string(),
+                    vec!["empty-field".to_string(), "no-site".to_string()]
+                ),
+                ("2 (PAR1)".to_string(), vec!["par-1".to_string()]),
+                (
+                    "3 (AMS1)".to_string(),
+                    vec!["ams-1".to_string(), "ams-2".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(
+            conflicts
+                .iter()
+                .map(|conflict| conflict.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "unknown-domain: its site 14 names the domain 9, which does not exist on Netshot",
+                "not-an-id: its site 15 names the domain \"par\", which is not a domain ID",
+            ]
+        );
+
+        // The default domain is a target even without any device
+        let (targets, _) =
+            split_by_site(vec![device("par-1", Some(10))], 1, &site_fields, &domains);
+        assert_eq!(targets[0].domain_id, 1);
+        assert!(targets[0].netbox_devices.is_empty());
+    }
+
+    #[test]
+    fn role_domains() {
+        let device = |name: &str, role: Option<(u32, &str)>| -> netbox::Device {
+            serde_json::from_value(json!({
+                "id": 1,
+                "name": name,
+                "role": role.map(|(id, slug)| json!({"id": id, "name": slug.to_uppercase(), "slug": slug})),
+            }))
+            .unwrap()
+        };
+        let domains: Vec<netshot::Domain> = serde_json::from_value(
+            json!([{"id": 1, "name": "CPE"}, {"id": 2, "name": "Backbone"}]),
+        )
+        .unwrap();
+        let role_domains: Vec<RoleDomain> = ["cpe=1", "BACKBONE=2", "7=2", "lab=3"]
+            .iter()
+            .map(|mapping| mapping.parse().unwrap())
+            .collect();
+        let devices = vec![
+            device("cpe-1", Some((4, "cpe"))),
+            device("core-1", Some((5, "backbone"))),
+            device("agg-1", Some((7, "aggregation"))),
+            device("srv-1", Some((8, "server"))),
+            device("no-role", None),
+        ];
+
+        let (targets, unmapped) = split_by_role(devices, &role_domains, &domains);
+        let split: Vec<(String, Vec<String>)> = targets
+            .iter()
+            .map(|target| {
+                (
+                    target.label(),
+                    target
+                        .netbox_devices
+                        .iter()
+                        .map(|device| device.display_name())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            split,
+            vec![
+                ("1 (CPE)".to_string(), vec!["cpe-1".to_string()]),
+                (
+                    "2 (Backbone)".to_string(),
+                    vec!["core-1".to_string(), "agg-1".to_string()]
+                ),
+                ("3".to_string(), Vec::new()),
+            ]
+        );
+        assert_eq!(
+            unmapped
+                .iter()
+                .map(|unmapped| unmapped.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "srv-1: its role server is mapped to no domain",
+                "no-role: it has no role"
+            ]
+        );
+
+        for invalid in ["cpe", "cpe=", "=1", "cpe=one"] {
+            assert!(invalid.parse::<RoleDomain>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn failures_are_isolated() {
+        let results = run_per_domain(&targets(3), 1, |target| match target.domain_id {
+            2 => Err(anyhow!("Netshot unreachable")),
+            domain_id => Ok(domain_id),
+        });
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &1);
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap(), &3);
+    }
+}
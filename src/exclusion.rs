@@ -0,0 +1,252 @@
+use crate::rest::{netbox, netshot};
+use crate::sync::{self, PlanOptions};
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// An IP, or a CIDR block such as a whole management range, left out of the synchronization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl Cidr {
+    /// Whether the IP is in the block, the IPs of the other family never being
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid IP or CIDR `{}`", s);
+        let (network, prefix_length) = match s.trim().split_once('/') {
+            Some((network, prefix_length)) => (
+                network.parse::<IpAddr>().map_err(|_| invalid())?,
+                Some(prefix_length.parse::<u8>().map_err(|_| invalid())?),
+            ),
+            None => (s.trim().parse::<IpAddr>().map_err(|_| invalid())?, None),
+        };
+        let max_length = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_length = prefix_length.unwrap_or(max_length);
+        if prefix_length > max_length {
+            return Err(invalid());
+        }
+        Ok(Cidr {
+            network,
+            prefix_length,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_length)
+    }
+}
+
+/// The blocks of an exclude file, one IP or CIDR per line. What follows a `#` is a comment.
+fn parse_exclude_file(text: &str, path: &str) -> Result<Vec<Cidr>, Error> {
+    let mut cidrs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let cidr = line
+            .parse()
+            .map_err(|error| anyhow!("{}:{}: {}", path, number + 1, error))?;
+        cidrs.push(cidr);
+    }
+    Ok(cidrs)
+}
+
+/// Read an --exclude-file
+pub fn read_exclude_file(path: &Path) -> Result<Vec<Cidr>, Error> {
+    let text = std::fs::read_to_string(path).map_err(|error| {
+        anyhow!(
+            "Unable to read the exclude file {}: {}",
+            path.display(),
+            error
+        )
+    })?;
+    parse_exclude_file(&text, &path.display().to_string())
+}
+
+/// The block an IP is excluded by, if any. What is not an IP is never excluded.
+fn excluded_by<'a>(exclusions: &'a [Cidr], ip: &str) -> Option<&'a Cidr> {
+    let ip = ip.split('/').next()?.trim().parse::<IpAddr>().ok()?;
+    exclusions.iter().find(|cidr| cidr.contains(&ip))
+}
+
+/// Leave out the Netbox devices with an excluded management IP, so that they are never registered. The IP is the
+/// one the plan resolves with the given options, from the custom field or the primary IPs.
+pub fn exclude_netbox_devices(
+    devices: &mut Vec<netbox::Device>,
+    exclusions: &[Cidr],
+    options: &PlanOptions,
+) {
+    if exclusions.is_empty() {
+        return;
+    }
+    devices.retain(|device| {
+        let excluded = sync::netbox_device_ip(device, options)
+            .ok()
+            .and_then(|(ip, _)| Some((excluded_by(exclusions, &ip)?, ip)));
+        match excluded {
+            Some((cidr, ip)) => {
+                log::debug!(
+                    "Netbox device {}({}) skipped, excluded by {}",
+                    device.display_name(),
+                    ip,
+                    cidr
+                );
+                false
+            }
+            None => true,
+        }
+    });
+}
+
+/// Leave out the Netshot devices with an excluded management IP, so that they are never disabled nor enabled
+pub fn exclude_netshot_devices(devices: &mut Vec<netshot::Device>, exclusions: &[Cidr]) {
+    devices.retain(
+        |device| match excluded_by(exclusions, &device.management_address.ip) {
+            Some(cidr) => {
+                log::debug!(
+                    "Netshot device {}({}) skipped, excluded by {}",
+                    device.name,
+                    device.management_address.ip,
+                    cidr
+                );
+                false
+            }
+            None => true,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn cidr(cidr: &str) -> Cidr {
+        cidr.parse().unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let range = cidr("10.1.0.0/16");
+        assert!(range.contains(&ip("10.1.0.0")));
+        assert!(range.contains(&ip("10.1.255.254")));
+        assert!(!range.contains(&ip("10.2.0.1")));
+        assert!(!range.contains(&ip("10.0.255.255")));
+        // Matched on the network bits only
+        assert!(cidr("192.168.1.77/24").contains(&ip("192.168.1.3")));
+        assert!(cidr("0.0.0.0/0").contains(&ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(&ip("2001:db8::1")));
+
+        let single = cidr("192.0.2.10");
+        assert_eq!(single.to_string(), "192.0.2.10/32");
+        assert!(single.contains(&ip("192.0.2.10")));
+        assert!(!single.contains(&ip("192.0.2.11")));
+
+        assert!(cidr("2001:db8::/32").contains(&ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(&ip("2001:db9::1")));
+
+        for invalid in [
+            "10.0.0.0/33",
+            "10.0.0/8",
+            "2001:db8::/129",
+            "lab-1",
+            "10.0.0.0/x",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn exclude_files() {
+        let text = "# Lab\n10.99.0.0/16\n\n192.0.2.4   # console server\n";
+        assert_eq!(
+            parse_exclude_file(text, "exclude.txt").unwrap(),
+            vec![cidr("10.99.0.0/16"), cidr("192.0.2.4/32")]
+        );
+        assert_eq!(
+            parse_exclude_file("10.0.0.1\nlab-1\n", "exclude.txt")
+                .unwrap_err()
+                .to_string(),
+            "exclude.txt:2: Invalid IP or CIDR `lab-1`"
+        );
+    }
+
+    #[test]
+    fn excluded_devices() {
+        let exclusions = vec![cidr("10.99.0.0/16"), cidr("192.0.2.4")];
+        let mut netbox_devices: Vec<netbox::Device> = serde_json::from_value(json!([
+            {"id": 1, "name": "lab-1", "primary_ip4": {"id": 1, "address": "10.99.3.1/24"}},
+            {"id": 2, "name": "edge-1", "primary_ip4": {"id": 2, "address": "10.98.3.1/24"}},
+            {"id": 3, "name": "no-ip"}
+        ]))
+        .unwrap();
+        exclude_netbox_devices(&mut netbox_devices, &exclusions, &PlanOptions::default());
+        assert_eq!(
+            netbox_devices
+                .iter()
+                .map(|device| device.id)
+                .collect::<Vec<u32>>(),
+            vec![2, 3]
+        );
+
+        // Excluded on the address of the custom field when there is one
+        let mut netbox_devices: Vec<netbox::Device> = serde_json::from_value(json!([
+            {"id": 1, "name": "oob-lab", "primary_ip4": {"id": 1, "address": "10.98.0.1/24"},
+             "custom_fields": {"oob_mgmt_ip": "10.99.0.1/24"}},
+            {"id": 2, "name": "oob-edge", "primary_ip4": {"id": 2, "address": "10.99.0.2/24"},
+             "custom_fields": {"oob_mgmt_ip": "10.98.0.2/24"}},
+            {"id": 3, "name": "no-oob", "primary_ip4": {"id": 3, "address": "10.99.0.3/24"}}
+        ]))
+        .unwrap();
+        let options = PlanOptions {
+            ip_custom_field: Some("oob_mgmt_ip".to_string()),
+            ..PlanOptions::default()
+        };
+        exclude_netbox_devices(&mut netbox_devices, &exclusions, &options);
+        assert_eq!(netbox_devices.len(), 1);
+        assert_eq!(netbox_devices[0].id, 2);
+
+        let mut netshot_devices: Vec<netshot::Device> = serde_json::from_value(json!([
+            {"id": 1, "name": "console", "mgmtAddress": {"ip": "192.0.2.4"}},
+            {"id": 2, "name": "edge-1", "mgmtAddress": {"ip": "10.98.3.1"}},
+            {"id": 3, "name": "lab-2", "mgmtAddress": {"ip": "10.99.200.7"}}
+        ]))
+        .unwrap();
+        exclude_netshot_devices(&mut netshot_devices, &exclusions);
+        assert_eq!(netshot_devices.len(), 1);
+        assert_eq!(netshot_devices[0].name, "edge-1");
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// The envelope an inventory size is expected to fall in, e.g. `1200±10%`, `1200+-50` or `1100..1300`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountExpectation {
+    pub min: usize,
+    pub max: usize,
+    expression: String,
+}
+
+impl FromStr for CountExpectation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow!(
+                "Invalid count expectation `{}`, expected N, N±P%, N±M or MIN..MAX",
+                s
+            )
+        };
+        let parse_count = |count: &str| count.trim().parse::<usize>().map_err(|_| invalid());
+
+        let (min, max) = if let Some((min, max)) = s.split_once("..") {
+            (parse_count(min)?, parse_count(max)?)
+        } else if let Some((count, tolerance)) = s.split_once('±').or_else(|| s.split_once("+-")) {
+            let count = parse_count(count)?;
+            let tolerance = match tolerance.trim().strip_suffix('%') {
+                Some(percentage) => {
+                    let percentage: f64 = percentage.trim().parse().map_err(|_| invalid())?;
+                    (count as f64 * percentage / 100.0).round() as usize
+                }
+                None => parse_count(tolerance)?,
+            };
+            (count.saturating_sub(tolerance), count + tolerance)
+        } else {
+            let count = parse_count(s)?;
+            (count, count)
+        };
+
+        if min > max {
+            return Err(invalid());
+        }
+        Ok(Self {
+            min,
+            max,
+            expression: s.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for CountExpectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}..{})", self.expression, self.min, self.max)
+    }
+}
+
+impl CountExpectation {
+    /// Make sure the count of the given inventory is within the envelope
+    pub fn check(&self, inventory: &str, count: usize) -> Result<(), Error> {
+        if count < self.min {
+            Err(anyhow!(
+                "{} returned {} devices, below the expected minimum of {} from {}",
+                inventory,
+                count,
+                self.min,
+                self
+            ))
+        } else if count > self.max {
+            Err(anyhow!(
+                "{} returned {} devices, above the expected maximum of {} from {}",
+                inventory,
+                count,
+                self.max,
+                self
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The number or percentage of failed changes above which a run is failed, e.g. `0`, `5` or `1%`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailThreshold {
+    Count(usize),
+    Percentage(f64),
+}
+
+impl FromStr for FailThreshold {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid fail threshold `{}`, expected N or P%", s);
+        match s.trim().strip_suffix('%') {
+            Some(percentage) => {
+                let percentage: f64 = percentage.trim().parse().map_err(|_| invalid())?;
+                if !(0.0..=100.0).contains(&percentage) {
+                    return Err(invalid());
+                }
+                Ok(FailThreshold::Percentage(percentage))
+            }
+            None => Ok(FailThreshold::Count(
+                s.trim().parse().map_err(|_| invalid())?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FailThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailThreshold::Count(count) => write!(f, "{}", count),
+            FailThreshold::Percentage(percentage) => write!(f, "{}%", percentage),
+        }
+    }
+}
+
+impl FailThreshold {
+    /// Are there more failures than tolerated among the attempted changes
+    pub fn exceeded(&self, failed: usize, attempted: usize) -> bool {
+        match self {
+            FailThreshold::Count(count) => failed > *count,
+            FailThreshold::Percentage(percentage) => {
+                failed as f64 * 100.0 > percentage * attempted as f64
+            }
+        }
+    }
+}
+
+/// A percentage between 0 and 100, with or without its `%` sign
+pub fn parse_percentage(s: &str) -> Result<f64, Error> {
+    let invalid = || {
+        anyhow!(
+            "Invalid percentage `{}`, expected P or P% between 0 and 100",
+            s
+        )
+    };
+    let percentage: f64 = s
+        .trim()
+        .trim_end_matches('%')
+        .trim()
+        .parse()
+        .map_err(|_| invalid())?;
+    match (0.0..=100.0).contains(&percentage) {
+        true => Ok(percentage),
+        false => Err(invalid()),
+    }
+}
+
+/// The error of a plan beyond the change limits, the run being blocked before any write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeLimitExceeded(pub String);
+
+impl fmt::Display for ChangeLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ChangeLimitExceeded {}
+
+/// The most changes a plan may hold, against the filters suddenly matching nothing
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChangeLimits {
+    pub max_changes: Option<usize>,
+    /// The share of the Netshot inventory to disable
+    pub max_disable_percent: Option<f64>,
+}
+
+impl ChangeLimits {
+    /// Make sure the planned changes stay within the limits, given the size of the Netshot inventory
+    pub fn check(
+        &self,
+        changes: usize,
+        disables: usize,
+        netshot_count: usize,
+    ) -> Result<(), ChangeLimitExceeded> {
+        if let Some(max_changes) = self.max_changes.filter(|max| changes > *max) {
+            return Err(ChangeLimitExceeded(format!(
+                "Blocked by the change limits: {} changes planned, above --max-changes {}",
+                changes, max_changes
+            )));
+        }
+        if let Some(max_disable_percent) = self.max_disable_percent {
+            if disables as f64 * 100.0 > max_disable_percent * netshot_count as f64 {
+                return Err(ChangeLimitExceeded(format!(
+                    "Blocked by the change limits: {} of the {} Netshot devices ({:.1}%) to be disabled, above --max-disable-percent {}",
+                    disables,
+                    netshot_count,
+                    disables as f64 * 100.0 / netshot_count as f64,
+                    max_disable_percent
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(expression: &str) -> (usize, usize) {
+        let expectation: CountExpectation = expression.parse().unwrap();
+        (expectation.min, expectation.max)
+    }
+
+    #[test]
+    fn parse_expectations() {
+        assert_eq!(bounds("1200±10%"), (1080, 1320));
+        assert_eq!(bounds("1200+-10%"), (1080, 1320));
+        assert_eq!(bounds("1200±50"), (1150, 1250));
+        assert_eq!(bounds("1100..1300"), (1100, 1300));
+        assert_eq!(bounds("12"), (12, 12));
+        assert_eq!(bounds("10±200%"), (0, 30));
+
+        assert!("1300..1100".parse::<CountExpectation>().is_err());
+        assert!("about 1200".parse::<CountExpectation>().is_err());
+        assert!("1200±ten%".parse::<CountExpectation>().is_err());
+    }
+
+    #[test]
+    fn check_counts() {
+        let expectation: CountExpectation = "1200±10%".parse().unwrap();
+
+        assert!(expectation.check("Netbox", 1080).is_ok());
+        assert!(expectation.check("Netbox", 1320).is_ok());
+
+        let error = expectation.check("Netbox", 1000).unwrap_err().to_string();
+        assert!(error.contains("returned 1000 devices"));
+        assert!(error.contains("below the expected minimum of 1080"));
+
+        let error = expectation.check("Netshot", 2000).unwrap_err().to_string();
+        assert!(error.contains("above the expected maximum of 1320"));
+    }
+
+    #[test]
+    fn fail_thresholds() {
+        let any: FailThreshold = "0".parse().unwrap();
+        assert!(!any.exceeded(0, 500));
+        assert!(any.exceeded(1, 500));
+
+        let count: FailThreshold = "5".parse().unwrap();
+        assert!(!count.exceeded(5, 10));
+        assert!(count.exceeded(6, 10));
+
+        let percentage: FailThreshold = "1%".parse().unwrap();
+        assert!(!percentage.exceeded(5, 500));
+        assert!(percentage.exceeded(6, 500));
+        assert!(!"100%".parse::<FailThreshold>().unwrap().exceeded(500, 500));
+        assert!("0%".parse::<FailThreshold>().unwrap().exceeded(1, 500));
+
+        assert!("120%".parse::<FailThreshold>().is_err());
+        assert!("some".parse::<FailThreshold>().is_err());
+    }
+
+    #[test]
+    fn change_limits() {
+        assert!(ChangeLimits::default().check(9000, 9000, 9000).is_ok());
+
+        let limits = ChangeLimits {
+            max_changes: Some(500),
+            max_disable_percent: Some(parse_percentage("10%").unwrap()),
+        };
+        assert!(limits.check(500, 100, 1000).is_ok());
+        // An empty Netbox filter disables the whole domain
+        assert_eq!(
+            limits.check(1000, 1000, 1000).unwrap_err().to_string(),
+            "Blocked by the change limits: 1000 changes planned, above --max-changes 500"
+        );
+        assert_eq!(
+            limits.check(101, 101, 1000).unwrap_err().to_string(),
+            "Blocked by the change limits: 101 of the 1000 Netshot devices (10.1%) to be disabled, above --max-disable-percent 10"
+        );
+        // The registrations of an empty Netshot domain disable nothing
+        assert!(limits.check(400, 0, 0).is_ok());
+
+        assert_eq!(parse_percentage("2.5").unwrap(), 2.5);
+        assert!(parse_percentage("101%").is_err());
+        assert!(parse_percentage("-1").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::rest::netshot;
+use anyhow::{anyhow, Error, Result};
+use serde::Serialize;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The output formats of the Netshot inventory export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(anyhow!(
+                "Unknown export format `{}`, expected csv or json",
+                s
+            )),
+        }
+    }
+}
+
+/// A row of the Netshot inventory export
+#[derive(Debug, Serialize)]
+pub struct ExportRow {
+    pub id: u32,
+    pub name: String,
+    pub ip: String,
+    pub status: Option<String>,
+    pub domain: Option<String>,
+    pub driver: Option<String>,
+    pub software_version: Option<String>,
+    pub family: Option<String>,
+    pub network_class: Option<String>,
+}
+
+impl ExportRow {
+    /// Build the row of a device, falling back to the domain used as filter when the device does not expose it
+    pub fn from_device(device: netshot::Device, domain_id: Option<u32>) -> Self {
+        let domain = match device.domain {
+            Some(domain) => Some(domain.name.unwrap_or(domain.id.to_string())),
+            None => domain_id.map(|id| id.to_string()),
+        };
+        ExportRow {
+            id: device.id,
+            name: device.name,
+            ip: device.management_address.ip,
+            status: device.status,
+            domain,
+            driver: device.driver,
+            software_version: device.software_version,
+            family: device.family,
+            network_class: device.network_class,
+        }
+    }
+}
+
+/// Write the export rows as they come, without buffering the whole inventory
+pub enum Exporter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Json { writer: W, rows: usize },
+}
+
+impl<W: Write> Exporter<W> {
+    /// Start an export in the given format
+    pub fn new(format: ExportFormat, mut writer: W) -> Result<Self, Error> {
+        match format {
+            ExportFormat::Csv => Ok(Exporter::Csv(Box::new(csv::Writer::from_writer(writer)))),
+            ExportFormat::Json => {
+                writer.write_all(b"[")?;
+                Ok(Exporter::Json { writer, rows: 0 })
+            }
+        }
+    }
+
+    /// Write a single row
+    pub fn write(&mut self, row: &ExportRow) -> Result<(), Error> {
+        match self {
+            Exporter::Csv(writer) => writer.serialize(row)?,
+            Exporter::Json { writer, rows } => {
+                if *rows > 0 {
+                    writer.write_all(b",")?;
+                }
+                writer.write_all(b"\n  ")?;
+                serde_json::to_writer(&mut *writer, row)?;
+                *rows += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Terminate the export and flush the output
+    pub fn finish(self) -> Result<(), Error> {
+        match self {
+            Exporter::Csv(mut writer) => writer.flush()?,
+            Exporter::Json { mut writer, rows } => {
+                if rows > 0 {
+                    writer.write_all(b"\n")?;
+                }
+                writer.write_all(b"]\n")?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Export the Netshot inventory, page by page
+pub fn export_netshot_inventory<W: Write>(
+    client: &netshot::NetshotClient,
+    domain_id: Option<u32>,
+    format: ExportFormat,
+    writer: W,
+) -> Result<usize, Error> {
+    let mut exporter = Exporter::new(format, writer)?;
+    let mut count = 0;
+    client.for_each_devices_page(domain_id, |page| {
+        for device in page {
+            exporter.write(&ExportRow::from_device(device, domain_id))?;
+            count += 1;
+        }
+        Ok(())
+    })?;
+    exporter.finish()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Vec<ExportRow> {
+        let body = std::fs::read_to_string("tests/data/netshot/compat/0.21/devices.json").unwrap();
+        let devices: Vec<netshot::Device> = serde_json::from_str(&body).unwrap();
+        devices
+            .into_iter()
+            .map(|device| ExportRow::from_device(device, None))
+            .collect()
+    }
+
+    fn export(format: ExportFormat, rows: &[ExportRow]) -> String {
+        let mut output = Vec::new();
+        let mut exporter = Exporter::new(format, &mut output).unwrap();
+        for row in rows {
+            exporter.write(row).unwrap();
+        }
+        exporter.finish().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn csv_export() {
+        assert_eq!(
+            export(ExportFormat::Csv, &rows()),
+            "id,name,ip,status,domain,driver,software_version,family,network_class\n\
+             1,test-device,1.2.3.4,INPRODUCTION,Default,CiscoNXOS,10.2(5),Nexus 9000 C93108TC-EX,SWITCH\n\
+             2,test-device-disabled,1.2.3.5,DISABLED,Default,CiscoNXOS,10.2(5),Nexus 9000 C93108TC-EX,SWITCH\n"
+        );
+    }
+
+    #[test]
+    fn non_ascii_export() {
+        let body = std::fs::read_to_string("tests/data/netshot/non_ascii_names.json").unwrap();
+        let devices: Vec<netshot::Device> = serde_json::from_str(&body).unwrap();
+        let rows: Vec<ExportRow> = devices
+            .into_iter()
+            .map(|device| ExportRow::from_device(device, None))
+            .collect();
+
+        let output = export(ExportFormat::Csv, &rows);
+        let mut reader = csv::Reader::from_reader(output.as_bytes());
+        let names: Vec<String> = reader
+            .records()
+            .map(|record| record.unwrap()[1].to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "東京-コア-1",
+                "DÜSSELDORF-Straße-sw-1.example.de",
+                "Zürich-Äußere-sw-2"
+            ]
+        );
+    }
+
+    #[test]
+    fn json_export() {
+        let output = export(ExportFormat::Json, &rows());
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(value.as_array().unwrap().len(), 2);
+        assert_eq!(value[1]["name"], "test-device-disabled");
+        assert_eq!(value[1]["domain"], "Default");
+        assert_eq!(value[1]["network_class"], "SWITCH");
+        assert_eq!(export(ExportFormat::Json, &[]), "[]\n");
+    }
+
+    #[test]
+    fn export_format() {
+        assert_eq!("csv".parse::<ExportFormat>().unwrap(), ExportFormat::Csv);
+        assert_eq!("json".parse::<ExportFormat>().unwrap(), ExportFormat::Json);
+        assert!("xml".parse::<ExportFormat>().is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use crate::rest::netbox::Device;
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::path::Path;
+
+/// The number of devices named as examples of a mismatch
+const MISMATCH_EXAMPLES: usize = 3;
+
+/// A device field a Netbox filter key can be checked against locally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Status,
+    Role,
+    RoleId,
+    Site,
+    SiteId,
+    Tag,
+}
+
+impl Field {
+    fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "status" => Some(Field::Status),
+            "role" | "device_role" => Some(Field::Role),
+            "role_id" | "device_role_id" => Some(Field::RoleId),
+            "site" => Some(Field::Site),
+            "site_id" => Some(Field::SiteId),
+            "tag" => Some(Field::Tag),
+            _ => None,
+        }
+    }
+
+    /// The values of the field on the device, empty when it has none
+    fn values(self, device: &Device) -> Vec<String> {
+        match self {
+            Field::Status => device.status().into_iter().collect(),
+            Field::Role => device
+                .role()
+                .and_then(|role| role.slug.clone())
+                .into_iter()
+                .collect(),
+            Field::RoleId => device
+                .role()
+                .map(|role| role.id.to_string())
+                .into_iter()
+                .collect(),
+            Field::Site => device
+                .site()
+                .and_then(|site| site.slug.clone())
+                .into_iter()
+                .collect(),
+            Field::SiteId => device
+                .site()
+                .map(|site| site.id.to_string())
+                .into_iter()
+                .collect(),
+            Field::Tag => device.tags(),
+        }
+    }
+}
+
+/// A condition of a Netbox filter, satisfied by the devices having one of its values (none of them when negated)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition {
+    key: String,
+    field: Field,
+    values: Vec<String>,
+    negated: bool,
+}
+
+impl Condition {
+    fn is_satisfied_by(&self, device: &Device) -> bool {
+        let values = self.field.values(device);
+        let matches = self.values.iter().any(|value| values.contains(value));
+        matches != self.negated
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs: Vec<String> = self
+            .values
+            .iter()
+            .map(|value| format!("{}={}", self.key, value))
+            .collect();
+        write!(f, "{}", pairs.join("&"))
+    }
+}
+
+/// The decoded key and value pairs of a filter query string, a `#` being part of a value rather than a fragment
+fn query_pairs(filter: &str) -> Vec<(String, String)> {
+    reqwest::Url::parse(&format!(
+        "http://netbox/?{}",
+        filter.trim_start_matches('?').replace('#', "%23")
+    ))
+    .expect("Any query string makes a valid URL")
+    .query_pairs()
+    .map(|(key, value)| (key.into_owned(), value.into_owned()))
+    .collect()
+}
+
+/// The key and value pairs of a filter file, one `key=value` per line, the values being plain rather than encoded.
+/// The blank lines and those starting with `#` are ignored.
+fn parse_filter_file(text: &str, path: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut pairs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                pairs.push((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => {
+                return Err(anyhow!(
+                    "{}:{}: expected key=value, got `{}`",
+                    path,
+                    number + 1,
+                    line
+                ))
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+/// The query string of the filter file pairs followed by those of the filter given on the command line
+fn merge_filters(file_pairs: Vec<(String, String)>, filter: &str) -> String {
+    let mut url = reqwest::Url::parse("http://netbox/").expect("A valid URL");
+    url.query_pairs_mut()
+        .extend_pairs(file_pairs.into_iter().chain(query_pairs(filter)));
+    url.query().unwrap_or_default().to_string()
+}
+
+/// Percent-encode the values of a filter typed as is, such as the non-ASCII site names or tenants with a `&`
+/// already encoded, so that it reaches Netbox unchanged
+pub fn encode_filter(filter: &str) -> String {
+    merge_filters(Vec::new(), filter)
+}
+
+/// Read a --netbox-devices-filter-file, merging the filter given on the command line after it
+pub fn read_filter_file(path: &Path, filter: &str) -> Result<String, Error> {
+    let text = std::fs::read_to_string(path).map_err(|error| {
+        anyhow!(
+            "Unable to read the filter file {}: {}",
+            path.display(),
+            error
+        )
+    })?;
+    let file_pairs = parse_filter_file(&text, &path.display().to_string())?;
+    Ok(merge_filters(file_pairs, filter))
+}
+
+/// The conditions of a filter query string which can be checked locally, and the keys which cannot
+fn parse_filter(filter: &str) -> (Vec<Condition>, Vec<String>) {
+    let mut conditions: Vec<Condition> = Vec::new();
+    let mut unchecked = Vec::new();
+    for (key, value) in query_pairs(filter) {
+        let (name, negated) = match key.strip_suffix("__n") {
+            Some(name) => (name, true),
+            None => (key.as_str(), false),
+        };
+        let field = match Field::from_key(name) {
+            Some(field) => field,
+            None => {
+                if !unchecked.contains(&key) {
+                    unchecked.push(key);
+                }
+                continue;
+            }
+        };
+        // Netbox requires every tag, but any of the values of the other fields
+        let existing = conditions
+            .iter_mut()
+            .find(|condition| condition.key == key && (field != Field::Tag || negated));
+        match existing {
+            Some(condition) => condition.values.push(value),
+            None => conditions.push(Condition {
+                key,
+                field,
+                values: vec![value],
+                negated,
+            }),
+        }
+    }
+    (conditions, unchecked)
+}
+
+/// A filter condition not satisfied by some of the fetched devices, which Netbox presumably ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub condition: String,
+    pub devices: usize,
+    pub fetched: usize,
+    pub examples: Vec<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` is not satisfied by {} of the {} devices fetched (e.g. {}), Netbox appears to have ignored it",
+            self.condition,
+            self.devices,
+            self.fetched,
+            self.examples.join(", ")
+        )
+    }
+}
+
+/// What checking the fetched devices against their filter found
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    pub mismatches: Vec<Mismatch>,
+    /// The filter keys which cannot be checked locally
+    pub unchecked: Vec<String>,
+}
+
+/// Check the devices fetched with the filter satisfy its status, role, site and tag conditions, as Netbox ignores the
+/// parameters it does not know instead of failing
+pub fn verify(filter: &str, devices: &[Device]) -> Verification {
+    let (conditions, unchecked) = parse_filter(filter);
+    let mismatches = conditions
+        .iter()
+        .filter_map(|condition| {
+            let failing: Vec<&Device> = devices
+                .iter()
+                .filter(|device| !condition.is_satisfied_by(device))
+                .collect();
+            if failing.is_empty() {
+                return None;
+            }
+            Some(Mismatch {
+                condition: condition.to_string(),
+                devices: failing.len(),
+                fetched: devices.len(),
+                examples: failing
+                    .iter()
+                    .take(MISMATCH_EXAMPLES)
+                    .map(|device| {
+                        let field = condition.key.trim_end_matches("__n");
+                        let values = condition.field.values(device);
+                        if values.is_empty() {
+                            format!("{} without {}", device.display_name(), field)
+                        } else {
+                            format!(
+                                "{} with {} {}",
+                                device.display_name(),
+                                field,
+                                values.join(",")
+                            )
+                        }
+                    })
+                    .collect(),
+            })
+        })
+        .collect();
+    Verification {
+        mismatches,
+        unchecked,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn device(name: &str, role: &str, site: &str, tags: &[&str]) -> Device {
+        serde_json::from_value(json!({
+            "id": 1,
+            "name": name,
+            "status": {"value": "active", "label": "Active"},
+            "role": {"id": 4, "name": role, "slug": role},
+            "site": {"id": 2, "name": site, "slug": site},
+            "tags": tags.iter().map(|tag| json!({"id": 1, "name": tag, "slug": tag})).collect::<Vec<_>>(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn filter_conditions() {
+        let (conditions, unchecked) =
+            parse_filter("role=cpe&role=router&tag=a&tag=b&status__n=offline&has_primary_ip=true");
+        assert_eq!(
+            conditions
+                .iter()
+                .map(|condition| condition.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "role=cpe&role=router",
+                "tag=a",
+                "tag=b",
+                "status__n=offline"
+            ]
+        );
+        assert_eq!(unchecked, vec!["has_primary_ip".to_string()]);
+    }
+
+    #[test]
+    fn filter_files() {
+        let text =
+            "# Production CPEs\nrole=customer-cpe\n\n  status = active\ntenant=Scaleway Dedibox\n";
+        let pairs = parse_filter_file(text, "prod-cpe.txt").unwrap();
+        assert_eq!(
+            merge_filters(pairs, "site=par1&tag=mgmt"),
+            "role=customer-cpe&status=active&tenant=Scaleway+Dedibox&site=par1&tag=mgmt"
+        );
+        assert_eq!(merge_filters(Vec::new(), "?tenant=a%20b"), "tenant=a+b");
+        assert_eq!(
+            encode_filter("site=東京&tenant=Müller %26 Söhne&tag=rack#4"),
+            "site=%E6%9D%B1%E4%BA%AC&tenant=M%C3%BCller+%26+S%C3%B6hne&tag=rack%234"
+        );
+        assert_eq!(
+            encode_filter("role=cpe&status=active"),
+            "role=cpe&status=active"
+        );
+        let pairs = parse_filter_file("site=münchen\ntenant=東京 DC\n", "dc.txt").unwrap();
+        assert_eq!(
+            merge_filters(pairs, ""),
+            "site=m%C3%BCnchen&tenant=%E6%9D%B1%E4%BA%AC+DC"
+        );
+
+        let error = parse_filter_file("role=cpe\n# site\npar1\n", "prod-cpe.txt").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "prod-cpe.txt:3: expected key=value, got `par1`"
+        );
+        assert!(parse_filter_file("=par1", "prod-cpe.txt").is_err());
+    }
+
+    #[test]
+    fn ignored_parameters() {
+        let devices = vec![
+            device("cpe-1", "customer-cpe", "par1", &["mgmt"]),
+            device("core-1", "core", "par1", &["mgmt", "backbone"]),
+        ];
+
+        // Honoured filters
+        assert_eq!(
+            verify("site=par1&tag=mgmt&status=active&role_id=4", &devices),
+            Verification::default()
+        );
+        assert_eq!(
+            verify("role=customer-cpe&role=core&role__n=cpe", &devices),
+            Verification::default()
+        );
+
+        // A role slug Netbox does not know, along with a key which cannot be checked
+        let verification = verify("role=cpe&tenant=scw", &devices);
+        assert_eq!(verification.unchecked, vec!["tenant".to_string()]);
+        assert_eq!(
+            verification.mismatches[0].to_string(),
+            "`role=cpe` is not satisfied by 2 of the 2 devices fetched (e.g. cpe-1 with role customer-cpe, core-1 with role core), Netbox appears to have ignored it"
+        );
+
+        // Every tag is required
+        let verification = verify("tag=mgmt&tag=backbone", &devices);
+        assert_eq!(verification.mismatches.len(), 1);
+        assert_eq!(verification.mismatches[0].condition, "tag=backbone");
+        assert_eq!(verification.mismatches[0].devices, 1);
+
+        // Older releases send the tags as names
+        let device: Device =
+            serde_json::from_value(json!({"id": 3, "name": "old", "tags": ["mgmt"]})).unwrap();
+        assert!(verify("tag=mgmt", &[device]).mismatches.is_empty());
+    }
+}
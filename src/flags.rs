@@ -0,0 +1,125 @@
+use std::ffi::OsString;
+use std::fmt;
+
+/// A flag renamed in a release, still accepted under its old name as a hidden alias for at least one more
+#[derive(Debug)]
+pub struct RenamedFlag {
+    pub old: &'static str,
+    pub new: &'static str,
+    pub old_env: Option<&'static str>,
+    pub new_env: Option<&'static str>,
+}
+
+/// The renamed flags, to be declared as an `alias` of their new name too
+pub const RENAMED_FLAGS: &[RenamedFlag] = &[];
+
+/// The use of a deprecated flag or environment variable, along with its replacement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeprecatedUse {
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for DeprecatedUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is deprecated and will be removed in a future release, use {} instead",
+            self.old, self.new
+        )
+    }
+}
+
+/// The deprecated flags and environment variables used, once each whatever the number of occurrences
+pub fn deprecated_uses<I, F>(renamed: &[RenamedFlag], args: I, is_set: F) -> Vec<DeprecatedUse>
+where
+    I: IntoIterator<Item = OsString>,
+    F: Fn(&str) -> bool,
+{
+    // The values after `--` are not flags
+    let args: Vec<OsString> = args
+        .into_iter()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .collect();
+    let mut uses = Vec::new();
+    for flag in renamed {
+        let old = format!("--{}", flag.old);
+        let used = args.iter().any(|arg| {
+            let arg = arg.to_string_lossy();
+            arg == old || arg.starts_with(&format!("{}=", old))
+        });
+        if used {
+            uses.push(DeprecatedUse {
+                old,
+                new: format!("--{}", flag.new),
+            });
+        }
+        if let (Some(old_env), Some(new_env)) = (flag.old_env, flag.new_env) {
+            if is_set(old_env) {
+                uses.push(DeprecatedUse {
+                    old: old_env.to_string(),
+                    new: new_env.to_string(),
+                });
+            }
+        }
+    }
+    uses
+}
+
+/// Copy the deprecated environment variables to their new name when it is not set, before the options are parsed
+pub fn migrate_env(renamed: &[RenamedFlag]) {
+    for flag in renamed {
+        if let (Some(old_env), Some(new_env)) = (flag.old_env, flag.new_env) {
+            if let (Some(value), None) = (std::env::var_os(old_env), std::env::var_os(new_env)) {
+                std::env::set_var(new_env, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RENAMED: &[RenamedFlag] = &[RenamedFlag {
+        old: "old-policy",
+        new: "new-policy",
+        old_env: Some("OLD_POLICY"),
+        new_env: Some("NEW_POLICY"),
+    }];
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        std::iter::once("netbox2netshot")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect()
+    }
+
+    #[test]
+    fn deprecated_flags() {
+        let none = |_: &str| false;
+        assert!(deprecated_uses(RENAMED, args(&["--new-policy", "x"]), none).is_empty());
+        assert!(deprecated_uses(RENAMED, args(&["--", "--old-policy"]), none).is_empty());
+
+        let uses = deprecated_uses(
+            RENAMED,
+            args(&["--old-policy", "x", "--old-policy=y"]),
+            none,
+        );
+        assert_eq!(uses.len(), 1);
+        assert_eq!(
+            uses[0].to_string(),
+            "--old-policy is deprecated and will be removed in a future release, use --new-policy instead"
+        );
+
+        let uses = deprecated_uses(RENAMED, args(&[]), |name| name == "OLD_POLICY");
+        assert_eq!(
+            uses,
+            vec![DeprecatedUse {
+                old: "OLD_POLICY".to_string(),
+                new: "NEW_POLICY".to_string(),
+            }]
+        );
+    }
+}
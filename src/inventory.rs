@@ -0,0 +1,131 @@
+use crate::rest::netshot::{self, DeviceDomain};
+use anyhow::{anyhow, Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// The file of the Netbox devices in a --dump-inventories directory
+pub const NETBOX_FILE: &str = "netbox-devices.json";
+
+/// The file of the Netshot devices of all the domains in a --dump-inventories directory
+pub const NETSHOT_FILE: &str = "netshot-devices.json";
+
+/// Read the devices of an inventory file, written by `write_inventory` or by hand as fixtures
+pub fn read_inventory<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, Error> {
+    let text = fs::read_to_string(path).map_err(|error| {
+        anyhow!(
+            "Unable to read the inventory file {}: {}",
+            path.display(),
+            error
+        )
+    })?;
+    serde_json::from_str(&text)
+        .map_err(|error| anyhow!("Invalid inventory file {}: {}", path.display(), error))
+}
+
+/// Write the devices of an inventory to a file of the directory, creating it when needed
+pub fn write_inventory<T: Serialize>(
+    directory: &Path,
+    file: &str,
+    devices: &[T],
+) -> Result<(), Error> {
+    let path = directory.join(file);
+    fs::create_dir_all(directory)
+        .and_then(|_| fs::write(&path, serde_json::to_vec_pretty(devices)?))
+        .map_err(|error| {
+            anyhow!(
+                "Unable to write the inventory file {}: {}",
+                path.display(),
+                error
+            )
+        })?;
+    log::info!("{} devices written to {}", devices.len(), path.display());
+    Ok(())
+}
+
+/// Tag the Netshot devices fetched from a domain with it, when Netshot leaves it out, so that the devices of all the
+/// domains can share a file
+pub fn set_domain(devices: &mut [netshot::Device], domain_id: u32) {
+    for device in devices.iter_mut().filter(|device| device.domain.is_none()) {
+        device.domain = Some(DeviceDomain {
+            id: domain_id,
+            name: None,
+        });
+    }
+}
+
+/// The Netshot devices of a domain in a file of all the domains, the devices without domain being in all of them
+pub fn domain_devices(devices: &[netshot::Device], domain_id: u32) -> Vec<netshot::Device> {
+    devices
+        .iter()
+        .filter(|device| {
+            device
+                .domain
+                .as_ref()
+                .is_none_or(|domain| domain.id == domain_id)
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rest::netbox;
+    use crate::rest::netbox::NetboxClient;
+    use crate::rest::netshot::NetshotClient;
+    use crate::sync::{self, PlanOptions};
+
+    #[test]
+    fn replayed_inventories() {
+        let url = mockito::server_url();
+        let _netbox = mockito::mock("GET", "/api/dcim/devices/")
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/single_good_device.json")
+            .create();
+        let _netshot = mockito::mock("GET", "/api/devices")
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netshot/duplicate_ip.json")
+            .create();
+
+        let netbox_devices = NetboxClient::builder(url.clone())
+            .build()
+            .unwrap()
+            .get_devices("")
+            .unwrap()
+            .items;
+        let mut netshot_devices = NetshotClient::builder(url)
+            .build()
+            .unwrap()
+            .get_devices(2)
+            .unwrap()
+            .items;
+        set_domain(&mut netshot_devices, 2);
+        let options = PlanOptions::default();
+        let fetched_plan = sync::plan(&netbox_devices, &netshot_devices, &options);
+
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-inventory-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        write_inventory(&directory, NETBOX_FILE, &netbox_devices).unwrap();
+        write_inventory(&directory, NETSHOT_FILE, &netshot_devices).unwrap();
+
+        let loaded_netbox: Vec<netbox::Device> =
+            read_inventory(&directory.join(NETBOX_FILE)).unwrap();
+        let loaded_netshot: Vec<netshot::Device> =
+            read_inventory(&directory.join(NETSHOT_FILE)).unwrap();
+        assert_eq!(loaded_netbox, netbox_devices);
+        assert_eq!(loaded_netshot, netshot_devices);
+        let loaded_netshot = domain_devices(&loaded_netshot, 2);
+        assert_eq!(loaded_netshot.len(), netshot_devices.len());
+        assert!(domain_devices(&loaded_netshot, 3).is_empty());
+        assert_eq!(
+            sync::plan(&loaded_netbox, &loaded_netshot, &options),
+            fetched_plan
+        );
+
+        assert!(read_inventory::<netshot::Device>(&directory.join("missing.json")).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+//! The synchronization of the Netbox devices to Netshot, for the tools embedding it rather than running the
+//! `netbox2netshot` CLI.
+//!
+//! A domain is synchronized in two steps: [`sync::plan`] compares the inventories fetched with the clients, without
+//! any call, and [`apply::apply`] writes the planned changes to Netshot.
+//! [`run::run_domains`] does both for every domain, as the CLI does: all the domains are planned, then checked against
+//! the change limits together, before the first change.
+//! [`run::Run`] wraps it the way the CLI runs it, from the state of the previous runs to the saved report.
+//!
+//! ```
+//! use netbox2netshot::prelude::*;
+//!
+//! let netbox_devices: Vec<NetboxDevice> = serde_json::from_str(
+//!     r#"[{"id": 1, "name": "edge-1", "primary_ip4": {"id": 1, "address": "10.0.0.1/32"}}]"#,
+//! )
+//! .unwrap();
+//! let plan = plan(&netbox_devices, &[], &PlanOptions::default());
+//! assert_eq!(plan.to_register[0].ip, "10.0.0.1");
+//! ```
+
+pub mod apply;
+pub(crate) mod attributes;
+pub(crate) mod budget;
+pub(crate) mod common;
+pub mod domains;
+pub(crate) mod exclusion;
+pub(crate) mod expectation;
+pub(crate) mod filter;
+pub(crate) mod inventory;
+pub(crate) mod mapping;
+pub(crate) mod metrics;
+pub(crate) mod paths;
+pub(crate) mod preflight;
+pub(crate) mod progress;
+pub mod report;
+pub mod rest;
+pub mod run;
+pub(crate) mod state;
+pub mod sync;
+pub(crate) mod window;
+
+/// The types needed to plan and apply a synchronization
+pub mod prelude {
+    pub use crate::apply::{apply, ApplyOptions, Recorders};
+    pub use crate::domains::DomainTarget;
+    pub use crate::report::{Action, ActionOutcome, ReportSummary};
+    pub use crate::rest::api::{NetboxApi, NetshotApi};
+    pub use crate::rest::netbox::{Device as NetboxDevice, NetboxClient};
+    pub use crate::rest::netshot::{Device as NetshotDevice, NetshotClient};
+    pub use crate::sync::{plan, PlanOptions, PlannedDevice, SyncPlan};
+}
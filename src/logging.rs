@@ -0,0 +1,236 @@
+use crate::domains;
+use anyhow::{anyhow, Error, Result};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use flexi_logger::{DeferredNow, Record};
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::{Map, Value as JsonValue};
+use std::io::Write;
+use std::str::FromStr;
+
+/// The format of the log records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// The human readable lines
+    #[default]
+    Text,
+    /// A JSON object per line, for the log shippers
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("Unknown log format `{}`, expected text or json", s)),
+        }
+    }
+}
+
+/// Where the log records go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogTarget {
+    /// The log files, duplicated to the console
+    #[default]
+    Both,
+    File,
+    /// The standard output only, never touching the filesystem
+    Stdout,
+    /// The standard error only, never touching the filesystem
+    Stderr,
+}
+
+impl FromStr for LogTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "both" => Ok(LogTarget::Both),
+            "file" => Ok(LogTarget::File),
+            "stdout" => Ok(LogTarget::Stdout),
+            "stderr" => Ok(LogTarget::Stderr),
+            _ => Err(anyhow!(
+                "Unknown log target `{}`, expected both, file, stdout or stderr",
+                s
+            )),
+        }
+    }
+}
+
+/// A console stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Stdout,
+    Stderr,
+}
+
+/// The writers of the logger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Writers {
+    pub file: bool,
+    pub console: Option<Console>,
+}
+
+impl LogTarget {
+    /// The writers of the target, the console records going to stderr when stdout carries the command output
+    pub fn writers(self, stdout_reserved: bool) -> Writers {
+        let console = match stdout_reserved {
+            true => Console::Stderr,
+            false => Console::Stdout,
+        };
+        match self {
+            LogTarget::Both => Writers {
+                file: true,
+                console: Some(console),
+            },
+            LogTarget::File => Writers {
+                file: true,
+                console: None,
+            },
+            LogTarget::Stdout => Writers {
+                file: false,
+                console: Some(console),
+            },
+            LogTarget::Stderr => Writers {
+                file: false,
+                console: Some(Console::Stderr),
+            },
+        }
+    }
+}
+
+/// Collect the key-values of a record, such as the `ip` and `action` of the synchronization messages
+struct Fields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            JsonValue::from(value)
+        } else if let Some(value) = value.to_i64() {
+            JsonValue::from(value)
+        } else if let Some(value) = value.to_bool() {
+            JsonValue::from(value)
+        } else {
+            JsonValue::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Log format writing each record as a single JSON object, with the key-values of the record as fields
+pub fn json_format(
+    w: &mut dyn Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> std::io::Result<()> {
+    let timestamp = Utc.timestamp_nanos(now.now().unix_timestamp_nanos() as i64);
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".to_string(),
+        JsonValue::from(timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)),
+    );
+    object.insert(
+        "level".to_string(),
+        JsonValue::from(record.level().as_str()),
+    );
+    object.insert("target".to_string(), JsonValue::from(record.target()));
+    object.insert(
+        "message".to_string(),
+        JsonValue::from(record.args().to_string()),
+    );
+    if let Some(domain) = domains::current_domain() {
+        object.insert("domain".to_string(), JsonValue::from(domain));
+    }
+    let _ = record.key_values().visit(&mut Fields(&mut object));
+    write!(w, "{}", JsonValue::Object(object))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_records() {
+        let mut output = Vec::new();
+        let mut now = DeferredNow::new();
+        let fields: &[(&str, Value)] = &[
+            ("ip", Value::from("1.2.3.4")),
+            ("hostname", Value::from("edge-1")),
+            ("action", Value::from("register")),
+            ("netshot_id", Value::from(42u32)),
+        ];
+        json_format(
+            &mut output,
+            &mut now,
+            &Record::builder()
+                .level(log::Level::Info)
+                .target("netbox2netshot")
+                .args(format_args!("Registering \"edge-1\"\nwith IP 1.2.3.4"))
+                .key_values(&fields)
+                .build(),
+        )
+        .unwrap();
+        output.push(b'\n');
+        json_format(
+            &mut output,
+            &mut now,
+            &Record::builder()
+                .level(log::Level::Warn)
+                .target("netbox2netshot::rest::netshot")
+                .args(format_args!("No field"))
+                .build(),
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let records: Vec<JsonValue> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["level"], "INFO");
+        assert_eq!(records[0]["target"], "netbox2netshot");
+        assert_eq!(
+            records[0]["message"],
+            "Registering \"edge-1\"\nwith IP 1.2.3.4"
+        );
+        assert_eq!(records[0]["ip"], "1.2.3.4");
+        assert_eq!(records[0]["hostname"], "edge-1");
+        assert_eq!(records[0]["action"], "register");
+        assert_eq!(records[0]["netshot_id"], 42);
+        assert!(records[0]["timestamp"]
+            .as_str()
+            .unwrap()
+            .parse::<chrono::DateTime<Utc>>()
+            .is_ok());
+        assert_eq!(records[1]["level"], "WARN");
+        assert!(records[1].get("ip").is_none());
+
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("logfmt".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn log_targets() {
+        let writers = |target: &str, stdout_reserved: bool| {
+            let writers = target
+                .parse::<LogTarget>()
+                .unwrap()
+                .writers(stdout_reserved);
+            (writers.file, writers.console)
+        };
+        assert_eq!(writers("both", false), (true, Some(Console::Stdout)));
+        assert_eq!(writers("both", true), (true, Some(Console::Stderr)));
+        assert_eq!(writers("file", false), (true, None));
+        assert_eq!(writers("file", true), (true, None));
+        assert_eq!(writers("stdout", false), (false, Some(Console::Stdout)));
+        // The command output is kept clean
+        assert_eq!(writers("stdout", true), (false, Some(Console::Stderr)));
+        assert_eq!(writers("stderr", false), (false, Some(Console::Stderr)));
+        assert_eq!(writers("stderr", true), (false, Some(Console::Stderr)));
+        assert!("syslog".parse::<LogTarget>().is_err());
+    }
+}
@@ -1,13 +1,34 @@
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{Error, Result};
-use flexi_logger::{Duplicate, FileSpec, Logger};
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Utc};
+use flexi_logger::{DeferredNow, Duplicate, FileSpec, Logger, Record};
+use structopt::clap;
 use structopt::StructOpt;
 
-use rest::{netbox, netshot};
+use rest::helpers::is_unreachable;
+use rest::retry::RetryPolicy;
+use rest::stats::RequestStats;
+use rest::{graphql, netbox, netshot};
 
-mod common;
-mod rest;
+use netbox2netshot::run::{cli, CheckResult};
+use netbox2netshot::{apply, domains, report, rest, run, sync};
+
+#[cfg(feature = "bench")]
+mod bench;
+mod config;
+#[cfg(feature = "export")]
+mod export;
+mod flags;
+mod logging;
+mod profile;
+#[cfg(feature = "self-update")]
+mod update;
 
 #[derive(Debug, StructOpt, Clone)]
 #[structopt(
@@ -18,238 +39,4069 @@ struct Opt {
     #[structopt(short, long, help = "Enable debug/verbose mode")]
     debug: bool,
 
-    #[structopt(long, help = "The directory to log to", default_value = "logs", env)]
+    #[structopt(
+        long,
+        help = "The directory to log to",
+        default_value = "logs",
+        env,
+        parse(try_from_str = cli::expand)
+    )]
     log_directory: String,
 
-    #[structopt(long, help = "The Netshot API URL", env)]
-    netshot_url: String,
+    #[structopt(
+        long,
+        help = "The prefix of the log file names, to tell apart the environments logging to the same directory",
+        env,
+        parse(try_from_str = cli::parse_log_file_prefix)
+    )]
+    log_file_prefix: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The format of the log records, text or json (one object per line)",
+        env,
+        possible_values = &["text", "json"],
+        default_value = "text"
+    )]
+    log_format: logging::LogFormat,
+
+    #[structopt(
+        long,
+        help = "Where to log: both (the --log-directory and the console), file, or stdout or stderr only, leaving the filesystem untouched",
+        env,
+        possible_values = &["both", "file", "stdout", "stderr"],
+        default_value = "both"
+    )]
+    log_target: logging::LogTarget,
 
     #[structopt(
         long,
-        help = "The TLS certificate to use to authenticate to Netshot (PKCS12 format)",
+        help = "Draw progress bars on stderr for the fetches and the changes, unless stderr is not a terminal, the log records are JSON or the console ones go to stderr"
+    )]
+    progress: bool,
+
+    #[structopt(long, help = "The Netshot API URL", env, parse(try_from_str = cli::parse_base_url))]
+    netshot_url: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The TLS certificate to use to authenticate to Netshot (PKCS12 or PEM format)",
         env
     )]
     netshot_tls_client_certificate: Option<String>,
 
-    #[structopt(long, help = "The optional password for the netshot PKCS12 file", env)]
+    #[structopt(
+        long,
+        help = "The optional password for the netshot PKCS12 file or PEM private key",
+        env
+    )]
     netshot_tls_client_certificate_password: Option<String>,
 
+    #[structopt(
+        long,
+        help = "The PEM private key of the --netshot-tls-client-certificate, unless it is in the certificate file",
+        env
+    )]
+    netshot_tls_client_key: Option<String>,
+
     #[structopt(long, help = "The Netshot token", env, hide_env_values = true)]
-    netshot_token: String,
+    netshot_token: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The Netshot user to log in with instead of --netshot-token, with --netshot-password or --netshot-password-file",
+        conflicts_with = "netshot-token",
+        env
+    )]
+    netshot_user: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The password of the --netshot-user",
+        requires = "netshot-user",
+        env,
+        hide_env_values = true
+    )]
+    netshot_password: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The file holding the password of the --netshot-user",
+        conflicts_with = "netshot-password",
+        requires = "netshot-user",
+        env,
+        parse(try_from_str = cli::expand_path)
+    )]
+    netshot_password_file: Option<PathBuf>,
 
     #[structopt(long, help = "The domain ID to use when importing a new device", env)]
-    netshot_domain_id: u32,
+    netshot_domain_id: Option<u32>,
+
+    #[structopt(
+        long,
+        help = "The custom field of the Netbox sites naming the Netshot domain ID of their devices (e.g. netshot_domain), --netshot-domain-id being used when it is empty",
+        env
+    )]
+    netshot_domain_id_from_netbox_custom_field: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The custom field of the Netbox devices holding their management address (e.g. oob_mgmt_ip), preferred over their primary IPs",
+        env
+    )]
+    netbox_ip_custom_field: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Leave out the Netbox devices and VMs with this tag slug (e.g. netshot-ignore), whatever the filters, their Netshot devices being disabled unless --exclude-tag-keeps-netshot",
+        env,
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    netbox_exclude_tag: Vec<String>,
+
+    #[structopt(
+        long,
+        help = "Leave the Netshot devices of the Netbox devices with an excluded tag as they are, rather than disabling them"
+    )]
+    exclude_tag_keeps_netshot: bool,
+
+    #[structopt(
+        long,
+        help = "The Netshot domain of the devices of a Netbox role, as <role>=<domain ID> (role slug, name or ID), repeated or comma separated; the devices of the other roles are skipped and --netshot-domain-id is not used",
+        use_delimiter = true,
+        number_of_values = 1,
+        conflicts_with = "netshot-domain-id-from-netbox-custom-field",
+        env
+    )]
+    role_domain_map: Vec<domains::RoleDomain>,
 
     #[structopt(long, help = "HTTP(s) proxy to use to connect to Netshot", env)]
     netshot_proxy: Option<String>,
 
-    #[structopt(long, help = "The Netbox API URL", env)]
-    netbox_url: String,
+    #[structopt(
+        long,
+        help = "The PEM CA bundle to trust along with the system CAs when connecting to Netshot",
+        env
+    )]
+    netshot_ca_certificate: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Do not verify the TLS certificate of Netshot (dangerous, prefer --netshot-ca-certificate)"
+    )]
+    netshot_insecure: bool,
+
+    #[structopt(
+        long,
+        help = "The Netshot API URL to apply the changes to, instead of --netshot-url",
+        requires = "netshot-apply-token",
+        env,
+        parse(try_from_str = cli::parse_base_url)
+    )]
+    netshot_apply_url: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The Netshot token of the --netshot-apply-url instance",
+        env,
+        hide_env_values = true
+    )]
+    netshot_apply_token: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The TLS certificate to use to authenticate to the --netshot-apply-url instance (PKCS12 or PEM format)",
+        env
+    )]
+    netshot_apply_tls_client_certificate: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The optional password for the --netshot-apply-url instance PKCS12 file or PEM private key",
+        env
+    )]
+    netshot_apply_tls_client_certificate_password: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The PEM private key of the --netshot-apply-tls-client-certificate, unless it is in the certificate file",
+        env
+    )]
+    netshot_apply_tls_client_key: Option<String>,
+
+    #[structopt(long, help = "The Netbox API URL", env, parse(try_from_str = cli::parse_base_url))]
+    netbox_url: Option<String>,
 
     #[structopt(
         long,
-        help = "The TLS certificate to use to authenticate to Netbox (PKCS12 format)",
+        help = "The TLS certificate to use to authenticate to Netbox (PKCS12 or PEM format)",
         env
     )]
     netbox_tls_client_certificate: Option<String>,
 
-    #[structopt(long, help = "The optional password for the netbox PKCS12 file", env)]
+    #[structopt(
+        long,
+        help = "The optional password for the netbox PKCS12 file or PEM private key",
+        env
+    )]
     netbox_tls_client_certificate_password: Option<String>,
 
+    #[structopt(
+        long,
+        help = "The PEM private key of the --netbox-tls-client-certificate, unless it is in the certificate file",
+        env
+    )]
+    netbox_tls_client_key: Option<String>,
+
     #[structopt(long, help = "The Netbox token", env, hide_env_values = true)]
     netbox_token: Option<String>,
 
     #[structopt(
         long,
-        default_value = "",
-        help = "The querystring to use to select the devices from netbox",
+        help = "Access Netbox without a token, only for instances exposing their devices anonymously",
+        conflicts_with = "netbox-token"
+    )]
+    netbox_anonymous: bool,
+
+    #[structopt(
+        long,
+        help = "The querystring to use to select the devices from netbox, repeated to select the devices matching any of them",
+        number_of_values = 1,
+        env
+    )]
+    netbox_devices_filter: Vec<String>,
+
+    #[structopt(
+        long,
+        help = "A file of the filter to select the devices from netbox, one key=value per line with # comments, followed by each --netbox-devices-filter",
+        env,
+        parse(try_from_str = cli::expand_path)
+    )]
+    netbox_devices_filter_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "An IP or CIDR never registered, disabled nor enabled, e.g. the lab devices only on Netshot (repeatable)",
+        number_of_values = 1,
+        use_delimiter = true,
+        env
+    )]
+    exclude_ip: Vec<run::Cidr>,
+
+    #[structopt(
+        long,
+        help = "A file of the IPs or CIDRs never registered, disabled nor enabled, one per line with # comments",
+        env,
+        parse(try_from_str = cli::expand_path)
+    )]
+    exclude_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "The querystring to use to select the VM from netbox, repeated to select the VMs matching any of them",
+        number_of_values = 1,
         env
     )]
-    netbox_devices_filter: String,
+    netbox_vms_filter: Vec<String>,
 
     #[structopt(
         long,
-        help = "The querystring to use to select the VM from netbox",
+        help = "The cluster (name or ID) of the VMs to select from netbox, combined with --netbox-vms-filter",
         env
     )]
-    netbox_vms_filter: Option<String>,
+    netbox_vms_cluster: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Skip the Netbox child devices (installed in a device bay of a chassis), only registering their parent"
+    )]
+    skip_child_devices: bool,
+
+    #[structopt(
+        long,
+        help = "Synchronize every member of the Netbox virtual chassis, rather than only their master (or the member owning the primary IP)"
+    )]
+    keep_virtual_chassis_members: bool,
+
+    #[structopt(
+        long,
+        help = "Check the fetched devices and VMs satisfy the status, role, site and tag conditions of their filter, warning about the ones Netbox appears to have ignored"
+    )]
+    verify_filter: bool,
 
     #[structopt(long, help = "HTTP(s) proxy to use to connect to Netbox", env)]
     netbox_proxy: Option<String>,
 
-    #[structopt(short, long, help = "Check mode, will not push any change to Netshot")]
+    #[structopt(
+        long,
+        help = "The PEM CA bundle to trust along with the system CAs when connecting to Netbox",
+        env
+    )]
+    netbox_ca_certificate: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Do not verify the TLS certificate of Netbox (dangerous, prefer --netbox-ca-certificate)"
+    )]
+    netbox_insecure: bool,
+
+    #[structopt(
+        long,
+        help = "The path (and query string) requested to check Netbox is reachable",
+        default_value = netbox::PATH_PING,
+        env
+    )]
+    netbox_ping_path: String,
+
+    #[structopt(
+        long,
+        help = "Do not check Netbox is reachable before fetching the devices"
+    )]
+    skip_ping: bool,
+
+    #[structopt(
+        long,
+        help = "Abort when the Netbox ping fails, instead of warning and carrying on with the real queries"
+    )]
+    strict_ping: bool,
+
+    #[structopt(
+        long,
+        help = "Pause until the Netbox rate limit resets once fewer requests remain (X-RateLimit-* headers), 0 to disable",
+        default_value = "5",
+        env
+    )]
+    netbox_rate_limit_floor: u32,
+
+    #[structopt(
+        long,
+        help = "The Netbox API the devices and VMs are fetched through: rest, or graphql for far fewer and smaller pages",
+        default_value = "rest",
+        possible_values = &["rest", "graphql"],
+        env
+    )]
+    netbox_api: graphql::NetboxApiMode,
+
+    #[structopt(
+        long,
+        help = "The raw GraphQL arguments filtering the devices with --netbox-api graphql (e.g. `role: \"edge\"`), instead of those built from --netbox-devices-filter",
+        conflicts_with = "netbox-devices-filter",
+        env
+    )]
+    netbox_graphql_filter: Option<String>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Check mode, will not push any change to Netshot nor to Netbox"
+    )]
     check: bool,
-}
 
-/// Main application entrypoint
-fn main() -> Result<(), Error> {
-    let opt: Opt = Opt::from_args();
-    let mut logging_level = "info";
-    let mut duplicate_level = Duplicate::Info;
-    if opt.debug {
-        logging_level = "debug";
-        duplicate_level = Duplicate::Debug;
-    }
+    #[structopt(
+        long,
+        help = "Print the planned registrations, disables and enables on stdout and exit with code 4 when there are any, to detect the drift in check mode",
+        requires = "check"
+    )]
+    fail_on_drift: bool,
 
-    Logger::try_with_str(logging_level)?
-        .log_to_file(FileSpec::default().directory(opt.clone().log_directory))
-        .duplicate_to_stdout(duplicate_level)
-        .start()
-        .unwrap();
+    #[structopt(
+        long,
+        help = "Refuse any request which could change Netshot, the Netshot actions being reported as skipped"
+    )]
+    no_netshot_writes: bool,
 
-    log::info!("Logger initialized with level {}", logging_level);
-    log::debug!("CLI Parameters : {:#?}", opt);
+    #[structopt(long, help = "Refuse any request which could change Netbox")]
+    no_netbox_writes: bool,
 
-    let netbox_client = netbox::NetboxClient::new(
-        opt.netbox_url,
-        opt.netbox_token,
-        opt.netbox_proxy,
-        opt.netbox_tls_client_certificate,
-        opt.netbox_tls_client_certificate_password,
-    )?;
-    netbox_client.ping()?;
-
-    let netshot_client = netshot::NetshotClient::new(
-        opt.netshot_url,
-        opt.netshot_token,
-        opt.netshot_proxy,
-        opt.netshot_tls_client_certificate,
-        opt.netshot_tls_client_certificate_password,
-    )?;
-    netshot_client.ping()?;
-
-    log::info!("Getting devices list from Netshot");
-    let netshot_devices = netshot_client.get_devices(opt.netshot_domain_id)?;
-
-    let netshot_disabled_devices: Vec<&netshot::Device> = netshot_devices
-        .iter()
-        .filter(|dev| &dev.status == "DISABLED")
-        .collect();
-
-    log::debug!("Building netshot devices simplified inventory");
-    let netshot_simplified_inventory: HashMap<&String, &String> = netshot_devices
-        .iter()
-        .map(|dev| (&dev.management_address.ip, &dev.name))
-        .collect();
-
-    log::info!("Getting devices list from Netbox");
-    let mut netbox_devices = netbox_client.get_devices(&opt.netbox_devices_filter)?;
-
-    if opt.netbox_vms_filter.is_some() {
-        log::info!("Getting VMS list rom Netbox");
-        let mut vms = netbox_client.get_vms(&opt.netbox_vms_filter.unwrap())?;
-        log::debug!("Merging VMs and Devices lists");
-        netbox_devices.append(&mut vms);
-    }
-
-    log::debug!("Building netbox devices simplified inventory");
-    let netbox_simplified_devices: HashMap<_, _> = netbox_devices
-        .into_iter()
-        .filter_map(|device| match device.primary_ip4 {
-            Some(x) => Some((
-                x.address.split("/").next().unwrap().to_owned(),
-                device.name.unwrap_or(device.id.to_string()),
-            )),
-            None => {
-                log::warn!(
-                    "Device {} is missing its primary IP address, skipping it",
-                    device.name.unwrap_or(device.id.to_string())
-                );
-                None
-            }
-        })
-        .collect();
-
-    log::debug!(
-        "Simplified inventories: Netbox({}), Netshot({})",
-        netbox_simplified_devices.len(),
-        netshot_simplified_inventory.len()
-    );
-
-    log::debug!("Comparing inventories");
-
-    let mut devices_to_register: Vec<String> = Vec::new();
-    for (ip, hostname) in &netbox_simplified_devices {
-        match netshot_simplified_inventory.get(ip) {
-            Some(x) => log::debug!("{}({}) is present on both", x, ip),
-            None => {
-                log::debug!("{}({}) missing from Netshot", hostname, ip);
-                devices_to_register.push(ip.clone());
-            }
-        }
-    }
+    #[structopt(
+        long,
+        help = "Shadow mode: run as --check with Netshot clients refusing any request which could change Netshot"
+    )]
+    read_only: bool,
 
-    let mut devices_to_disable: Vec<String> = Vec::new();
-    for (ip, hostname) in &netshot_simplified_inventory {
-        match netbox_simplified_devices.get(*ip) {
-            Some(x) => log::debug!("{}({}) is present on both", x, ip),
-            None => {
-                log::debug!("{}({}) to be disabled (missing on Netbox)", hostname, ip);
-                devices_to_disable.push(ip.to_string());
-            }
-        }
-    }
+    #[structopt(
+        long,
+        help = "Skip the devices that cannot be parsed instead of failing"
+    )]
+    skip_bad_records: bool,
 
-    let mut devices_to_enable: Vec<String> = Vec::new();
-    for device in &netshot_disabled_devices {
-        match netbox_simplified_devices.get(device.management_address.ip.as_str()) {
-            Some(_x) => {
-                log::debug!(
-                    "{}({}) to be enabled (present on Netbox)",
-                    device.name,
-                    device.management_address.ip
-                );
-                devices_to_enable.push(device.management_address.ip.clone());
-            }
-            None => {}
-        }
-    }
-
-    log::info!(
-        "Found {} devices missing on Netshot, to be added",
-        devices_to_register.len()
-    );
-    log::info!(
-        "Found {} devices missing on Netbox, to be disabled",
-        devices_to_disable.len()
-    );
-    log::info!(
-        "Found {} devices disabled on Netshot but present on Netbox, to be enabled",
-        devices_to_enable.len()
-    );
-
-    if !opt.check {
-        for device in devices_to_register {
-            let registration = netshot_client.register_device(device, opt.netshot_domain_id);
-            if let Err(error) = registration {
-                log::warn!("Registration failure: {}", error);
-            }
-        }
+    #[structopt(
+        long,
+        help = "Skip the Netbox devices without a name, same as --allow-nameless skip"
+    )]
+    require_name: bool,
 
-        for device in devices_to_disable {
-            let registration = netshot_client.disable_device(device);
-            if let Err(error) = registration {
-                log::warn!("Disable failure: {}", error);
-            }
-        }
-        for device in devices_to_enable {
-            let registration = netshot_client.enable_device(device);
-            if let Err(error) = registration {
-                log::warn!("Enable failure: {}", error);
-            }
-        }
-    }
-    Ok(())
-}
+    #[structopt(
+        long,
+        help = "What to do with the Netbox devices without a name: register them as netbox-id-<id> (default) or skip them",
+        conflicts_with = "require-name",
+        env
+    )]
+    allow_nameless: Option<sync::NamelessPolicy>,
 
-#[cfg(test)]
-mod tests {
-    use flexi_logger::{AdaptiveFormat, Logger};
+    #[structopt(
+        long,
+        help = "Register the devices by the IP of their management-only interface rather than their primary IP, when they have one (two more Netbox queries per 50 devices)"
+    )]
+    prefer_mgmt_interface: bool,
 
-    #[ctor::ctor]
-    fn enable_logging() {
-        Logger::try_with_str("debug")
-            .unwrap()
-            .adaptive_format_for_stderr(AdaptiveFormat::Detailed);
+    #[structopt(long, help = "Allow registering devices by their IPv6 primary address")]
+    allow_ipv6: bool,
+
+    #[structopt(
+        long,
+        help = "Register the dual-stack devices by their IPv6 primary address rather than their IPv4 one, implies --allow-ipv6"
+    )]
+    prefer_ipv6: bool,
+
+    #[structopt(
+        long,
+        help = "Register the devices whose primary IP is a loopback, link-local, unspecified or multicast address"
+    )]
+    allow_special_ips: bool,
+
+    #[structopt(
+        long,
+        help = "The identifier of the run, in the comments of its discovery tasks (generated from the time and PID by default)",
+        env
+    )]
+    run_id: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The comments of the discovery tasks of the registrations, {run_id} being replaced by the run identifier (\"created by netbox2netshot run {run_id}\" by default, empty to send none)",
+        env
+    )]
+    task_comments: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The priority of the discovery tasks of the registrations, left to Netshot by default",
+        env,
+        allow_hyphen_values = true
+    )]
+    task_priority: Option<i32>,
+
+    #[structopt(
+        long,
+        help = "Register the devices through an SNMP discovery of their type and name, or with false create them with the --netshot-driver and their Netbox name",
+        env,
+        default_value = "true",
+        parse(try_from_str)
+    )]
+    netshot_auto_discover: bool,
+
+    #[structopt(
+        long,
+        help = "The Netshot driver of the devices registered with --netshot-auto-discover false (e.g. CiscoIOS12)",
+        env
+    )]
+    netshot_driver: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The ID of the Netshot credential set the devices are registered with",
+        env
+    )]
+    netshot_credentialset_id: Option<u32>,
+
+    #[structopt(
+        long,
+        help = "The name of the Netshot credential set the devices are registered with, looked up on every Netshot instance",
+        env,
+        conflicts_with = "netshot-credentialset-id"
+    )]
+    netshot_credentialset_name: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Defer the registrations of the run when Netshot has more discovery tasks scheduled or running",
+        env
+    )]
+    max_pending_discoveries: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "What to do with the Netshot devices missing from the Netbox inventory: disable or ignore them",
+        default_value = "disable",
+        env
+    )]
+    missing_means: sync::MissingPolicy,
+
+    #[structopt(
+        long,
+        help = "How the Netbox devices are found on Netshot: by management IP, by name (the nameless ones by IP), or by either of them (both), warning when only one matches",
+        default_value = "ip",
+        env
+    )]
+    match_on: sync::MatchOn,
+
+    #[structopt(
+        long,
+        help = "Move the Netshot devices paired by name (unique on both sides) or mapping to the new IP of their Netbox device, rather than registering it again and disabling the former address"
+    )]
+    update_changed_ips: bool,
+
+    #[structopt(
+        long,
+        help = "Rename the Netshot devices whose Netbox device has another name (compared case-insensitively), the Netbox name winning"
+    )]
+    sync_names: bool,
+
+    #[structopt(
+        long,
+        help = "The domain suffix left out when comparing the names with --sync-names, e.g. example.com",
+        requires = "sync-names",
+        env
+    )]
+    strip_name_suffix: Option<String>,
+
+    #[structopt(
+        long,
+        help = "What to do with the stale Netshot devices: disable them, add them to a static group (group:<name>, removed from it when back on Netbox) or purge them",
+        default_value = "disable",
+        env
+    )]
+    stale_action: sync::StaleAction,
+
+    #[structopt(
+        long,
+        help = "Delete the Netshot devices missing from Netbox which have stayed disabled for longer than this number of days, according to their last change on Netshot",
+        env
+    )]
+    purge_disabled_after: Option<u32>,
+
+    #[structopt(long, help = "Create the --stale-action group when it does not exist")]
+    create_groups: bool,
+
+    #[structopt(
+        long,
+        help = "Delete the groups created by netbox2netshot left without members, once the apply fully succeeded"
+    )]
+    prune_empty_groups: bool,
+
+    #[structopt(
+        long,
+        help = "The ID of a static Netshot group the registered devices are added to, once discovered",
+        env
+    )]
+    netshot_group_id: Option<u32>,
+
+    #[structopt(
+        long,
+        help = "Wait for the discovery task of each registration to end, counting the failed ones as failures"
+    )]
+    wait_for_tasks: bool,
+
+    #[structopt(
+        long,
+        help = "How long to wait in seconds for the discovery tasks of the registrations, polled together once all are submitted, with --wait-for-tasks, --netshot-group-id or --new-device-status DISABLED",
+        env,
+        default_value = "300"
+    )]
+    task_timeout: u64,
+
+    #[structopt(
+        long,
+        help = "How the Netbox device statuses count for the disables, e.g. active=present,offline=absent,planned=ignore (absent devices are handled as missing, the other statuses are present, except the offline and paused VMs which are skipped)",
+        env
+    )]
+    status_presence: Option<sync::StatusPresence>,
+
+    #[structopt(
+        long,
+        help = "The Netbox statuses whose devices are disabled on Netshot and never registered, even with --missing-means ignore, e.g. offline,decommissioning (comma separated), unless --status-presence names them",
+        env,
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    netbox_disable_statuses: Vec<String>,
+
+    #[structopt(
+        long,
+        help = "How the Netshot device statuses count, e.g. INVENTORIED=absent,PREPRODUCTION=present (present devices missing from Netbox are disabled, absent ones present on Netbox are enabled, ignored ones are left alone, the other statuses are present except the --netshot-disabled-statuses)",
+        env
+    )]
+    netshot_status_policy: Option<sync::StatusPresence>,
+
+    #[structopt(
+        long,
+        help = "The Netshot statuses counting as disabled (comma separated), the devices present on Netbox with one of them being enabled, unless --netshot-status-policy says otherwise",
+        default_value = "DISABLED",
+        env
+    )]
+    netshot_disabled_statuses: sync::DisabledStatuses,
+
+    #[structopt(
+        long,
+        help = "The status of the registered devices once discovered: INPRODUCTION, or DISABLED to onboard them without monitoring them yet, the --state-file remembering them so they are left disabled until enabled on Netshot",
+        parse(try_from_str = netshot::DeviceStatus::parse_settable),
+        env
+    )]
+    new_device_status: Option<netshot::DeviceStatus>,
+
+    #[structopt(
+        long,
+        help = "Before disabling a device missing from the filtered Netbox inventory, check it has been deleted or decommissioned on Netbox"
+    )]
+    verify_deletions: bool,
+
+    #[structopt(
+        long,
+        help = "Do not verify that the Netshot token can register and update devices before applying changes"
+    )]
+    skip_permission_check: bool,
+
+    #[structopt(
+        long,
+        help = "Print per-endpoint HTTP request statistics at the end of the run (full URLs with --debug)"
+    )]
+    http_stats: bool,
+
+    #[structopt(
+        long,
+        help = "The number of retries of the HTTP requests failing on a connection error, a timeout or a 5xx status",
+        env,
+        default_value = "3"
+    )]
+    http_retries: u32,
+
+    #[structopt(
+        long,
+        help = "The delay in milliseconds before the first retry of an HTTP request, doubled at each retry and jittered",
+        env,
+        default_value = "500"
+    )]
+    http_retry_base_delay_ms: u64,
+
+    #[structopt(
+        long,
+        help = "The number of retries of the HTTP requests answered 429 Too Many Requests in a row, after which the run fails as rate limited",
+        env,
+        default_value = "5"
+    )]
+    http_rate_limit_retries: u32,
+
+    #[structopt(
+        long,
+        help = "The longest wait in seconds before the retry of a rate-limited HTTP request, whatever its Retry-After header asks",
+        env,
+        default_value = "120"
+    )]
+    max_retry_after: u64,
+
+    #[structopt(
+        long,
+        help = "Exit with an error when more than this number of Netbox devices are skipped in a domain",
+        env
+    )]
+    fail_on_skipped: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Exit with an error before any change in any domain when an IP is shared by several Netbox devices or several Netshot devices, in check mode too"
+    )]
+    fail_on_duplicate_ip: bool,
+
+    #[structopt(
+        long,
+        help = "The number (N) or percentage (P%) of failed changes of a domain above which the run exits with an error",
+        default_value = "0",
+        env
+    )]
+    fail_threshold: run::FailThreshold,
+
+    #[structopt(
+        long,
+        help = "The daily time window (HH:MM-HH:MM) outside of which the disables, stale group additions and purges are deferred",
+        env
+    )]
+    disable_window: Option<run::TimeWindow>,
+
+    #[structopt(
+        long,
+        help = "The timezone of the disable window (e.g. Europe/Paris), defaults to the local one",
+        env
+    )]
+    window_timezone: Option<chrono_tz::Tz>,
+
+    #[structopt(
+        long,
+        help = "The maximum number of changes to apply per run, over all the domains, the others being deferred to the next runs",
+        env
+    )]
+    changes_budget: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "The order in which the action categories of every domain consume the changes budget",
+        default_value = "register,enable,disable,update_credentials,fix_prefix_length,update_address,rename,update_attributes,add_to_group,remove_from_group,purge,purge_disabled",
+        env
+    )]
+    budget_order: run::BudgetOrder,
+
+    #[structopt(
+        long,
+        help = "The specific credential set IDs (comma separated) the Netshot devices present on Netbox must use, updated when they differ",
+        use_delimiter = true,
+        env
+    )]
+    ensure_credential_set_ids: Vec<u32>,
+
+    #[structopt(
+        long,
+        help = "The Netbox fields (comma separated: site, rack, serial, asset-tag, url) written into the comments of the Netshot devices present on Netbox, updated when they differ",
+        use_delimiter = true,
+        env
+    )]
+    sync_attributes: Vec<run::Attribute>,
+
+    #[structopt(
+        long,
+        help = "Update the prefix length of the Netshot management addresses differing from the Netbox primary IP one"
+    )]
+    fix_prefix_length: bool,
+
+    #[structopt(
+        long,
+        help = "The number of Netshot domains to synchronize at once",
+        default_value = "1",
+        env
+    )]
+    domain_concurrency: usize,
+
+    #[structopt(
+        long,
+        help = "The number of changes applied to Netshot at once within a domain, and of --sync-attributes comment lookups",
+        default_value = "4",
+        env
+    )]
+    parallelism: usize,
+
+    #[structopt(
+        long,
+        help = "Match the Netbox devices missing on Netshot against the interface addresses of the Netshot devices"
+    )]
+    match_any_netshot_address: bool,
+
+    #[structopt(
+        long,
+        help = "The expected number of Netbox devices (N, N±P%, N±M or MIN..MAX), the run is aborted outside of it",
+        env
+    )]
+    expect_netbox_count: Option<run::CountExpectation>,
+
+    #[structopt(
+        long,
+        help = "The expected number of Netshot devices (N, N±P%, N±M or MIN..MAX), the run is aborted outside of it",
+        env
+    )]
+    expect_netshot_count: Option<run::CountExpectation>,
+
+    #[structopt(
+        long,
+        help = "The most registrations, enables and disables (or other stale actions) the domains may plan together, the run being blocked before any write above it",
+        env
+    )]
+    max_changes: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "The largest share of the Netshot devices of the domains which may be disabled (or go through the other stale actions) together, the run being blocked before any write above it",
+        env,
+        parse(try_from_str = cli::parse_percentage)
+    )]
+    max_disable_percent: Option<f64>,
+
+    #[structopt(
+        long,
+        help = "Warn when a TLS client certificate expires within this number of days",
+        default_value = "14",
+        env
+    )]
+    cert_expiry_warning_days: i64,
+
+    #[structopt(
+        long,
+        help = "Look up the Netshot devices to disable/enable missing from the fetched inventory by batches of this size instead of one by one",
+        env
+    )]
+    batch_size: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "The timeout in seconds of each register/disable/enable call to Netshot",
+        env
+    )]
+    per_action_timeout: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "The timeout in seconds of each request to Netbox, 0 for none",
+        env,
+        default_value = "5"
+    )]
+    netbox_timeout: u64,
+
+    #[structopt(
+        long,
+        help = "The timeout in seconds of each request to Netshot, 0 for none",
+        env,
+        default_value = "5"
+    )]
+    netshot_timeout: u64,
+
+    #[structopt(
+        long,
+        help = "The number of records requested per page from the Netshot devices and other listings",
+        env,
+        default_value = "100"
+    )]
+    netshot_page_size: u32,
+
+    #[structopt(
+        long,
+        help = "Write a report of the planned and applied changes, recorded incrementally to <report>.partial.jsonl while applying",
+        env,
+        visible_alias = "report-file",
+        parse(try_from_str = cli::expand_path)
+    )]
+    report: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "The format of the --report (json or yaml)",
+        env,
+        default_value = "json"
+    )]
+    report_format: report::ReportFormat,
+
+    #[cfg(feature = "csv-report")]
+    #[structopt(
+        long,
+        help = "Write the actions as CSV, one row per device: planned in check mode, applied otherwise. The file of the previous run is replaced.",
+        env,
+        parse(try_from_str = cli::expand_path)
+    )]
+    csv_report: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Write the fetched Netbox and Netshot devices as JSON to netbox-devices.json and netshot-devices.json in this directory, to replay the run with --netbox-from-file and --netshot-from-file",
+        env,
+        parse(try_from_str = cli::expand_path)
+    )]
+    dump_inventories: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Load the Netbox devices from a --dump-inventories file instead of fetching them, in check mode only",
+        env,
+        requires = "check",
+        parse(try_from_str = cli::expand_path)
+    )]
+    netbox_from_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Load the Netshot devices of the domains from a --dump-inventories file instead of fetching them, in check mode only",
+        env,
+        requires = "check",
+        parse(try_from_str = cli::expand_path)
+    )]
+    netshot_from_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "The file persisting the Netbox to Netshot device IDs mapping across runs, matched before the IPs",
+        env,
+        parse(try_from_str = cli::expand_path)
+    )]
+    mapping_file: Option<PathBuf>,
+
+    #[cfg(feature = "metrics")]
+    #[structopt(
+        long,
+        help = "Write the metrics of the run (device counts, planned changes, failures) to this file for the textfile collector of the Prometheus node exporter, in check mode too",
+        env,
+        parse(try_from_str = cli::expand_path)
+    )]
+    metrics_textfile: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "The file recording the state of the runs, e.g. when the last successful run ended and the actions which keep failing",
+        env,
+        parse(try_from_str = cli::expand_path)
+    )]
+    state_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "The minimum number of seconds since the last successful run, a run started sooner exits with code 75",
+        requires = "state-file",
+        env
+    )]
+    min_run_interval: Option<i64>,
+
+    #[structopt(long, help = "Run even when --min-run-interval is not elapsed")]
+    force: bool,
+
+    #[structopt(
+        long,
+        help = "The number of consecutive failures of an action on a device, recorded in the --state-file, above which it is reported as chronic",
+        default_value = "3",
+        env
+    )]
+    max_consecutive_failures: u32,
+
+    #[structopt(
+        long,
+        help = "The number of runs, recorded in the --state-file, after which a registered device still missing on Netshot is reported as not converging",
+        default_value = "2",
+        env
+    )]
+    registration_grace: u32,
+
+    #[structopt(
+        long,
+        help = "Query the discovery task of the registrations not converging, to report why it failed"
+    )]
+    recheck_stalled_registrations: bool,
+
+    #[structopt(
+        long,
+        help = "Register again the devices whose previous discovery failed on a missing driver or on the credentials, held back otherwise"
+    )]
+    retry_permanent_failures: bool,
+
+    #[structopt(
+        long,
+        help = "Print every option with its effective value and where it comes from, then exit"
+    )]
+    print_effective_config: bool,
+
+    #[structopt(
+        long,
+        help = "The configuration file (TOML, or YAML when named *.yaml or *.yml) of the options, and of the option bundles selected with --profile",
+        env = "NETBOX2NETSHOT_CONFIG"
+    )]
+    config: Option<String>,
+
+    #[structopt(
+        long,
+        help = "The profile of the --config file to take the options from, the command line and the environment overriding it",
+        env = "NETBOX2NETSHOT_PROFILE"
+    )]
+    profile: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Refuse the deprecated flags and environment variables rather than warning about them"
+    )]
+    strict_flags: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+enum Command {
+    #[cfg(feature = "bench")]
+    #[structopt(
+        about = "Time the planning of synthetic inventories, without any network access",
+        setting = clap::AppSettings::Hidden
+    )]
+    Bench(bench::BenchOpt),
+
+    #[cfg(feature = "export")]
+    #[structopt(about = "Commands working on Netshot only")]
+    Netshot(NetshotCommand),
+
+    #[structopt(
+        about = "Run the read-only validations of a first synchronization and report pass/warn/fail per check"
+    )]
+    Preflight(PreflightOpt),
+
+    #[structopt(about = "Print the JSON Schema of the --report file")]
+    ReportSchema,
+
+    #[cfg(feature = "self-update")]
+    #[structopt(
+        about = "Update the executable to the latest release, through --netbox-proxy or --netshot-proxy if set"
+    )]
+    SelfUpdate(update::UpdateOpt),
+}
+
+/// The options of the preflight checks
+#[derive(Debug, StructOpt, Clone)]
+struct PreflightOpt {
+    #[structopt(long, help = "Print the checks as JSON rather than as a table")]
+    json: bool,
+}
+
+#[cfg(feature = "export")]
+#[derive(Debug, StructOpt, Clone)]
+enum NetshotCommand {
+    #[structopt(about = "Export the Netshot inventory (filtered by --netshot-domain-id if given)")]
+    Export {
+        #[structopt(long, help = "The export format (csv or json)", default_value = "csv")]
+        output: export::ExportFormat,
+    },
+}
+
+/// The exit code of a run started before --min-run-interval is elapsed, EX_TEMPFAIL from sysexits.h
+const EXIT_TOO_SOON: i32 = 75;
+
+/// The exit code of a run which could not reach Netbox or Netshot, EX_UNAVAILABLE from sysexits.h
+const EXIT_UNAVAILABLE: i32 = 69;
+
+/// The exit code of a run which failed to apply some of its changes
+const EXIT_PARTIAL_FAILURE: i32 = 2;
+
+/// The exit code of a run blocked by the change limits, before any write
+const EXIT_CHANGES_BLOCKED: i32 = 3;
+
+/// The exit code of a --fail-on-drift check run which planned changes
+const EXIT_DRIFT: i32 = 4;
+
+/// The exit code of a failed run, telling the unreachable APIs apart from the partial failures
+fn exit_code(error: &Error) -> i32 {
+    if error.downcast_ref::<run::PartialFailure>().is_some() {
+        EXIT_PARTIAL_FAILURE
+    } else if error.downcast_ref::<run::ChangeLimitExceeded>().is_some() {
+        EXIT_CHANGES_BLOCKED
+    } else if error.downcast_ref::<run::DriftDetected>().is_some() {
+        EXIT_DRIFT
+    } else if is_unreachable(error) {
+        EXIT_UNAVAILABLE
+    } else {
+        1
+    }
+}
+
+/// Get the value of an option required by the synchronization, or exit with the usual CLI error
+fn required<T: Clone>(value: &Option<T>, flag: &str) -> T {
+    match value {
+        Some(value) => value.clone(),
+        None => clap::Error::with_description(
+            &format!(
+                "The following required arguments were not provided:\n    --{}",
+                flag
+            ),
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    }
+}
+
+/// Log format prefixing the lines emitted while synchronizing a domain with that domain
+fn log_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    if let Some(domain) = domains::current_domain() {
+        write!(w, "[domain {}] ", domain)?;
+    }
+    flexi_logger::default_format(w, now, record)
+}
+
+/// The plan options resolving the management IP of the Netbox devices, shared by the exclusions and the plan
+fn address_options(opt: &Opt) -> sync::PlanOptions {
+    let mut options = sync::PlanOptions::default();
+    options.nameless = match opt.allow_nameless {
+        _ if opt.require_name => sync::NamelessPolicy::Skip,
+        Some(policy) => policy,
+        None => sync::NamelessPolicy::Register,
+    };
+    options.allow_ipv6 = opt.allow_ipv6 || opt.prefer_ipv6;
+    options.prefer_ipv6 = opt.prefer_ipv6;
+    options.allow_special_ips = opt.allow_special_ips;
+    options.ip_custom_field = opt.netbox_ip_custom_field.clone();
+    options
+}
+
+/// Refuse a --netbox-graphql-filter the REST API would silently ignore
+fn check_graphql_filter(opt: &Opt) -> Result<(), clap::Error> {
+    match (&opt.netbox_graphql_filter, opt.netbox_api) {
+        (Some(_), graphql::NetboxApiMode::Rest) => Err(clap::Error::with_description(
+            "--netbox-graphql-filter requires --netbox-api graphql",
+            clap::ErrorKind::MissingRequiredArgument,
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Exit with the usual CLI error when Netbox has neither a token nor anonymous access
+fn require_netbox_auth(opt: &Opt) {
+    if opt.netbox_token.is_none() && !opt.netbox_anonymous {
+        clap::Error::with_description(
+            "Either --netbox-token or --netbox-anonymous must be provided",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+}
+
+/// Exit with the usual CLI error unless Netshot has either a token or a user with a password
+fn require_netshot_auth(opt: &Opt) {
+    let error = match &opt.netshot_user {
+        None if opt.netshot_token.is_none() => {
+            "Either --netshot-token or --netshot-user must be provided"
+        }
+        Some(_) if opt.netshot_password.is_none() && opt.netshot_password_file.is_none() => {
+            "--netshot-user requires --netshot-password or --netshot-password-file"
+        }
+        _ => return,
+    };
+    clap::Error::with_description(error, clap::ErrorKind::MissingRequiredArgument).exit();
+}
+
+/// The user and password to log in to Netshot with, when not authenticating with a token
+fn netshot_login(opt: &Opt) -> Result<Option<netshot::Login>, Error> {
+    let user = match &opt.netshot_user {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let password = match (&opt.netshot_password, &opt.netshot_password_file) {
+        (_, Some(path)) => std::fs::read_to_string(path)
+            .map_err(|error| {
+                anyhow!(
+                    "Unable to read the Netshot password file {}: {}",
+                    path.display(),
+                    error
+                )
+            })?
+            .trim_end_matches(&['\r', '\n'][..])
+            .to_string(),
+        (password, None) => password.clone().unwrap_or_default(),
+    };
+    Ok(Some(netshot::Login::new(user.clone(), password)))
+}
+
+/// Initialize the logging to the --log-target, the console records going to stderr when stdout carries the command output
+fn init_logger(opt: &Opt, stdout_reserved: bool) -> Result<(), Error> {
+    let mut logging_level = "info";
+    let mut duplicate_level = Duplicate::Info;
+    if opt.debug {
+        logging_level = "debug";
+        duplicate_level = Duplicate::Debug;
+    }
+
+    let writers = opt.log_target.writers(stdout_reserved);
+    let logger = Logger::try_with_str(logging_level)?.format(match opt.log_format {
+        logging::LogFormat::Text => log_format,
+        logging::LogFormat::Json => logging::json_format,
+    });
+    // Without log file, the log directory is neither created nor written to
+    let logger = match (writers.file, writers.console) {
+        (true, console) => {
+            let mut file_spec = FileSpec::default().directory(&opt.log_directory);
+            if let Some(prefix) = &opt.log_file_prefix {
+                file_spec = file_spec.basename(prefix);
+            }
+            let logger = logger.log_to_file(file_spec);
+            match console {
+                Some(logging::Console::Stdout) => logger.duplicate_to_stdout(duplicate_level),
+                Some(logging::Console::Stderr) => logger.duplicate_to_stderr(duplicate_level),
+                None => logger,
+            }
+        }
+        (false, Some(logging::Console::Stdout)) => logger.log_to_stdout(),
+        (false, _) => logger.log_to_stderr(),
+    };
+    logger.start().unwrap();
+    // The bars would be mixed with the records
+    if opt.progress
+        && opt.log_format == logging::LogFormat::Text
+        && writers.console != Some(logging::Console::Stderr)
+    {
+        cli::enable_progress();
+    }
+
+    match &opt.run_id {
+        Some(run_id) => log::info!(
+            "Logger initialized with level {} for run {}",
+            logging_level,
+            run_id
+        ),
+        None => log::info!("Logger initialized with level {}", logging_level),
+    }
+    for deprecated in deprecated_uses() {
+        log::warn!("{}", deprecated);
+    }
+    log::debug!("CLI Parameters : {:#?}", opt);
+    Ok(())
+}
+
+/// Warn when the TLS client certificate of a client is about to expire
+fn warn_certificate_expiry(opt: &Opt, service: &str, expires_at: Option<DateTime<Utc>>) {
+    if let Some(expires_at) = expires_at {
+        let remaining = expires_at - Utc::now();
+        if remaining < chrono::Duration::days(opt.cert_expiry_warning_days) {
+            log::warn!(
+                "The {} TLS client certificate expires in {} days, on {}",
+                service,
+                remaining.num_days(),
+                expires_at
+            );
+        }
+    }
+}
+
+/// Create the HTTP request statistics shared by the clients, when requested
+fn build_http_stats(opt: &Opt) -> Option<Arc<RequestStats>> {
+    if opt.http_stats {
+        Some(Arc::new(RequestStats::new(opt.debug)))
+    } else {
+        None
+    }
+}
+
+/// The timeout of the requests given in seconds, 0 meaning none
+fn request_timeout(seconds: u64) -> Option<Duration> {
+    Some(Duration::from_secs(seconds)).filter(|timeout| !timeout.is_zero())
+}
+
+/// How the HTTP requests failing transiently are retried
+fn retry_policy(opt: &Opt) -> RetryPolicy {
+    RetryPolicy::new(
+        opt.http_retries,
+        Duration::from_millis(opt.http_retry_base_delay_ms),
+    )
+    .rate_limited(
+        opt.http_rate_limit_retries,
+        Duration::from_secs(opt.max_retry_after),
+    )
+}
+
+/// Create the Netbox client, without checking the connection
+fn build_netbox_client(
+    opt: &Opt,
+    netbox_url: String,
+    http_stats: Option<Arc<RequestStats>>,
+) -> Result<netbox::NetboxClient, Error> {
+    // An explicit --netbox-anonymous wins over a token inherited from the environment
+    let netbox_token = if opt.netbox_anonymous {
+        None
+    } else {
+        opt.netbox_token.clone()
+    };
+    let netbox_client = netbox::NetboxClient::builder(netbox_url)
+        .token(netbox_token)
+        .proxy(opt.netbox_proxy.clone())
+        .tls_client_certificate(
+            opt.netbox_tls_client_certificate.clone(),
+            opt.netbox_tls_client_certificate_password.clone(),
+        )
+        .tls_client_key(opt.netbox_tls_client_key.clone())
+        .ca_certificate(opt.netbox_ca_certificate.clone())
+        .insecure(opt.netbox_insecure)
+        .skip_bad_records(opt.skip_bad_records)
+        .stats(http_stats)
+        .retry(retry_policy(opt))
+        .timeout(request_timeout(opt.netbox_timeout))
+        .rate_limit_floor(opt.netbox_rate_limit_floor)
+        .ping_path(opt.netbox_ping_path.clone())
+        .read_only(opt.check || opt.no_netbox_writes)
+        .api(opt.netbox_api)
+        .graphql_filter(opt.netbox_graphql_filter.clone())
+        .build()?;
+    warn_certificate_expiry(opt, "Netbox", netbox_client.certificate_expiry);
+    Ok(netbox_client)
+}
+
+/// Create the Netshot client used to fetch the inventory
+fn build_netshot_client(
+    opt: &Opt,
+    netshot_url: String,
+    http_stats: Option<Arc<RequestStats>>,
+) -> Result<netshot::NetshotClient, Error> {
+    let mut netshot_client = netshot::NetshotClient::builder(netshot_url)
+        .token(opt.netshot_token.clone().unwrap_or_default())
+        .login(netshot_login(opt)?)
+        .proxy(opt.netshot_proxy.clone())
+        .tls_client_certificate(
+            opt.netshot_tls_client_certificate.clone(),
+            opt.netshot_tls_client_certificate_password.clone(),
+        )
+        .tls_client_key(opt.netshot_tls_client_key.clone())
+        .ca_certificate(opt.netshot_ca_certificate.clone())
+        .insecure(opt.netshot_insecure)
+        .skip_bad_records(opt.skip_bad_records)
+        .action_timeout(opt.per_action_timeout.map(Duration::from_secs))
+        .registration_task(task_comments(opt), opt.task_priority)
+        .driver(netshot_driver(opt))
+        .credential_set_id(opt.netshot_credentialset_id)
+        .stats(http_stats)
+        .retry(retry_policy(opt))
+        .timeout(request_timeout(opt.netshot_timeout))
+        .page_size(opt.netshot_page_size)
+        .read_only(opt.check || opt.no_netshot_writes)
+        .build()?;
+    warn_certificate_expiry(opt, "Netshot", netshot_client.certificate_expiry);
+    // The inventory loaded from a file, Netshot is only queried for the details some options need
+    if opt.netshot_from_file.is_none() {
+        ping_netshot(&netshot_client)?;
+        netshot_client.detect_version()?;
+    }
+    if let Some(name) = &opt.netshot_credentialset_name {
+        netshot_client.resolve_credential_set(name)?;
+    }
+    Ok(netshot_client)
+}
+
+/// Abort before fetching anything when Netshot cannot be reached or refuses the token
+fn ping_netshot(netshot_client: &netshot::NetshotClient) -> Result<(), Error> {
+    match netshot_client.ping()? {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "Netshot ping on {} got an error status",
+            netshot_client.url
+        )),
+    }
+}
+
+/// Create the Netshot client the changes are applied to, when --netshot-apply-url is given
+fn build_netshot_apply_client(
+    opt: &Opt,
+    http_stats: Option<Arc<RequestStats>>,
+) -> Result<Option<netshot::NetshotClient>, Error> {
+    let url = match opt.netshot_apply_url.clone() {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    log::info!("Changes will be applied to the Netshot instance at {}", url);
+    let mut client = netshot::NetshotClient::builder(url)
+        .token(opt.netshot_apply_token.clone().unwrap_or_default())
+        .proxy(opt.netshot_proxy.clone())
+        .tls_client_certificate(
+            opt.netshot_apply_tls_client_certificate.clone(),
+            opt.netshot_apply_tls_client_certificate_password.clone(),
+        )
+        .tls_client_key(opt.netshot_apply_tls_client_key.clone())
+        .ca_certificate(opt.netshot_ca_certificate.clone())
+        .insecure(opt.netshot_insecure)
+        .action_timeout(opt.per_action_timeout.map(Duration::from_secs))
+        .registration_task(task_comments(opt), opt.task_priority)
+        .driver(netshot_driver(opt))
+        .credential_set_id(opt.netshot_credentialset_id)
+        .stats(http_stats)
+        .retry(retry_policy(opt))
+        .timeout(request_timeout(opt.netshot_timeout))
+        .read_only(opt.check || opt.no_netshot_writes)
+        .build()?;
+    warn_certificate_expiry(opt, "Netshot apply", client.certificate_expiry);
+    ping_netshot(&client)?;
+    client.detect_version()?;
+    if let Some(name) = &opt.netshot_credentialset_name {
+        client.resolve_credential_set(name)?;
+    }
+    Ok(Some(client))
+}
+
+/// The driver of the devices registered without auto-discovery
+fn netshot_driver(opt: &Opt) -> Option<String> {
+    opt.netshot_driver
+        .clone()
+        .filter(|_| !opt.netshot_auto_discover)
+}
+
+/// The comments of the discovery tasks of the registrations, none when configured empty
+fn task_comments(opt: &Opt) -> Option<String> {
+    let run_id = opt.run_id.as_deref().unwrap_or_default();
+    match &opt.task_comments {
+        Some(comments) if comments.is_empty() => None,
+        Some(comments) => Some(comments.replace("{run_id}", run_id)),
+        None => Some(format!("created by netbox2netshot run {}", run_id)),
+    }
+}
+
+/// How the domains are planned, guarded and applied
+fn run_options(opt: &Opt) -> run::RunOptions {
+    let mut options = run::RunOptions::default();
+    options.run_id = opt.run_id.clone().unwrap_or_default();
+    options.check = opt.check;
+    options.read_only = opt.read_only;
+    options.no_netshot_writes = opt.no_netshot_writes;
+    options.netbox_url = opt.netbox_url.clone().unwrap_or_default();
+    options.netbox_devices_filter = opt.netbox_devices_filter.clone();
+    options.graphql_filter = opt.netbox_graphql_filter.is_some();
+    options.netbox_vms_filter = opt.netbox_vms_filter.clone();
+    options.netbox_vms_cluster = opt.netbox_vms_cluster.clone();
+    options.verify_filter = opt.verify_filter;
+    options.prefer_mgmt_interface = opt.prefer_mgmt_interface;
+    options.netbox_from_file = opt.netbox_from_file.clone();
+    options.netshot_from_file = opt.netshot_from_file.clone();
+    options.dump_inventories = opt.dump_inventories.clone();
+    options.netshot_domain_id = opt.netshot_domain_id.unwrap_or_default();
+    options.site_domain_field = opt.netshot_domain_id_from_netbox_custom_field.clone();
+    options.role_domain_map = opt.role_domain_map.clone();
+    options.exclude_ip = opt.exclude_ip.clone();
+    options.expect_netbox_count = opt.expect_netbox_count.clone();
+    options.expect_netshot_count = opt.expect_netshot_count.clone();
+    options.plan = plan_options(opt);
+    options.match_any_netshot_address = opt.match_any_netshot_address;
+    options.verify_deletions = opt.verify_deletions;
+    options.purge_disabled_after = opt.purge_disabled_after;
+    options.sync_attributes = opt.sync_attributes.clone();
+    options.stale_action = opt.stale_action.clone();
+    options.prune_empty_groups = opt.prune_empty_groups;
+    options.disable_window = opt.disable_window;
+    options.window_timezone = opt.window_timezone;
+    options.retry_permanent_failures = opt.retry_permanent_failures;
+    options.max_pending_discoveries = opt.max_pending_discoveries;
+    options.fail_on_duplicate_ip = opt.fail_on_duplicate_ip;
+    options.change_limits = run::ChangeLimits {
+        max_changes: opt.max_changes,
+        max_disable_percent: opt.max_disable_percent,
+    };
+    options.changes_budget = opt.changes_budget;
+    options.budget_order = opt.budget_order.clone();
+    options.domain_concurrency = opt.domain_concurrency;
+    options.apply = apply_options(opt);
+    options.apply_to_other_netshot = opt.netshot_apply_url.is_some();
+    options.fail_threshold = opt.fail_threshold;
+    options.fail_on_skipped = opt.fail_on_skipped;
+    options.fail_on_drift = opt.fail_on_drift;
+    options.report = opt.report.clone();
+    options.report_format = opt.report_format;
+    #[cfg(feature = "csv-report")]
+    {
+        options.csv_report = opt.csv_report.clone();
+    }
+    #[cfg(feature = "metrics")]
+    {
+        options.metrics_textfile = opt.metrics_textfile.clone();
+    }
+    options.mapping_file = opt.mapping_file.clone();
+    options.state_file = opt.state_file.clone();
+    options.min_run_interval = opt.min_run_interval;
+    options.force = opt.force;
+    options.max_consecutive_failures = opt.max_consecutive_failures;
+    options.registration_grace = opt.registration_grace;
+    options.recheck_stalled_registrations = opt.recheck_stalled_registrations;
+    options
+}
+
+/// How the plan is computed, on top of the address options
+fn plan_options(opt: &Opt) -> sync::PlanOptions {
+    let mut options = address_options(opt);
+    options.missing = opt.missing_means;
+    options.status_presence = opt.status_presence.clone().unwrap_or_default();
+    options.netbox_disable_statuses = opt.netbox_disable_statuses.clone();
+    options.fix_prefix_length = opt.fix_prefix_length;
+    options.skip_child_devices = opt.skip_child_devices;
+    options.keep_virtual_chassis_members = opt.keep_virtual_chassis_members;
+    options.netshot_status_policy = opt.netshot_status_policy.clone().unwrap_or_default();
+    options.netshot_disabled_statuses = opt.netshot_disabled_statuses.clone();
+    options.match_on = opt.match_on;
+    options.exclude_tags = opt.netbox_exclude_tag.clone();
+    options.exclude_tag_keeps_netshot = opt.exclude_tag_keeps_netshot;
+    options.update_changed_ips = opt.update_changed_ips;
+    options.sync_names = opt.sync_names;
+    options.name_suffix = opt.strip_name_suffix.clone();
+    options
+}
+
+/// How the changes are applied
+fn apply_options(opt: &Opt) -> apply::ApplyOptions {
+    let mut options = apply::ApplyOptions::default();
+    options.parallelism = opt.parallelism;
+    options.batch_size = opt.batch_size;
+    options.wait_for_tasks = opt.wait_for_tasks;
+    options.task_timeout = Duration::from_secs(opt.task_timeout);
+    options.group_id = opt.netshot_group_id;
+    options.disable_registered = opt.new_device_status == Some(netshot::DeviceStatus::Disabled);
+    options.credential_set_ids = opt.ensure_credential_set_ids.clone();
+    options.stale_group = opt.stale_action.group_name().map(str::to_string);
+    options.create_stale_group = opt.create_groups;
+    options
+}
+
+/// Export the Netshot inventory to stdout
+#[cfg(feature = "export")]
+fn export_netshot(opt: &Opt, format: export::ExportFormat) -> Result<(), Error> {
+    let netshot_url = required(&opt.netshot_url, "netshot-url");
+    require_netshot_auth(opt);
+    init_logger(opt, true)?;
+
+    let http_stats = build_http_stats(opt);
+    let netshot_client = build_netshot_client(opt, netshot_url, http_stats.clone())?;
+    log::info!("Exporting devices list from Netshot");
+    let count = export::export_netshot_inventory(
+        &netshot_client,
+        opt.netshot_domain_id,
+        format,
+        std::io::stdout().lock(),
+    )?;
+    log::info!("Exported {} devices", count);
+    if let Some(http_stats) = http_stats {
+        http_stats.log_summary();
+    }
+    Ok(())
+}
+
+/// Run the read-only validations of a first synchronization, the plan computed as in check mode
+fn preflight_checks(opt: &Opt) -> Vec<CheckResult> {
+    // The clients refuse the writes in check mode
+    let mut opt = opt.clone();
+    opt.check = true;
+    opt.netshot_domain_id = Some(required(&opt.netshot_domain_id, "netshot-domain-id"));
+    let options = run_options(&opt);
+    let mut checks = Vec::new();
+
+    let netbox_client =
+        match build_netbox_client(&opt, required(&opt.netbox_url, "netbox-url"), None) {
+            Ok(client) => client,
+            Err(error) => {
+                checks.push(CheckResult::fail("netbox-client", format!("{:#}", error)));
+                return checks;
+            }
+        };
+    if let Some(expires_at) = netbox_client.certificate_expiry {
+        checks.push(cli::certificate_expiry(
+            "netbox-certificate",
+            expires_at,
+            opt.cert_expiry_warning_days,
+            Utc::now(),
+        ));
+    }
+    let netbox_access = if opt.netbox_anonymous {
+        netbox_client.check_anonymous_access(options.devices_filters()[0])
+    } else {
+        match netbox_client.ping() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(anyhow!(
+                "Netbox ping on {} got an error status",
+                opt.netbox_ping_path
+            )),
+            Err(error) => Err(error),
+        }
+    };
+    checks.push(CheckResult::from_result(
+        "netbox-access",
+        netbox_access,
+        "Netbox is reachable",
+    ));
+
+    let netshot_client =
+        match build_netshot_client(&opt, required(&opt.netshot_url, "netshot-url"), None) {
+            Ok(client) => client,
+            Err(error) => {
+                checks.push(CheckResult::fail("netshot-access", format!("{:#}", error)));
+                return checks;
+            }
+        };
+    checks.push(CheckResult::pass("netshot-access", "Netshot is reachable"));
+    checks.push(cli::netshot_version(
+        "netshot-version",
+        netshot_client.version,
+    ));
+    if let Some(expires_at) = netshot_client.certificate_expiry {
+        checks.push(cli::certificate_expiry(
+            "netshot-certificate",
+            expires_at,
+            opt.cert_expiry_warning_days,
+            Utc::now(),
+        ));
+    }
+    match build_netshot_apply_client(&opt, None) {
+        Ok(apply_client) => {
+            if let Some(apply_client) = &apply_client {
+                checks.push(cli::netshot_version(
+                    "netshot-apply-version",
+                    apply_client.version,
+                ));
+            }
+            let apply_client = apply_client.as_ref().unwrap_or(&netshot_client);
+            checks.push(CheckResult::from_result(
+                "netshot-permissions",
+                apply_client.check_write_permission(),
+                "The Netshot token can apply the changes",
+            ));
+        }
+        Err(error) => checks.push(CheckResult::fail(
+            "netshot-permissions",
+            format!("{:#}", error),
+        )),
+    }
+
+    checks.append(&mut run::preflight(
+        &options,
+        &netbox_client,
+        &netshot_client,
+    ));
+    checks
+}
+
+/// Print the preflight checks and exit with the worst severity
+fn run_preflight(opt: &Opt, preflight_opt: &PreflightOpt) -> Result<(), Error> {
+    require_netbox_auth(opt);
+    require_netshot_auth(opt);
+    init_logger(opt, true)?;
+
+    let checks = preflight_checks(opt);
+    if preflight_opt.json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        cli::print_table(&checks);
+    }
+    let severity = cli::worst_severity(&checks);
+    log::info!("Preflight checks done, worst severity {}", severity);
+    log::logger().flush();
+    std::process::exit(severity.exit_code());
+}
+
+/// The deprecated flags and environment variables the program was started with
+fn deprecated_uses() -> Vec<flags::DeprecatedUse> {
+    flags::deprecated_uses(flags::RENAMED_FLAGS, std::env::args_os(), |name| {
+        std::env::var_os(name).is_some()
+    })
+}
+
+/// Insert the options of the --config file, and of its --profile if any, which are not given otherwise
+fn apply_config(
+    args: Vec<OsString>,
+    profile: Option<&str>,
+) -> Result<(Vec<OsString>, HashMap<String, profile::OptionSource>), Error> {
+    let config =
+        profile::arg_value(&args, "config").or_else(|| std::env::var("NETBOX2NETSHOT_CONFIG").ok());
+    let config = match (config, profile) {
+        (Some(config), _) => config,
+        (None, Some(profile)) => {
+            return Err(anyhow!("--profile {} requires a --config file", profile))
+        }
+        (None, None) => return Ok((args, HashMap::new())),
+    };
+    let options = profile::ConfigFile::load(&cli::expand(&config)?)?.resolve(profile)?;
+    profile::apply_profile(&Opt::clap(), args, &options, |env| {
+        std::env::var_os(env).is_some()
+    })
+}
+
+/// Main application entrypoint
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("Error: {:?}", error);
+        std::process::exit(exit_code(&error));
+    }
+}
+
+/// Run the command line, its error deciding the exit code
+fn run() -> Result<(), Error> {
+    flags::migrate_env(flags::RENAMED_FLAGS);
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let profile = profile::arg_value(&args, "profile")
+        .or_else(|| std::env::var("NETBOX2NETSHOT_PROFILE").ok());
+    let (args, profile_sources) = apply_config(args, profile.as_deref()).unwrap_or_else(|error| {
+        clap::Error::with_description(&format!("{:#}", error), clap::ErrorKind::InvalidValue).exit()
+    });
+    let matches = Opt::clap().get_matches_from(args);
+    let mut opt = Opt::from_clap(&matches);
+    if opt.strict_flags {
+        if let Some(deprecated) = deprecated_uses().first() {
+            clap::Error::with_description(
+                &format!("{}, refused by --strict-flags", deprecated),
+                clap::ErrorKind::UnknownArgument,
+            )
+            .exit();
+        }
+    }
+    if let Some(path) = &opt.netbox_devices_filter_file {
+        opt.netbox_devices_filter = run_options(&opt)
+            .devices_filters()
+            .into_iter()
+            .map(|devices_filter| cli::read_filter_file(path, devices_filter))
+            .collect::<Result<_, Error>>()
+            .unwrap_or_else(|error| {
+                clap::Error::with_description(
+                    &format!("{:#}", error),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+    }
+    if let Some(path) = &opt.exclude_file {
+        let mut exclusions = cli::read_exclude_file(path).unwrap_or_else(|error| {
+            clap::Error::with_description(&format!("{:#}", error), clap::ErrorKind::InvalidValue)
+                .exit()
+        });
+        opt.exclude_ip.append(&mut exclusions);
+    }
+    match (opt.netshot_auto_discover, &opt.netshot_driver) {
+        (false, None) => clap::Error::with_description(
+            "--netshot-auto-discover false requires the --netshot-driver of the devices",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+        (true, Some(_)) => clap::Error::with_description(
+            "--netshot-driver only applies with --netshot-auto-discover false",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit(),
+        _ => {}
+    }
+    if opt.new_device_status == Some(netshot::DeviceStatus::Disabled) && opt.state_file.is_none() {
+        clap::Error::with_description(
+            "--new-device-status DISABLED requires --state-file, to leave the onboarded devices disabled on the next runs",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    if let Err(error) = check_graphql_filter(&opt) {
+        error.exit();
+    }
+    for devices_filter in &mut opt.netbox_devices_filter {
+        *devices_filter = cli::encode_filter(devices_filter);
+    }
+    for vms_filter in &mut opt.netbox_vms_filter {
+        *vms_filter = cli::encode_filter(vms_filter);
+    }
+    if opt.print_effective_config {
+        config::print_effective_config(&Opt::clap(), &matches, &profile_sources);
+        return Ok(());
+    }
+    #[cfg(feature = "bench")]
+    if let Some(Command::Bench(bench_opt)) = &opt.command {
+        return bench::run(bench_opt);
+    }
+    #[cfg(feature = "export")]
+    if let Some(Command::Netshot(NetshotCommand::Export { output })) = &opt.command {
+        return export_netshot(&opt, *output);
+    }
+    if let Some(Command::Preflight(preflight_opt)) = &opt.command {
+        return run_preflight(&opt, preflight_opt);
+    }
+    if let Some(Command::ReportSchema) = &opt.command {
+        println!("{}", serde_json::to_string_pretty(&report::v1::schema())?);
+        return Ok(());
+    }
+    #[cfg(feature = "self-update")]
+    if let Some(Command::SelfUpdate(update_opt)) = &opt.command {
+        let proxy = opt
+            .netbox_proxy
+            .clone()
+            .or_else(|| opt.netshot_proxy.clone());
+        return update::run(update_opt, proxy);
+    }
+    let netbox_url = required(&opt.netbox_url, "netbox-url");
+    let netshot_url = required(&opt.netshot_url, "netshot-url");
+    require_netshot_auth(&opt);
+    // The --role-domain-map names every domain instead
+    if opt.role_domain_map.is_empty() {
+        required(&opt.netshot_domain_id, "netshot-domain-id");
+    }
+    require_netbox_auth(&opt);
+    let run_id = opt.run_id.get_or_insert_with(cli::new_run_id).clone();
+    init_logger(&opt, false)?;
+    if opt.read_only {
+        // The clients refuse the writes anyway, the check mode keeps them from being attempted
+        opt.check = true;
+        log::info!("Starting run {} in read-only mode", run_id);
+    } else {
+        log::info!("Starting run {}", run_id);
+    }
+    match (&opt.profile, &opt.config) {
+        (Some(profile), Some(config)) => {
+            log::info!("Using the options of profile {} of {}", profile, config)
+        }
+        (None, Some(config)) => log::info!("Using the options of {}", config),
+        _ => {}
+    }
+    let options = run_options(&opt);
+    let synchronization = match run::Run::start(&options) {
+        Ok(synchronization) => synchronization,
+        Err(error) if error.is::<run::TooSoon>() => {
+            log::warn!("{}", error);
+            log::logger().flush();
+            std::process::exit(EXIT_TOO_SOON);
+        }
+        Err(error) => return Err(error),
+    };
+
+    let http_stats = build_http_stats(&opt);
+    let netbox_client = build_netbox_client(&opt, netbox_url, http_stats.clone())?;
+    if opt.netbox_from_file.is_some() {
+        log::info!("Not checking the Netbox access, the devices being loaded from a file");
+    } else if opt.netbox_anonymous {
+        log::warn!("Accessing Netbox anonymously");
+        netbox_client.check_anonymous_access(options.devices_filters()[0])?;
+    } else if !opt.skip_ping {
+        let ping = match netbox_client.ping() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(anyhow!(
+                "Netbox ping on {} got an error status",
+                opt.netbox_ping_path
+            )),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = ping {
+            if opt.strict_ping {
+                return Err(error);
+            }
+            log::warn!("{:#}, carrying on with the real queries", error);
+        }
+    }
+
+    let netshot_client = build_netshot_client(&opt, netshot_url, http_stats.clone())?;
+
+    let netshot_apply_client = build_netshot_apply_client(&opt, http_stats.clone())?;
+    let apply_client = netshot_apply_client.as_ref().unwrap_or(&netshot_client);
+    if !opt.check && !opt.no_netshot_writes && !opt.skip_permission_check {
+        apply_client.check_write_permission()?;
+    }
+    synchronization.synchronize(run::Clients {
+        netbox: &netbox_client,
+        netshot: &netshot_client,
+        netshot_apply: netshot_apply_client.as_ref(),
+        http_stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apply::Recorders;
+    use domains::DomainTarget;
+    use flexi_logger::{AdaptiveFormat, Logger};
+    use netshot::{DeviceUpdatedPayload, NewDeviceCreatedPayload};
+    use report::{Action, ActionOutcome, AuditLog, ReportSummary};
+    use rest::api::{NetboxApi, NetshotApi};
+    use rest::optional::OptionalFeatures;
+    use rest::snapshot::{Fetched, Source};
+    use serde_json::json;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    #[ctor::ctor]
+    fn enable_logging() {
+        let _ = Logger::try_with_str("debug")
+            .unwrap()
+            .adaptive_format_for_stderr(AdaptiveFormat::Detailed);
+    }
+
+    /// A Netbox selecting the given devices for each filter, on which the given IPs are still in service, the
+    /// others being deleted
+    #[derive(Default)]
+    struct FakeNetbox {
+        in_service: Vec<&'static str>,
+        devices: Vec<(&'static str, serde_json::Value)>,
+    }
+
+    impl NetboxApi for FakeNetbox {
+        fn get_devices(&self, query_string: &str) -> Result<Fetched<netbox::Device>, Error> {
+            let devices = match self
+                .devices
+                .iter()
+                .find(|(filter, _)| *filter == query_string)
+            {
+                Some((_, devices)) => serde_json::from_value(devices.clone())?,
+                None => Vec::new(),
+            };
+            Ok(Fetched::single(Source::NetboxDevices, devices))
+        }
+
+        fn get_vms(&self, _query_string: &str) -> Result<Fetched<netbox::Device>, Error> {
+            Ok(Fetched::single(Source::NetboxVms, Vec::new()))
+        }
+
+        fn deletion_status(
+            &self,
+            ip: &str,
+            _include_vms: bool,
+        ) -> Result<netbox::DeletionStatus, Error> {
+            match self.in_service.contains(&ip) {
+                true => Ok(netbox::DeletionStatus::OutOfScope(ip.to_string())),
+                false => Ok(netbox::DeletionStatus::Deleted),
+            }
+        }
+
+        fn get_management_ips(
+            &self,
+            _device_ids: &[u32],
+        ) -> Result<HashMap<u32, netbox::PrimaryIP>, Error> {
+            Ok(HashMap::new())
+        }
+    }
+
+    /// A Netshot serving a canned inventory, failing the actions on the given IPs and recording the calls
+    struct FakeNetshot {
+        devices: serde_json::Value,
+        failing_ips: Vec<&'static str>,
+        calls: Mutex<Vec<String>>,
+        pending_discoveries: usize,
+        groups: Vec<netshot::Group>,
+        tasks: Vec<netshot::Task>,
+    }
+
+    impl FakeNetshot {
+        /// Refuse the IDs of the devices of another Netshot
+        fn known_id(&self, device_id: u32) -> Result<(), Error> {
+            let devices: Vec<netshot::Device> = serde_json::from_value(self.devices.clone())?;
+            match devices.iter().any(|device| device.id == device_id) {
+                true => Ok(()),
+                false => Err(anyhow!("No device {} on this Netshot", device_id)),
+            }
+        }
+
+        fn call(&self, call: String, ip_address: &str) -> Result<(), Error> {
+            self.calls.lock().unwrap().push(call);
+            match self.failing_ips.contains(&ip_address) {
+                true => Err(anyhow!("Got status 500 for {}", ip_address)),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl NetshotApi for FakeNetshot {
+        fn get_devices(&self, _domain_id: u32) -> Result<Fetched<netshot::Device>, Error> {
+            Ok(Fetched::single(
+                Source::NetshotDevices,
+                serde_json::from_value(self.devices.clone())?,
+            ))
+        }
+
+        fn get_device_addresses(&self, _device_id: u32) -> Result<Vec<String>, Error> {
+            Ok(Vec::new())
+        }
+
+        fn register_device(
+            &self,
+            ip_address: String,
+            domain_id: u32,
+            _name: &str,
+        ) -> Result<NewDeviceCreatedPayload, Error> {
+            self.call(
+                format!("register {} in {}", ip_address, domain_id),
+                &ip_address,
+            )?;
+            // The discovery task is numbered after the last byte of the IP
+            let task_id = ip_address.rsplit('.').next().unwrap_or_default();
+            Ok(NewDeviceCreatedPayload {
+                task_id: task_id.parse().unwrap_or(1),
+                status: Some("SCHEDULED".to_string()),
+            })
+        }
+
+        fn disable_device(
+            &self,
+            ip_address: String,
+        ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+            self.call(format!("disable {}", ip_address), &ip_address)?;
+            Ok(Some(DeviceUpdatedPayload::default()))
+        }
+
+        fn enable_device(&self, ip_address: String) -> Result<Option<DeviceUpdatedPayload>, Error> {
+            self.call(format!("enable {}", ip_address), &ip_address)?;
+            Ok(Some(DeviceUpdatedPayload::default()))
+        }
+
+        fn disable_device_by_id(
+            &self,
+            device: &netshot::Device,
+        ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+            self.known_id(device.id)?;
+            self.disable_device(device.management_address.ip.clone())
+        }
+
+        fn enable_device_by_id(
+            &self,
+            device: &netshot::Device,
+        ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+            self.known_id(device.id)?;
+            self.enable_device(device.management_address.ip.clone())
+        }
+
+        fn set_devices_enabled(
+            &self,
+            ip_addresses: &[String],
+            enabled: bool,
+            _batch_size: usize,
+        ) -> Vec<(
+            String,
+            Duration,
+            Result<Option<DeviceUpdatedPayload>, Error>,
+        )> {
+            ip_addresses
+                .iter()
+                .map(|ip_address| {
+                    let update = match enabled {
+                        true => self.enable_device(ip_address.clone()),
+                        false => self.disable_device(ip_address.clone()),
+                    };
+                    (ip_address.clone(), Duration::ZERO, update)
+                })
+                .collect()
+        }
+
+        fn get_device_credential_set_ids(&self, device_id: u32) -> Result<Vec<u32>, Error> {
+            Ok(vec![device_id])
+        }
+
+        fn count_pending_discoveries(&self) -> Result<usize, Error> {
+            Ok(self.pending_discoveries)
+        }
+
+        fn set_device_credential_sets(
+            &self,
+            ip_address: String,
+            credential_set_ids: &[u32],
+        ) -> Result<DeviceUpdatedPayload, Error> {
+            self.call(
+                format!("credentials {} {:?}", ip_address, credential_set_ids),
+                &ip_address,
+            )?;
+            Ok(DeviceUpdatedPayload::default())
+        }
+
+        fn get_device_comments(&self, device_id: u32) -> Result<Option<String>, Error> {
+            Ok(Some(format!("Netbox site: site-{}", device_id)))
+        }
+
+        fn update_device_comments(
+            &self,
+            ip_address: String,
+            comments: &str,
+        ) -> Result<DeviceUpdatedPayload, Error> {
+            self.call(
+                format!("comments {} {:?}", ip_address, comments),
+                &ip_address,
+            )?;
+            Ok(DeviceUpdatedPayload::default())
+        }
+
+        fn set_device_prefix_length(
+            &self,
+            ip_address: String,
+            prefix_length: u8,
+        ) -> Result<DeviceUpdatedPayload, Error> {
+            self.call(
+                format!("prefix {}/{}", ip_address, prefix_length),
+                &ip_address,
+            )?;
+            Ok(DeviceUpdatedPayload::default())
+        }
+
+        fn update_device_ip(
+            &self,
+            device_id: u32,
+            ip_address: String,
+        ) -> Result<DeviceUpdatedPayload, Error> {
+            self.known_id(device_id)?;
+            self.call(format!("address {} {}", device_id, ip_address), &ip_address)?;
+            Ok(DeviceUpdatedPayload::default())
+        }
+
+        fn rename_device(
+            &self,
+            device_id: u32,
+            name: String,
+        ) -> Result<DeviceUpdatedPayload, Error> {
+            self.known_id(device_id)?;
+            self.call(format!("rename {} {}", device_id, name), &name)?;
+            Ok(DeviceUpdatedPayload::default())
+        }
+
+        fn get_task(&self, task_id: u32) -> Result<netshot::Task, Error> {
+            self.tasks
+                .iter()
+                .find(|task| task.id == task_id)
+                .cloned()
+                .ok_or_else(|| anyhow!("No task {}", task_id))
+        }
+
+        fn get_groups(&self) -> Result<Vec<netshot::Group>, Error> {
+            Ok(self.groups.clone())
+        }
+
+        fn create_static_group(&self, name: &str) -> Result<netshot::Group, Error> {
+            self.call(format!("create group {}", name), "")?;
+            Ok(serde_json::from_value(
+                json!({"id": 9, "name": name, "type": "StaticDeviceGroup"}),
+            )?)
+        }
+
+        fn set_group_membership(
+            &self,
+            group_name: &str,
+            ip_address: String,
+            member: bool,
+        ) -> Result<bool, Error> {
+            let verb = if member { "add" } else { "remove" };
+            self.call(
+                format!("{} {} in {}", verb, ip_address, group_name),
+                &ip_address,
+            )?;
+            Ok(true)
+        }
+
+        fn add_device_to_group(&self, device_id: u32, group_id: u32) -> Result<bool, Error> {
+            self.call(format!("add {} to group {}", device_id, group_id), "")?;
+            Ok(true)
+        }
+
+        fn find_device(&self, ip_address: &str) -> Result<Option<netshot::Device>, Error> {
+            // The registered devices are found once discovered, with the ID of their discovery task
+            let task_id = ip_address.rsplit('.').next().unwrap_or_default();
+            Ok(Some(serde_json::from_value(json!({
+                "id": task_id.parse::<u32>()?,
+                "name": format!("discovered-{}", task_id),
+                "mgmtAddress": {"ip": ip_address},
+                "status": "INPRODUCTION"
+            }))?))
+        }
+
+        fn delete_device(&self, ip_address: String) -> Result<(), Error> {
+            self.call(format!("delete {}", ip_address), &ip_address)
+        }
+
+        fn refresh_groups(&self) -> Result<Vec<netshot::Group>, Error> {
+            // The groups as left by the membership updates made so far
+            let devices: Vec<netshot::Device> = serde_json::from_value(self.devices.clone())?;
+            let calls = self.calls.lock().unwrap();
+            let mut groups = self.get_groups()?;
+            for group in &mut groups {
+                for device in &devices {
+                    let ip = &device.management_address.ip;
+                    if calls.contains(&format!("add {} in {}", ip, group.name)) {
+                        group.static_devices.push(device.id);
+                    }
+                    if calls.contains(&format!("remove {} in {}", ip, group.name)) {
+                        group.static_devices.retain(|id| *id != device.id);
+                    }
+                }
+            }
+            Ok(groups)
+        }
+
+        fn delete_group(&self, group: &netshot::Group) -> Result<(), Error> {
+            self.call(format!("delete group {}", group.name), "")
+        }
+    }
+
+    #[test]
+    fn exit_codes() {
+        let refused = reqwest::blocking::get("http://127.0.0.1:1/api/").unwrap_err();
+        let unreachable = Error::from(refused).context("Unable to reach Netbox");
+        assert_eq!(exit_code(&unreachable), EXIT_UNAVAILABLE);
+        let gateway = Error::from(rest::helpers::StatusError {
+            service: "Netshot",
+            endpoint: "/api/domains".to_string(),
+            status: 503,
+        });
+        assert_eq!(exit_code(&gateway), EXIT_UNAVAILABLE);
+
+        let partial = Error::from(run::PartialFailure(
+            "domain PAR1: 3 of 5 changes failed, above the fail threshold of 0".to_string(),
+        ));
+        assert_eq!(exit_code(&partial), EXIT_PARTIAL_FAILURE);
+        let blocked = Error::from(run::ChangeLimitExceeded(
+            "domain PAR1: Blocked by the change limits".to_string(),
+        ));
+        assert_eq!(exit_code(&blocked), EXIT_CHANGES_BLOCKED);
+        let drift = Error::from(run::DriftDetected(
+            "Netshot drifted from Netbox, 3 devices to register, 1 to disable and 0 to enable"
+                .to_string(),
+        ));
+        assert_eq!(exit_code(&drift), EXIT_DRIFT);
+        assert_eq!(exit_code(&anyhow!("Invalid mapping file")), 1);
+    }
+
+    #[test]
+    fn duplicate_ips() {
+        let netshot = FakeNetshot {
+            devices: json!([]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "edge-1", "primary_ip4": {"id": 1, "address": "1.2.3.5/32"}},
+                {"id": 2, "name": "edge-2", "primary_ip4": {"id": 2, "address": "1.2.3.5/32"}}
+            ]))
+            .unwrap(),
+        };
+        let sync = |args: &[&str]| {
+            let opt = Opt::from_iter(std::iter::once("netbox2netshot").chain(args.iter().copied()));
+            run::run_domain(
+                &run_options(&opt),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+        };
+
+        let error = sync(&["--fail-on-duplicate-ip"]).unwrap_err();
+        assert!(error.to_string().contains("--fail-on-duplicate-ip"));
+        assert!(netshot.calls.lock().unwrap().is_empty());
+        assert!(sync(&["--fail-on-duplicate-ip", "--check"]).is_err());
+
+        // The duplicates of a domain block the others before anything is applied
+        let opt = Opt::from_iter(["netbox2netshot", "--fail-on-duplicate-ip"]);
+        let clean_target = DomainTarget {
+            domain_id: 3,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 3, "name": "edge-3", "primary_ip4": {"id": 3, "address": "1.2.3.6/32"}}
+            ]))
+            .unwrap(),
+        };
+        let mut plans: Vec<run::PlannedDomain> = [&clean_target, &target]
+            .iter()
+            .map(|target| {
+                run::plan_domain(
+                    &run_options(&opt),
+                    &FakeNetbox::default(),
+                    &netshot,
+                    &netshot,
+                    Recorders::default(),
+                    target,
+                )
+                .unwrap()
+            })
+            .collect();
+        let mut planned: Vec<&mut run::PlannedDomain> = plans.iter_mut().collect();
+        assert!(run::guard_plans(&run_options(&opt), &mut planned).is_err());
+        assert!(netshot.calls.lock().unwrap().is_empty());
+
+        // The first device of the IP wins otherwise
+        let (sync_plan, _) = sync(&[]).unwrap();
+        assert_eq!(sync_plan.netbox_conflicts.len(), 1);
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec!["register 1.2.3.5 in 2"]
+        );
+    }
+
+    #[test]
+    fn converged_ipv6_registrations() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 5, "name": "edge", "mgmtAddress": {"ip": "2001:0db8:0000:0000:0000:0000:0000:0005"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "edge", "primary_ip6": {"id": 1, "address": "2001:db8::5/128"}}
+            ]))
+            .unwrap(),
+        };
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-converged-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut history = run::RunState::load(&directory.join("state.json")).unwrap();
+        history.record_registration("2001:db8::5", 2, 10, Utc::now());
+        let history = Mutex::new(history);
+
+        let opt = Opt::from_iter(["netbox2netshot", "--allow-ipv6"]);
+        run::plan_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders {
+                history: Some(&history),
+                ..Recorders::default()
+            },
+            &target,
+        )
+        .unwrap();
+        // Found on Netshot in its uncompressed form, the registration converged before any prune
+        assert!(history.lock().unwrap().registrations.is_empty());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn held_registrations() {
+        let netshot = FakeNetshot {
+            devices: json!([]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: serde_json::from_value(json!([
+                {"id": 10, "status": "FAILURE", "log": "No driver could be found for this device"},
+                {"id": 11, "status": "FAILURE", "log": "SNMP: Timeout while waiting for the response"}
+            ]))
+            .unwrap(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "no-driver", "primary_ip4": {"id": 1, "address": "1.2.3.5/32"}},
+                {"id": 2, "name": "slow", "primary_ip4": {"id": 2, "address": "1.2.3.6/32"}}
+            ]))
+            .unwrap(),
+        };
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-held-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut history = run::RunState::load(&directory.join("state.json")).unwrap();
+        history.record_registration("1.2.3.5", 2, 10, Utc::now());
+        history.record_registration("1.2.3.6", 2, 11, Utc::now());
+        let history = Mutex::new(history);
+        let recorders = Recorders {
+            history: Some(&history),
+            ..Recorders::default()
+        };
+
+        let opt = Opt::from_iter(["netbox2netshot"]);
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            recorders,
+            &target,
+        )
+        .unwrap();
+        assert_eq!(sync_plan.held_registrations.len(), 1);
+        assert_eq!(sync_plan.held_registrations[0].device.ip, "1.2.3.5");
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec!["register 1.2.3.6 in 2"]
+        );
+        let failure_classes: Vec<_> = history
+            .lock()
+            .unwrap()
+            .registrations
+            .iter()
+            .map(|registration| registration.failure_class)
+            .collect();
+        assert_eq!(
+            failure_classes,
+            vec![
+                Some(netshot::DiscoveryFailure::Driver),
+                Some(netshot::DiscoveryFailure::Timeout)
+            ]
+        );
+
+        let opt = Opt::from_iter(["netbox2netshot", "--retry-permanent-failures"]);
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            recorders,
+            &target,
+        )
+        .unwrap();
+        assert!(sync_plan.held_registrations.is_empty());
+        assert_eq!(sync_plan.to_register.len(), 2);
+    }
+
+    #[test]
+    fn netshot_authentication() {
+        let error = Opt::from_iter_safe([
+            "netbox2netshot",
+            "--netshot-token",
+            "XXX",
+            "--netshot-user",
+            "sync",
+        ])
+        .unwrap_err();
+        assert_eq!(error.kind, clap::ErrorKind::ArgumentConflict);
+        for password in ["--netshot-password", "--netshot-password-file"] {
+            let error = Opt::from_iter_safe(["netbox2netshot", password, "secret"]).unwrap_err();
+            assert_eq!(
+                error.kind,
+                clap::ErrorKind::MissingRequiredArgument,
+                "{}",
+                password
+            );
+        }
+
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-password-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("password");
+        std::fs::write(&path, "secret\n").unwrap();
+        let opt = Opt::from_iter([
+            OsString::from("netbox2netshot"),
+            OsString::from("--netshot-user"),
+            OsString::from("sync"),
+            OsString::from("--netshot-password-file"),
+            path.into_os_string(),
+        ]);
+        let login = netshot_login(&opt).unwrap().unwrap();
+        assert_eq!(
+            format!("{:?}", login),
+            r#"Login { user: "sync", password: "***" }"#
+        );
+        assert_eq!(
+            serde_json::to_string(&login).unwrap(),
+            r#"{"username":"sync","password":"secret"}"#
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let opt = Opt::from_iter(["netbox2netshot", "--netshot-token", "XXX"]);
+        assert!(netshot_login(&opt).unwrap().is_none());
+    }
+
+    #[test]
+    fn graphql_filter() {
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--netbox-graphql-filter",
+            "role: \"edge\"",
+        ]);
+        assert_eq!(
+            check_graphql_filter(&opt).unwrap_err().kind,
+            clap::ErrorKind::MissingRequiredArgument
+        );
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--netbox-api",
+            "graphql",
+            "--netbox-graphql-filter",
+            "role: \"edge\"",
+        ]);
+        assert!(check_graphql_filter(&opt).is_ok());
+    }
+
+    #[test]
+    fn apply_with_failures() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "kept", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "removed", "mgmtAddress": {"ip": "1.2.3.7"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "back", "mgmtAddress": {"ip": "1.2.3.8"}, "status": "DISABLED"}
+            ]),
+            failing_ips: vec!["1.2.3.5"],
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "kept", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}},
+                {"id": 2, "name": "new", "primary_ip4": {"id": 2, "address": "1.2.3.5/32"}},
+                {"id": 3, "name": "back", "primary_ip4": {"id": 3, "address": "1.2.3.8/32"}}
+            ]))
+            .unwrap(),
+        };
+
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-apply-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let audit = AuditLog::create(&directory.join("report.json")).unwrap();
+        let history = Mutex::new(run::RunState::load(&directory.join("state.json")).unwrap());
+        let outcomes = Mutex::new(Vec::new());
+
+        let opt = Opt::from_iter(["netbox2netshot"]);
+        let (sync_plan, applied) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders {
+                audit: Some(&audit),
+                history: Some(&history),
+                outcomes: Some(&outcomes),
+                ..Recorders::default()
+            },
+            &target,
+        )
+        .unwrap();
+        let outcomes = outcomes.into_inner().unwrap();
+        assert_eq!(ReportSummary::from_outcomes(&outcomes), applied);
+        let failures: Vec<&ActionOutcome> =
+            outcomes.iter().filter(|outcome| outcome.failed()).collect();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].action, Action::Register);
+        assert_eq!(failures[0].ip, "1.2.3.5");
+        assert!(failures[0].error.is_some());
+
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(sync_plan.to_register[0].previous_failures, None);
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec!["register 1.2.3.5 in 2", "disable 1.2.3.7", "enable 1.2.3.8"]
+        );
+        assert_eq!(applied.failed_by_action[&Action::Register], 1);
+        assert!(opt
+            .fail_threshold
+            .exceeded(applied.failed(), applied.attempted()));
+        let summary = audit.finalize(report::RunDetails::default()).unwrap();
+        assert_eq!(summary.failures, 1);
+        assert_eq!(summary.disabled, 1);
+        assert_eq!(summary.enabled, 1);
+
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders {
+                history: Some(&history),
+                ..Recorders::default()
+            },
+            &target,
+        )
+        .unwrap();
+        assert_eq!(sync_plan.to_register[0].previous_failures, Some(1));
+        assert_eq!(history.lock().unwrap().chronic_failures(1)[0].ip, "1.2.3.5");
+
+        // The failures of the device gone from Netbox are forgotten
+        let gone = DomainTarget {
+            domain_id: target.domain_id,
+            domain_name: None,
+            netbox_devices: target
+                .netbox_devices
+                .iter()
+                .filter(|device| device.id != 2)
+                .cloned()
+                .collect(),
+        };
+        run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders {
+                history: Some(&history),
+                ..Recorders::default()
+            },
+            &gone,
+        )
+        .unwrap();
+        assert!(history.lock().unwrap().failures.is_empty());
+    }
+
+    #[test]
+    fn budgeted_credential_updates() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "outdated", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "up-to-date", "mgmtAddress": {"ip": "1.2.3.6"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "removed", "mgmtAddress": {"ip": "1.2.3.7"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "outdated", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}},
+                {"id": 2, "name": "new", "primary_ip4": {"id": 2, "address": "1.2.3.5/32"}},
+                {"id": 3, "name": "up-to-date", "primary_ip4": {"id": 3, "address": "1.2.3.6/32"}}
+            ]))
+            .unwrap(),
+        };
+
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--ensure-credential-set-ids",
+            "3",
+            "--changes-budget",
+            "2",
+            "--budget-order",
+            "update_credentials,register",
+        ]);
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec!["register 1.2.3.5 in 2", "credentials 1.2.3.4 [3]"]
+        );
+        assert_eq!(sync_plan.budget_deferred.to_disable[0].ip, "1.2.3.7");
+    }
+
+    #[test]
+    fn synced_attributes() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "unchanged", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "moved", "mgmtAddress": {"ip": "1.2.3.6"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "unchanged", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"},
+                 "site": {"id": 1, "name": "site-1"}, "serial": ""},
+                {"id": 3, "name": "moved", "primary_ip4": {"id": 3, "address": "1.2.3.6/32"},
+                 "site": {"id": 4, "name": "par1"}}
+            ]))
+            .unwrap(),
+        };
+
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--netbox-url",
+            "https://netbox.example.org",
+            "--sync-attributes",
+            "site,serial",
+        ]);
+        let (sync_plan, applied) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+
+        assert_eq!(sync_plan.to_update_attributes.len(), 1);
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec![r#"comments 1.2.3.6 "Netbox site: par1""#]
+        );
+        assert_eq!(applied.attributes_updated, 1);
+    }
+
+    #[test]
+    fn change_limits() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "edge-1", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "edge-2", "mgmtAddress": {"ip": "1.2.3.5"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "edge-3", "mgmtAddress": {"ip": "1.2.3.6"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        // A filter matching nothing
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: Vec::new(),
+        };
+        let sync_with = |args: &[&str]| {
+            let opt = Opt::from_iter(
+                [
+                    "netbox2netshot",
+                    "--netbox-url",
+                    "https://netbox.example.org",
+                ]
+                .iter()
+                .chain(args),
+            );
+            run::run_domain(
+                &run_options(&opt),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+        };
+
+        let error = sync_with(&["--max-disable-percent", "50%"]).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<run::ChangeLimitExceeded>(),
+            Some(&run::ChangeLimitExceeded(
+                "Blocked by the change limits: 3 of the 3 Netshot devices (100.0%) to be disabled, above --max-disable-percent 50".to_string()
+            ))
+        );
+        assert!(sync_with(&["--max-changes", "2"])
+            .unwrap_err()
+            .is::<run::ChangeLimitExceeded>());
+        assert!(netshot.calls.lock().unwrap().is_empty());
+
+        // The check mode still plans, the changes budget does not lift the limits
+        let (sync_plan, _) = sync_with(&["--max-changes", "2", "--check"]).unwrap();
+        assert_eq!(sync_plan.to_disable.len(), 3);
+        assert!(sync_with(&["--max-changes", "2", "--changes-budget", "1"]).is_err());
+        assert_eq!(sync_with(&["--max-changes", "3"]).unwrap().1.disabled, 3);
+
+        // The limits apply to the changes of every domain together
+        let opt = Opt::from_iter(["netbox2netshot", "--max-changes", "4"]);
+        let other_target = DomainTarget {
+            domain_id: 3,
+            domain_name: None,
+            netbox_devices: Vec::new(),
+        };
+        let mut plans: Vec<run::PlannedDomain> = [&target, &other_target]
+            .iter()
+            .map(|target| {
+                run::plan_domain(
+                    &run_options(&opt),
+                    &FakeNetbox::default(),
+                    &netshot,
+                    &netshot,
+                    Recorders::default(),
+                    target,
+                )
+                .unwrap()
+            })
+            .collect();
+        let exceeded = run::guard_plans(
+            &run_options(&opt),
+            &mut plans.iter_mut().collect::<Vec<_>>(),
+        )
+        .unwrap();
+        assert_eq!(
+            exceeded,
+            Some(run::ChangeLimitExceeded(
+                "Blocked by the change limits: 6 changes planned, above --max-changes 4"
+                    .to_string()
+            ))
+        );
+        let mut planned = [&mut plans[0]];
+        assert_eq!(
+            run::guard_plans(&run_options(&opt), &mut planned).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn changed_ips() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "renumbered", "mgmtAddress": {"ip": "10.0.0.1"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "twin", "mgmtAddress": {"ip": "10.0.0.2"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "twin", "mgmtAddress": {"ip": "10.0.0.3"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "renumbered", "primary_ip4": {"id": 1, "address": "10.1.0.1/32"}},
+                {"id": 2, "name": "twin", "primary_ip4": {"id": 2, "address": "10.1.0.2/32"}}
+            ]))
+            .unwrap(),
+        };
+        let sync_with = |update_changed_ips: bool| {
+            netshot.calls.lock().unwrap().clear();
+            let mut args = vec![
+                "netbox2netshot",
+                "--netbox-url",
+                "https://netbox.example.org",
+            ];
+            if update_changed_ips {
+                args.push("--update-changed-ips");
+            }
+            let opt = Opt::from_iter(args);
+            run::run_domain(
+                &run_options(&opt),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+            .unwrap()
+        };
+
+        let (_, applied) = sync_with(false);
+        assert_eq!(applied.registered, 2);
+        assert_eq!(applied.disabled, 3);
+
+        // The device named twice on Netshot is still registered again
+        let (sync_plan, applied) = sync_with(true);
+        assert_eq!(sync_plan.to_update_address.len(), 1);
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec![
+                "register 10.1.0.2 in 2",
+                "disable 10.0.0.2",
+                "disable 10.0.0.3",
+                "address 1 10.1.0.1"
+            ]
+        );
+        assert_eq!(applied.registered, 1);
+        assert_eq!(applied.disabled, 2);
+        assert_eq!(applied.addresses_updated, 1);
+    }
+
+    #[test]
+    fn synced_names() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "edge-1-old", "mgmtAddress": {"ip": "10.0.0.1"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "EDGE-2.example.com", "mgmtAddress": {"ip": "10.0.0.2"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "edge-3", "mgmtAddress": {"ip": "10.0.0.3"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "edge-1", "primary_ip4": {"id": 1, "address": "10.0.0.1/32"}},
+                {"id": 2, "name": "edge-2", "primary_ip4": {"id": 2, "address": "10.0.0.2/32"}},
+                {"id": 3, "primary_ip4": {"id": 3, "address": "10.0.0.3/32"}}
+            ]))
+            .unwrap(),
+        };
+        let sync_with = |extra_args: &[&str]| {
+            netshot.calls.lock().unwrap().clear();
+            let mut args = vec![
+                "netbox2netshot",
+                "--netbox-url",
+                "https://netbox.example.org",
+            ];
+            args.extend(extra_args);
+            let opt = Opt::from_iter(args);
+            run::run_domain(
+                &run_options(&opt),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+            .unwrap()
+        };
+
+        let (sync_plan, _) = sync_with(&[]);
+        assert!(sync_plan.to_rename.is_empty());
+
+        // The nameless Netbox device is left alone, the check mode only lists the renames
+        let (sync_plan, _) = sync_with(&["--sync-names", "--check"]);
+        assert_eq!(sync_plan.to_rename.len(), 2);
+        assert!(netshot.calls.lock().unwrap().is_empty());
+
+        let (sync_plan, applied) =
+            sync_with(&["--sync-names", "--strip-name-suffix", "example.com"]);
+        assert_eq!(sync_plan.to_rename.len(), 1);
+        assert_eq!(*netshot.calls.lock().unwrap(), vec!["rename 1 edge-1"]);
+        assert_eq!(applied.renamed, 1);
+    }
+
+    #[test]
+    fn verified_deletions() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "deleted", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "re-roled", "mgmtAddress": {"ip": "1.2.3.5"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let netbox = FakeNetbox {
+            in_service: vec!["1.2.3.5"],
+            ..FakeNetbox::default()
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: Vec::new(),
+        };
+
+        let opt = Opt::from_iter(["netbox2netshot", "--verify-deletions"]);
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &netbox,
+            &netshot,
+            &netshot,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+
+        assert_eq!(*netshot.calls.lock().unwrap(), vec!["disable 1.2.3.4"]);
+        assert_eq!(sync_plan.out_of_scope[0].ip, "1.2.3.5");
+    }
+
+    #[test]
+    fn check_mode_applies_nothing() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 2, "name": "removed", "mgmtAddress": {"ip": "1.2.3.7"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: Vec::new(),
+        };
+
+        let opt = Opt::from_iter(["netbox2netshot", "--check"]);
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+
+        assert_eq!(sync_plan.to_disable.len(), 1);
+        assert!(netshot.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn management_interfaces() {
+        let netbox_client = netbox::NetboxClient::builder(mockito::server_url())
+            .build()
+            .unwrap();
+        let _devices = mockito::mock("GET", "/api/dcim/devices/")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "tag".to_string(),
+                "mgmt".to_string(),
+            ))
+            .with_body(
+                json!({"count": 2, "next": null, "previous": null, "results": [
+                    {"id": 1, "name": "data-ip", "primary_ip4": {"id": 10, "family": 4, "address": "10.0.0.1/24"}},
+                    {"id": 2, "name": "no-mgmt", "primary_ip4": {"id": 20, "family": 4, "address": "10.0.0.2/24"}}
+                ]})
+                .to_string(),
+            )
+            .create();
+        let interfaces = mockito::mock("GET", "/api/dcim/interfaces/")
+            .match_query(mockito::Matcher::Regex(
+                "mgmt_only=true&device_id=1&device_id=2$".to_string(),
+            ))
+            .with_body(
+                json!({"count": 1, "next": null, "previous": null, "results": [
+                    {"id": 100, "name": "mgmt0", "device": {"id": 1, "name": "data-ip"}}
+                ]})
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+        let _addresses = mockito::mock("GET", "/api/ipam/ip-addresses/")
+            .match_query(mockito::Matcher::Regex("interface_id=100$".to_string()))
+            .with_body(
+                json!({"count": 2, "next": null, "previous": null, "results": [
+                    {"id": 30, "family": {"value": 6, "label": "IPv6"}, "address": "2001:db8::1/64", "assigned_object_id": 100},
+                    {"id": 31, "family": {"value": 4, "label": "IPv4"}, "address": "192.168.0.1/24", "assigned_object_id": 100}
+                ]})
+                .to_string(),
+            )
+            .create();
+
+        let opt = Opt::from_iter(["netbox2netshot", "--netbox-devices-filter", "tag=mgmt"]);
+        let (devices, _) = run::fetch_netbox_inventory(
+            &run_options(&opt),
+            &netbox_client,
+            &OptionalFeatures::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            devices[0].primary_ip4.as_ref().unwrap().address,
+            "10.0.0.1/24"
+        );
+
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--netbox-devices-filter",
+            "tag=mgmt",
+            "--prefer-mgmt-interface",
+        ]);
+        let (devices, _) = run::fetch_netbox_inventory(
+            &run_options(&opt),
+            &netbox_client,
+            &OptionalFeatures::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            devices[0].primary_ip4.as_ref().unwrap().address,
+            "192.168.0.1/24"
+        );
+        assert_eq!(
+            devices[1].primary_ip4.as_ref().unwrap().address,
+            "10.0.0.2/24"
+        );
+        interfaces.assert();
+    }
+
+    #[test]
+    fn union_of_filters() {
+        let netbox = FakeNetbox {
+            devices: vec![
+                (
+                    "role=edge",
+                    json!([
+                        {"id": 1, "name": "edge-1", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}},
+                        {"id": 3, "name": "edge-core", "primary_ip4": {"id": 3, "address": "1.2.3.6/32"}}
+                    ]),
+                ),
+                (
+                    "role=core",
+                    json!([
+                        {"id": 3, "name": "edge-core", "primary_ip4": {"id": 3, "address": "1.2.3.6/32"}},
+                        {"id": 2, "name": "core-1", "primary_ip4": {"id": 2, "address": "1.2.3.5/32"}}
+                    ]),
+                ),
+            ],
+            ..FakeNetbox::default()
+        };
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--netbox-devices-filter",
+            "role=edge",
+            "--netbox-devices-filter",
+            "role=core",
+        ]);
+        let (devices, snapshots) =
+            run::fetch_netbox_inventory(&run_options(&opt), &netbox, &OptionalFeatures::default())
+                .unwrap();
+        let names: Vec<String> = devices.iter().map(|device| device.display_name()).collect();
+        assert_eq!(names, vec!["edge-1", "edge-core", "core-1"]);
+        assert_eq!(snapshots.len(), 2);
+
+        // The devices of every filter are kept enabled
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 10, "name": "edge-1", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": 11, "name": "core-1", "mgmtAddress": {"ip": "1.2.3.5"}, "status": "INPRODUCTION"},
+                {"id": 12, "name": "edge-core", "mgmtAddress": {"ip": "1.2.3.6"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: devices,
+        };
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &netbox,
+            &netshot,
+            &netshot,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+        assert!(sync_plan.to_disable.is_empty());
+        assert!(sync_plan.to_register.is_empty());
+        assert_eq!(
+            run_options(&opt).devices_filters(),
+            vec!["role=edge", "role=core"]
+        );
+        assert_eq!(
+            run_options(&Opt::from_iter(["netbox2netshot"])).devices_filters(),
+            vec![""]
+        );
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        let page: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("tests/data/netbox/non_ascii_names.json").unwrap(),
+        )
+        .unwrap();
+        let netshot = FakeNetshot {
+            devices: serde_json::from_str(
+                &std::fs::read_to_string("tests/data/netshot/non_ascii_names.json").unwrap(),
+            )
+            .unwrap(),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: Some("東京".to_string()),
+            netbox_devices: serde_json::from_value(page["results"].clone()).unwrap(),
+        };
+
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-names-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let report_path = directory.join("report.json");
+        let audit = AuditLog::create(&report_path).unwrap();
+
+        let opt = Opt::from_iter(["netbox2netshot"]);
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders {
+                audit: Some(&audit),
+                ..Recorders::default()
+            },
+            &target,
+        )
+        .unwrap();
+        // The case and domain of the Netshot names do not make a conflict
+        assert!(sync_plan.name_conflicts.is_empty());
+        assert_eq!(
+            sync_plan.to_enable[0].name,
+            "DÜSSELDORF-Straße-sw-1.example.de"
+        );
+        assert_eq!(sync_plan.to_disable[0].name, "Zürich-Äußere-sw-2");
+
+        audit.finalize(report::RunDetails::default()).unwrap();
+        let report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&report_path).unwrap()).unwrap();
+        let mut names: Vec<&str> = report["actions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|action| action["name"].as_str().unwrap())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            vec!["DÜSSELDORF-Straße-sw-1.example.de", "Zürich-Äußere-sw-2"]
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn forbidden_writes() {
+        let netshot_client = netshot::NetshotClient::builder(mockito::server_url())
+            .token("forbidden-writes")
+            .build()
+            .unwrap();
+        // Slow answers, so that the registrations sent before the first refusal are still in flight
+        let registrations = mockito::mock("POST", "/api/devices")
+            .match_header("X-Netshot-API-Token", "forbidden-writes")
+            .with_status(403)
+            .with_body_from_fn(|body| {
+                std::thread::sleep(Duration::from_millis(50));
+                body.write_all(json!({"errorMsg": "Forbidden"}).to_string().as_bytes())
+            })
+            .expect_at_least(1)
+            .expect_at_most(4)
+            .create();
+        let netshot = FakeNetshot {
+            devices: json!([]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: Some("PAR1".to_string()),
+            netbox_devices: (1..=10)
+                .map(|id| {
+                    serde_json::from_value(json!({
+                        "id": id,
+                        "name": format!("par-{}", id),
+                        "primary_ip4": {"id": id, "address": format!("1.2.3.{}/32", id)}
+                    }))
+                    .unwrap()
+                })
+                .collect(),
+        };
+
+        // No registration is sent once the first refusal is handled, at most --parallelism of them being in flight
+        let opt = Opt::from_iter(["netbox2netshot"]);
+        assert_eq!(opt.parallelism, 4);
+        let (sync_plan, applied) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot_client,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+        registrations.assert();
+        assert_eq!(sync_plan.to_register.len(), 10);
+        assert!((1..=4).contains(&applied.failures));
+        assert_eq!(applied.failures + applied.aborted, 10);
+        assert_eq!(applied.failed_by_action[&Action::Register], 10);
+        assert!(run::check_domain_thresholds(&run_options(&opt), &applied, 0).is_err());
+    }
+
+    #[test]
+    fn forbidden_sites() {
+        let netbox_client = netbox::NetboxClient::builder(mockito::server_url())
+            .build()
+            .unwrap();
+        let netshot_client = netshot::NetshotClient::builder(mockito::server_url())
+            .build()
+            .unwrap();
+        let sites = mockito::mock("GET", "/api/dcim/sites/")
+            .match_query(mockito::Matcher::Any)
+            .with_status(403)
+            .with_body(
+                json!({"detail": "You do not have permission to perform this action."}).to_string(),
+            )
+            .expect(1)
+            .create();
+        let _domains = mockito::mock("GET", "/api/domains")
+            .match_query(mockito::Matcher::Any)
+            .with_body(json!([{"id": 1, "name": "Default"}, {"id": 2, "name": "PAR1"}]).to_string())
+            .create();
+        let devices: Vec<netbox::Device> = serde_json::from_value(json!([
+            {"id": 1, "name": "par-1", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}, "site": {"id": 10}}
+        ]))
+        .unwrap();
+
+        // The devices would otherwise all move to the default domain
+        let mut options = run::RunOptions::default();
+        options.netshot_domain_id = 1;
+        options.site_domain_field = Some("cf_netshot_domain".to_string());
+        let error =
+            run::domain_targets(&options, &netbox_client, &netshot_client, devices).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Unable to look up the Netbox sites"));
+        sites.assert();
+    }
+
+    #[test]
+    fn forbidden_management_interfaces() {
+        let netbox_client = netbox::NetboxClient::builder(mockito::server_url())
+            .build()
+            .unwrap();
+        let _devices = mockito::mock("GET", "/api/dcim/devices/")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "tag".to_string(),
+                "forbidden-mgmt".to_string(),
+            ))
+            .with_body(
+                json!({"count": 1, "next": null, "previous": null, "results": [
+                    {"id": 7, "name": "data-ip", "primary_ip4": {"id": 70, "family": 4, "address": "10.0.7.1/24"}}
+                ]})
+                .to_string(),
+            )
+            .create();
+        let interfaces = mockito::mock("GET", "/api/dcim/interfaces/")
+            .match_query(mockito::Matcher::Regex("device_id=7$".to_string()))
+            .with_status(403)
+            .with_body(
+                json!({"detail": "You do not have permission to perform this action."}).to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--netbox-devices-filter",
+            "tag=forbidden-mgmt",
+            "--prefer-mgmt-interface",
+        ]);
+        let features = OptionalFeatures::default();
+        for _ in 0..2 {
+            let (devices, _) =
+                run::fetch_netbox_inventory(&run_options(&opt), &netbox_client, &features).unwrap();
+            assert_eq!(
+                devices[0].primary_ip4.as_ref().unwrap().address,
+                "10.0.7.1/24"
+            );
+        }
+        interfaces.assert();
+        assert_eq!(features.degraded()[0].endpoint, "/api/dcim/interfaces/");
+        assert_eq!(features.degraded()[0].status, 403);
+
+        // The synchronization carries on with the primary IPs
+        let (devices, _) =
+            run::fetch_netbox_inventory(&run_options(&opt), &netbox_client, &features).unwrap();
+        let target = DomainTarget {
+            domain_id: 1,
+            domain_name: None,
+            netbox_devices: devices,
+        };
+        let netshot = FakeNetshot {
+            devices: json!([]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let (_, applied) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+        assert_eq!(applied.registered, 1);
+    }
+
+    #[test]
+    fn stale_actions() {
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "back", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}}
+            ]))
+            .unwrap(),
+        };
+        let netshot = |groups: serde_json::Value| FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "back", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "stale", "mgmtAddress": {"ip": "1.2.3.7"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "quarantined", "mgmtAddress": {"ip": "1.2.3.9"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: serde_json::from_value(groups).unwrap(),
+            tasks: Vec::new(),
+        };
+        let quarantine = json!([
+            {"id": 9, "name": "Quarantine", "type": "StaticDeviceGroup", "staticDevices": [1, 3]}
+        ]);
+
+        for (args, groups, calls) in [
+            (
+                vec!["--stale-action", "group:Quarantine"],
+                quarantine.clone(),
+                vec!["add 1.2.3.7 in Quarantine", "remove 1.2.3.4 in Quarantine"],
+            ),
+            (
+                vec!["--stale-action", "group:Quarantine", "--create-groups"],
+                json!([]),
+                vec![
+                    "create group Quarantine",
+                    "add 1.2.3.7 in Quarantine",
+                    "add 1.2.3.9 in Quarantine",
+                ],
+            ),
+            (
+                vec!["--stale-action", "purge"],
+                quarantine.clone(),
+                vec!["delete 1.2.3.7", "delete 1.2.3.9"],
+            ),
+        ] {
+            let opt = Opt::from_iter(std::iter::once("netbox2netshot").chain(args));
+            let netshot = netshot(groups);
+            let (sync_plan, applied) = run::run_domain(
+                &run_options(&opt),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+            .unwrap();
+
+            assert!(sync_plan.to_disable.is_empty());
+            assert_eq!(*netshot.calls.lock().unwrap(), calls);
+            assert_eq!(
+                applied.attempted(),
+                calls.len() - usize::from(opt.create_groups)
+            );
+        }
+
+        // The group is only created by the apply
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--stale-action",
+            "group:Quarantine",
+            "--create-groups",
+            "--check",
+        ]);
+        let missing_group = netshot(json!([]));
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &missing_group,
+            &missing_group,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+        assert_eq!(sync_plan.to_add_to_group.len(), 2);
+        assert!(missing_group.calls.lock().unwrap().is_empty());
+
+        let opt = Opt::from_iter(["netbox2netshot", "--stale-action", "group:Quarantine"]);
+        let error = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot(json!([])),
+            &netshot(json!([])),
+            Recorders::default(),
+            &target,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("--create-groups"));
+
+        // The members of the group are matched whatever the form of their IPv6 address on Netshot
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "back", "primary_ip6": {"id": 1, "address": "2001:db8::4/128"}}
+            ]))
+            .unwrap(),
+        };
+        let ipv6 = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "back", "mgmtAddress": {"ip": "2001:0db8:0000:0000:0000:0000:0000:0004"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "stale", "mgmtAddress": {"ip": "2001:0db8:0000:0000:0000:0000:0000:0007"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "quarantined", "mgmtAddress": {"ip": "2001:0db8:0000:0000:0000:0000:0000:0009"}, "status": "INPRODUCTION"}
+            ]),
+            ..netshot(quarantine)
+        };
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--allow-ipv6",
+            "--stale-action",
+            "group:Quarantine",
+            "--check",
+        ]);
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &ipv6,
+            &ipv6,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+        let ips = |devices: &[sync::PlannedDevice]| -> Vec<String> {
+            devices.iter().map(|device| device.ip.clone()).collect()
+        };
+        assert_eq!(ips(&sync_plan.to_add_to_group), vec!["2001:db8::7"]);
+        assert_eq!(ips(&sync_plan.to_remove_from_group), vec!["2001:db8::4"]);
+    }
+
+    #[test]
+    fn foreign_apply_ids() {
+        let netshot = |ids: [u32; 3]| FakeNetshot {
+            devices: json!([
+                {"id": ids[0], "name": "old-name", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": ids[1], "name": "stale", "mgmtAddress": {"ip": "1.2.3.7"}, "status": "INPRODUCTION"},
+                {"id": ids[2], "name": "back", "mgmtAddress": {"ip": "1.2.3.8"}, "status": "DISABLED"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        // The devices of the apply Netshot are found under the last byte of their IP
+        let (primary, staging) = (netshot([1, 2, 3]), netshot([4, 7, 8]));
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "new-name", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}},
+                {"id": 3, "name": "back", "primary_ip4": {"id": 3, "address": "1.2.3.8/32"}}
+            ]))
+            .unwrap(),
+        };
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--sync-names",
+            "--netshot-apply-url",
+            "https://staging.example.org",
+            "--netshot-apply-token",
+            "token",
+        ]);
+
+        let (_, applied) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &primary,
+            &staging,
+            Recorders::default(),
+            &target,
+        )
+        .unwrap();
+        assert_eq!(applied.failed(), 0);
+        assert!(primary.calls.lock().unwrap().is_empty());
+        assert_eq!(
+            *staging.calls.lock().unwrap(),
+            vec!["disable 1.2.3.7", "enable 1.2.3.8", "rename 4 new-name"]
+        );
+    }
+
+    #[test]
+    fn uncompressed_ipv6_ids() {
+        // Netshot does not compress the IPv6 addresses, the plan is in the Netbox form
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "old-name", "mgmtAddress": {"ip": "2001:0db8:0000:0000:0000:0000:0000:0001"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "stale", "mgmtAddress": {"ip": "2001:0db8:0000:0000:0000:0000:0000:0002"}, "status": "INPRODUCTION"},
+                {"id": 3, "name": "back", "mgmtAddress": {"ip": "2001:0db8:0000:0000:0000:0000:0000:0003"}, "status": "DISABLED"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "new-name", "primary_ip6": {"id": 1, "address": "2001:db8::1/128"}},
+                {"id": 3, "name": "back", "primary_ip6": {"id": 3, "address": "2001:db8::3/128"}}
+            ]))
+            .unwrap(),
+        };
+        let outcomes = Mutex::new(Vec::new());
+        let opt = Opt::from_iter(["netbox2netshot", "--allow-ipv6", "--sync-names"]);
+
+        let (_, applied) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders {
+                outcomes: Some(&outcomes),
+                ..Recorders::default()
+            },
+            &target,
+        )
+        .unwrap();
+        assert_eq!(applied.failed(), 0);
+        // Updated by ID, with the address of the inventory rather than searched by the planned one
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec![
+                "disable 2001:0db8:0000:0000:0000:0000:0000:0002",
+                "enable 2001:0db8:0000:0000:0000:0000:0000:0003",
+                "rename 1 new-name"
+            ]
+        );
+        let netshot_ids: Vec<(Action, Option<u32>)> = outcomes
+            .into_inner()
+            .unwrap()
+            .iter()
+            .map(|outcome| (outcome.action, outcome.netshot_id))
+            .collect();
+        assert_eq!(
+            netshot_ids,
+            vec![
+                (Action::Disable, Some(2)),
+                (Action::Enable, Some(3)),
+                (Action::Rename, Some(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn purged_disabled_devices() {
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "removed", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"},
+                {"id": 2, "name": "long-gone", "mgmtAddress": {"ip": "1.2.3.5"}, "status": "DISABLED", "changeDate": 1619787406000i64}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "new", "primary_ip4": {"id": 1, "address": "1.2.3.6/32"}}
+            ]))
+            .unwrap(),
+        };
+        let sync_with = |extra_args: &[&str]| {
+            netshot.calls.lock().unwrap().clear();
+            let args = ["netbox2netshot", "--parallelism", "1"]
+                .iter()
+                .chain(extra_args);
+            run::run_domain(
+                &run_options(&Opt::from_iter(args)),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+        };
+
+        // The check mode lists the purges, which count in the changes limits
+        let (sync_plan, _) = sync_with(&["--purge-disabled-after", "90", "--check"]).unwrap();
+        assert_eq!(sync_plan.to_purge_disabled.len(), 1);
+        assert_eq!(sync_plan.to_disable.len(), 1);
+        assert!(netshot.calls.lock().unwrap().is_empty());
+        assert!(sync_with(&["--purge-disabled-after", "90", "--max-changes", "2"]).is_err());
+
+        let (_, applied) = sync_with(&["--purge-disabled-after", "90"]).unwrap();
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec!["register 1.2.3.6 in 2", "disable 1.2.3.4", "delete 1.2.3.5"]
+        );
+        assert_eq!((applied.purged, applied.purged_disabled), (0, 1));
+
+        // Nothing is deleted outside of the disable window, whatever the stale action
+        let later = Utc::now() + chrono::Duration::hours(2);
+        let window = format!(
+            "{}-{}",
+            later.format("%H:%M"),
+            (later + chrono::Duration::hours(1)).format("%H:%M")
+        );
+        let (sync_plan, _) = sync_with(&[
+            "--purge-disabled-after",
+            "90",
+            "--stale-action",
+            "purge",
+            "--disable-window",
+            &window,
+            "--window-timezone",
+            "UTC",
+        ])
+        .unwrap();
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec!["register 1.2.3.6 in 2"]
+        );
+        assert_eq!(
+            sync_plan
+                .deferred_disables
+                .iter()
+                .map(|device| device.ip.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1.2.3.4", "1.2.3.5"]
+        );
+    }
+
+    #[test]
+    fn disabled_registrations() {
+        let netshot = FakeNetshot {
+            devices: json!([]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: serde_json::from_value(json!([
+                {"id": 5, "status": "SUCCESS"},
+                {"id": 6, "status": "FAILURE", "log": "No driver could be found for this device"}
+            ]))
+            .unwrap(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "discovered", "primary_ip4": {"id": 1, "address": "1.2.3.5/32"}},
+                {"id": 2, "name": "no-driver", "primary_ip4": {"id": 2, "address": "1.2.3.6/32"}}
+            ]))
+            .unwrap(),
+        };
+
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-onboarded-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let history = Mutex::new(run::RunState::load(&directory.join("state.json")).unwrap());
+        let recorders = || Recorders {
+            history: Some(&history),
+            ..Default::default()
+        };
+
+        // Only the discovered device can be disabled once all the registrations are submitted, the other one is reported
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--new-device-status",
+            "disabled",
+            "--parallelism",
+            "1",
+        ]);
+        let (_, applied) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            recorders(),
+            &target,
+        )
+        .unwrap();
+        assert_eq!(applied.registered, 2);
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec![
+                "register 1.2.3.5 in 2",
+                "register 1.2.3.6 in 2",
+                "disable 1.2.3.5"
+            ]
+        );
+
+        // The next run leaves the onboarded device disabled, while enabling the other disabled ones
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 5, "name": "discovered", "mgmtAddress": {"ip": "1.2.3.5"}, "status": "DISABLED"},
+                {"id": 6, "name": "no-driver", "mgmtAddress": {"ip": "1.2.3.6"}, "status": "DISABLED"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            recorders(),
+            &target,
+        )
+        .unwrap();
+        assert_eq!(sync_plan.onboarded[0].ip, "1.2.3.5");
+        assert_eq!(*netshot.calls.lock().unwrap(), vec!["enable 1.2.3.6"]);
+        assert!(history.lock().unwrap().is_onboarded(2, "1.2.3.5"));
+
+        // Once enabled on Netshot, the device is no longer held back
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 5, "name": "discovered", "mgmtAddress": {"ip": "1.2.3.5"}, "status": "INPRODUCTION"}
+            ]),
+            ..netshot
+        };
+        run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            recorders(),
+            &target,
+        )
+        .unwrap();
+        assert!(!history.lock().unwrap().is_onboarded(2, "1.2.3.5"));
+
+        assert!(
+            Opt::from_iter_safe(["netbox2netshot", "--new-device-status", "PREPRODUCTION"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn grouped_registrations() {
+        let netshot = FakeNetshot {
+            devices: json!([]),
+            failing_ips: vec!["1.2.3.7"],
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: serde_json::from_value(json!([
+                {"id": 5, "status": "SUCCESS"},
+                {"id": 6, "status": "FAILURE", "log": "No driver could be found for this device"}
+            ]))
+            .unwrap(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "discovered", "primary_ip4": {"id": 1, "address": "1.2.3.5/32"}},
+                {"id": 2, "name": "no-driver", "primary_ip4": {"id": 2, "address": "1.2.3.6/32"}},
+                {"id": 3, "name": "refused", "primary_ip4": {"id": 3, "address": "1.2.3.7/32"}}
+            ]))
+            .unwrap(),
+        };
+
+        // The device whose discovery failed is reported, without failing the run unless --wait-for-tasks
+        for (arguments, registered) in [
+            (vec!["--netshot-group-id", "9"], 2),
+            (vec!["--netshot-group-id", "9", "--wait-for-tasks"], 1),
+        ] {
+            netshot.calls.lock().unwrap().clear();
+            let opt = Opt::from_iter(std::iter::once("netbox2netshot").chain(arguments));
+            let (_, applied) = run::run_domain(
+                &run_options(&opt),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+            .unwrap();
+            assert_eq!(applied.registered, registered);
+            assert_eq!(applied.failures, 3 - registered);
+            let calls = netshot.calls.lock().unwrap();
+            assert_eq!(
+                calls
+                    .iter()
+                    .filter(|call| call.contains("group"))
+                    .collect::<Vec<_>>(),
+                vec!["add 5 to group 9"]
+            );
+        }
+
+        let mut netshot = netshot;
+        netshot
+            .tasks
+            .push(serde_json::from_value(json!({"id": 7, "status": "SCHEDULED"})).unwrap());
+        // The tasks are polled against one deadline, the ended ones being resolved even once it passed
+        let mut discoveries = apply::wait_for_discoveries(&netshot, &[7, 6, 5], Instant::now());
+        assert_eq!(
+            discoveries.remove(&7).unwrap().unwrap_err().to_string(),
+            "The discovery task 7 is still SCHEDULED after --task-timeout"
+        );
+        assert_eq!(
+            discoveries.remove(&6).unwrap().unwrap_err().to_string(),
+            "The discovery task 6 failed on a driver problem: No driver could be found for this device"
+        );
+        assert!(discoveries.remove(&5).unwrap().is_ok());
+    }
+
+    #[test]
+    fn offline_inventories() {
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-offline-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let devices: Vec<netshot::Device> = serde_json::from_value(json!([
+            {"id": 1, "name": "kept", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION", "mgmtDomain": {"id": 2}},
+            {"id": 2, "name": "removed", "mgmtAddress": {"ip": "1.2.3.7"}, "status": "INPRODUCTION", "mgmtDomain": {"id": 2}},
+            {"id": 3, "name": "elsewhere", "mgmtAddress": {"ip": "1.2.3.9"}, "status": "INPRODUCTION", "mgmtDomain": {"id": 3}}
+        ]))
+        .unwrap();
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("netshot-devices.json");
+        std::fs::write(&path, serde_json::to_vec(&devices).unwrap()).unwrap();
+
+        // Never asked for its devices
+        let netshot = FakeNetshot {
+            devices: json!([
+                {"id": 4, "name": "live", "mgmtAddress": {"ip": "1.2.3.10"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: Vec::new(),
+            tasks: Vec::new(),
+        };
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "kept", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}}
+            ]))
+            .unwrap(),
+        };
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--check",
+            "--netshot-from-file",
+            path.to_str().unwrap(),
+        ]);
+        let dumped = Mutex::new(Vec::new());
+        let (sync_plan, _) = run::run_domain(
+            &run_options(&opt),
+            &FakeNetbox::default(),
+            &netshot,
+            &netshot,
+            Recorders {
+                netshot_inventory: Some(&dumped),
+                ..Recorders::default()
+            },
+            &target,
+        )
+        .unwrap();
+        assert!(sync_plan.to_register.is_empty());
+        assert_eq!(sync_plan.to_disable.len(), 1);
+        assert_eq!(sync_plan.to_disable[0].ip, "1.2.3.7");
+        assert!(netshot.calls.lock().unwrap().is_empty());
+        assert_eq!(dumped.into_inner().unwrap(), devices[..2].to_vec());
+
+        let error = Opt::clap()
+            .get_matches_from_safe([
+                "netbox2netshot",
+                "--netshot-from-file",
+                path.to_str().unwrap(),
+            ])
+            .unwrap_err();
+        assert_eq!(error.kind, clap::ErrorKind::MissingRequiredArgument);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn pruned_groups() {
+        let netshot = FakeNetshot {
+            devices: json!([]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: serde_json::from_value(json!([
+                {"id": 9, "name": "Quarantine", "folder": "netbox2netshot", "type": "StaticDeviceGroup"},
+                {"id": 10, "name": "Lab", "folder": "", "type": "StaticDeviceGroup"},
+                {"id": 11, "name": "Site PAR2", "folder": "netbox2netshot", "type": "StaticDeviceGroup"},
+                {"id": 12, "name": "Site PAR3", "folder": "netbox2netshot", "type": "StaticDeviceGroup", "staticDevices": [4]},
+                {"id": 2, "name": "Distribution", "folder": "netbox2netshot", "type": "DynamicDeviceGroup"}
+            ]))
+            .unwrap(),
+            tasks: Vec::new(),
+        };
+        let opt = Opt::from_iter([
+            "netbox2netshot",
+            "--stale-action",
+            "group:Quarantine",
+            "--prune-empty-groups",
+        ]);
+
+        assert_eq!(
+            run::prune_empty_groups(&run_options(&opt), &netshot),
+            vec![netshot::PrunedGroup {
+                id: 11,
+                name: "Site PAR2".to_string()
+            }]
+        );
+        assert_eq!(
+            *netshot.calls.lock().unwrap(),
+            vec!["delete group Site PAR2"]
+        );
+    }
+
+    #[test]
+    fn pruned_stale_group() {
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "back", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}}
+            ]))
+            .unwrap(),
+        };
+        let netshot = || {
+            FakeNetshot {
+            devices: json!([
+                {"id": 1, "name": "back", "mgmtAddress": {"ip": "1.2.3.4"}, "status": "INPRODUCTION"}
+            ]),
+            failing_ips: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+            pending_discoveries: 0,
+            groups: serde_json::from_value(json!([
+                {"id": 9, "name": "Quarantine", "folder": "netbox2netshot", "type": "StaticDeviceGroup", "staticDevices": [1]}
+            ]))
+            .unwrap(),
+            tasks: Vec::new(),
+        }
+        };
+
+        // The last quarantined device back on Netbox leaves the group empty
+        for (create_groups, pruned) in [(true, vec!["Quarantine"]), (false, vec![])] {
+            let mut args = vec![
+                "netbox2netshot",
+                "--stale-action",
+                "group:Quarantine",
+                "--prune-empty-groups",
+            ];
+            if create_groups {
+                args.push("--create-groups");
+            }
+            let opt = Opt::from_iter(args);
+            let netshot = netshot();
+            run::run_domain(
+                &run_options(&opt),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+            .unwrap();
+
+            let pruned_groups = run::prune_empty_groups(&run_options(&opt), &netshot);
+            assert_eq!(
+                pruned_groups
+                    .iter()
+                    .map(|group| group.name.as_str())
+                    .collect::<Vec<_>>(),
+                pruned
+            );
+            let mut calls = vec!["remove 1.2.3.4 in Quarantine".to_string()];
+            calls.extend(pruned.iter().map(|name| format!("delete group {}", name)));
+            assert_eq!(*netshot.calls.lock().unwrap(), calls);
+        }
+    }
+
+    #[test]
+    fn pending_discoveries() {
+        let target = DomainTarget {
+            domain_id: 2,
+            domain_name: None,
+            netbox_devices: serde_json::from_value(json!([
+                {"id": 1, "name": "new", "primary_ip4": {"id": 1, "address": "1.2.3.5/32"}},
+                {"id": 2, "name": "back", "primary_ip4": {"id": 2, "address": "1.2.3.8/32"}}
+            ]))
+            .unwrap(),
+        };
+        let opt = Opt::from_iter(["netbox2netshot", "--max-pending-discoveries", "10"]);
+
+        for (pending_discoveries, calls) in [
+            (11, vec!["enable 1.2.3.8"]),
+            (10, vec!["register 1.2.3.5 in 2", "enable 1.2.3.8"]),
+        ] {
+            let netshot = FakeNetshot {
+                devices: json!([
+                    {"id": 3, "name": "back", "mgmtAddress": {"ip": "1.2.3.8"}, "status": "DISABLED"}
+                ]),
+                failing_ips: Vec::new(),
+                calls: Mutex::new(Vec::new()),
+                pending_discoveries,
+                groups: Vec::new(),
+                tasks: Vec::new(),
+            };
+            let (sync_plan, _) = run::run_domain(
+                &run_options(&opt),
+                &FakeNetbox::default(),
+                &netshot,
+                &netshot,
+                Recorders::default(),
+                &target,
+            )
+            .unwrap();
+
+            assert_eq!(*netshot.calls.lock().unwrap(), calls);
+            assert_eq!(
+                sync_plan.deferred_registrations.len(),
+                usize::from(pending_discoveries > 10)
+            );
+        }
     }
 }
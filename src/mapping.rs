@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The version of the mapping file format, bumped on incompatible changes
+const MAPPING_VERSION: u32 = 1;
+
+/// The Netshot device a Netbox device has been matched with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappingEntry {
+    pub netbox_id: u32,
+    pub netshot_id: u32,
+    pub domain_id: u32,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// The mapping file as written on disk
+#[derive(Debug, Serialize)]
+struct MappingFile<'a> {
+    version: u32,
+    entries: Vec<&'a MappingEntry>,
+}
+
+/// The Netbox to Netshot device IDs mapping, persisted across runs
+#[derive(Debug)]
+pub struct DeviceMapping {
+    path: PathBuf,
+    entries: BTreeMap<u32, MappingEntry>,
+}
+
+impl DeviceMapping {
+    /// Load the mapping file, starting with an empty mapping when it does not exist yet.
+    /// The entries which cannot be parsed are pruned, a file from a newer release is refused.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut mapping = DeviceMapping {
+            path: path.to_path_buf(),
+            entries: BTreeMap::new(),
+        };
+        if !path.exists() {
+            log::info!("No mapping file at {}, starting a new one", path.display());
+            return Ok(mapping);
+        }
+
+        let content: Value = match serde_json::from_str(&fs::read_to_string(path)?) {
+            Ok(content) => content,
+            Err(error) => {
+                log::warn!(
+                    "The mapping file {} is corrupt ({}), starting a new one",
+                    path.display(),
+                    error
+                );
+                return Ok(mapping);
+            }
+        };
+        let version = content.get("version").and_then(Value::as_u64);
+        if version != Some(MAPPING_VERSION as u64) {
+            return Err(anyhow!(
+                "Unsupported version {:?} of the mapping file {}, expected {}",
+                version,
+                path.display(),
+                MAPPING_VERSION
+            ));
+        }
+
+        let entries = content
+            .get("entries")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        for (index, entry) in entries.into_iter().enumerate() {
+            match serde_json::from_value::<MappingEntry>(entry) {
+                Ok(entry) => mapping.insert(entry),
+                Err(error) => log::warn!(
+                    "Pruning the corrupt entry {} of the mapping file: {}",
+                    index,
+                    error
+                ),
+            }
+        }
+        log::info!(
+            "Loaded {} entries from the mapping file {}",
+            mapping.entries.len(),
+            path.display()
+        );
+        Ok(mapping)
+    }
+
+    /// Add an entry, replacing the ones of the same Netbox or Netshot device
+    fn insert(&mut self, entry: MappingEntry) {
+        let replaced: Vec<u32> = self
+            .entries
+            .values()
+            .filter(|existing| {
+                existing.netshot_id == entry.netshot_id && existing.netbox_id != entry.netbox_id
+            })
+            .map(|existing| existing.netbox_id)
+            .collect();
+        for netbox_id in replaced {
+            log::warn!(
+                "Netshot device {} was mapped to Netbox device {}, now to {}",
+                entry.netshot_id,
+                netbox_id,
+                entry.netbox_id
+            );
+            self.entries.remove(&netbox_id);
+        }
+        self.entries.insert(entry.netbox_id, entry);
+    }
+
+    /// The Netshot device ID of every mapped Netbox device ID
+    pub fn netshot_ids(&self) -> HashMap<u32, u32> {
+        self.entries
+            .values()
+            .map(|entry| (entry.netbox_id, entry.netshot_id))
+            .collect()
+    }
+
+    /// Record that a Netbox device matches a Netshot device
+    pub fn record(&mut self, domain_id: u32, netbox_id: u32, netshot_id: u32, now: DateTime<Utc>) {
+        let first_seen = match self.entries.get(&netbox_id) {
+            Some(entry) if entry.netshot_id == netshot_id => entry.first_seen,
+            _ => now,
+        };
+        self.insert(MappingEntry {
+            netbox_id,
+            netshot_id,
+            domain_id,
+            first_seen,
+            last_seen: now,
+        });
+    }
+
+    /// Remove the entries of the domain whose Netbox or Netshot device is gone
+    pub fn prune(&mut self, domain_id: u32, netbox_ids: &HashSet<u32>, netshot_ids: &HashSet<u32>) {
+        self.entries.retain(|_, entry| {
+            if entry.domain_id != domain_id {
+                return true;
+            }
+            let side = if !netbox_ids.contains(&entry.netbox_id) {
+                "Netbox"
+            } else if !netshot_ids.contains(&entry.netshot_id) {
+                "Netshot"
+            } else {
+                return true;
+            };
+            log::info!(
+                "Pruning the mapping of Netbox device {} to Netshot device {}, gone from {}",
+                entry.netbox_id,
+                entry.netshot_id,
+                side
+            );
+            false
+        });
+    }
+
+    /// Write the mapping file, through a temporary file so it is never left half written
+    pub fn save(&self) -> Result<(), Error> {
+        let mapping_file = MappingFile {
+            version: MAPPING_VERSION,
+            entries: self.entries.values().collect(),
+        };
+
+        let mut temporary_path = self.path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        let mut file = File::create(&temporary_path)?;
+        serde_json::to_writer_pretty(&mut file, &mapping_file)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, &self.path)?;
+
+        log::info!(
+            "Saved {} entries to the mapping file {}",
+            self.entries.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping_path(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "netbox2netshot-mapping-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory.join("map.json")
+    }
+
+    #[test]
+    fn mapping_round_trip() {
+        let path = mapping_path("round-trip");
+        let first_run: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+        let second_run: DateTime<Utc> = "2024-01-02T00:00:00Z".parse().unwrap();
+
+        let mut mapping = DeviceMapping::load(&path).unwrap();
+        mapping.record(2, 1, 2318, first_run);
+        mapping.record(2, 2, 2319, first_run);
+        mapping.save().unwrap();
+
+        let mut mapping = DeviceMapping::load(&path).unwrap();
+        mapping.record(2, 1, 2318, second_run);
+        // The Netshot device has been matched with another Netbox device
+        mapping.record(2, 3, 2319, second_run);
+
+        assert_eq!(mapping.netshot_ids(), HashMap::from([(1, 2318), (3, 2319)]));
+        assert_eq!(mapping.entries[&1].first_seen, first_run);
+        assert_eq!(mapping.entries[&1].last_seen, second_run);
+    }
+
+    #[test]
+    fn prune_stale_entries() {
+        let now = Utc::now();
+        let mut mapping = DeviceMapping::load(&mapping_path("prune")).unwrap();
+        mapping.record(2, 1, 2318, now);
+        mapping.record(2, 2, 2319, now);
+        mapping.record(2, 3, 2320, now);
+        mapping.record(5, 4, 2321, now);
+
+        mapping.prune(2, &HashSet::from([1, 2]), &HashSet::from([2318, 2320]));
+
+        assert_eq!(mapping.netshot_ids(), HashMap::from([(1, 2318), (4, 2321)]));
+    }
+
+    #[test]
+    fn corrupt_mapping_file() {
+        let path = mapping_path("corrupt");
+        fs::write(
+            &path,
+            r#"{"version": 1, "entries": [
+                {"netbox_id": 1, "netshot_id": 2318, "domain_id": 2, "first_seen": "2024-01-01T00:00:00Z", "last_seen": "2024-01-01T00:00:00Z"},
+                {"netbox_id": "two", "netshot_id": 2319}
+            ]}"#,
+        )
+        .unwrap();
+        let mapping = DeviceMapping::load(&path).unwrap();
+        assert_eq!(mapping.netshot_ids(), HashMap::from([(1, 2318)]));
+
+        fs::write(&path, r#"{"version": 1, "entries": ["#).unwrap();
+        assert!(DeviceMapping::load(&path).unwrap().entries.is_empty());
+
+        fs::write(&path, r#"{"version": 2, "entries": []}"#).unwrap();
+        let error = DeviceMapping::load(&path).unwrap_err();
+        assert!(error.to_string().contains("Unsupported version"));
+    }
+}
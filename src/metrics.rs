@@ -0,0 +1,227 @@
+use crate::rest::helpers::CertificateExpiry;
+use crate::sync::SyncPlan;
+#[cfg(feature = "metrics")]
+use anyhow::{Error, Result};
+#[cfg(feature = "metrics")]
+use chrono::{DateTime, Utc};
+#[cfg(feature = "metrics")]
+use std::fmt::Write as _;
+#[cfg(feature = "metrics")]
+use std::fs::{self, File};
+#[cfg(feature = "metrics")]
+use std::io::Write;
+#[cfg(feature = "metrics")]
+use std::path::{Path, PathBuf};
+
+/// The figures of a run, written for the textfile collector of the Prometheus node exporter
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunMetrics {
+    pub netbox_devices: usize,
+    pub netshot_devices: usize,
+    pub to_register: usize,
+    pub to_disable: usize,
+    pub to_enable: usize,
+    /// The changes which timed out, failed or were aborted
+    pub failures: usize,
+    pub success: bool,
+    pub client_certificates: Vec<CertificateExpiry>,
+}
+
+/// Append a gauge along with its help
+#[cfg(feature = "metrics")]
+fn gauge(output: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(output, "# HELP netbox2netshot_{} {}", name, help);
+    let _ = writeln!(output, "# TYPE netbox2netshot_{} gauge", name);
+    let _ = writeln!(output, "netbox2netshot_{} {}", name, value);
+}
+
+impl RunMetrics {
+    /// Count the changes planned in a domain
+    pub fn add_plan(&mut self, plan: &SyncPlan) {
+        self.to_register += plan.to_register.len();
+        self.to_disable += plan.to_disable.len();
+        self.to_enable += plan.to_enable.len();
+    }
+
+    /// Whether any registration, disable or enable was planned, Netshot having drifted from Netbox
+    pub fn has_drift(&self) -> bool {
+        self.to_register + self.to_disable + self.to_enable > 0
+    }
+
+    /// The planned changes on a single line of stable format, for the annotations of the drift alerts
+    pub fn drift_summary(&self) -> String {
+        format!(
+            "drift={} to_register={} to_disable={} to_enable={}",
+            self.has_drift(),
+            self.to_register,
+            self.to_disable,
+            self.to_enable
+        )
+    }
+
+    /// The metrics in the Prometheus text format, the run having ended at the given time
+    #[cfg(feature = "metrics")]
+    pub fn render(&self, finished_at: DateTime<Utc>) -> String {
+        let mut output = String::new();
+        gauge(
+            &mut output,
+            "netbox_devices",
+            "The Netbox devices in the scope of the synchronization",
+            self.netbox_devices,
+        );
+        gauge(
+            &mut output,
+            "netshot_devices",
+            "The devices fetched from Netshot",
+            self.netshot_devices,
+        );
+        gauge(
+            &mut output,
+            "devices_to_register",
+            "The Netbox devices missing from Netshot",
+            self.to_register,
+        );
+        gauge(
+            &mut output,
+            "devices_to_disable",
+            "The Netshot devices missing from Netbox",
+            self.to_disable,
+        );
+        gauge(
+            &mut output,
+            "devices_to_enable",
+            "The disabled Netshot devices back on Netbox",
+            self.to_enable,
+        );
+        gauge(
+            &mut output,
+            "failures_total",
+            "The changes of the run which timed out, failed or were aborted",
+            self.failures,
+        );
+        gauge(
+            &mut output,
+            "last_run_success",
+            "Whether the run succeeded (1) or not (0)",
+            u8::from(self.success),
+        );
+        gauge(
+            &mut output,
+            "last_run_timestamp_seconds",
+            "The time the run ended, in seconds since the epoch",
+            finished_at.timestamp(),
+        );
+        if !self.client_certificates.is_empty() {
+            let name = "netbox2netshot_client_certificate_expiry_timestamp_seconds";
+            let _ = writeln!(
+                output,
+                "# HELP {} The expiry date of the TLS client certificate of each service, in seconds since the epoch",
+                name
+            );
+            let _ = writeln!(output, "# TYPE {} gauge", name);
+            for certificate in &self.client_certificates {
+                let _ = writeln!(
+                    output,
+                    "{}{{service=\"{}\"}} {}",
+                    name,
+                    certificate.service,
+                    certificate.expires_at.timestamp()
+                );
+            }
+        }
+        output
+    }
+
+    /// Write the metrics file, through a temporary file so the collector never reads it half written
+    #[cfg(feature = "metrics")]
+    pub fn write(&self, path: &Path, finished_at: DateTime<Utc>) -> Result<(), Error> {
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(self.render(finished_at).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, path)?;
+        log::info!("Wrote the run metrics to {}", path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn textfile_metrics() {
+        let metrics = RunMetrics {
+            netbox_devices: 120,
+            netshot_devices: 118,
+            to_register: 3,
+            to_disable: 1,
+            to_enable: 0,
+            failures: 2,
+            success: false,
+            client_certificates: vec![CertificateExpiry {
+                service: "netbox".to_string(),
+                expires_at: "2024-03-01T00:00:00Z".parse().unwrap(),
+            }],
+        };
+        let finished_at = "2024-01-01T00:00:00Z".parse().unwrap();
+        let rendered = metrics.render(finished_at);
+        assert!(rendered.starts_with(
+            "# HELP netbox2netshot_netbox_devices The Netbox devices in the scope of the synchronization\n\
+             # TYPE netbox2netshot_netbox_devices gauge\n\
+             netbox2netshot_netbox_devices 120\n"
+        ));
+        let samples: Vec<&str> = rendered
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                "netbox2netshot_netbox_devices 120",
+                "netbox2netshot_netshot_devices 118",
+                "netbox2netshot_devices_to_register 3",
+                "netbox2netshot_devices_to_disable 1",
+                "netbox2netshot_devices_to_enable 0",
+                "netbox2netshot_failures_total 2",
+                "netbox2netshot_last_run_success 0",
+                "netbox2netshot_last_run_timestamp_seconds 1704067200",
+                "netbox2netshot_client_certificate_expiry_timestamp_seconds{service=\"netbox\"} 1709251200",
+            ]
+        );
+
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-metrics-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("netbox2netshot.prom");
+        metrics.write(&path, finished_at).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), rendered);
+        assert!(!directory.join("netbox2netshot.prom.tmp").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn drift_summary() {
+        let mut metrics = RunMetrics {
+            netbox_devices: 120,
+            netshot_devices: 120,
+            ..RunMetrics::default()
+        };
+        assert!(!metrics.has_drift());
+        assert_eq!(
+            metrics.drift_summary(),
+            "drift=false to_register=0 to_disable=0 to_enable=0"
+        );
+        metrics.to_register = 3;
+        metrics.to_disable = 1;
+        assert!(metrics.has_drift());
+        assert_eq!(
+            metrics.drift_summary(),
+            "drift=true to_register=3 to_disable=1 to_enable=0"
+        );
+    }
+}
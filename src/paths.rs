@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Error, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Expand `~`, `$VAR`, `${VAR}` and `%VAR%` in a path given on the command line or in the environment
+pub fn expand(path: &str) -> Result<String, Error> {
+    expand_with(path, |name| std::env::var(name).ok())
+}
+
+/// Expand a path option, see `expand`
+pub fn expand_path(path: &str) -> Result<PathBuf, Error> {
+    expand(path).map(PathBuf::from)
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || character == '_')
+}
+
+fn expand_with<F: Fn(&str) -> Option<String>>(path: &str, lookup: F) -> Result<String, Error> {
+    let variable = |name: &str| {
+        lookup(name).ok_or_else(|| {
+            anyhow!(
+                "`{}` refers to the environment variable {} which is not set",
+                path,
+                name
+            )
+        })
+    };
+
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        let home = lookup("HOME")
+            .or_else(|| lookup("USERPROFILE"))
+            .ok_or_else(|| anyhow!("`{}` starts with ~ but no home directory is known", path))?;
+        expanded.push_str(&home);
+        rest = &rest[1..];
+    }
+
+    while let Some(position) = rest.find(['$', '%']) {
+        expanded.push_str(&rest[..position]);
+        let tail = &rest[position + 1..];
+        let (name, consumed) = if rest[position..].starts_with('%') {
+            match tail.find('%') {
+                Some(end) if is_variable_name(&tail[..end]) => (&tail[..end], end + 1),
+                _ => ("", 0),
+            }
+        } else if let Some(braced) = tail.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) if is_variable_name(&braced[..end]) => (&braced[..end], end + 2),
+                _ => ("", 0),
+            }
+        } else {
+            let end = tail
+                .find(|character: char| !character.is_ascii_alphanumeric() && character != '_')
+                .unwrap_or(tail.len());
+            (&tail[..end], end)
+        };
+        if name.is_empty() {
+            // A lone `$` or `%` is part of the path
+            expanded.push_str(&rest[position..=position]);
+        } else {
+            expanded.push_str(&variable(name)?);
+        }
+        rest = &tail[consumed..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Create the missing parent directories of an output file, naming its option when it fails
+pub fn create_parent_directory(flag: &str, path: &Path) -> Result<(), Error> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => {
+            log::info!("Creating the directory {} of {}", parent.display(), flag);
+            fs::create_dir_all(parent).map_err(|error| {
+                anyhow!(
+                    "Unable to create the directory {} of {}: {}",
+                    parent.display(),
+                    flag,
+                    error
+                )
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/netops".to_string()),
+            "USERPROFILE" => Some("C:\\Users\\netops".to_string()),
+            "ENV" => Some("prod".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expansions() {
+        for (path, expanded) in [
+            ("~/state.json", "/home/netops/state.json"),
+            ("~", "/home/netops"),
+            ("$HOME/reports/$ENV.json", "/home/netops/reports/prod.json"),
+            ("${HOME}/${ENV}_state.json", "/home/netops/prod_state.json"),
+            (
+                "%USERPROFILE%\\reports\\%ENV%.json",
+                "C:\\Users\\netops\\reports\\prod.json",
+            ),
+            ("C:\\certs\\netbox.p12", "C:\\certs\\netbox.p12"),
+            ("reports/100%.json", "reports/100%.json"),
+            ("cost$.json", "cost$.json"),
+            ("~netops/state.json", "~netops/state.json"),
+        ] {
+            assert_eq!(expand_with(path, lookup).unwrap(), expanded, "{}", path);
+        }
+
+        let windows = |name: &str| (name == "USERPROFILE").then(|| "C:\\Users\\netops".to_string());
+        assert_eq!(
+            expand_with("~\\state.json", windows).unwrap(),
+            "C:\\Users\\netops\\state.json"
+        );
+
+        let error = expand_with("$NETBOX2NETSHOT_DIR/state.json", lookup).unwrap_err();
+        assert!(error.to_string().contains("NETBOX2NETSHOT_DIR"));
+        assert!(expand_with("~/state.json", |_| None).is_err());
+    }
+
+    #[test]
+    fn parent_directories() {
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-paths-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let path = directory.join("reports").join("run.json");
+
+        create_parent_directory("--report", &path).unwrap();
+        assert!(directory.join("reports").is_dir());
+        create_parent_directory("--report", Path::new("run.json")).unwrap();
+
+        fs::write(directory.join("file"), "").unwrap();
+        let error =
+            create_parent_directory("--state-file", &directory.join("file").join("state.json"))
+                .unwrap_err();
+        assert!(error.to_string().contains("--state-file"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+use crate::rest::netshot::{Domain, NetshotVersion};
+use crate::sync::SyncPlan;
+use anyhow::Error;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::fmt;
+
+/// How a check went, from the best to the worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Severity {
+    /// The exit code of a preflight run whose worst check has this severity, as the Nagios plugins do
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Severity::Pass => 0,
+            Severity::Warn => 1,
+            Severity::Fail => 2,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Severity::Pass => "PASS",
+            Severity::Warn => "WARN",
+            Severity::Fail => "FAIL",
+        })
+    }
+}
+
+/// The outcome of a single read-only validation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckResult {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl CheckResult {
+    pub fn pass(check: &'static str, message: impl Into<String>) -> Self {
+        Self::new(check, Severity::Pass, message)
+    }
+
+    pub fn warn(check: &'static str, message: impl Into<String>) -> Self {
+        Self::new(check, Severity::Warn, message)
+    }
+
+    pub fn fail(check: &'static str, message: impl Into<String>) -> Self {
+        Self::new(check, Severity::Fail, message)
+    }
+
+    fn new(check: &'static str, severity: Severity, message: impl Into<String>) -> Self {
+        CheckResult {
+            check,
+            severity,
+            message: message.into(),
+        }
+    }
+
+    /// Pass with the given message, or fail with the error
+    pub fn from_result(check: &'static str, result: Result<(), Error>, message: &str) -> Self {
+        match result {
+            Ok(()) => Self::pass(check, message),
+            Err(error) => Self::fail(check, format!("{:#}", error)),
+        }
+    }
+}
+
+/// The worst severity of the checks, passing when there are none
+pub fn worst_severity(checks: &[CheckResult]) -> Severity {
+    checks
+        .iter()
+        .map(|check| check.severity)
+        .max()
+        .unwrap_or(Severity::Pass)
+}
+
+/// Print the checks as an aligned table
+pub fn print_table(checks: &[CheckResult]) {
+    let width = checks
+        .iter()
+        .map(|check| check.check.len())
+        .max()
+        .unwrap_or_default();
+    for check in checks {
+        println!(
+            "{:4}  {:width$}  {}",
+            check.severity,
+            check.check,
+            check.message,
+            width = width
+        );
+    }
+}
+
+/// Warn when a TLS client certificate expires within `warning_days`
+pub fn certificate_expiry(
+    check: &'static str,
+    expires_at: DateTime<Utc>,
+    warning_days: i64,
+    now: DateTime<Utc>,
+) -> CheckResult {
+    let remaining = expires_at - now;
+    if remaining < Duration::days(warning_days) {
+        CheckResult::warn(
+            check,
+            format!(
+                "The TLS client certificate expires in {} days, on {}",
+                remaining.num_days(),
+                expires_at
+            ),
+        )
+    } else {
+        CheckResult::pass(
+            check,
+            format!("The TLS client certificate expires on {}", expires_at),
+        )
+    }
+}
+
+/// Warn when the Netshot release is unknown or newer than the newest one whose payloads are known
+pub fn netshot_version(check: &'static str, version: Option<NetshotVersion>) -> CheckResult {
+    match version {
+        Some(version) if version.is_newer_than_known() => CheckResult::warn(
+            check,
+            format!(
+                "Netshot {} is newer than the releases known to netbox2netshot",
+                version
+            ),
+        ),
+        Some(version) => CheckResult::pass(check, format!("Netshot {}", version)),
+        None => CheckResult::warn(check, "The Netshot version could not be detected"),
+    }
+}
+
+/// Fail when the Netshot domain to synchronize does not exist
+pub fn domain_exists(domains: Result<Vec<Domain>, Error>, domain_id: u32) -> CheckResult {
+    let check = "netshot-domain";
+    match domains {
+        Ok(domains) => match domains.iter().find(|domain| domain.id == domain_id) {
+            Some(domain) => CheckResult::pass(
+                check,
+                format!("Domain {} ({}) exists", domain_id, domain.name),
+            ),
+            None => CheckResult::fail(
+                check,
+                format!("Domain {} does not exist on Netshot", domain_id),
+            ),
+        },
+        Err(error) => CheckResult::fail(check, format!("Unable to list the domains: {:#}", error)),
+    }
+}
+
+/// Fail when the Netbox filter selects nothing, as a typo in a filter selects everything or nothing
+pub fn netbox_inventory(devices: usize) -> CheckResult {
+    let check = "netbox-inventory";
+    if devices == 0 {
+        CheckResult::fail(check, "The Netbox filters select no device")
+    } else {
+        CheckResult::pass(
+            check,
+            format!("The Netbox filters select {} devices", devices),
+        )
+    }
+}
+
+/// Warn about the IPs shared by several Netbox devices or several Netshot devices
+pub fn duplicate_ips(plan: &SyncPlan) -> CheckResult {
+    let check = "duplicate-ips";
+    let mut messages = Vec::new();
+    for (conflicts, devices) in [
+        (&plan.netbox_conflicts, "Netbox devices"),
+        (&plan.conflicts, "Netshot devices"),
+    ] {
+        if conflicts.is_empty() {
+            continue;
+        }
+        let ips: Vec<&str> = conflicts
+            .iter()
+            .map(|conflict| conflict.ip.as_str())
+            .collect();
+        messages.push(format!(
+            "{} IPs are shared by several {}: {}",
+            ips.len(),
+            devices,
+            ips.join(", ")
+        ));
+    }
+    if messages.is_empty() {
+        return CheckResult::pass(
+            check,
+            "No IP is shared by several Netbox or Netshot devices",
+        );
+    }
+    CheckResult::warn(check, messages.join("; "))
+}
+
+/// Warn about the Netbox devices left out of the synchronization
+pub fn skipped_devices(plan: &SyncPlan) -> CheckResult {
+    let check = "skipped-devices";
+    if plan.skipped.is_empty() {
+        return CheckResult::pass(check, "No Netbox device is skipped");
+    }
+    let reasons: Vec<String> = plan
+        .skipped_by_reason()
+        .iter()
+        .map(|(reason, count)| format!("{} ({})", count, reason))
+        .collect();
+    CheckResult::warn(
+        check,
+        format!("Skipped Netbox devices: {}", reasons.join(", ")),
+    )
+}
+
+/// Sum up the changes the first run would apply
+pub fn plan_summary(plan: &SyncPlan) -> CheckResult {
+    CheckResult::pass(
+        "plan",
+        format!(
+            "{} registrations, {} disables, {} enables and {} credential updates",
+            plan.to_register.len(),
+            plan.to_disable.len(),
+            plan.to_enable.len(),
+            plan.to_update_credentials.len()
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{Conflict, SkipReason, SkippedDevice};
+    use anyhow::anyhow;
+
+    #[test]
+    fn check_severities() {
+        let now: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+        assert_eq!(
+            certificate_expiry("netbox-certificate", now + Duration::days(10), 30, now).severity,
+            Severity::Warn
+        );
+        assert_eq!(
+            certificate_expiry("netbox-certificate", now + Duration::days(60), 30, now).severity,
+            Severity::Pass
+        );
+
+        let domains = || {
+            Ok(vec![Domain {
+                id: 2,
+                name: "PAR1".to_string(),
+                description: None,
+            }])
+        };
+        assert_eq!(
+            domain_exists(domains(), 2),
+            CheckResult::pass("netshot-domain", "Domain 2 (PAR1) exists")
+        );
+        assert_eq!(domain_exists(domains(), 3).severity, Severity::Fail);
+        assert_eq!(
+            domain_exists(Err(anyhow!("Got status 500")), 2).severity,
+            Severity::Fail
+        );
+
+        assert_eq!(
+            netshot_version("netshot-version", Some(NetshotVersion::new(0, 18, 1))),
+            CheckResult::pass("netshot-version", "Netshot 0.18.1")
+        );
+        assert_eq!(
+            netshot_version("netshot-version", Some(NetshotVersion::new(0, 22, 0))).severity,
+            Severity::Warn
+        );
+        assert_eq!(
+            netshot_version("netshot-version", None).severity,
+            Severity::Warn
+        );
+
+        assert_eq!(netbox_inventory(0).severity, Severity::Fail);
+        assert_eq!(netbox_inventory(12).severity, Severity::Pass);
+
+        let mut plan = SyncPlan::default();
+        assert_eq!(duplicate_ips(&plan).severity, Severity::Pass);
+        assert_eq!(skipped_devices(&plan).severity, Severity::Pass);
+        plan.conflicts.push(Conflict {
+            ip: "1.2.3.4".to_string(),
+            devices: Vec::new(),
+        });
+        plan.skipped.push(SkippedDevice {
+            id: 1,
+            name: "no-ip".to_string(),
+            reason: SkipReason::MissingPrimaryIp,
+        });
+        assert_eq!(
+            duplicate_ips(&plan),
+            CheckResult::warn(
+                "duplicate-ips",
+                "1 IPs are shared by several Netshot devices: 1.2.3.4"
+            )
+        );
+        plan.netbox_conflicts.push(Conflict {
+            ip: "10.0.0.1".to_string(),
+            devices: Vec::new(),
+        });
+        assert_eq!(
+            duplicate_ips(&plan).message,
+            "1 IPs are shared by several Netbox devices: 10.0.0.1; 1 IPs are shared by several Netshot devices: 1.2.3.4"
+        );
+        assert_eq!(
+            skipped_devices(&plan).message,
+            "Skipped Netbox devices: 1 (missing primary IP)"
+        );
+    }
+
+    #[test]
+    fn worst_severities() {
+        assert_eq!(worst_severity(&[]), Severity::Pass);
+        let checks = vec![
+            CheckResult::pass("plan", ""),
+            CheckResult::warn("duplicate-ips", ""),
+        ];
+        assert_eq!(worst_severity(&checks), Severity::Warn);
+        assert_eq!(worst_severity(&checks).exit_code(), 1);
+        assert_eq!(
+            CheckResult::from_result("netshot-permissions", Err(anyhow!("read-only")), "").severity,
+            Severity::Fail
+        );
+        assert_eq!(Severity::Fail.exit_code(), 2);
+    }
+}
@@ -0,0 +1,753 @@
+use anyhow::{anyhow, Error, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fmt;
+use structopt::clap::{App, ErrorKind};
+
+/// The options selecting the profile, which a profile cannot set
+const PROFILE_OPTIONS: &[&str] = &["config", "profile"];
+
+/// The value of an option in the configuration file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    Scalar(String),
+    Boolean(bool),
+    Array(Vec<String>),
+}
+
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigValue::Scalar(value) => write!(f, "{}", value),
+            ConfigValue::Boolean(value) => write!(f, "{}", value),
+            ConfigValue::Array(values) => write!(f, "{}", values.join(",")),
+        }
+    }
+}
+
+/// A named bundle of options, possibly extending another profile
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub extends: Option<String>,
+    pub options: BTreeMap<String, ConfigValue>,
+}
+
+/// The configuration file, in TOML or in YAML: the options of every run, then `[profile.<name>]` sections
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    /// The options outside of any profile, which the profiles override
+    pub options: BTreeMap<String, ConfigValue>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// The profile setting an option, `None` for the options outside of any profile
+pub type OptionSource = Option<String>;
+
+/// What sets an option, to name it in the errors
+fn origin(source: &OptionSource) -> String {
+    match source {
+        Some(profile) => format!("Profile `{}`", profile),
+        None => "The configuration file".to_string(),
+    }
+}
+
+/// An option name of a YAML file, dashes and underscores alike
+fn yaml_key(key: &serde_yaml::Value) -> Result<String, Error> {
+    match key {
+        serde_yaml::Value::String(key) if is_name(key) => Ok(key.replace('_', "-")),
+        key => Err(anyhow!("invalid option name `{:?}`", key)),
+    }
+}
+
+fn yaml_scalar(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(value) => Some(value.clone()),
+        serde_yaml::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn yaml_value(key: &str, value: &serde_yaml::Value) -> Result<ConfigValue, Error> {
+    let value = match value {
+        serde_yaml::Value::Bool(value) => Some(ConfigValue::Boolean(*value)),
+        serde_yaml::Value::Sequence(values) => values
+            .iter()
+            .map(yaml_scalar)
+            .collect::<Option<Vec<String>>>()
+            .map(ConfigValue::Array),
+        value => yaml_scalar(value).map(ConfigValue::Scalar),
+    };
+    value.ok_or_else(|| anyhow!("invalid value of `{}`", key))
+}
+
+/// The options of a YAML mapping, along with the profile it extends if any
+fn yaml_options(
+    mapping: &serde_yaml::Mapping,
+    in_profile: bool,
+) -> Result<(BTreeMap<String, ConfigValue>, Option<String>), Error> {
+    let mut options = BTreeMap::new();
+    let mut extends = None;
+    for (key, value) in mapping {
+        let key = yaml_key(key)?;
+        if key == "extends" && !in_profile {
+            return Err(anyhow!("`extends` must be in a profile"));
+        } else if key == "extends" {
+            extends =
+                Some(yaml_scalar(value).ok_or_else(|| anyhow!("`extends` must name a profile"))?);
+        } else if options
+            .insert(key.clone(), yaml_value(&key, value)?)
+            .is_some()
+        {
+            return Err(anyhow!("`{}` is set twice", key));
+        }
+    }
+    Ok((options, extends))
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|character| {
+            character.is_ascii_alphanumeric() || character == '-' || character == '_'
+        })
+}
+
+impl ConfigFile {
+    /// Read the configuration file, in YAML when its extension is `.yaml` or `.yml`, in TOML otherwise
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| anyhow!("Unable to read the --config file {}: {}", path, error))?;
+        let config = if path.ends_with(".yaml") || path.ends_with(".yml") {
+            ConfigFile::parse_yaml(&text)
+        } else {
+            ConfigFile::parse(&text)
+        };
+        config.map_err(|error| anyhow!("Invalid --config file {}: {}", path, error))
+    }
+
+    /// Parse a YAML configuration file, made of options and of the `profile` mapping of the profiles
+    pub fn parse_yaml(text: &str) -> Result<Self, Error> {
+        ConfigFile::from_document(serde_yaml::from_str(text)?)
+    }
+
+    /// The configuration of a parsed file, the profiles being in its `profile` mapping
+    fn from_document(document: serde_yaml::Value) -> Result<Self, Error> {
+        let mapping = match document {
+            serde_yaml::Value::Mapping(mapping) => mapping,
+            serde_yaml::Value::Null => return Ok(ConfigFile::default()),
+            _ => return Err(anyhow!("expected a mapping of options")),
+        };
+        let profiles = mapping.get("profile").cloned();
+        let mut options = mapping;
+        options.remove("profile");
+
+        let mut config = ConfigFile {
+            options: yaml_options(&options, false)?.0,
+            profiles: BTreeMap::new(),
+        };
+        let profiles = match profiles {
+            Some(serde_yaml::Value::Mapping(profiles)) => profiles,
+            Some(_) => {
+                return Err(anyhow!(
+                    "`profile` must map the profile names to their options"
+                ))
+            }
+            None => return Ok(config),
+        };
+        for (name, profile) in &profiles {
+            let name = match name {
+                serde_yaml::Value::String(name) if is_name(name) => name.clone(),
+                name => return Err(anyhow!("invalid profile name `{:?}`", name)),
+            };
+            let (options, extends) = match profile {
+                serde_yaml::Value::Mapping(profile) => yaml_options(profile, true)
+                    .map_err(|error| anyhow!("profile `{}`: {}", name, error))?,
+                _ => return Err(anyhow!("profile `{}` must be a mapping of options", name)),
+            };
+            config.profiles.insert(name, Profile { extends, options });
+        }
+        Ok(config)
+    }
+
+    /// Parse a TOML configuration file, made of options and of the `[profile.<name>]` tables of the profiles
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        // Read into the same document as YAML, the option values being checked alike
+        let document: serde_yaml::Value = toml::from_str(text)?;
+        ConfigFile::from_document(document)
+    }
+
+    /// The options of the profile if any along with the profile setting each, the extended profiles and the options
+    /// outside of any profile being overridden
+    pub fn resolve(
+        &self,
+        name: Option<&str>,
+    ) -> Result<BTreeMap<String, (ConfigValue, OptionSource)>, Error> {
+        let mut chain: Vec<&str> = Vec::new();
+        let mut next = name;
+        while let Some(name) = next {
+            if chain.contains(&name) {
+                chain.push(name);
+                return Err(anyhow!("Profile cycle: {}", chain.join(" -> ")));
+            }
+            let profile = self.profiles.get(name).ok_or_else(|| match chain.last() {
+                Some(child) => anyhow!(
+                    "Profile `{}` extends `{}` which does not exist",
+                    child,
+                    name
+                ),
+                None => anyhow!(
+                    "Profile `{}` does not exist, the profiles are: {}",
+                    name,
+                    self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            })?;
+            chain.push(name);
+            next = profile.extends.as_deref();
+        }
+
+        let mut options: BTreeMap<String, (ConfigValue, OptionSource)> = self
+            .options
+            .iter()
+            .map(|(key, value)| (key.clone(), (value.clone(), None)))
+            .collect();
+        for name in chain.into_iter().rev() {
+            for (key, value) in &self.profiles[name].options {
+                options.insert(key.clone(), (value.clone(), Some(name.to_string())));
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// The value of a long option given on the command line, before the options are parsed
+pub fn arg_value(args: &[OsString], long: &str) -> Option<String> {
+    let flag = format!("--{}", long);
+    let prefix = format!("--{}=", long);
+    let mut args = args
+        .iter()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().map(|value| value.into_owned());
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// The environment variable of an option, named after it as structopt does
+fn env_name(name: &str) -> String {
+    name.to_uppercase().replace('-', "_")
+}
+
+/// The arguments setting an option to its value in the configuration file, the repeatable options being given once
+/// per value
+fn option_args(name: &str, value: &ConfigValue) -> Vec<OsString> {
+    match value {
+        ConfigValue::Boolean(true) => vec![format!("--{}", name).into()],
+        ConfigValue::Boolean(false) => Vec::new(),
+        ConfigValue::Scalar(value) => vec![format!("--{}={}", name, value).into()],
+        ConfigValue::Array(values) => values
+            .iter()
+            .map(|value| format!("--{}={}", name, value).into())
+            .collect(),
+    }
+}
+
+/// Check an option of the configuration file against the command line parser, on its own
+fn check_option(
+    app: &App,
+    program: &OsString,
+    name: &str,
+    value: &ConfigValue,
+    source: &OptionSource,
+) -> Result<(), Error> {
+    // A flag set to false is still checked
+    let args = match value {
+        ConfigValue::Boolean(false) => vec![format!("--{}", name).into()],
+        value => option_args(name, value),
+    };
+    let matches = match app
+        .clone()
+        .get_matches_from_safe(std::iter::once(program).chain(&args))
+    {
+        Ok(matches) => matches,
+        // The options it requires may be given otherwise
+        Err(error) if error.kind == ErrorKind::MissingRequiredArgument => return Ok(()),
+        Err(error) => {
+            return Err(match error.kind {
+                ErrorKind::UnknownArgument => {
+                    anyhow!("{} sets the unknown option `{}`", origin(source), name)
+                }
+                ErrorKind::UnexpectedMultipleUsage => anyhow!(
+                    "{} sets `{}` to several values while it takes one: {}",
+                    origin(source),
+                    name,
+                    value
+                ),
+                ErrorKind::EmptyValue => {
+                    anyhow!("{} sets the option `{}` to a boolean", origin(source), name)
+                }
+                _ => anyhow!(
+                    "{} sets `{}` to the invalid value `{}`",
+                    origin(source),
+                    name,
+                    value
+                ),
+            })
+        }
+    };
+    // An alias is parsed under the name of its option
+    if matches.occurrences_of(name) == 0 {
+        return Err(anyhow!(
+            "{} sets the unknown option `{}`",
+            origin(source),
+            name
+        ));
+    }
+    // A flag ignores the value of `--flag=value`, an option given alone takes its default
+    let takes_value = matches
+        .values_of(name)
+        .is_some_and(|mut values| values.next().is_some());
+    match value {
+        ConfigValue::Boolean(_) if takes_value => Err(anyhow!(
+            "{} sets the option `{}` to a boolean",
+            origin(source),
+            name
+        )),
+        ConfigValue::Scalar(_) | ConfigValue::Array(_) if !takes_value => Err(anyhow!(
+            "{} sets the flag `{}` to a non-boolean",
+            origin(source),
+            name
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Insert the options of the configuration file which are not given on the command line nor in the environment right
+/// after the program name, and tell which profile each inserted option comes from. The options are checked and
+/// found on the command line by parsing them as clap does.
+pub fn apply_profile(
+    app: &App,
+    args: Vec<OsString>,
+    options: &BTreeMap<String, (ConfigValue, OptionSource)>,
+    is_set: impl Fn(&str) -> bool,
+) -> Result<(Vec<OsString>, HashMap<String, OptionSource>), Error> {
+    let program = args.first().cloned().unwrap_or_default();
+    let mut inserted: BTreeMap<&str, (Vec<OsString>, &OptionSource)> = BTreeMap::new();
+
+    for (name, (value, source)) in options {
+        if PROFILE_OPTIONS.contains(&name.as_str()) {
+            return Err(anyhow!(
+                "{} sets the unknown option `{}`",
+                origin(source),
+                name
+            ));
+        }
+        check_option(app, &program, name, value, source)?;
+        // The flags have no environment variable
+        let from_env = !matches!(value, ConfigValue::Boolean(_)) && is_set(&env_name(name));
+        let option_args = option_args(name, value);
+        if !from_env && !option_args.is_empty() {
+            inserted.insert(name, (option_args, source));
+        }
+    }
+
+    // The options given on the command line too are refused as given twice, until only the file gives them. The
+    // repeatable ones take the values of both.
+    let with_profile = |inserted: &BTreeMap<&str, (Vec<OsString>, &OptionSource)>| {
+        let mut args = args.iter().cloned();
+        args.next()
+            .into_iter()
+            .chain(inserted.values().flat_map(|(args, _)| args.clone()))
+            .chain(args)
+            .collect::<Vec<OsString>>()
+    };
+    loop {
+        match app.clone().get_matches_from_safe(with_profile(&inserted)) {
+            Ok(matches) => {
+                inserted.retain(|name, (option_args, _)| {
+                    matches.occurrences_of(name) <= option_args.len() as u64
+                });
+                break;
+            }
+            Err(error) if error.kind == ErrorKind::UnexpectedMultipleUsage => {
+                let given = error
+                    .info
+                    .iter()
+                    .flatten()
+                    .find_map(|name| inserted.remove(name.as_str()));
+                if given.is_none() {
+                    break;
+                }
+            }
+            // Left to the parsing of the command line to report
+            Err(_) => break,
+        }
+    }
+
+    let sources = inserted
+        .iter()
+        .map(|(name, (_, source))| (name.to_string(), (*source).clone()))
+        .collect();
+    Ok((with_profile(&inserted), sources))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::clap::Arg;
+
+    const CONFIG: &str = r#"
+# Shared by every profile
+[profile.base]
+netbox_url = "https://netbox.example.net" # the URL
+verify-filter = true
+
+[profile.prod]
+extends = "base"
+changes-budget = 50
+ensure-credential-set-ids = [1, 2]
+
+[profile.lab]
+extends = 'base'
+netbox-url = 'https://netbox.lab#1'
+verify-filter = false
+"#;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        std::iter::once("netbox2netshot")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect()
+    }
+
+    fn app() -> App<'static, 'static> {
+        App::new("netbox2netshot")
+            .arg(Arg::with_name("verify-filter").long("verify-filter"))
+            .arg(
+                Arg::with_name("netbox-url")
+                    .long("netbox-url")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("changes-budget")
+                    .long("changes-budget")
+                    .takes_value(true)
+                    .env("CHANGES_BUDGET"),
+            )
+            .arg(
+                Arg::with_name("netbox-devices-filter")
+                    .long("netbox-devices-filter")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("ensure-credential-set-ids")
+                    .long("ensure-credential-set-ids")
+                    .takes_value(true)
+                    .multiple(true)
+                    .use_delimiter(true),
+            )
+            .arg(
+                Arg::with_name("profile-test-threshold")
+                    .long("profile-test-threshold")
+                    .takes_value(true)
+                    .default_value("0")
+                    .env("PROFILE_TEST_THRESHOLD"),
+            )
+            .arg(
+                Arg::with_name("netshot-domain-id")
+                    .long("netshot-domain-id")
+                    .takes_value(true)
+                    .default_value("1"),
+            )
+            .arg(
+                Arg::with_name("netshot-user")
+                    .long("netshot-user")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("netshot-password")
+                    .long("netshot-password")
+                    .takes_value(true)
+                    .requires("netshot-user"),
+            )
+            .arg(
+                Arg::with_name("report")
+                    .long("report")
+                    .takes_value(true)
+                    .visible_alias("report-file"),
+            )
+    }
+
+    #[test]
+    fn profile_resolution() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+        let prod = config.resolve(Some("prod")).unwrap();
+        assert_eq!(
+            prod["netbox-url"],
+            (
+                ConfigValue::Scalar("https://netbox.example.net".to_string()),
+                Some("base".to_string())
+            )
+        );
+        assert_eq!(
+            prod["ensure-credential-set-ids"].0,
+            ConfigValue::Array(vec!["1".to_string(), "2".to_string()])
+        );
+        let lab = config.resolve(Some("lab")).unwrap();
+        assert_eq!(lab["netbox-url"].0.to_string(), "https://netbox.lab#1");
+        assert_eq!(
+            lab["verify-filter"],
+            (ConfigValue::Boolean(false), Some("lab".to_string()))
+        );
+        assert!(config.resolve(None).unwrap().is_empty());
+
+        assert_eq!(
+            config.resolve(Some("staging")).unwrap_err().to_string(),
+            "Profile `staging` does not exist, the profiles are: base, lab, prod"
+        );
+        let cycle = ConfigFile::parse("[profile.a]\nextends = \"b\"\n[profile.b]\nextends = \"a\"")
+            .unwrap();
+        assert_eq!(
+            cycle.resolve(Some("a")).unwrap_err().to_string(),
+            "Profile cycle: a -> b -> a"
+        );
+
+        for (text, error) in [
+            ("extends = \"base\"", "`extends` must be in a profile"),
+            ("[prod]\ncheck = true", "invalid value of `prod`"),
+            (
+                "[profile.a]\nnetbox-url = \"x\"\nnetbox_url = \"y\"",
+                "profile `a`: `netbox-url` is set twice",
+            ),
+            (
+                "netbox-url = { host = \"x\" }",
+                "invalid value of `netbox-url`",
+            ),
+        ] {
+            assert_eq!(ConfigFile::parse(text).unwrap_err().to_string(), error);
+        }
+        // The syntax errors are located
+        let error = ConfigFile::parse("[profile.a]\ncheck = yes").unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{}", error);
+        let error = ConfigFile::parse("[profile.a]\ncheck = true\ncheck = false").unwrap_err();
+        assert!(error.to_string().contains("duplicate"), "{}", error);
+
+        // Any TOML, such as the arrays over several lines and the escapes
+        let config = ConfigFile::parse(
+            "netbox-devices-filter = [\n  \"role=edge\",\n  \"role=core\", # the cores\n]\nnetbox-url = \"https://netbox.example.net/\\u0023\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.options["netbox-devices-filter"],
+            ConfigValue::Array(vec!["role=edge".to_string(), "role=core".to_string()])
+        );
+        assert_eq!(
+            config.options["netbox-url"].to_string(),
+            "https://netbox.example.net/#"
+        );
+    }
+
+    #[test]
+    fn profile_arguments() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+        let prod = config.resolve(Some("prod")).unwrap();
+
+        // The options given on the command line, under any form, or in the environment are left out
+        for cli in [
+            &["--netbox-url", "https://netbox.cli", "--verify-filter"][..],
+            &["--netbox-url=https://netbox.cli", "--verify-filter"][..],
+        ] {
+            let (with_profile, sources) =
+                apply_profile(&app(), args(cli), &prod, |env| env == "CHANGES_BUDGET").unwrap();
+            let mut expected = vec![
+                "--ensure-credential-set-ids=1",
+                "--ensure-credential-set-ids=2",
+            ];
+            expected.extend(cli);
+            assert_eq!(with_profile, args(&expected));
+            assert_eq!(
+                sources,
+                HashMap::from([(
+                    "ensure-credential-set-ids".to_string(),
+                    Some("prod".to_string())
+                )])
+            );
+        }
+        let (with_profile, sources) = apply_profile(&app(), args(&[]), &prod, |_| false).unwrap();
+        assert_eq!(
+            with_profile,
+            args(&[
+                "--changes-budget=50",
+                "--ensure-credential-set-ids=1",
+                "--ensure-credential-set-ids=2",
+                "--netbox-url=https://netbox.example.net",
+                "--verify-filter",
+            ])
+        );
+        assert_eq!(sources["verify-filter"], Some("base".to_string()));
+
+        // The command line replaces the values of a repeatable option
+        let filters =
+            ConfigFile::parse("netbox-devices-filter = [\"role=edge\", \"role=core\"]").unwrap();
+        let filters = filters.resolve(None).unwrap();
+        let (with_profile, _) = apply_profile(&app(), args(&[]), &filters, |_| false).unwrap();
+        assert_eq!(
+            with_profile,
+            args(&[
+                "--netbox-devices-filter=role=edge",
+                "--netbox-devices-filter=role=core"
+            ])
+        );
+        let cli = ["--netbox-devices-filter", "role=spine"];
+        let (with_profile, sources) =
+            apply_profile(&app(), args(&cli), &filters, |_| false).unwrap();
+        assert_eq!(with_profile, args(&cli));
+        assert!(sources.is_empty());
+
+        // The options required by the command line can come from the file
+        let user = ConfigFile::parse("netshot-user = \"sync\"").unwrap();
+        let (with_profile, _) = apply_profile(
+            &app(),
+            args(&["--netshot-password=secret"]),
+            &user.resolve(None).unwrap(),
+            |_| false,
+        )
+        .unwrap();
+        assert_eq!(
+            with_profile,
+            args(&["--netshot-user=sync", "--netshot-password=secret"])
+        );
+
+        for (text, error) in [
+            (
+                "[profile.a]\nnetbox-uri = \"x\"",
+                "Profile `a` sets the unknown option `netbox-uri`",
+            ),
+            (
+                "[profile.a]\nreport-file = \"x\"",
+                "Profile `a` sets the unknown option `report-file`",
+            ),
+            (
+                "[profile.a]\nverify-filter = \"yes\"",
+                "Profile `a` sets the flag `verify-filter` to a non-boolean",
+            ),
+            (
+                "[profile.a]\nnetbox-url = true",
+                "Profile `a` sets the option `netbox-url` to a boolean",
+            ),
+            (
+                "[profile.a]\nnetbox-url = [\"x\", \"y\"]",
+                "Profile `a` sets `netbox-url` to several values while it takes one: x,y",
+            ),
+        ] {
+            let config = ConfigFile::parse(text).unwrap();
+            assert_eq!(
+                apply_profile(
+                    &app(),
+                    args(&[]),
+                    &config.resolve(Some("a")).unwrap(),
+                    |_| false
+                )
+                .unwrap_err()
+                .to_string(),
+                error
+            );
+        }
+        assert_eq!(
+            arg_value(&args(&["--profile", "prod"]), "profile").as_deref(),
+            Some("prod")
+        );
+        assert_eq!(
+            arg_value(&args(&["--profile=lab"]), "profile").as_deref(),
+            Some("lab")
+        );
+        assert_eq!(arg_value(&args(&["--", "--profile=lab"]), "profile"), None);
+    }
+
+    #[test]
+    fn config_precedence() {
+        let yaml = r#"
+netbox_url: https://netbox.file
+profile-test-threshold: 5%
+verify-filter: true
+ensure-credential-set-ids: [1, 2]
+profile:
+  backbone:
+    profile-test-threshold: 10%
+"#;
+        let toml = "netbox_url = \"https://netbox.file\"\nprofile-test-threshold = \"5%\"\nverify-filter = true\nensure-credential-set-ids = [1, 2]\n\n[profile.backbone]\nprofile-test-threshold = \"10%\"\n";
+        let config = ConfigFile::parse_yaml(yaml).unwrap();
+        assert_eq!(config, ConfigFile::parse(toml).unwrap());
+
+        let matches = |cli: &[&str], profile: Option<&str>| {
+            let options = config.resolve(profile).unwrap();
+            let (args, _) = apply_profile(&app(), args(cli), &options, |env| {
+                std::env::var_os(env).is_some()
+            })
+            .unwrap();
+            app().get_matches_from(args)
+        };
+
+        // The file over the default
+        let from_file = matches(&[], None);
+        assert_eq!(from_file.value_of("profile-test-threshold"), Some("5%"));
+        assert_eq!(from_file.value_of("netshot-domain-id"), Some("1"));
+        assert_eq!(
+            from_file.values_of_lossy("ensure-credential-set-ids"),
+            Some(vec!["1".to_string(), "2".to_string()])
+        );
+        assert!(from_file.is_present("verify-filter"));
+        assert_eq!(
+            matches(&[], Some("backbone")).value_of("profile-test-threshold"),
+            Some("10%")
+        );
+        // The environment over the file, the command line over both
+        std::env::set_var("PROFILE_TEST_THRESHOLD", "20%");
+        assert_eq!(
+            matches(&[], Some("backbone")).value_of("profile-test-threshold"),
+            Some("20%")
+        );
+        assert_eq!(
+            matches(&["--profile-test-threshold", "30%"], Some("backbone"))
+                .value_of("profile-test-threshold"),
+            Some("30%")
+        );
+        std::env::remove_var("PROFILE_TEST_THRESHOLD");
+        assert_eq!(
+            matches(&["--netbox-url=https://netbox.cli"], None).value_of("netbox-url"),
+            Some("https://netbox.cli")
+        );
+
+        let unknown = ConfigFile::parse_yaml("netbox-uri: https://netbox.file").unwrap();
+        assert_eq!(
+            apply_profile(&app(), args(&[]), &unknown.resolve(None).unwrap(), |_| {
+                false
+            })
+            .unwrap_err()
+            .to_string(),
+            "The configuration file sets the unknown option `netbox-uri`"
+        );
+        for (text, error) in [
+            ("- check", "expected a mapping of options"),
+            ("netbox-url: {host: x}", "invalid value of `netbox-url`"),
+            ("netbox-url: x\nnetbox_url: y", "`netbox-url` is set twice"),
+            (
+                "profile: [prod]",
+                "`profile` must map the profile names to their options",
+            ),
+            (
+                "profile:\n  prod:\n    extends: [base]",
+                "profile `prod`: `extends` must name a profile",
+            ),
+        ] {
+            assert_eq!(ConfigFile::parse_yaml(text).unwrap_err().to_string(), error);
+        }
+    }
+}
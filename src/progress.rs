@@ -0,0 +1,81 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// The bars of the run, drawn together on stderr so that the concurrent domains do not overwrite each other
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Draw the progress bars, unless stderr is not a terminal: the piped output and the log files are never written to
+pub fn enable() {
+    if std::io::stderr().is_terminal() {
+        BARS.get_or_init(MultiProgress::new);
+    }
+}
+
+/// The progress of a long fetch or of the changes of an action, cleared when dropped
+pub(crate) struct Progress(ProgressBar);
+
+impl Progress {
+    /// Start a progress, a counter until its total is known. Nothing is drawn unless enabled.
+    pub fn start(message: String, total: Option<usize>) -> Self {
+        let bar = match BARS.get() {
+            Some(bars) => bars.add(ProgressBar::new_spinner()),
+            None => ProgressBar::hidden(),
+        };
+        bar.set_style(
+            ProgressStyle::with_template("{spinner} {msg}: {pos}")
+                .expect("The progress counter template is valid"),
+        );
+        bar.set_message(message);
+        let progress = Progress(bar);
+        if let Some(total) = total {
+            progress.total(total);
+        }
+        progress
+    }
+
+    /// Set the total, e.g. once announced by the first page of a fetch
+    pub fn total(&self, total: usize) {
+        if self.0.length() != Some(total as u64) {
+            self.0.set_length(total as u64);
+            self.0.set_style(
+                ProgressStyle::with_template("{msg}: [{bar:40}] {pos}/{len} ({eta})")
+                    .expect("The progress bar template is valid")
+                    .progress_chars("=> "),
+            );
+        }
+    }
+
+    pub fn advance(&self, count: usize) {
+        self.0.inc(count as u64);
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.0.finish_and_clear();
+        if let Some(bars) = BARS.get() {
+            bars.remove(&self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counted_progress() {
+        // Never drawn as the tests do not enable the bars
+        let progress = Progress::start("Fetching the Netbox devices".to_string(), None);
+        assert!(progress.0.is_hidden());
+        assert_eq!(progress.0.length(), None);
+        progress.advance(1000);
+        progress.total(9000);
+        progress.advance(1000);
+        assert_eq!(
+            (progress.0.position(), progress.0.length()),
+            (2000, Some(9000))
+        );
+    }
+}
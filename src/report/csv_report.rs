@@ -0,0 +1,201 @@
+use super::{Action, ActionOutcome, ActionStatus};
+use crate::sync::SyncPlan;
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A row of the CSV report, a planned or applied action on a device
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Row {
+    timestamp: DateTime<Utc>,
+    action: Action,
+    ip: String,
+    hostname: String,
+    domain_id: u32,
+    /// planned, success, failed or skipped
+    result: &'static str,
+    error: String,
+}
+
+/// The actions of a run as CSV, one row per device, written at the end of the run over the previous report
+#[derive(Debug)]
+pub struct CsvReport {
+    path: PathBuf,
+    rows: Mutex<Vec<Row>>,
+}
+
+impl CsvReport {
+    pub fn new(path: &Path) -> Self {
+        CsvReport {
+            path: path.to_path_buf(),
+            rows: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Add the actions planned in a domain, for the runs in check mode
+    pub fn record_plan(&self, domain_id: u32, plan: &SyncPlan, planned_at: DateTime<Utc>) {
+        let mut rows = self.rows.lock().unwrap();
+        for (action, devices) in plan.actions() {
+            rows.extend(devices.iter().map(|device| Row {
+                timestamp: planned_at,
+                action,
+                ip: device.ip.clone(),
+                hostname: device.name.clone(),
+                domain_id,
+                result: "planned",
+                error: String::new(),
+            }));
+        }
+    }
+
+    /// Add an applied action
+    pub fn record_outcome(&self, outcome: &ActionOutcome) {
+        let result = match outcome.status {
+            ActionStatus::Applied | ActionStatus::Unchanged => "success",
+            ActionStatus::Skipped => "skipped",
+            ActionStatus::Timeout | ActionStatus::Failed | ActionStatus::Aborted => "failed",
+        };
+        self.rows.lock().unwrap().push(Row {
+            timestamp: outcome.timestamp,
+            action: outcome.action,
+            ip: outcome.ip.clone(),
+            hostname: outcome.name.clone(),
+            domain_id: outcome.domain_id,
+            result,
+            error: outcome.error.clone().unwrap_or_default(),
+        });
+    }
+
+    /// The report as CSV, with a header even when there is no action
+    fn render(&self) -> Result<Vec<u8>, Error> {
+        let rows = self.rows.lock().unwrap();
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        writer.write_record([
+            "timestamp",
+            "action",
+            "ip",
+            "hostname",
+            "domain_id",
+            "result",
+            "error",
+        ])?;
+        for row in rows.iter() {
+            writer.serialize(row)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|error| anyhow!("Unable to write the CSV report: {}", error))
+    }
+
+    /// Write the report, through a temporary file so that it is never read half written
+    pub fn write(&self) -> Result<(), Error> {
+        let mut temporary_path = self.path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        fs::write(&temporary_path, self.render()?)?;
+        fs::rename(&temporary_path, &self.path)?;
+        log::info!("CSV report written to {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rest::{netbox, netshot};
+    use crate::sync::{self, PlannedDevice};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn planned_and_applied_actions() {
+        let netbox_devices: Vec<netbox::Device> = serde_json::from_value(json!([
+            {"id": 1, "name": "edge-1, spare", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}},
+            {"id": 2, "name": "core-\"1\"", "primary_ip4": {"id": 2, "address": "1.2.3.5/32"}},
+            {"id": 3, "name": "access-1", "primary_ip4": {"id": 3, "address": "1.2.3.6/32"}}
+        ]))
+        .unwrap();
+        let netshot_devices: Vec<netshot::Device> = serde_json::from_value(json!([
+            {"id": 10, "name": "access-1", "mgmtAddress": {"ip": "1.2.3.6"}, "status": "DISABLED"},
+            {"id": 11, "name": "old-1", "mgmtAddress": {"ip": "1.2.3.7"}, "status": "INPRODUCTION"}
+        ]))
+        .unwrap();
+        let plan = sync::plan(
+            &netbox_devices,
+            &netshot_devices,
+            &sync::PlanOptions::default(),
+        );
+
+        let planned_at = "2024-01-01T00:00:00Z".parse().unwrap();
+        let report = CsvReport::new(Path::new("actions.csv"));
+        report.record_plan(2, &plan, planned_at);
+        assert_eq!(
+            String::from_utf8(report.render().unwrap()).unwrap(),
+            "timestamp,action,ip,hostname,domain_id,result,error\n\
+             2024-01-01T00:00:00Z,register,1.2.3.4,\"edge-1, spare\",2,planned,\n\
+             2024-01-01T00:00:00Z,register,1.2.3.5,\"core-\"\"1\"\"\",2,planned,\n\
+             2024-01-01T00:00:00Z,disable,1.2.3.7,old-1,2,planned,\n\
+             2024-01-01T00:00:00Z,enable,1.2.3.6,access-1,2,planned,\n"
+        );
+
+        let device = |ip: &str, name: &str| PlannedDevice {
+            ip: ip.to_string(),
+            name: name.to_string(),
+            netshot: None,
+            disabled_by: None,
+            prefix_length: None,
+            parent: None,
+            previous_failures: None,
+            reason: None,
+            comments: None,
+        };
+        let report = CsvReport::new(Path::new("actions.csv"));
+        let mut applied = ActionOutcome::new(
+            2,
+            Action::Register,
+            &device("1.2.3.4", "edge-1, spare"),
+            Some(12),
+            Duration::from_millis(30),
+            &Ok(true),
+        );
+        applied.timestamp = planned_at;
+        let mut failed = ActionOutcome::new(
+            2,
+            Action::Disable,
+            &device("1.2.3.7", "old-1"),
+            Some(11),
+            Duration::from_millis(30),
+            &Err(anyhow!("Got status 500, \"boom\"")),
+        );
+        failed.timestamp = planned_at;
+        report.record_outcome(&applied);
+        report.record_outcome(&failed);
+        assert_eq!(
+            String::from_utf8(report.render().unwrap()).unwrap(),
+            "timestamp,action,ip,hostname,domain_id,result,error\n\
+             2024-01-01T00:00:00Z,register,1.2.3.4,\"edge-1, spare\",2,success,\n\
+             2024-01-01T00:00:00Z,disable,1.2.3.7,old-1,2,failed,\"Got status 500, \"\"boom\"\"\"\n"
+        );
+
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-csv-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("actions.csv");
+        fs::write(&path, "previous run").unwrap();
+        let report = CsvReport::new(&path);
+        report.record_outcome(&applied);
+        report.write().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            String::from_utf8(report.render().unwrap()).unwrap()
+        );
+        assert!(!directory.join("actions.csv.tmp").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
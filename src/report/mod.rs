@@ -0,0 +1,825 @@
+use crate::rest::helpers::{is_timeout, CertificateExpiry, ReadOnlyViolation};
+use crate::rest::netshot::PrunedGroup;
+use crate::rest::optional::DegradedFeature;
+use crate::rest::snapshot::{Consistency, FetchSnapshot};
+use crate::rest::stats::EndpointSummary;
+use crate::state::{FailureRecord, PendingRegistration};
+use crate::sync::{PlannedDevice, SyncPlan};
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use v1::{DomainPlan, DomainSummary, Report};
+
+#[cfg(feature = "csv-report")]
+pub mod csv_report;
+pub mod v1;
+
+/// What the report tells about the run besides the plans and actions of the audit log
+#[derive(Debug, Default)]
+pub struct RunDetails {
+    pub read_only: bool,
+    pub change_limits_exceeded: Option<String>,
+    pub http_requests: Option<Vec<EndpointSummary>>,
+    pub chronic_failures: Vec<FailureRecord>,
+    pub snapshots: Vec<FetchSnapshot>,
+    pub consistency: Consistency,
+    pub stalled_registrations: Vec<PendingRegistration>,
+    pub degraded_features: Vec<DegradedFeature>,
+    pub pruned_groups: Vec<PrunedGroup>,
+    pub netbox_devices_filter: Option<String>,
+    pub client_certificates: Vec<CertificateExpiry>,
+    pub netshot_version: Option<String>,
+    pub netshot_apply_version: Option<String>,
+}
+
+/// The file formats of the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+impl FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            "yaml" => Ok(ReportFormat::Yaml),
+            _ => Err(anyhow!(
+                "Unknown report format `{}`, expected json or yaml",
+                s
+            )),
+        }
+    }
+}
+
+/// Why the devices of the action lists of a plan are in them, unless they tell it with their `disabled_by`
+const PLAN_REASONS: &[(Action, &str)] = &[
+    (Action::Register, "missing on Netshot"),
+    (Action::Enable, "disabled on Netshot but present on Netbox"),
+    (
+        Action::UpdateCredentials,
+        "not using the --ensure-credential-set-ids",
+    ),
+    (
+        Action::FixPrefixLength,
+        "prefix length differing from Netbox",
+    ),
+    (Action::UpdateAddress, "re-addressed on Netbox"),
+    (Action::Rename, "renamed on Netbox"),
+    (Action::UpdateAttributes, "attributes differing from Netbox"),
+    (Action::RemoveFromGroup, "back on Netbox"),
+];
+
+/// The plan of a domain as recorded in the report, each planned device along with the reason of its action
+pub fn reported_plan(sync_plan: &SyncPlan) -> SyncPlan {
+    let mut plan = sync_plan.clone();
+    let annotate = |devices: &mut Vec<PlannedDevice>, reason: Option<&str>| {
+        for device in devices {
+            device.reason = device.disabled_by.as_deref().or(reason).map(str::to_string);
+        }
+    };
+    for (action, devices) in plan.actions_mut() {
+        let reason = PLAN_REASONS
+            .iter()
+            .find(|(planned, _)| *planned == action)
+            .map(|(_, reason)| *reason);
+        annotate(devices, reason);
+    }
+    annotate(&mut plan.deferred_disables, None);
+    annotate(&mut plan.deferred_registrations, None);
+    annotate(&mut plan.out_of_scope, None);
+    plan
+}
+
+/// A change applied to Netshot
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Action {
+    Register,
+    Disable,
+    Enable,
+    UpdateCredentials,
+    FixPrefixLength,
+    UpdateAddress,
+    Rename,
+    UpdateAttributes,
+    AddToGroup,
+    RemoveFromGroup,
+    Purge,
+    PurgeDisabled,
+}
+
+/// How an action went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionStatus {
+    Applied,
+    Unchanged,
+    /// Not attempted as the writes to the service are disabled
+    Skipped,
+    Timeout,
+    Failed,
+    /// Not attempted as Netshot refused an earlier action of the category for lack of privileges
+    Aborted,
+}
+
+/// The outcome of an action on a single device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[non_exhaustive]
+pub struct ActionOutcome {
+    pub timestamp: DateTime<Utc>,
+    pub domain_id: u32,
+    pub action: Action,
+    pub ip: String,
+    pub name: String,
+    pub status: ActionStatus,
+    #[serde(default)]
+    pub netshot_id: Option<u32>,
+    #[serde(default)]
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The name of the parent device, for the Netbox child devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+/// An entry of the incremental audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "entry", rename_all = "snake_case")]
+pub enum AuditEntry {
+    Plan {
+        domain_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        domain_name: Option<String>,
+        plan: Box<SyncPlan>,
+    },
+    Action(ActionOutcome),
+}
+
+/// The number of actions per status
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+#[non_exhaustive]
+pub struct ReportSummary {
+    pub registered: usize,
+    pub disabled: usize,
+    pub enabled: usize,
+    pub credentials_updated: usize,
+    pub prefixes_fixed: usize,
+    #[serde(default)]
+    pub addresses_updated: usize,
+    #[serde(default)]
+    pub renamed: usize,
+    #[serde(default)]
+    pub attributes_updated: usize,
+    pub added_to_group: usize,
+    pub removed_from_group: usize,
+    pub purged: usize,
+    /// The devices deleted after staying disabled for longer than --purge-disabled-after
+    #[serde(default)]
+    pub purged_disabled: usize,
+    pub unchanged: usize,
+    /// The actions not attempted as the writes are disabled
+    pub skipped: usize,
+    pub timeouts: usize,
+    pub failures: usize,
+    /// The actions not attempted as Netshot refused an earlier one of their category for lack of privileges
+    #[serde(default)]
+    pub aborted: usize,
+    pub failed_by_action: BTreeMap<Action, usize>,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Action::Register => "register",
+            Action::Disable => "disable",
+            Action::Enable => "enable",
+            Action::UpdateCredentials => "update_credentials",
+            Action::FixPrefixLength => "fix_prefix_length",
+            Action::UpdateAddress => "update_address",
+            Action::Rename => "rename",
+            Action::UpdateAttributes => "update_attributes",
+            Action::AddToGroup => "add_to_group",
+            Action::RemoveFromGroup => "remove_from_group",
+            Action::Purge => "purge",
+            Action::PurgeDisabled => "purge_disabled",
+        };
+        f.write_str(action)
+    }
+}
+
+/// Quote a key=value log value when it would not be parsable as is
+fn log_value(value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("{:?}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for ActionOutcome {
+    /// The outcome as a single key=value line, for log-based alerting
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "action={} domain_id={} ip={} name={} result={}",
+            self.action,
+            self.domain_id,
+            log_value(&self.ip),
+            log_value(&self.name),
+            self.result()
+        )?;
+        if let Some(parent) = &self.parent {
+            write!(f, " parent={}", log_value(parent))?;
+        }
+        if let Some(netshot_id) = self.netshot_id {
+            write!(f, " netshot_id={}", netshot_id)?;
+        }
+        write!(f, " duration_ms={}", self.duration_ms)?;
+        if let Some(error) = &self.error {
+            write!(f, " error={:?}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// The error of the actions aborted for lack of privileges
+const ABORTED_ERROR: &str = "aborted: insufficient privileges";
+
+impl ActionOutcome {
+    /// The short result of the action, as logged
+    pub fn result(&self) -> &'static str {
+        match self.status {
+            ActionStatus::Applied => "ok",
+            ActionStatus::Unchanged => "unchanged",
+            ActionStatus::Skipped => "skipped",
+            ActionStatus::Timeout => "timeout",
+            ActionStatus::Failed => "error",
+            ActionStatus::Aborted => "aborted",
+        }
+    }
+
+    /// Did the action fail, time out or get aborted
+    pub fn failed(&self) -> bool {
+        matches!(
+            self.status,
+            ActionStatus::Timeout | ActionStatus::Failed | ActionStatus::Aborted
+        )
+    }
+
+    /// The level of the log line of the action, the failures being warnings
+    pub fn level(&self) -> log::Level {
+        match self.failed() {
+            true => log::Level::Warn,
+            false => log::Level::Info,
+        }
+    }
+
+    /// Build the outcome of an action from its result, telling whether the device was changed
+    pub fn new(
+        domain_id: u32,
+        action: Action,
+        device: &PlannedDevice,
+        netshot_id: Option<u32>,
+        duration: Duration,
+        result: &Result<bool, Error>,
+    ) -> Self {
+        let (status, error) = match result {
+            Ok(true) => (ActionStatus::Applied, None),
+            Ok(false) => (ActionStatus::Unchanged, None),
+            Err(error) if error.is::<ReadOnlyViolation>() => (
+                ActionStatus::Skipped,
+                error.downcast_ref::<ReadOnlyViolation>().map(|violation| {
+                    format!("{} writes disabled", violation.service.to_lowercase())
+                }),
+            ),
+            Err(error) if is_timeout(error) => (ActionStatus::Timeout, Some(error.to_string())),
+            Err(error) => (ActionStatus::Failed, Some(error.to_string())),
+        };
+        ActionOutcome {
+            timestamp: Utc::now(),
+            domain_id,
+            action,
+            ip: device.ip.clone(),
+            name: device.name.clone(),
+            status,
+            netshot_id,
+            duration_ms: duration.as_millis() as u64,
+            error,
+            parent: device.parent.clone(),
+        }
+    }
+
+    /// The outcome of an action not attempted, as an earlier one of its category was refused for lack of privileges
+    pub fn aborted(
+        domain_id: u32,
+        action: Action,
+        device: &PlannedDevice,
+        netshot_id: Option<u32>,
+    ) -> Self {
+        ActionOutcome {
+            status: ActionStatus::Aborted,
+            error: Some(ABORTED_ERROR.to_string()),
+            ..ActionOutcome::new(
+                domain_id,
+                action,
+                device,
+                netshot_id,
+                Duration::ZERO,
+                &Ok(false),
+            )
+        }
+    }
+}
+
+impl ReportSummary {
+    /// Count the actions per status
+    pub fn from_outcomes(outcomes: &[ActionOutcome]) -> Self {
+        let mut summary = ReportSummary::default();
+        for outcome in outcomes {
+            summary.record(outcome);
+        }
+        summary
+    }
+
+    /// Count a single action
+    pub fn record(&mut self, outcome: &ActionOutcome) {
+        match (outcome.status, outcome.action) {
+            (ActionStatus::Applied, Action::Register) => self.registered += 1,
+            (ActionStatus::Applied, Action::Disable) => self.disabled += 1,
+            (ActionStatus::Applied, Action::Enable) => self.enabled += 1,
+            (ActionStatus::Applied, Action::UpdateCredentials) => self.credentials_updated += 1,
+            (ActionStatus::Applied, Action::FixPrefixLength) => self.prefixes_fixed += 1,
+            (ActionStatus::Applied, Action::UpdateAddress) => self.addresses_updated += 1,
+            (ActionStatus::Applied, Action::Rename) => self.renamed += 1,
+            (ActionStatus::Applied, Action::UpdateAttributes) => self.attributes_updated += 1,
+            (ActionStatus::Applied, Action::AddToGroup) => self.added_to_group += 1,
+            (ActionStatus::Applied, Action::RemoveFromGroup) => self.removed_from_group += 1,
+            (ActionStatus::Applied, Action::Purge) => self.purged += 1,
+            (ActionStatus::Applied, Action::PurgeDisabled) => self.purged_disabled += 1,
+            (ActionStatus::Unchanged, _) => self.unchanged += 1,
+            (ActionStatus::Skipped, _) => self.skipped += 1,
+            (ActionStatus::Timeout, _) => self.timeouts += 1,
+            (ActionStatus::Failed, _) => self.failures += 1,
+            (ActionStatus::Aborted, _) => self.aborted += 1,
+        }
+        if let ActionStatus::Timeout | ActionStatus::Failed | ActionStatus::Aborted = outcome.status
+        {
+            *self.failed_by_action.entry(outcome.action).or_default() += 1;
+        }
+    }
+
+    /// The number of actions which timed out, failed or were aborted
+    pub fn failed(&self) -> usize {
+        self.timeouts + self.failures + self.aborted
+    }
+
+    /// The number of actions attempted
+    pub fn attempted(&self) -> usize {
+        self.registered
+            + self.disabled
+            + self.enabled
+            + self.credentials_updated
+            + self.prefixes_fixed
+            + self.addresses_updated
+            + self.renamed
+            + self.attributes_updated
+            + self.added_to_group
+            + self.removed_from_group
+            + self.purged
+            + self.purged_disabled
+            + self.unchanged
+            + self.failed()
+    }
+}
+
+/// Read the entries of an audit log. A truncated last line, left by a process killed mid-write, is ignored.
+pub fn read_audit_log(path: &Path) -> Result<Vec<AuditEntry>, Error> {
+    let lines = BufReader::new(File::open(path)?)
+        .lines()
+        .collect::<Result<Vec<String>, _>>()?;
+    let mut entries = Vec::with_capacity(lines.len());
+    for (index, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(error) if index + 1 == lines.len() => {
+                log::warn!(
+                    "Ignoring the truncated last entry of {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+            Err(error) => {
+                return Err(anyhow!(
+                    "Invalid entry at line {} of {}: {}",
+                    index + 1,
+                    path.display(),
+                    error
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// The audit log appended after each action, finalized into the report at the end of the run
+#[derive(Debug)]
+pub struct AuditLog {
+    report_path: PathBuf,
+    partial_path: PathBuf,
+    file: Mutex<File>,
+    format: ReportFormat,
+}
+
+impl AuditLog {
+    /// Start the audit log next to the report, keeping aside the one left by an interrupted run
+    pub fn create(report_path: &Path) -> Result<Self, Error> {
+        let mut partial_path = report_path.as_os_str().to_owned();
+        partial_path.push(".partial.jsonl");
+        let partial_path = PathBuf::from(partial_path);
+
+        if partial_path.exists() {
+            let mut interrupted_path = partial_path.as_os_str().to_owned();
+            interrupted_path.push(format!(
+                ".interrupted-{}",
+                Utc::now().format("%Y%m%dT%H%M%S")
+            ));
+            log::warn!(
+                "Found the audit log of an interrupted run, keeping it as {}",
+                interrupted_path.to_string_lossy()
+            );
+            fs::rename(&partial_path, &interrupted_path)?;
+        }
+
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&partial_path)
+            .map_err(|error| {
+                anyhow!(
+                    "Unable to create the audit log {}: {}",
+                    partial_path.display(),
+                    error
+                )
+            })?;
+        log::info!("Recording the run to {}", partial_path.display());
+
+        Ok(AuditLog {
+            report_path: report_path.to_path_buf(),
+            partial_path,
+            file: Mutex::new(file),
+            format: ReportFormat::default(),
+        })
+    }
+
+    /// Write the report in the given format rather than JSON
+    pub fn with_format(mut self, format: ReportFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Append an entry and make sure it reached the disk before going on
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Build the report from the audit log and the details of the run, write it atomically and remove the audit log
+    pub fn finalize(self, run: RunDetails) -> Result<ReportSummary, Error> {
+        drop(self.file);
+
+        let mut plans = Vec::new();
+        let mut actions = Vec::new();
+        for entry in read_audit_log(&self.partial_path)? {
+            match entry {
+                AuditEntry::Plan {
+                    domain_id,
+                    domain_name,
+                    plan,
+                } => plans.push(DomainPlan {
+                    domain_id,
+                    domain_name,
+                    plan: *plan,
+                }),
+                AuditEntry::Action(outcome) => actions.push(outcome),
+            }
+        }
+
+        let mut domains: BTreeMap<u32, DomainSummary> = BTreeMap::new();
+        for plan in &plans {
+            domains.insert(
+                plan.domain_id,
+                DomainSummary {
+                    domain_id: plan.domain_id,
+                    domain_name: plan.domain_name.clone(),
+                    summary: ReportSummary::default(),
+                },
+            );
+        }
+        for outcome in &actions {
+            domains
+                .entry(outcome.domain_id)
+                .or_insert_with(|| DomainSummary {
+                    domain_id: outcome.domain_id,
+                    domain_name: None,
+                    summary: ReportSummary::default(),
+                })
+                .summary
+                .record(outcome);
+        }
+
+        let report = Report {
+            schema_version: v1::SCHEMA_VERSION,
+            generated_at: Utc::now(),
+            read_only: run.read_only,
+            change_limits_exceeded: run.change_limits_exceeded,
+            summary: ReportSummary::from_outcomes(&actions),
+            domains: domains.into_values().collect(),
+            plans,
+            actions,
+            http_requests: run.http_requests,
+            chronic_failures: run.chronic_failures,
+            snapshots: run.snapshots,
+            consistency: run.consistency,
+            stalled_registrations: run.stalled_registrations,
+            degraded_features: run.degraded_features,
+            pruned_groups: run.pruned_groups,
+            netbox_devices_filter: run.netbox_devices_filter,
+            client_certificates: run.client_certificates,
+            netshot_version: run.netshot_version,
+            netshot_apply_version: run.netshot_apply_version,
+        };
+
+        let mut temporary_path = self.report_path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        let mut file = File::create(&temporary_path)?;
+        match self.format {
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut file, &report)?;
+                file.write_all(b"\n")?;
+            }
+            ReportFormat::Yaml => serde_yaml::to_writer(&mut file, &report)?,
+        }
+        file.sync_all()?;
+        fs::rename(&temporary_path, &self.report_path)?;
+        fs::remove_file(&self.partial_path)?;
+
+        log::info!("Report written to {}", self.report_path.display());
+        Ok(report.summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rest::snapshot::{Fetched, Source};
+
+    fn device(ip: &str) -> PlannedDevice {
+        PlannedDevice {
+            ip: ip.to_string(),
+            name: format!("device-{}", ip),
+            netshot: None,
+            disabled_by: None,
+            prefix_length: None,
+            parent: None,
+            previous_failures: None,
+            reason: None,
+            comments: None,
+        }
+    }
+
+    fn report_path(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "netbox2netshot-report-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory.join("report.json")
+    }
+
+    #[test]
+    fn summary_from_audit_log() {
+        let path = report_path("summary");
+        let audit = AuditLog::create(&path).unwrap();
+
+        audit
+            .append(&AuditEntry::Plan {
+                domain_id: 2,
+                domain_name: Some("PAR1".to_string()),
+                plan: Box::new(SyncPlan {
+                    to_register: vec![device("1.2.3.4")],
+                    ..SyncPlan::default()
+                }),
+            })
+            .unwrap();
+        for (action, ip, result) in [
+            (Action::Register, "1.2.3.4", Ok(true)),
+            (Action::Disable, "1.2.3.5", Ok(false)),
+            (Action::Disable, "1.2.3.6", Err(anyhow!("Got status 500"))),
+        ] {
+            audit
+                .append(&AuditEntry::Action(ActionOutcome::new(
+                    2,
+                    action,
+                    &device(ip),
+                    None,
+                    Duration::from_millis(120),
+                    &result,
+                )))
+                .unwrap();
+        }
+
+        let snapshots =
+            vec![Fetched::single(Source::NetshotDevices, vec![device("10.0.0.1")]).snapshot];
+        let consistency = Consistency::new(&snapshots, None);
+        let summary = audit
+            .finalize(RunDetails {
+                snapshots,
+                consistency,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            summary,
+            ReportSummary {
+                registered: 1,
+                unchanged: 1,
+                failures: 1,
+                failed_by_action: BTreeMap::from([(Action::Disable, 1)]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(summary.attempted(), 3);
+
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(report["plans"][0]["domain_id"], 2);
+        assert_eq!(report["plans"][0]["domain_name"], "PAR1");
+        assert_eq!(report["domains"][0]["domain_name"], "PAR1");
+        assert_eq!(report["domains"][0]["summary"]["failures"], 1);
+        assert_eq!(report["actions"][2]["status"], "failed");
+        assert_eq!(report["actions"][2]["error"], "Got status 500");
+        assert_eq!(report["snapshots"][0]["source"], "netshot_devices");
+        assert_eq!(report["snapshots"][0]["items"], 1);
+        assert_eq!(
+            report["consistency"]["started_at"],
+            report["snapshots"][0]["started_at"]
+        );
+        assert!(!path.with_extension("json.partial.jsonl").exists());
+    }
+
+    #[test]
+    fn outcome_lines() {
+        let device = PlannedDevice {
+            ip: "10.9.9.9".to_string(),
+            name: "old-fw".to_string(),
+            netshot: None,
+            disabled_by: None,
+            prefix_length: None,
+            parent: None,
+            previous_failures: None,
+            reason: None,
+            comments: None,
+        };
+        let duration = Duration::from_millis(120);
+
+        let outcome =
+            ActionOutcome::new(2, Action::Disable, &device, Some(2318), duration, &Ok(true));
+        assert_eq!(
+            outcome.to_string(),
+            "action=disable domain_id=2 ip=10.9.9.9 name=old-fw result=ok netshot_id=2318 duration_ms=120"
+        );
+        assert_eq!(outcome.level(), log::Level::Info);
+
+        let device = PlannedDevice {
+            name: "new fw".to_string(),
+            parent: Some("chassis-1".to_string()),
+            ..device
+        };
+        let error = Err(anyhow!("Failed to register new device 10.9.9.9"));
+        let outcome = ActionOutcome::new(2, Action::Register, &device, None, duration, &error);
+        assert_eq!(
+            outcome.to_string(),
+            "action=register domain_id=2 ip=10.9.9.9 name=\"new fw\" result=error parent=chassis-1 duration_ms=120 error=\"Failed to register new device 10.9.9.9\""
+        );
+        assert_eq!(outcome.level(), log::Level::Warn);
+
+        let violation = Err(Error::new(ReadOnlyViolation {
+            service: "Netshot",
+            method: "POST".to_string(),
+            endpoint: "/api/devices".to_string(),
+        }));
+        let outcome = ActionOutcome::new(2, Action::Register, &device, None, duration, &violation);
+        assert_eq!(outcome.status, ActionStatus::Skipped);
+        assert_eq!(outcome.error.as_deref(), Some("netshot writes disabled"));
+        let mut summary = ReportSummary::default();
+        summary.record(&outcome);
+        assert_eq!((summary.skipped, summary.attempted()), (1, 0));
+    }
+
+    #[test]
+    fn plan_reasons() {
+        let sync_plan = SyncPlan {
+            to_register: vec![device("1.2.3.4")],
+            to_disable: vec![PlannedDevice {
+                disabled_by: Some("missing-means disable".to_string()),
+                ..device("1.2.3.5")
+            }],
+            to_enable: vec![device("1.2.3.6")],
+            ..SyncPlan::default()
+        };
+        let plan = reported_plan(&sync_plan);
+        let reason = |devices: &[PlannedDevice]| devices[0].reason.clone().unwrap();
+        assert_eq!(reason(&plan.to_register), "missing on Netshot");
+        assert_eq!(reason(&plan.to_disable), "missing-means disable");
+        assert_eq!(
+            reason(&plan.to_enable),
+            "disabled on Netshot but present on Netbox"
+        );
+
+        // The same report in YAML
+        let path = report_path("yaml");
+        let audit = AuditLog::create(&path)
+            .unwrap()
+            .with_format("yaml".parse().unwrap());
+        audit
+            .append(&AuditEntry::Plan {
+                domain_id: 2,
+                domain_name: None,
+                plan: Box::new(plan),
+            })
+            .unwrap();
+        audit.finalize(RunDetails::default()).unwrap();
+        let report: serde_yaml::Value =
+            serde_yaml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            report["plans"][0]["plan"]["to_register"][0]["ip"],
+            serde_yaml::Value::from("1.2.3.4")
+        );
+        assert!("xml".parse::<ReportFormat>().is_err());
+    }
+
+    #[test]
+    fn interrupted_audit_log() {
+        let path = report_path("interrupted");
+        let audit = AuditLog::create(&path).unwrap();
+        audit
+            .append(&AuditEntry::Action(ActionOutcome::new(
+                2,
+                Action::Enable,
+                &device("1.2.3.4"),
+                Some(2318),
+                Duration::from_millis(120),
+                &Ok(true),
+            )))
+            .unwrap();
+
+        // Simulate a process killed in the middle of the next write
+        let partial_path = path.with_extension("json.partial.jsonl");
+        let mut file = OpenOptions::new().append(true).open(&partial_path).unwrap();
+        file.write_all(br#"{"entry":"action","timest"#).unwrap();
+
+        let entries = read_audit_log(&partial_path).unwrap();
+        assert_eq!(entries.len(), 1);
+
+        // The next run keeps the interrupted audit log aside
+        drop(audit);
+        AuditLog::create(&path).unwrap();
+        let kept = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .contains(".interrupted-")
+            })
+            .count();
+        assert_eq!(kept, 1);
+    }
+}
@@ -0,0 +1,300 @@
+use super::{ActionOutcome, ReportSummary};
+use crate::rest::helpers::CertificateExpiry;
+use crate::rest::netshot::PrunedGroup;
+use crate::rest::optional::DegradedFeature;
+use crate::rest::snapshot::{Consistency, FetchSnapshot};
+use crate::rest::stats::EndpointSummary;
+use crate::state::{FailureRecord, PendingRegistration};
+use crate::sync::SyncPlan;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+
+/// The version of the report format, bumped on incompatible changes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The plan computed for a domain
+#[derive(Debug, Serialize, JsonSchema)]
+#[non_exhaustive]
+pub struct DomainPlan {
+    pub domain_id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain_name: Option<String>,
+    /// The devices to register, disable, enable or update, and the ones left out
+    pub plan: SyncPlan,
+}
+
+/// The number of actions per status of a domain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+#[non_exhaustive]
+pub struct DomainSummary {
+    pub domain_id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain_name: Option<String>,
+    pub summary: ReportSummary,
+}
+
+/// The report of a run
+#[derive(Debug, Serialize, JsonSchema)]
+#[non_exhaustive]
+pub struct Report {
+    pub schema_version: u32,
+    pub generated_at: DateTime<Utc>,
+    /// The Netbox devices filter of the run, including the --netbox-devices-filter-file one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub netbox_devices_filter: Option<String>,
+    /// Whether the run was a --read-only one, which could not change Netshot
+    #[serde(default)]
+    pub read_only: bool,
+    /// The --max-changes or --max-disable-percent limit the plans of the run exceeded, which blocked their apply, or
+    /// would have outside of check mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change_limits_exceeded: Option<String>,
+    pub summary: ReportSummary,
+    pub domains: Vec<DomainSummary>,
+    pub plans: Vec<DomainPlan>,
+    pub actions: Vec<ActionOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_requests: Option<Vec<EndpointSummary>>,
+    /// The actions which failed more than --max-consecutive-failures times in a row, to be investigated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chronic_failures: Vec<FailureRecord>,
+    /// When each inventory the plans are based on was fetched
+    pub snapshots: Vec<FetchSnapshot>,
+    /// The window spanned by the snapshots and what may make them inconsistent
+    pub consistency: Consistency,
+    /// The registrations whose device is still not on Netshot after --registration-grace runs
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stalled_registrations: Vec<PendingRegistration>,
+    /// The optional features disabled during the run, as one of their endpoints was forbidden or missing
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub degraded_features: Vec<DegradedFeature>,
+    /// The empty groups created by netbox2netshot deleted at the end of the run
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pruned_groups: Vec<PrunedGroup>,
+    /// When the TLS client certificates of the clients expire
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_certificates: Vec<CertificateExpiry>,
+    /// The Netshot release, when it could be detected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub netshot_version: Option<String>,
+    /// The release of the --netshot-apply-url Netshot, when it could be detected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub netshot_apply_version: Option<String>,
+}
+
+/// The JSON Schema of the report, for the consumers to validate it against
+pub fn schema() -> Value {
+    let mut schema = serde_json::to_value(schemars::schema_for!(Report))
+        .expect("The report schema is always serializable");
+    schema["$id"] = Value::from(format!(
+        "https://github.com/scaleway/netbox2netshot/report/v{}.json",
+        SCHEMA_VERSION
+    ));
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{Action, ActionStatus};
+    use crate::rest::netshot::DiscoveryFailure;
+    use crate::rest::snapshot::Source;
+    use crate::sync::{HeldRegistration, PlannedDevice, SkipReason, SkippedDevice, SyncPlan};
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    fn planned_device(ip: &str, name: &str) -> PlannedDevice {
+        PlannedDevice {
+            ip: ip.to_string(),
+            name: name.to_string(),
+            netshot: None,
+            disabled_by: None,
+            prefix_length: None,
+            parent: None,
+            previous_failures: None,
+            reason: None,
+            comments: None,
+        }
+    }
+
+    fn outcome(action: Action, ip: &str, status: ActionStatus) -> ActionOutcome {
+        ActionOutcome {
+            timestamp: "2024-01-01T00:00:01Z".parse().unwrap(),
+            domain_id: 2,
+            action,
+            ip: ip.to_string(),
+            name: format!("device-{}", ip),
+            status,
+            netshot_id: Some(2318),
+            duration_ms: 120,
+            error: (status == ActionStatus::Failed).then(|| "Got status 500".to_string()),
+            parent: (action == Action::Register).then(|| "chassis-1".to_string()),
+        }
+    }
+
+    /// A report with every optional field set
+    fn full_report() -> Report {
+        let plan = SyncPlan {
+            to_register: vec![PlannedDevice {
+                previous_failures: Some(1),
+                reason: None,
+                comments: None,
+                ..planned_device("10.0.0.1", "new-router")
+            }],
+            to_disable: vec![PlannedDevice {
+                disabled_by: Some("missing-means disable".to_string()),
+                ..planned_device("10.0.0.2", "old-router")
+            }],
+            held_registrations: vec![HeldRegistration {
+                device: planned_device("10.0.0.3", "no-driver"),
+                failure: DiscoveryFailure::Driver,
+                reason: Some("No driver matches the device".to_string()),
+            }],
+            skipped: vec![SkippedDevice {
+                id: 7,
+                name: "no-ip".to_string(),
+                reason: SkipReason::MissingPrimaryIp,
+            }],
+            ..Default::default()
+        };
+        let summary = ReportSummary {
+            registered: 1,
+            failures: 1,
+            failed_by_action: BTreeMap::from([(Action::Disable, 1)]),
+            ..Default::default()
+        };
+
+        let snapshots = vec![
+            FetchSnapshot {
+                source: Source::NetboxDevices,
+                domain_id: None,
+                started_at: "2024-01-01T00:00:00Z".parse().unwrap(),
+                finished_at: "2024-01-01T00:00:00.400Z".parse().unwrap(),
+                items: 2,
+                pages: 2,
+                announced_items: Some(3),
+                drift: vec!["2 items fetched while 3 were announced".to_string()],
+            },
+            FetchSnapshot {
+                source: Source::NetshotDevices,
+                domain_id: Some(2),
+                started_at: "2024-01-01T00:00:00.500Z".parse().unwrap(),
+                finished_at: "2024-01-01T00:00:00.600Z".parse().unwrap(),
+                items: 1,
+                pages: 1,
+                announced_items: None,
+                drift: Vec::new(),
+            },
+        ];
+        let http_requests = vec![EndpointSummary {
+            method: "POST".to_string(),
+            endpoint: "/api/devices".to_string(),
+            requests: 1,
+            retried: 0,
+            throttled: 0,
+            throttled_ms: 0,
+            status_classes: BTreeMap::from([("2xx".to_string(), 1)]),
+            p50_ms: 120,
+            p95_ms: 120,
+            max_ms: 120,
+        }];
+
+        Report {
+            schema_version: SCHEMA_VERSION,
+            generated_at: "2024-01-01T00:00:02Z".parse().unwrap(),
+            read_only: false,
+            change_limits_exceeded: Some(
+                "Blocked by the change limits: 600 changes planned, above --max-changes 500"
+                    .to_string(),
+            ),
+            summary: summary.clone(),
+            domains: vec![DomainSummary {
+                domain_id: 2,
+                domain_name: Some("PAR1".to_string()),
+                summary,
+            }],
+            plans: vec![DomainPlan {
+                domain_id: 2,
+                domain_name: Some("PAR1".to_string()),
+                plan,
+            }],
+            actions: vec![
+                outcome(Action::Register, "10.0.0.1", ActionStatus::Applied),
+                outcome(Action::Disable, "10.0.0.2", ActionStatus::Failed),
+            ],
+            consistency: Consistency::new(&snapshots, Some(&http_requests)),
+            http_requests: Some(http_requests),
+            chronic_failures: vec![FailureRecord {
+                ip: "10.0.0.2".to_string(),
+                domain_id: 2,
+                action: Action::Disable,
+                consecutive_failures: 4,
+                last_failure: "2024-01-01T00:00:01Z".parse().unwrap(),
+                last_error: Some("Got status 500".to_string()),
+            }],
+            snapshots,
+            stalled_registrations: vec![PendingRegistration {
+                ip: "10.0.0.3".to_string(),
+                domain_id: 2,
+                task_id: 4242,
+                last_task_id: Some(4300),
+                registered_at: "2023-12-31T00:00:00Z".parse().unwrap(),
+                attempts: 3,
+                runs: 3,
+                task_status: Some("FAILURE".to_string()),
+                failure_reason: Some("No driver matches the device".to_string()),
+                failure_class: Some(DiscoveryFailure::Driver),
+            }],
+            degraded_features: vec![DegradedFeature {
+                feature: "management interface lookup".to_string(),
+                endpoint: "/api/dcim/interfaces/".to_string(),
+                status: 403,
+            }],
+            pruned_groups: vec![PrunedGroup {
+                id: 11,
+                name: "Site PAR2".to_string(),
+            }],
+            netbox_devices_filter: Some("role=customer-cpe&status=active".to_string()),
+            client_certificates: vec![CertificateExpiry {
+                service: "netshot".to_string(),
+                expires_at: "2024-03-01T00:00:00Z".parse().unwrap(),
+            }],
+            netshot_version: Some("0.21.3".to_string()),
+            netshot_apply_version: None,
+        }
+    }
+
+    #[test]
+    fn golden_report() {
+        let golden: Value =
+            serde_json::from_str(&fs::read_to_string("tests/data/report/v1.json").unwrap())
+                .unwrap();
+        assert_eq!(serde_json::to_value(full_report()).unwrap(), golden);
+    }
+
+    #[test]
+    fn report_schema() {
+        let schema = schema();
+        assert_eq!(schema["title"], "Report");
+        let required = schema["required"].as_array().unwrap();
+        assert!(required.contains(&Value::from("schema_version")));
+        assert!(!required.contains(&Value::from("http_requests")));
+
+        let properties = schema["properties"].as_object().unwrap();
+        let golden = serde_json::to_value(full_report()).unwrap();
+        for field in golden.as_object().unwrap().keys() {
+            assert!(
+                properties.contains_key(field),
+                "{} is not in the schema",
+                field
+            );
+        }
+
+        // The plans are typed down to their devices
+        let definitions = &schema["definitions"];
+        assert!(definitions["SyncPlan"]["properties"]["to_register"].is_object());
+        assert!(definitions["PlannedDevice"]["properties"]["reason"].is_object());
+    }
+}
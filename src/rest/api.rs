@@ -0,0 +1,310 @@
+use crate::rest::netbox::{self, DeletionStatus, NetboxClient};
+use crate::rest::netshot::{self, DeviceUpdatedPayload, NetshotClient, NewDeviceCreatedPayload};
+use crate::rest::query::SearchQuery;
+use crate::rest::snapshot::Fetched;
+use anyhow::{Error, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The Netbox operations the synchronization relies on, so it can run against canned inventories in tests
+pub trait NetboxApi: Sync {
+    /// Get the devices using the given filter
+    fn get_devices(&self, query_string: &str) -> Result<Fetched<netbox::Device>, Error>;
+
+    /// Get the VMs as device using the given filter
+    fn get_vms(&self, query_string: &str) -> Result<Fetched<netbox::Device>, Error>;
+
+    /// Find out whether the device with the given primary IP has been deleted or decommissioned
+    fn deletion_status(&self, ip: &str, include_vms: bool) -> Result<DeletionStatus, Error>;
+
+    /// Get the IP assigned to a management-only interface of each of the given devices, when they have one
+    fn get_management_ips(
+        &self,
+        device_ids: &[u32],
+    ) -> Result<HashMap<u32, netbox::PrimaryIP>, Error>;
+}
+
+/// The Netshot operations the synchronization relies on, so it can run against canned responses in tests
+pub trait NetshotApi: Sync {
+    /// Get devices registered in Netshot
+    fn get_devices(&self, domain_id: u32) -> Result<Fetched<netshot::Device>, Error>;
+
+    /// Get the IP addresses configured on the interfaces of a device
+    fn get_device_addresses(&self, device_id: u32) -> Result<Vec<String>, Error>;
+
+    /// Register a given IP into Netshot
+    fn register_device(
+        &self,
+        ip_address: String,
+        domain_id: u32,
+        name: &str,
+    ) -> Result<NewDeviceCreatedPayload, Error>;
+
+    /// Disable a given device
+    fn disable_device(&self, ip_address: String) -> Result<Option<DeviceUpdatedPayload>, Error>;
+
+    /// Enable a given device
+    fn enable_device(&self, ip_address: String) -> Result<Option<DeviceUpdatedPayload>, Error>;
+
+    /// Disable a device of the fetched inventory, by ID
+    fn disable_device_by_id(
+        &self,
+        device: &netshot::Device,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error>;
+
+    /// Enable a device of the fetched inventory, by ID
+    fn enable_device_by_id(
+        &self,
+        device: &netshot::Device,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error>;
+
+    /// Set the given devices to a given state by batches
+    fn set_devices_enabled(
+        &self,
+        ip_addresses: &[String],
+        enabled: bool,
+        batch_size: usize,
+    ) -> Vec<(
+        String,
+        Duration,
+        Result<Option<DeviceUpdatedPayload>, Error>,
+    )>;
+
+    /// Get the IDs of the specific credential sets assigned to a device
+    fn get_device_credential_set_ids(&self, device_id: u32) -> Result<Vec<u32>, Error>;
+
+    /// Count the device discovery tasks scheduled or running
+    fn count_pending_discoveries(&self) -> Result<usize, Error>;
+
+    /// Replace the specific credential sets of the device with the given IP
+    fn set_device_credential_sets(
+        &self,
+        ip_address: String,
+        credential_set_ids: &[u32],
+    ) -> Result<DeviceUpdatedPayload, Error>;
+
+    /// Get the free-text comments of a device
+    fn get_device_comments(&self, device_id: u32) -> Result<Option<String>, Error>;
+
+    /// Replace the comments of the device with the given IP
+    fn update_device_comments(
+        &self,
+        ip_address: String,
+        comments: &str,
+    ) -> Result<DeviceUpdatedPayload, Error>;
+
+    /// Set the prefix length of the management address of the device with the given IP
+    fn set_device_prefix_length(
+        &self,
+        ip_address: String,
+        prefix_length: u8,
+    ) -> Result<DeviceUpdatedPayload, Error>;
+
+    /// Move the device with the given ID to a new management address
+    fn update_device_ip(
+        &self,
+        device_id: u32,
+        ip_address: String,
+    ) -> Result<DeviceUpdatedPayload, Error>;
+
+    /// Rename the device with the given ID
+    fn rename_device(&self, device_id: u32, name: String) -> Result<DeviceUpdatedPayload, Error>;
+
+    /// Get a single task, e.g. the discovery started by a registration
+    fn get_task(&self, task_id: u32) -> Result<netshot::Task, Error>;
+
+    /// Get the device groups
+    fn get_groups(&self) -> Result<Vec<netshot::Group>, Error>;
+
+    /// Get the device groups again, rather than from the cache
+    fn refresh_groups(&self) -> Result<Vec<netshot::Group>, Error>;
+
+    /// Create an empty static group
+    fn create_static_group(&self, name: &str) -> Result<netshot::Group, Error>;
+
+    /// Add the device with the given IP to the named static group, or remove it, false when it already was
+    fn set_group_membership(
+        &self,
+        group_name: &str,
+        ip_address: String,
+        member: bool,
+    ) -> Result<bool, Error>;
+
+    /// Add a device to a static group, false when it already was a member
+    fn add_device_to_group(&self, device_id: u32, group_id: u32) -> Result<bool, Error>;
+
+    /// Find the device with the given IP, e.g. the one created by a registration
+    fn find_device(&self, ip_address: &str) -> Result<Option<netshot::Device>, Error>;
+
+    /// Delete the device with the given IP
+    fn delete_device(&self, ip_address: String) -> Result<(), Error>;
+
+    /// Delete a device group
+    fn delete_group(&self, group: &netshot::Group) -> Result<(), Error>;
+}
+
+impl NetboxApi for NetboxClient {
+    fn get_devices(&self, query_string: &str) -> Result<Fetched<netbox::Device>, Error> {
+        NetboxClient::get_devices(self, query_string)
+    }
+
+    fn get_vms(&self, query_string: &str) -> Result<Fetched<netbox::Device>, Error> {
+        NetboxClient::get_vms(self, query_string)
+    }
+
+    fn deletion_status(&self, ip: &str, include_vms: bool) -> Result<DeletionStatus, Error> {
+        NetboxClient::deletion_status(self, ip, include_vms)
+    }
+
+    fn get_management_ips(
+        &self,
+        device_ids: &[u32],
+    ) -> Result<HashMap<u32, netbox::PrimaryIP>, Error> {
+        NetboxClient::get_management_ips(self, device_ids)
+    }
+}
+
+impl NetshotApi for NetshotClient {
+    fn get_devices(&self, domain_id: u32) -> Result<Fetched<netshot::Device>, Error> {
+        NetshotClient::get_devices(self, domain_id)
+    }
+
+    fn get_device_addresses(&self, device_id: u32) -> Result<Vec<String>, Error> {
+        NetshotClient::get_device_addresses(self, device_id)
+    }
+
+    fn register_device(
+        &self,
+        ip_address: String,
+        domain_id: u32,
+        name: &str,
+    ) -> Result<NewDeviceCreatedPayload, Error> {
+        NetshotClient::register_device(self, ip_address, domain_id, name)
+    }
+
+    fn disable_device(&self, ip_address: String) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        NetshotClient::disable_device(self, ip_address)
+    }
+
+    fn enable_device(&self, ip_address: String) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        NetshotClient::enable_device(self, ip_address)
+    }
+
+    fn disable_device_by_id(
+        &self,
+        device: &netshot::Device,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        NetshotClient::disable_device_by_id(self, device)
+    }
+
+    fn enable_device_by_id(
+        &self,
+        device: &netshot::Device,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        NetshotClient::enable_device_by_id(self, device)
+    }
+
+    fn set_devices_enabled(
+        &self,
+        ip_addresses: &[String],
+        enabled: bool,
+        batch_size: usize,
+    ) -> Vec<(
+        String,
+        Duration,
+        Result<Option<DeviceUpdatedPayload>, Error>,
+    )> {
+        NetshotClient::set_devices_enabled(self, ip_addresses, enabled, batch_size)
+    }
+
+    fn get_device_credential_set_ids(&self, device_id: u32) -> Result<Vec<u32>, Error> {
+        NetshotClient::get_device_credential_set_ids(self, device_id)
+    }
+
+    fn count_pending_discoveries(&self) -> Result<usize, Error> {
+        NetshotClient::count_pending_discoveries(self)
+    }
+
+    fn set_device_credential_sets(
+        &self,
+        ip_address: String,
+        credential_set_ids: &[u32],
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        NetshotClient::set_device_credential_sets(self, ip_address, credential_set_ids)
+    }
+
+    fn get_device_comments(&self, device_id: u32) -> Result<Option<String>, Error> {
+        NetshotClient::get_device_comments(self, device_id)
+    }
+
+    fn update_device_comments(
+        &self,
+        ip_address: String,
+        comments: &str,
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        NetshotClient::update_device_comments(self, ip_address, comments)
+    }
+
+    fn set_device_prefix_length(
+        &self,
+        ip_address: String,
+        prefix_length: u8,
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        NetshotClient::set_device_prefix_length(self, ip_address, prefix_length)
+    }
+
+    fn update_device_ip(
+        &self,
+        device_id: u32,
+        ip_address: String,
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        NetshotClient::update_device_ip(self, device_id, ip_address)
+    }
+
+    fn rename_device(&self, device_id: u32, name: String) -> Result<DeviceUpdatedPayload, Error> {
+        NetshotClient::rename_device(self, device_id, name)
+    }
+
+    fn get_task(&self, task_id: u32) -> Result<netshot::Task, Error> {
+        NetshotClient::get_task(self, task_id)
+    }
+
+    fn get_groups(&self) -> Result<Vec<netshot::Group>, Error> {
+        NetshotClient::get_groups(self)
+    }
+
+    fn refresh_groups(&self) -> Result<Vec<netshot::Group>, Error> {
+        NetshotClient::refresh_groups(self)
+    }
+
+    fn create_static_group(&self, name: &str) -> Result<netshot::Group, Error> {
+        NetshotClient::create_static_group(self, name)
+    }
+
+    fn set_group_membership(
+        &self,
+        group_name: &str,
+        ip_address: String,
+        member: bool,
+    ) -> Result<bool, Error> {
+        NetshotClient::set_group_membership(self, group_name, ip_address, member)
+    }
+
+    fn add_device_to_group(&self, device_id: u32, group_id: u32) -> Result<bool, Error> {
+        NetshotClient::add_device_to_group(self, device_id, group_id)
+    }
+
+    fn find_device(&self, ip_address: &str) -> Result<Option<netshot::Device>, Error> {
+        let response = NetshotClient::search_device(self, &SearchQuery::ip(ip_address)?)?;
+        // The search also matches the devices with the IP on another interface than their management one
+        Ok(response.device_with_ip(ip_address).ok().cloned())
+    }
+
+    fn delete_device(&self, ip_address: String) -> Result<(), Error> {
+        NetshotClient::delete_device(self, ip_address)
+    }
+
+    fn delete_group(&self, group: &netshot::Group) -> Result<(), Error> {
+        NetshotClient::delete_group(self, group)
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Error, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// The Netbox GraphQL endpoint
+pub(crate) const PATH_GRAPHQL: &str = "/graphql/";
+
+/// The number of devices requested by GraphQL query, far more than the REST pages hold
+pub(crate) const GRAPHQL_LIMIT: u32 = 1000;
+
+/// The fields of the devices, matching what the REST endpoint gives the `Device` struct
+const DEVICE_FIELDS: &str = "id name status \
+    primary_ip4 { id address family { value } } primary_ip6 { id address family { value } } \
+    role { id name slug } site { id name slug } rack { id name } serial asset_tag tags { name slug } custom_fields \
+    parent_bay { device { id name } } virtual_chassis { id name master { id } } vc_position vc_priority";
+
+/// The fields of the virtual machines, which have no parent device
+const VM_FIELDS: &str = "id name status \
+    primary_ip4 { id address family { value } } primary_ip6 { id address family { value } } \
+    role { id name slug } site { id name slug } tags { name slug } custom_fields";
+
+/// The Netbox API the devices are fetched through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetboxApiMode {
+    Rest,
+    Graphql,
+}
+
+impl FromStr for NetboxApiMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rest" => Ok(NetboxApiMode::Rest),
+            "graphql" => Ok(NetboxApiMode::Graphql),
+            _ => Err(anyhow!(
+                "Unknown Netbox API `{}`, expected rest or graphql",
+                s
+            )),
+        }
+    }
+}
+
+/// A GraphQL list of devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum List {
+    Devices,
+    VirtualMachines,
+}
+
+impl List {
+    pub fn name(self) -> &'static str {
+        match self {
+            List::Devices => "device_list",
+            List::VirtualMachines => "virtual_machine_list",
+        }
+    }
+
+    fn fields(self) -> &'static str {
+        match self {
+            List::Devices => DEVICE_FIELDS,
+            List::VirtualMachines => VM_FIELDS,
+        }
+    }
+}
+
+/// Whether a filter key is a valid GraphQL argument name
+fn is_name(key: &str) -> bool {
+    let mut characters = key.chars();
+    matches!(characters.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && characters.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// The GraphQL arguments of a REST filter query string, the values of a repeated key making a single list:
+/// `role=edge&role=core&status=active` gives `role: ["edge", "core"], status: ["active"]`
+pub(crate) fn filter_arguments(query_string: &str) -> Result<String, Error> {
+    let url = reqwest::Url::parse(&format!(
+        "http://netbox/?{}",
+        query_string.trim_start_matches('?')
+    ))?;
+    let mut arguments: Vec<(String, Vec<String>)> = Vec::new();
+    for (key, value) in url.query_pairs() {
+        if !is_name(&key) {
+            return Err(anyhow!(
+                "The filter key `{}` cannot be a GraphQL argument",
+                key
+            ));
+        }
+        // The JSON strings are valid GraphQL strings, escapes included
+        let value = Value::String(value.into_owned()).to_string();
+        match arguments.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, values)) => values.push(value),
+            None => arguments.push((key.into_owned(), vec![value])),
+        }
+    }
+    Ok(arguments
+        .iter()
+        .map(|(name, values)| format!("{}: [{}]", name, values.join(", ")))
+        .collect::<Vec<String>>()
+        .join(", "))
+}
+
+/// The query of a page of a list, filtered with the given arguments
+pub(crate) fn list_query(list: List, arguments: &str, limit: u32, offset: u32) -> String {
+    let arguments = if arguments.trim().is_empty() {
+        String::new()
+    } else {
+        format!("{}, ", arguments.trim())
+    };
+    format!(
+        "query {{ {}({}limit: {}, offset: {}) {{ {} }} }}",
+        list.name(),
+        arguments,
+        limit,
+        offset,
+        list.fields()
+    )
+}
+
+/// An error reported by the GraphQL endpoint
+#[derive(Debug, Deserialize)]
+struct GraphqlError {
+    message: String,
+}
+
+/// The response to a GraphQL query
+#[derive(Debug, Deserialize)]
+struct GraphqlResponse {
+    #[serde(default)]
+    data: Option<Value>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
+
+/// Turn the string IDs of GraphQL into numbers and the parent bay into the parent device, as the REST API sends them
+fn normalize(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            if let Some(id) = object.get("id").and_then(Value::as_str) {
+                if let Ok(id) = id.parse::<u64>() {
+                    object.insert("id".to_string(), Value::from(id));
+                }
+            }
+            if let Some(parent_bay) = object.remove("parent_bay") {
+                let device = parent_bay.get("device").cloned().unwrap_or(Value::Null);
+                object.insert("parent_device".to_string(), device);
+            }
+            object.values_mut().for_each(normalize);
+        }
+        Value::Array(values) => values.iter_mut().for_each(normalize),
+        _ => {}
+    }
+}
+
+/// The records of a list in a GraphQL response, failing on the errors it reports
+pub(crate) fn parse_response(body: &str, list: List) -> Result<Vec<Value>, Error> {
+    let response: GraphqlResponse = serde_json::from_str(body)?;
+    if !response.errors.is_empty() {
+        let messages: Vec<&str> = response
+            .errors
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect();
+        return Err(anyhow!(
+            "The Netbox GraphQL query of {} failed: {}",
+            list.name(),
+            messages.join("; ")
+        ));
+    }
+    let mut data = response.data.unwrap_or_default();
+    let records = match data.get_mut(list.name()).map(Value::take) {
+        Some(Value::Array(records)) => records,
+        _ => {
+            return Err(anyhow!(
+                "The Netbox GraphQL response has no {} list",
+                list.name()
+            ))
+        }
+    };
+    Ok(records
+        .into_iter()
+        .map(|mut record| {
+            normalize(&mut record);
+            record
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn query_arguments() {
+        assert_eq!(
+            filter_arguments("role=edge&status=active&role=core").unwrap(),
+            r#"role: ["edge", "core"], status: ["active"]"#
+        );
+        assert_eq!(
+            filter_arguments("tenant=M%C3%BCller+%22DC%22&site__n=par1").unwrap(),
+            r#"tenant: ["Müller \"DC\""], site__n: ["par1"]"#
+        );
+        assert_eq!(filter_arguments("").unwrap(), "");
+        assert!(filter_arguments("cf_rack-row=4").is_err());
+        assert!(filter_arguments("1role=edge").is_err());
+
+        assert_eq!(
+            list_query(List::VirtualMachines, "", 1000, 2000),
+            format!(
+                "query {{ virtual_machine_list(limit: 1000, offset: 2000) {{ {} }} }}",
+                VM_FIELDS
+            )
+        );
+        assert!(list_query(List::Devices, r#"role: ["edge"]"#, 10, 0)
+            .starts_with(r#"query { device_list(role: ["edge"], limit: 10, offset: 0) { id name"#));
+        assert_eq!(
+            "graphql".parse::<NetboxApiMode>().unwrap(),
+            NetboxApiMode::Graphql
+        );
+        assert!("soap".parse::<NetboxApiMode>().is_err());
+    }
+
+    #[test]
+    fn responses() {
+        let body = json!({"data": {"device_list": [{
+            "id": "12",
+            "name": "edge-1",
+            "primary_ip4": {"id": "7", "address": "1.2.3.4/32"},
+            "parent_bay": {"device": {"id": "3", "name": "chassis-1"}},
+        }, {"id": "13", "name": "edge-2", "parent_bay": null}]}});
+        assert_eq!(
+            parse_response(&body.to_string(), List::Devices).unwrap(),
+            vec![
+                json!({
+                    "id": 12,
+                    "name": "edge-1",
+                    "primary_ip4": {"id": 7, "address": "1.2.3.4/32"},
+                    "parent_device": {"id": 3, "name": "chassis-1"},
+                }),
+                json!({"id": 13, "name": "edge-2", "parent_device": null}),
+            ]
+        );
+
+        let body = json!({"data": null, "errors": [
+            {"message": "Cannot query field \"role\" on type \"DeviceType\"."},
+            {"message": "Unknown argument \"rack\"."},
+        ]});
+        assert_eq!(
+            parse_response(&body.to_string(), List::Devices)
+                .unwrap_err()
+                .to_string(),
+            "The Netbox GraphQL query of device_list failed: Cannot query field \"role\" on type \"DeviceType\".; Unknown argument \"rack\"."
+        );
+        assert!(parse_response(r#"{"data": {}}"#, List::VirtualMachines).is_err());
+    }
+}
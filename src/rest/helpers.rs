@@ -1,21 +1,564 @@
-use anyhow::Error;
-use reqwest::Identity;
+use crate::paths;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, TimeZone, Utc};
+use openssl::asn1::Asn1Time;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::x509::{X509Ref, X509};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::redirect::Policy;
+use reqwest::{Certificate, Identity, Method, StatusCode};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
+use std::time::Duration;
 
-/// Create an identity from a private key and certificate registered in a PKCS12 file (with or without password)
-pub fn build_identity_from_file(
+/// The time the services have to answer a request, unless configured otherwise
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client identity and the expiry date of its certificate
+#[derive(Debug)]
+pub(crate) struct ClientIdentity {
+    pub identity: Identity,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The expiry date of the TLS client certificate of a service, for the report and the metrics
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct CertificateExpiry {
+    /// The client of the certificate: netbox, netshot or netshot-apply
+    pub service: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The expiry date (notAfter) of the certificate of a PKCS12 file
+fn certificate_expiry(buf: &[u8], password: &str) -> Result<DateTime<Utc>, Error> {
+    let certificate = Pkcs12::from_der(buf)?
+        .parse2(password)?
+        .cert
+        .ok_or_else(|| anyhow!("No certificate found"))?;
+    not_after(&certificate)
+}
+
+/// The expiry date (notAfter) of a certificate
+fn not_after(certificate: &X509Ref) -> Result<DateTime<Utc>, Error> {
+    let since_epoch = Asn1Time::from_unix(0)?.diff(certificate.not_after())?;
+    Utc.timestamp_opt(
+        i64::from(since_epoch.days) * 86400 + i64::from(since_epoch.secs),
+        0,
+    )
+    .single()
+    .ok_or_else(|| anyhow!("Invalid certificate expiry date"))
+}
+
+/// Read a file of the TLS client identity, its path being expanded first
+fn read_tls_file(filename: &str, kind: &str) -> Result<(String, Vec<u8>), Error> {
+    let filename = paths::expand(filename)?;
+    let mut buf = Vec::new();
+    File::open(&filename)
+        .and_then(|mut file| file.read_to_end(&mut buf))
+        .map_err(|error| anyhow!("Unable to read the {} {}: {}", kind, filename, error))?;
+    Ok((filename, buf))
+}
+
+/// Whether a file is PEM encoded rather than DER
+fn is_pem(buf: &[u8]) -> bool {
+    String::from_utf8_lossy(buf).contains("-----BEGIN ")
+}
+
+/// Create an identity from a PEM certificate (chain) and the private key of the key file, or of the certificate file
+/// when they come together. The key may be PKCS#8, PKCS#1 or SEC1, the password decrypting it if given.
+fn build_pem_identity(
+    filename: &str,
+    buf: &[u8],
+    key: Option<(String, Vec<u8>)>,
+    password: Option<&str>,
+) -> Result<(Identity, DateTime<Utc>), Error> {
+    let certificates = X509::stack_from_pem(buf)
+        .ok()
+        .filter(|certificates| !certificates.is_empty())
+        .ok_or_else(|| {
+            anyhow!(
+                "Unable to parse the TLS client certificate {}: expected a PEM certificate",
+                filename
+            )
+        })?;
+    let (key_filename, key) = key.unwrap_or_else(|| (filename.to_string(), buf.to_vec()));
+    let key = match password {
+        Some(password) => PKey::private_key_from_pem_passphrase(&key, password.as_bytes()),
+        None => PKey::private_key_from_pem(&key),
+    }
+    .map_err(|_| {
+        anyhow!(
+            "Unable to parse the TLS client key {}: expected a PEM private key (PKCS#8, PKCS#1 or SEC1)",
+            key_filename
+        )
+    })?;
+
+    let mut chain = Vec::new();
+    for certificate in &certificates {
+        chain.extend(certificate.to_pem()?);
+    }
+    let identity = Identity::from_pkcs8_pem(&chain, &key.private_key_to_pem_pkcs8()?)?;
+    Ok((identity, not_after(&certificates[0])?))
+}
+
+/// Load the certificates of a PEM CA bundle, to trust along with the system ones
+pub(crate) fn load_ca_certificates(filename: &str) -> Result<Vec<Certificate>, Error> {
+    let (filename, buf) = read_tls_file(filename, "CA certificate")?;
+    let certificates = X509::stack_from_pem(&buf)
+        .ok()
+        .filter(|certificates| !certificates.is_empty())
+        .ok_or_else(|| {
+            anyhow!(
+                "Unable to parse the CA certificate {}: expected a PEM certificate bundle",
+                filename
+            )
+        })?;
+    log::info!(
+        "Trusting the {} CA certificate(s) of {}",
+        certificates.len(),
+        filename
+    );
+    certificates
+        .iter()
+        .map(|certificate| Ok(Certificate::from_der(&certificate.to_der()?)?))
+        .collect()
+}
+
+/// Create an identity from a private key and certificate registered in a PKCS12 file (with or without password), or
+/// from a PEM certificate along with its PEM private key, in the key file if any
+pub(crate) fn build_identity_from_file(
     filename: String,
     password: Option<String>,
-) -> Result<Identity, Error> {
-    let mut buf = Vec::new();
-    File::open(filename.as_str())?.read_to_end(&mut buf)?;
+    key_filename: Option<String>,
+) -> Result<ClientIdentity, Error> {
+    let (filename, buf) = read_tls_file(&filename, "TLS client certificate")?;
+    let key = key_filename
+        .map(|key_filename| read_tls_file(&key_filename, "TLS client key"))
+        .transpose()?;
 
-    log::info!("Building identity from {} PFX/P12 file", filename);
-    let identity = match password {
-        Some(p) => Identity::from_pkcs12_der(&buf, p.as_str())?,
-        None => Identity::from_pkcs12_der(&buf, "")?,
+    let (identity, expires_at) = if key.is_some() || is_pem(&buf) {
+        log::info!("Building identity from {} PEM file", filename);
+        build_pem_identity(&filename, &buf, key, password.as_deref())?
+    } else {
+        log::info!("Building identity from {} PFX/P12 file", filename);
+        let password = password.unwrap_or_default();
+        let identity = Identity::from_pkcs12_der(&buf, password.as_str()).map_err(|error| {
+            anyhow!(
+                "Unable to parse the TLS client certificate {}: expected a PKCS12 (PFX/P12) file, or a PEM certificate: {}",
+                filename,
+                error
+            )
+        })?;
+        let expires_at = certificate_expiry(&buf, password.as_str()).map_err(|error| {
+            anyhow!("Unable to read the certificate of {}: {}", filename, error)
+        })?;
+        (identity, expires_at)
     };
+    log::debug!("Certificate of {} expires on {}", filename, expires_at);
+    if expires_at <= Utc::now() {
+        return Err(anyhow!(
+            "The TLS client certificate {} expired on {}",
+            filename,
+            expires_at
+        ));
+    }
+
+    Ok(ClientIdentity {
+        identity,
+        expires_at,
+    })
+}
+
+/// The maximum number of redirects followed for a single request
+const MAX_REDIRECTS: usize = 10;
+
+/// Follow the redirects within the same origin only, so the authentication headers never reach another host
+pub(crate) fn same_origin_redirect_policy() -> Policy {
+    Policy::custom(|attempt| {
+        let origin = attempt.previous().first().map(|url| url.origin());
+        if origin.as_ref() != Some(&attempt.url().origin()) {
+            let error = format!(
+                "Refusing to follow the redirect to another host ({}), the authentication headers would be sent there",
+                attempt.url()
+            );
+            attempt.error(error)
+        } else if attempt.previous().len() > MAX_REDIRECTS {
+            let error = format!("Too many redirects, last one to {}", attempt.url());
+            attempt.error(error)
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// The error of a request refused by a client whose writes are disabled, as it could have changed the service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadOnlyViolation {
+    pub service: &'static str,
+    pub method: String,
+    pub endpoint: String,
+}
+
+impl fmt::Display for ReadOnlyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Read-only violation: refused {} {} on {}",
+            self.method, self.endpoint, self.service
+        )
+    }
+}
+
+impl std::error::Error for ReadOnlyViolation {}
+
+/// Refuse the requests which could change the service, but the GET, HEAD and the POSTs to the given read-only endpoints
+pub(crate) fn check_read_only(
+    service: &'static str,
+    template: &str,
+    request: &RequestBuilder,
+    read_only_posts: &[&str],
+) -> Result<(), ReadOnlyViolation> {
+    let method = request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .map(|request| request.method().clone());
+    match method {
+        Some(Method::GET | Method::HEAD) => Ok(()),
+        Some(Method::POST) if read_only_posts.contains(&template) => Ok(()),
+        method => Err(ReadOnlyViolation {
+            service,
+            method: method
+                .map(|method| method.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            endpoint: template.to_string(),
+        }),
+    }
+}
+
+/// The error of a request the service answered with an error status, so the callers can tell the statuses apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusError {
+    pub service: &'static str,
+    pub endpoint: String,
+    pub status: u16,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} answered {} on {}",
+            self.service, self.status, self.endpoint
+        )
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Fail with a `StatusError` on the responses with an error status
+pub(crate) fn check_status(
+    service: &'static str,
+    template: &str,
+    response: Response,
+) -> Result<Response, StatusError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    Err(StatusError {
+        service,
+        endpoint: template.to_string(),
+        status: response.status().as_u16(),
+    })
+}
+
+/// The error of a write the service answered with an error status, with the message of the failed action and the
+/// `StatusError` as its cause
+pub(crate) fn write_error(
+    service: &'static str,
+    template: &str,
+    status: StatusCode,
+    message: String,
+) -> Error {
+    Error::new(StatusError {
+        service,
+        endpoint: template.to_string(),
+        status: status.as_u16(),
+    })
+    .context(message)
+}
+
+/// The error of a read the service answered with an error status, with the message of the failed query and the
+/// `StatusError` as its cause
+pub(crate) fn read_error(
+    service: &'static str,
+    template: &str,
+    status: StatusCode,
+    message: String,
+) -> Error {
+    Error::new(StatusError {
+        service,
+        endpoint: template.to_string(),
+        status: status.as_u16(),
+    })
+    .context(message)
+}
+
+/// Is the error caused by a 403 status, the token lacking the privilege for the request
+pub(crate) fn is_forbidden(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<StatusError>()
+            .is_some_and(|error| error.status == StatusCode::FORBIDDEN.as_u16())
+    })
+}
+
+/// Is the error caused by a service which could not be reached: a connection failure, a timeout, or a gateway status
+/// of the load balancer in front of it
+pub fn is_unreachable(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+            return error.is_connect() || error.is_timeout();
+        }
+        cause
+            .downcast_ref::<StatusError>()
+            .is_some_and(|error| (502..=504).contains(&error.status))
+    })
+}
+
+/// Name the endpoint and the limit of a request which timed out, leaving the other errors as they are
+pub(crate) fn timeout_context(
+    error: Error,
+    service: &str,
+    template: &str,
+    limit: Option<Duration>,
+) -> Error {
+    match limit {
+        Some(limit) if is_timeout(&error) => error.context(format!(
+            "{} {} timed out after {:?}",
+            service, template, limit
+        )),
+        _ => error,
+    }
+}
+
+/// The time limit of a request, when it has its own
+pub fn request_timeout(request: &RequestBuilder) -> Option<Duration> {
+    request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .and_then(|request| request.timeout().copied())
+}
+
+/// Is the error caused by a request timeout
+pub fn is_timeout(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|error| error.is_timeout())
+    })
+}
+
+/// Read the body of a response expected to be JSON, naming the likely cause when it is not,
+/// as auth proxies and expired sessions answer with a 200 HTML login page
+pub(crate) fn json_body(response: Response) -> Result<String, Error> {
+    let url = response.url().clone();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    // JSON is UTF-8 whatever the charset some servers declare, decoding the body as such would garble the names
+    let bytes = response.bytes().map_err(|error| match error.is_timeout() {
+        true => Error::from(error).context(format!("Timed out reading the answer of {}", url)),
+        false => error.into(),
+    })?;
+    let body = String::from_utf8_lossy(&bytes);
+
+    let content_type = match content_type {
+        Some(content_type) if !content_type.contains("json") => content_type,
+        // Some servers omit the header, an HTML body is still recognizable
+        None if body.trim_start().starts_with('<') => "text/html".to_string(),
+        _ => {
+            return String::from_utf8(bytes.to_vec())
+                .map_err(|_| anyhow!("The JSON received from {} is not valid UTF-8", url))
+        }
+    };
+    let first_line = body
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    Err(anyhow!(
+        "Expected JSON but received {} from {}, the token is likely invalid or an auth proxy intercepted the request: {}",
+        content_type,
+        url,
+        first_line
+    ))
+}
+
+/// Describe a raw record using its id and name, whenever they are present
+fn describe_record(value: &Value) -> String {
+    let id = value.get("id").map(|id| id.to_string());
+    let name = value.get("name").and_then(|name| name.as_str());
+    match (id, name) {
+        (Some(id), Some(name)) => format!("{} (id {})", name, id),
+        (Some(id), None) => format!("id {}", id),
+        (None, Some(name)) => name.to_string(),
+        (None, None) => "without id nor name".to_string(),
+    }
+}
+
+/// Parse a list of records one by one, so a malformed record can be named and optionally skipped
+pub(crate) fn parse_records<T, F>(
+    values: Vec<Value>,
+    kind: &str,
+    skip_bad_records: bool,
+    parse: F,
+) -> Result<Vec<T>, Error>
+where
+    F: Fn(Value) -> Result<T, Error>,
+{
+    let mut records = Vec::with_capacity(values.len());
+    for (index, value) in values.into_iter().enumerate() {
+        let description = describe_record(&value);
+        let pretty_value = serde_json::to_string_pretty(&value)?;
+        match parse(value) {
+            Ok(record) => records.push(record),
+            Err(error) if skip_bad_records => {
+                log::warn!(
+                    "Skipping malformed {} {} at index {}: {}",
+                    kind,
+                    description,
+                    index,
+                    error
+                );
+            }
+            Err(error) => {
+                log::warn!(
+                    "Malformed {} {} at index {}: {}",
+                    kind,
+                    description,
+                    index,
+                    error
+                );
+                return Err(anyhow!(
+                    "Failed to parse {} {} at index {}: {}\n{}",
+                    kind,
+                    description,
+                    index,
+                    error,
+                    pretty_value
+                ));
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_expiry() {
+        let identity = build_identity_from_file(
+            String::from("tests/data/certificates/valid.p12"),
+            Some(String::from("test")),
+            None,
+        )
+        .unwrap();
+        assert_eq!(identity.expires_at.to_string(), "2099-01-01 12:00:00 UTC");
+    }
+
+    #[test]
+    fn ca_certificates() {
+        let certificates = load_ca_certificates("tests/data/certificates/valid.crt").unwrap();
+        assert_eq!(certificates.len(), 1);
+        assert_eq!(
+            load_ca_certificates("tests/data/certificates/valid.pem")
+                .unwrap()
+                .len(),
+            1
+        );
+
+        for garbage in ["valid.key", "valid.p12"] {
+            let error =
+                load_ca_certificates(&format!("tests/data/certificates/{}", garbage)).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "Unable to parse the CA certificate tests/data/certificates/{}: expected a PEM certificate bundle",
+                    garbage
+                )
+            );
+        }
+        let error = load_ca_certificates("tests/data/certificates/missing.crt").unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Unable to read the CA certificate tests/data/certificates/missing.crt"));
+    }
+
+    #[test]
+    fn pem_identities() {
+        // A certificate and its PKCS#8 key apart, or along with its SEC1 key in the same file
+        for (certificate, key) in [("valid.crt", Some("valid.key")), ("valid.pem", None)] {
+            let identity = build_identity_from_file(
+                format!("tests/data/certificates/{}", certificate),
+                None,
+                key.map(|key| format!("tests/data/certificates/{}", key)),
+            )
+            .unwrap();
+            assert_eq!(identity.expires_at.to_string(), "2099-01-01 12:00:00 UTC");
+        }
+
+        let error = build_identity_from_file(
+            String::from("tests/data/certificates/valid.crt"),
+            None,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unable to parse the TLS client key tests/data/certificates/valid.crt: expected a PEM private key (PKCS#8, PKCS#1 or SEC1)"
+        );
+        let error = build_identity_from_file(
+            String::from("tests/data/certificates/valid.p12"),
+            None,
+            Some(String::from("tests/data/certificates/valid.key")),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unable to parse the TLS client certificate tests/data/certificates/valid.p12: expected a PEM certificate"
+        );
+        let error = build_identity_from_file(
+            String::from("tests/data/certificates/valid.p12"),
+            Some(String::from("wrong")),
+            None,
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("expected a PKCS12 (PFX/P12) file, or a PEM certificate"));
+    }
 
-    Ok(identity)
+    #[test]
+    fn expired_identity() {
+        let error = build_identity_from_file(
+            String::from("tests/data/certificates/expired.p12"),
+            Some(String::from("test")),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The TLS client certificate tests/data/certificates/expired.p12 expired on 2020-06-01 00:00:00 UTC"
+        );
+    }
 }
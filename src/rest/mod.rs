@@ -1,3 +1,11 @@
+pub mod api;
+pub mod graphql;
 pub mod helpers;
 pub mod netbox;
 pub mod netshot;
+pub mod optional;
+pub mod query;
+pub(crate) mod ratelimit;
+pub mod retry;
+pub mod snapshot;
+pub mod stats;
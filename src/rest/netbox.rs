@@ -1,15 +1,64 @@
 use crate::common::APP_USER_AGENT;
-use crate::rest::helpers::build_identity_from_file;
+use crate::progress::Progress;
+use crate::rest::graphql::{self, List, NetboxApiMode, GRAPHQL_LIMIT, PATH_GRAPHQL};
+use crate::rest::helpers::{
+    build_identity_from_file, check_read_only, check_status, json_body, load_ca_certificates,
+    parse_records, request_timeout, same_origin_redirect_policy, timeout_context, DEFAULT_TIMEOUT,
+};
+use crate::rest::ratelimit::RateLimit;
+use crate::rest::retry::RetryPolicy;
+use crate::rest::snapshot::{FetchSnapshot, Fetched, Source};
+use crate::rest::stats::{self, RequestStats};
 use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Utc};
+use reqwest::blocking::{RequestBuilder, Response};
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Proxy;
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 const API_LIMIT: u32 = 100;
-const PATH_PING: &str = "/api/dcim/devices/?name=netbox2netshot-ping";
+pub const PATH_PING: &str = "/api/dcim/devices/?name=netbox2netshot-ping";
 const PATH_DCIM_DEVICES: &str = "/api/dcim/devices/";
 const PATH_VIRT_VM: &str = "/api/virtualization/virtual-machines/";
+const PATH_DCIM_INTERFACES: &str = "/api/dcim/interfaces/";
+const PATH_IPAM_IP_ADDRESSES: &str = "/api/ipam/ip-addresses/";
+const PATH_DCIM_SITES: &str = "/api/dcim/sites/";
+
+/// The number of device or interface IDs filtered on by a single request
+const ID_BATCH_SIZE: usize = 50;
+
+/// The device statuses meaning the device has been taken out of service
+const DECOMMISSIONED_STATUSES: &[&str] = &["decommissioning"];
+
+/// The VM statuses meaning the VM is not running, so cannot be discovered
+const POWERED_OFF_VM_STATUSES: &[&str] = &["offline", "paused"];
+
+/// The POST requests which only read Netbox
+const READ_ONLY_POSTS: &[&str] = &[PATH_GRAPHQL];
+
+/// The VM filter selecting a cluster by ID, or by name
+pub fn vms_cluster_query(cluster: &str) -> String {
+    if cluster.parse::<u32>().is_ok() {
+        return format!("cluster_id={}", cluster);
+    }
+    let mut url = reqwest::Url::parse("http://netbox/").expect("The base URL is valid");
+    url.query_pairs_mut().append_pair("cluster", cluster);
+    url.query().unwrap_or_default().to_string()
+}
+
+/// The devices fetched with several filters, those matched by more than one of them being kept once
+pub fn merge_devices(results: Vec<Vec<Device>>) -> Vec<Device> {
+    let mut seen = HashSet::new();
+    results
+        .into_iter()
+        .flatten()
+        .filter(|device| seen.insert(device.id))
+        .collect()
+}
 
 /// The Netbox client
 #[derive(Debug)]
@@ -17,70 +66,597 @@ pub struct NetboxClient {
     pub url: String,
     pub token: String,
     pub client: reqwest::blocking::Client,
+    pub skip_bad_records: bool,
+    pub stats: Option<Arc<RequestStats>>,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    pub certificate_expiry: Option<DateTime<Utc>>,
+    pub ping_path: String,
+    pub read_only: bool,
+    pub api: NetboxApiMode,
+    pub graphql_filter: Option<String>,
+    graphql_page_size: u32,
+    deletion_cache: Mutex<HashMap<String, DeletionStatus>>,
+    site_cache: Mutex<HashMap<u32, HashMap<String, serde_json::Value>>>,
+    rate_limit: RateLimit,
+}
+
+/// What became of a device which left the filtered inventory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeletionStatus {
+    /// No device or VM has the IP as primary address anymore
+    Deleted,
+    /// The device is still there, with a decommissioned status
+    Decommissioned(String),
+    /// The device is still there and in service, it merely fell out of the filter
+    OutOfScope(String),
 }
 
 /// Represent the primary_ip field from the DCIM device API call
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PrimaryIP {
     pub id: u32,
-    pub family: u8,
+    #[serde(default, deserialize_with = "deserialize_family")]
+    pub family: Option<u8>,
     pub address: String,
 }
 
+/// Represent the parent_device field of a child device, installed in a device bay
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ParentDevice {
+    pub id: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Represent the virtual_chassis field of a stack member
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VirtualChassis {
+    pub id: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Unset until a member is elected master on Netbox
+    #[serde(default)]
+    pub master: Option<VirtualChassisMaster>,
+}
+
+/// Represent the master field of a virtual chassis
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VirtualChassisMaster {
+    pub id: u32,
+}
+
+/// Represent the role field from the DCIM device API call
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Role {
+    pub id: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub slug: Option<String>,
+}
+
+/// Represent the site field from the DCIM device API call
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Site {
+    pub id: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub slug: Option<String>,
+}
+
+/// Represent the rack field from the DCIM device API call
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Rack {
+    pub id: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
 /// Represent the required information from the DCIM device API call
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Device {
     pub id: u32,
+    #[serde(default, deserialize_with = "deserialize_name")]
     pub name: Option<String>,
+    #[serde(default)]
     pub primary_ip4: Option<PrimaryIP>,
+    #[serde(default)]
+    pub primary_ip6: Option<PrimaryIP>,
+    #[serde(default)]
+    pub primary_ip: Option<PrimaryIP>,
+    #[serde(default)]
+    pub role: Option<Role>,
+    /// Only set for the child devices, e.g. independently managed line cards
+    #[serde(default)]
+    pub parent_device: Option<ParentDevice>,
+    /// Only set for the members of a virtual chassis, e.g. stacked switches
+    #[serde(default)]
+    pub virtual_chassis: Option<VirtualChassis>,
+    #[serde(default)]
+    pub vc_position: Option<u32>,
+    #[serde(default)]
+    pub vc_priority: Option<u32>,
+    // Not an alias of `role`: Netbox 3.6 and 3.7 send both fields, which serde would reject as a duplicate
+    #[serde(default)]
+    device_role: Option<Role>,
+    // A {value, label} object, the value being a number on the oldest Netbox releases, or a GraphQL enum string
+    #[serde(default)]
+    status: Option<serde_json::Value>,
+    #[serde(default)]
+    site: Option<Site>,
+    #[serde(default)]
+    rack: Option<Rack>,
+    // Blank rather than null when unset
+    #[serde(default)]
+    serial: Option<String>,
+    #[serde(default)]
+    asset_tag: Option<String>,
+    // {id, name, slug} objects, plain names on the releases older than 2.9
+    #[serde(default)]
+    tags: Vec<serde_json::Value>,
+    #[serde(default)]
+    custom_fields: BTreeMap<String, serde_json::Value>,
+    /// Set for the devices fetched from the virtual machines endpoint
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub virtual_machine: bool,
 }
 
 /// Represent the API response from /api/dcim/devices call
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct NetboxDCIMDeviceList {
     count: u32,
     next: Option<String>,
     previous: Option<String>,
     results: Vec<Device>,
+    /// The records on the page, including the bad ones skipped
+    #[serde(skip)]
+    received: usize,
+}
+
+/// A page of any Netbox list endpoint
+#[derive(Debug, Deserialize)]
+struct NetboxPage<T> {
+    next: Option<String>,
+    results: Vec<T>,
+}
+
+/// The object an interface or IP address is attached to
+#[derive(Debug, Deserialize)]
+struct NestedObject {
+    id: u32,
+}
+
+/// Represent the required information from the DCIM interface API call
+#[derive(Debug, Deserialize)]
+struct Interface {
+    id: u32,
+    device: NestedObject,
+}
+
+/// Represent the custom fields from the DCIM site API call
+#[derive(Debug, Deserialize)]
+struct SiteCustomFields {
+    id: u32,
+    #[serde(default)]
+    custom_fields: HashMap<String, serde_json::Value>,
+}
+
+/// Represent the required information from the IPAM IP address API call
+#[derive(Debug, Deserialize)]
+struct IpAddress {
+    id: u32,
+    #[serde(default, deserialize_with = "deserialize_family")]
+    family: Option<u8>,
+    address: String,
+    #[serde(default)]
+    assigned_object_id: Option<u32>,
+}
+
+/// Represent the API response from /api/dcim/devices call before the devices are parsed
+#[derive(Debug, Deserialize)]
+struct NetboxRawDeviceList {
+    count: u32,
+    next: Option<String>,
+    previous: Option<String>,
+    results: Vec<serde_json::Value>,
+}
+
+/// Empty and whitespace-only names are considered missing
+fn deserialize_name<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.filter(|name| !name.trim().is_empty()))
+}
+
+/// The address family is a plain number in nested IP addresses, but an object on older Netbox releases
+fn deserialize_family<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Family {
+        Value(u8),
+        Object { value: u8 },
+    }
+
+    Ok(
+        Option::<Family>::deserialize(deserializer)?.map(|family| match family {
+            Family::Value(value) | Family::Object { value } => value,
+        }),
+    )
+}
+
+/// The request of a page of a list endpoint, with its offset for the logs and the errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PageRequest {
+    url: String,
+    limit: u32,
+    offset: u32,
+}
+
+impl PageRequest {
+    /// The page of the given offset, its URL built from the base URL of the client
+    fn at(base_url: &str, path: &str, query_string: &str, limit: u32, offset: u32) -> Self {
+        PageRequest {
+            url: format!(
+                "{}{}?limit={}&offset={}&{}",
+                base_url, path, limit, offset, query_string
+            ),
+            limit,
+            offset,
+        }
+    }
+
+    /// The page following this one of the given number of records, at the `next` URL Netbox announced, or at the
+    /// following offset when that URL is unusable. Fails when the pagination does not move forward, so that a
+    /// misbehaving server cannot loop forever.
+    fn next(
+        &self,
+        base_url: &str,
+        path: &str,
+        query_string: &str,
+        next: &str,
+        received: usize,
+    ) -> Result<Self, Error> {
+        let page = match resolve_next_url(base_url, path, next) {
+            Ok(url) => {
+                let offset = url
+                    .query_pairs()
+                    .find(|(key, _)| key == "offset")
+                    .and_then(|(_, offset)| offset.parse().ok())
+                    .unwrap_or(self.offset + received as u32);
+                PageRequest {
+                    url: url.to_string(),
+                    limit: self.limit,
+                    offset,
+                }
+            }
+            Err(error) => {
+                let offset = self.offset + received as u32;
+                log::warn!(
+                    "Unusable next page URL {} from Netbox ({}), carrying on at offset {}",
+                    next,
+                    error,
+                    offset
+                );
+                PageRequest::at(base_url, path, query_string, self.limit, offset)
+            }
+        };
+        if page.url == self.url || page.offset < self.offset || received == 0 {
+            return Err(anyhow!(
+                "Netbox pagination does not advance, next page {} after offset {}",
+                next,
+                self.offset
+            ));
+        }
+        Ok(page)
+    }
 }
 
-/// Extract the offset from the URL returned from the API
-fn extract_offset(url_string: &String) -> Result<u32, Error> {
-    let url = reqwest::Url::parse(url_string)?;
-    let offset_string = url.query_pairs().find(|(key, _)| key == "offset");
-    match offset_string {
-        Some((_, x)) => Ok(x.parse()?),
-        None => Err(anyhow!("No offset found in url")),
+/// Resolve the `next` URL of a page. Behind a reverse proxy, Netbox may announce its own host, or another scheme:
+/// such a URL is resolved against the base URL of the client instead, keeping its query.
+fn resolve_next_url(base_url: &str, path: &str, next: &str) -> Result<reqwest::Url, Error> {
+    let endpoint = reqwest::Url::parse(&format!("{}{}", base_url, path))?;
+    let next_url = endpoint.join(next)?;
+    if !matches!(next_url.scheme(), "http" | "https") {
+        return Err(anyhow!("unsupported scheme {}", next_url.scheme()));
+    }
+    if next_url.origin() == endpoint.origin() {
+        return Ok(next_url);
     }
+    let mut resolved = endpoint;
+    resolved.set_query(next_url.query());
+    Ok(resolved)
 }
 
 impl Device {
-    /// Is this a valid device for import
-    pub fn is_valid(&self) -> bool {
-        self.primary_ip4.is_some() && self.name.is_some()
+    /// Is this a valid device for import, a primary IP being required and a name unless nameless devices are allowed
+    pub fn is_valid(&self, allow_nameless: bool) -> bool {
+        let has_primary_ip =
+            self.primary_ip4.is_some() || self.primary_ip6.is_some() || self.primary_ip.is_some();
+        has_primary_ip && (allow_nameless || self.name.is_some())
+    }
+
+    /// The value of a custom field of the device, `None` when unset
+    pub fn custom_field(&self, name: &str) -> Option<&serde_json::Value> {
+        self.custom_fields
+            .get(name)
+            .filter(|value| !value.is_null())
+    }
+
+    /// The device name, or a `netbox-id-<id>` pseudo-name for the nameless devices
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("netbox-id-{}", self.id),
+        }
+    }
+
+    /// The device status value (e.g. `active`), falling back to its label on the releases using numbers
+    pub fn status(&self) -> Option<String> {
+        let status = self.status.as_ref()?;
+        if let Some(status) = status.as_str() {
+            return Some(status.to_lowercase());
+        }
+        match status.get("value").and_then(|value| value.as_str()) {
+            Some(value) => Some(value.to_string()),
+            None => status
+                .get("label")
+                .and_then(|label| label.as_str())
+                .map(str::to_lowercase),
+        }
+    }
+
+    /// The name of the parent device of a child device, or a `netbox-id-<id>` pseudo-name
+    pub fn parent_name(&self) -> Option<String> {
+        self.parent_device
+            .as_ref()
+            .map(|parent| match &parent.name {
+                Some(name) => name.clone(),
+                None => format!("netbox-id-{}", parent.id),
+            })
+    }
+
+    /// Is it the master of its virtual chassis
+    pub fn is_virtual_chassis_master(&self) -> bool {
+        self.virtual_chassis
+            .as_ref()
+            .and_then(|chassis| chassis.master.as_ref())
+            .is_some_and(|master| master.id == self.id)
+    }
+
+    /// Is it a VM whose status tells it is not running
+    pub fn is_powered_off_vm(&self) -> bool {
+        self.virtual_machine
+            && self
+                .status()
+                .is_some_and(|status| POWERED_OFF_VM_STATUSES.contains(&status.as_str()))
+    }
+
+    /// Is the given IP the primary address of the device
+    pub fn has_primary_ip(&self, ip: &str) -> bool {
+        [&self.primary_ip4, &self.primary_ip6, &self.primary_ip]
+            .iter()
+            .filter_map(|primary_ip| primary_ip.as_ref())
+            .any(|primary_ip| primary_ip.address.split('/').next() == Some(ip))
+    }
+
+    /// Use the given IP as management address, in place of the primary IP of the same family
+    pub fn set_management_ip(&mut self, ip: PrimaryIP) {
+        if ip.family == Some(6) {
+            self.primary_ip4 = None;
+            self.primary_ip6 = Some(ip);
+        } else {
+            self.primary_ip4 = Some(ip);
+        }
+    }
+
+    /// The device role, whichever field name the Netbox release uses
+    pub fn role(&self) -> Option<&Role> {
+        self.role.as_ref().or(self.device_role.as_ref())
+    }
+
+    /// The device site, unset for the VMs outside of any site
+    pub fn site(&self) -> Option<&Site> {
+        self.site.as_ref()
+    }
+
+    /// The rack the device is mounted in, if any
+    pub fn rack(&self) -> Option<&Rack> {
+        self.rack.as_ref()
+    }
+
+    /// The serial number of the device, if it is set
+    pub fn serial(&self) -> Option<&str> {
+        self.serial
+            .as_deref()
+            .filter(|serial| !serial.trim().is_empty())
+    }
+
+    /// The asset tag of the device, if it is set
+    pub fn asset_tag(&self) -> Option<&str> {
+        self.asset_tag
+            .as_deref()
+            .filter(|asset_tag| !asset_tag.trim().is_empty())
+    }
+
+    /// The slugs of the device tags, or their names on the releases without tag objects
+    pub fn tags(&self) -> Vec<String> {
+        self.tags
+            .iter()
+            .filter_map(|tag| {
+                tag.get("slug")
+                    .and_then(|slug| slug.as_str())
+                    .or_else(|| tag.as_str())
+                    .map(str::to_string)
+            })
+            .collect()
     }
 }
 
-impl NetboxClient {
-    /// Create a client without authentication
-    pub fn new_anonymous(url: String, proxy: Option<String>) -> Result<Self, Error> {
-        NetboxClient::new(url, None, proxy, None, None)
+/// Builder of a Netbox client, gathering the connection options
+#[derive(Debug)]
+pub struct NetboxClientBuilder {
+    url: String,
+    token: Option<String>,
+    proxy: Option<String>,
+    tls_client_certificate: Option<String>,
+    tls_client_certificate_password: Option<String>,
+    tls_client_key: Option<String>,
+    ca_certificate: Option<String>,
+    insecure: bool,
+    skip_bad_records: bool,
+    stats: Option<Arc<RequestStats>>,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    rate_limit_floor: u32,
+    ping_path: String,
+    read_only: bool,
+    api: NetboxApiMode,
+    graphql_filter: Option<String>,
+    graphql_page_size: u32,
+}
+
+impl NetboxClientBuilder {
+    /// Start building an anonymous client to the given Netbox URL
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            token: None,
+            proxy: None,
+            tls_client_certificate: None,
+            tls_client_certificate_password: None,
+            tls_client_key: None,
+            ca_certificate: None,
+            insecure: false,
+            skip_bad_records: false,
+            stats: None,
+            retry: RetryPolicy::default(),
+            timeout: Some(DEFAULT_TIMEOUT),
+            rate_limit_floor: 0,
+            ping_path: PATH_PING.to_string(),
+            read_only: false,
+            api: NetboxApiMode::Rest,
+            graphql_filter: None,
+            graphql_page_size: GRAPHQL_LIMIT,
+        }
     }
 
-    /// Create a client with the given authentication token
-    pub fn new(
-        url: String,
-        token: Option<String>,
-        proxy: Option<String>,
+    /// The API token, the client being anonymous without one
+    pub fn token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// The proxy to go through, if any
+    pub fn proxy(mut self, proxy: Option<String>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// The PKCS12 file to authenticate with, if any, and its optional password
+    pub fn tls_client_certificate(
+        mut self,
         tls_client_certificate: Option<String>,
         tls_client_certificate_password: Option<String>,
-    ) -> Result<Self, Error> {
-        log::debug!("Creating new Netbox client to {}", url);
+    ) -> Self {
+        self.tls_client_certificate = tls_client_certificate;
+        self.tls_client_certificate_password = tls_client_certificate_password;
+        self
+    }
+
+    /// The PEM private key of a PEM TLS client certificate, unless it is in the certificate file
+    pub fn tls_client_key(mut self, tls_client_key: Option<String>) -> Self {
+        self.tls_client_key = tls_client_key;
+        self
+    }
+
+    /// The PEM CA bundle to trust along with the system CAs, if any
+    pub fn ca_certificate(mut self, ca_certificate: Option<String>) -> Self {
+        self.ca_certificate = ca_certificate;
+        self
+    }
+
+    /// Accept any server certificate, whoever issued it and whatever its name
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Skip the devices that cannot be parsed instead of failing
+    pub fn skip_bad_records(mut self, skip_bad_records: bool) -> Self {
+        self.skip_bad_records = skip_bad_records;
+        self
+    }
+
+    /// The statistics to record the requests into, if any
+    pub fn stats(mut self, stats: Option<Arc<RequestStats>>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// The time the service has to answer each request, 5 seconds by default, `None` for no limit
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How the requests failing transiently are retried, not at all by default
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Pause until the rate limit resets once fewer requests remain, 0 (the default) disabling the throttling
+    pub fn rate_limit_floor(mut self, rate_limit_floor: u32) -> Self {
+        self.rate_limit_floor = rate_limit_floor;
+        self
+    }
+
+    /// The path (and query string) requested to ping Netbox, for tokens not allowed on the default one
+    pub fn ping_path(mut self, ping_path: impl Into<String>) -> Self {
+        self.ping_path = ping_path.into();
+        self
+    }
+
+    /// Refuse any request which could change Netbox
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// The API the devices and VMs are fetched through, REST by default
+    pub fn api(mut self, api: NetboxApiMode) -> Self {
+        self.api = api;
+        self
+    }
+
+    /// The GraphQL arguments filtering the devices instead of those built from the REST filter, if any
+    pub fn graphql_filter(mut self, graphql_filter: Option<String>) -> Self {
+        self.graphql_filter = graphql_filter;
+        self
+    }
+
+    /// The number of devices requested by GraphQL query
+    pub fn graphql_page_size(mut self, graphql_page_size: u32) -> Self {
+        self.graphql_page_size = graphql_page_size.max(1);
+        self
+    }
+
+    /// Create the client
+    pub fn build(self) -> Result<NetboxClient, Error> {
+        log::debug!("Creating new Netbox client to {}", self.url);
         let mut http_client = reqwest::blocking::Client::builder()
             .user_agent(APP_USER_AGENT)
-            .timeout(Duration::from_secs(5));
+            .timeout(self.timeout)
+            .redirect(same_origin_redirect_policy());
 
-        http_client = match token {
+        http_client = match self.token {
             Some(ref t) => {
                 let mut http_headers = HeaderMap::new();
                 let header_value = HeaderValue::from_str(format!("Token {}", t).as_str())?;
@@ -90,60 +666,201 @@ impl NetboxClient {
             None => http_client,
         };
 
-        http_client = match proxy {
+        http_client = match self.proxy {
             Some(p) => http_client.proxy(Proxy::all(p)?),
             None => http_client,
         };
 
-        http_client = match tls_client_certificate {
-            Some(c) => http_client.identity(build_identity_from_file(
-                c,
-                tls_client_certificate_password,
-            )?),
+        if let Some(ca_certificate) = self.ca_certificate {
+            for certificate in load_ca_certificates(&ca_certificate)? {
+                http_client = http_client.add_root_certificate(certificate);
+            }
+        }
+        if self.insecure {
+            log::warn!(
+                "The TLS certificate of Netbox at {} is NOT verified, anyone on the path can impersonate it",
+                self.url
+            );
+            http_client = http_client.danger_accept_invalid_certs(true);
+        }
+
+        let mut certificate_expiry = None;
+        http_client = match self.tls_client_certificate {
+            Some(c) => {
+                let client_identity = build_identity_from_file(
+                    c,
+                    self.tls_client_certificate_password,
+                    self.tls_client_key,
+                )?;
+                certificate_expiry = Some(client_identity.expires_at);
+                http_client.identity(client_identity.identity)
+            }
             None => http_client,
         };
 
-        Ok(Self {
-            url,
-            token: token.unwrap_or("".to_string()),
+        Ok(NetboxClient {
+            url: self.url,
+            token: self.token.unwrap_or_default(),
             client: http_client.build()?,
+            skip_bad_records: self.skip_bad_records,
+            stats: self.stats,
+            retry: self.retry,
+            timeout: self.timeout,
+            certificate_expiry,
+            ping_path: self.ping_path,
+            read_only: self.read_only,
+            api: self.api,
+            graphql_filter: self.graphql_filter,
+            graphql_page_size: self.graphql_page_size,
+            deletion_cache: Mutex::new(HashMap::new()),
+            site_cache: Mutex::new(HashMap::new()),
+            rate_limit: RateLimit::new(self.rate_limit_floor),
         })
     }
+}
+
+impl NetboxClient {
+    /// Start building a client to the given Netbox URL
+    pub fn builder(url: impl Into<String>) -> NetboxClientBuilder {
+        NetboxClientBuilder::new(url)
+    }
+
+    /// Create a client without authentication
+    pub fn new_anonymous(url: String, proxy: Option<String>) -> Result<Self, Error> {
+        NetboxClient::builder(url).proxy(proxy).build()
+    }
+
+    /// Create a client with the given authentication token
+    pub fn new(
+        url: String,
+        token: Option<String>,
+        proxy: Option<String>,
+        tls_client_certificate: Option<String>,
+        tls_client_certificate_password: Option<String>,
+    ) -> Result<Self, Error> {
+        NetboxClient::builder(url)
+            .token(token)
+            .proxy(proxy)
+            .tls_client_certificate(tls_client_certificate, tls_client_certificate_password)
+            .build()
+    }
+
+    /// Send a request to the given endpoint template, recording it when statistics are collected,
+    /// after waiting for the rate limit to reset when the previous response asked for it
+    fn send(&self, template: &str, request: RequestBuilder) -> Result<Response, Error> {
+        if self.read_only {
+            check_read_only("Netbox", template, &request, READ_ONLY_POSTS)?;
+        }
+        if let Some(pause) = self.rate_limit.wait("Netbox") {
+            let throttled = request.try_clone().and_then(|request| request.build().ok());
+            if let (Some(stats), Some(throttled)) = (&self.stats, throttled) {
+                stats.record_throttle(
+                    throttled.method().as_str(),
+                    template,
+                    throttled.url().as_str(),
+                    pause,
+                );
+            }
+        }
+        let limit = request_timeout(&request).or(self.timeout);
+        let response = stats::send(
+            &self.client,
+            self.stats.as_deref(),
+            &self.retry,
+            READ_ONLY_POSTS,
+            template,
+            request,
+        )
+        .map_err(|error| timeout_context(error, "Netbox", template, limit))?;
+        self.rate_limit.update(response.headers());
+        Ok(response)
+    }
 
     /// Ping the service to make sure it is reachable and pass the authentication (if there is any)
     pub fn ping(&self) -> Result<bool, Error> {
-        let url = format!("{}{}", self.url, PATH_PING);
+        let url = format!("{}{}", self.url, self.ping_path);
         log::debug!("Pinging {}", url);
-        let response = self.client.get(url).send()?;
+        let template = self.ping_path.split('?').next().unwrap_or_default();
+        let response = self.send(template, self.client.get(url))?;
         log::debug!("Ping response: {}", response.status());
         Ok(response.status().is_success())
     }
 
+    /// Make sure an anonymous client actually sees devices, as Netbox answers restricted querysets with empty pages
+    pub fn check_anonymous_access(&self, query_string: &str) -> Result<(), Error> {
+        if !self.ping()? {
+            return Err(anyhow!(
+                "Anonymous access to Netbox {} was refused",
+                self.url
+            ));
+        }
+
+        let page = self.get_devices_page(PATH_DCIM_DEVICES, query_string, 1, 0)?;
+        if page.count == 0 {
+            return Err(anyhow!(
+                "Anonymous access to Netbox {} returned no devices, the queryset is probably restricted to authenticated users",
+                self.url
+            ));
+        }
+        Ok(())
+    }
+
     /// Get a single device page
     pub fn get_devices_page(
         &self,
         path: &str,
-        query_string: &String,
+        query_string: &str,
         limit: u32,
         offset: u32,
     ) -> Result<NetboxDCIMDeviceList, Error> {
-        let url = format!(
-            "{}{}?limit={}&offset={}&{}",
-            self.url, path, limit, offset, query_string
-        );
-        let page: NetboxDCIMDeviceList = self.client.get(url).send()?.json()?;
-        Ok(page)
+        let page = PageRequest::at(&self.url, path, query_string, limit, offset);
+        self.get_devices_page_at(path, &page)
+    }
+
+    /// Get the device page of the given request
+    fn get_devices_page_at(
+        &self,
+        path: &str,
+        request: &PageRequest,
+    ) -> Result<NetboxDCIMDeviceList, Error> {
+        let page: NetboxRawDeviceList =
+            serde_json::from_str(&json_body(self.send(path, self.client.get(&request.url))?)?)?;
+        let received = page.results.len();
+
+        let results = parse_records(page.results, "device", self.skip_bad_records, |value| {
+            Ok(serde_json::from_value(value)?)
+        })
+        .map_err(|error| anyhow!("{} (offset {} of {})", error, request.offset, path))?;
+
+        Ok(NetboxDCIMDeviceList {
+            count: page.count,
+            next: page.next,
+            previous: page.previous,
+            results,
+            received,
+        })
     }
 
     /// Get the devices using the given filter
-    pub fn get_devices(&self, query_string: &String) -> Result<Vec<Device>, Error> {
+    pub fn get_devices(&self, query_string: &str) -> Result<Fetched<Device>, Error> {
+        if self.api == NetboxApiMode::Graphql {
+            let arguments = match &self.graphql_filter {
+                Some(filter) => filter.clone(),
+                None => graphql::filter_arguments(query_string)?,
+            };
+            return self.get_graphql_list(List::Devices, &arguments);
+        }
         let mut devices: Vec<Device> = Vec::new();
-        let mut offset = 0;
+        let mut snapshot = FetchSnapshot::start(Source::NetboxDevices);
+        let progress = Progress::start(format!("Fetching the {}", Source::NetboxDevices), None);
+        let mut page = PageRequest::at(&self.url, PATH_DCIM_DEVICES, query_string, API_LIMIT, 0);
 
         loop {
-            let mut response =
-                self.get_devices_page(PATH_DCIM_DEVICES, &query_string, API_LIMIT, offset)?;
+            let mut response = self.get_devices_page_at(PATH_DCIM_DEVICES, &page)?;
 
+            snapshot.page(response.received, Some(response.count));
+            progress.total(response.count as usize);
+            progress.advance(response.received);
             devices.append(&mut response.results);
 
             let pages_count = response.count / API_LIMIT;
@@ -151,31 +868,48 @@ impl NetboxClient {
                 "Got {} devices on the {} matches (page {}/{})",
                 devices.len(),
                 response.count,
-                (offset / API_LIMIT),
+                (page.offset / API_LIMIT),
                 pages_count
             );
 
             match response.next {
-                Some(x) => {
-                    offset = extract_offset(&x)?;
+                Some(next) => {
+                    page = page.next(
+                        &self.url,
+                        PATH_DCIM_DEVICES,
+                        query_string,
+                        &next,
+                        response.received,
+                    )?;
                 }
                 None => break,
             }
         }
 
         log::info!("Fetched {} devices from Netbox", devices.len());
-        Ok(devices)
+        Ok(Fetched {
+            snapshot: snapshot.finish(devices.len()),
+            items: devices,
+        })
     }
 
     /// Get the VMs as device using the given filter
-    pub fn get_vms(&self, query_string: &String) -> Result<Vec<Device>, Error> {
+    pub fn get_vms(&self, query_string: &str) -> Result<Fetched<Device>, Error> {
+        if self.api == NetboxApiMode::Graphql {
+            let arguments = graphql::filter_arguments(query_string)?;
+            return self.get_graphql_list(List::VirtualMachines, &arguments);
+        }
         let mut devices: Vec<Device> = Vec::new();
-        let mut offset = 0;
+        let mut snapshot = FetchSnapshot::start(Source::NetboxVms);
+        let progress = Progress::start(format!("Fetching the {}", Source::NetboxVms), None);
+        let mut page = PageRequest::at(&self.url, PATH_VIRT_VM, query_string, API_LIMIT, 0);
 
         loop {
-            let mut response =
-                self.get_devices_page(PATH_VIRT_VM, &query_string, API_LIMIT, offset)?;
+            let mut response = self.get_devices_page_at(PATH_VIRT_VM, &page)?;
 
+            snapshot.page(response.received, Some(response.count));
+            progress.total(response.count as usize);
+            progress.advance(response.received);
             devices.append(&mut response.results);
 
             let pages_count = response.count / API_LIMIT;
@@ -183,69 +917,809 @@ impl NetboxClient {
                 "Got {} VM devices on the {} matches (page {}/{})",
                 devices.len(),
                 response.count,
-                (offset / API_LIMIT),
+                (page.offset / API_LIMIT),
                 pages_count
             );
 
             match response.next {
-                Some(x) => {
-                    offset = extract_offset(&x)?;
+                Some(next) => {
+                    page = page.next(
+                        &self.url,
+                        PATH_VIRT_VM,
+                        query_string,
+                        &next,
+                        response.received,
+                    )?;
                 }
                 None => break,
             }
         }
 
+        for device in &mut devices {
+            device.virtual_machine = true;
+        }
         log::info!("Fetched {} VM devices from Netbox", devices.len());
-        Ok(devices)
+        Ok(Fetched {
+            snapshot: snapshot.finish(devices.len()),
+            items: devices,
+        })
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use mockito;
 
-    #[test]
-    fn anonymous_initialization() {
-        let url = mockito::server_url();
-        let client = NetboxClient::new_anonymous(url.clone(), None).unwrap();
-        assert_eq!(client.token, "");
-        assert_eq!(client.url, url);
-    }
+    /// Get the devices or VMs of a GraphQL list matching the given arguments, a page per query
+    fn get_graphql_list(&self, list: List, arguments: &str) -> Result<Fetched<Device>, Error> {
+        let mut devices: Vec<Device> = Vec::new();
+        let source = match list {
+            List::Devices => Source::NetboxDevices,
+            List::VirtualMachines => Source::NetboxVms,
+        };
+        let mut snapshot = FetchSnapshot::start(source);
+        // GraphQL announces no total
+        let progress = Progress::start(format!("Fetching the {}", source), None);
+        let mut offset = 0;
 
-    #[test]
-    fn authenticated_initialization() {
-        let url = mockito::server_url();
-        let token = String::from("hello");
-        let client = NetboxClient::new(url.clone(), Some(token.clone()), None, None, None).unwrap();
-        assert_eq!(client.token, token);
-        assert_eq!(client.url, url);
-    }
+        loop {
+            let query = graphql::list_query(list, arguments, self.graphql_page_size, offset);
+            log::debug!("Querying the Netbox GraphQL API: {}", query);
+            let request = self
+                .client
+                .post(format!("{}{}", self.url, PATH_GRAPHQL))
+                .json(&serde_json::json!({ "query": query }));
+            let response = check_status("Netbox", PATH_GRAPHQL, self.send(PATH_GRAPHQL, request)?)?;
+            let records = graphql::parse_response(&json_body(response)?, list)?;
+            let received = records.len();
 
-    #[test]
-    fn failed_ping() {
-        let url = mockito::server_url();
+            let mut page = parse_records(records, "device", self.skip_bad_records, |value| {
+                Ok(serde_json::from_value(value)?)
+            })
+            .map_err(|error| anyhow!("{} (offset {} of {})", error, offset, list.name()))?;
+            snapshot.page(received, None);
+            progress.advance(received);
+            devices.append(&mut page);
+            log::debug!(
+                "Got {} devices from the GraphQL {} (offset {})",
+                devices.len(),
+                list.name(),
+                offset
+            );
 
-        let _mock = mockito::mock("GET", mockito::Matcher::Any)
-            .with_status(403)
-            .create();
+            if received < self.graphql_page_size as usize {
+                break;
+            }
+            offset += self.graphql_page_size;
+        }
 
-        let client = NetboxClient::new_anonymous(url.clone(), None).unwrap();
-        let ping = client.ping().unwrap();
-        assert_eq!(ping, false);
+        if list == List::VirtualMachines {
+            for device in &mut devices {
+                device.virtual_machine = true;
+            }
+        }
+        log::info!("Fetched {} {} through GraphQL", devices.len(), source);
+        Ok(Fetched {
+            snapshot: snapshot.finish(devices.len()),
+            items: devices,
+        })
     }
 
-    #[test]
-    fn successful_ping() {
-        let url = mockito::server_url();
-
-        let _mock = mockito::mock("GET", PATH_PING)
-            .with_body_from_file("tests/data/netbox/ping.json")
-            .create();
+    /// Get every object of a list endpoint matching the given filter, page by page
+    fn get_all<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query_string: &str,
+    ) -> Result<Vec<T>, Error> {
+        let mut objects: Vec<T> = Vec::new();
+        let mut request = PageRequest::at(&self.url, path, query_string, API_LIMIT, 0);
 
-        let client = NetboxClient::new_anonymous(url.clone(), None).unwrap();
+        loop {
+            let response = check_status(
+                "Netbox",
+                path,
+                self.send(path, self.client.get(&request.url))?,
+            )?;
+            let mut page: NetboxPage<T> = serde_json::from_str(&json_body(response)?)?;
+            let received = page.results.len();
+            objects.append(&mut page.results);
+            match page.next {
+                Some(next) => {
+                    request = request.next(&self.url, path, query_string, &next, received)?;
+                }
+                None => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    /// Get the IP assigned to a management-only interface of each of the given devices, when they have one.
+    /// The devices, then their interfaces, are looked up by batches; IPv4 addresses are preferred.
+    pub fn get_management_ips(&self, device_ids: &[u32]) -> Result<HashMap<u32, PrimaryIP>, Error> {
+        let mut interface_devices: HashMap<u32, u32> = HashMap::new();
+        for batch in device_ids.chunks(ID_BATCH_SIZE) {
+            let query_string = batch
+                .iter()
+                .map(|id| format!("device_id={}", id))
+                .collect::<Vec<String>>()
+                .join("&");
+            let interfaces: Vec<Interface> = self.get_all(
+                PATH_DCIM_INTERFACES,
+                &format!("mgmt_only=true&{}", query_string),
+            )?;
+            interface_devices.extend(
+                interfaces
+                    .into_iter()
+                    .map(|interface| (interface.id, interface.device.id)),
+            );
+        }
+
+        let mut interface_ids: Vec<u32> = interface_devices.keys().copied().collect();
+        interface_ids.sort_unstable();
+        let mut management_ips: HashMap<u32, PrimaryIP> = HashMap::new();
+        for batch in interface_ids.chunks(ID_BATCH_SIZE) {
+            let query_string = batch
+                .iter()
+                .map(|id| format!("interface_id={}", id))
+                .collect::<Vec<String>>()
+                .join("&");
+            let addresses: Vec<IpAddress> = self.get_all(PATH_IPAM_IP_ADDRESSES, &query_string)?;
+            for address in addresses {
+                let device_id = match address
+                    .assigned_object_id
+                    .and_then(|interface_id| interface_devices.get(&interface_id))
+                {
+                    Some(device_id) => *device_id,
+                    None => continue,
+                };
+                let ip = PrimaryIP {
+                    id: address.id,
+                    family: address.family,
+                    address: address.address,
+                };
+                let keep_existing = management_ips
+                    .get(&device_id)
+                    .is_some_and(|existing| existing.family != Some(6) || ip.family == Some(6));
+                if !keep_existing {
+                    management_ips.insert(device_id, ip);
+                }
+            }
+        }
+
+        log::info!(
+            "Found a management interface IP for {} of the {} devices",
+            management_ips.len(),
+            device_ids.len()
+        );
+        Ok(management_ips)
+    }
+
+    /// Get the custom fields of the given sites, looked up by batches. The answers are cached.
+    pub fn get_site_custom_fields(
+        &self,
+        site_ids: &[u32],
+    ) -> Result<HashMap<u32, HashMap<String, serde_json::Value>>, Error> {
+        let missing: Vec<u32> = {
+            let cache = self.site_cache.lock().unwrap();
+            site_ids
+                .iter()
+                .filter(|site_id| !cache.contains_key(site_id))
+                .copied()
+                .collect()
+        };
+        for batch in missing.chunks(ID_BATCH_SIZE) {
+            let query_string = batch
+                .iter()
+                .map(|id| format!("id={}", id))
+                .collect::<Vec<String>>()
+                .join("&");
+            let sites: Vec<SiteCustomFields> = self.get_all(PATH_DCIM_SITES, &query_string)?;
+            self.site_cache
+                .lock()
+                .unwrap()
+                .extend(sites.into_iter().map(|site| (site.id, site.custom_fields)));
+        }
+
+        let cache = self.site_cache.lock().unwrap();
+        Ok(site_ids
+            .iter()
+            .filter_map(|site_id| Some((*site_id, cache.get(site_id)?.clone())))
+            .collect())
+    }
+
+    /// Get every device or VM of the given search, page by page, without the progress reporting of a full fetch
+    fn search_devices(&self, path: &str, query_string: &str) -> Result<Vec<Device>, Error> {
+        let mut devices: Vec<Device> = Vec::new();
+        let mut page = PageRequest::at(&self.url, path, query_string, API_LIMIT, 0);
+
+        loop {
+            let mut response = self.get_devices_page_at(path, &page)?;
+            devices.append(&mut response.results);
+            match response.next {
+                Some(next) => {
+                    page = page.next(&self.url, path, query_string, &next, response.received)?;
+                }
+                None => break,
+            }
+        }
+        Ok(devices)
+    }
+
+    /// Find out, outside of any filter, whether the device with the given primary IP has been deleted or
+    /// decommissioned. The VMs are looked up too when `include_vms` is set. The answers are cached.
+    pub fn deletion_status(&self, ip: &str, include_vms: bool) -> Result<DeletionStatus, Error> {
+        if let Some(status) = self.deletion_cache.lock().unwrap().get(ip) {
+            return Ok(status.clone());
+        }
+
+        // The search matches the primary addresses by prefix, hence the exact comparison
+        let query_string = format!("q={}", ip);
+        let mut devices = self.search_devices(PATH_DCIM_DEVICES, &query_string)?;
+        if include_vms {
+            devices.append(&mut self.search_devices(PATH_VIRT_VM, &query_string)?);
+        }
+
+        let mut status = DeletionStatus::Deleted;
+        for device in devices.iter().filter(|device| device.has_primary_ip(ip)) {
+            let device_status = device.status().unwrap_or_default();
+            if !DECOMMISSIONED_STATUSES.contains(&device_status.as_str()) {
+                status = DeletionStatus::OutOfScope(device.display_name());
+                break;
+            }
+            status = DeletionStatus::Decommissioned(device.display_name());
+        }
+        log::debug!("Netbox deletion status of {}: {:?}", ip, status);
+
+        self.deletion_cache
+            .lock()
+            .unwrap()
+            .insert(ip.to_string(), status.clone());
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attributes::{comment_lines, Attribute};
+    use crate::rest::helpers::ReadOnlyViolation;
+    use mockito;
+
+    #[test]
+    fn merged_filters() {
+        let device = |id: u32, name: &str| -> Device {
+            serde_json::from_value(serde_json::json!({"id": id, "name": name})).unwrap()
+        };
+        let edges = vec![device(1, "edge-1"), device(2, "edge-2"), device(5, "dual")];
+        let cores = vec![device(5, "dual"), device(3, "core-1"), device(2, "edge-2")];
+
+        let names = |devices: Vec<Device>| -> Vec<String> {
+            devices.iter().map(|device| device.display_name()).collect()
+        };
+        assert_eq!(
+            names(merge_devices(vec![edges.clone(), cores.clone()])),
+            vec!["edge-1", "edge-2", "dual", "core-1"]
+        );
+        assert_eq!(
+            names(merge_devices(vec![cores, Vec::new(), edges])),
+            vec!["dual", "core-1", "edge-2", "edge-1"]
+        );
+        assert!(merge_devices(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn read_only_client() {
+        let url = mockito::server_url();
+
+        let write = mockito::mock("POST", PATH_DCIM_DEVICES).expect(0).create();
+        let _devices = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/single_good_device.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone())
+            .read_only(true)
+            .build()
+            .unwrap();
+        assert_eq!(client.get_devices("").unwrap().items.len(), 1);
+        let error = client
+            .send(
+                PATH_DCIM_DEVICES,
+                client.client.post(format!("{}{}", url, PATH_DCIM_DEVICES)),
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ReadOnlyViolation>(),
+            Some(&ReadOnlyViolation {
+                service: "Netbox",
+                method: "POST".to_string(),
+                endpoint: PATH_DCIM_DEVICES.to_string(),
+            })
+        );
+        write.assert();
+    }
+
+    #[test]
+    fn anonymous_initialization() {
+        let url = mockito::server_url();
+        let client = NetboxClient::new_anonymous(url.clone(), None).unwrap();
+        assert_eq!(client.token, "");
+        assert_eq!(client.url, url);
+    }
+
+    #[test]
+    fn authenticated_initialization() {
+        let url = mockito::server_url();
+        let token = String::from("hello");
+        let client = NetboxClient::new(url.clone(), Some(token.clone()), None, None, None).unwrap();
+        assert_eq!(client.token, token);
+        assert_eq!(client.url, url);
+    }
+
+    #[test]
+    fn failed_ping() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", mockito::Matcher::Any)
+            .with_status(403)
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
         let ping = client.ping().unwrap();
-        assert_eq!(ping, true);
+        assert!(!ping);
+    }
+
+    #[test]
+    fn successful_ping() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_PING)
+            .with_body_from_file("tests/data/netbox/ping.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let ping = client.ping().unwrap();
+        assert!(ping);
+    }
+
+    #[test]
+    fn custom_ping_path() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", "/api/status/")
+            .with_body("{}")
+            .create();
+
+        let client = NetboxClient::builder(url.clone())
+            .ping_path("/api/status/")
+            .build()
+            .unwrap();
+        assert!(client.ping().unwrap());
+    }
+
+    #[test]
+    fn anonymous_access() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/single_good_device.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        assert!(client.check_anonymous_access("").is_ok());
+    }
+
+    #[test]
+    fn retried_requests() {
+        let url = mockito::server_url();
+
+        let unavailable = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_status(503)
+            .expect(2)
+            .create();
+        let devices = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/single_good_device.json")
+            .expect(1)
+            .create();
+
+        let stats = Arc::new(RequestStats::new(false));
+        let client = NetboxClient::builder(url.clone())
+            .stats(Some(stats.clone()))
+            .retry(RetryPolicy::new(3, Duration::from_millis(1)))
+            .build()
+            .unwrap();
+        assert_eq!(client.get_devices("").unwrap().items.len(), 1);
+        unavailable.assert();
+        devices.assert();
+        assert_eq!(stats.summary()[0].retried, 1);
+
+        // The client errors are not retried
+        let forbidden = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_status(403)
+            .expect(1)
+            .create();
+        assert!(client.get_devices("").is_err());
+        forbidden.assert();
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::UrlEncoded(
+                "site".to_string(),
+                "東京 DC".to_string(),
+            ))
+            .with_header("content-type", "application/json")
+            .with_body_from_file("tests/data/netbox/non_ascii_names.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let filter = crate::filter::encode_filter("site=東京 DC");
+        let devices = client.get_devices(&filter).unwrap().items;
+        let names: Vec<String> = devices.iter().map(|device| device.display_name()).collect();
+        assert_eq!(names, vec!["東京-コア-1", "Düsseldorf-Straße-sw-1"]);
+    }
+
+    #[test]
+    fn html_login_page() {
+        let url = mockito::server_url();
+
+        // Without a Content-Type, the HTML body is recognized as such
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/sso_login_page.html")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let error = client.get_devices("").unwrap_err().to_string();
+
+        assert!(error.starts_with(&format!(
+            "Expected JSON but received text/html from {}{}",
+            url, PATH_DCIM_DEVICES
+        )));
+        assert!(error.ends_with("<!DOCTYPE html>"));
+    }
+
+    #[test]
+    fn restricted_anonymous_access() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/ping.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let error = client.check_anonymous_access("").unwrap_err();
+        assert!(error.to_string().contains("returned no devices"));
+    }
+
+    #[test]
+    fn site_custom_fields() {
+        let url = mockito::server_url();
+
+        let sites = mockito::mock("GET", PATH_DCIM_SITES)
+            .match_query(mockito::Matcher::Regex("&id=1&id=3$".into()))
+            .with_body_from_file("tests/data/netbox/sites.json")
+            .expect(1)
+            .create();
+
+        let client = NetboxClient::builder(url).build().unwrap();
+        for _ in 0..2 {
+            let fields = client.get_site_custom_fields(&[1, 3]).unwrap();
+            assert_eq!(fields[&1]["netshot_domain"], 2);
+            assert!(fields[&3]["netshot_domain"].is_null());
+        }
+        sites.assert();
+    }
+
+    #[test]
+    fn deletion_statuses() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::UrlEncoded("q".into(), "1.2.3.4".into()))
+            .with_body_from_file("tests/data/netbox/lookup_by_ip.json")
+            .expect(1)
+            .create();
+        let _mock2 = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::UrlEncoded("q".into(), "1.2.3.5".into()))
+            .with_body_from_file("tests/data/netbox/lookup_by_ip.json")
+            .create();
+        let _mock3 = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::UrlEncoded("q".into(), "1.2.3.6".into()))
+            .with_body_from_file("tests/data/netbox/lookup_by_ip.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+
+        assert_eq!(
+            client.deletion_status("1.2.3.4", false).unwrap(),
+            DeletionStatus::OutOfScope("re-roled-device".to_string())
+        );
+        assert_eq!(
+            client.deletion_status("1.2.3.5", false).unwrap(),
+            DeletionStatus::Decommissioned("decommissioned-device".to_string())
+        );
+        assert_eq!(
+            client.deletion_status("1.2.3.6", false).unwrap(),
+            DeletionStatus::Deleted
+        );
+        // Answered from the cache
+        client.deletion_status("1.2.3.4", false).unwrap();
+        _mock.assert();
+    }
+
+    #[test]
+    fn paged_deletion_status() {
+        let url = mockito::server_url();
+        // The search matches many addresses sharing the prefix, the exact one on the second page
+        let device = |id: u32, name: &str, address: &str, status: &str| {
+            serde_json::json!({
+                "id": id,
+                "name": name,
+                "status": {"value": status},
+                "primary_ip": {"id": id, "family": 4, "address": address},
+            })
+        };
+        let first_page = serde_json::json!({
+            "count": 2,
+            "next": format!("{}{}?limit=1&offset=1&q=10.1.1.1", url, PATH_DCIM_DEVICES),
+            "previous": null,
+            "results": [device(1, "prefix-sharing", "10.1.1.10/32", "active")],
+        });
+        let second_page = serde_json::json!({
+            "count": 2,
+            "next": null,
+            "previous": null,
+            "results": [device(2, "decommissioned-device", "10.1.1.1/32", "decommissioning")],
+        });
+
+        let first = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("q".into(), "10.1.1.1".into()),
+                mockito::Matcher::UrlEncoded("offset".into(), "0".into()),
+            ]))
+            .with_body(first_page.to_string())
+            .expect(1)
+            .create();
+        let second = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("q".into(), "10.1.1.1".into()),
+                mockito::Matcher::UrlEncoded("offset".into(), "1".into()),
+            ]))
+            .with_body(second_page.to_string())
+            .expect(1)
+            .create();
+
+        let client = NetboxClient::builder(url).build().unwrap();
+        assert_eq!(
+            client.deletion_status("10.1.1.1", false).unwrap(),
+            DeletionStatus::Decommissioned("decommissioned-device".to_string())
+        );
+        first.assert();
+        second.assert();
+    }
+
+    #[test]
+    fn cross_origin_redirect() {
+        let url = mockito::server_url();
+        let other_host = url.replace("127.0.0.1", "localhost");
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_status(301)
+            .with_header("Location", &format!("{}/moved/", other_host))
+            .create();
+        let moved = mockito::mock("GET", "/moved/")
+            .match_header("Authorization", "Token secret-token")
+            .expect(0)
+            .create();
+
+        let client = NetboxClient::builder(url)
+            .token(Some("secret-token".to_string()))
+            .build()
+            .unwrap();
+        let error = client.get_devices("").unwrap_err();
+
+        assert!(format!("{:?}", error).contains("Refusing to follow the redirect"));
+        moved.assert();
+    }
+
+    #[test]
+    fn same_origin_redirect() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_status(301)
+            .with_header("Location", &format!("{}/moved/", url))
+            .create();
+        let _moved = mockito::mock("GET", "/moved/")
+            .match_header("Authorization", "Token secret-token")
+            .with_body_from_file("tests/data/netbox/single_good_device.json")
+            .create();
+
+        let client = NetboxClient::builder(url)
+            .token(Some("secret-token".to_string()))
+            .build()
+            .unwrap();
+
+        assert_eq!(client.get_devices("").unwrap().items.len(), 1);
+    }
+
+    #[test]
+    fn followed_next_urls() {
+        let url = mockito::server_url();
+        let device = |id: u32| {
+            format!(
+                r#"{{"id": {}, "name": "edge-{}", "primary_ip4": {{"id": {}, "address": "10.0.0.{}/32"}}}}"#,
+                id, id, id, id
+            )
+        };
+
+        // Netbox served under a sub-path, announcing the extra parameters of a pagination plugin
+        let _first = mockito::mock("GET", "/netbox/api/dcim/devices/")
+            .match_query(mockito::Matcher::UrlEncoded("offset".into(), "0".into()))
+            .with_body(format!(
+                r#"{{"count": 3, "next": "{}/netbox/api/dcim/devices/?limit=1000&offset=1000&cursor=abc", "previous": null, "results": [{}]}}"#,
+                url,
+                device(1)
+            ))
+            .create();
+        // Then its own host, rewritten by the reverse proxy, without offset
+        let _second = mockito::mock("GET", "/netbox/api/dcim/devices/")
+            .match_query(mockito::Matcher::UrlEncoded("cursor".into(), "abc".into()))
+            .with_body(format!(
+                r#"{{"count": 3, "next": "http://netbox.internal:8000/api/dcim/devices/?cursor=def", "previous": null, "results": [{}]}}"#,
+                device(2)
+            ))
+            .create();
+        let third = mockito::mock("GET", "/netbox/api/dcim/devices/")
+            .match_query(mockito::Matcher::Exact("cursor=def".into()))
+            .with_body(format!(
+                r#"{{"count": 3, "next": null, "previous": null, "results": [{}]}}"#,
+                device(3)
+            ))
+            .create();
+
+        let client = NetboxClient::builder(format!("{}/netbox", url))
+            .build()
+            .unwrap();
+        let devices = client.get_devices("").unwrap().items;
+        assert_eq!(
+            devices.iter().map(|device| device.id).collect::<Vec<u32>>(),
+            vec![1, 2, 3]
+        );
+        third.assert();
+    }
+
+    #[test]
+    fn next_page_requests() {
+        let base_url = "https://proxy.example.org/netbox";
+        let first = PageRequest::at(base_url, PATH_DCIM_DEVICES, "role=edge", 100, 0);
+        assert_eq!(
+            first.url,
+            "https://proxy.example.org/netbox/api/dcim/devices/?limit=100&offset=0&role=edge"
+        );
+
+        let next = first
+            .next(
+                base_url,
+                PATH_DCIM_DEVICES,
+                "role=edge",
+                "/netbox/api/dcim/devices/?limit=100&offset=100&role=edge",
+                100,
+            )
+            .unwrap();
+        assert_eq!(
+            next.url,
+            "https://proxy.example.org/netbox/api/dcim/devices/?limit=100&offset=100&role=edge"
+        );
+        assert_eq!(next.offset, 100);
+
+        // An unusable URL falls back to the following offset
+        let fallback = next
+            .next(base_url, PATH_DCIM_DEVICES, "role=edge", "http://[::1", 100)
+            .unwrap();
+        assert_eq!(
+            fallback,
+            PageRequest::at(base_url, PATH_DCIM_DEVICES, "role=edge", 100, 200)
+        );
+
+        let error = fallback
+            .next(
+                base_url,
+                PATH_DCIM_DEVICES,
+                "role=edge",
+                "https://proxy.example.org/netbox/api/dcim/devices/?limit=100&offset=100&role=edge",
+                100,
+            )
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Netbox pagination does not advance"));
+    }
+
+    #[test]
+    fn too_many_requests() {
+        let url = mockito::server_url();
+
+        let rejected = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_status(429)
+            .with_header("Retry-After", "1")
+            .with_body("Request was throttled.")
+            .expect(1)
+            .create();
+        let accepted = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/single_good_device.json")
+            .create();
+        let stats = Arc::new(RequestStats::new(false));
+        let client = NetboxClient::builder(url.clone())
+            .retry(RetryPolicy::default().rate_limited(2, Duration::from_secs(60)))
+            .stats(Some(stats.clone()))
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        assert_eq!(client.get_devices("").unwrap().items.len(), 1);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(stats.summary()[0].throttled, 1);
+        rejected.assert();
+        drop(rejected);
+        drop(accepted);
+
+        // The server keeps rejecting the requests
+        let rejected = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_status(429)
+            .with_header("Retry-After", "0")
+            .with_body("Request was throttled.")
+            .expect(3)
+            .create();
+        let error = client.get_devices("").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The server is rate limiting the requests, GET /api/dcim/devices/ still got 429 Too Many Requests after 2 retries"
+        );
+        rejected.assert();
+    }
+
+    #[test]
+    fn rate_limited_pages() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", "/api/virtualization/virtual-machines/")
+            .match_query(mockito::Matcher::UrlEncoded("offset".into(), "0".into()))
+            .with_header("X-RateLimit-Remaining", "1")
+            .with_header("X-RateLimit-Reset", "1")
+            .with_body(
+                r#"{"count": 2, "next": "http://netbox/api/virtualization/virtual-machines/?limit=100&offset=100", "previous": null,
+                    "results": [{"id": 1, "name": "vm-1", "primary_ip4": {"id": 1, "address": "1.2.3.4/32"}}]}"#,
+            )
+            .create();
+        let _mock2 = mockito::mock("GET", "/api/virtualization/virtual-machines/")
+            .match_query(mockito::Matcher::UrlEncoded("offset".into(), "100".into()))
+            .with_body(
+                r#"{"count": 2, "next": null, "previous": null,
+                    "results": [{"id": 2, "name": "vm-2", "primary_ip4": {"id": 2, "address": "1.2.3.5/32"}}]}"#,
+            )
+            .create();
+
+        let stats = Arc::new(RequestStats::new(false));
+        let client = NetboxClient::builder(url.clone())
+            .rate_limit_floor(5)
+            .stats(Some(stats.clone()))
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        let vms = client.get_vms("").unwrap().items;
+
+        assert_eq!(vms.len(), 2);
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert_eq!(stats.summary()[0].throttled, 1);
     }
 
     #[test]
@@ -257,17 +1731,17 @@ mod tests {
             .with_body_from_file("tests/data/netbox/single_good_device.json")
             .create();
 
-        let client = NetboxClient::new_anonymous(url.clone(), None).unwrap();
-        let devices = client.get_devices(&String::from("")).unwrap();
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let devices = client.get_devices("").unwrap().items;
 
         assert_eq!(devices.len(), 1);
 
         let device = devices.first().unwrap();
 
         assert_eq!(device.name.as_ref().unwrap(), "test-device");
-        assert_eq!(device.id, 1 as u32);
+        assert_eq!(device.id, 1);
         assert_eq!(device.primary_ip4.as_ref().unwrap().address, "1.2.3.4/32");
-        assert_eq!(device.is_valid(), true);
+        assert!(device.is_valid(false));
     }
 
     #[test]
@@ -279,14 +1753,15 @@ mod tests {
             .with_body_from_file("tests/data/netbox/single_device_without_primary_ip.json")
             .create();
 
-        let client = NetboxClient::new_anonymous(url.clone(), None).unwrap();
-        let devices = client.get_devices(&String::from("")).unwrap();
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let devices = client.get_devices("").unwrap().items;
 
         assert_eq!(devices.len(), 1);
 
         let device = devices.first().unwrap();
 
-        assert_eq!(device.is_valid(), false);
+        assert!(!device.is_valid(false));
+        assert!(!device.is_valid(true));
     }
 
     #[test]
@@ -298,13 +1773,309 @@ mod tests {
             .with_body_from_file("tests/data/netbox/single_device_without_name.json")
             .create();
 
-        let client = NetboxClient::new_anonymous(url.clone(), None).unwrap();
-        let devices = client.get_devices(&String::from("")).unwrap();
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let devices = client.get_devices("").unwrap().items;
 
         assert_eq!(devices.len(), 1);
 
         let device = devices.first().unwrap();
 
-        assert_eq!(device.is_valid(), false);
+        assert!(!device.is_valid(false));
+        assert!(device.is_valid(true));
+        assert_eq!(device.display_name(), format!("netbox-id-{}", device.id));
+    }
+
+    #[test]
+    fn devices_compatibility() {
+        for version in ["3.5", "3.7", "4.1"] {
+            let url = mockito::server_url();
+
+            let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+                .match_query(mockito::Matcher::Any)
+                .with_body_from_file(format!("tests/data/netbox/compat/{}/devices.json", version))
+                .create();
+
+            let client = NetboxClient::builder(url.clone()).build().unwrap();
+            let devices = client.get_devices("").unwrap().items;
+
+            assert_eq!(devices.len(), 2, "Netbox {}", version);
+
+            let device = devices.first().unwrap();
+
+            assert_eq!(device.id, 1, "Netbox {}", version);
+            assert_eq!(
+                device.name.as_deref(),
+                Some("test-device"),
+                "Netbox {}",
+                version
+            );
+            let primary_ip4 = device.primary_ip4.as_ref().unwrap();
+            assert_eq!(primary_ip4.address, "1.2.3.4/32", "Netbox {}", version);
+            assert_eq!(primary_ip4.family, Some(4), "Netbox {}", version);
+            let role = device.role().unwrap();
+            assert_eq!(role.slug.as_deref(), Some("router"), "Netbox {}", version);
+            assert!(device.is_valid(false), "Netbox {}", version);
+
+            assert!(!devices[1].is_valid(false), "Netbox {}", version);
+        }
+    }
+
+    #[test]
+    fn malformed_device_index() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/malformed_device.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let error = client.get_devices("").unwrap_err().to_string();
+
+        assert!(error.contains("test-device-malformed (id 2) at index 1"));
+        assert!(error.contains(r#""name": "test-device-malformed""#));
+    }
+
+    #[test]
+    fn skipped_malformed_device() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/malformed_device.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone())
+            .skip_bad_records(true)
+            .build()
+            .unwrap();
+        let devices = client.get_devices("").unwrap().items;
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, 1);
+    }
+
+    #[test]
+    fn devices_with_blank_names() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/devices_with_blank_names.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let devices = client.get_devices("").unwrap().items;
+
+        assert_eq!(devices.len(), 4);
+        assert_eq!(devices[0].name, None);
+        assert_eq!(devices[1].name, None);
+        assert_eq!(devices[2].name, None);
+        assert_eq!(devices[3].name.as_deref(), Some(" test-device "));
+    }
+
+    #[test]
+    fn vm_with_generic_primary_ip() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_VIRT_VM)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/vm_with_generic_primary_ip.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let devices = client.get_vms("").unwrap().items;
+
+        assert_eq!(devices.len(), 1);
+
+        let device = devices.first().unwrap();
+
+        assert!(device.primary_ip4.is_none());
+        assert!(device.primary_ip6.is_none());
+        assert_eq!(device.primary_ip.as_ref().unwrap().address, "1.2.3.4/24");
+    }
+
+    #[test]
+    fn child_devices() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/chassis_with_line_cards.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let devices = client.get_devices("").unwrap().items;
+
+        assert_eq!(
+            devices
+                .iter()
+                .map(|device| (device.display_name(), device.parent_name()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("chassis-1".to_string(), None),
+                ("chassis-1-lc1".to_string(), Some("chassis-1".to_string())),
+                ("chassis-1-lc2".to_string(), Some("netbox-id-1".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn virtual_chassis_members() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DCIM_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netbox/virtual_chassis.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let devices = client.get_devices("").unwrap().items;
+
+        assert_eq!(
+            devices
+                .iter()
+                .map(|device| (
+                    device.virtual_chassis.as_ref().map(|chassis| chassis.id),
+                    device.vc_position,
+                    device.vc_priority,
+                    device.is_virtual_chassis_master()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(4), Some(1), Some(255), true),
+                (Some(4), Some(2), Some(128), false),
+                (Some(4), Some(3), None, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn powered_off_vms() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_VIRT_VM)
+            .match_query(mockito::Matcher::UrlEncoded(
+                "cluster".to_string(),
+                "PAR1 Cluster".to_string(),
+            ))
+            .with_body_from_file("tests/data/netbox/powered_off_vms.json")
+            .create();
+
+        let client = NetboxClient::builder(url.clone()).build().unwrap();
+        let vms = client
+            .get_vms(&vms_cluster_query("PAR1 Cluster"))
+            .unwrap()
+            .items;
+
+        assert_eq!(vms.len(), 3);
+        assert!(vms.iter().all(|vm| vm.virtual_machine));
+        assert_eq!(
+            vms.iter()
+                .map(|vm| (vm.status(), vm.is_powered_off_vm()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some("active".to_string()), false),
+                (Some("offline".to_string()), true),
+                (Some("paused".to_string()), true)
+            ]
+        );
+        assert_eq!(vms_cluster_query("12"), "cluster_id=12");
+    }
+
+    #[test]
+    fn graphql_devices() {
+        let url = mockito::server_url();
+
+        let first = mockito::mock("POST", PATH_GRAPHQL)
+            .match_body(mockito::Matcher::Regex(
+                r#"device_list\(role: \[\\"edge\\", \\"core\\"\], limit: 2, offset: 0\)"#
+                    .to_string(),
+            ))
+            .with_body_from_file("tests/data/netbox/graphql_devices_page1.json")
+            .create();
+        let second = mockito::mock("POST", PATH_GRAPHQL)
+            .match_body(mockito::Matcher::Regex("limit: 2, offset: 2".to_string()))
+            .with_body_from_file("tests/data/netbox/graphql_devices_page2.json")
+            .create();
+
+        // The queries only read Netbox, a read-only client is allowed to send them
+        let client = NetboxClient::builder(url)
+            .api(NetboxApiMode::Graphql)
+            .graphql_page_size(2)
+            .read_only(true)
+            .build()
+            .unwrap();
+        let fetched = client
+            .get_devices(&String::from("role=edge&role=core"))
+            .unwrap();
+        first.assert();
+        second.assert();
+        assert_eq!(fetched.snapshot.pages, 2);
+
+        let devices = fetched.items;
+        assert_eq!(
+            devices.iter().map(|device| device.id).collect::<Vec<u32>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(devices[0].primary_ip4.as_ref().unwrap().family, Some(4));
+        assert_eq!(devices[0].status(), Some("active".to_string()));
+        assert_eq!(devices[0].role().unwrap().slug.as_deref(), Some("edge"));
+        assert_eq!(devices[0].tags(), vec!["mgmt".to_string()]);
+        assert_eq!(
+            devices[1].primary_ip6.as_ref().unwrap().address,
+            "2001:db8::2/128"
+        );
+        assert_eq!(devices[1].parent_name(), Some("edge-1".to_string()));
+        assert!(devices.iter().all(|device| device.is_valid(false)));
+
+        // The --sync-attributes are queried along with the devices
+        let attributes: Vec<Attribute> = "site,rack,serial,asset-tag"
+            .split(',')
+            .map(|attribute| attribute.parse().unwrap())
+            .collect();
+        assert_eq!(
+            comment_lines(&devices[0], &attributes, ""),
+            vec![
+                "Netbox site: PAR1",
+                "Netbox rack: R12",
+                "Netbox serial: FOC1234X"
+            ]
+        );
+    }
+
+    #[test]
+    fn graphql_filter_and_errors() {
+        let url = mockito::server_url();
+
+        let _vms = mockito::mock("POST", PATH_GRAPHQL)
+            .match_body(mockito::Matcher::Regex(
+                r#"virtual_machine_list\(cluster_id: \[\\"3\\"\], limit: 1000"#.to_string(),
+            ))
+            .with_body(r#"{"data": {"virtual_machine_list": [{"id": "7", "name": "vm-1", "status": "OFFLINE"}]}}"#)
+            .create();
+        let _devices = mockito::mock("POST", PATH_GRAPHQL)
+            .match_body(mockito::Matcher::Regex(
+                r#"device_list\(rack_group: \\"r1\\", limit"#.to_string(),
+            ))
+            .with_body_from_file("tests/data/netbox/graphql_errors.json")
+            .create();
+
+        let client = NetboxClient::builder(url)
+            .api(NetboxApiMode::Graphql)
+            .graphql_filter(Some(r#"rack_group: "r1""#.to_string()))
+            .build()
+            .unwrap();
+        let vms = client.get_vms(&String::from("cluster_id=3")).unwrap().items;
+        assert_eq!(vms.len(), 1);
+        assert!(vms[0].virtual_machine);
+        assert!(vms[0].is_powered_off_vm());
+
+        // The raw filter replaces the one built from the query string
+        let error = client.get_devices(&String::from("role=edge")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The Netbox GraphQL query of device_list failed: Unknown argument \"rack_group\" on field \"device_list\" of type \"Query\"."
+        );
     }
 }
@@ -1,38 +1,325 @@
 use crate::common::APP_USER_AGENT;
-use crate::rest::helpers::build_identity_from_file;
+use crate::progress::Progress;
+use crate::rest::helpers::{
+    build_identity_from_file, check_read_only, json_body, load_ca_certificates, parse_records,
+    read_error, request_timeout, same_origin_redirect_policy, timeout_context, write_error,
+    DEFAULT_TIMEOUT,
+};
+use crate::rest::query::SearchQuery;
+use crate::rest::retry::RetryPolicy;
+use crate::rest::snapshot::{FetchSnapshot, Fetched, Source};
+use crate::rest::stats::{self, RequestStats};
 use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Utc};
+use reqwest::blocking::{RequestBuilder, Response};
 use reqwest::header::{HeaderMap, HeaderValue};
-use reqwest::Proxy;
-use serde;
-use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use reqwest::{Proxy, StatusCode};
+use schemars::JsonSchema;
+use serde::de::{self, DeserializeOwned, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const PATH_DEVICES: &str = "/api/devices";
 const PATH_DEVICES_SEARCH: &str = "/api/devices/search";
+const PATH_DEVICE_INTERFACES: &str = "/interfaces";
+const PATH_USER: &str = "/api/user";
+const PATH_DOMAINS: &str = "/api/domains";
+const PATH_GROUPS: &str = "/api/groups";
+const PATH_CREDENTIAL_SETS: &str = "/api/credentialsets";
+const PATH_TASKS: &str = "/api/tasks";
+const API_LIMIT: u32 = 100;
+
+/// The type of the tasks started by the device registrations
+const TASK_TYPE_DISCOVERY: &str = "DiscoverDeviceTypeTask";
+/// The statuses of the tasks not finished yet
+const TASK_PENDING_STATUSES: [&str; 2] = ["SCHEDULED", "RUNNING"];
+const TASK_FINISHED_STATUSES: [&str; 3] = ["SUCCESS", "FAILURE", "CANCELLED"];
+const PATH_SERVER_INFO: &str = "/api/serverinfo";
+const TEMPLATE_DEVICE: &str = "/api/devices/{id}";
+const TEMPLATE_DEVICE_INTERFACES: &str = "/api/devices/{id}/interfaces";
+const TEMPLATE_GROUP: &str = "/api/groups/{id}";
+const TEMPLATE_TASK: &str = "/api/tasks/{id}";
+
+/// The type of the groups whose members are listed explicitly
+const GROUP_TYPE_STATIC: &str = "StaticDeviceGroup";
+/// The folder of the groups created by netbox2netshot, as the Netshot groups have no description to mark them with
+pub(crate) const MANAGED_GROUP_FOLDER: &str = "netbox2netshot";
+
+/// The Netshot user level required to register and update devices (READ-WRITE)
+const LEVEL_READ_WRITE: u32 = 100;
+
+/// The newest Netshot release whose payloads are known to this tool
+const NEWEST_KNOWN_VERSION: NetshotVersion = NetshotVersion::new(0, 21, 0);
+
+/// Fields we cannot work without, with the Netshot releases most likely involved when they are missing
+const REQUIRED_FIELDS: &[(&str, &str)] = &[
+    ("id", "exposed by every Netshot release since 0.16"),
+    ("name", "exposed by every Netshot release since 0.16"),
+    ("mgmtAddress", "format changed around Netshot 0.16"),
+    ("ip", "format changed around Netshot 0.16"),
+];
+
+/// A Netshot release number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NetshotVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
 
 #[derive(Debug)]
 pub struct NetshotClient {
     pub url: String,
     pub token: String,
     pub client: reqwest::blocking::Client,
+    pub skip_bad_records: bool,
+    pub action_timeout: Option<Duration>,
+    pub stats: Option<Arc<RequestStats>>,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    pub certificate_expiry: Option<DateTime<Utc>>,
+    pub version: Option<NetshotVersion>,
+    pub page_size: u32,
+    /// Refuse any request which could change Netshot
+    pub read_only: bool,
+    task_comments: Option<String>,
+    task_priority: Option<i32>,
+    driver: Option<String>,
+    credential_set_id: Option<u32>,
+    login: Option<Login>,
+    addresses_cache: Mutex<HashMap<u32, Vec<String>>>,
+    domains_cache: Mutex<Option<Vec<Domain>>>,
+    groups_cache: Mutex<Option<Vec<Group>>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The management address of a device, sent as a plain IP string by the Netshot releases older than 0.16 and as a
+/// structure since
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct ManagementAddress {
     #[serde(rename = "prefixLength")]
-    pub prefix_length: u8,
+    pub prefix_length: Option<u8>,
     #[serde(rename = "addressUsage")]
-    pub address_usage: String,
+    pub address_usage: Option<String>,
     pub ip: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The structured management address of Netshot 0.16 and later
+#[derive(Debug, Deserialize)]
+struct StructuredAddress {
+    #[serde(rename = "prefixLength", default)]
+    prefix_length: Option<u8>,
+    #[serde(rename = "addressUsage", default)]
+    address_usage: Option<String>,
+    ip: String,
+}
+
+struct ManagementAddressVisitor;
+
+impl<'de> Visitor<'de> for ManagementAddressVisitor {
+    type Value = ManagementAddress;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an IP string or a management address object")
+    }
+
+    fn visit_str<E: de::Error>(self, ip: &str) -> Result<Self::Value, E> {
+        Ok(ManagementAddress {
+            prefix_length: None,
+            address_usage: None,
+            ip: ip.to_string(),
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let address = StructuredAddress::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        Ok(ManagementAddress {
+            prefix_length: address.prefix_length,
+            address_usage: address.address_usage,
+            ip: address.ip,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ManagementAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ManagementAddressVisitor)
+    }
+}
+
+/// The statuses of the Netshot devices, the other ones (if any) being kept as strings in `Device`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DeviceStatus {
+    InProduction,
+    PreProduction,
+    Disabled,
+}
+
+impl DeviceStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceStatus::InProduction => "INPRODUCTION",
+            DeviceStatus::PreProduction => "PREPRODUCTION",
+            DeviceStatus::Disabled => "DISABLED",
+        }
+    }
+
+    /// Parse a status the API can give to a device, which only toggles between INPRODUCTION and DISABLED
+    pub fn parse_settable(s: &str) -> Result<Self, Error> {
+        match s.parse()? {
+            DeviceStatus::PreProduction => Err(anyhow!(
+                "The Netshot API only sets the INPRODUCTION and DISABLED statuses"
+            )),
+            status => Ok(status),
+        }
+    }
+
+    /// Whether a raw status, as Netshot sends it, is this one
+    pub fn is(self, status: Option<&str>) -> bool {
+        status == Some(self.as_str())
+    }
+}
+
+impl FromStr for DeviceStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_str() {
+            "INPRODUCTION" => Ok(DeviceStatus::InProduction),
+            "PREPRODUCTION" => Ok(DeviceStatus::PreProduction),
+            "DISABLED" => Ok(DeviceStatus::Disabled),
+            _ => Err(anyhow!(
+                "Unknown Netshot device status `{}`, expected INPRODUCTION, PREPRODUCTION or DISABLED",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceDomain {
+    pub id: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Device {
     pub id: u32,
     pub name: String,
     #[serde(rename = "mgmtAddress")]
     pub management_address: ManagementAddress,
-    pub status: String,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub driver: Option<String>,
+    #[serde(rename = "softwareVersion", default)]
+    pub software_version: Option<String>,
+    #[serde(rename = "mgmtDomain", default)]
+    pub domain: Option<DeviceDomain>,
+    #[serde(default)]
+    pub family: Option<String>,
+    #[serde(rename = "networkClass", default)]
+    pub network_class: Option<String>,
+    #[serde(rename = "createdDate", default, deserialize_with = "deserialize_date")]
+    pub created_date: Option<DateTime<Utc>>,
+    /// The last change of the device, e.g. its disabling
+    #[serde(rename = "changeDate", default, deserialize_with = "deserialize_date")]
+    pub change_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Domain {
+    pub id: u32,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Group {
+    pub id: u32,
+    pub name: String,
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(rename = "type", default)]
+    pub group_type: Option<String>,
+    /// The IDs of the member devices, for the static groups
+    #[serde(rename = "staticDevices", default)]
+    pub static_devices: Vec<u32>,
+}
+
+impl Group {
+    /// Are the members of this group listed explicitly
+    pub fn is_static(&self) -> bool {
+        self.group_type
+            .as_deref()
+            .is_some_and(|group_type| group_type.contains(GROUP_TYPE_STATIC))
+    }
+
+    /// Was this group created by netbox2netshot
+    pub fn is_managed(&self) -> bool {
+        self.folder.as_deref() == Some(MANAGED_GROUP_FOLDER)
+    }
+
+    /// Is this a static group without any member, the members of the dynamic groups being unknown
+    pub fn is_empty(&self) -> bool {
+        self.is_static() && self.static_devices.is_empty()
+    }
+}
+
+/// An empty group created by netbox2netshot, deleted by --prune-empty-groups
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PrunedGroup {
+    pub id: u32,
+    pub name: String,
+}
+
+/// A static group, as created or updated
+#[derive(Debug, Serialize)]
+struct StaticGroupPayload {
+    name: String,
+    folder: String,
+    #[serde(rename = "type")]
+    group_type: String,
+    #[serde(rename = "staticDevices")]
+    static_devices: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Interface {
+    #[serde(rename = "interfaceName", default)]
+    pub name: Option<String>,
+    #[serde(rename = "ip4Addresses", default)]
+    pub ip4_addresses: Vec<ManagementAddress>,
+    #[serde(rename = "ip6Addresses", default)]
+    pub ip6_addresses: Vec<ManagementAddress>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ServerInfo {
+    #[serde(rename = "serverVersion")]
+    server_version: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct User {
+    #[serde(default)]
+    pub username: Option<String>,
+    pub level: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -45,13 +332,31 @@ struct NewDevicePayload {
 
     #[serde(rename = "domainId")]
     domain_id: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comments: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<i32>,
+
+    /// The name and driver of the devices created without auto-discovery
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+
+    #[serde(rename = "deviceType", skip_serializing_if = "Option::is_none")]
+    device_type: Option<String>,
+
+    /// The credential set the registered devices are discovered and snapshotted with
+    #[serde(rename = "credentialSetIds", skip_serializing_if = "Option::is_none")]
+    credential_set_ids: Option<Vec<u32>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct NewDeviceCreatedPayload {
     #[serde(rename = "id")]
     pub task_id: u32,
-    pub status: String,
+    #[serde(default)]
+    pub status: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,306 +364,3492 @@ struct UpdateDevicePayload {
     enabled: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DeviceUpdatedPayload {
+/// A Netshot task, only the fields needed to count the pending discoveries and tell why one failed
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct Task {
+    pub id: u32,
     pub status: String,
+    #[serde(rename = "type", default)]
+    pub task_type: Option<String>,
+    #[serde(default)]
+    pub log: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct DeviceSearchQueryPayload {
-    query: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DeviceSearchResultPayload {
-    pub query: String,
-    pub devices: Vec<Device>,
+/// Why the discovery of a new device failed, telling whether registering it again is worth it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscoveryFailure {
+    /// No Netshot driver matches the device
+    Driver,
+    /// The device refused the credentials
+    Credentials,
+    /// The device did not answer in time
+    Timeout,
+    Unknown,
 }
 
-impl NetshotClient {
-    /// Create a client with the given authentication token
-    pub fn new(
-        url: String,
-        token: String,
-        proxy: Option<String>,
-        tls_client_certificate: Option<String>,
-        tls_client_certificate_password: Option<String>,
-    ) -> Result<Self, Error> {
-        log::debug!("Creating new Netshot client to {}", url);
-        let mut http_headers = HeaderMap::new();
-        let header_value = HeaderValue::from_str(token.as_str())?;
-        http_headers.insert("X-Netshot-API-Token", header_value);
-        http_headers.insert("Accept", HeaderValue::from_str("application/json")?);
-        let mut http_client = reqwest::blocking::Client::builder()
-            .user_agent(APP_USER_AGENT)
-            .timeout(Duration::from_secs(5))
-            .default_headers(http_headers);
-
-        http_client = match proxy {
-            Some(p) => http_client.proxy(Proxy::all(p)?),
-            None => http_client,
-        };
+impl DiscoveryFailure {
+    /// Classify the failure from the log of the discovery task
+    pub fn classify(log: &str) -> Self {
+        let log = log.to_lowercase();
+        let mentions = |words: &[&str]| words.iter().any(|word| log.contains(word));
+        if mentions(&["driver"]) {
+            DiscoveryFailure::Driver
+        } else if mentions(&[
+            "authentication",
+            "credential",
+            "password",
+            "permission denied",
+            "access denied",
+        ]) {
+            DiscoveryFailure::Credentials
+        } else if mentions(&["timeout", "timed out", "no response", "unreachable"]) {
+            DiscoveryFailure::Timeout
+        } else {
+            DiscoveryFailure::Unknown
+        }
+    }
 
-        http_client = match tls_client_certificate {
-            Some(c) => http_client.identity(build_identity_from_file(
-                c,
-                tls_client_certificate_password,
-            )?),
-            None => http_client,
-        };
+    /// Whether registering the device again fails the same way until something changes on the device or in Netshot
+    pub fn is_permanent(self) -> bool {
+        matches!(
+            self,
+            DiscoveryFailure::Driver | DiscoveryFailure::Credentials
+        )
+    }
+}
 
-        Ok(Self {
-            url,
-            token,
-            client: http_client.build()?,
+impl fmt::Display for DiscoveryFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            DiscoveryFailure::Driver => "driver",
+            DiscoveryFailure::Credentials => "credentials",
+            DiscoveryFailure::Timeout => "timeout",
+            DiscoveryFailure::Unknown => "unknown",
         })
     }
+}
 
-    /// To be implemented server side, always return true for now
-    pub fn ping(&self) -> Result<bool, Error> {
-        log::warn!("Not health check implemented on Netshot, ping will always succeed");
-        Ok(true)
+impl Task {
+    /// How the discovery failed, for a failed task
+    pub fn discovery_failure(&self) -> Option<DiscoveryFailure> {
+        (self.status == "FAILURE")
+            .then(|| DiscoveryFailure::classify(self.log.as_deref().unwrap_or_default()))
     }
 
-    /// Get devices registered in Netshot
-    pub fn get_devices(&self,
-                       domain_id: u32,
-    ) -> Result<Vec<Device>, Error> {
-        let url = format!("{}{}?group={}", self.url, PATH_DEVICES, domain_id);
-        let devices: Vec<Device> = self.client.get(url).send()?.json()?;
-
-        log::debug!("Got {} devices from Netshot", devices.len());
-
-        Ok(devices)
+    /// The last line of the log of a failed task, which usually tells why
+    pub fn failure_reason(&self) -> Option<String> {
+        if self.status != "FAILURE" {
+            return None;
+        }
+        self.log
+            .as_deref()?
+            .lines()
+            .map(str::trim)
+            .rfind(|line| !line.is_empty())
+            .map(str::to_string)
     }
 
-    /// Register a given IP into Netshot and return the corresponding device
-    pub fn register_device(
-        &self,
-        ip_address: String,
-        domain_id: u32,
-    ) -> Result<NewDeviceCreatedPayload, Error> {
-        log::info!("Registering new device with IP {}", ip_address);
+    /// Has this task ended, whether it succeeded or not
+    pub fn is_finished(&self) -> bool {
+        TASK_FINISHED_STATUSES.contains(&self.status.as_str())
+    }
 
-        let new_device = NewDevicePayload {
-            auto_discover: true,
-            ip_address: ip_address.clone(),
-            domain_id,
-        };
+    /// Is this the discovery of a new device, as started by a registration
+    pub fn is_discovery(&self) -> bool {
+        self.task_type
+            .as_deref()
+            .is_some_and(|task_type| task_type.contains(TASK_TYPE_DISCOVERY))
+    }
+}
 
-        let url = format!("{}{}", self.url, PATH_DEVICES);
-        let response = self.client.post(url).json(&new_device).send()?;
+/// The response to a device update: the updated device when Netshot echoes it back, or the error it reports
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceUpdatedPayload {
+    #[serde(default)]
+    pub id: Option<u32>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(rename = "errorCode", default)]
+    pub error_code: Option<i64>,
+    #[serde(rename = "errorMsg", alias = "message", default)]
+    pub error_message: Option<String>,
+}
 
-        if !response.status().is_success() {
-            log::warn!(
-                "Failed to register new device {}, got status {}",
+impl DeviceUpdatedPayload {
+    /// Check the update left the device in the requested state, when Netshot echoes it back
+    fn check_enabled(&self, ip_address: &str, enabled: bool) -> Result<(), Error> {
+        if let Some(message) = &self.error_message {
+            return Err(anyhow!(
+                "Netshot rejected the update of device {} to enabled={}: {} (error code {})",
                 ip_address,
-                response.status().to_string()
-            );
-            return Err(anyhow!("Failed to register new device {}", ip_address));
+                enabled,
+                message,
+                self.error_code
+                    .map_or_else(|| "unknown".to_string(), |code| code.to_string())
+            ));
+        }
+        match self.status.as_deref() {
+            Some(status) if DeviceStatus::Disabled.is(Some(status)) == enabled => Err(anyhow!(
+                "Device {} is still {} after the update to enabled={}",
+                ip_address,
+                status,
+                enabled
+            )),
+            Some(status) => {
+                log::info!("Device {} is now {}", ip_address, status);
+                Ok(())
+            }
+            None => {
+                log::debug!(
+                    "Netshot did not echo the status of device {}, assuming enabled={}",
+                    ip_address,
+                    enabled
+                );
+                Ok(())
+            }
         }
+    }
+}
 
-        let device_registration: NewDeviceCreatedPayload = response.json()?;
-        log::debug!(
-            "Device registration for device {} requested with task ID {}",
-            ip_address,
-            device_registration.task_id
-        );
+/// The body of the Netshot error responses
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct ErrorPayload {
+    #[serde(rename = "errorCode", default)]
+    pub error_code: Option<i64>,
+    #[serde(rename = "errorMsg", alias = "message", default)]
+    pub error_message: Option<String>,
+}
 
-        Ok(device_registration)
+/// The number of characters of a raw error body kept in the errors
+const ERROR_BODY_LIMIT: usize = 200;
+
+/// What an error response tells about the failure, to append to the error: the message and code Netshot reports,
+/// or else the start of the body as it is
+fn error_details(response: Response) -> String {
+    let body = response.text().unwrap_or_default();
+    if let Ok(ErrorPayload {
+        error_code,
+        error_message: Some(message),
+    }) = serde_json::from_str(&body)
+    {
+        return match error_code {
+            Some(code) => format!(": {} (error code {})", message, code),
+            None => format!(": {}", message),
+        };
+    }
+    let body = body.trim();
+    match body.char_indices().nth(ERROR_BODY_LIMIT) {
+        _ if body.is_empty() => String::new(),
+        Some((end, _)) => format!(": {}...", &body[..end]),
+        None => format!(": {}", body),
     }
+}
 
-    /// Search for a device
-    pub fn search_device(&self, query_string: String) -> Result<DeviceSearchResultPayload, Error> {
-        let url = format!("{}{}", self.url, PATH_DEVICES_SEARCH);
+#[derive(Debug, Serialize, Deserialize)]
+struct UpdateCredentialSetsPayload {
+    #[serde(rename = "credentialSetIds")]
+    credential_set_ids: Vec<u32>,
+}
 
-        let query = DeviceSearchQueryPayload {
-            query: query_string.clone(),
-        };
+#[derive(Debug, Serialize)]
+struct UpdateCommentsPayload {
+    comments: String,
+}
 
-        let response = self.client.post(url).json(&query).send()?;
+/// The management address in CIDR notation, from which Netshot takes the prefix length
+#[derive(Debug, Serialize)]
+struct UpdateAddressPayload {
+    #[serde(rename = "ipAddress")]
+    ip_address: String,
+}
 
-        if !response.status().is_success() {
-            log::warn!(
-                "Failed to search for device with query `{}`: {}",
-                query_string.clone(),
-                response.status().to_string()
-            );
-            return Err(anyhow!(
-                "Failed to search for device with query: {}",
-                query_string
-            ));
-        }
+/// The new name of a device renamed on Netbox
+#[derive(Debug, Serialize)]
+struct RenamePayload {
+    name: String,
+}
 
-        let search_result: DeviceSearchResultPayload = response.json()?;
-        log::debug!(
-            "Found {} devices with the given search",
-            search_result.devices.len(),
-        );
+/// The user and password of a Netshot session, logged in with instead of an API token
+#[derive(Clone, Serialize)]
+pub struct Login {
+    #[serde(rename = "username")]
+    pub user: String,
+    password: String,
+}
 
-        Ok(search_result)
+impl Login {
+    pub fn new(user: impl Into<String>, password: impl Into<String>) -> Self {
+        Login {
+            user: user.into(),
+            password: password.into(),
+        }
     }
+}
 
-    /// Set the given device to a given state (enabled/disabled)
-    fn set_device_enabled(
-        &self,
-        ip_address: String,
-        enabled: bool,
-    ) -> Result<Option<DeviceUpdatedPayload>, Error> {
-        log::info!(
-            "Setting device with IP {} to enabled={}",
-            ip_address,
-            enabled
-        );
+impl fmt::Debug for Login {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Login")
+            .field("user", &self.user)
+            .field("password", &"***")
+            .finish()
+    }
+}
 
-        let state = UpdateDevicePayload { enabled: enabled };
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CredentialSet {
+    pub id: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+}
 
-        // Search for the device ID
-        let response = self.search_device(format!("[IP] IS {}", ip_address))?;
-        let device = response.devices.first().unwrap();
+/// The details of a single device, only exposed by the per-device endpoint
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceDetails {
+    pub id: u32,
+    pub name: String,
+    #[serde(rename = "credentialSets", default)]
+    pub credential_sets: Vec<CredentialSet>,
+    #[serde(default)]
+    pub comments: Option<String>,
+}
 
-        if !enabled && device.status == "DISABLED" {
-            log::warn!(
-                "Device {}({}) is already disabled, skipping",
-                device.name,
-                ip_address
-            );
-            return Ok(Option::None);
-        } else if enabled && device.status != "DISABLED" {
-            log::warn!(
-                "Device {}({}) is already enabled, skipping",
-                device.name,
-                ip_address
-            );
-            return Ok(Option::None);
-        }
+#[derive(Debug, Serialize, Deserialize)]
+struct DeviceSearchQueryPayload {
+    query: String,
+}
 
-        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device.id);
-        let response = self.client.put(url).json(&state).send()?;
+/// The action of the structured log fields of a state update
+fn state_action(enabled: bool) -> &'static str {
+    match enabled {
+        true => "enable",
+        false => "disable",
+    }
+}
 
-        if !response.status().is_success() {
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceSearchResultPayload {
+    #[serde(default)]
+    pub query: String,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+}
+
+/// A device search result, its devices yet to be parsed
+#[derive(Debug, Deserialize)]
+struct RawSearchResult {
+    #[serde(default)]
+    query: String,
+    #[serde(default)]
+    devices: Vec<Value>,
+}
+
+impl DeviceSearchResultPayload {
+    /// The device found with exactly this management IP, the first one when several devices share it. The device may
+    /// well be gone since the inventory was fetched, which is an error of this device only.
+    pub fn device_with_ip(&self, ip_address: &str) -> Result<&Device, Error> {
+        let ip = ip_address.parse::<IpAddr>().ok();
+        let mut matches = self.devices.iter().filter(|device| {
+            let address = device.management_address.ip.as_str();
+            match ip {
+                Some(ip) => address.parse::<IpAddr>().ok() == Some(ip),
+                None => address == ip_address,
+            }
+        });
+        let device = matches
+            .next()
+            .ok_or_else(|| anyhow!("No device found on Netshot with IP {}", ip_address))?;
+        let others: Vec<String> = matches
+            .filter(|other| other.id != device.id)
+            .map(|other| format!("{}(id {})", other.name, other.id))
+            .collect();
+        if !others.is_empty() {
             log::warn!(
-                "Failed to update state for device {}, got status {}",
+                "{}(id {}) shares the IP {} with {} on Netshot, updating the former only",
+                device.name,
+                device.id,
                 ip_address,
-                response.status().to_string()
+                others.join(", ")
             );
-            return Err(anyhow!(
-                "Failed to update state for device {}, got status {}",
-                ip_address,
-                response.status().to_string()
-            ));
         }
+        Ok(device)
+    }
+}
 
-        let device_update: DeviceUpdatedPayload = response.json()?;
-        log::debug!("Device state of {} set to enabled={}", ip_address, enabled);
+impl NetshotVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
 
-        Ok(Option::Some(device_update))
+    /// Whether the release is newer than the newest one netbox2netshot knows the payloads of
+    pub fn is_newer_than_known(&self) -> bool {
+        (self.major, self.minor) > (NEWEST_KNOWN_VERSION.major, NEWEST_KNOWN_VERSION.minor)
     }
+}
 
-    /// Disable a given device
-    pub fn disable_device(
-        &self,
-        ip_address: String,
+impl FromStr for NetshotVersion {
+    type Err = Error;
+
+    /// Parse a release number such as `0.21.3`, `v0.18.1` or `0.19.0-beta2`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let numbers: Vec<u32> = s
+            .trim()
+            .trim_start_matches('v')
+            .split(|c: char| !c.is_ascii_digit())
+            .take(3)
+            .map(|number| number.parse::<u32>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow!("Invalid Netshot version `{}`", s))?;
+        match numbers[..] {
+            [major, minor] => Ok(Self::new(major, minor, 0)),
+            [major, minor, patch] => Ok(Self::new(major, minor, patch)),
+            _ => Err(anyhow!("Invalid Netshot version `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for NetshotVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Device {
+    /// Is this device disabled in Netshot
+    pub fn is_disabled(&self) -> bool {
+        DeviceStatus::Disabled.is(self.status.as_deref())
+    }
+
+    /// When the device last changed, or else was created
+    pub fn last_change(&self) -> Option<DateTime<Utc>> {
+        self.change_date.or(self.created_date)
+    }
+
+    /// Is this device in production in Netshot, i.e. neither disabled nor in pre-production
+    pub fn is_in_production(&self) -> bool {
+        DeviceStatus::InProduction.is(self.status.as_deref())
+    }
+}
+
+/// A date as Netshot sends it, in milliseconds since the epoch, or as written back to an inventory file
+fn deserialize_date<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Date {
+        Millis(i64),
+        Text(DateTime<Utc>),
+    }
+
+    match Option::<Date>::deserialize(deserializer)? {
+        Some(Date::Millis(millis)) => DateTime::from_timestamp_millis(millis)
+            .map(Some)
+            .ok_or_else(|| de::Error::custom(format!("invalid date {}", millis))),
+        Some(Date::Text(date)) => Ok(Some(date)),
+        None => Ok(None),
+    }
+}
+
+/// Name the missing field and the likely Netshot version when a payload cannot be parsed
+fn describe_parse_error(error: serde_json::Error, what: &str) -> Error {
+    let message = error.to_string();
+    let required_field = REQUIRED_FIELDS
+        .iter()
+        .find(|(field, _)| message.contains(&format!("missing field `{}`", field)));
+    match required_field {
+        Some((field, version)) => anyhow!(
+            "Failed to parse Netshot {}, required field `{}` is missing ({}): {}",
+            what,
+            field,
+            version,
+            message
+        ),
+        None => anyhow!("Failed to parse Netshot {}: {}", what, message),
+    }
+}
+
+/// Deserialize a Netshot response body
+fn parse_json<T: DeserializeOwned>(body: &str, what: &str) -> Result<T, Error> {
+    serde_json::from_str(body).map_err(|error| describe_parse_error(error, what))
+}
+
+/// Deserialize a Netshot device array element by element
+pub fn parse_devices(body: &str, skip_bad_records: bool) -> Result<Vec<Device>, Error> {
+    parse_device_values(parse_json(body, "devices list")?, skip_bad_records)
+}
+
+/// Parse the records of a devices list page, whatever the form of their management address
+fn parse_device_values(values: Vec<Value>, skip_bad_records: bool) -> Result<Vec<Device>, Error> {
+    parse_records(values, "device", skip_bad_records, |value| {
+        serde_json::from_value(value).map_err(|error| describe_parse_error(error, "device"))
+    })
+}
+
+/// Builder of a Netshot client, gathering the connection options
+#[derive(Debug)]
+pub struct NetshotClientBuilder {
+    url: String,
+    token: String,
+    proxy: Option<String>,
+    tls_client_certificate: Option<String>,
+    tls_client_certificate_password: Option<String>,
+    tls_client_key: Option<String>,
+    ca_certificate: Option<String>,
+    insecure: bool,
+    skip_bad_records: bool,
+    action_timeout: Option<Duration>,
+    stats: Option<Arc<RequestStats>>,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    page_size: u32,
+    read_only: bool,
+    task_comments: Option<String>,
+    task_priority: Option<i32>,
+    driver: Option<String>,
+    credential_set_id: Option<u32>,
+    login: Option<Login>,
+}
+
+impl NetshotClientBuilder {
+    /// Start building a client to the given Netshot URL
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            token: String::new(),
+            proxy: None,
+            tls_client_certificate: None,
+            tls_client_certificate_password: None,
+            tls_client_key: None,
+            ca_certificate: None,
+            insecure: false,
+            skip_bad_records: false,
+            action_timeout: None,
+            stats: None,
+            retry: RetryPolicy::default(),
+            timeout: Some(DEFAULT_TIMEOUT),
+            page_size: API_LIMIT,
+            read_only: false,
+            task_comments: None,
+            task_priority: None,
+            driver: None,
+            credential_set_id: None,
+            login: None,
+        }
+    }
+
+    /// The API token
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = token.into();
+        self
+    }
+
+    /// The user and password to open a session with instead of the token, if any
+    pub fn login(mut self, login: Option<Login>) -> Self {
+        self.login = login;
+        self
+    }
+
+    /// The proxy to go through, if any
+    pub fn proxy(mut self, proxy: Option<String>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// The PKCS12 file to authenticate with, if any, and its optional password
+    pub fn tls_client_certificate(
+        mut self,
+        tls_client_certificate: Option<String>,
+        tls_client_certificate_password: Option<String>,
+    ) -> Self {
+        self.tls_client_certificate = tls_client_certificate;
+        self.tls_client_certificate_password = tls_client_certificate_password;
+        self
+    }
+
+    /// The PEM private key of a PEM TLS client certificate, unless it is in the certificate file
+    pub fn tls_client_key(mut self, tls_client_key: Option<String>) -> Self {
+        self.tls_client_key = tls_client_key;
+        self
+    }
+
+    /// The PEM CA bundle to trust along with the system CAs, if any
+    pub fn ca_certificate(mut self, ca_certificate: Option<String>) -> Self {
+        self.ca_certificate = ca_certificate;
+        self
+    }
+
+    /// Accept any server certificate, whoever issued it and whatever its name
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Skip the devices that cannot be parsed instead of failing
+    pub fn skip_bad_records(mut self, skip_bad_records: bool) -> Self {
+        self.skip_bad_records = skip_bad_records;
+        self
+    }
+
+    /// The timeout of each register/disable/enable call, if any
+    pub fn action_timeout(mut self, action_timeout: Option<Duration>) -> Self {
+        self.action_timeout = action_timeout;
+        self
+    }
+
+    /// The statistics to record the requests into, if any
+    pub fn stats(mut self, stats: Option<Arc<RequestStats>>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// The time the service has to answer each request, 5 seconds by default, `None` for no limit
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How the requests failing transiently are retried, not at all by default
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The number of records requested per page on the paginated endpoints
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// The comments and priority of the discovery tasks created by the registrations, sent only when set
+    pub fn registration_task(
+        mut self,
+        task_comments: Option<String>,
+        task_priority: Option<i32>,
+    ) -> Self {
+        self.task_comments = task_comments;
+        self.task_priority = task_priority;
+        self
+    }
+
+    /// Register the devices with the given driver (e.g. `CiscoIOS12`) rather than through an SNMP discovery
+    pub fn driver(mut self, driver: Option<String>) -> Self {
+        self.driver = driver;
+        self
+    }
+
+    /// Register the devices with the given credential set rather than leaving them without
+    pub fn credential_set_id(mut self, credential_set_id: Option<u32>) -> Self {
+        self.credential_set_id = credential_set_id;
+        self
+    }
+
+    /// Create the client
+    pub fn build(self) -> Result<NetshotClient, Error> {
+        log::debug!("Creating new Netshot client to {}", self.url);
+        let mut http_headers = HeaderMap::new();
+        // A session is authenticated by the cookie of its login instead
+        if self.login.is_none() {
+            let header_value = HeaderValue::from_str(self.token.as_str())?;
+            http_headers.insert("X-Netshot-API-Token", header_value);
+        }
+        http_headers.insert("Accept", HeaderValue::from_str("application/json")?);
+        let mut http_client = reqwest::blocking::Client::builder()
+            .user_agent(APP_USER_AGENT)
+            .timeout(self.timeout)
+            .redirect(same_origin_redirect_policy())
+            .cookie_store(self.login.is_some())
+            .default_headers(http_headers);
+
+        http_client = match self.proxy {
+            Some(p) => http_client.proxy(Proxy::all(p)?),
+            None => http_client,
+        };
+
+        if let Some(ca_certificate) = self.ca_certificate {
+            for certificate in load_ca_certificates(&ca_certificate)? {
+                http_client = http_client.add_root_certificate(certificate);
+            }
+        }
+        if self.insecure {
+            log::warn!(
+                "The TLS certificate of Netshot at {} is NOT verified, anyone on the path can impersonate it",
+                self.url
+            );
+            http_client = http_client.danger_accept_invalid_certs(true);
+        }
+
+        let mut certificate_expiry = None;
+        http_client = match self.tls_client_certificate {
+            Some(c) => {
+                let client_identity = build_identity_from_file(
+                    c,
+                    self.tls_client_certificate_password,
+                    self.tls_client_key,
+                )?;
+                certificate_expiry = Some(client_identity.expires_at);
+                http_client.identity(client_identity.identity)
+            }
+            None => http_client,
+        };
+
+        let client = NetshotClient {
+            url: self.url,
+            token: self.token,
+            client: http_client.build()?,
+            skip_bad_records: self.skip_bad_records,
+            action_timeout: self.action_timeout,
+            stats: self.stats,
+            retry: self.retry,
+            timeout: self.timeout,
+            certificate_expiry,
+            version: None,
+            page_size: self.page_size,
+            read_only: self.read_only,
+            task_comments: self.task_comments,
+            task_priority: self.task_priority,
+            driver: self.driver,
+            credential_set_id: self.credential_set_id,
+            login: self.login,
+            addresses_cache: Mutex::new(HashMap::new()),
+            domains_cache: Mutex::new(None),
+            groups_cache: Mutex::new(None),
+        };
+        if client.login.is_some() {
+            client.log_in()?;
+        }
+        Ok(client)
+    }
+}
+
+impl NetshotClient {
+    /// Start building a client to the given Netshot URL
+    pub fn builder(url: impl Into<String>) -> NetshotClientBuilder {
+        NetshotClientBuilder::new(url)
+    }
+
+    /// Create a client with the given authentication token
+    pub fn new(
+        url: String,
+        token: String,
+        proxy: Option<String>,
+        tls_client_certificate: Option<String>,
+        tls_client_certificate_password: Option<String>,
+    ) -> Result<Self, Error> {
+        NetshotClient::builder(url)
+            .token(token)
+            .proxy(proxy)
+            .tls_client_certificate(tls_client_certificate, tls_client_certificate_password)
+            .build()
+    }
+
+    /// Bound the requests of a register/disable/enable action by the action timeout, if any
+    fn action_request(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.action_timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    /// Send a request to the given endpoint template, recording it when statistics are collected.
+    /// A request refused as the session expired is sent again once logged in again.
+    fn send(&self, template: &str, request: RequestBuilder) -> Result<Response, Error> {
+        if self.read_only {
+            // The device search is the only POST reading data
+            check_read_only("Netshot", template, &request, &[PATH_DEVICES_SEARCH])?;
+        }
+        let replay = self.login.as_ref().and_then(|_| request.try_clone());
+        let response = self.send_once(template, request)?;
+        match replay {
+            Some(replay) if response.status() == StatusCode::UNAUTHORIZED => {
+                log::warn!(
+                    "The Netshot session expired on {}, logging in again",
+                    template
+                );
+                self.log_in()?;
+                self.send_once(template, replay)
+            }
+            _ => Ok(response),
+        }
+    }
+
+    fn send_once(&self, template: &str, request: RequestBuilder) -> Result<Response, Error> {
+        let limit = request_timeout(&request).or(self.timeout);
+        stats::send(
+            &self.client,
+            self.stats.as_deref(),
+            &self.retry,
+            &[PATH_DEVICES_SEARCH],
+            template,
+            request,
+        )
+        .map_err(|error| timeout_context(error, "Netshot", template, limit))
+    }
+
+    /// Open a session with the user and password, its cookie authenticating the following requests
+    fn log_in(&self) -> Result<(), Error> {
+        let login = match &self.login {
+            Some(login) => login,
+            None => return Ok(()),
+        };
+        let url = format!("{}{}", self.url, PATH_USER);
+        log::debug!("Logging in to Netshot {} as {}", self.url, login.user);
+        // Logging in changes nothing, so is allowed in read-only mode
+        let response = stats::send(
+            &self.client,
+            self.stats.as_deref(),
+            &self.retry,
+            &[PATH_USER],
+            PATH_USER,
+            self.client.post(url).json(login),
+        )
+        .map_err(|error| timeout_context(error, "Netshot", PATH_USER, self.timeout))
+        .map_err(|error| error.context(format!("Unable to reach Netshot at {}", self.url)))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(write_error(
+                "Netshot",
+                PATH_USER,
+                status,
+                format!(
+                    "Unable to log in to Netshot {} as {}, got status {}{}",
+                    self.url,
+                    login.user,
+                    status,
+                    error_details(response)
+                ),
+            ));
+        }
+        log::debug!("Logged in to Netshot {} as {}", self.url, login.user);
+        Ok(())
+    }
+
+    /// Ping the service to make sure it is reachable and accepts the token, by getting the user of the token.
+    /// Fails when Netshot cannot be reached or refuses the token, false on the other error statuses.
+    pub fn ping(&self) -> Result<bool, Error> {
+        let url = format!("{}{}", self.url, PATH_USER);
+        log::debug!("Pinging {}", url);
+        let response = self
+            .send(PATH_USER, self.client.get(url))
+            .map_err(|error| error.context(format!("Unable to reach Netshot at {}", self.url)))?;
+        let status = response.status();
+        log::debug!("Ping response: {}", status);
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(read_error(
+                "Netshot",
+                PATH_USER,
+                status,
+                format!(
+                    "Authentication to Netshot {} failed, check the {}",
+                    self.url,
+                    self.credentials()
+                ),
+            ));
+        }
+        Ok(status.is_success())
+    }
+
+    /// What the client authenticates with, for the error messages
+    fn credentials(&self) -> &'static str {
+        match self.login {
+            Some(_) => "user and password",
+            None => "token",
+        }
+    }
+
+    /// Query the Netshot release to select the matching payload formats, keeping the current ones if it cannot be detected
+    pub fn detect_version(&mut self) -> Result<Option<NetshotVersion>, Error> {
+        let url = format!("{}{}", self.url, PATH_SERVER_INFO);
+        let response = self.send(PATH_SERVER_INFO, self.client.get(url))?;
+        if !response.status().is_success() {
+            log::warn!(
+                "Unable to detect the Netshot version, got status {} on {}",
+                response.status(),
+                PATH_SERVER_INFO
+            );
+            return Ok(None);
+        }
+
+        // An unexpected answer leaves the version unknown rather than aborting the run
+        let version = json_body(response)
+            .and_then(|body| parse_json::<ServerInfo>(&body, "server info"))
+            .and_then(|server_info| server_info.server_version.parse::<NetshotVersion>());
+        let version = match version {
+            Ok(version) => version,
+            Err(error) => {
+                log::warn!("Unable to detect the Netshot version: {:#}", error);
+                return Ok(None);
+            }
+        };
+        log::info!("Netshot version {} detected", version);
+        if version.is_newer_than_known() {
+            log::warn!(
+                "Netshot {} is newer than {}, the newest release known to netbox2netshot, payloads may have changed",
+                version,
+                NEWEST_KNOWN_VERSION
+            );
+        }
+        self.version = Some(version);
+        Ok(self.version)
+    }
+
+    /// Fetch every page of a listing endpoint, stopping on a short or repeated page
+    fn get_paginated<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query_string: &str,
+        what: &str,
+    ) -> Result<Vec<T>, Error> {
+        let mut records: Vec<T> = Vec::new();
+        let mut previous_page: Option<Vec<Value>> = None;
+        let mut offset = 0;
+
+        loop {
+            let mut url = format!(
+                "{}{}?offset={}&limit={}",
+                self.url, path, offset, self.page_size
+            );
+            if !query_string.is_empty() {
+                url = format!("{}&{}", url, query_string);
+            }
+            let page: Vec<Value> = parse_json(
+                &json_body(self.send(path, self.client.get(url))?)?,
+                &format!("{} list", what),
+            )?;
+            let page_length = page.len() as u32;
+
+            // A server ignoring the offset hands over its whole list on every page
+            if page_length > 0 && previous_page.as_ref() == Some(&page) {
+                log::warn!(
+                    "Netshot ignores the paging of {}, offset {} returned the previous page again, taking it as the end of the list",
+                    path,
+                    offset
+                );
+                break;
+            }
+
+            let mut parsed = parse_records(page.clone(), what, self.skip_bad_records, |value| {
+                serde_json::from_value(value).map_err(|error| describe_parse_error(error, what))
+            })
+            .map_err(|error| anyhow!("{} (offset {} of {})", error, offset, path))?;
+            records.append(&mut parsed);
+            log::debug!(
+                "Got {} {} records from Netshot (page {})",
+                records.len(),
+                what,
+                offset / self.page_size.max(1)
+            );
+
+            if page_length < self.page_size {
+                break;
+            }
+            previous_page = Some(page);
+            offset += page_length;
+        }
+
+        Ok(records)
+    }
+
+    /// Get the Netshot domains, cached for the lifetime of the client
+    pub fn get_domains(&self) -> Result<Vec<Domain>, Error> {
+        if let Some(domains) = self.domains_cache.lock().unwrap().as_ref() {
+            return Ok(domains.clone());
+        }
+
+        let domains: Vec<Domain> = self.get_paginated(PATH_DOMAINS, "", "domain")?;
+        log::info!("Fetched {} domains from Netshot", domains.len());
+        *self.domains_cache.lock().unwrap() = Some(domains.clone());
+        Ok(domains)
+    }
+
+    /// Get the Netshot credential sets
+    pub fn get_credential_sets(&self) -> Result<Vec<CredentialSet>, Error> {
+        let credential_sets: Vec<CredentialSet> =
+            self.get_paginated(PATH_CREDENTIAL_SETS, "", "credential set")?;
+        log::debug!(
+            "Fetched {} credential sets from Netshot",
+            credential_sets.len()
+        );
+        Ok(credential_sets)
+    }
+
+    /// Register the devices with the credential set of the given name, failing when Netshot has none or several
+    pub fn resolve_credential_set(&mut self, name: &str) -> Result<u32, Error> {
+        let credential_sets = self.get_credential_sets()?;
+        let matching: Vec<u32> = credential_sets
+            .iter()
+            .filter(|credential_set| credential_set.name.as_deref() == Some(name))
+            .map(|credential_set| credential_set.id)
+            .collect();
+        match matching.as_slice() {
+            [id] => {
+                log::info!("Registering the devices with the credential set {} ({})", name, id);
+                self.credential_set_id = Some(*id);
+                Ok(*id)
+            }
+            [] => Err(anyhow!("Netshot has no credential set named {}", name)),
+            ids => Err(anyhow!(
+                "Netshot has {} credential sets named {}: {:?}, use --netshot-credentialset-id instead",
+                ids.len(),
+                name,
+                ids
+            )),
+        }
+    }
+
+    /// Get the Netshot device groups, cached for the lifetime of the client
+    pub fn get_groups(&self) -> Result<Vec<Group>, Error> {
+        if let Some(groups) = self.groups_cache.lock().unwrap().as_ref() {
+            return Ok(groups.clone());
+        }
+
+        let groups: Vec<Group> = self.get_paginated(PATH_GROUPS, "", "group")?;
+        log::info!("Fetched {} groups from Netshot", groups.len());
+        *self.groups_cache.lock().unwrap() = Some(groups.clone());
+        Ok(groups)
+    }
+
+    /// Get the Netshot device groups again, as the cached ones miss the changes of the other clients
+    pub fn refresh_groups(&self) -> Result<Vec<Group>, Error> {
+        *self.groups_cache.lock().unwrap() = None;
+        self.get_groups()
+    }
+
+    /// Create an empty static group, in the folder marking it as created by netbox2netshot
+    pub fn create_static_group(&self, name: &str) -> Result<Group, Error> {
+        log::info!("Creating the static group {}", name);
+        let payload = StaticGroupPayload {
+            name: name.to_string(),
+            folder: MANAGED_GROUP_FOLDER.to_string(),
+            group_type: GROUP_TYPE_STATIC.to_string(),
+            static_devices: Vec::new(),
+        };
+        let url = format!("{}{}", self.url, PATH_GROUPS);
+        let response = self.send(
+            PATH_GROUPS,
+            self.action_request(self.client.post(url).json(&payload)),
+        )?;
+
+        if !response.status().is_success() {
+            return Err(write_error(
+                "Netshot",
+                PATH_GROUPS,
+                response.status(),
+                format!(
+                    "Failed to create the group {}, got status {}",
+                    name,
+                    response.status()
+                ),
+            ));
+        }
+
+        let group: Group = parse_json(&json_body(response)?, "group")?;
+        if let Some(groups) = self.groups_cache.lock().unwrap().as_mut() {
+            groups.push(group.clone());
+        }
+        Ok(group)
+    }
+
+    /// Add the device with the given IP to the named static group, or remove it from the group.
+    /// Returns false when the membership already is the requested one.
+    pub fn set_group_membership(
+        &self,
+        group_name: &str,
+        ip_address: String,
+        member: bool,
+    ) -> Result<bool, Error> {
+        log::info!(
+            "Setting the membership of device with IP {} in group {} to {}",
+            ip_address,
+            group_name,
+            member
+        );
+
+        let response = self.search_device(&SearchQuery::ip(&ip_address)?)?;
+        let device = response.device_with_ip(&ip_address)?;
+
+        self.update_static_group(
+            |group| group.name == group_name,
+            group_name,
+            device.id,
+            &format!("{}({})", device.name, ip_address),
+            member,
+        )
+    }
+
+    /// Add the device with the given ID to the static group with the given ID.
+    /// Returns false when it already is a member.
+    pub fn add_device_to_group(&self, device_id: u32, group_id: u32) -> Result<bool, Error> {
+        log::info!("Adding device {} to group {}", device_id, group_id);
+        self.update_static_group(
+            |group| group.id == group_id,
+            &format!("with ID {}", group_id),
+            device_id,
+            &format!("with ID {}", device_id),
+            true,
+        )
+    }
+
+    /// Add a device to the first static group matching, or remove it from the group.
+    /// Returns false when the membership already is the requested one.
+    fn update_static_group(
+        &self,
+        matches: impl Fn(&Group) -> bool,
+        group_name: &str,
+        device_id: u32,
+        device_name: &str,
+        member: bool,
+    ) -> Result<bool, Error> {
+        self.get_groups()?;
+        // The membership is read from the cache and written as a whole, the lock keeps the updates in sequence
+        let mut groups_cache = self.groups_cache.lock().unwrap();
+        let group = groups_cache
+            .iter_mut()
+            .flatten()
+            .find(|group| matches(group))
+            .ok_or_else(|| anyhow!("No group {} found on Netshot", group_name))?;
+        if !group.is_static() {
+            return Err(anyhow!("The Netshot group {} is not static", group_name));
+        }
+        if group.static_devices.contains(&device_id) == member {
+            log::debug!(
+                "Device {} already has the membership {} in group {}",
+                device_name,
+                member,
+                group_name
+            );
+            return Ok(false);
+        }
+
+        let mut static_devices = group.static_devices.clone();
+        if member {
+            static_devices.push(device_id);
+        } else {
+            static_devices.retain(|id| *id != device_id);
+        }
+        let payload = StaticGroupPayload {
+            name: group.name.clone(),
+            folder: group.folder.clone().unwrap_or_default(),
+            group_type: GROUP_TYPE_STATIC.to_string(),
+            static_devices: static_devices.clone(),
+        };
+        let url = format!("{}{}/{}", self.url, PATH_GROUPS, group.id);
+        let response = self.send(
+            TEMPLATE_GROUP,
+            self.action_request(self.client.put(url).json(&payload)),
+        )?;
+
+        if !response.status().is_success() {
+            return Err(write_error(
+                "Netshot",
+                TEMPLATE_GROUP,
+                response.status(),
+                format!(
+                    "Failed to update the members of group {} for device {}, got status {}",
+                    group_name,
+                    device_name,
+                    response.status()
+                ),
+            ));
+        }
+        group.static_devices = static_devices;
+        log::debug!(
+            "Membership of {} in group {} updated",
+            device_name,
+            group_name
+        );
+        Ok(true)
+    }
+
+    /// Delete a device group, its members being left alone
+    pub fn delete_group(&self, group: &Group) -> Result<(), Error> {
+        log::info!("Deleting the group {}(id {})", group.name, group.id);
+        let url = format!("{}{}/{}", self.url, PATH_GROUPS, group.id);
+        let response = self.send(TEMPLATE_GROUP, self.action_request(self.client.delete(url)))?;
+
+        if !response.status().is_success() {
+            return Err(write_error(
+                "Netshot",
+                TEMPLATE_GROUP,
+                response.status(),
+                format!(
+                    "Failed to delete the group {}, got status {}",
+                    group.name,
+                    response.status()
+                ),
+            ));
+        }
+        if let Some(groups) = self.groups_cache.lock().unwrap().as_mut() {
+            groups.retain(|cached| cached.id != group.id);
+        }
+        Ok(())
+    }
+
+    /// Get the tasks in the given status (e.g. SCHEDULED)
+    pub fn get_tasks(&self, status: &str) -> Result<Vec<Task>, Error> {
+        let tasks: Vec<Task> =
+            self.get_paginated(PATH_TASKS, &format!("status={}", status), "task")?;
+        log::debug!("Fetched {} {} tasks from Netshot", tasks.len(), status);
+        Ok(tasks)
+    }
+
+    /// Get a single task, e.g. the discovery started by a registration
+    pub fn get_task(&self, task_id: u32) -> Result<Task, Error> {
+        let url = format!("{}{}/{}", self.url, PATH_TASKS, task_id);
+        parse_json(
+            &json_body(self.send(TEMPLATE_TASK, self.client.get(url))?)?,
+            "task",
+        )
+    }
+
+    /// Count the device discovery tasks scheduled or running
+    pub fn count_pending_discoveries(&self) -> Result<usize, Error> {
+        let mut count = 0;
+        for status in TASK_PENDING_STATUSES {
+            count += self
+                .get_tasks(status)?
+                .iter()
+                .filter(|task| task.is_discovery())
+                .count();
+        }
+        Ok(count)
+    }
+
+    /// Make sure the token is allowed to register and update devices, using the privileges of its user
+    pub fn check_write_permission(&self) -> Result<(), Error> {
+        let url = format!("{}{}", self.url, PATH_USER);
+        let response = self.send(PATH_USER, self.client.get(url))?;
+
+        let status = response.status();
+        // A refused authentication is no matter of privileges
+        if status == StatusCode::UNAUTHORIZED {
+            return Err(read_error(
+                "Netshot",
+                PATH_USER,
+                status,
+                format!(
+                    "Authentication to Netshot {} failed, check the {}",
+                    self.url,
+                    self.credentials()
+                ),
+            ));
+        }
+        if status == StatusCode::FORBIDDEN {
+            return Err(anyhow!(
+                "Netshot token appears read-only, got status {} on {}",
+                status,
+                PATH_USER
+            ));
+        }
+        if !status.is_success() {
+            log::warn!(
+                "Unable to verify the Netshot token permissions, got status {} on {}",
+                status,
+                PATH_USER
+            );
+            return Ok(());
+        }
+
+        let user: User = parse_json(&json_body(response)?, "user")?;
+        log::debug!(
+            "Netshot token belongs to {:?} with level {}",
+            user.username,
+            user.level
+        );
+        if user.level < LEVEL_READ_WRITE {
+            return Err(anyhow!(
+                "Netshot token appears read-only, its user {} has level {} (at least {} is required)",
+                user.username.as_deref().unwrap_or("<unknown>"),
+                user.level,
+                LEVEL_READ_WRITE
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get devices registered in Netshot
+    pub fn get_devices(&self, domain_id: u32) -> Result<Fetched<Device>, Error> {
+        let mut devices: Vec<Device> = Vec::new();
+        let mut snapshot = FetchSnapshot::start(Source::NetshotDevices);
+        snapshot.domain_id = Some(domain_id);
+        let progress = Progress::start(
+            format!(
+                "Fetching the {} of domain {}",
+                Source::NetshotDevices,
+                domain_id
+            ),
+            None,
+        );
+        self.for_each_devices_page(Some(domain_id), |mut page| {
+            snapshot.page(page.len(), None);
+            progress.advance(page.len());
+            devices.append(&mut page);
+            Ok(())
+        })?;
+
+        log::debug!("Got {} devices from Netshot", devices.len());
+
+        Ok(Fetched {
+            snapshot: snapshot.finish(devices.len()),
+            items: devices,
+        })
+    }
+
+    /// Fetch the devices registered in Netshot (optionally in a single domain), handing over each page as it arrives
+    pub fn for_each_devices_page<F>(
+        &self,
+        domain_id: Option<u32>,
+        mut handle_page: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(Vec<Device>) -> Result<(), Error>,
+    {
+        let mut previous_page: Option<Vec<Value>> = None;
+        let mut offset = 0;
+
+        loop {
+            let mut url = format!(
+                "{}{}?offset={}&limit={}",
+                self.url, PATH_DEVICES, offset, self.page_size
+            );
+            if let Some(domain_id) = domain_id {
+                url = format!("{}&group={}", url, domain_id);
+            }
+            let values: Vec<Value> = parse_json(
+                &json_body(self.send(PATH_DEVICES, self.client.get(url))?)?,
+                "devices list",
+            )?;
+            let page_length = values.len() as u32;
+
+            // A server ignoring the offset hands over its whole list on every page
+            if page_length > 0 && previous_page.as_ref() == Some(&values) {
+                log::warn!(
+                    "Netshot ignores the paging of {}, offset {} returned the previous page again, taking it as the end of the list",
+                    PATH_DEVICES,
+                    offset
+                );
+                break;
+            }
+
+            let page = parse_device_values(values.clone(), self.skip_bad_records)
+                .map_err(|error| anyhow!("{} (offset {} of {})", error, offset, PATH_DEVICES))?;
+            handle_page(page)?;
+            log::debug!(
+                "Got {} device records from Netshot at offset {}",
+                page_length,
+                offset
+            );
+
+            // A short page is the last one, a longer one means the release sends every device at once
+            if page_length != self.page_size {
+                break;
+            }
+            previous_page = Some(values);
+            offset += page_length;
+        }
+        Ok(())
+    }
+
+    /// Get the IP addresses configured on the interfaces of a device, cached for the lifetime of the client
+    pub fn get_device_addresses(&self, device_id: u32) -> Result<Vec<String>, Error> {
+        if let Some(addresses) = self.addresses_cache.lock().unwrap().get(&device_id) {
+            return Ok(addresses.clone());
+        }
+
+        let url = format!(
+            "{}{}/{}{}",
+            self.url, PATH_DEVICES, device_id, PATH_DEVICE_INTERFACES
+        );
+        let interfaces: Vec<Interface> = parse_json(
+            &json_body(self.send(TEMPLATE_DEVICE_INTERFACES, self.client.get(url))?)?,
+            "device interfaces",
+        )?;
+        let addresses: Vec<String> = interfaces
+            .into_iter()
+            .flat_map(|interface| {
+                interface
+                    .ip4_addresses
+                    .into_iter()
+                    .chain(interface.ip6_addresses)
+            })
+            .map(|address| address.ip)
+            .collect();
+        log::debug!(
+            "Got {} interface addresses for Netshot device {}",
+            addresses.len(),
+            device_id
+        );
+
+        self.addresses_cache
+            .lock()
+            .unwrap()
+            .insert(device_id, addresses.clone());
+        Ok(addresses)
+    }
+
+    /// Register a given IP into Netshot and return the corresponding device
+    pub fn register_device(
+        &self,
+        ip_address: String,
+        domain_id: u32,
+        name: &str,
+    ) -> Result<NewDeviceCreatedPayload, Error> {
+        log::info!(
+            action = "register",
+            ip = ip_address.as_str(),
+            hostname = name,
+            domain_id = domain_id;
+            "Registering new device with IP {}",
+            ip_address
+        );
+
+        // Without a driver, Netshot discovers the device type and name over SNMP
+        let new_device = NewDevicePayload {
+            auto_discover: self.driver.is_none(),
+            ip_address: ip_address.clone(),
+            domain_id,
+            comments: self.task_comments.clone(),
+            priority: self.task_priority,
+            name: self.driver.as_ref().map(|_| name.to_string()),
+            device_type: self.driver.clone(),
+            credential_set_ids: self.credential_set_id.map(|id| vec![id]),
+        };
+
+        let url = format!("{}{}", self.url, PATH_DEVICES);
+        let response = self.send(
+            PATH_DEVICES,
+            self.action_request(self.client.post(url).json(&new_device)),
+        )?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = format!(
+                "Failed to register new device {}, got status {}{}",
+                ip_address,
+                status,
+                error_details(response)
+            );
+            log::warn!("{}", message);
+            return Err(write_error("Netshot", PATH_DEVICES, status, message));
+        }
+
+        let device_registration: NewDeviceCreatedPayload =
+            parse_json(&json_body(response)?, "device registration task")?;
+        log::debug!(
+            "Device registration for device {} requested with task ID {}",
+            ip_address,
+            device_registration.task_id
+        );
+
+        Ok(device_registration)
+    }
+
+    /// Search for a device
+    pub fn search_device(&self, query: &SearchQuery) -> Result<DeviceSearchResultPayload, Error> {
+        let url = format!("{}{}", self.url, PATH_DEVICES_SEARCH);
+        let query_string = query.as_str();
+
+        let query = DeviceSearchQueryPayload {
+            query: query_string.to_string(),
+        };
+
+        let response = self.send(
+            PATH_DEVICES_SEARCH,
+            self.action_request(self.client.post(url).json(&query)),
+        )?;
+
+        if !response.status().is_success() {
+            let message = format!(
+                "Failed to search for device with query `{}`, got status {}{}",
+                query_string,
+                response.status(),
+                error_details(response)
+            );
+            log::warn!("{}", message);
+            return Err(anyhow!(message));
+        }
+
+        // The devices are parsed as in the lists, whatever the form of their management address
+        let raw_result: RawSearchResult =
+            parse_json(&json_body(response)?, "device search result")?;
+        let search_result = DeviceSearchResultPayload {
+            query: raw_result.query,
+            devices: parse_device_values(raw_result.devices, self.skip_bad_records)?,
+        };
+        log::debug!(
+            "Found {} devices with the given search",
+            search_result.devices.len(),
+        );
+
+        Ok(search_result)
+    }
+
+    /// Set the given device to a given state (enabled/disabled)
+    fn set_device_enabled(
+        &self,
+        ip_address: String,
+        enabled: bool,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        log::info!(
+            action = state_action(enabled),
+            ip = ip_address.as_str();
+            "Setting device with IP {} to enabled={}",
+            ip_address,
+            enabled
+        );
+
+        // Search for the device ID
+        let response = self.search_device(&SearchQuery::ip(&ip_address)?)?;
+        let device = response.device_with_ip(&ip_address)?;
+
+        self.update_device_state(device, &ip_address, enabled)
+    }
+
+    /// Set a device of the fetched inventory to a given state, with the ID and status already known
+    fn set_device_enabled_by_id(
+        &self,
+        device: &Device,
+        enabled: bool,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        let ip_address = device.management_address.ip.as_str();
+        log::info!(
+            action = state_action(enabled),
+            ip = ip_address,
+            netshot_id = device.id;
+            "Setting device {}({}) to enabled={}",
+            device.name,
+            ip_address,
+            enabled
+        );
+        self.update_device_state(device, ip_address, enabled)
+    }
+
+    /// Set the given devices to a given state, resolving them with a single search per batch of `batch_size` IPs.
+    /// Netshot has no bulk update, so each device is still updated (and reported, with the duration of its update)
+    /// on its own; the members a batch search fails to resolve are retried individually.
+    pub fn set_devices_enabled(
+        &self,
+        ip_addresses: &[String],
+        enabled: bool,
+        batch_size: usize,
+    ) -> Vec<(
+        String,
+        Duration,
+        Result<Option<DeviceUpdatedPayload>, Error>,
+    )> {
+        let mut outcomes = Vec::with_capacity(ip_addresses.len());
+
+        for batch in ip_addresses.chunks(batch_size.max(1)) {
+            log::info!(
+                "Setting {} devices to enabled={} in a batch",
+                batch.len(),
+                enabled
+            );
+            let search = SearchQuery::any_ip(batch).and_then(|query| match query {
+                Some(query) => self.search_device(&query),
+                None => Ok(DeviceSearchResultPayload::default()),
+            });
+            let found = match search {
+                Ok(response) => response,
+                Err(error) => {
+                    log::warn!(
+                        "Batch search failed, falling back to per-device updates: {}",
+                        error
+                    );
+                    DeviceSearchResultPayload::default()
+                }
+            };
+
+            for ip_address in batch {
+                // Netshot does not compress the IPv6 addresses, the devices are matched on the parsed IP
+                let device = found.device_with_ip(ip_address).ok();
+                let start = Instant::now();
+                let outcome = match device {
+                    Some(device) => self.update_device_state(device, ip_address, enabled),
+                    None => self.set_device_enabled(ip_address.clone(), enabled),
+                };
+                outcomes.push((ip_address.clone(), start.elapsed(), outcome));
+            }
+        }
+
+        outcomes
+    }
+
+    /// Update the state of a device found on Netshot, unless it already is in that state
+    fn update_device_state(
+        &self,
+        device: &Device,
+        ip_address: &str,
+        enabled: bool,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        let state = UpdateDevicePayload { enabled };
+
+        if !enabled && device.is_disabled() {
+            log::warn!(
+                action = state_action(enabled),
+                ip = ip_address,
+                hostname = device.name.as_str(),
+                netshot_id = device.id;
+                "Device {}({}) is already disabled, skipping",
+                device.name,
+                ip_address
+            );
+            return Ok(Option::None);
+        } else if enabled && device.is_in_production() {
+            log::warn!(
+                action = state_action(enabled),
+                ip = ip_address,
+                hostname = device.name.as_str(),
+                netshot_id = device.id;
+                "Device {}({}) is already enabled, skipping",
+                device.name,
+                ip_address
+            );
+            return Ok(Option::None);
+        }
+
+        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device.id);
+        let response = self.send(
+            TEMPLATE_DEVICE,
+            self.action_request(self.client.put(url).json(&state)),
+        )?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = format!(
+                "Failed to update state for device {}, got status {}{}",
+                ip_address,
+                status,
+                error_details(response)
+            );
+            log::warn!("{}", message);
+            return Err(write_error("Netshot", TEMPLATE_DEVICE, status, message));
+        }
+
+        let device_update: DeviceUpdatedPayload =
+            parse_json(&json_body(response)?, "device update result")?;
+        device_update.check_enabled(ip_address, enabled)?;
+        log::debug!("Device state of {} set to enabled={}", ip_address, enabled);
+
+        Ok(Option::Some(device_update))
+    }
+
+    /// Get the details of a device, including its specific credential sets
+    pub fn get_device_details(&self, device_id: u32) -> Result<DeviceDetails, Error> {
+        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device_id);
+        let response = self.send(TEMPLATE_DEVICE, self.client.get(url))?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Failed to get the details of device {}, got status {}",
+                device_id,
+                response.status().to_string()
+            ));
+        }
+
+        parse_json(&json_body(response)?, "device details")
+    }
+
+    /// Get the IDs of the specific credential sets assigned to a device
+    pub fn get_device_credential_set_ids(&self, device_id: u32) -> Result<Vec<u32>, Error> {
+        let details = self.get_device_details(device_id)?;
+        Ok(details
+            .credential_sets
+            .iter()
+            .map(|credential_set| credential_set.id)
+            .collect())
+    }
+
+    /// Replace the specific credential sets of the device with the given IP
+    pub fn set_device_credential_sets(
+        &self,
+        ip_address: String,
+        credential_set_ids: &[u32],
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        log::info!(
+            "Setting the credential sets of device with IP {} to {:?}",
+            ip_address,
+            credential_set_ids
+        );
+
+        let response = self.search_device(&SearchQuery::ip(&ip_address)?)?;
+        let device = response.device_with_ip(&ip_address)?;
+
+        let update = UpdateCredentialSetsPayload {
+            credential_set_ids: credential_set_ids.to_vec(),
+        };
+        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device.id);
+        let response = self.send(
+            TEMPLATE_DEVICE,
+            self.action_request(self.client.put(url).json(&update)),
+        )?;
+
+        if !response.status().is_success() {
+            log::warn!(
+                "Failed to update the credential sets of device {}, got status {}",
+                ip_address,
+                response.status().to_string()
+            );
+            return Err(write_error(
+                "Netshot",
+                TEMPLATE_DEVICE,
+                response.status(),
+                format!(
+                    "Failed to update the credential sets of device {}, got status {}",
+                    ip_address,
+                    response.status()
+                ),
+            ));
+        }
+
+        let device_update: DeviceUpdatedPayload =
+            parse_json(&json_body(response)?, "device update result")?;
+        log::debug!("Credential sets of {} updated", ip_address);
+
+        Ok(device_update)
+    }
+
+    /// Get the free-text comments of a device
+    pub fn get_device_comments(&self, device_id: u32) -> Result<Option<String>, Error> {
+        Ok(self.get_device_details(device_id)?.comments)
+    }
+
+    /// Replace the comments of the device with the given IP
+    pub fn update_device_comments(
+        &self,
+        ip_address: String,
+        comments: &str,
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        log::info!("Updating the comments of device with IP {}", ip_address);
+
+        let response = self.search_device(&SearchQuery::ip(&ip_address)?)?;
+        let device = response.device_with_ip(&ip_address)?;
+
+        let update = UpdateCommentsPayload {
+            comments: comments.to_string(),
+        };
+        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device.id);
+        let response = self.send(
+            TEMPLATE_DEVICE,
+            self.action_request(self.client.put(url).json(&update)),
+        )?;
+
+        if !response.status().is_success() {
+            return Err(write_error(
+                "Netshot",
+                TEMPLATE_DEVICE,
+                response.status(),
+                format!(
+                    "Failed to update the comments of device {}, got status {}",
+                    ip_address,
+                    response.status()
+                ),
+            ));
+        }
+
+        let device_update: DeviceUpdatedPayload =
+            parse_json(&json_body(response)?, "device update result")?;
+        log::debug!("Comments of {} updated", ip_address);
+
+        Ok(device_update)
+    }
+
+    /// Set the prefix length of the management address of the device with the given IP
+    pub fn set_device_prefix_length(
+        &self,
+        ip_address: String,
+        prefix_length: u8,
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        log::info!(
+            "Setting the management address of device with IP {} to {}/{}",
+            ip_address,
+            ip_address,
+            prefix_length
+        );
+
+        let response = self.search_device(&SearchQuery::ip(&ip_address)?)?;
+        let device = response.device_with_ip(&ip_address)?;
+
+        let update = UpdateAddressPayload {
+            ip_address: format!("{}/{}", ip_address, prefix_length),
+        };
+        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device.id);
+        let response = self.send(
+            TEMPLATE_DEVICE,
+            self.action_request(self.client.put(url).json(&update)),
+        )?;
+
+        if !response.status().is_success() {
+            return Err(write_error(
+                "Netshot",
+                TEMPLATE_DEVICE,
+                response.status(),
+                format!(
+                    "Failed to update the prefix length of device {}, got status {}",
+                    ip_address,
+                    response.status()
+                ),
+            ));
+        }
+
+        let device_update: DeviceUpdatedPayload =
+            parse_json(&json_body(response)?, "device update result")?;
+        log::debug!("Prefix length of {} updated", ip_address);
+
+        Ok(device_update)
+    }
+
+    /// Move the device with the given ID to a new management address, keeping its configuration history
+    pub fn update_device_ip(
+        &self,
+        device_id: u32,
+        ip_address: String,
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        log::info!(
+            action = "update_address",
+            netshot_id = device_id,
+            ip = ip_address.as_str();
+            "Setting the management address of device {} to {}",
+            device_id,
+            ip_address
+        );
+
+        let update = UpdateAddressPayload {
+            ip_address: ip_address.clone(),
+        };
+        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device_id);
+        let response = self.send(
+            TEMPLATE_DEVICE,
+            self.action_request(self.client.put(url).json(&update)),
+        )?;
+
+        if !response.status().is_success() {
+            return Err(write_error(
+                "Netshot",
+                TEMPLATE_DEVICE,
+                response.status(),
+                format!(
+                    "Failed to update the management address of device {} to {}, got status {}",
+                    device_id,
+                    ip_address,
+                    response.status()
+                ),
+            ));
+        }
+
+        let device_update: DeviceUpdatedPayload =
+            parse_json(&json_body(response)?, "device update result")?;
+        log::debug!("Management address of device {} updated", device_id);
+
+        Ok(device_update)
+    }
+
+    /// Rename the device with the given ID, e.g. after its renaming on Netbox
+    pub fn rename_device(
+        &self,
+        device_id: u32,
+        name: String,
+    ) -> Result<DeviceUpdatedPayload, Error> {
+        log::info!(
+            action = "rename",
+            netshot_id = device_id,
+            hostname = name.as_str();
+            "Renaming device {} to {}",
+            device_id,
+            name
+        );
+
+        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device_id);
+        let response = self.send(
+            TEMPLATE_DEVICE,
+            self.action_request(
+                self.client
+                    .put(url)
+                    .json(&RenamePayload { name: name.clone() }),
+            ),
+        )?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = format!(
+                "Failed to rename device {} to {}, got status {}{}",
+                device_id,
+                name,
+                status,
+                error_details(response)
+            );
+            log::warn!("{}", message);
+            return Err(write_error("Netshot", TEMPLATE_DEVICE, status, message));
+        }
+
+        let device_update: DeviceUpdatedPayload =
+            parse_json(&json_body(response)?, "device update result")?;
+        log::debug!("Device {} renamed to {}", device_id, name);
+
+        Ok(device_update)
+    }
+
+    /// Delete the device with the given IP from Netshot
+    pub fn delete_device(&self, ip_address: String) -> Result<(), Error> {
+        log::info!(
+            action = "purge",
+            ip = ip_address.as_str();
+            "Deleting device with IP {}",
+            ip_address
+        );
+
+        let response = self.search_device(&SearchQuery::ip(&ip_address)?)?;
+        let device = response.device_with_ip(&ip_address)?;
+
+        let url = format!("{}{}/{}", self.url, PATH_DEVICES, device.id);
+        let response = self.send(
+            TEMPLATE_DEVICE,
+            self.action_request(self.client.delete(url)),
+        )?;
+
+        if !response.status().is_success() {
+            return Err(write_error(
+                "Netshot",
+                TEMPLATE_DEVICE,
+                response.status(),
+                format!(
+                    "Failed to delete device {}, got status {}",
+                    ip_address,
+                    response.status()
+                ),
+            ));
+        }
+        log::debug!("Device {} deleted", ip_address);
+        Ok(())
+    }
+
+    /// Disable a given device
+    pub fn disable_device(
+        &self,
+        ip_address: String,
     ) -> Result<Option<DeviceUpdatedPayload>, Error> {
         self.set_device_enabled(ip_address, false)
     }
 
-    /// Enable a given device
-    pub fn enable_device(&self, ip_address: String) -> Result<Option<DeviceUpdatedPayload>, Error> {
-        self.set_device_enabled(ip_address, true)
+    /// Enable a given device
+    pub fn enable_device(&self, ip_address: String) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        self.set_device_enabled(ip_address, true)
+    }
+
+    /// Disable a device of the fetched inventory, without searching for it
+    pub fn disable_device_by_id(
+        &self,
+        device: &Device,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        self.set_device_enabled_by_id(device, false)
+    }
+
+    /// Enable a device of the fetched inventory, without searching for it
+    pub fn enable_device_by_id(
+        &self,
+        device: &Device,
+    ) -> Result<Option<DeviceUpdatedPayload>, Error> {
+        self.set_device_enabled_by_id(device, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rest::helpers::{is_timeout, ReadOnlyViolation};
+    use mockito;
+
+    #[test]
+    fn authenticated_initialization() {
+        let url = mockito::server_url();
+        let token = String::from("hello");
+        let client = NetshotClient::new(url.clone(), token.clone(), None, None, None).unwrap();
+        assert_eq!(client.token, token);
+        assert_eq!(client.url, url);
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        let url = mockito::server_url();
+
+        // The charset some releases declare is not the one of their JSON
+        let _mock = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_header("content-type", "application/json;charset=ISO-8859-1")
+            .with_body_from_file("tests/data/netshot/non_ascii_names.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let names: Vec<String> = client
+            .get_devices(1)
+            .unwrap()
+            .items
+            .into_iter()
+            .map(|device| device.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "東京-コア-1",
+                "DÜSSELDORF-Straße-sw-1.example.de",
+                "Zürich-Äußere-sw-2"
+            ]
+        );
+    }
+
+    #[test]
+    fn single_good_device() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netshot/single_good_device.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let devices = client.get_devices(1).unwrap().items;
+
+        assert_eq!(devices.len(), 1);
+
+        let device = devices.first().unwrap();
+
+        assert_eq!(device.name, "test-device");
+        assert_eq!(device.id, 1);
+        assert_eq!(device.management_address.ip, "1.2.3.4");
+    }
+
+    #[test]
+    fn html_login_page() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body_from_file("tests/data/sso_login_page.html")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let error = client.get_devices(1).unwrap_err().to_string();
+
+        assert!(error.starts_with("Expected JSON but received text/html; charset=utf-8 from"));
+        assert!(error.contains("token is likely invalid or an auth proxy intercepted the request"));
+        assert!(error.ends_with("<!DOCTYPE html>"));
+    }
+
+    #[test]
+    fn good_device_registration() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("POST", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .match_body(r#"{"autoDiscover":true,"ipAddress":"1.2.3.4","domainId":2}"#)
+            .with_body_from_file("tests/data/netshot/good_device_registration.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let registration = client
+            .register_device(String::from("1.2.3.4"), 2, "edge-1")
+            .unwrap();
+
+        assert_eq!(registration.task_id, 504);
+        assert_eq!(registration.status.as_deref(), Some("SCHEDULED"));
+    }
+
+    #[test]
+    fn driver_device_registration() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("POST", PATH_DEVICES)
+            .match_body(
+                r#"{"autoDiscover":false,"ipAddress":"1.2.3.4","domainId":2,"name":"edge-1","deviceType":"CiscoIOS12"}"#,
+            )
+            .with_body_from_file("tests/data/netshot/good_device_registration.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .driver(Some("CiscoIOS12".to_string()))
+            .build()
+            .unwrap();
+        let registration = client
+            .register_device(String::from("1.2.3.4"), 2, "edge-1")
+            .unwrap();
+
+        assert_eq!(registration.task_id, 504);
+    }
+
+    #[test]
+    fn credential_set_registration() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("POST", PATH_DEVICES)
+            .match_body(
+                r#"{"autoDiscover":true,"ipAddress":"1.2.3.6","domainId":2,"credentialSetIds":[7]}"#,
+            )
+            .with_body_from_file("tests/data/netshot/good_device_registration.json")
+            .create();
+        let _credential_sets = mockito::mock("GET", PATH_CREDENTIAL_SETS)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netshot/credential_sets.json")
+            .create();
+
+        let mut client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert_eq!(client.resolve_credential_set("Lab-SSH").unwrap(), 7);
+        let registration = client
+            .register_device(String::from("1.2.3.6"), 2, "edge-1")
+            .unwrap();
+        assert_eq!(registration.task_id, 504);
+
+        let client = NetshotClient::builder(url.clone())
+            .credential_set_id(Some(7))
+            .build()
+            .unwrap();
+        assert!(client
+            .register_device(String::from("1.2.3.6"), 2, "edge-1")
+            .is_ok());
+
+        let mut client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert!(client.resolve_credential_set("Unknown").is_err());
+        // Two sets share the name
+        assert!(client.resolve_credential_set("Core-SNMP").is_err());
+    }
+
+    #[test]
+    fn retried_registration() {
+        let url = mockito::server_url();
+
+        let unavailable = mockito::mock("POST", PATH_DEVICES)
+            .with_status(503)
+            .expect(2)
+            .create();
+        let registered = mockito::mock("POST", PATH_DEVICES)
+            .with_body_from_file("tests/data/netshot/good_device_registration.json")
+            .expect(1)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .retry(RetryPolicy::new(3, Duration::from_millis(1)))
+            .build()
+            .unwrap();
+        let registration = client
+            .register_device(String::from("1.2.3.4"), 2, "edge-1")
+            .unwrap();
+        assert_eq!(registration.task_id, 504);
+        unavailable.assert();
+        registered.assert();
+
+        // The service itself may have registered the device before failing
+        let failing = mockito::mock("POST", PATH_DEVICES)
+            .with_status(500)
+            .expect(1)
+            .create();
+        assert!(client
+            .register_device(String::from("1.2.3.4"), 2, "edge-1")
+            .is_err());
+        failing.assert();
+    }
+
+    #[test]
+    fn device_registration_task_attributes() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("POST", PATH_DEVICES)
+            .match_body(
+                r#"{"autoDiscover":true,"ipAddress":"1.2.3.4","domainId":2,"comments":"created by netbox2netshot run 42","priority":-5}"#,
+            )
+            .with_body_from_file("tests/data/netshot/good_device_registration.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .registration_task(
+                Some("created by netbox2netshot run 42".to_string()),
+                Some(-5),
+            )
+            .build()
+            .unwrap();
+        let registration = client
+            .register_device(String::from("1.2.3.4"), 2, "edge-1")
+            .unwrap();
+
+        assert_eq!(registration.task_id, 504);
+    }
+
+    #[test]
+    fn device_registration_timeout() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("POST", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .match_body(r#"{"autoDiscover":true,"ipAddress":"1.2.3.5","domainId":2}"#)
+            .with_body_from_fn(|_| {
+                std::thread::sleep(Duration::from_millis(500));
+                Ok(())
+            })
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .action_timeout(Some(Duration::from_millis(50)))
+            .build()
+            .unwrap();
+        let error = client
+            .register_device(String::from("1.2.3.5"), 2, "edge-2")
+            .unwrap_err();
+
+        assert!(is_timeout(&error));
+    }
+
+    #[test]
+    fn request_timeout() {
+        // A server accepting the connections but never answering
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let client = NetshotClient::builder(url)
+            .timeout(Some(Duration::from_millis(50)))
+            .build()
+            .unwrap();
+        let error = client.get_devices(1).unwrap_err();
+        assert!(is_timeout(&error));
+        assert!(
+            error
+                .to_string()
+                .starts_with("Netshot /api/devices timed out after 50ms"),
+            "{:#}",
+            error
+        );
+
+        // Without a limit, the client waits for a slow answer
+        let _mock = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_fn(|body| {
+                std::thread::sleep(Duration::from_millis(100));
+                body.write_all(b"[]")
+            })
+            .create();
+        let client = NetshotClient::builder(mockito::server_url())
+            .timeout(None)
+            .build()
+            .unwrap();
+        assert!(client.get_devices(1).unwrap().items.is_empty());
+    }
+
+    #[test]
+    fn session_login() {
+        let url = mockito::server_url();
+
+        let login = mockito::mock("POST", PATH_USER)
+            .match_body(r#"{"username":"sync","password":"secret"}"#)
+            .with_header("Set-Cookie", "JSESSIONID=first; Path=/")
+            .with_body(r#"{"id": 3, "username": "sync", "level": 100}"#)
+            .create();
+        let devices = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .match_header("Cookie", "JSESSIONID=first")
+            .match_header("X-Netshot-API-Token", mockito::Matcher::Missing)
+            .with_body_from_file("tests/data/netshot/single_good_device.json")
+            .create();
+        let client = NetshotClient::builder(url.clone())
+            .login(Some(Login::new("sync", "secret")))
+            .build()
+            .unwrap();
+        assert_eq!(client.get_devices(1).unwrap().items.len(), 1);
+        login.assert();
+        devices.assert();
+        drop(login);
+
+        // The expired session is opened again once, then the request is sent again
+        let relogin = mockito::mock("POST", PATH_USER)
+            .match_body(r#"{"username":"sync","password":"secret"}"#)
+            .with_header("Set-Cookie", "JSESSIONID=second; Path=/")
+            .with_body(r#"{"id": 3, "username": "sync", "level": 100}"#)
+            .create();
+        let _expired = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .match_header("Cookie", "JSESSIONID=first")
+            .with_status(401)
+            .create();
+        let renewed = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .match_header("Cookie", "JSESSIONID=second")
+            .with_body_from_file("tests/data/netshot/single_good_device.json")
+            .create();
+        assert_eq!(client.get_devices(1).unwrap().items.len(), 1);
+        relogin.assert();
+        renewed.assert();
+        drop(relogin);
+
+        let _refused = mockito::mock("POST", PATH_USER)
+            .with_status(401)
+            .with_body(r#"{"errorCode": 1, "errorMsg": "Invalid credentials"}"#)
+            .create();
+        let error = NetshotClient::builder(url.clone())
+            .login(Some(Login::new("sync", "wrong")))
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Unable to log in to Netshot {} as sync, got status 401 Unauthorized: Invalid credentials (error code 1)",
+                url
+            )
+        );
+    }
+
+    #[test]
+    fn successful_ping() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_USER)
+            .match_header("X-Netshot-API-Token", "secret")
+            .with_body(r#"{"id":2,"username":"netbox2netshot","level":100}"#)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .token("secret")
+            .build()
+            .unwrap();
+        assert!(client.ping().unwrap());
+    }
+
+    #[test]
+    fn failed_ping() {
+        let url = mockito::server_url();
+
+        let refused = mockito::mock("GET", PATH_USER).with_status(401).create();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let error = client.ping().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Authentication to Netshot {} failed, check the token", url)
+        );
+        assert!(!crate::rest::helpers::is_unreachable(&error));
+        assert_eq!(
+            error
+                .root_cause()
+                .downcast_ref::<crate::rest::helpers::StatusError>()
+                .unwrap()
+                .to_string(),
+            "Netshot answered 401 on /api/user"
+        );
+        drop(refused);
+
+        let _broken = mockito::mock("GET", PATH_USER).with_status(500).create();
+        assert!(!client.ping().unwrap());
+    }
+
+    #[test]
+    fn unreachable_ping() {
+        let client = NetshotClient::builder("http://127.0.0.1:1")
+            .build()
+            .unwrap();
+        let error = client.ping().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unable to reach Netshot at http://127.0.0.1:1"
+        );
+        assert!(crate::rest::helpers::is_unreachable(&error));
+    }
+
+    #[test]
+    fn write_permission() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_USER)
+            .with_body(r#"{"id":2,"username":"netbox2netshot","level":100}"#)
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert!(client.check_write_permission().is_ok());
+    }
+
+    #[test]
+    fn read_only_permission() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_USER)
+            .with_body(r#"{"id":3,"username":"auditor","level":10}"#)
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let error = client.check_write_permission().unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Netshot token appears read-only"));
+    }
+
+    #[test]
+    fn invalid_token_permission() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_USER)
+            .match_header("X-Netshot-API-Token", "expired")
+            .with_status(401)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .token("expired")
+            .build()
+            .unwrap();
+        let error = client.check_write_permission().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Authentication to Netshot {} failed, check the token", url)
+        );
+    }
+
+    #[test]
+    fn search_devices() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_query(mockito::Matcher::Any)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let result = client
+            .search_device(&SearchQuery::ip("1.2.3.4").unwrap())
+            .unwrap();
+
+        assert_eq!(result.devices.len(), 2);
+        assert_eq!(result.query, "[IP] IS 1.2.3.4");
+    }
+
+    #[test]
+    fn error_bodies() {
+        let url = mockito::server_url();
+
+        let _register = mockito::mock("POST", PATH_DEVICES)
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"ipAddress":"10.1.2.3"}"#.to_string(),
+            ))
+            .with_status(400)
+            .with_body(r#"{"errorMsg": "No such domain", "errorCode": 20}"#)
+            .create();
+        let _search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 10.1.2.4"}"#)
+            .with_status(502)
+            .with_body(format!("<html>{}</html>", "Bad Gateway ".repeat(50)))
+            .create();
+        let _empty = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 10.1.2.5"}"#)
+            .with_status(500)
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let error = client
+            .register_device(String::from("10.1.2.3"), 99, "edge-1")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to register new device 10.1.2.3, got status 400 Bad Request: No such domain (error code 20)"
+        );
+
+        // The raw bodies are truncated
+        let error = client
+            .search_device(&SearchQuery::ip("10.1.2.4").unwrap())
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with(
+            "Failed to search for device with query `[IP] IS 10.1.2.4`, got status 502 Bad Gateway: <html>Bad Gateway Bad"
+        ));
+        let (_, body) = error.split_once(": ").unwrap();
+        assert_eq!(body.len(), ERROR_BODY_LIMIT + "...".len());
+        assert!(body.ends_with("Gateway Ba..."));
+
+        let error = client
+            .search_device(&SearchQuery::ip("10.1.2.5").unwrap())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to search for device with query `[IP] IS 10.1.2.5`, got status 500 Internal Server Error"
+        );
+    }
+
+    #[test]
+    fn disable_device() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .match_query(mockito::Matcher::Any)
+            .match_body(r#"{"enabled":false}"#)
+            .with_body_from_file("tests/data/netshot/disable_device.json")
+            .create();
+
+        let _mock2 = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_query(mockito::Matcher::Any)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let registration = client.disable_device(String::from("1.2.3.4")).unwrap();
+
+        assert_eq!(registration.unwrap().status.as_deref(), Some("DISABLED"));
+    }
+
+    #[test]
+    fn disable_device_by_id() {
+        let url = mockito::server_url();
+
+        let update = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2330).as_str())
+            .match_body(r#"{"enabled":false}"#)
+            .with_body(r#"{"id": 2330, "name": "edge-1", "status": "DISABLED"}"#)
+            .create();
+        let search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_query(mockito::Matcher::Any)
+            .expect(0)
+            .create();
+
+        // The fetched devices are updated with their ID and status, without searching for them
+        let devices: Vec<Device> = serde_json::from_str(
+            r#"[
+                {"id": 2330, "name": "edge-1", "mgmtAddress": {"ip": "10.0.3.1"}, "status": "INPRODUCTION"},
+                {"id": 2331, "name": "edge-2", "mgmtAddress": {"ip": "10.0.3.2"}, "status": "DISABLED"}
+            ]"#,
+        )
+        .unwrap();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let registration = client.disable_device_by_id(&devices[0]).unwrap();
+        assert_eq!(registration.unwrap().status.as_deref(), Some("DISABLED"));
+        assert!(client.disable_device_by_id(&devices[1]).unwrap().is_none());
+        update.assert();
+        search.assert();
+    }
+
+    #[test]
+    fn find_device() {
+        use crate::rest::api::NetshotApi;
+
+        let url = mockito::server_url();
+
+        let _search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 10.0.5.1"}"#)
+            .with_body(
+                r#"{"query": "[IP] IS 10.0.5.1", "devices": [
+                    {"id": 2340, "name": "core-1", "mgmtAddress": {"ip": "10.0.5.254"}, "status": "INPRODUCTION"},
+                    {"id": 2341, "name": "edge-1", "mgmtAddress": {"ip": "10.0.5.1"}, "status": "INPRODUCTION"}
+                ]}"#,
+            )
+            .create();
+        let _missing = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 10.0.5.2"}"#)
+            .with_body(
+                r#"{"query": "[IP] IS 10.0.5.2", "devices": [
+                    {"id": 2340, "name": "core-1", "mgmtAddress": {"ip": "10.0.5.254"}, "status": "INPRODUCTION"}
+                ]}"#,
+            )
+            .create();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert_eq!(client.find_device("10.0.5.1").unwrap().unwrap().id, 2341);
+        assert!(client.find_device("10.0.5.2").unwrap().is_none());
+    }
+
+    #[test]
+    fn vanished_device() {
+        let url = mockito::server_url();
+
+        // Removed from Netshot since the devices were listed
+        let _search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.9"}"#)
+            .with_body(r#"{"query": "[IP] IS 1.2.3.9", "devices": []}"#)
+            .create();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let error = client.disable_device(String::from("1.2.3.9")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No device found on Netshot with IP 1.2.3.9"
+        );
+
+        let search: DeviceSearchResultPayload = serde_json::from_value(serde_json::json!({
+            "devices": [
+                {"id": 1, "name": "edge-10", "mgmtAddress": {"ip": "1.2.3.10"}},
+                {"id": 2, "name": "edge-1", "mgmtAddress": {"ip": "1.2.3.1"}},
+                {"id": 3, "name": "edge-1-copy", "mgmtAddress": {"ip": "1.2.3.1"}}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(search.device_with_ip("1.2.3.1").unwrap().id, 2);
+        assert_eq!(search.device_with_ip("1.2.3.10").unwrap().id, 1);
+        assert!(search.device_with_ip("1.2.3.100").is_err());
+    }
+
+    #[test]
+    fn device_dates() {
+        let device: Device = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "name": "edge-1",
+            "mgmtAddress": {"ip": "10.0.0.1"},
+            "status": "DISABLED",
+            "createdDate": 1619787406000i64,
+            "changeDate": null
+        }))
+        .unwrap();
+        assert_eq!(
+            device.last_change().unwrap().to_rfc3339(),
+            "2021-04-30T12:56:46+00:00"
+        );
+        assert_eq!(device.change_date, None);
+
+        // Written back as text to the inventory files
+        let written = serde_json::to_value(&device).unwrap();
+        assert_eq!(written["createdDate"], "2021-04-30T12:56:46Z");
+        assert_eq!(serde_json::from_value::<Device>(written).unwrap(), device);
+    }
+
+    #[test]
+    fn device_statuses() {
+        assert_eq!(
+            "preproduction".parse::<DeviceStatus>().unwrap(),
+            DeviceStatus::PreProduction
+        );
+        assert_eq!(
+            serde_json::to_value(DeviceStatus::InProduction).unwrap(),
+            "INPRODUCTION"
+        );
+        assert_eq!(
+            serde_json::from_value::<DeviceStatus>(serde_json::json!("DISABLED")).unwrap(),
+            DeviceStatus::Disabled
+        );
+        assert!("RETIRED".parse::<DeviceStatus>().is_err());
+        assert_eq!(
+            DeviceStatus::parse_settable("disabled").unwrap(),
+            DeviceStatus::Disabled
+        );
+        assert!(DeviceStatus::parse_settable("PREPRODUCTION").is_err());
+        assert!(DeviceStatus::Disabled.is(Some("DISABLED")));
+        assert!(!DeviceStatus::Disabled.is(None));
+    }
+
+    #[test]
+    fn preproduction_enable() {
+        let url = mockito::server_url();
+
+        let _search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 10.0.2.2"}"#)
+            .with_body(
+                r#"{"devices": [{"id": 2, "name": "edge-1", "mgmtAddress": {"ip": "10.0.2.2"}, "status": "PREPRODUCTION"}]}"#,
+            )
+            .create();
+        let _in_production = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 10.0.2.1"}"#)
+            .with_body(
+                r#"{"devices": [{"id": 1, "name": "core-1", "mgmtAddress": {"ip": "10.0.2.1"}, "status": "INPRODUCTION"}]}"#,
+            )
+            .create();
+        let update = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2).as_str())
+            .match_body(r#"{"enabled":true}"#)
+            .with_body(r#"{"id": 2, "name": "edge-1", "status": "INPRODUCTION"}"#)
+            .create();
+
+        // Only the devices in production are already enabled
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert!(client
+            .enable_device(String::from("10.0.2.2"))
+            .unwrap()
+            .is_some());
+        assert!(client
+            .enable_device(String::from("10.0.2.1"))
+            .unwrap()
+            .is_none());
+        update.assert();
+    }
+
+    #[test]
+    fn rejected_device_updates() {
+        let url = mockito::server_url();
+
+        let _search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .create();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+
+        // The update "succeeds" but the device is echoed back in its previous status
+        let update = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .match_body(r#"{"enabled":false}"#)
+            .with_body(r#"{"id": 2318, "name": "test-device", "status": "INPRODUCTION"}"#)
+            .create();
+        let error = client.disable_device(String::from("1.2.3.4")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Device 1.2.3.4 is still INPRODUCTION after the update to enabled=false"
+        );
+        drop(update);
+
+        let _update = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .match_body(r#"{"enabled":false}"#)
+            .with_status(400)
+            .with_body(r#"{"errorCode": 42, "errorMsg": "The device is locked by a running task"}"#)
+            .create();
+        let error = client.disable_device(String::from("1.2.3.4")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to update state for device 1.2.3.4, got status 400 Bad Request: The device is locked by a running task (error code 42)"
+        );
+    }
+
+    #[test]
+    fn device_credential_sets() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .with_body_from_file("tests/data/netshot/device_details.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let credential_set_ids = client.get_device_credential_set_ids(2318).unwrap();
+
+        assert_eq!(credential_set_ids, vec![3, 7]);
+        assert_eq!(
+            client.get_device_comments(2318).unwrap().as_deref(),
+            Some("Core switch\nNetbox site: PAR1")
+        );
+    }
+
+    #[test]
+    fn update_credential_sets() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .match_body(r#"{"credentialSetIds":[9]}"#)
+            .with_body_from_file("tests/data/netshot/disable_device.json")
+            .create();
+
+        let _mock2 = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let update = client.set_device_credential_sets(String::from("1.2.3.4"), &[9]);
+
+        assert!(update.is_ok());
+    }
+
+    #[test]
+    fn update_comments() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .match_body(r#"{"comments":"Core switch\nNetbox site: PAR2"}"#)
+            .with_body_from_file("tests/data/netshot/disable_device.json")
+            .create();
+
+        let _mock2 = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let update = client
+            .update_device_comments(String::from("1.2.3.4"), "Core switch\nNetbox site: PAR2");
+
+        assert!(update.is_ok());
+    }
+
+    #[test]
+    fn update_prefix_length() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .match_body(r#"{"ipAddress":"1.2.3.4/24"}"#)
+            .with_body_from_file("tests/data/netshot/disable_device.json")
+            .create();
+
+        let _mock2 = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let update = client.set_device_prefix_length(String::from("1.2.3.4"), 24);
+
+        assert!(update.is_ok());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use mockito;
+    #[test]
+    fn update_ip() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2319).as_str())
+            .match_body(r#"{"ipAddress":"10.0.0.9"}"#)
+            .with_body_from_file("tests/data/netshot/disable_device.json")
+            .create();
+        let _refused = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2320).as_str())
+            .with_status(400)
+            .with_body(r#"{"errorCode": 30, "errorMsg": "A device already exists with this IP."}"#)
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert!(client
+            .update_device_ip(2319, String::from("10.0.0.9"))
+            .is_ok());
+        assert!(client
+            .update_device_ip(2320, String::from("10.0.0.9"))
+            .is_err());
+    }
 
     #[test]
-    fn authenticated_initialization() {
+    fn rename() {
         let url = mockito::server_url();
-        let token = String::from("hello");
-        let client = NetshotClient::new(url.clone(), token.clone(), None, None, None).unwrap();
-        assert_eq!(client.token, token);
-        assert_eq!(client.url, url);
+
+        let _mock = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2321).as_str())
+            .match_body(r#"{"name":"edge-2"}"#)
+            .with_body_from_file("tests/data/netshot/disable_device.json")
+            .create();
+        let _refused = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2322).as_str())
+            .with_status(400)
+            .with_body(r#"{"errorCode": 31, "errorMsg": "Invalid device name."}"#)
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert!(client.rename_device(2321, String::from("edge-2")).is_ok());
+        let error = client
+            .rename_device(2322, String::from("edge 2"))
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Invalid device name. (error code 31)"));
     }
 
     #[test]
-    fn single_good_device() {
+    fn static_group_membership() {
         let url = mockito::server_url();
 
-        let _mock = mockito::mock("GET", PATH_DEVICES)
+        let _groups = mockito::mock("GET", PATH_GROUPS)
             .match_query(mockito::Matcher::Any)
-            .with_body_from_file("tests/data/netshot/single_good_device.json")
+            .with_body_from_file("tests/data/netshot/static_groups.json")
+            .create();
+        let _search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .create();
+        let update = mockito::mock("PUT", format!("{}/{}", PATH_GROUPS, 9).as_str())
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"name":"Quarantine","type":"StaticDeviceGroup","staticDevices":[1,2318]}"#
+                    .to_string(),
+            ))
+            .with_body("{}")
+            .expect(1)
             .create();
 
-        let client = NetshotClient::new(url.clone(), String::new(), None, None, None).unwrap();
-        let devices = client.get_devices(1).unwrap();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert!(client
+            .set_group_membership("Quarantine", String::from("1.2.3.4"), true)
+            .unwrap());
+        // Already a member, nothing to update
+        assert!(!client
+            .set_group_membership("Quarantine", String::from("1.2.3.4"), true)
+            .unwrap());
+        assert!(client
+            .set_group_membership("Distribution", String::from("1.2.3.4"), true)
+            .is_err());
+        update.assert();
+    }
 
-        assert_eq!(devices.len(), 1);
+    #[test]
+    fn group_by_id() {
+        let url = mockito::server_url();
 
-        let device = devices.first().unwrap();
+        let _groups = mockito::mock("GET", PATH_GROUPS)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netshot/static_groups.json")
+            .create();
+        let update = mockito::mock("PUT", format!("{}/{}", PATH_GROUPS, 9).as_str())
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"name":"Quarantine","type":"StaticDeviceGroup","staticDevices":[1,2320]}"#
+                    .to_string(),
+            ))
+            .with_body("{}")
+            .expect(1)
+            .create();
 
-        assert_eq!(device.name, "test-device");
-        assert_eq!(device.id, 1 as u32);
-        assert_eq!(device.management_address.ip, "1.2.3.4");
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        assert!(client.add_device_to_group(2320, 9).unwrap());
+        assert!(!client.add_device_to_group(2320, 9).unwrap());
+        assert!(!client.add_device_to_group(1, 9).unwrap());
+        let error = client.add_device_to_group(2320, 2).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The Netshot group with ID 2 is not static"
+        );
+        assert!(client.add_device_to_group(2320, 404).is_err());
+        update.assert();
     }
 
     #[test]
-    fn good_device_registration() {
+    fn managed_groups() {
         let url = mockito::server_url();
 
-        let _mock = mockito::mock("POST", PATH_DEVICES)
+        let groups = mockito::mock("GET", PATH_GROUPS)
             .match_query(mockito::Matcher::Any)
-            .match_body(r#"{"autoDiscover":true,"ipAddress":"1.2.3.4","domainId":2}"#)
-            .with_body_from_file("tests/data/netshot/good_device_registration.json")
+            .with_body_from_file("tests/data/netshot/managed_groups.json")
+            .expect(2)
+            .create();
+        let deletion = mockito::mock("DELETE", format!("{}/{}", PATH_GROUPS, 11).as_str())
+            .with_status(204)
+            .expect(1)
             .create();
 
-        let client = NetshotClient::new(url.clone(), String::new(), None, None, None).unwrap();
-        let registration = client.register_device(String::from("1.2.3.4"), 2).unwrap();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let managed: Vec<(String, bool)> = client
+            .get_groups()
+            .unwrap()
+            .into_iter()
+            .filter(|group| group.is_managed())
+            .map(|group| (group.name.clone(), group.is_empty()))
+            .collect();
+        assert_eq!(
+            managed,
+            vec![
+                ("Quarantine".to_string(), false),
+                ("Site PAR2".to_string(), true),
+                ("Distribution".to_string(), false)
+            ]
+        );
 
-        assert_eq!(registration.task_id, 504);
-        assert_eq!(registration.status, "SCHEDULED");
+        let groups_list = client.refresh_groups().unwrap();
+        client.delete_group(&groups_list[2]).unwrap();
+        assert_eq!(client.get_groups().unwrap().len(), 3);
+        groups.assert();
+        deletion.assert();
     }
 
     #[test]
-    fn search_devices() {
+    fn delete_device() {
         let url = mockito::server_url();
 
-        let _mock = mockito::mock("POST", PATH_DEVICES_SEARCH)
-            .match_query(mockito::Matcher::Any)
+        let deletion = mockito::mock("DELETE", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .with_status(204)
+            .create();
+        let _search = mockito::mock("POST", PATH_DEVICES_SEARCH)
             .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
             .with_body_from_file("tests/data/netshot/search.json")
             .create();
 
-        let client = NetshotClient::new(url.clone(), String::new(), None, None, None).unwrap();
-        let result = client
-            .search_device(String::from("[IP] IS 1.2.3.4"))
-            .unwrap();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        client.delete_device(String::from("1.2.3.4")).unwrap();
+        deletion.assert();
+    }
 
-        assert_eq!(result.devices.len(), 2);
-        assert_eq!(result.query, "[IP] IS 1.2.3.4");
+    #[test]
+    fn read_only_client() {
+        let url = mockito::server_url();
+
+        let registration = mockito::mock("POST", PATH_DEVICES).expect(0).create();
+        let _search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .create();
+        let update = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+            .expect(0)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .read_only(true)
+            .build()
+            .unwrap();
+        let error = client
+            .register_device(String::from("1.2.3.4"), 2, "edge-1")
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ReadOnlyViolation>(),
+            Some(&ReadOnlyViolation {
+                service: "Netshot",
+                method: "POST".to_string(),
+                endpoint: PATH_DEVICES.to_string(),
+            })
+        );
+        // The search goes through, the update it leads to does not
+        let error = client.disable_device(String::from("1.2.3.4")).unwrap_err();
+        assert!(error.is::<ReadOnlyViolation>());
+        registration.assert();
+        update.assert();
     }
 
     #[test]
-    fn disable_device() {
+    fn batch_disable_devices() {
         let url = mockito::server_url();
 
-        let _mock = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
+        let batch_search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_query(mockito::Matcher::Any)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.4 OR [IP] IS 1.2.3.9"}"#)
+            .with_body_from_file("tests/data/netshot/search.json")
+            .expect(1)
+            .create();
+        let single_search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_query(mockito::Matcher::Any)
+            .match_body(r#"{"query":"[IP] IS 1.2.3.9"}"#)
+            .with_body(r#"{"query":"[IP] IS 1.2.3.9","devices":[]}"#)
+            .expect(1)
+            .create();
+        let update = mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, 2318).as_str())
             .match_query(mockito::Matcher::Any)
             .match_body(r#"{"enabled":false}"#)
             .with_body_from_file("tests/data/netshot/disable_device.json")
+            .expect(1)
             .create();
 
-        let _mock2 = mockito::mock("POST", PATH_DEVICES_SEARCH)
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let outcomes = client.set_devices_enabled(
+            &[String::from("1.2.3.4"), String::from("1.2.3.9")],
+            false,
+            50,
+        );
+
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].0, "1.2.3.4");
+        assert!(outcomes[0].2.as_ref().unwrap().is_some());
+        assert_eq!(outcomes[1].0, "1.2.3.9");
+        assert!(outcomes[1].2.is_err());
+        batch_search.assert();
+        single_search.assert();
+        update.assert();
+    }
+
+    #[test]
+    fn batch_disable_ipv6_devices() {
+        let url = mockito::server_url();
+
+        let batch_search = mockito::mock("POST", PATH_DEVICES_SEARCH)
             .match_query(mockito::Matcher::Any)
-            .match_body(r#"{"query":"[IP] IS 1.2.3.4"}"#)
-            .with_body_from_file("tests/data/netshot/search.json")
+            .match_body(r#"{"query":"[IP] IS 2001:db8::5 OR [IP] IS 2001:db8::6"}"#)
+            .with_body(
+                r#"{"query":"[IP] IS 2001:db8::5 OR [IP] IS 2001:db8::6","devices":[
+                    {"id":2325,"name":"edge-5","mgmtAddress":{"ip":"2001:0db8:0000:0000:0000:0000:0000:0005"},"status":"INPRODUCTION"},
+                    {"id":2326,"name":"edge-6","mgmtAddress":{"ip":"2001:0db8:0000:0000:0000:0000:0000:0006"},"status":"INPRODUCTION"}
+                ]}"#,
+            )
+            .expect(1)
+            .create();
+        let single_search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Regex(
+                r#"^\{"query":"\[IP\] IS 2001:db8::[56]"\}$"#.to_string(),
+            ))
+            .expect(0)
             .create();
+        let updates: Vec<mockito::Mock> = [2325, 2326]
+            .iter()
+            .map(|id| {
+                mockito::mock("PUT", format!("{}/{}", PATH_DEVICES, id).as_str())
+                    .match_query(mockito::Matcher::Any)
+                    .match_body(r#"{"enabled":false}"#)
+                    .with_body_from_file("tests/data/netshot/disable_device.json")
+                    .expect(1)
+                    .create()
+            })
+            .collect();
 
-        let client = NetshotClient::new(url.clone(), String::new(), None, None, None).unwrap();
-        let registration = client.disable_device(String::from("1.2.3.4")).unwrap();
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let outcomes = client.set_devices_enabled(
+            &[String::from("2001:db8::5"), String::from("2001:db8::6")],
+            false,
+            50,
+        );
+
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes
+            .iter()
+            .all(|(_, _, outcome)| outcome.as_ref().unwrap().is_some()));
+        batch_search.assert();
+        single_search.assert();
+        for update in updates {
+            update.assert();
+        }
+    }
+
+    #[test]
+    fn device_addresses() {
+        let url = mockito::server_url();
+
+        let mock = mockito::mock(
+            "GET",
+            format!("{}/{}{}", PATH_DEVICES, 1, PATH_DEVICE_INTERFACES).as_str(),
+        )
+        .with_body_from_file("tests/data/netshot/device_interfaces.json")
+        .expect(1)
+        .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let addresses = client.get_device_addresses(1).unwrap();
+        let cached_addresses = client.get_device_addresses(1).unwrap();
+
+        assert_eq!(addresses, vec!["10.0.0.1", "192.168.0.1", "2001:db8::1"]);
+        assert_eq!(addresses, cached_addresses);
+        mock.assert();
+    }
+
+    #[test]
+    fn search_compatibility() {
+        let url = mockito::server_url();
+        let client = NetshotClient::builder(url.clone())
+            .token("compat-search")
+            .build()
+            .unwrap();
+        for version in ["0.15", "0.16", "0.18", "0.21"] {
+            let devices = std::fs::read_to_string(format!(
+                "tests/data/netshot/compat/{}/devices.json",
+                version
+            ))
+            .unwrap();
+            let search = mockito::mock("POST", PATH_DEVICES_SEARCH)
+                .match_header("X-Netshot-API-Token", "compat-search")
+                .with_body(format!(
+                    r#"{{"query": "[IP] IS 1.2.3.4", "devices": {}}}"#,
+                    devices
+                ))
+                .create();
+            let result = client
+                .search_device(&SearchQuery::ip("1.2.3.4").unwrap())
+                .unwrap();
+            let device = result.device_with_ip("1.2.3.4").unwrap();
+            assert_eq!(device.id, 1, "Netshot {}", version);
+            assert!(!device.is_disabled(), "Netshot {}", version);
+            drop(search);
+        }
+    }
+
+    #[test]
+    fn devices_compatibility() {
+        for version in ["0.15", "0.16", "0.18", "0.21"] {
+            let body = std::fs::read_to_string(format!(
+                "tests/data/netshot/compat/{}/devices.json",
+                version
+            ))
+            .unwrap();
+            let devices = parse_devices(&body, false).unwrap();
+
+            assert_eq!(devices.len(), 2, "Netshot {}", version);
+            assert_eq!(devices[0].id, 1, "Netshot {}", version);
+            assert_eq!(devices[0].name, "test-device", "Netshot {}", version);
+            assert_eq!(
+                devices[0].management_address.ip, "1.2.3.4",
+                "Netshot {}",
+                version
+            );
+            assert!(!devices[0].is_disabled(), "Netshot {}", version);
+            assert_eq!(
+                devices[1].management_address.ip, "1.2.3.5",
+                "Netshot {}",
+                version
+            );
+            assert!(devices[1].is_disabled(), "Netshot {}", version);
+        }
+
+        let body = std::fs::read_to_string("tests/data/netshot/compat/0.21/devices.json").unwrap();
+        let devices = parse_devices(&body, false).unwrap();
+
+        assert_eq!(devices[0].driver.as_deref(), Some("CiscoNXOS"));
+        assert_eq!(devices[0].software_version.as_deref(), Some("10.2(5)"));
+        assert_eq!(devices[0].domain.as_ref().unwrap().id, 1);
+    }
+
+    #[test]
+    fn paginated_devices() {
+        let url = mockito::server_url();
+
+        let first_page = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("offset".into(), "0".into()),
+                mockito::Matcher::UrlEncoded("limit".into(), "2".into()),
+                mockito::Matcher::UrlEncoded("group".into(), "4".into()),
+            ]))
+            .with_body_from_file("tests/data/netshot/devices_page_1.json")
+            .expect(1)
+            .create();
+        let second_page = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("offset".into(), "2".into()),
+                mockito::Matcher::UrlEncoded("limit".into(), "2".into()),
+                mockito::Matcher::UrlEncoded("group".into(), "4".into()),
+            ]))
+            .with_body_from_file("tests/data/netshot/devices_page_2.json")
+            .expect(1)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .page_size(2)
+            .build()
+            .unwrap();
+        let fetched = client.get_devices(4).unwrap();
+
+        first_page.assert();
+        second_page.assert();
+        assert_eq!(fetched.items.len(), 3);
+        assert_eq!(fetched.snapshot.pages, 2);
+        assert_eq!(fetched.items[2].name, "core-1");
+    }
+
+    #[test]
+    fn unpaginated_devices() {
+        let url = mockito::server_url();
+
+        // The releases without paging on the devices ignore the limit and send them all at once
+        let devices = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netshot/devices_page_1.json")
+            .expect(1)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .page_size(1)
+            .build()
+            .unwrap();
+        assert_eq!(client.get_devices(4).unwrap().items.len(), 2);
+        devices.assert();
+    }
+
+    #[test]
+    fn paginated_groups() {
+        let url = mockito::server_url();
+
+        let first_page = mockito::mock("GET", PATH_GROUPS)
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("offset".into(), "0".into()),
+                mockito::Matcher::UrlEncoded("limit".into(), "2".into()),
+            ]))
+            .with_body_from_file("tests/data/netshot/groups_page_1.json")
+            .expect(1)
+            .create();
+        let second_page = mockito::mock("GET", PATH_GROUPS)
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("offset".into(), "2".into()),
+                mockito::Matcher::UrlEncoded("limit".into(), "2".into()),
+            ]))
+            .with_body_from_file("tests/data/netshot/groups_page_2.json")
+            .expect(1)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .page_size(2)
+            .build()
+            .unwrap();
+        let groups = client.get_groups().unwrap();
+
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].name, "Core");
+        assert_eq!(groups[2].name, "Access");
+        assert_eq!(groups[2].folder.as_deref(), Some("Sites/PAR1"));
+
+        // The complete set is served from the cache afterwards
+        assert_eq!(client.get_groups().unwrap().len(), 3);
+        first_page.assert();
+        second_page.assert();
+    }
+
+    #[test]
+    fn pending_discoveries() {
+        let url = mockito::server_url();
+
+        let scheduled = mockito::mock("GET", PATH_TASKS)
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("status".into(), "SCHEDULED".into()),
+                mockito::Matcher::UrlEncoded("offset".into(), "0".into()),
+            ]))
+            .with_body(
+                r#"[{"id": 1, "status": "SCHEDULED", "type": "DiscoverDeviceTypeTask"},
+                    {"id": 2, "status": "SCHEDULED", "type": "TakeSnapshotTask"}]"#,
+            )
+            .expect(1)
+            .create();
+        let scheduled_end = mockito::mock("GET", PATH_TASKS)
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("status".into(), "SCHEDULED".into()),
+                mockito::Matcher::UrlEncoded("offset".into(), "2".into()),
+            ]))
+            .with_body(r#"[{"id": 3, "status": "SCHEDULED", "type": "DiscoverDeviceTypeTask"}]"#)
+            .expect(1)
+            .create();
+        let _running = mockito::mock("GET", PATH_TASKS)
+            .match_query(mockito::Matcher::UrlEncoded(
+                "status".into(),
+                "RUNNING".into(),
+            ))
+            .with_body(r#"[{"id": 4, "status": "RUNNING", "type": "DiscoverDeviceTypeTask"}]"#)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .page_size(2)
+            .build()
+            .unwrap();
+
+        assert_eq!(client.count_pending_discoveries().unwrap(), 3);
+        scheduled.assert();
+        scheduled_end.assert();
+    }
+
+    #[test]
+    fn failed_discovery_task() {
+        let url = mockito::server_url();
+
+        let _task = mockito::mock("GET", "/api/tasks/4242")
+            .with_body(
+                r#"{"id": 4242, "status": "FAILURE", "type": "DiscoverDeviceTypeTask",
+                    "log": "Starting discovery\nNo driver matches the device\n\n"}"#,
+            )
+            .create();
+        let _done = mockito::mock("GET", "/api/tasks/4243")
+            .with_body(r#"{"id": 4243, "status": "SUCCESS", "log": "Device created"}"#)
+            .create();
+        let _cancelled = mockito::mock("GET", "/api/tasks/4244")
+            .with_body_from_file("tests/data/netshot/task_cancelled.json")
+            .create();
+
+        let client = NetshotClient::builder(url.clone()).build().unwrap();
+        let task = client.get_task(4242).unwrap();
+        assert_eq!(task.status, "FAILURE");
+        assert_eq!(
+            task.failure_reason().as_deref(),
+            Some("No driver matches the device")
+        );
+        assert_eq!(task.discovery_failure(), Some(DiscoveryFailure::Driver));
+        assert_eq!(client.get_task(4243).unwrap().failure_reason(), None);
+        assert_eq!(client.get_task(4243).unwrap().discovery_failure(), None);
+        assert!(task.is_finished());
+
+        let cancelled = client.get_task(4244).unwrap();
+        assert!(cancelled.is_finished());
+        assert_eq!(cancelled.failure_reason(), None);
+        let scheduled: Task =
+            serde_json::from_str(r#"{"id": 4245, "status": "SCHEDULED"}"#).unwrap();
+        assert!(!scheduled.is_finished());
+    }
+
+    #[test]
+    fn discovery_failures() {
+        for (log, failure) in [
+            (
+                "No driver could be found for this device",
+                DiscoveryFailure::Driver,
+            ),
+            (
+                "Unable to find an appropriate driver for device 10.0.0.1",
+                DiscoveryFailure::Driver,
+            ),
+            (
+                "SSH: Authentication failed with credential set Lab-SSH",
+                DiscoveryFailure::Credentials,
+            ),
+            ("Telnet: Permission denied", DiscoveryFailure::Credentials),
+            (
+                "SNMP: Timeout while waiting for the response",
+                DiscoveryFailure::Timeout,
+            ),
+            ("Connection timed out", DiscoveryFailure::Timeout),
+            ("Host unreachable", DiscoveryFailure::Timeout),
+            ("java.lang.NullPointerException", DiscoveryFailure::Unknown),
+            ("", DiscoveryFailure::Unknown),
+        ] {
+            assert_eq!(DiscoveryFailure::classify(log), failure, "{}", log);
+        }
+        assert!(DiscoveryFailure::Driver.is_permanent());
+        assert!(DiscoveryFailure::Credentials.is_permanent());
+        assert!(!DiscoveryFailure::Timeout.is_permanent());
+        assert!(!DiscoveryFailure::Unknown.is_permanent());
+    }
+
+    #[test]
+    fn non_advancing_pagination() {
+        let url = mockito::server_url();
 
-        assert_eq!(registration.unwrap().status, "DISABLED");
+        // The server ignores the offset and sends its full list every time
+        let domains = mockito::mock("GET", PATH_DOMAINS)
+            .match_query(mockito::Matcher::Any)
+            .with_body(r#"[{"id":1,"name":"Default"},{"id":2,"name":"PAR1"}]"#)
+            .expect(2)
+            .create();
+        let devices = mockito::mock("GET", PATH_DEVICES)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/data/netshot/mgmt_address_string.json")
+            .expect(2)
+            .create();
+
+        let client = NetshotClient::builder(url.clone())
+            .page_size(2)
+            .build()
+            .unwrap();
+        let names: Vec<String> = client
+            .get_domains()
+            .unwrap()
+            .into_iter()
+            .map(|domain| domain.name)
+            .collect();
+        assert_eq!(names, vec!["Default", "PAR1"]);
+        domains.assert();
+
+        let mut pages = Vec::new();
+        client
+            .for_each_devices_page(None, |page| {
+                pages.push(page.len());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(pages, vec![2]);
+        devices.assert();
+    }
+
+    #[test]
+    fn management_address_forms() {
+        // Whatever the detected release, e.g. while migrating between two Netshot servers
+        for form in ["string", "object"] {
+            let body =
+                std::fs::read_to_string(format!("tests/data/netshot/mgmt_address_{}.json", form))
+                    .unwrap();
+            let devices = parse_devices(&body, false).unwrap();
+
+            assert_eq!(devices.len(), 2, "{} form", form);
+            assert_eq!(
+                devices[0].management_address.ip, "10.0.0.1",
+                "{} form",
+                form
+            );
+            assert_eq!(
+                devices[1].management_address.ip, "10.0.0.2",
+                "{} form",
+                form
+            );
+        }
+        let body = std::fs::read_to_string("tests/data/netshot/mgmt_address_object.json").unwrap();
+        let devices = parse_devices(&body, false).unwrap();
+        assert_eq!(devices[0].management_address.prefix_length, Some(24));
+        assert_eq!(
+            devices[0].management_address.address_usage.as_deref(),
+            Some("PRIMARY")
+        );
+        let body = std::fs::read_to_string("tests/data/netshot/mgmt_address_string.json").unwrap();
+        let devices = parse_devices(&body, false).unwrap();
+        assert_eq!(devices[0].management_address.prefix_length, None);
+        assert_eq!(devices[0].management_address.address_usage, None);
+
+        let error = parse_devices(r#"[{"id":1,"name":"test-device","mgmtAddress":42}]"#, false)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("expected an IP string or a management address object"));
+    }
+
+    #[test]
+    fn cross_origin_redirect() {
+        let url = mockito::server_url();
+        let other_host = url.replace("127.0.0.1", "localhost");
+
+        let _mock = mockito::mock("GET", PATH_SERVER_INFO)
+            .with_status(302)
+            .with_header("Location", &format!("{}/elsewhere", other_host))
+            .create();
+        let elsewhere = mockito::mock("GET", "/elsewhere")
+            .match_header("X-Netshot-API-Token", "secret-token")
+            .expect(0)
+            .create();
+
+        let mut client = NetshotClient::builder(url)
+            .token("secret-token")
+            .build()
+            .unwrap();
+        let error = client.detect_version().unwrap_err();
+
+        assert!(format!("{:?}", error).contains("Refusing to follow the redirect"));
+        elsewhere.assert();
+    }
+
+    #[test]
+    fn unexpected_server_info() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_SERVER_INFO)
+            .match_header("X-Netshot-API-Token", "unexpected-info")
+            .with_body(r#"{"name":"Netshot"}"#)
+            .create();
+
+        let mut client = NetshotClient::builder(url.clone())
+            .token("unexpected-info")
+            .build()
+            .unwrap();
+        assert_eq!(client.detect_version().unwrap(), None);
+        assert_eq!(client.version, None);
+    }
+
+    #[test]
+    fn version_detection() {
+        let url = mockito::server_url();
+
+        let _mock = mockito::mock("GET", PATH_SERVER_INFO)
+            .with_body(r#"{"serverVersion":"0.18.1","maxIdleTimout":1800}"#)
+            .create();
+
+        let mut client = NetshotClient::builder(url.clone()).build().unwrap();
+        let version = client.detect_version().unwrap();
+
+        assert_eq!(version, Some(NetshotVersion::new(0, 18, 1)));
+        assert_eq!(client.version, version);
+    }
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!(
+            "0.21.3".parse::<NetshotVersion>().unwrap(),
+            NetshotVersion::new(0, 21, 3)
+        );
+        assert_eq!(
+            "v0.19.0-beta2".parse::<NetshotVersion>().unwrap(),
+            NetshotVersion::new(0, 19, 0)
+        );
+        assert_eq!(
+            "0.16".parse::<NetshotVersion>().unwrap(),
+            NetshotVersion::new(0, 16, 0)
+        );
+        assert!("unknown".parse::<NetshotVersion>().is_err());
+    }
+
+    #[test]
+    fn device_with_minimal_fields() {
+        let devices = parse_devices(
+            r#"[{"id":1,"name":"test-device","mgmtAddress":{"ip":"1.2.3.4"}}]"#,
+            false,
+        )
+        .unwrap();
+
+        let device = devices.first().unwrap();
+
+        assert_eq!(device.management_address.prefix_length, None);
+        assert_eq!(device.management_address.address_usage, None);
+        assert_eq!(device.status, None);
+        assert!(!device.is_disabled());
+    }
+
+    #[test]
+    fn device_missing_required_field() {
+        let error = parse_devices(r#"[{"id":1,"name":"test-device"}]"#, false)
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("`mgmtAddress`"));
+        assert!(error.contains("Netshot 0.16"));
+        assert!(error.contains("test-device (id 1) at index 0"));
+    }
+
+    #[test]
+    fn skipped_malformed_device() {
+        let devices = parse_devices(
+            r#"[{"id":1,"name":"test-device"},{"id":2,"name":"test-device-2","mgmtAddress":{"ip":"1.2.3.5"}}]"#,
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, 2);
     }
 }
@@ -0,0 +1,119 @@
+use crate::rest::helpers::StatusError;
+use anyhow::{Error, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// The statuses of an endpoint the token is not allowed to use, or which the release does not have
+const UNAVAILABLE_STATUSES: &[u16] = &[403, 404];
+
+/// An optional feature disabled for the rest of the run, as one of its endpoints is unavailable
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct DegradedFeature {
+    pub feature: String,
+    pub endpoint: String,
+    pub status: u16,
+}
+
+/// The optional enrichments of the synchronization, which degrade rather than fail when their endpoints are
+/// forbidden or missing, as the scoped tokens may not allow them
+#[derive(Debug, Default)]
+pub struct OptionalFeatures {
+    degraded: Mutex<Vec<DegradedFeature>>,
+}
+
+impl OptionalFeatures {
+    /// Run the call of an optional feature, `None` once the feature is degraded, the other errors being returned
+    pub fn call<T, F>(&self, feature: &str, call: F) -> Result<Option<T>, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        if self.is_degraded(feature) {
+            return Ok(None);
+        }
+        let error = match call() {
+            Ok(result) => return Ok(Some(result)),
+            Err(error) => error,
+        };
+        let status_error = match error.downcast_ref::<StatusError>() {
+            Some(status_error) if UNAVAILABLE_STATUSES.contains(&status_error.status) => {
+                status_error
+            }
+            _ => return Err(error),
+        };
+        log::warn!(
+            "{}, disabling the {} for the rest of the run",
+            status_error,
+            feature
+        );
+        self.degraded.lock().unwrap().push(DegradedFeature {
+            feature: feature.to_string(),
+            endpoint: status_error.endpoint.clone(),
+            status: status_error.status,
+        });
+        Ok(None)
+    }
+
+    pub fn is_degraded(&self, feature: &str) -> bool {
+        self.degraded
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|degraded| degraded.feature == feature)
+    }
+
+    /// The features degraded so far, in the order they were
+    pub fn degraded(&self) -> Vec<DegradedFeature> {
+        self.degraded.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::cell::Cell;
+
+    fn status(status: u16) -> Error {
+        StatusError {
+            service: "Netbox",
+            endpoint: "/api/dcim/sites/".to_string(),
+            status,
+        }
+        .into()
+    }
+
+    #[test]
+    fn degraded_features() {
+        let features = OptionalFeatures::default();
+        let calls = Cell::new(0);
+        let forbidden = || {
+            calls.set(calls.get() + 1);
+            Err::<(), _>(status(403))
+        };
+
+        assert_eq!(features.call("site domains", forbidden).unwrap(), None);
+        assert_eq!(features.call("site domains", forbidden).unwrap(), None);
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            features.degraded(),
+            vec![DegradedFeature {
+                feature: "site domains".to_string(),
+                endpoint: "/api/dcim/sites/".to_string(),
+                status: 403,
+            }]
+        );
+
+        assert_eq!(
+            features.call("management interfaces", || Ok(2)).unwrap(),
+            Some(2)
+        );
+        assert!(features
+            .call("management interfaces", || Err::<(), _>(status(500)))
+            .is_err());
+        assert!(features
+            .call("management interfaces", || Err::<(), _>(anyhow!("timeout")))
+            .is_err());
+        assert!(!features.is_degraded("management interfaces"));
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::net::IpAddr;
+
+/// A Netshot device search query, only ever built from validated IPs so that no value taken from Netbox can change its
+/// meaning
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchQuery(String);
+
+impl SearchQuery {
+    /// The device whose management address is the given IP
+    pub fn ip(ip: &str) -> Result<Self, Error> {
+        let ip: IpAddr = ip.parse().map_err(|_| {
+            anyhow!(
+                "`{}` is not an IP address, refusing to search Netshot for it",
+                ip.escape_debug()
+            )
+        })?;
+        Ok(SearchQuery(format!("[IP] IS {}", ip)))
+    }
+
+    /// The devices matching any of the queries, `None` when there is none
+    pub fn any(queries: Vec<SearchQuery>) -> Option<Self> {
+        match queries.len() {
+            0 => None,
+            1 => queries.into_iter().next(),
+            _ => Some(SearchQuery(
+                queries
+                    .iter()
+                    .map(|query| query.0.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" OR "),
+            )),
+        }
+    }
+
+    /// The devices whose management address is any of the given IPs
+    pub fn any_ip<S: AsRef<str>>(ips: &[S]) -> Result<Option<Self>, Error> {
+        let queries = ips
+            .iter()
+            .map(|ip| SearchQuery::ip(ip.as_ref()))
+            .collect::<Result<Vec<SearchQuery>, Error>>()?;
+        Ok(SearchQuery::any(queries))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SearchQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_queries() {
+        assert_eq!(
+            SearchQuery::ip("1.2.3.4").unwrap().as_str(),
+            "[IP] IS 1.2.3.4"
+        );
+        assert_eq!(
+            SearchQuery::ip("2001:DB8::1").unwrap().as_str(),
+            "[IP] IS 2001:db8::1"
+        );
+        for ip in ["1.2.3.4 OR [Name] IS \"x\"", "1.2.3.4/32", "", "router-1"] {
+            assert!(SearchQuery::ip(ip).is_err(), "{}", ip);
+        }
+        assert_eq!(
+            SearchQuery::any_ip(&["1.2.3.4", "1.2.3.5"])
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            "[IP] IS 1.2.3.4 OR [IP] IS 1.2.3.5"
+        );
+        assert_eq!(SearchQuery::any_ip::<&str>(&[]).unwrap(), None);
+        assert!(SearchQuery::any_ip(&["1.2.3.4", "1.2.3.5]"]).is_err());
+    }
+}
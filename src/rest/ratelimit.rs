@@ -0,0 +1,107 @@
+use reqwest::header::HeaderMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HEADER_REMAINING: &str = "X-RateLimit-Remaining";
+const HEADER_RESET: &str = "X-RateLimit-Reset";
+
+/// The longest pause, whatever the reset header says
+const MAX_PAUSE: Duration = Duration::from_secs(300);
+
+/// Reset values above this one are UNIX timestamps rather than a number of seconds
+const TIMESTAMP_THRESHOLD: u64 = 1_000_000_000;
+
+/// Self-throttling on the rate-limit headers of the responses, pausing when few requests remain
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    floor: u32,
+    resume_at: Mutex<Option<SystemTime>>,
+}
+
+/// The time to wait for the reset of the rate limit, when fewer than `floor` requests remain
+fn pause_from_headers(headers: &HeaderMap, floor: u32, now: SystemTime) -> Option<Duration> {
+    let header =
+        |name: &str| -> Option<u64> { headers.get(name)?.to_str().ok()?.trim().parse().ok() };
+    let remaining = header(HEADER_REMAINING)?;
+    if remaining >= u64::from(floor) {
+        return None;
+    }
+    let reset = header(HEADER_RESET)?;
+    let pause = if reset > TIMESTAMP_THRESHOLD {
+        (UNIX_EPOCH + Duration::from_secs(reset))
+            .duration_since(now)
+            .unwrap_or_default()
+    } else {
+        Duration::from_secs(reset)
+    };
+    Some(pause.min(MAX_PAUSE))
+}
+
+impl RateLimit {
+    /// Pause once fewer than `floor` requests remain, 0 disabling the throttling
+    pub fn new(floor: u32) -> Self {
+        Self {
+            floor,
+            ..Default::default()
+        }
+    }
+
+    /// Take the rate-limit headers of a response into account, if there are any
+    pub fn update(&self, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        if let Some(pause) = pause_from_headers(headers, self.floor, now) {
+            *self.resume_at.lock().unwrap() = Some(now + pause);
+        }
+    }
+
+    /// Wait for the rate limit to reset when required, returning the time spent waiting
+    pub fn wait(&self, service: &str) -> Option<Duration> {
+        let resume_at = self.resume_at.lock().unwrap().take()?;
+        let pause = resume_at.duration_since(SystemTime::now()).ok()?;
+        log::info!(
+            "{} rate limit nearly reached, pausing {}s until it resets",
+            service,
+            pause.as_secs_f32().ceil()
+        );
+        std::thread::sleep(pause);
+        Some(pause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(remaining: &str, reset: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER_REMAINING, HeaderValue::from_str(remaining).unwrap());
+        headers.insert(HEADER_RESET, HeaderValue::from_str(reset).unwrap());
+        headers
+    }
+
+    #[test]
+    fn pauses() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        assert_eq!(pause_from_headers(&headers("50", "30"), 5, now), None);
+        assert_eq!(
+            pause_from_headers(&headers("4", "30"), 5, now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            pause_from_headers(&headers("0", "1700000012"), 5, now),
+            Some(Duration::from_secs(12))
+        );
+        assert_eq!(
+            pause_from_headers(&headers("0", "1699999990"), 5, now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            pause_from_headers(&headers("0", "86400"), 5, now),
+            Some(MAX_PAUSE)
+        );
+        assert_eq!(pause_from_headers(&headers("0", "30"), 0, now), None);
+        assert_eq!(pause_from_headers(&HeaderMap::new(), 5, now), None);
+    }
+}
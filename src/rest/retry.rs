@@ -0,0 +1,146 @@
+use chrono::{DateTime, Utc};
+use reqwest::blocking::Response;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Method, StatusCode};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The longest delay between two attempts, whatever the number of attempts already made
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// The statuses of a load balancer or gateway which did not reach the service, safe to retry on any request
+const GATEWAY_STATUSES: &[StatusCode] = &[
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// How the requests failing transiently are retried, with an exponential backoff and jitter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt, 0 (the default) disabling them
+    pub retries: u32,
+    pub base_delay: Duration,
+    /// The number of retries of a request answered 429 Too Many Requests in a row, 0 (the default) disabling them
+    pub rate_limit_retries: u32,
+    /// The longest wait before the retry of a rate-limited request, whatever its Retry-After header says
+    pub max_retry_after: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, base_delay: Duration) -> Self {
+        RetryPolicy {
+            retries,
+            base_delay,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Retry the requests rejected by the rate limit of the server, waiting as long as it asks within a limit
+    pub fn rate_limited(mut self, rate_limit_retries: u32, max_retry_after: Duration) -> Self {
+        self.rate_limit_retries = rate_limit_retries;
+        self.max_retry_after = max_retry_after;
+        self
+    }
+
+    /// The wait before the given retry (starting at 1) of a rate-limited request, as told by its Retry-After header
+    /// in seconds or as an HTTP date, or else the backoff delay
+    pub fn retry_after(&self, headers: &HeaderMap, retry: u32, now: DateTime<Utc>) -> Duration {
+        let header = headers
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim);
+        let requested = header.and_then(|value| match value.parse::<u64>() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => DateTime::parse_from_rfc2822(value).ok().map(|date| {
+                (date.with_timezone(&Utc) - now)
+                    .to_std()
+                    .unwrap_or_default()
+            }),
+        });
+        requested
+            .unwrap_or_else(|| self.delay(retry))
+            .min(self.max_retry_after)
+    }
+
+    /// Whether the outcome of an attempt is worth another one: a connection error, a timeout or a 5xx status.
+    /// The POST requests creating something are only retried when they cannot have reached the service.
+    pub fn should_retry(
+        &self,
+        attempt: u32,
+        idempotent: bool,
+        outcome: &Result<Response, reqwest::Error>,
+    ) -> bool {
+        if attempt > self.retries {
+            return false;
+        }
+        match outcome {
+            Ok(response) if idempotent => response.status().is_server_error(),
+            Ok(response) => GATEWAY_STATUSES.contains(&response.status()),
+            Err(error) if idempotent => error.is_connect() || error.is_timeout(),
+            Err(error) => error.is_connect(),
+        }
+    }
+
+    /// The delay before the given retry (starting at 1): the base delay doubled at each retry, half of it being random
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(MAX_DELAY);
+        delay / 2 + delay.mul_f64(jitter() / 2.0)
+    }
+}
+
+/// Whether a request can be sent again without creating anything twice
+pub(crate) fn is_idempotent(method: &Method, read_only_posts: &[&str], template: &str) -> bool {
+    method != Method::POST || read_only_posts.contains(&template)
+}
+
+/// A number between 0 and 1 varying from a call to the next, enough to spread the retries of concurrent runs
+fn jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    f64::from(nanos % 1000) / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delays() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(200));
+        for (retry, base) in [(1, 200), (2, 400), (3, 800)] {
+            let delay = policy.delay(retry);
+            assert!(delay >= Duration::from_millis(base / 2), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(base), "{:?}", delay);
+        }
+        assert!(RetryPolicy::new(30, Duration::from_secs(1)).delay(30) <= MAX_DELAY);
+
+        let policy = policy.rate_limited(5, Duration::from_secs(60));
+        let now = "2024-01-01T00:00:00Z".parse().unwrap();
+        let retry_after = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            policy.retry_after(&headers, 1, now)
+        };
+        assert_eq!(retry_after("12"), Duration::from_secs(12));
+        assert_eq!(retry_after("3600"), Duration::from_secs(60));
+        assert_eq!(
+            retry_after("Mon, 01 Jan 2024 00:00:30 GMT"),
+            Duration::from_secs(30)
+        );
+        assert_eq!(retry_after("Sun, 31 Dec 2023 23:59:00 GMT"), Duration::ZERO);
+        assert!(policy.retry_after(&HeaderMap::new(), 1, now) <= Duration::from_millis(200));
+
+        assert!(is_idempotent(&Method::GET, &[], "/api/devices"));
+        assert!(!is_idempotent(&Method::POST, &[], "/api/devices"));
+        assert!(is_idempotent(
+            &Method::POST,
+            &["/api/devices/search"],
+            "/api/devices/search"
+        ));
+    }
+}
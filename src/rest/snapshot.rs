@@ -0,0 +1,262 @@
+use crate::rest::stats::EndpointSummary;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The source an inventory is fetched from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    NetboxDevices,
+    NetboxVms,
+    NetshotDevices,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Source::NetboxDevices => "Netbox devices",
+            Source::NetboxVms => "Netbox VMs",
+            Source::NetshotDevices => "Netshot devices",
+        })
+    }
+}
+
+/// When an inventory was fetched and what came back, as the plan is only as consistent as its inputs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct FetchSnapshot {
+    pub source: Source,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_id: Option<u32>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub items: usize,
+    pub pages: u32,
+    /// The number of items announced by the source, when it announces one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announced_items: Option<u32>,
+    /// The changes of the source noticed while it was being fetched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drift: Vec<String>,
+}
+
+impl FetchSnapshot {
+    /// Start the snapshot of a source, to be completed by `finish`
+    pub fn start(source: Source) -> Self {
+        let now = Utc::now();
+        FetchSnapshot {
+            source,
+            domain_id: None,
+            started_at: now,
+            finished_at: now,
+            items: 0,
+            pages: 0,
+            announced_items: None,
+            drift: Vec::new(),
+        }
+    }
+
+    /// Count a fetched page, noting the drift when the announced total changes between pages
+    pub fn page(&mut self, items: usize, announced_items: Option<u32>) {
+        self.pages += 1;
+        self.items += items;
+        if let (Some(previous), Some(announced)) = (self.announced_items, announced_items) {
+            if previous != announced {
+                self.drift.push(format!(
+                    "the total went from {} to {} on page {}",
+                    previous, announced, self.pages
+                ));
+            }
+        }
+        self.announced_items = announced_items.or(self.announced_items);
+    }
+
+    /// Complete the snapshot with the number of items actually kept
+    pub fn finish(mut self, items: usize) -> Self {
+        self.finished_at = Utc::now();
+        if let Some(announced) = self.announced_items {
+            if self.items != announced as usize {
+                self.drift.push(format!(
+                    "{} items fetched while {} were announced",
+                    self.items, announced
+                ));
+            }
+        }
+        self.items = items;
+        self
+    }
+}
+
+/// The items of a source along with the snapshot they were fetched in
+#[derive(Debug)]
+pub struct Fetched<T> {
+    pub items: Vec<T>,
+    pub snapshot: FetchSnapshot,
+}
+
+impl<T> Fetched<T> {
+    /// Items fetched in a single request
+    pub fn single(source: Source, items: Vec<T>) -> Self {
+        let mut snapshot = FetchSnapshot::start(source);
+        snapshot.page(items.len(), None);
+        Fetched {
+            snapshot: snapshot.finish(items.len()),
+            items,
+        }
+    }
+}
+
+/// Whether the inventories the plan is based on make up a consistent snapshot
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct Consistency {
+    /// When the first fetch started
+    pub started_at: Option<DateTime<Utc>>,
+    /// When the last fetch ended
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The changes of the sources noticed while they were being fetched
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub drift: Vec<String>,
+    /// The GET requests which had to be retried, unknown without --http-stats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retried_requests: Option<usize>,
+}
+
+impl Consistency {
+    pub fn new(snapshots: &[FetchSnapshot], http_requests: Option<&[EndpointSummary]>) -> Self {
+        Consistency {
+            started_at: snapshots.iter().map(|snapshot| snapshot.started_at).min(),
+            finished_at: snapshots.iter().map(|snapshot| snapshot.finished_at).max(),
+            drift: snapshots
+                .iter()
+                .flat_map(|snapshot| {
+                    snapshot
+                        .drift
+                        .iter()
+                        .map(move |drift| match snapshot.domain_id {
+                            Some(domain_id) => {
+                                format!("{} of domain {}: {}", snapshot.source, domain_id, drift)
+                            }
+                            None => format!("{}: {}", snapshot.source, drift),
+                        })
+                })
+                .collect(),
+            retried_requests: http_requests.map(|endpoints| {
+                endpoints
+                    .iter()
+                    .filter(|endpoint| endpoint.method == "GET")
+                    .map(|endpoint| endpoint.retried)
+                    .sum()
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Consistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (started_at, finished_at) = match (self.started_at, self.finished_at) {
+            (Some(started_at), Some(finished_at)) => (started_at, finished_at),
+            _ => return write!(f, "no inventory fetched"),
+        };
+        write!(
+            f,
+            "inventories fetched from {} to {} ({} ms)",
+            started_at.format("%H:%M:%S%.3f"),
+            finished_at.format("%H:%M:%S%.3f"),
+            (finished_at - started_at).num_milliseconds()
+        )?;
+        if !self.drift.is_empty() {
+            write!(f, ", drift: {}", self.drift.join("; "))?;
+        }
+        match self.retried_requests {
+            Some(retried) if retried > 0 => write!(f, ", {} retried requests", retried),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_drift() {
+        let mut snapshot = FetchSnapshot::start(Source::NetboxDevices);
+        snapshot.page(50, Some(120));
+        snapshot.page(50, Some(120));
+        snapshot.page(20, Some(120));
+        let snapshot = snapshot.finish(120);
+        assert!(snapshot.drift.is_empty());
+        assert_eq!(snapshot.pages, 3);
+
+        let mut snapshot = FetchSnapshot::start(Source::NetboxDevices);
+        snapshot.page(50, Some(120));
+        snapshot.page(50, Some(121));
+        snapshot.page(20, Some(121));
+        let snapshot = snapshot.finish(118);
+        assert_eq!(
+            snapshot.drift,
+            vec![
+                "the total went from 120 to 121 on page 2".to_string(),
+                "120 items fetched while 121 were announced".to_string(),
+            ]
+        );
+        assert_eq!(snapshot.items, 118);
+        assert_eq!(snapshot.announced_items, Some(121));
+    }
+
+    #[test]
+    fn consistency_window() {
+        let snapshot = |source, started_at: &str, finished_at: &str| FetchSnapshot {
+            source,
+            domain_id: None,
+            started_at: started_at.parse().unwrap(),
+            finished_at: finished_at.parse().unwrap(),
+            items: 1,
+            pages: 1,
+            announced_items: None,
+            drift: Vec::new(),
+        };
+        let mut netshot = snapshot(
+            Source::NetshotDevices,
+            "2024-01-01T00:00:02Z",
+            "2024-01-01T00:00:03.500Z",
+        );
+        netshot.domain_id = Some(2);
+        netshot
+            .drift
+            .push("the total went from 1 to 2 on page 2".to_string());
+        let snapshots = vec![
+            snapshot(
+                Source::NetboxDevices,
+                "2024-01-01T00:00:00Z",
+                "2024-01-01T00:00:01Z",
+            ),
+            netshot,
+        ];
+        let endpoint = |method: &str, retried| EndpointSummary {
+            method: method.to_string(),
+            endpoint: "/api/devices".to_string(),
+            requests: 2,
+            retried,
+            throttled: 0,
+            throttled_ms: 0,
+            status_classes: Default::default(),
+            p50_ms: 0,
+            p95_ms: 0,
+            max_ms: 0,
+        };
+
+        let consistency =
+            Consistency::new(&snapshots, Some(&[endpoint("GET", 2), endpoint("POST", 1)]));
+        assert_eq!(consistency.retried_requests, Some(2));
+        assert_eq!(
+            consistency.to_string(),
+            "inventories fetched from 00:00:00.000 to 00:00:03.500 (3500 ms), drift: Netshot devices of domain 2: the total went from 1 to 2 on page 2, 2 retried requests"
+        );
+        assert_eq!(
+            Consistency::new(&[], None).to_string(),
+            "no inventory fetched"
+        );
+    }
+}
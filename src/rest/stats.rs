@@ -0,0 +1,308 @@
+use crate::rest::retry::{is_idempotent, RetryPolicy};
+use anyhow::{anyhow, Error, Result};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::StatusCode;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The status class recorded when no response was received (timeout, connection error...)
+const NO_RESPONSE: &str = "error";
+
+/// The raw measures of an endpoint
+#[derive(Debug, Default)]
+struct EndpointMeasures {
+    retried: usize,
+    throttled: usize,
+    throttled_duration: Duration,
+    status_classes: BTreeMap<String, usize>,
+    durations: Vec<Duration>,
+}
+
+/// The aggregated statistics of an endpoint
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct EndpointSummary {
+    pub method: String,
+    pub endpoint: String,
+    pub requests: usize,
+    pub retried: usize,
+    pub throttled: usize,
+    pub throttled_ms: u128,
+    pub status_classes: BTreeMap<String, usize>,
+    pub p50_ms: u128,
+    pub p95_ms: u128,
+    pub max_ms: u128,
+}
+
+/// The HTTP requests statistics, shared by the Netbox and Netshot clients
+#[derive(Debug, Default)]
+pub struct RequestStats {
+    record_urls: bool,
+    endpoints: Mutex<BTreeMap<(String, String), EndpointMeasures>>,
+}
+
+/// The duration at the given percentile of the sorted durations
+fn percentile(durations: &[Duration], percentile: usize) -> u128 {
+    if durations.is_empty() {
+        return 0;
+    }
+    let index = (durations.len() * percentile).div_ceil(100).max(1) - 1;
+    durations[index.min(durations.len() - 1)].as_millis()
+}
+
+impl RequestStats {
+    /// Create the statistics, keyed by endpoint template or by full URL when `record_urls` is set
+    pub fn new(record_urls: bool) -> Self {
+        Self {
+            record_urls,
+            ..Default::default()
+        }
+    }
+
+    /// Record a request to an endpoint
+    pub fn record(
+        &self,
+        method: &str,
+        template: &str,
+        url: &str,
+        status: Option<StatusCode>,
+        attempts: u32,
+        duration: Duration,
+    ) {
+        let endpoint = if self.record_urls { url } else { template };
+        let status_class = match status {
+            Some(status) => format!("{}xx", status.as_u16() / 100),
+            None => NO_RESPONSE.to_string(),
+        };
+
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let measures = endpoints
+            .entry((method.to_string(), endpoint.to_string()))
+            .or_default();
+        if attempts > 1 {
+            measures.retried += 1;
+        }
+        *measures.status_classes.entry(status_class).or_default() += 1;
+        measures.durations.push(duration);
+    }
+
+    /// Record a request delayed to respect the rate limit of the service
+    pub fn record_throttle(&self, method: &str, template: &str, url: &str, pause: Duration) {
+        let endpoint = if self.record_urls { url } else { template };
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let measures = endpoints
+            .entry((method.to_string(), endpoint.to_string()))
+            .or_default();
+        measures.throttled += 1;
+        measures.throttled_duration += pause;
+    }
+
+    /// Aggregate the statistics of every endpoint
+    pub fn summary(&self) -> Vec<EndpointSummary> {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints
+            .iter()
+            .map(|((method, endpoint), measures)| {
+                let mut durations = measures.durations.clone();
+                durations.sort();
+                EndpointSummary {
+                    method: method.clone(),
+                    endpoint: endpoint.clone(),
+                    requests: durations.len(),
+                    retried: measures.retried,
+                    throttled: measures.throttled,
+                    throttled_ms: measures.throttled_duration.as_millis(),
+                    status_classes: measures.status_classes.clone(),
+                    p50_ms: percentile(&durations, 50),
+                    p95_ms: percentile(&durations, 95),
+                    max_ms: durations.last().map_or(0, Duration::as_millis),
+                }
+            })
+            .collect()
+    }
+
+    /// Log the statistics as a table
+    pub fn log_summary(&self) {
+        log::info!(
+            "{:<7} {:<45} {:>8} {:>7} {:>9} {:>8} {:>8} {:>8}  statuses",
+            "METHOD",
+            "ENDPOINT",
+            "REQUESTS",
+            "RETRIED",
+            "THROTTLED",
+            "P50(ms)",
+            "P95(ms)",
+            "MAX(ms)"
+        );
+        for endpoint in self.summary() {
+            let statuses: Vec<String> = endpoint
+                .status_classes
+                .iter()
+                .map(|(class, count)| format!("{}={}", class, count))
+                .collect();
+            log::info!(
+                "{:<7} {:<45} {:>8} {:>7} {:>9} {:>8} {:>8} {:>8}  {}",
+                endpoint.method,
+                endpoint.endpoint,
+                endpoint.requests,
+                endpoint.retried,
+                endpoint.throttled,
+                endpoint.p50_ms,
+                endpoint.p95_ms,
+                endpoint.max_ms,
+                statuses.join(" ")
+            );
+        }
+    }
+}
+
+/// Send the request, retrying its transient failures, and record it under the endpoint template when statistics are
+/// collected. The POST requests of `read_only_posts` are retried as the other reads.
+pub fn send(
+    client: &Client,
+    stats: Option<&RequestStats>,
+    retry: &RetryPolicy,
+    read_only_posts: &[&str],
+    template: &str,
+    request: RequestBuilder,
+) -> Result<Response, Error> {
+    let mut request = request.build()?;
+    let method = request.method().to_string();
+    let url = request.url().to_string();
+    let idempotent = is_idempotent(request.method(), read_only_posts, template);
+    let start = Instant::now();
+    let mut attempt = 1;
+    // The rate-limited attempts in a row, retried apart from the failed ones
+    let mut rate_limited = 0;
+    let response = loop {
+        // The requests with a streamed body cannot be sent twice
+        let next = request.try_clone();
+        let response = client.execute(request);
+        let rate_limit_headers = match &response {
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                Some(response.headers())
+            }
+            _ => None,
+        };
+        match (next, rate_limit_headers) {
+            // The server rejected the request before processing it, so it is retried whatever its method
+            (Some(next), Some(headers)) if rate_limited < retry.rate_limit_retries => {
+                rate_limited += 1;
+                let delay = retry.retry_after(headers, rate_limited, chrono::Utc::now());
+                log::info!(
+                    "{} {} rate limited by the server, retrying in {}s ({}/{})",
+                    method,
+                    template,
+                    delay.as_secs_f32().ceil(),
+                    rate_limited,
+                    retry.rate_limit_retries
+                );
+                if let Some(stats) = stats {
+                    stats.record_throttle(&method, template, &url, delay);
+                }
+                std::thread::sleep(delay);
+                request = next;
+            }
+            (Some(next), None) if retry.should_retry(attempt, idempotent, &response) => {
+                let delay = retry.delay(attempt);
+                log::warn!(
+                    "{} {} failed ({}), retrying in {} ms ({}/{})",
+                    method,
+                    template,
+                    match &response {
+                        Ok(response) => response.status().to_string(),
+                        Err(error) => error.to_string(),
+                    },
+                    delay.as_millis(),
+                    attempt,
+                    retry.retries
+                );
+                std::thread::sleep(delay);
+                request = next;
+                attempt += 1;
+                rate_limited = 0;
+            }
+            _ => break response,
+        }
+    };
+    if let Some(stats) = stats {
+        stats.record(
+            &method,
+            template,
+            &url,
+            response.as_ref().ok().map(Response::status),
+            attempt,
+            start.elapsed(),
+        );
+    }
+    let response = response?;
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(anyhow!(
+            "The server is rate limiting the requests, {} {} still got {} after {} retries",
+            method,
+            template,
+            response.status(),
+            rate_limited
+        ));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(stats: &RequestStats, status: Option<u16>, attempts: u32, duration_ms: u64) {
+        stats.record(
+            "GET",
+            "/api/dcim/devices/",
+            "http://netbox/api/dcim/devices/?site=secret-site",
+            status.map(|status| StatusCode::from_u16(status).unwrap()),
+            attempts,
+            Duration::from_millis(duration_ms),
+        );
+    }
+
+    #[test]
+    fn aggregated_summary() {
+        let stats = RequestStats::new(false);
+        for duration_ms in 1..=100 {
+            record(&stats, Some(200), 1, duration_ms);
+        }
+        record(&stats, Some(503), 3, 500);
+        record(&stats, None, 1, 5000);
+        stats.record_throttle(
+            "GET",
+            "/api/dcim/devices/",
+            "http://netbox/api/dcim/devices/?site=secret-site",
+            Duration::from_secs(30),
+        );
+
+        let summary = stats.summary();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].endpoint, "/api/dcim/devices/");
+        assert_eq!(summary[0].requests, 102);
+        assert_eq!(summary[0].retried, 1);
+        assert_eq!(summary[0].throttled, 1);
+        assert_eq!(summary[0].throttled_ms, 30000);
+        assert_eq!(summary[0].status_classes["2xx"], 100);
+        assert_eq!(summary[0].status_classes["5xx"], 1);
+        assert_eq!(summary[0].status_classes["error"], 1);
+        assert_eq!(summary[0].p50_ms, 51);
+        assert_eq!(summary[0].p95_ms, 97);
+        assert_eq!(summary[0].max_ms, 5000);
+    }
+
+    #[test]
+    fn full_urls_only_when_requested() {
+        let stats = RequestStats::new(true);
+        record(&stats, Some(200), 1, 10);
+
+        assert_eq!(
+            stats.summary()[0].endpoint,
+            "http://netbox/api/dcim/devices/?site=secret-site"
+        );
+    }
+}
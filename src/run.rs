@@ -0,0 +1,1862 @@
+use crate::apply::{self, Recorders};
+use crate::attributes;
+use crate::domains::{self, DomainTarget, RoleDomain};
+use crate::exclusion;
+use crate::filter;
+use crate::inventory;
+use crate::metrics::RunMetrics;
+use crate::paths;
+use crate::preflight;
+#[cfg(feature = "csv-report")]
+use crate::report::csv_report::CsvReport;
+use crate::report::{
+    self, Action, ActionOutcome, AuditEntry, AuditLog, ReportFormat, ReportSummary,
+};
+use crate::rest::api::{NetboxApi, NetshotApi};
+use crate::rest::helpers::CertificateExpiry;
+use crate::rest::netbox;
+use crate::rest::netshot;
+use crate::rest::optional::OptionalFeatures;
+use crate::rest::snapshot::{Consistency, FetchSnapshot, Fetched, Source};
+use crate::rest::stats::RequestStats;
+use crate::state::PendingRegistration;
+use crate::sync;
+use anyhow::{anyhow, Error, Result};
+use chrono::Utc;
+use chrono_tz::Tz;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub use crate::attributes::Attribute;
+pub use crate::budget::BudgetOrder;
+pub use crate::exclusion::Cidr;
+pub use crate::expectation::{ChangeLimitExceeded, ChangeLimits, CountExpectation, FailThreshold};
+pub use crate::mapping::DeviceMapping;
+pub use crate::preflight::{CheckResult, Severity};
+pub use crate::state::RunState;
+pub use crate::window::TimeWindow;
+
+/// The helpers of the `netbox2netshot` CLI, parsing its flags and printing its checks, which are not part of the
+/// library API
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::common::{new_run_id, parse_base_url, parse_log_file_prefix, APP_USER_AGENT};
+    pub use crate::exclusion::read_exclude_file;
+    pub use crate::expectation::parse_percentage;
+    pub use crate::filter::{encode_filter, read_filter_file};
+    pub use crate::paths::{expand, expand_path};
+    pub use crate::preflight::{certificate_expiry, netshot_version, print_table, worst_severity};
+    pub use crate::progress::enable as enable_progress;
+}
+
+/// How the domains are planned, guarded and applied, the CLI flags resolved, the CLI defaults being the default
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RunOptions {
+    /// The ID of the run, in the logs, the report and the task comments
+    pub run_id: String,
+    /// Only plan, without changing Netshot
+    pub check: bool,
+    /// Whether the clients refuse the writes, the run being then in check mode as well
+    pub read_only: bool,
+    /// Whether the writes to Netshot are disabled, the Netshot actions being reported as skipped
+    pub no_netshot_writes: bool,
+    /// The Netbox base URL, linked from the synchronized attributes
+    pub netbox_url: String,
+    /// The querystrings selecting the Netbox devices, all of them when there is none
+    pub netbox_devices_filter: Vec<String>,
+    /// Whether a raw GraphQL filter replaces the devices filters, which the devices then need not satisfy
+    pub graphql_filter: bool,
+    /// The querystrings selecting the Netbox VMs, none being synchronized when there is none and no cluster
+    pub netbox_vms_filter: Vec<String>,
+    pub netbox_vms_cluster: Option<String>,
+    /// Warn about the conditions of the filters the fetched devices do not satisfy
+    pub verify_filter: bool,
+    pub prefer_mgmt_interface: bool,
+    /// The Netbox inventory file read in place of fetching the devices
+    pub netbox_from_file: Option<PathBuf>,
+    /// The Netshot inventory file read in place of fetching the devices
+    pub netshot_from_file: Option<PathBuf>,
+    /// The directory the fetched inventories are written to
+    pub dump_inventories: Option<PathBuf>,
+    /// The domain of the devices, unless split by their site or role
+    pub netshot_domain_id: u32,
+    /// The custom field of the sites naming the domain of their devices
+    pub site_domain_field: Option<String>,
+    pub role_domain_map: Vec<RoleDomain>,
+    pub exclude_ip: Vec<Cidr>,
+    pub expect_netbox_count: Option<CountExpectation>,
+    pub expect_netshot_count: Option<CountExpectation>,
+    /// The plan options, the device IDs mapping being the one of the recorders
+    pub plan: sync::PlanOptions,
+    pub match_any_netshot_address: bool,
+    pub verify_deletions: bool,
+    pub purge_disabled_after: Option<u32>,
+    pub sync_attributes: Vec<Attribute>,
+    pub stale_action: sync::StaleAction,
+    pub prune_empty_groups: bool,
+    pub disable_window: Option<TimeWindow>,
+    pub window_timezone: Option<Tz>,
+    pub retry_permanent_failures: bool,
+    pub max_pending_discoveries: Option<usize>,
+    pub fail_on_duplicate_ip: bool,
+    pub change_limits: ChangeLimits,
+    pub changes_budget: Option<usize>,
+    pub budget_order: BudgetOrder,
+    /// The domains planned or applied at once
+    pub domain_concurrency: usize,
+    pub apply: apply::ApplyOptions,
+    /// Whether the changes go to another Netshot than the fetched one, whose device IDs are not the fetched ones
+    pub apply_to_other_netshot: bool,
+    pub fail_threshold: FailThreshold,
+    pub fail_on_skipped: Option<usize>,
+    /// Fail the run when any registration, disable or enable is planned
+    pub fail_on_drift: bool,
+    pub report: Option<PathBuf>,
+    pub report_format: ReportFormat,
+    #[cfg(feature = "csv-report")]
+    pub csv_report: Option<PathBuf>,
+    #[cfg(feature = "metrics")]
+    pub metrics_textfile: Option<PathBuf>,
+    pub mapping_file: Option<PathBuf>,
+    pub state_file: Option<PathBuf>,
+    /// The seconds to wait after the last successful run before running again, unless forced
+    pub min_run_interval: Option<i64>,
+    pub force: bool,
+    pub max_consecutive_failures: u32,
+    pub registration_grace: u32,
+    pub recheck_stalled_registrations: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            run_id: String::new(),
+            check: false,
+            read_only: false,
+            no_netshot_writes: false,
+            netbox_url: String::new(),
+            netbox_devices_filter: Vec::new(),
+            graphql_filter: false,
+            netbox_vms_filter: Vec::new(),
+            netbox_vms_cluster: None,
+            verify_filter: false,
+            prefer_mgmt_interface: false,
+            netbox_from_file: None,
+            netshot_from_file: None,
+            dump_inventories: None,
+            netshot_domain_id: 0,
+            site_domain_field: None,
+            role_domain_map: Vec::new(),
+            exclude_ip: Vec::new(),
+            expect_netbox_count: None,
+            expect_netshot_count: None,
+            plan: sync::PlanOptions::default(),
+            match_any_netshot_address: false,
+            verify_deletions: false,
+            purge_disabled_after: None,
+            sync_attributes: Vec::new(),
+            stale_action: sync::StaleAction::default(),
+            prune_empty_groups: false,
+            disable_window: None,
+            window_timezone: None,
+            retry_permanent_failures: false,
+            max_pending_discoveries: None,
+            fail_on_duplicate_ip: false,
+            change_limits: ChangeLimits::default(),
+            changes_budget: None,
+            budget_order: BudgetOrder::default(),
+            domain_concurrency: 1,
+            apply: apply::ApplyOptions::default(),
+            apply_to_other_netshot: false,
+            fail_threshold: FailThreshold::Count(0),
+            fail_on_skipped: None,
+            fail_on_drift: false,
+            report: None,
+            report_format: ReportFormat::default(),
+            #[cfg(feature = "csv-report")]
+            csv_report: None,
+            #[cfg(feature = "metrics")]
+            metrics_textfile: None,
+            mapping_file: None,
+            state_file: None,
+            min_run_interval: None,
+            force: false,
+            max_consecutive_failures: 3,
+            registration_grace: 2,
+            recheck_stalled_registrations: false,
+        }
+    }
+}
+
+impl RunOptions {
+    /// The querystrings selecting the devices, a single empty one selecting them all when none is given
+    pub fn devices_filters(&self) -> Vec<&str> {
+        match self.netbox_devices_filter.is_empty() {
+            true => vec![""],
+            false => self
+                .netbox_devices_filter
+                .iter()
+                .map(String::as_str)
+                .collect(),
+        }
+    }
+
+    /// The querystrings selecting the VMs, none when they are not synchronized
+    fn vms_filters(&self) -> Vec<String> {
+        let cluster = self
+            .netbox_vms_cluster
+            .as_deref()
+            .map(netbox::vms_cluster_query);
+        match cluster {
+            Some(cluster) if self.netbox_vms_filter.is_empty() => vec![cluster],
+            Some(cluster) => self
+                .netbox_vms_filter
+                .iter()
+                .map(|filter| match filter.is_empty() {
+                    true => cluster.clone(),
+                    false => format!("{}&{}", filter, cluster),
+                })
+                .collect(),
+            None => self.netbox_vms_filter.clone(),
+        }
+    }
+}
+
+/// The error of a run which reached both APIs but failed to apply some of its changes
+#[derive(Debug)]
+pub struct PartialFailure(pub String);
+
+impl fmt::Display for PartialFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PartialFailure {}
+
+/// The error of a --fail-on-drift check run which found Netshot out of sync with Netbox
+#[derive(Debug)]
+pub struct DriftDetected(pub String);
+
+impl fmt::Display for DriftDetected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DriftDetected {}
+
+/// The refusal of a run started before --min-run-interval elapsed since the last successful one
+#[derive(Debug)]
+pub struct TooSoon(pub String);
+
+impl fmt::Display for TooSoon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TooSoon {}
+
+/// The clients of a run, the changes going to `netshot_apply` when given
+pub struct Clients<'a> {
+    pub netbox: &'a netbox::NetboxClient,
+    pub netshot: &'a netshot::NetshotClient,
+    pub netshot_apply: Option<&'a netshot::NetshotClient>,
+    /// The statistics of the requests of the clients, when recorded
+    pub http_stats: Option<Arc<RequestStats>>,
+}
+
+/// A run of the synchronization, started before its clients are built
+pub struct Run<'a> {
+    options: &'a RunOptions,
+    run_state: Option<RunState>,
+}
+
+impl<'a> Run<'a> {
+    /// Create the directories of the output files and load the state, refusing with [`TooSoon`] a run started less
+    /// than --min-run-interval after the last successful one, unless forced or in check mode
+    pub fn start(options: &'a RunOptions) -> Result<Self, Error> {
+        for devices_filter in options.devices_filters() {
+            log::info!("Using the Netbox devices filter `{}`", devices_filter);
+        }
+        if options.no_netshot_writes && !options.check {
+            log::warn!(
+                "The writes to Netshot are disabled, the Netshot actions are reported as skipped"
+            );
+        }
+        // The output files only, the state and mapping files being left unwritten in check mode
+        for (flag, path) in [
+            ("--report", options.report.as_ref()),
+            #[cfg(feature = "csv-report")]
+            ("--csv-report", options.csv_report.as_ref()),
+            (
+                "--state-file",
+                options.state_file.as_ref().filter(|_| !options.check),
+            ),
+            (
+                "--mapping-file",
+                options.mapping_file.as_ref().filter(|_| !options.check),
+            ),
+        ] {
+            if let Some(path) = path {
+                paths::create_parent_directory(flag, path)?;
+            }
+        }
+
+        let run_state = options
+            .state_file
+            .as_deref()
+            .map(RunState::load)
+            .transpose()?;
+        if let (Some(run_state), Some(min_run_interval)) = (&run_state, options.min_run_interval) {
+            let too_soon =
+                run_state.too_soon(chrono::Duration::seconds(min_run_interval), Utc::now());
+            match too_soon {
+                Some(_) if options.check => {
+                    log::info!("Ignoring --min-run-interval in check mode")
+                }
+                Some(_) if options.force => {
+                    log::warn!("Forcing a run before --min-run-interval elapsed")
+                }
+                Some(remaining) => {
+                    return Err(TooSoon(format!(
+                        "The last successful run ended at {}, less than {}s ago, exiting without changes (retry in {}s or use --force)",
+                        run_state.last_success.unwrap_or_default(),
+                        min_run_interval,
+                        remaining.num_seconds() + 1
+                    ))
+                    .into());
+                }
+                None => {}
+            }
+        }
+        Ok(Run { options, run_state })
+    }
+
+    /// Synchronize the Netbox devices into their Netshot domains, then save the report, the mapping and the state.
+    /// The errors of the run come after its outputs are saved: [`PartialFailure`] when domains or changes failed,
+    /// [`ChangeLimitExceeded`] when the change limits blocked the apply and [`DriftDetected`] with --fail-on-drift.
+    pub fn synchronize(self, clients: Clients) -> Result<(), Error> {
+        let options = self.options;
+        let netbox_client = clients.netbox;
+        let netshot_client = clients.netshot;
+        let apply_client = clients.netshot_apply.unwrap_or(netshot_client);
+        let run_state = self.run_state.map(Mutex::new);
+
+        let client_certificates: Vec<CertificateExpiry> = [
+            ("netbox", netbox_client.certificate_expiry),
+            ("netshot", netshot_client.certificate_expiry),
+            (
+                "netshot-apply",
+                clients
+                    .netshot_apply
+                    .and_then(|client| client.certificate_expiry),
+            ),
+        ]
+        .iter()
+        .filter_map(|&(service, expires_at)| {
+            Some(CertificateExpiry {
+                service: service.to_string(),
+                expires_at: expires_at?,
+            })
+        })
+        .collect();
+
+        let features = OptionalFeatures::default();
+        let (netbox_devices, snapshots) = match &options.netbox_from_file {
+            Some(path) => {
+                log::info!("Loading devices list of Netbox from {}", path.display());
+                let mut fetched =
+                    Fetched::single(Source::NetboxDevices, inventory::read_inventory(path)?);
+                exclusion::exclude_netbox_devices(
+                    &mut fetched.items,
+                    &options.exclude_ip,
+                    &options.plan,
+                );
+                (fetched.items, vec![fetched.snapshot])
+            }
+            None => fetch_netbox_inventory(options, netbox_client, &features)?,
+        };
+        let snapshots = Mutex::new(snapshots);
+        let netbox_inventory = options
+            .dump_inventories
+            .as_ref()
+            .map(|_| netbox_devices.clone());
+        let netshot_inventory = Mutex::new(Vec::new());
+
+        let netbox_count_violation = options
+            .expect_netbox_count
+            .as_ref()
+            .and_then(|expectation| expectation.check("Netbox", netbox_devices.len()).err());
+        if let Some(error) = netbox_count_violation.as_ref() {
+            if !options.check {
+                return Err(anyhow!("{}", error));
+            }
+            log::error!("{}, computing the plan anyway in check mode", error);
+        }
+
+        let audit = options
+            .report
+            .as_deref()
+            .map(|path| {
+                AuditLog::create(path).map(|audit| audit.with_format(options.report_format))
+            })
+            .transpose()?;
+        #[cfg(feature = "csv-report")]
+        let csv_report = options.csv_report.as_deref().map(CsvReport::new);
+        let mapping = options
+            .mapping_file
+            .as_deref()
+            .map(DeviceMapping::load)
+            .transpose()?
+            .map(Mutex::new);
+
+        let targets = domain_targets(options, netbox_client, netshot_client, netbox_devices)?;
+        let outcomes = Mutex::new(Vec::new());
+        let recorders = Recorders {
+            audit: audit.as_ref(),
+            mapping: mapping.as_ref(),
+            history: run_state.as_ref(),
+            snapshots: Some(&snapshots),
+            outcomes: Some(&outcomes),
+            #[cfg(feature = "csv-report")]
+            csv: csv_report.as_ref(),
+            netshot_inventory: options
+                .dump_inventories
+                .as_ref()
+                .map(|_| &netshot_inventory),
+        };
+        let (results, change_limits_exceeded) = run_domains(
+            options,
+            netbox_client,
+            netshot_client,
+            apply_client,
+            recorders,
+            &targets,
+        )?;
+        let blocked = change_limits_exceeded.is_some() && !options.check;
+
+        let mut failed_domains = 0;
+        let mut threshold_violations = Vec::new();
+        let mut metrics = RunMetrics {
+            netbox_devices: targets
+                .iter()
+                .map(|target| target.netbox_devices.len())
+                .sum(),
+            ..RunMetrics::default()
+        };
+        for (target, result) in targets.iter().zip(results) {
+            match result {
+                Ok((sync_plan, domain_applied)) => {
+                    metrics.add_plan(&sync_plan);
+                    if !options.check && !blocked && targets.len() > 1 {
+                        log_applied(&domain_applied, Some(target), None);
+                    }
+                    if let Err(error) =
+                        check_domain_thresholds(options, &domain_applied, sync_plan.skipped.len())
+                    {
+                        threshold_violations.push(format!("domain {}: {}", target.label(), error));
+                    }
+                }
+                Err(error) => {
+                    log::error!(
+                        "Synchronization of domain {} failed: {:?}",
+                        target.label(),
+                        error
+                    );
+                    failed_domains += 1;
+                }
+            }
+        }
+        // The summary of the run is made of the recorded outcomes, whether their domain completed or not
+        let outcomes = outcomes.into_inner().unwrap();
+        let applied = ReportSummary::from_outcomes(&outcomes);
+
+        let pruned_groups =
+            if !options.prune_empty_groups || options.check || options.no_netshot_writes {
+                Vec::new()
+            } else if failed_domains > 0
+                || applied.failed() > 0
+                || !threshold_violations.is_empty()
+                || blocked
+            {
+                log::warn!("Not pruning the empty groups, as the apply did not fully succeed");
+                Vec::new()
+            } else {
+                prune_empty_groups(options, apply_client)
+            };
+
+        if let Some(http_stats) = &clients.http_stats {
+            http_stats.log_summary();
+        }
+        let snapshots = snapshots.into_inner().unwrap();
+        let http_requests = clients.http_stats.map(|http_stats| http_stats.summary());
+        let consistency = Consistency::new(&snapshots, http_requests.as_deref());
+        #[cfg(feature = "metrics")]
+        if let Some(path) = &options.metrics_textfile {
+            metrics.netshot_devices = snapshots
+                .iter()
+                .filter(|snapshot| snapshot.source == Source::NetshotDevices)
+                .map(|snapshot| snapshot.items)
+                .sum();
+            metrics.failures = applied.failed();
+            metrics.client_certificates = client_certificates.clone();
+            metrics.success = netbox_count_violation.is_none()
+                && failed_domains == 0
+                && !blocked
+                && threshold_violations.is_empty();
+            metrics.write(path, Utc::now())?;
+        }
+        if let Some(mapping) = mapping {
+            if options.check {
+                log::info!("Not saving the mapping file in check mode");
+            } else {
+                mapping.into_inner().unwrap().save()?;
+            }
+        }
+        let mut run_state = run_state.map(|run_state| run_state.into_inner().unwrap());
+        let chronic_failures = match &run_state {
+            Some(run_state) => run_state.chronic_failures(options.max_consecutive_failures),
+            None => Vec::new(),
+        };
+        for record in &chronic_failures {
+            log::warn!(
+                "{} of {} in domain {} failed {} times in a row, last at {}: {}, to be investigated",
+                record.action,
+                record.ip,
+                record.domain_id,
+                record.consecutive_failures,
+                record.last_failure,
+                record.last_error.as_deref().unwrap_or("timeout")
+            );
+        }
+        let stalled_registrations = match run_state.as_mut() {
+            Some(run_state) => stalled_registrations(options, netshot_client, run_state),
+            None => Vec::new(),
+        };
+        if let Some(run_state) = run_state.as_ref().filter(|_| !options.check) {
+            // The failure history is kept even when the run fails below
+            run_state.save()?;
+        }
+        if let Some(audit) = audit {
+            audit.finalize(report::RunDetails {
+                read_only: options.read_only,
+                change_limits_exceeded: change_limits_exceeded
+                    .as_ref()
+                    .map(|exceeded| exceeded.to_string()),
+                http_requests,
+                chronic_failures,
+                snapshots,
+                consistency: consistency.clone(),
+                stalled_registrations,
+                degraded_features: features.degraded(),
+                pruned_groups,
+                netbox_devices_filter: Some(options.devices_filters().join(" OR ")),
+                client_certificates,
+                netshot_version: netshot_client.version.map(|version| version.to_string()),
+                netshot_apply_version: clients
+                    .netshot_apply
+                    .and_then(|client| client.version)
+                    .map(|version| version.to_string()),
+            })?;
+        }
+        #[cfg(feature = "csv-report")]
+        if let Some(csv_report) = csv_report {
+            csv_report.write()?;
+        }
+        if let (Some(directory), Some(netbox_inventory)) =
+            (&options.dump_inventories, netbox_inventory)
+        {
+            inventory::write_inventory(directory, inventory::NETBOX_FILE, &netbox_inventory)?;
+            let netshot_inventory = netshot_inventory.into_inner().unwrap();
+            inventory::write_inventory(directory, inventory::NETSHOT_FILE, &netshot_inventory)?;
+        }
+        if options.read_only {
+            log::info!(
+                "Read-only run {}, no change pushed to Netshot, {}",
+                options.run_id,
+                consistency
+            );
+        } else if options.check {
+            log::info!("Planned on the {}", consistency);
+        } else {
+            log_applied(&applied, None, Some(&consistency));
+            log_failures(&outcomes);
+        }
+
+        if let Some(error) = netbox_count_violation {
+            return Err(error);
+        }
+
+        if let Some(exceeded) = change_limits_exceeded.filter(|_| blocked) {
+            return Err(exceeded.into());
+        }
+
+        if failed_domains > 0 {
+            return Err(PartialFailure(format!(
+                "{} of {} domains failed to synchronize",
+                failed_domains,
+                targets.len()
+            ))
+            .into());
+        }
+
+        if !threshold_violations.is_empty() {
+            return Err(PartialFailure(threshold_violations.join("; ")).into());
+        }
+
+        if options.fail_on_drift {
+            println!("{}", metrics.drift_summary());
+            if metrics.has_drift() {
+                return Err(DriftDetected(format!(
+                    "Netshot drifted from Netbox, {} devices to register, {} to disable and {} to enable",
+                    metrics.to_register, metrics.to_disable, metrics.to_enable
+                ))
+                .into());
+            }
+        }
+
+        if let Some(run_state) = run_state.as_mut().filter(|_| !options.check) {
+            run_state.last_success = Some(Utc::now());
+            run_state.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// Split the Netbox devices between their Netshot domains, by role with --role-domain-map, by the custom field of
+/// their site with --netshot-domain-id-from-netbox-custom-field, all of them going to --netshot-domain-id otherwise
+pub fn domain_targets(
+    options: &RunOptions,
+    netbox_client: &netbox::NetboxClient,
+    netshot_client: &netshot::NetshotClient,
+    netbox_devices: Vec<netbox::Device>,
+) -> Result<Vec<DomainTarget>, Error> {
+    match &options.site_domain_field {
+        _ if !options.role_domain_map.is_empty() => {
+            role_domain_targets(options, netshot_client, netbox_devices)
+        }
+        Some(field) => site_domain_targets(
+            field,
+            netbox_client,
+            netshot_client,
+            options.netshot_domain_id,
+            netbox_devices,
+        ),
+        None => Ok(vec![DomainTarget {
+            domain_id: options.netshot_domain_id,
+            domain_name: domain_name(netshot_client, options.netshot_domain_id),
+            netbox_devices,
+        }]),
+    }
+}
+
+/// Run the preflight checks of the domain and of its plan, computed as in check mode, the checks of the clients
+/// being left to the caller which builds them
+pub fn preflight(
+    options: &RunOptions,
+    netbox_client: &netbox::NetboxClient,
+    netshot_client: &netshot::NetshotClient,
+) -> Vec<CheckResult> {
+    let mut options = options.clone();
+    options.check = true;
+    options.report = None;
+    options.mapping_file = None;
+    let mut checks = Vec::new();
+
+    let domains = netshot_client.get_domains();
+    let domain_name = domains.as_ref().ok().and_then(|domains| {
+        domains
+            .iter()
+            .find(|domain| domain.id == options.netshot_domain_id)
+            .map(|domain| domain.name.clone())
+    });
+    checks.push(preflight::domain_exists(domains, options.netshot_domain_id));
+
+    let netbox_devices =
+        match fetch_netbox_inventory(&options, netbox_client, &OptionalFeatures::default()) {
+            Ok((devices, _)) => devices,
+            Err(error) => {
+                checks.push(CheckResult::fail(
+                    "netbox-inventory",
+                    format!("{:#}", error),
+                ));
+                return checks;
+            }
+        };
+    checks.push(preflight::netbox_inventory(netbox_devices.len()));
+    if let Some(expectation) = &options.expect_netbox_count {
+        checks.push(CheckResult::from_result(
+            "netbox-count",
+            expectation.check("Netbox", netbox_devices.len()),
+            "The Netbox inventory size is as expected",
+        ));
+    }
+
+    let target = DomainTarget {
+        domain_id: options.netshot_domain_id,
+        domain_name,
+        netbox_devices,
+    };
+    // The Netshot count expectation is checked while planning, in check mode it fails the plan
+    match run_domain(
+        &options,
+        netbox_client,
+        netshot_client,
+        netshot_client,
+        Recorders::default(),
+        &target,
+    ) {
+        Ok((sync_plan, _)) => {
+            checks.push(preflight::duplicate_ips(&sync_plan));
+            checks.push(preflight::skipped_devices(&sync_plan));
+            checks.push(preflight::plan_summary(&sync_plan));
+        }
+        Err(error) => checks.push(CheckResult::fail("plan", format!("{:#}", error))),
+    }
+    checks
+}
+
+/// A domain planned, its plan waiting for the guards of the run before being applied
+pub struct PlannedDomain {
+    pub sync_plan: sync::SyncPlan,
+    pub netshot_devices: Vec<netshot::Device>,
+    /// The --expect-netshot-count violation, only left to the apply in check mode
+    pub netshot_count_violation: Option<Error>,
+}
+
+/// The plan of a domain with the summary of its applied changes
+pub type DomainResult = Result<(sync::SyncPlan, ReportSummary), Error>;
+
+/// Synchronize the Netbox devices of every target into its Netshot domain. Every domain is planned before the first
+/// change, so that the guards see the whole run: the change limits the plans exceed together are given along the
+/// results, nothing being applied then outside of check mode.
+pub fn run_domains<B: NetboxApi, N: NetshotApi, A: NetshotApi>(
+    options: &RunOptions,
+    netbox_client: &B,
+    netshot_client: &N,
+    apply_client: &A,
+    recorders: Recorders,
+    targets: &[DomainTarget],
+) -> Result<(Vec<DomainResult>, Option<ChangeLimitExceeded>), Error> {
+    let mut plans = domains::run_per_domain(targets, options.domain_concurrency, |target| {
+        plan_domain(
+            options,
+            netbox_client,
+            netshot_client,
+            apply_client,
+            recorders,
+            target,
+        )
+    });
+    let mut planned: Vec<&mut PlannedDomain> = plans
+        .iter_mut()
+        .filter_map(|plan| plan.as_mut().ok())
+        .collect();
+    let change_limits_exceeded = guard_plans(options, &mut planned)?;
+    let blocked = change_limits_exceeded.is_some() && !options.check;
+    let plans: HashMap<u32, Mutex<Option<Result<PlannedDomain, Error>>>> = targets
+        .iter()
+        .map(|target| target.domain_id)
+        .zip(plans.into_iter().map(|plan| Mutex::new(Some(plan))))
+        .collect();
+    let results = domains::run_per_domain(targets, options.domain_concurrency, |target| {
+        let planned = plans[&target.domain_id]
+            .lock()
+            .unwrap()
+            .take()
+            .expect("each domain is applied once")?;
+        match blocked {
+            true => Ok((planned.sync_plan, ReportSummary::default())),
+            false => apply_domain(options, apply_client, recorders, target, planned),
+        }
+    });
+    Ok((results, change_limits_exceeded))
+}
+
+/// Synchronize the Netbox devices of the target into its Netshot domain, guarded on its own as by the preflight checks
+pub fn run_domain<B: NetboxApi, N: NetshotApi, A: NetshotApi>(
+    options: &RunOptions,
+    netbox_client: &B,
+    netshot_client: &N,
+    apply_client: &A,
+    recorders: Recorders,
+    target: &DomainTarget,
+) -> Result<(sync::SyncPlan, ReportSummary), Error> {
+    let mut planned = plan_domain(
+        options,
+        netbox_client,
+        netshot_client,
+        apply_client,
+        recorders,
+        target,
+    )?;
+    if let Some(exceeded) = guard_plans(options, &mut [&mut planned])?.filter(|_| !options.check) {
+        return Err(exceeded.into());
+    }
+    apply_domain(options, apply_client, recorders, target, planned)
+}
+
+/// Plan the synchronization of the Netbox devices of the target into its Netshot domain, without changing Netshot
+pub fn plan_domain<B: NetboxApi, N: NetshotApi, A: NetshotApi>(
+    options: &RunOptions,
+    netbox_client: &B,
+    netshot_client: &N,
+    apply_client: &A,
+    recorders: Recorders,
+    target: &DomainTarget,
+) -> Result<PlannedDomain, Error> {
+    let fetched = match &options.netshot_from_file {
+        Some(path) => {
+            log::info!("Loading devices list of Netshot from {}", path.display());
+            let devices = inventory::read_inventory(path)?;
+            let mut fetched = Fetched::single(
+                Source::NetshotDevices,
+                inventory::domain_devices(&devices, target.domain_id),
+            );
+            fetched.snapshot.domain_id = Some(target.domain_id);
+            fetched
+        }
+        None => {
+            log::info!("Getting devices list from Netshot");
+            netshot_client.get_devices(target.domain_id)?
+        }
+    };
+    if let Some(inventory) = recorders.netshot_inventory {
+        let mut devices = fetched.items.clone();
+        inventory::set_domain(&mut devices, target.domain_id);
+        inventory.lock().unwrap().append(&mut devices);
+    }
+    if let Some(snapshots) = recorders.snapshots {
+        snapshots.lock().unwrap().push(fetched.snapshot);
+    }
+    let mut netshot_devices = fetched.items;
+    exclusion::exclude_netshot_devices(&mut netshot_devices, &options.exclude_ip);
+    if let Some(history) = recorders.history {
+        let netshot_ips: HashSet<String> = netshot_devices
+            .iter()
+            .map(|device| sync::canonical_ip(&device.management_address.ip))
+            .collect();
+        history
+            .lock()
+            .unwrap()
+            .check_registrations(target.domain_id, |ip| netshot_ips.contains(ip));
+    }
+
+    let netshot_count_violation = options
+        .expect_netshot_count
+        .as_ref()
+        .and_then(|expectation| expectation.check("Netshot", netshot_devices.len()).err());
+    if let Some(error) = netshot_count_violation.as_ref() {
+        if !options.check {
+            return Err(anyhow!("{}", error));
+        }
+        log::error!("{}, computing the plan anyway in check mode", error);
+    }
+
+    let plan_options = sync::PlanOptions {
+        mapping: recorders
+            .mapping
+            .map(|mapping| mapping.lock().unwrap().netshot_ids())
+            .unwrap_or_default(),
+        ..options.plan.clone()
+    };
+    let mut sync_plan = sync::plan(&target.netbox_devices, &netshot_devices, &plan_options);
+
+    if let Some(mapping) = recorders.mapping {
+        let netbox_ids: HashSet<u32> = target
+            .netbox_devices
+            .iter()
+            .map(|device| device.id)
+            .collect();
+        let netshot_ids: HashSet<u32> = netshot_devices.iter().map(|device| device.id).collect();
+        let now = Utc::now();
+        let mut mapping = mapping.lock().unwrap();
+        mapping.prune(target.domain_id, &netbox_ids, &netshot_ids);
+        for device in &sync_plan.matched {
+            mapping.record(target.domain_id, device.netbox_id, device.netshot_id, now);
+        }
+    }
+
+    if options.match_any_netshot_address && !sync_plan.to_register.is_empty() {
+        let candidates = sync_plan.soft_match_candidates(&netshot_devices);
+        log::info!(
+            "Looking up the interface addresses of {} Netshot devices",
+            candidates.len()
+        );
+        let mut secondary_addresses = HashMap::new();
+        for device in candidates {
+            for address in netshot_client.get_device_addresses(device.id)? {
+                secondary_addresses.entry(address).or_insert(device);
+            }
+        }
+        sync_plan.resolve_soft_matches(&secondary_addresses);
+    }
+
+    if options.verify_deletions && !sync_plan.to_disable.is_empty() {
+        log::info!(
+            "Verifying the deletion of {} devices on Netbox",
+            sync_plan.to_disable.len()
+        );
+        sync_plan.verify_deletions(|device| {
+            match netbox_client.deletion_status(&device.ip, !options.vms_filters().is_empty())? {
+                netbox::DeletionStatus::Deleted => Ok(true),
+                netbox::DeletionStatus::Decommissioned(name) => {
+                    log::debug!("{}({}) is decommissioned on Netbox", name, device.ip);
+                    Ok(true)
+                }
+                netbox::DeletionStatus::OutOfScope(name) => {
+                    log::info!(
+                        "{}({}) is out of scope but still in service on Netbox as {}, left untouched",
+                        device.name,
+                        device.ip,
+                        name
+                    );
+                    Ok(false)
+                }
+            }
+        })?;
+    }
+
+    if let Some(days) = options.purge_disabled_after {
+        sync_plan.purge_disabled(
+            &netshot_devices,
+            chrono::Duration::days(days.into()),
+            Utc::now(),
+        );
+    }
+
+    if !options.apply.credential_set_ids.is_empty() {
+        log::info!(
+            "Checking the credential sets of {} Netshot devices",
+            sync_plan.matched.len()
+        );
+        sync_plan.plan_credential_sets(&options.apply.credential_set_ids, |device_id| {
+            netshot_client.get_device_credential_set_ids(device_id)
+        })?;
+    }
+
+    if !options.sync_attributes.is_empty() {
+        log::info!(
+            "Checking the attributes of {} Netshot devices",
+            sync_plan.matched.len()
+        );
+        let netbox_devices: HashMap<u32, &netbox::Device> = target
+            .netbox_devices
+            .iter()
+            .map(|device| (device.id, device))
+            .collect();
+        sync_plan.plan_attributes(options.apply.parallelism, |device| {
+            let lines = match netbox_devices.get(&device.netbox_id) {
+                Some(netbox_device) => attributes::comment_lines(
+                    netbox_device,
+                    &options.sync_attributes,
+                    &options.netbox_url,
+                ),
+                None => return Ok(None),
+            };
+            let current = netshot_client
+                .get_device_comments(device.netshot_id)?
+                .unwrap_or_default();
+            let comments = attributes::merge_comments(&current, &lines);
+            Ok((comments != current).then_some(comments))
+        })?;
+    }
+
+    let stale_group_members = match options.stale_action.group_name() {
+        Some(group_name) => {
+            stale_group_members(options, netshot_client, group_name, &netshot_devices)?
+        }
+        None => HashSet::new(),
+    };
+    sync_plan.apply_stale_action(&options.stale_action, &stale_group_members);
+
+    // The stale devices are deferred whatever their stale action, the purges being the most destructive changes
+    if let Some(disable_window) = options.disable_window {
+        if !disable_window.contains_now(options.window_timezone) {
+            for (action, devices) in [
+                (Action::Disable, &sync_plan.to_disable),
+                (Action::AddToGroup, &sync_plan.to_add_to_group),
+                (Action::Purge, &sync_plan.to_purge),
+                (Action::PurgeDisabled, &sync_plan.to_purge_disabled),
+            ] {
+                for device in devices {
+                    log::debug!(
+                        "{}({}) {} deferred due to change window",
+                        device.name,
+                        device.ip,
+                        action
+                    );
+                }
+            }
+            sync_plan.defer_disables();
+            log::info!(
+                "Outside of the {} change window, {} disables, group additions and purges deferred",
+                disable_window,
+                sync_plan.deferred_disables.len()
+            );
+        }
+    }
+
+    if let Some(history) = recorders.history {
+        hold_failed_registrations(options, netshot_client, history, target, &mut sync_plan);
+        let history = history.lock().unwrap();
+        sync_plan.hold_onboarded(|ip| history.is_onboarded(target.domain_id, ip));
+        if !sync_plan.onboarded.is_empty() {
+            log::info!(
+                "Leaving disabled {} devices onboarded with --new-device-status DISABLED",
+                sync_plan.onboarded.len()
+            );
+        }
+    }
+
+    if let Some(max_pending_discoveries) = options.max_pending_discoveries {
+        if !sync_plan.to_register.is_empty() {
+            match apply_client.count_pending_discoveries() {
+                Ok(pending) if pending > max_pending_discoveries => {
+                    log::warn!(
+                        "Netshot has {} pending discoveries, above the maximum of {}, {} registrations deferred",
+                        pending,
+                        max_pending_discoveries,
+                        sync_plan.to_register.len()
+                    );
+                    sync_plan.defer_registrations();
+                }
+                Ok(pending) => log::info!("Netshot has {} pending discoveries", pending),
+                Err(error) => log::warn!(
+                    "Unable to count the pending discoveries on Netshot, registering anyway: {}",
+                    error
+                ),
+            }
+        }
+    }
+
+    Ok(PlannedDomain {
+        sync_plan,
+        netshot_devices,
+        netshot_count_violation,
+    })
+}
+
+/// Check the plans of every domain before any of them is applied, failing the run on the IPs shared by several
+/// devices with --fail-on-duplicate-ip, even in check mode. The change limits the plans exceed together are given,
+/// to block them all outside of check mode, and the --changes-budget is spent over them.
+pub fn guard_plans(
+    options: &RunOptions,
+    planned: &mut [&mut PlannedDomain],
+) -> Result<Option<ChangeLimitExceeded>, Error> {
+    let duplicates: usize = planned
+        .iter()
+        .map(|planned| planned.sync_plan.netbox_conflicts.len() + planned.sync_plan.conflicts.len())
+        .sum();
+    if options.fail_on_duplicate_ip && duplicates > 0 {
+        return Err(anyhow!(
+            "{} IPs are shared by several devices on Netbox or Netshot (--fail-on-duplicate-ip)",
+            duplicates
+        ));
+    }
+
+    // The limits apply to the whole plan of the run, the budget only spreading it over several runs
+    let (mut changes, mut stale_changes, mut netshot_count) = (0, 0, 0);
+    for planned in planned.iter() {
+        let sync_plan = &planned.sync_plan;
+        let stale = sync_plan.to_disable.len()
+            + sync_plan.to_add_to_group.len()
+            + sync_plan.to_purge.len()
+            + sync_plan.to_purge_disabled.len();
+        changes += sync_plan.to_register.len() + sync_plan.to_enable.len() + stale;
+        stale_changes += stale;
+        netshot_count += planned.netshot_devices.len();
+    }
+    let exceeded = options
+        .change_limits
+        .check(changes, stale_changes, netshot_count)
+        .err();
+    match &exceeded {
+        None => {}
+        Some(exceeded) if options.check => {
+            log::warn!("{}, an apply would have been blocked", exceeded)
+        }
+        Some(exceeded) => {
+            log::error!("{}, nothing is applied", exceeded);
+            for planned in planned.iter() {
+                for (action, devices) in planned.sync_plan.actions() {
+                    for device in devices {
+                        log::warn!("{} of {}({}) blocked", action, device.name, device.ip);
+                    }
+                }
+            }
+        }
+    }
+
+    // The budget only spends what the limits let through, shared by the domains in the --budget-order
+    if let Some(changes_budget) = options.changes_budget {
+        let mut plans: Vec<&mut sync::SyncPlan> = planned
+            .iter_mut()
+            .map(|planned| &mut planned.sync_plan)
+            .collect();
+        sync::apply_budget(&mut plans, changes_budget, &options.budget_order);
+    }
+    Ok(exceeded)
+}
+
+/// Apply the plan of a domain once guarded, recording it first
+pub fn apply_domain<A: NetshotApi>(
+    options: &RunOptions,
+    apply_client: &A,
+    recorders: Recorders,
+    target: &DomainTarget,
+    planned: PlannedDomain,
+) -> Result<(sync::SyncPlan, ReportSummary), Error> {
+    let PlannedDomain {
+        mut sync_plan,
+        netshot_devices,
+        netshot_count_violation,
+    } = planned;
+
+    if let Some(changes_budget) = options.changes_budget {
+        let deferred = &sync_plan.budget_deferred;
+        if !deferred.is_empty() {
+            for (action, devices) in [
+                (Action::Register, &deferred.to_register),
+                (Action::Enable, &deferred.to_enable),
+                (Action::Disable, &deferred.to_disable),
+                (Action::UpdateCredentials, &deferred.to_update_credentials),
+                (Action::FixPrefixLength, &deferred.to_fix_prefix_length),
+                (Action::UpdateAddress, &deferred.to_update_address),
+                (Action::Rename, &deferred.to_rename),
+                (Action::UpdateAttributes, &deferred.to_update_attributes),
+                (Action::AddToGroup, &deferred.to_add_to_group),
+                (Action::RemoveFromGroup, &deferred.to_remove_from_group),
+                (Action::Purge, &deferred.to_purge),
+                (Action::PurgeDisabled, &deferred.to_purge_disabled),
+            ] {
+                for device in devices {
+                    log::debug!(
+                        "{}({}) {} deferred due to changes budget",
+                        device.name,
+                        device.ip,
+                        action
+                    );
+                }
+            }
+            log::info!(
+                "Changes budget of {} reached, deferred {} registrations, {} enables, {} disables, {} credential updates, {} prefix length fixes, {} address updates, {} renames, {} attribute updates, {} group additions, {} group removals, {} purges and {} disabled device purges",
+                changes_budget,
+                deferred.to_register.len(),
+                deferred.to_enable.len(),
+                deferred.to_disable.len(),
+                deferred.to_update_credentials.len(),
+                deferred.to_fix_prefix_length.len(),
+                deferred.to_update_address.len(),
+                deferred.to_rename.len(),
+                deferred.to_update_attributes.len(),
+                deferred.to_add_to_group.len(),
+                deferred.to_remove_from_group.len(),
+                deferred.to_purge.len(),
+                deferred.to_purge_disabled.len()
+            );
+        }
+    }
+
+    log::info!(
+        "Found {} devices missing on Netshot, to be added",
+        sync_plan.to_register.len()
+    );
+    log::info!(
+        "Found {} devices missing on Netbox, to be disabled",
+        sync_plan.to_disable.len()
+    );
+    if let Some(group_name) = options.stale_action.group_name() {
+        log::info!(
+            "Found {} devices missing on Netbox, to be added to group {}",
+            sync_plan.to_add_to_group.len(),
+            group_name
+        );
+        log::info!(
+            "Found {} devices of group {} back on Netbox, to be removed from it",
+            sync_plan.to_remove_from_group.len(),
+            group_name
+        );
+    }
+    if options.stale_action == sync::StaleAction::Purge {
+        log::info!(
+            "Found {} devices missing on Netbox, to be purged",
+            sync_plan.to_purge.len()
+        );
+    }
+    if let Some(days) = options.purge_disabled_after {
+        log::info!(
+            "Found {} devices missing on Netbox and disabled on Netshot for more than {} days, to be purged",
+            sync_plan.to_purge_disabled.len(),
+            days
+        );
+    }
+    log::info!(
+        "Found {} devices disabled on Netshot but present on Netbox, to be enabled",
+        sync_plan.to_enable.len()
+    );
+    if !options.apply.credential_set_ids.is_empty() {
+        log::info!(
+            "Found {} devices with other credential sets on Netshot, to be updated",
+            sync_plan.to_update_credentials.len()
+        );
+    }
+    if !options.sync_attributes.is_empty() {
+        log::info!(
+            "Found {} devices with other attributes on Netshot, to be updated",
+            sync_plan.to_update_attributes.len()
+        );
+    }
+    log::info!(
+        "Found {} devices whose Netshot prefix length differs from the Netbox one{}",
+        sync_plan.prefix_mismatches.len(),
+        if options.plan.fix_prefix_length {
+            ", to be updated"
+        } else {
+            ""
+        }
+    );
+    if options.verify_deletions {
+        log::info!(
+            "Found {} devices out of the Netbox filter but still in service, left untouched",
+            sync_plan.out_of_scope.len()
+        );
+    }
+    if options.plan.sync_names {
+        log::info!(
+            "Found {} devices renamed on Netbox, to be renamed on Netshot",
+            sync_plan.to_rename.len()
+        );
+    }
+    if options.plan.update_changed_ips {
+        log::info!(
+            "Found {} devices re-addressed on Netbox, to be updated on Netshot",
+            sync_plan.to_update_address.len()
+        );
+    } else if recorders.mapping.is_some() {
+        log::info!(
+            "Found {} devices mapped to a Netshot device with another address, to be fixed manually",
+            sync_plan.address_changes.len()
+        );
+    }
+    log::info!(
+        "Found {} disabled devices whose IP now belongs to another Netbox device, to be renamed or resolved manually",
+        sync_plan.name_conflicts.len()
+    );
+    log::info!(
+        "Found {} IPs shared by several devices on Netshot, to be resolved manually",
+        sync_plan.conflicts.len()
+    );
+    if !sync_plan.netbox_conflicts.is_empty() {
+        log::warn!(
+            "Found {} primary IPs shared by several devices on Netbox, only the first device of each being synchronized",
+            sync_plan.netbox_conflicts.len()
+        );
+    }
+    if options.match_any_netshot_address {
+        log::info!(
+            "Found {} devices registered on Netshot with another address, to be fixed manually",
+            sync_plan.soft_matches.len()
+        );
+    }
+    for (reason, count) in sync_plan.skipped_by_reason() {
+        log::info!("Skipped {} Netbox devices ({})", count, reason);
+    }
+
+    if let Some(history) = recorders.history {
+        let mut history = history.lock().unwrap();
+        history.prune(target.domain_id, |ip, action| {
+            sync_plan.is_planned(ip, action)
+        });
+        sync_plan.annotate_failures(|ip, action| {
+            history.previous_failures(target.domain_id, ip, action)
+        });
+        for (action, devices) in sync_plan.actions() {
+            for device in devices {
+                if let Some(failures) = device.previous_failures {
+                    log::info!(
+                        "{}({}) {} failed {} times previously",
+                        device.name,
+                        device.ip,
+                        action,
+                        failures
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(audit) = recorders.audit {
+        audit.append(&AuditEntry::Plan {
+            domain_id: target.domain_id,
+            domain_name: target.domain_name.clone(),
+            plan: Box::new(report::reported_plan(&sync_plan)),
+        })?;
+    }
+    #[cfg(feature = "csv-report")]
+    if let Some(csv) = recorders.csv.filter(|_| options.check) {
+        csv.record_plan(target.domain_id, &sync_plan, Utc::now());
+    }
+
+    let mut applied = ReportSummary::default();
+    if !options.check {
+        // The IDs of the fetched devices are not those of the --netshot-apply-url devices
+        let netshot_ids_shared = !options.apply_to_other_netshot;
+        applied = apply::apply(
+            &sync_plan,
+            apply_client,
+            Some(netshot_devices.as_slice()).filter(|_| netshot_ids_shared),
+            target,
+            &options.apply,
+            recorders,
+        )?;
+    }
+
+    match netshot_count_violation {
+        Some(error) => Err(error),
+        None => Ok((sync_plan, applied)),
+    }
+}
+/// Look up the discovery task of the previous registration of the devices to register again, and hold back the ones
+/// which failed in a way registering them again does not fix, unless --retry-permanent-failures
+fn hold_failed_registrations<N: NetshotApi>(
+    options: &RunOptions,
+    netshot_client: &N,
+    history: &Mutex<RunState>,
+    target: &DomainTarget,
+    sync_plan: &mut sync::SyncPlan,
+) {
+    let planned: HashSet<&str> = sync_plan
+        .to_register
+        .iter()
+        .map(|device| device.ip.as_str())
+        .collect();
+    // The history is not locked during the queries, as the other domains record into it meanwhile
+    let tasks: Vec<(String, u32)> = history
+        .lock()
+        .unwrap()
+        .registrations
+        .iter()
+        .filter(|registration| {
+            registration.domain_id == target.domain_id && planned.contains(registration.ip.as_str())
+        })
+        .map(|registration| (registration.ip.clone(), registration.latest_task_id()))
+        .collect();
+    let mut failures = HashMap::new();
+    for (ip, task_id) in tasks {
+        let task = match netshot_client.get_task(task_id) {
+            Ok(task) => task,
+            Err(error) => {
+                log::warn!(
+                    "Unable to get the discovery task {} of {}: {:#}",
+                    task_id,
+                    ip,
+                    error
+                );
+                continue;
+            }
+        };
+        if let Some(registration) =
+            history
+                .lock()
+                .unwrap()
+                .registrations
+                .iter_mut()
+                .find(|registration| {
+                    registration.ip == ip && registration.domain_id == target.domain_id
+                })
+        {
+            registration.record_task(&task);
+        }
+        let failure = match task.discovery_failure() {
+            Some(failure) => failure,
+            None => continue,
+        };
+        let reason = task.failure_reason();
+        if failure.is_permanent() && !options.retry_permanent_failures {
+            log::warn!(
+                "Holding back the registration of {}, its discovery (task {}) failed on a {} problem: {}, use --retry-permanent-failures to register it again",
+                ip,
+                task_id,
+                failure,
+                reason.as_deref().unwrap_or("no reason given")
+            );
+            failures.insert(ip, (failure, reason));
+        } else {
+            log::info!(
+                "Registering {} again, its discovery (task {}) failed on a {} problem",
+                ip,
+                task_id,
+                failure
+            );
+        }
+    }
+    sync_plan.hold_registrations(|ip| failures.get(ip).cloned());
+}
+
+/// The canonical IPs of the devices of the domain in the --stale-action group, none when it is to be created by the apply
+fn stale_group_members<N: NetshotApi>(
+    options: &RunOptions,
+    netshot_client: &N,
+    group_name: &str,
+    netshot_devices: &[netshot::Device],
+) -> Result<HashSet<String>, Error> {
+    let existing = netshot_client
+        .get_groups()?
+        .into_iter()
+        .find(|group| group.name == group_name);
+    let group = match existing {
+        Some(group) => group,
+        None if !options.apply.create_stale_group => {
+            return Err(anyhow!(
+                "The Netshot group {} does not exist, create it or use --create-groups",
+                group_name
+            ))
+        }
+        None => {
+            log::info!(
+                "The Netshot group {} does not exist, to be created",
+                group_name
+            );
+            return Ok(HashSet::new());
+        }
+    };
+    if !group.is_static() {
+        return Err(anyhow!(
+            "The Netshot group {} is not static, its members cannot be set",
+            group_name
+        ));
+    }
+
+    let member_ids: HashSet<u32> = group.static_devices.into_iter().collect();
+    Ok(netshot_devices
+        .iter()
+        .filter(|device| member_ids.contains(&device.id))
+        .map(|device| sync::canonical_ip(&device.management_address.ip))
+        .collect())
+}
+
+/// The name of a Netshot domain, only used to label the logs and the report
+fn domain_name(netshot_client: &netshot::NetshotClient, domain_id: u32) -> Option<String> {
+    match netshot_client.get_domains() {
+        Ok(domains) => domains
+            .into_iter()
+            .find(|domain| domain.id == domain_id)
+            .map(|domain| domain.name),
+        Err(error) => {
+            log::warn!("Unable to get the name of domain {}: {}", domain_id, error);
+            None
+        }
+    }
+}
+
+/// Split the Netbox devices between the Netshot domains named by the given custom field of their site
+fn site_domain_targets(
+    field: &str,
+    netbox_client: &netbox::NetboxClient,
+    netshot_client: &netshot::NetshotClient,
+    default_domain_id: u32,
+    netbox_devices: Vec<netbox::Device>,
+) -> Result<Vec<DomainTarget>, Error> {
+    // The filters name the custom fields with a cf_ prefix, the API without
+    let field = field.strip_prefix("cf_").unwrap_or(field);
+    let domains = netshot_client.get_domains().map_err(|error| {
+        anyhow!(
+            "Unable to list the Netshot domains to validate the ones of the Netbox sites: {}",
+            error
+        )
+    })?;
+    let mut site_ids: Vec<u32> = netbox_devices
+        .iter()
+        .filter_map(|device| device.site().map(|site| site.id))
+        .collect();
+    site_ids.sort_unstable();
+    site_ids.dedup();
+    // Not an optional enrichment: without the sites, every device would move to the default domain
+    let site_fields: HashMap<u32, serde_json::Value> = netbox_client
+        .get_site_custom_fields(&site_ids)
+        .map_err(|error| {
+            anyhow!(
+                "Unable to look up the Netbox sites to place the devices in their domains: {}",
+                error
+            )
+        })?
+        .into_iter()
+        .map(|(site_id, mut fields)| {
+            (
+                site_id,
+                fields.remove(field).unwrap_or(serde_json::Value::Null),
+            )
+        })
+        .collect();
+
+    let (targets, conflicts) =
+        domains::split_by_site(netbox_devices, default_domain_id, &site_fields, &domains);
+    for conflict in &conflicts {
+        log::warn!("Skipping {}", conflict);
+    }
+    if !conflicts.is_empty() {
+        log::warn!(
+            "{} Netbox devices skipped as the custom field {} of their site names no Netshot domain",
+            conflicts.len(),
+            field
+        );
+    }
+    for target in &targets {
+        log::info!(
+            "{} Netbox devices to synchronize into domain {}",
+            target.netbox_devices.len(),
+            target.label()
+        );
+    }
+    Ok(targets)
+}
+
+/// Split the Netbox devices between the Netshot domains their role is mapped to by --role-domain-map
+fn role_domain_targets(
+    options: &RunOptions,
+    netshot_client: &netshot::NetshotClient,
+    netbox_devices: Vec<netbox::Device>,
+) -> Result<Vec<DomainTarget>, Error> {
+    let domains = netshot_client.get_domains().map_err(|error| {
+        anyhow!(
+            "Unable to list the Netshot domains to validate the --role-domain-map ones: {}",
+            error
+        )
+    })?;
+    for role_domain in &options.role_domain_map {
+        if !domains
+            .iter()
+            .any(|domain| domain.id == role_domain.domain_id)
+        {
+            return Err(anyhow!(
+                "The --role-domain-map domain {} of role {} does not exist on Netshot",
+                role_domain.domain_id,
+                role_domain.role
+            ));
+        }
+    }
+
+    let (targets, unmapped) =
+        domains::split_by_role(netbox_devices, &options.role_domain_map, &domains);
+    for unmapped in &unmapped {
+        log::warn!("Skipping {}", unmapped);
+    }
+    if !unmapped.is_empty() {
+        log::warn!(
+            "{} Netbox devices skipped as their role is mapped to no Netshot domain by --role-domain-map",
+            unmapped.len()
+        );
+    }
+    for target in &targets {
+        log::info!(
+            "{} Netbox devices to synchronize into domain {}",
+            target.netbox_devices.len(),
+            target.label()
+        );
+    }
+    Ok(targets)
+}
+
+/// Warn about the registrations whose device is still not on Netshot after --registration-grace runs,
+/// looking up why their discovery task failed when requested
+fn stalled_registrations(
+    options: &RunOptions,
+    netshot_client: &netshot::NetshotClient,
+    run_state: &mut RunState,
+) -> Vec<PendingRegistration> {
+    let mut stalled = Vec::new();
+    for registration in run_state.stalled_registrations(options.registration_grace) {
+        if options.recheck_stalled_registrations {
+            match netshot_client.get_task(registration.latest_task_id()) {
+                Ok(task) => registration.record_task(&task),
+                Err(error) => log::warn!(
+                    "Unable to get the task {} of the registration of {}: {:#}",
+                    registration.latest_task_id(),
+                    registration.ip,
+                    error
+                ),
+            }
+        }
+        log::warn!(
+            "Registration not converging: {} is still missing in domain {} {} runs after its registration (task {}, {} attempts){}",
+            registration.ip,
+            registration.domain_id,
+            registration.runs,
+            registration.task_id,
+            registration.attempts,
+            match (&registration.task_status, &registration.failure_reason) {
+                (Some(status), Some(reason)) => format!(", task {}: {}", status, reason),
+                (Some(status), None) => format!(", task {}", status),
+                _ => String::new(),
+            }
+        );
+        stalled.push(registration.clone());
+    }
+    stalled
+}
+
+/// Log the summary of the applied changes, of a single domain or of the whole run
+fn log_applied(
+    applied: &ReportSummary,
+    target: Option<&DomainTarget>,
+    consistency: Option<&Consistency>,
+) {
+    let prefix = match target {
+        Some(target) => format!("Domain {}: applied", target.label()),
+        None => "Applied".to_string(),
+    };
+    let suffix = match consistency {
+        Some(consistency) => format!(", on the {}", consistency),
+        None => String::new(),
+    };
+    log::info!(
+        "{} {} registrations, {} disables, {} enables, {} credential updates, {} prefix length fixes, {} address updates, {} renames, {} attribute updates, {} group additions, {} group removals, {} purges and {} disabled device purges, {} unchanged, {} skipped, {} timeouts, {} failures, {} aborted{}",
+        prefix,
+        applied.registered,
+        applied.disabled,
+        applied.enabled,
+        applied.credentials_updated,
+        applied.prefixes_fixed,
+        applied.addresses_updated,
+        applied.renamed,
+        applied.attributes_updated,
+        applied.added_to_group,
+        applied.removed_from_group,
+        applied.purged,
+        applied.purged_disabled,
+        applied.unchanged,
+        applied.skipped,
+        applied.timeouts,
+        applied.failures,
+        applied.aborted,
+        suffix
+    );
+    if target.is_none() {
+        for (action, count) in &applied.failed_by_action {
+            log::warn!("Failed {} {} changes", count, action);
+        }
+    }
+}
+
+/// List the changes which failed with their error among the outcomes, once the run is over
+fn log_failures(outcomes: &[ActionOutcome]) {
+    let failures: Vec<&ActionOutcome> =
+        outcomes.iter().filter(|outcome| outcome.failed()).collect();
+    if failures.is_empty() {
+        return;
+    }
+    log::error!("{} changes failed:", failures.len());
+    for outcome in failures {
+        log::error!(
+            "  {} {} ({}) in domain {}: {}",
+            outcome.action,
+            outcome.name,
+            outcome.ip,
+            outcome.domain_id,
+            outcome.error.as_deref().unwrap_or("timed out")
+        );
+    }
+}
+
+/// Check the fail threshold and the skipped devices threshold against the outcome of a domain
+pub fn check_domain_thresholds(
+    options: &RunOptions,
+    applied: &ReportSummary,
+    skipped_devices: usize,
+) -> Result<(), Error> {
+    if options
+        .fail_threshold
+        .exceeded(applied.failed(), applied.attempted())
+    {
+        return Err(anyhow!(
+            "{} of {} changes failed, above the fail threshold of {}",
+            applied.failed(),
+            applied.attempted(),
+            options.fail_threshold
+        ));
+    }
+    if applied.aborted > 0 {
+        return Err(anyhow!(
+            "{} changes were aborted, as the Netshot token lacks the read-write privilege on the domain",
+            applied.aborted
+        ));
+    }
+    if let Some(threshold) = options.fail_on_skipped {
+        if skipped_devices > threshold {
+            return Err(anyhow!(
+                "{} Netbox devices have been skipped, above the threshold of {}",
+                skipped_devices,
+                threshold
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Use the IP of the management-only interface of the devices in place of their primary IP, when there is one
+fn prefer_management_interfaces<B: NetboxApi>(
+    netbox_client: &B,
+    features: &OptionalFeatures,
+    netbox_devices: &mut [netbox::Device],
+) -> Result<(), Error> {
+    let device_ids: Vec<u32> = netbox_devices
+        .iter()
+        .filter(|device| device.is_valid(true))
+        .map(|device| device.id)
+        .collect();
+    log::info!(
+        "Looking up the management interfaces of {} devices",
+        device_ids.len()
+    );
+    let mut management_ips = match features.call("management interface lookup", || {
+        netbox_client.get_management_ips(&device_ids)
+    })? {
+        Some(management_ips) => management_ips,
+        None => return Ok(()),
+    };
+
+    for device in netbox_devices
+        .iter_mut()
+        .filter(|device| device.is_valid(true))
+    {
+        match management_ips.remove(&device.id) {
+            Some(ip) if device.has_primary_ip(ip.address.split('/').next().unwrap_or_default()) => {}
+            Some(ip) => {
+                log::info!(
+                    "Device {} has the management interface IP {}, using it instead of its primary IP",
+                    device.display_name(),
+                    ip.address
+                );
+                device.set_management_ip(ip);
+            }
+            None => log::warn!(
+                "Device {} has no IP on a management-only interface, falling back to its primary IP",
+                device.display_name()
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Delete the empty groups created by netbox2netshot, once every membership update of the run is done.
+/// The --stale-action group emptied of the devices back on Netbox goes too with --create-groups,
+/// as the next runs create it again once a device goes stale, and is kept otherwise.
+pub fn prune_empty_groups<A: NetshotApi>(
+    options: &RunOptions,
+    apply_client: &A,
+) -> Vec<netshot::PrunedGroup> {
+    // The memberships changed during the run, the cached groups may be stale
+    let groups = match apply_client.refresh_groups() {
+        Ok(groups) => groups,
+        Err(error) => {
+            log::error!("Unable to list the groups to prune: {:#}", error);
+            return Vec::new();
+        }
+    };
+    let mut pruned = Vec::new();
+    for group in groups
+        .iter()
+        .filter(|group| group.is_managed() && group.is_empty())
+    {
+        if options.stale_action.group_name() == Some(group.name.as_str())
+            && !options.apply.create_stale_group
+        {
+            log::debug!(
+                "Keeping the empty --stale-action group {}, not created again without --create-groups",
+                group.name
+            );
+            continue;
+        }
+        match apply_client.delete_group(group) {
+            Ok(()) => {
+                log::info!("Deleted the empty group {}(id {})", group.name, group.id);
+                pruned.push(netshot::PrunedGroup {
+                    id: group.id,
+                    name: group.name.clone(),
+                });
+            }
+            Err(error) => log::error!(
+                "Failed to delete the empty group {}(id {}): {:#}",
+                group.name,
+                group.id,
+                error
+            ),
+        }
+    }
+    pruned
+}
+
+/// Fetch the Netbox devices, and the VMs when a filter is given for them
+pub fn fetch_netbox_inventory<B: NetboxApi>(
+    options: &RunOptions,
+    netbox_client: &B,
+    features: &OptionalFeatures,
+) -> Result<(Vec<netbox::Device>, Vec<FetchSnapshot>), Error> {
+    log::info!("Getting devices list from Netbox");
+    let mut snapshots = Vec::new();
+    let mut results = Vec::new();
+    for devices_filter in options.devices_filters() {
+        let fetched = netbox_client.get_devices(devices_filter)?;
+        // A raw GraphQL filter replaces the devices filter, which the devices then need not satisfy
+        if options.verify_filter && !options.graphql_filter {
+            verify_filter("--netbox-devices-filter", devices_filter, &fetched.items);
+        }
+        snapshots.push(fetched.snapshot);
+        results.push(fetched.items);
+    }
+    // The devices matched by any of the filters make up the inventory, the disables being planned against all of them
+    let mut netbox_devices = netbox::merge_devices(results);
+    if options.prefer_mgmt_interface {
+        prefer_management_interfaces(netbox_client, features, &mut netbox_devices)?;
+    }
+
+    let mut results = Vec::new();
+    for vms_filter in options.vms_filters() {
+        log::info!("Getting VMS list rom Netbox");
+        let vms = netbox_client.get_vms(&vms_filter)?;
+        if options.verify_filter {
+            verify_filter("--netbox-vms-filter", &vms_filter, &vms.items);
+        }
+        snapshots.push(vms.snapshot);
+        results.push(vms.items);
+    }
+    if !results.is_empty() {
+        log::debug!("Merging VMs and Devices lists");
+        netbox_devices.append(&mut netbox::merge_devices(results));
+    }
+    exclusion::exclude_netbox_devices(&mut netbox_devices, &options.exclude_ip, &options.plan);
+    for snapshot in &snapshots {
+        for drift in &snapshot.drift {
+            log::warn!("{} changed while being fetched: {}", snapshot.source, drift);
+        }
+    }
+    Ok((netbox_devices, snapshots))
+}
+
+/// Warn about the conditions of a Netbox filter the fetched devices do not satisfy, Netbox ignoring unknown parameters
+fn verify_filter(flag: &str, filter: &str, devices: &[netbox::Device]) {
+    if devices.is_empty() && !filter.is_empty() {
+        log::warn!(
+            "{} `{}` selects nothing, check its values exist on Netbox",
+            flag,
+            filter
+        );
+    }
+    let verification = filter::verify(filter, devices);
+    for mismatch in &verification.mismatches {
+        log::warn!("{} {}", flag, mismatch);
+    }
+    if !verification.unchecked.is_empty() {
+        log::info!(
+            "{} keys which cannot be verified locally: {}",
+            flag,
+            verification.unchecked.join(", ")
+        );
+    }
+}
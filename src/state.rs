@@ -0,0 +1,513 @@
+use crate::report::{Action, ActionOutcome, ActionStatus};
+use crate::rest::netshot::{DiscoveryFailure, Task};
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The version of the state file format, bumped on incompatible changes
+const STATE_VERSION: u32 = 1;
+
+/// The state file as written on disk
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    #[serde(default)]
+    last_success: Option<DateTime<Utc>>,
+    #[serde(default)]
+    failures: Vec<FailureRecord>,
+    #[serde(default)]
+    registrations: Vec<PendingRegistration>,
+    #[serde(default)]
+    onboarded: Vec<OnboardedDevice>,
+}
+
+/// An action which failed on a device in the previous runs, until it succeeds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[non_exhaustive]
+pub struct FailureRecord {
+    pub ip: String,
+    /// The domain of the device, the same IP being another device in another domain
+    #[serde(default)]
+    pub domain_id: u32,
+    pub action: Action,
+    pub consecutive_failures: u32,
+    pub last_failure: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// A registration whose device has not shown up in the Netshot inventory yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[non_exhaustive]
+pub struct PendingRegistration {
+    pub ip: String,
+    pub domain_id: u32,
+    /// The discovery task of the first registration
+    pub task_id: u32,
+    /// The discovery task of the last registration, when the device was registered again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_task_id: Option<u32>,
+    pub registered_at: DateTime<Utc>,
+    /// How many times the device was registered, as it is registered again on every run until it shows up
+    pub attempts: u32,
+    /// The number of runs since the first registration which did not find the device on Netshot
+    pub runs: u32,
+    /// The last known status of the discovery task
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_class: Option<DiscoveryFailure>,
+}
+
+/// A device registered then disabled with --new-device-status DISABLED, not to be enabled back by the next runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct OnboardedDevice {
+    pub ip: String,
+    pub domain_id: u32,
+    pub onboarded_at: DateTime<Utc>,
+}
+
+impl PendingRegistration {
+    /// The discovery task of the last registration
+    pub fn latest_task_id(&self) -> u32 {
+        self.last_task_id.unwrap_or(self.task_id)
+    }
+
+    /// Remember the status of the last discovery task and why it failed
+    pub fn record_task(&mut self, task: &Task) {
+        self.task_status = Some(task.status.clone());
+        self.failure_reason = task.failure_reason();
+        self.failure_class = task.discovery_failure();
+    }
+}
+
+/// What is remembered from one run to the next
+#[derive(Debug)]
+pub struct RunState {
+    path: PathBuf,
+    /// When the last run which applied its changes without error ended
+    pub last_success: Option<DateTime<Utc>>,
+    /// The actions which kept failing, by device
+    pub failures: Vec<FailureRecord>,
+    /// The registrations waiting for their device to show up on Netshot
+    pub registrations: Vec<PendingRegistration>,
+    /// The devices onboarded disabled, until they are enabled on Netshot or leave Netbox
+    pub onboarded: Vec<OnboardedDevice>,
+}
+
+impl RunState {
+    /// Load the state file, starting with an empty state when it does not exist yet or is corrupt.
+    /// A file from a newer release is refused.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut state = RunState {
+            path: path.to_path_buf(),
+            last_success: None,
+            failures: Vec::new(),
+            registrations: Vec::new(),
+            onboarded: Vec::new(),
+        };
+        if !path.exists() {
+            log::info!("No state file at {}, starting a new one", path.display());
+            return Ok(state);
+        }
+
+        let state_file: StateFile = match serde_json::from_str(&fs::read_to_string(path)?) {
+            Ok(state_file) => state_file,
+            Err(error) => {
+                log::warn!(
+                    "The state file {} is corrupt ({}), starting a new one",
+                    path.display(),
+                    error
+                );
+                return Ok(state);
+            }
+        };
+        if state_file.version != STATE_VERSION {
+            return Err(anyhow!(
+                "Unsupported version {} of the state file {}, expected {}",
+                state_file.version,
+                path.display(),
+                STATE_VERSION
+            ));
+        }
+        state.last_success = state_file.last_success;
+        state.failures = state_file.failures;
+        state.registrations = state_file.registrations;
+        state.onboarded = state_file.onboarded;
+        Ok(state)
+    }
+
+    /// The time left before a new run is allowed, if the last successful run is more recent than `min_interval`
+    pub fn too_soon(
+        &self,
+        min_interval: chrono::Duration,
+        now: DateTime<Utc>,
+    ) -> Option<chrono::Duration> {
+        let next_run = self.last_success? + min_interval;
+        (next_run > now).then(|| next_run - now)
+    }
+
+    /// How many times in a row the action failed on the device of the domain in the previous runs
+    pub fn previous_failures(&self, domain_id: u32, ip: &str, action: Action) -> u32 {
+        self.failures
+            .iter()
+            .find(|record| {
+                record.domain_id == domain_id && record.ip == ip && record.action == action
+            })
+            .map(|record| record.consecutive_failures)
+            .unwrap_or_default()
+    }
+
+    /// Count a failed or timed out action, forget the failures of an action which went through
+    pub fn record_outcome(&mut self, outcome: &ActionOutcome) {
+        let position = self.failures.iter().position(|record| {
+            record.domain_id == outcome.domain_id
+                && record.ip == outcome.ip
+                && record.action == outcome.action
+        });
+        match (outcome.status, position) {
+            (ActionStatus::Applied | ActionStatus::Unchanged, Some(position)) => {
+                self.failures.remove(position);
+            }
+            (ActionStatus::Applied | ActionStatus::Unchanged, None) => {}
+            // Nothing was attempted, the history is left as is
+            (ActionStatus::Skipped | ActionStatus::Aborted, _) => {}
+            (ActionStatus::Failed | ActionStatus::Timeout, Some(position)) => {
+                let record = &mut self.failures[position];
+                record.consecutive_failures += 1;
+                record.last_failure = outcome.timestamp;
+                record.last_error = outcome.error.clone();
+            }
+            (ActionStatus::Failed | ActionStatus::Timeout, None) => {
+                self.failures.push(FailureRecord {
+                    ip: outcome.ip.clone(),
+                    domain_id: outcome.domain_id,
+                    action: outcome.action,
+                    consecutive_failures: 1,
+                    last_failure: outcome.timestamp,
+                    last_error: outcome.error.clone(),
+                })
+            }
+        }
+    }
+
+    /// The actions which failed more than `max_consecutive_failures` times in a row
+    pub fn chronic_failures(&self, max_consecutive_failures: u32) -> Vec<FailureRecord> {
+        self.failures
+            .iter()
+            .filter(|record| record.consecutive_failures > max_consecutive_failures)
+            .cloned()
+            .collect()
+    }
+
+    /// Remember a registration, keeping the task of the first one when the device is registered again
+    pub fn record_registration(
+        &mut self,
+        ip: &str,
+        domain_id: u32,
+        task_id: u32,
+        registered_at: DateTime<Utc>,
+    ) {
+        match self
+            .registrations
+            .iter_mut()
+            .find(|registration| registration.ip == ip && registration.domain_id == domain_id)
+        {
+            Some(registration) => {
+                registration.attempts += 1;
+                registration.last_task_id = Some(task_id);
+            }
+            None => self.registrations.push(PendingRegistration {
+                ip: ip.to_string(),
+                domain_id,
+                task_id,
+                last_task_id: None,
+                registered_at,
+                attempts: 1,
+                runs: 0,
+                task_status: None,
+                failure_reason: None,
+                failure_class: None,
+            }),
+        }
+    }
+
+    /// Forget the registrations of the domain whose device is on Netshot, count a run for the others
+    pub fn check_registrations<F: Fn(&str) -> bool>(&mut self, domain_id: u32, on_netshot: F) {
+        self.registrations.retain_mut(|registration| {
+            if registration.domain_id != domain_id {
+                return true;
+            }
+            if on_netshot(&registration.ip) {
+                log::debug!(
+                    "The registration of {} (task {}) converged",
+                    registration.ip,
+                    registration.task_id
+                );
+                return false;
+            }
+            registration.runs += 1;
+            true
+        });
+    }
+
+    /// Remember a device onboarded disabled, so it is left disabled by the next runs
+    pub fn record_onboarded(&mut self, ip: &str, domain_id: u32, onboarded_at: DateTime<Utc>) {
+        if !self.is_onboarded(domain_id, ip) {
+            self.onboarded.push(OnboardedDevice {
+                ip: ip.to_string(),
+                domain_id,
+                onboarded_at,
+            });
+        }
+    }
+
+    /// Whether the device of the domain was onboarded disabled
+    pub fn is_onboarded(&self, domain_id: u32, ip: &str) -> bool {
+        self.onboarded
+            .iter()
+            .any(|device| device.domain_id == domain_id && device.ip == ip)
+    }
+
+    /// Forget the failures, the registrations and the onboarded devices of the domain whose action is no longer
+    /// planned, as their device left Netbox or got in sync another way
+    pub fn prune<F: Fn(&str, Action) -> bool>(&mut self, domain_id: u32, is_planned: F) {
+        self.failures.retain(|record| {
+            if record.domain_id != domain_id || is_planned(&record.ip, record.action) {
+                return true;
+            }
+            log::debug!(
+                "Forgetting the {} failures of {}, no longer planned",
+                record.action,
+                record.ip
+            );
+            false
+        });
+        self.registrations.retain(|registration| {
+            if registration.domain_id != domain_id || is_planned(&registration.ip, Action::Register)
+            {
+                return true;
+            }
+            log::debug!(
+                "Forgetting the registration of {} (task {}), no longer planned",
+                registration.ip,
+                registration.task_id
+            );
+            false
+        });
+        self.onboarded.retain(|device| {
+            if device.domain_id != domain_id || is_planned(&device.ip, Action::Enable) {
+                return true;
+            }
+            log::debug!(
+                "Forgetting that {} was onboarded disabled, it is enabled or left Netbox",
+                device.ip
+            );
+            false
+        });
+    }
+
+    /// The registrations whose device is still not on Netshot more than `grace` runs later
+    pub fn stalled_registrations(&mut self, grace: u32) -> Vec<&mut PendingRegistration> {
+        self.registrations
+            .iter_mut()
+            .filter(|registration| registration.runs > grace)
+            .collect()
+    }
+
+    /// Write the state file, through a temporary file so it is never left half written
+    pub fn save(&self) -> Result<(), Error> {
+        let state_file = StateFile {
+            version: STATE_VERSION,
+            last_success: self.last_success,
+            failures: self.failures.clone(),
+            registrations: self.registrations.clone(),
+            onboarded: self.onboarded.clone(),
+        };
+
+        let mut temporary_path = self.path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        let mut file = File::create(&temporary_path)?;
+        serde_json::to_writer_pretty(&mut file, &state_file)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimum_interval() {
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-state-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("state.json");
+        let last_success: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+        let min_interval = chrono::Duration::seconds(120);
+
+        let mut state = RunState::load(&path).unwrap();
+        assert_eq!(state.too_soon(min_interval, last_success), None);
+        state.last_success = Some(last_success);
+        state.save().unwrap();
+
+        let state = RunState::load(&path).unwrap();
+        assert_eq!(state.last_success, Some(last_success));
+        assert_eq!(
+            state.too_soon(min_interval, last_success + chrono::Duration::seconds(30)),
+            Some(chrono::Duration::seconds(90))
+        );
+        assert_eq!(
+            state.too_soon(min_interval, last_success + min_interval),
+            None
+        );
+
+        fs::write(&path, r#"{"version": 2}"#).unwrap();
+        assert!(RunState::load(&path).is_err());
+        fs::write(&path, "{").unwrap();
+        assert_eq!(RunState::load(&path).unwrap().last_success, None);
+    }
+
+    fn outcome(ip: &str, status: ActionStatus) -> ActionOutcome {
+        ActionOutcome {
+            timestamp: "2024-01-01T00:00:00Z".parse().unwrap(),
+            domain_id: 2,
+            action: Action::Register,
+            ip: ip.to_string(),
+            name: format!("device-{}", ip),
+            status,
+            netshot_id: None,
+            duration_ms: 120,
+            error: (status == ActionStatus::Failed).then(|| "Got status 500".to_string()),
+            parent: None,
+        }
+    }
+
+    #[test]
+    fn failure_history() {
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-failures-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("state.json");
+
+        let mut state = RunState::load(&path).unwrap();
+        for _ in 0..3 {
+            state.record_outcome(&outcome("10.0.0.1", ActionStatus::Failed));
+        }
+        state.record_outcome(&outcome("10.0.0.2", ActionStatus::Timeout));
+        state.record_outcome(&outcome("10.0.0.3", ActionStatus::Applied));
+        state.save().unwrap();
+
+        let mut state = RunState::load(&path).unwrap();
+        assert_eq!(state.previous_failures(2, "10.0.0.1", Action::Register), 3);
+        assert_eq!(state.previous_failures(2, "10.0.0.1", Action::Disable), 0);
+        assert_eq!(state.previous_failures(3, "10.0.0.1", Action::Register), 0);
+        assert_eq!(state.previous_failures(2, "10.0.0.2", Action::Register), 1);
+        assert_eq!(state.previous_failures(2, "10.0.0.3", Action::Register), 0);
+        let chronic = state.chronic_failures(2);
+        assert_eq!(chronic.len(), 1);
+        assert_eq!(chronic[0].ip, "10.0.0.1");
+        assert_eq!(chronic[0].last_error.as_deref(), Some("Got status 500"));
+
+        state.record_outcome(&outcome("10.0.0.1", ActionStatus::Applied));
+        assert_eq!(state.previous_failures(2, "10.0.0.1", Action::Register), 0);
+        assert!(state
+            .chronic_failures(0)
+            .iter()
+            .all(|record| record.ip == "10.0.0.2"));
+    }
+
+    #[test]
+    fn pruned_history() {
+        let directory =
+            std::env::temp_dir().join(format!("netbox2netshot-pruned-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let registered_at: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+
+        let mut state = RunState::load(&directory.join("state.json")).unwrap();
+        let mut other_domain = outcome("10.0.0.1", ActionStatus::Failed);
+        other_domain.domain_id = 3;
+        for outcome in [
+            outcome("10.0.0.1", ActionStatus::Failed),
+            outcome("10.0.0.2", ActionStatus::Failed),
+            other_domain,
+        ] {
+            state.record_outcome(&outcome);
+        }
+        state.record_registration("10.0.0.1", 2, 100, registered_at);
+        state.record_registration("10.0.0.2", 2, 101, registered_at);
+        state.record_registration("10.0.0.2", 3, 102, registered_at);
+        state.record_onboarded("10.0.0.1", 2, registered_at);
+        state.record_onboarded("10.0.0.2", 2, registered_at);
+
+        // 10.0.0.2 left the Netbox inventory of domain 2
+        state.prune(2, |ip, action| {
+            ip == "10.0.0.1" && (action == Action::Register || action == Action::Enable)
+        });
+        assert!(state.is_onboarded(2, "10.0.0.1"));
+        assert!(!state.is_onboarded(2, "10.0.0.2"));
+        assert_eq!(state.previous_failures(2, "10.0.0.1", Action::Register), 1);
+        assert_eq!(state.previous_failures(2, "10.0.0.2", Action::Register), 0);
+        assert_eq!(state.previous_failures(3, "10.0.0.1", Action::Register), 1);
+        assert_eq!(
+            state
+                .registrations
+                .iter()
+                .map(|registration| (registration.domain_id, registration.ip.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "10.0.0.1"), (3, "10.0.0.2")]
+        );
+    }
+
+    #[test]
+    fn pending_registrations() {
+        let directory = std::env::temp_dir().join(format!(
+            "netbox2netshot-registrations-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("state.json");
+        let registered_at: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+
+        let mut state = RunState::load(&path).unwrap();
+        state.record_registration("10.0.0.1", 2, 100, registered_at);
+        state.record_registration("10.0.0.2", 2, 101, registered_at);
+        state.record_registration("10.0.0.3", 3, 102, registered_at);
+        state.save().unwrap();
+
+        let mut state = RunState::load(&path).unwrap();
+        for _ in 0..3 {
+            state.check_registrations(2, |ip| ip == "10.0.0.2");
+            // The device is registered again as it is still missing
+            state.record_registration("10.0.0.1", 2, 200, registered_at);
+        }
+        let stalled = state.stalled_registrations(2);
+        assert_eq!(stalled.len(), 1);
+        assert_eq!(
+            (
+                stalled[0].ip.as_str(),
+                stalled[0].task_id,
+                stalled[0].attempts
+            ),
+            ("10.0.0.1", 100, 4)
+        );
+        assert_eq!(stalled[0].latest_task_id(), 200);
+        assert!(state.stalled_registrations(3).is_empty());
+        assert_eq!(state.registrations.len(), 2);
+        assert_eq!(state.registrations[1].runs, 0);
+    }
+}
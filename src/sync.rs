@@ -0,0 +1,3327 @@
+use crate::budget::BudgetOrder;
+use crate::domains;
+use crate::report::Action;
+use crate::rest::{netbox, netshot};
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Why a Netbox device has been left out of the synchronization
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum SkipReason {
+    MissingName,
+    MissingPrimaryIp,
+    InvalidPrimaryIp,
+    Ipv6NotAllowed,
+    SpecialPrimaryIp,
+    PoweredOffVm,
+    ChildDevice,
+}
+
+/// A Netbox device left out of the synchronization
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct SkippedDevice {
+    pub id: u32,
+    pub name: String,
+    pub reason: SkipReason,
+}
+
+/// What Netshot knows about a device, for the reviewers of the plan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct NetshotFacts {
+    pub family: Option<String>,
+    pub network_class: Option<String>,
+    pub software_version: Option<String>,
+}
+
+impl NetshotFacts {
+    /// The facts exposed by a Netshot device
+    pub fn of(device: &netshot::Device) -> Self {
+        NetshotFacts {
+            family: device.family.clone(),
+            network_class: device.network_class.clone(),
+            software_version: device.software_version.clone(),
+        }
+    }
+}
+
+impl fmt::Display for NetshotFacts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = |fact: &Option<String>| fact.clone().unwrap_or_else(|| "?".to_string());
+        write!(
+            f,
+            "{} {}, version {}",
+            unknown(&self.network_class),
+            unknown(&self.family),
+            unknown(&self.software_version)
+        )
+    }
+}
+
+/// A device on which an action is planned
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[non_exhaustive]
+pub struct PlannedDevice {
+    pub ip: String,
+    pub name: String,
+    /// Only known for the devices already on Netshot (disable and enable)
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub netshot: Option<NetshotFacts>,
+    /// The policy which planned the disable, for the devices to disable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disabled_by: Option<String>,
+    /// The prefix length of the Netbox primary IP, for the devices coming from Netbox
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix_length: Option<u8>,
+    /// The name of the parent device, for the Netbox child devices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// How many times in a row the planned action failed in the previous runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_failures: Option<u32>,
+    /// Why the action is planned, in the report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The Netshot comments to write, for the devices whose attributes to update
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comments: Option<String>,
+}
+
+/// A Netshot device present on Netbox too
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedDevice {
+    pub netbox_id: u32,
+    pub netshot_id: u32,
+    pub ip: String,
+    pub name: String,
+}
+
+/// A device sharing its IP with other devices
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ConflictingDevice {
+    pub id: u32,
+    pub name: String,
+}
+
+/// An IP used by several devices on the same side, which needs a manual resolution
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Conflict {
+    pub ip: String,
+    pub devices: Vec<ConflictingDevice>,
+}
+
+impl Conflict {
+    /// Add a device colliding with an existing one, to the conflict of their IP
+    fn record(
+        conflicts: &mut Vec<Conflict>,
+        ip: &str,
+        existing: ConflictingDevice,
+        device: ConflictingDevice,
+    ) {
+        match conflicts.iter_mut().find(|conflict| conflict.ip == ip) {
+            Some(conflict) => conflict.devices.push(device),
+            None => conflicts.push(Conflict {
+                ip: ip.to_string(),
+                devices: vec![existing, device],
+            }),
+        }
+    }
+}
+
+/// A registration held back, as the previous discovery of the device failed in a way registering it again does not fix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct HeldRegistration {
+    #[serde(flatten)]
+    pub device: PlannedDevice,
+    pub failure: netshot::DiscoveryFailure,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A Netbox device matching a secondary address of a Netshot device, rather than its management address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct SoftMatch {
+    pub netbox_device: PlannedDevice,
+    pub netshot_id: u32,
+    pub netshot_name: String,
+    pub netshot_ip: String,
+}
+
+/// A Netbox device mapped to a Netshot device registered with another address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AddressChange {
+    pub netbox_device: PlannedDevice,
+    pub netshot_id: u32,
+    pub netshot_name: String,
+    pub netshot_ip: String,
+}
+
+/// A disabled Netshot device whose IP is now the primary IP of a differently named Netbox device, which enabling would
+/// monitor under the wrong name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct NameConflict {
+    pub ip: String,
+    pub netshot_id: u32,
+    pub netshot_name: String,
+    pub netbox_name: String,
+}
+
+/// A device whose management address prefix length on Netshot differs from the Netbox primary IP one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PrefixMismatch {
+    pub ip: String,
+    pub netshot_id: u32,
+    pub netshot_name: String,
+    pub netbox_prefix_length: u8,
+    pub netshot_prefix_length: u8,
+}
+
+/// The actions left over by the changes budget, for a later run
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[non_exhaustive]
+pub struct BudgetDeferred {
+    pub to_register: Vec<PlannedDevice>,
+    pub to_disable: Vec<PlannedDevice>,
+    pub to_enable: Vec<PlannedDevice>,
+    pub to_update_credentials: Vec<PlannedDevice>,
+    pub to_fix_prefix_length: Vec<PlannedDevice>,
+    pub to_update_address: Vec<PlannedDevice>,
+    pub to_rename: Vec<PlannedDevice>,
+    pub to_update_attributes: Vec<PlannedDevice>,
+    pub to_add_to_group: Vec<PlannedDevice>,
+    pub to_remove_from_group: Vec<PlannedDevice>,
+    pub to_purge: Vec<PlannedDevice>,
+    pub to_purge_disabled: Vec<PlannedDevice>,
+}
+
+impl BudgetDeferred {
+    /// The number of actions left over
+    pub fn len(&self) -> usize {
+        self.to_register.len()
+            + self.to_disable.len()
+            + self.to_enable.len()
+            + self.to_update_credentials.len()
+            + self.to_fix_prefix_length.len()
+            + self.to_update_address.len()
+            + self.to_rename.len()
+            + self.to_update_attributes.len()
+            + self.to_add_to_group.len()
+            + self.to_remove_from_group.len()
+            + self.to_purge.len()
+            + self.to_purge_disabled.len()
+    }
+
+    /// Whether nothing has been left over
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The actions left over, with their devices
+    pub fn actions(&self) -> [(Action, &Vec<PlannedDevice>); 12] {
+        [
+            (Action::Register, &self.to_register),
+            (Action::Disable, &self.to_disable),
+            (Action::Enable, &self.to_enable),
+            (Action::UpdateCredentials, &self.to_update_credentials),
+            (Action::FixPrefixLength, &self.to_fix_prefix_length),
+            (Action::UpdateAddress, &self.to_update_address),
+            (Action::Rename, &self.to_rename),
+            (Action::UpdateAttributes, &self.to_update_attributes),
+            (Action::AddToGroup, &self.to_add_to_group),
+            (Action::RemoveFromGroup, &self.to_remove_from_group),
+            (Action::Purge, &self.to_purge),
+            (Action::PurgeDisabled, &self.to_purge_disabled),
+        ]
+    }
+}
+
+/// The actions required to bring Netshot in sync with Netbox
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[non_exhaustive]
+pub struct SyncPlan {
+    pub to_register: Vec<PlannedDevice>,
+    pub to_disable: Vec<PlannedDevice>,
+    pub to_enable: Vec<PlannedDevice>,
+    pub to_update_credentials: Vec<PlannedDevice>,
+    pub to_fix_prefix_length: Vec<PlannedDevice>,
+    /// The devices re-addressed on Netbox, whose Netshot device is to be moved to the new address
+    pub to_update_address: Vec<PlannedDevice>,
+    /// The devices renamed on Netbox, with --sync-names
+    pub to_rename: Vec<PlannedDevice>,
+    /// The devices whose Netshot comments miss the --sync-attributes
+    pub to_update_attributes: Vec<PlannedDevice>,
+    /// The stale devices to move into the --stale-action group
+    pub to_add_to_group: Vec<PlannedDevice>,
+    /// The members of the --stale-action group back on Netbox
+    pub to_remove_from_group: Vec<PlannedDevice>,
+    /// The stale devices to delete from Netshot
+    pub to_purge: Vec<PlannedDevice>,
+    /// The stale devices disabled on Netshot for longer than --purge-disabled-after, to delete
+    pub to_purge_disabled: Vec<PlannedDevice>,
+    pub skipped: Vec<SkippedDevice>,
+    pub conflicts: Vec<Conflict>,
+    /// The primary IPs of several Netbox devices, only the first of which is synchronized
+    pub netbox_conflicts: Vec<Conflict>,
+    /// The stale devices left as they are until the change window, whatever their stale action
+    pub deferred_disables: Vec<PlannedDevice>,
+    pub deferred_registrations: Vec<PlannedDevice>,
+    /// The registrations held back until --retry-permanent-failures
+    pub held_registrations: Vec<HeldRegistration>,
+    /// The devices onboarded disabled with --new-device-status DISABLED, left disabled until enabled on Netshot
+    pub onboarded: Vec<PlannedDevice>,
+    pub out_of_scope: Vec<PlannedDevice>,
+    pub soft_matches: Vec<SoftMatch>,
+    pub address_changes: Vec<AddressChange>,
+    /// The devices not enabled, as their IP now belongs to another Netbox device
+    pub name_conflicts: Vec<NameConflict>,
+    pub prefix_mismatches: Vec<PrefixMismatch>,
+    pub budget_deferred: BudgetDeferred,
+    #[serde(skip)]
+    pub matched: Vec<MatchedDevice>,
+}
+
+/// What to do with the Netbox devices without a name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NamelessPolicy {
+    /// Register them under a `netbox-id-<id>` pseudo-name
+    #[default]
+    Register,
+    Skip,
+}
+
+/// How a Netbox device is found on Netshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchOn {
+    /// By its management IP
+    #[default]
+    Ip,
+    /// By its name, the nameless devices being matched by IP
+    Name,
+    /// By its IP or its name
+    Both,
+}
+
+/// What to do with the Netshot devices missing from the Netbox inventory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingPolicy {
+    #[default]
+    Disable,
+    /// Leave them as they are
+    Ignore,
+}
+
+/// What to do with the stale Netshot devices, the ones planned to be disabled
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StaleAction {
+    #[default]
+    Disable,
+    /// Add them to the named static group, and remove them from it when they reappear on Netbox
+    Group(String),
+    /// Delete them from Netshot
+    Purge,
+}
+
+impl StaleAction {
+    /// The name of the static group the stale devices go to, if any
+    pub fn group_name(&self) -> Option<&str> {
+        match self {
+            StaleAction::Group(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// How a Netbox device status counts when computing the disables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    /// Registered and enabled, as the devices without a configured status
+    Present,
+    /// Handled as missing from Netbox
+    Absent,
+    /// Neither registered, enabled nor disabled
+    Ignore,
+}
+
+/// The presence of the Netbox device statuses, e.g. `active=present,offline=absent,planned=ignore`.
+/// The statuses left out are present.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatusPresence(pub BTreeMap<String, Presence>);
+
+/// The Netshot device statuses counting as disabled, the devices present on Netbox with one of them being enabled,
+/// e.g. `DISABLED,PREPRODUCTION`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisabledStatuses(pub Vec<netshot::DeviceStatus>);
+
+impl Default for DisabledStatuses {
+    fn default() -> Self {
+        DisabledStatuses(vec![netshot::DeviceStatus::Disabled])
+    }
+}
+
+impl DisabledStatuses {
+    /// Whether the Netshot device has one of the statuses
+    pub fn contains(&self, device: &netshot::Device) -> bool {
+        self.0
+            .iter()
+            .any(|status| status.is(device.status.as_deref()))
+    }
+}
+
+impl FromStr for DisabledStatuses {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|status| !status.trim().is_empty())
+            .map(|status| match status.parse()? {
+                netshot::DeviceStatus::InProduction => Err(anyhow!(
+                    "INPRODUCTION cannot count as disabled, the devices present on Netbox being enabled into it"
+                )),
+                status => Ok(status),
+            })
+            .collect::<Result<_, _>>()
+            .map(DisabledStatuses)
+    }
+}
+
+impl StatusPresence {
+    /// The presence of a device with the given status
+    pub fn of(&self, status: Option<&str>) -> Presence {
+        self.get(status).unwrap_or(Presence::Present)
+    }
+
+    /// The presence given to a status, if any
+    pub fn get(&self, status: Option<&str>) -> Option<Presence> {
+        status.and_then(|status| self.0.get(status)).copied()
+    }
+}
+
+/// The options changing how the plan is computed
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub struct PlanOptions {
+    pub nameless: NamelessPolicy,
+    pub allow_ipv6: bool,
+    /// Use the IPv6 primary address of the dual-stack devices rather than their IPv4 one
+    pub prefer_ipv6: bool,
+    /// Keep the loopback, link-local, unspecified and multicast primary IPs
+    pub allow_special_ips: bool,
+    /// The Netshot device ID of the Netbox device IDs known from the previous runs
+    pub mapping: HashMap<u32, u32>,
+    pub missing: MissingPolicy,
+    pub status_presence: StatusPresence,
+    /// The Netbox statuses whose devices are disabled on Netshot and never registered, whatever the missing policy,
+    /// unless the status presence names them
+    pub netbox_disable_statuses: Vec<String>,
+    /// Plan the update of the Netshot prefix lengths differing from the Netbox ones
+    pub fix_prefix_length: bool,
+    /// Leave the child devices out, only keeping the chassis
+    pub skip_child_devices: bool,
+    /// Synchronize every member of the virtual chassis, rather than only the master
+    pub keep_virtual_chassis_members: bool,
+    /// How the Netshot device statuses count: present ones may be disabled, absent ones enabled,
+    /// ignored ones neither. The statuses left out keep the default handling, only the disabled statuses being enabled.
+    pub netshot_status_policy: StatusPresence,
+    /// The Netshot statuses the devices present on Netbox are enabled from
+    pub netshot_disabled_statuses: DisabledStatuses,
+    pub match_on: MatchOn,
+    /// The custom field holding the management address, preferred over the primary IPs
+    pub ip_custom_field: Option<String>,
+    /// The tags of the Netbox devices left out of the synchronization
+    pub exclude_tags: Vec<String>,
+    /// Leave the Netshot devices of the tagged Netbox devices alone rather than disabling them
+    pub exclude_tag_keeps_netshot: bool,
+    /// Move the Netshot devices found by name or mapping under another address to their Netbox IP, rather than
+    /// leaving them to be fixed manually
+    pub update_changed_ips: bool,
+    /// Plan the renaming of the Netshot devices named differently on Netbox
+    pub sync_names: bool,
+    /// The domain suffix stripped from both names before comparing them, e.g. `example.com`
+    pub name_suffix: Option<String>,
+}
+
+impl FromStr for MatchOn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(MatchOn::Ip),
+            "name" => Ok(MatchOn::Name),
+            "both" => Ok(MatchOn::Both),
+            _ => Err(anyhow!(
+                "Unknown matching `{}`, expected ip, name or both",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for MissingPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(MissingPolicy::Disable),
+            "ignore" => Ok(MissingPolicy::Ignore),
+            _ => Err(anyhow!(
+                "Unknown missing devices policy `{}`, expected disable or ignore",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for StaleAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("group", name)) if !name.trim().is_empty() => {
+                Ok(StaleAction::Group(name.trim().to_string()))
+            }
+            None if s == "disable" => Ok(StaleAction::Disable),
+            None if s == "purge" => Ok(StaleAction::Purge),
+            _ => Err(anyhow!(
+                "Unknown stale action `{}`, expected disable, group:<name> or purge",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StaleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StaleAction::Disable => f.write_str("disable"),
+            StaleAction::Group(name) => write!(f, "group:{}", name),
+            StaleAction::Purge => f.write_str("purge"),
+        }
+    }
+}
+
+impl fmt::Display for MissingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MissingPolicy::Disable => "disable",
+            MissingPolicy::Ignore => "ignore",
+        })
+    }
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Presence::Present => "present",
+            Presence::Absent => "absent",
+            Presence::Ignore => "ignore",
+        })
+    }
+}
+
+impl FromStr for StatusPresence {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut presences = BTreeMap::new();
+        for pair in s.split(',') {
+            let (status, presence) = pair.split_once('=').ok_or_else(|| {
+                anyhow!(
+                    "Invalid status presence `{}`, expected <status>=present|absent|ignore",
+                    pair
+                )
+            })?;
+            let presence = match presence.trim() {
+                "present" => Presence::Present,
+                "absent" => Presence::Absent,
+                "ignore" => Presence::Ignore,
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown presence `{}` of status {}, expected present, absent or ignore",
+                        presence,
+                        status
+                    ))
+                }
+            };
+            presences.insert(status.trim().to_string(), presence);
+        }
+        Ok(StatusPresence(presences))
+    }
+}
+
+impl FromStr for NamelessPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "register" => Ok(NamelessPolicy::Register),
+            "skip" => Ok(NamelessPolicy::Skip),
+            _ => Err(anyhow!(
+                "Unknown nameless devices policy `{}`, expected register or skip",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            SkipReason::MissingName => "missing name",
+            SkipReason::MissingPrimaryIp => "missing primary IP",
+            SkipReason::InvalidPrimaryIp => "invalid primary IP",
+            SkipReason::Ipv6NotAllowed => "IPv6 primary IP while IPv6 is not allowed",
+            SkipReason::SpecialPrimaryIp => {
+                "loopback, link-local, unspecified or multicast primary IP"
+            }
+            SkipReason::PoweredOffVm => "powered-off VM",
+            SkipReason::ChildDevice => "child device",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl SyncPlan {
+    /// The Netshot devices whose management address is unknown to Netbox, they may have been registered with another address
+    pub fn soft_match_candidates<'a>(
+        &self,
+        netshot_devices: &'a [netshot::Device],
+    ) -> Vec<&'a netshot::Device> {
+        netshot_devices
+            .iter()
+            .filter(|device| {
+                self.to_disable
+                    .iter()
+                    .any(|planned| planned.ip == canonical_ip(&device.management_address.ip))
+            })
+            .collect()
+    }
+
+    /// Keep the devices to register matching a secondary address of a Netshot device (by address) out of the actions
+    pub fn resolve_soft_matches(
+        &mut self,
+        secondary_addresses: &HashMap<String, &netshot::Device>,
+    ) {
+        let mut to_register = Vec::new();
+        for device in self.to_register.drain(..) {
+            let netshot_device = match secondary_addresses.get(&device.ip) {
+                Some(netshot_device) => netshot_device,
+                None => {
+                    to_register.push(device);
+                    continue;
+                }
+            };
+            log::warn!(
+                "{}({}) matches an interface address of Netshot device {}({}), not registering it",
+                device.name,
+                device.ip,
+                netshot_device.name,
+                netshot_device.management_address.ip
+            );
+            let netshot_ip = canonical_ip(&netshot_device.management_address.ip);
+            self.to_disable.retain(|planned| planned.ip != netshot_ip);
+            self.soft_matches.push(SoftMatch {
+                netbox_device: device,
+                netshot_id: netshot_device.id,
+                netshot_name: netshot_device.name.clone(),
+                netshot_ip: netshot_device.management_address.ip.clone(),
+            });
+        }
+        self.to_register = to_register;
+    }
+
+    /// Only keep the disables of the devices confirmed as gone from Netbox, the others being left untouched
+    pub fn verify_deletions<F>(&mut self, mut is_gone: F) -> Result<(), Error>
+    where
+        F: FnMut(&PlannedDevice) -> Result<bool, Error>,
+    {
+        let mut to_disable = Vec::new();
+        for device in self.to_disable.drain(..) {
+            if is_gone(&device)? {
+                to_disable.push(device);
+            } else {
+                self.out_of_scope.push(device);
+            }
+        }
+        self.to_disable = to_disable;
+        Ok(())
+    }
+
+    /// Delete rather than disable the devices already disabled on Netshot for longer than `max_age`, as told by their
+    /// last change
+    pub fn purge_disabled(
+        &mut self,
+        netshot_devices: &[netshot::Device],
+        max_age: Duration,
+        now: DateTime<Utc>,
+    ) {
+        let disabled_since: HashMap<String, DateTime<Utc>> = netshot_devices
+            .iter()
+            .filter(|device| device.is_disabled())
+            .filter_map(|device| {
+                let since = device.last_change()?;
+                Some((canonical_ip(&device.management_address.ip), since))
+            })
+            .collect();
+        let mut to_disable = Vec::new();
+        for mut device in self.to_disable.drain(..) {
+            let age = disabled_since.get(&device.ip).map(|since| now - *since);
+            match age.filter(|age| *age > max_age) {
+                Some(age) => {
+                    log::info!(
+                        action = "purge_disabled",
+                        ip = device.ip.as_str(),
+                        hostname = device.name.as_str();
+                        "{}({}) has been disabled on Netshot for {} days, to be purged",
+                        device.name,
+                        device.ip,
+                        age.num_days()
+                    );
+                    device.disabled_by =
+                        Some(format!("disabled on Netshot for {} days", age.num_days()));
+                    self.to_purge_disabled.push(device);
+                }
+                None => to_disable.push(device),
+            }
+        }
+        self.to_disable = to_disable;
+    }
+
+    /// The devices planned for each action, in the order the actions are applied
+    pub fn actions(&self) -> [(Action, &Vec<PlannedDevice>); 12] {
+        [
+            (Action::Register, &self.to_register),
+            (Action::Disable, &self.to_disable),
+            (Action::Enable, &self.to_enable),
+            (Action::UpdateCredentials, &self.to_update_credentials),
+            (Action::FixPrefixLength, &self.to_fix_prefix_length),
+            (Action::UpdateAddress, &self.to_update_address),
+            (Action::Rename, &self.to_rename),
+            (Action::UpdateAttributes, &self.to_update_attributes),
+            (Action::AddToGroup, &self.to_add_to_group),
+            (Action::RemoveFromGroup, &self.to_remove_from_group),
+            (Action::Purge, &self.to_purge),
+            (Action::PurgeDisabled, &self.to_purge_disabled),
+        ]
+    }
+
+    /// Is the action planned on the device, in this run or in a later one as deferred or held back
+    pub fn is_planned(&self, ip: &str, action: Action) -> bool {
+        let deferred = [
+            (Action::Disable, &self.deferred_disables),
+            (Action::AddToGroup, &self.deferred_disables),
+            (Action::Purge, &self.deferred_disables),
+            (Action::PurgeDisabled, &self.deferred_disables),
+            (Action::Register, &self.deferred_registrations),
+        ];
+        let held = match action {
+            Action::Register => self
+                .held_registrations
+                .iter()
+                .any(|held| held.device.ip == ip),
+            Action::Enable => self.onboarded.iter().any(|device| device.ip == ip),
+            _ => false,
+        };
+        held || self
+            .actions()
+            .iter()
+            .chain(self.budget_deferred.actions().iter())
+            .chain(deferred.iter())
+            .any(|(planned, devices)| {
+                *planned == action && devices.iter().any(|device| device.ip == ip)
+            })
+    }
+
+    /// The lists of devices of each action, to annotate them
+    pub fn actions_mut(&mut self) -> [(Action, &mut Vec<PlannedDevice>); 12] {
+        [
+            (Action::Register, &mut self.to_register),
+            (Action::Disable, &mut self.to_disable),
+            (Action::Enable, &mut self.to_enable),
+            (Action::UpdateCredentials, &mut self.to_update_credentials),
+            (Action::FixPrefixLength, &mut self.to_fix_prefix_length),
+            (Action::UpdateAddress, &mut self.to_update_address),
+            (Action::Rename, &mut self.to_rename),
+            (Action::UpdateAttributes, &mut self.to_update_attributes),
+            (Action::AddToGroup, &mut self.to_add_to_group),
+            (Action::RemoveFromGroup, &mut self.to_remove_from_group),
+            (Action::Purge, &mut self.to_purge),
+            (Action::PurgeDisabled, &mut self.to_purge_disabled),
+        ]
+    }
+
+    /// Annotate the planned devices with the number of times their action failed in the previous runs
+    pub fn annotate_failures(&mut self, previous_failures: impl Fn(&str, Action) -> u32) {
+        for (action, devices) in self.actions_mut() {
+            for device in devices.iter_mut() {
+                let failures = previous_failures(&device.ip, action);
+                device.previous_failures = (failures > 0).then_some(failures);
+            }
+        }
+    }
+
+    /// Replace the disables with the stale action. With a group, the stale devices already in it are left as they are
+    /// and the members of the group back on Netbox (given by IP) are planned to leave it.
+    pub fn apply_stale_action(
+        &mut self,
+        stale_action: &StaleAction,
+        group_members: &HashSet<String>,
+    ) {
+        match stale_action {
+            StaleAction::Disable => {}
+            StaleAction::Group(name) => {
+                for device in self.to_disable.drain(..) {
+                    if group_members.contains(&device.ip) {
+                        log::debug!(
+                            "{}({}) already is in group {}",
+                            device.name,
+                            device.ip,
+                            name
+                        );
+                    } else {
+                        self.to_add_to_group.push(device);
+                    }
+                }
+                for device in &self.matched {
+                    if group_members.contains(&device.ip) {
+                        log::debug!(
+                            "{}({}) is back on Netbox, to be removed from group {}",
+                            device.name,
+                            device.ip,
+                            name
+                        );
+                        self.to_remove_from_group.push(PlannedDevice {
+                            ip: device.ip.clone(),
+                            name: device.name.clone(),
+                            netshot: None,
+                            disabled_by: None,
+                            prefix_length: None,
+                            parent: None,
+                            previous_failures: None,
+                            reason: None,
+                            comments: None,
+                        });
+                    }
+                }
+            }
+            StaleAction::Purge => self.to_purge.append(&mut self.to_disable),
+        }
+    }
+
+    /// Postpone the changes of the stale devices to a later run, e.g. when outside of the change window: their disables,
+    /// group additions and purges, along with the purges of the devices disabled for too long
+    pub fn defer_disables(&mut self) {
+        for devices in [
+            &mut self.to_disable,
+            &mut self.to_add_to_group,
+            &mut self.to_purge,
+            &mut self.to_purge_disabled,
+        ] {
+            self.deferred_disables.append(devices);
+        }
+    }
+
+    /// Postpone the registrations to a later run, e.g. when Netshot has too many pending discoveries
+    pub fn defer_registrations(&mut self) {
+        self.deferred_registrations.append(&mut self.to_register);
+    }
+
+    /// Hold back the registrations for which `permanent_failure` gives the failure of the previous discovery and its reason
+    pub fn hold_registrations(
+        &mut self,
+        permanent_failure: impl Fn(&str) -> Option<(netshot::DiscoveryFailure, Option<String>)>,
+    ) {
+        for device in std::mem::take(&mut self.to_register) {
+            match permanent_failure(&device.ip) {
+                Some((failure, reason)) => self.held_registrations.push(HeldRegistration {
+                    device,
+                    failure,
+                    reason,
+                }),
+                None => self.to_register.push(device),
+            }
+        }
+    }
+
+    /// Leave disabled the devices `is_onboarded` says were onboarded disabled, rather than enabling them
+    pub fn hold_onboarded<F: Fn(&str) -> bool>(&mut self, is_onboarded: F) {
+        let (onboarded, to_enable) = std::mem::take(&mut self.to_enable)
+            .into_iter()
+            .partition(|device| is_onboarded(&device.ip));
+        self.onboarded = onboarded;
+        self.to_enable = to_enable;
+    }
+
+    /// Spend what is left of the budget on the actions of the category, by IP, deferring the others
+    fn spend_budget(&mut self, action: Action, remaining: &mut usize) {
+        let (planned, deferred) = match action {
+            Action::Register => (&mut self.to_register, &mut self.budget_deferred.to_register),
+            Action::Disable => (&mut self.to_disable, &mut self.budget_deferred.to_disable),
+            Action::Enable => (&mut self.to_enable, &mut self.budget_deferred.to_enable),
+            Action::UpdateCredentials => (
+                &mut self.to_update_credentials,
+                &mut self.budget_deferred.to_update_credentials,
+            ),
+            Action::FixPrefixLength => (
+                &mut self.to_fix_prefix_length,
+                &mut self.budget_deferred.to_fix_prefix_length,
+            ),
+            Action::UpdateAddress => (
+                &mut self.to_update_address,
+                &mut self.budget_deferred.to_update_address,
+            ),
+            Action::Rename => (&mut self.to_rename, &mut self.budget_deferred.to_rename),
+            Action::UpdateAttributes => (
+                &mut self.to_update_attributes,
+                &mut self.budget_deferred.to_update_attributes,
+            ),
+            Action::AddToGroup => (
+                &mut self.to_add_to_group,
+                &mut self.budget_deferred.to_add_to_group,
+            ),
+            Action::RemoveFromGroup => (
+                &mut self.to_remove_from_group,
+                &mut self.budget_deferred.to_remove_from_group,
+            ),
+            Action::Purge => (&mut self.to_purge, &mut self.budget_deferred.to_purge),
+            Action::PurgeDisabled => (
+                &mut self.to_purge_disabled,
+                &mut self.budget_deferred.to_purge_disabled,
+            ),
+        };
+        planned.sort_by_cached_key(|device| (device.ip.parse::<IpAddr>().ok(), device.ip.clone()));
+        let kept = planned.len().min(*remaining);
+        deferred.extend(planned.drain(kept..));
+        *remaining -= kept;
+    }
+
+    /// Plan the update of the matched devices whose specific credential sets differ from the wanted ones
+    pub fn plan_credential_sets<F>(&mut self, wanted: &[u32], mut current: F) -> Result<(), Error>
+    where
+        F: FnMut(u32) -> Result<Vec<u32>, Error>,
+    {
+        let wanted: BTreeSet<u32> = wanted.iter().copied().collect();
+        for device in &self.matched {
+            let credential_set_ids: BTreeSet<u32> =
+                current(device.netshot_id)?.into_iter().collect();
+            if credential_set_ids != wanted {
+                log::debug!(
+                    "{}({}) has the credential sets {:?}, to be updated",
+                    device.name,
+                    device.ip,
+                    credential_set_ids
+                );
+                self.to_update_credentials.push(PlannedDevice {
+                    ip: device.ip.clone(),
+                    name: device.name.clone(),
+                    netshot: None,
+                    disabled_by: None,
+                    prefix_length: None,
+                    parent: None,
+                    previous_failures: None,
+                    reason: None,
+                    comments: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Plan the update of the matched devices `comments` gives new Netshot comments for, as they differ from the current ones,
+    /// with at most `parallelism` lookups at once
+    pub fn plan_attributes<F>(&mut self, parallelism: usize, comments: F) -> Result<(), Error>
+    where
+        F: Fn(&MatchedDevice) -> Result<Option<String>, Error> + Sync,
+    {
+        // The comments are looked up device by device, several at once
+        let mut outdated = HashMap::new();
+        domains::for_each_parallel(&self.matched, parallelism, comments, |device, comments| {
+            if let Some(comments) = comments.transpose()?.flatten() {
+                outdated.insert(device.netshot_id, comments);
+            }
+            Ok(false)
+        })?;
+        for device in &self.matched {
+            if let Some(comments) = outdated.remove(&device.netshot_id) {
+                log::debug!(
+                    "{}({}) has outdated attributes, to be updated",
+                    device.name,
+                    device.ip
+                );
+                self.to_update_attributes.push(PlannedDevice {
+                    ip: device.ip.clone(),
+                    name: device.name.clone(),
+                    netshot: None,
+                    disabled_by: None,
+                    prefix_length: None,
+                    parent: None,
+                    previous_failures: None,
+                    reason: None,
+                    comments: Some(comments),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the skipped devices per reason
+    pub fn skipped_by_reason(&self) -> BTreeMap<SkipReason, usize> {
+        let mut counts = BTreeMap::new();
+        for device in &self.skipped {
+            *counts.entry(device.reason).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+/// Whether an IP cannot be the management address of a real device: loopback, link-local, unspecified or multicast
+fn is_special_ip(ip: &IpAddr) -> bool {
+    let link_local = match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    };
+    link_local || ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+}
+
+/// The canonical form of an IP, as Netshot does not compress the IPv6 addresses as Netbox does. What is not an IP is
+/// kept as is, to match nothing else.
+pub(crate) fn canonical_ip(ip: &str) -> String {
+    ip.trim()
+        .parse::<IpAddr>()
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|_| ip.to_string())
+}
+
+/// A device name without its case, domain and separators, `None` for the IPs Netshot names the undiscovered devices with
+fn normalized_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    if name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    let host = name.split('.').next().unwrap_or_default();
+    Some(
+        host.chars()
+            .filter(|character| character.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect(),
+    )
+}
+
+/// The key of a device name when matching by name, its lowercase, `None` for the IPs Netshot names the undiscovered
+/// devices with
+fn name_key(name: &str) -> Option<String> {
+    let name = name.trim();
+    match name.parse::<IpAddr>() {
+        Ok(_) => None,
+        Err(_) => Some(name.to_lowercase()),
+    }
+}
+
+/// A device name compared by --sync-names: without its case nor the domain suffix, `None` for the IPs Netshot names
+/// the undiscovered devices with
+fn rename_key(name: &str, suffix: Option<&str>) -> Option<String> {
+    let name = name_key(name)?;
+    let stripped = suffix
+        .map(|suffix| format!(".{}", suffix.trim_start_matches('.').to_lowercase()))
+        .and_then(|suffix| name.strip_suffix(&suffix).map(str::to_string));
+    Some(stripped.unwrap_or(name))
+}
+
+/// Whether two device names designate different devices, once normalized
+fn names_differ(netshot_name: &str, netbox_name: &str) -> bool {
+    match (normalized_name(netshot_name), normalized_name(netbox_name)) {
+        (Some(netshot_name), Some(netbox_name)) => netshot_name != netbox_name,
+        _ => false,
+    }
+}
+
+/// The management address of a device in a custom field, a string or an IP address object, with an optional prefix
+/// length. `None` when the field is empty, or malformed with a warning, the primary IPs being used instead.
+fn custom_field_address(device: &netbox::Device, field: &str) -> Option<String> {
+    let address = match device.custom_field(field) {
+        Some(serde_json::Value::String(address)) => address.trim(),
+        Some(serde_json::Value::Object(object)) => object
+            .get("address")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+            .trim(),
+        _ => "",
+    };
+    if address.is_empty() {
+        log::debug!(
+            "{} has no address in its {} custom field, falling back to its primary IP",
+            device.display_name(),
+            field
+        );
+        return None;
+    }
+    let mut parts = address.splitn(2, '/');
+    let valid_ip = parts.next().unwrap_or_default().parse::<IpAddr>().is_ok();
+    let valid_prefix_length = parts.next().is_none_or(|prefix_length| {
+        prefix_length
+            .parse::<u8>()
+            .is_ok_and(|length| length <= 128)
+    });
+    if !valid_ip || !valid_prefix_length {
+        log::warn!(
+            "{} has a malformed {} custom field `{}`, falling back to its primary IP",
+            device.display_name(),
+            field,
+            address
+        );
+        return None;
+    }
+    Some(address.to_string())
+}
+
+/// Extract the management IP of a Netbox device with its prefix length, or the reason why it cannot be used
+pub(crate) fn netbox_device_ip(
+    device: &netbox::Device,
+    options: &PlanOptions,
+) -> Result<(String, Option<u8>), SkipReason> {
+    let allow_nameless = options.nameless == NamelessPolicy::Register;
+    if device.name.is_none() && !allow_nameless {
+        return Err(SkipReason::MissingName);
+    }
+    let custom_address = options
+        .ip_custom_field
+        .as_deref()
+        .and_then(|field| custom_field_address(device, field));
+    let (address, family) = match custom_address {
+        Some(address) => (address, None),
+        None => {
+            // The generic primary_ip is the last resort, some automations only fill this one
+            let primary_ip6 = device.primary_ip6.as_ref().filter(|_| options.allow_ipv6);
+            let primary_ip = match options.prefer_ipv6 {
+                true => primary_ip6.or(device.primary_ip4.as_ref()),
+                false => device.primary_ip4.as_ref().or(primary_ip6),
+            }
+            .or(device.primary_ip.as_ref())
+            .ok_or(SkipReason::MissingPrimaryIp)?;
+            (primary_ip.address.clone(), primary_ip.family)
+        }
+    };
+    let mut parts = address.split('/');
+    let address = parts.next().unwrap_or_default();
+    let prefix_length = parts
+        .next()
+        .and_then(|prefix_length| prefix_length.parse().ok());
+    let ip: IpAddr = address.parse().map_err(|_| SkipReason::InvalidPrimaryIp)?;
+    if (ip.is_ipv6() || family == Some(6)) && !options.allow_ipv6 {
+        return Err(SkipReason::Ipv6NotAllowed);
+    }
+    if is_special_ip(&ip) && !options.allow_special_ips {
+        return Err(SkipReason::SpecialPrimaryIp);
+    }
+    Ok((ip.to_string(), prefix_length))
+}
+
+/// The member synchronized for each virtual chassis, by chassis ID: the master unless another member owns the
+/// management IP, else the first member by position
+fn virtual_chassis_members(
+    netbox_devices: &[netbox::Device],
+    options: &PlanOptions,
+) -> HashMap<u32, u32> {
+    let mut members: HashMap<u32, (&netbox::Device, bool)> = HashMap::new();
+    for device in netbox_devices {
+        let chassis = match &device.virtual_chassis {
+            Some(chassis) => chassis,
+            None => continue,
+        };
+        let has_ip = netbox_device_ip(device, options).is_ok();
+        let rank = |device: &netbox::Device, has_ip: bool| {
+            (
+                has_ip,
+                device.is_virtual_chassis_master(),
+                device.vc_priority,
+                Reverse(device.vc_position.unwrap_or(u32::MAX)),
+                Reverse(device.id),
+            )
+        };
+        members
+            .entry(chassis.id)
+            .and_modify(|(kept, kept_has_ip)| {
+                if rank(device, has_ip) > rank(kept, *kept_has_ip) {
+                    *kept = device;
+                    *kept_has_ip = has_ip;
+                }
+            })
+            .or_insert((device, has_ip));
+    }
+    members
+        .into_iter()
+        .map(|(chassis_id, (device, _))| (chassis_id, device.id))
+        .collect()
+}
+
+/// Keep at most `budget` actions over the plans of every domain, spent by category in the given order, then by domain
+/// and by IP within each category
+pub fn apply_budget(plans: &mut [&mut SyncPlan], budget: usize, order: &BudgetOrder) {
+    let mut remaining = budget;
+    for action in &order.0 {
+        for sync_plan in plans.iter_mut() {
+            sync_plan.spend_budget(*action, &mut remaining);
+        }
+    }
+}
+
+/// Compare the Netbox and Netshot inventories and compute the actions to apply on Netshot
+pub fn plan(
+    netbox_devices: &[netbox::Device],
+    netshot_devices: &[netshot::Device],
+    options: &PlanOptions,
+) -> SyncPlan {
+    let mut sync_plan = SyncPlan::default();
+
+    log::debug!("Building netbox devices simplified inventory");
+    let mut netbox_inventory: Vec<PlannedDevice> = Vec::new();
+    let mut netbox_ips: HashSet<String> = HashSet::new();
+    let mut netbox_ids: HashMap<String, u32> = HashMap::new();
+    // The IPs of the devices whose status is absent, with that status, and of those to leave alone
+    let mut absent_ips: HashMap<String, String> = HashMap::new();
+    let mut ignored_ips: HashSet<String> = HashSet::new();
+    // The nameless devices go by a pseudo-name, which cannot be compared to the Netshot one
+    let mut nameless_ips: HashSet<String> = HashSet::new();
+    // The IPs of the devices with an excluded tag, with that tag
+    let mut excluded_tag_ips: HashMap<String, String> = HashMap::new();
+    // The IPs of the devices with a status to disable, with that status
+    let mut disabled_status_ips: HashMap<String, String> = HashMap::new();
+    let disables_status = |status: &Option<String>| {
+        status.as_ref().is_some_and(|status| {
+            options.netbox_disable_statuses.contains(status)
+                && !options.status_presence.0.contains_key(status)
+        })
+    };
+    let chassis_members = match options.keep_virtual_chassis_members {
+        true => HashMap::new(),
+        false => virtual_chassis_members(netbox_devices, options),
+    };
+    for device in netbox_devices {
+        let name = device.display_name();
+        if let Some(chassis) = &device.virtual_chassis {
+            if chassis_members
+                .get(&chassis.id)
+                .is_some_and(|&member| member != device.id)
+            {
+                log::debug!(
+                    "{} is a member of the virtual chassis {}, only its master is synchronized, skipping it",
+                    name,
+                    chassis.name.clone().unwrap_or_else(|| format!("netbox-id-{}", chassis.id))
+                );
+                continue;
+            }
+        }
+        let excluded_tag = device
+            .tags()
+            .into_iter()
+            .find(|tag| options.exclude_tags.contains(tag));
+        if let Some(tag) = excluded_tag {
+            log::debug!("{} has the excluded tag {}, skipping it", name, tag);
+            if let Ok((ip, _)) = netbox_device_ip(device, options) {
+                if options.exclude_tag_keeps_netshot {
+                    ignored_ips.insert(ip);
+                } else {
+                    excluded_tag_ips.insert(ip, tag);
+                }
+            }
+            continue;
+        }
+        let status = device.status();
+        // Unless their status is given a presence, the powered-off VMs would never be discovered
+        let powered_off = device.is_powered_off_vm()
+            && !disables_status(&status)
+            && !status
+                .as_ref()
+                .is_some_and(|status| options.status_presence.0.contains_key(status));
+        let device_ip = match netbox_device_ip(device, options) {
+            Ok(_) if powered_off => Err(SkipReason::PoweredOffVm),
+            Ok(_) if options.skip_child_devices && device.parent_device.is_some() => {
+                Err(SkipReason::ChildDevice)
+            }
+            device_ip => device_ip,
+        };
+        match device_ip {
+            Ok((ip, _)) if disables_status(&status) => {
+                log::debug!("{}({}) has the status {:?} to disable", name, ip, status);
+                disabled_status_ips.insert(ip, status.unwrap_or_default());
+            }
+            Ok((ip, prefix_length)) => match options.status_presence.of(status.as_deref()) {
+                Presence::Absent => {
+                    log::debug!("{}({}) has the absent status {:?}", name, ip, status);
+                    absent_ips.insert(ip, status.unwrap_or_default());
+                }
+                Presence::Ignore => {
+                    log::debug!("{}({}) has the ignored status {:?}", name, ip, status);
+                    ignored_ips.insert(ip);
+                }
+                Presence::Present => {
+                    if let Some(&existing_id) = netbox_ids.get(&ip) {
+                        let existing_name = netbox_inventory
+                            .iter()
+                            .find(|planned| planned.ip == ip)
+                            .map(|planned| planned.name.clone())
+                            .unwrap_or_default();
+                        log::warn!(
+                            "{}(id {}) and {}(id {}) share the same primary IP {} on Netbox, only the first is synchronized",
+                            existing_name,
+                            existing_id,
+                            name,
+                            device.id,
+                            ip
+                        );
+                        let existing = ConflictingDevice {
+                            id: existing_id,
+                            name: existing_name,
+                        };
+                        let device = ConflictingDevice {
+                            id: device.id,
+                            name,
+                        };
+                        Conflict::record(&mut sync_plan.netbox_conflicts, &ip, existing, device);
+                    } else {
+                        netbox_ips.insert(ip.clone());
+                        netbox_ids.insert(ip.clone(), device.id);
+                        if device.name.is_none() {
+                            nameless_ips.insert(ip.clone());
+                        }
+                        netbox_inventory.push(PlannedDevice {
+                            ip,
+                            name,
+                            netshot: None,
+                            disabled_by: None,
+                            prefix_length,
+                            parent: device.parent_name(),
+                            previous_failures: None,
+                            reason: None,
+                            comments: None,
+                        });
+                    }
+                }
+            },
+            Err(reason) => {
+                log::warn!("Device {} has {}, skipping it", name, reason);
+                sync_plan.skipped.push(SkippedDevice {
+                    id: device.id,
+                    name,
+                    reason,
+                });
+            }
+        }
+    }
+
+    log::debug!("Building netshot devices simplified inventory");
+    let netshot_ips: Vec<String> = netshot_devices
+        .iter()
+        .map(|device| canonical_ip(&device.management_address.ip))
+        .collect();
+    let mut netshot_inventory: HashMap<&str, &netshot::Device> = HashMap::new();
+    let mut conflicts: Vec<Conflict> = Vec::new();
+    for (device, ip) in netshot_devices.iter().zip(&netshot_ips) {
+        let ip = ip.as_str();
+        let existing = match netshot_inventory.get(ip) {
+            Some(existing) => existing,
+            None => {
+                netshot_inventory.insert(ip, device);
+                continue;
+            }
+        };
+        log::warn!(
+            "{}(id {}) and {}(id {}) share the same IP {} on Netshot, manual resolution needed",
+            existing.name,
+            existing.id,
+            device.name,
+            device.id,
+            ip
+        );
+        let existing = ConflictingDevice {
+            id: existing.id,
+            name: existing.name.clone(),
+        };
+        let device = ConflictingDevice {
+            id: device.id,
+            name: device.name.clone(),
+        };
+        Conflict::record(&mut conflicts, ip, existing, device);
+    }
+    let conflicting_ips: HashSet<String> = conflicts
+        .iter()
+        .map(|conflict| conflict.ip.clone())
+        .collect();
+
+    log::debug!(
+        "Simplified inventories: Netbox({}), Netshot({})",
+        netbox_inventory.len(),
+        netshot_inventory.len()
+    );
+
+    log::debug!("Comparing inventories");
+    let netshot_by_id: HashMap<u32, &netshot::Device> = netshot_devices
+        .iter()
+        .map(|device| (device.id, device))
+        .collect();
+    // The names shared by several devices on either side match none of them
+    let mut netshot_by_name: HashMap<String, Option<&netshot::Device>> = HashMap::new();
+    let mut netbox_name_counts: HashMap<String, usize> = HashMap::new();
+    if options.match_on != MatchOn::Ip || options.update_changed_ips {
+        for (device, ip) in netshot_devices.iter().zip(&netshot_ips) {
+            if let Some(key) = name_key(&device.name).filter(|_| !conflicting_ips.contains(ip)) {
+                netshot_by_name
+                    .entry(key)
+                    .and_modify(|existing| *existing = None)
+                    .or_insert(Some(device));
+            }
+        }
+        for device in &netbox_inventory {
+            if let Some(key) = name_key(&device.name).filter(|_| !nameless_ips.contains(&device.ip))
+            {
+                *netbox_name_counts.entry(key).or_default() += 1;
+            }
+        }
+    }
+    let name_match = |device: &PlannedDevice| {
+        let key = name_key(&device.name)?;
+        if netbox_name_counts.get(&key) != Some(&1) {
+            return None;
+        }
+        netshot_by_name.get(&key).copied().flatten()
+    };
+    let netbox_by_ip: HashMap<&str, &PlannedDevice> = netbox_inventory
+        .iter()
+        .map(|device| (device.ip.as_str(), device))
+        .collect();
+    let mut mapped_netshot_ids: HashSet<u32> = HashSet::new();
+    let address_fix = match options.update_changed_ips {
+        true => "to be updated",
+        false => "to be fixed manually",
+    };
+    for device in &netbox_inventory {
+        // The mapping from the previous runs comes first, it survives address changes
+        let mapped = options
+            .mapping
+            .get(&netbox_ids[&device.ip])
+            .and_then(|netshot_id| netshot_by_id.get(netshot_id))
+            .filter(|mapped| {
+                !conflicting_ips.contains(&canonical_ip(&mapped.management_address.ip))
+            });
+        if let Some(mapped) = mapped {
+            mapped_netshot_ids.insert(mapped.id);
+            if canonical_ip(&mapped.management_address.ip) != device.ip {
+                log::warn!(
+                    "{}({}) is mapped to Netshot device {}({}) registered with another address, {}",
+                    device.name,
+                    device.ip,
+                    mapped.name,
+                    mapped.management_address.ip,
+                    address_fix
+                );
+                sync_plan.address_changes.push(AddressChange {
+                    netbox_device: device.clone(),
+                    netshot_id: mapped.id,
+                    netshot_name: mapped.name.clone(),
+                    netshot_ip: mapped.management_address.ip.clone(),
+                });
+                continue;
+            }
+        }
+        let ip_match = netshot_inventory.get(device.ip.as_str()).copied();
+        let name_match = name_match(device);
+        let named_ip_match = ip_match
+            .filter(|_| options.match_on != MatchOn::Ip && !nameless_ips.contains(&device.ip));
+        if let Some(ip_match) = named_ip_match {
+            let same_device = name_match.is_some_and(|name_match| name_match.id == ip_match.id);
+            if !same_device && names_differ(&ip_match.name, &device.name) {
+                if options.match_on == MatchOn::Both {
+                    log::warn!(
+                        "{}({}) is matched by IP only, it is named {} on Netshot",
+                        device.name,
+                        device.ip,
+                        ip_match.name
+                    );
+                } else if name_match.is_none() {
+                    // Netshot would refuse to register the IP again
+                    log::warn!(
+                        "{}({}) is missing from Netshot by name but its IP is registered as {}, not registering it, to be renamed or resolved manually",
+                        device.name,
+                        device.ip,
+                        ip_match.name
+                    );
+                    sync_plan.name_conflicts.push(NameConflict {
+                        ip: device.ip.clone(),
+                        netshot_id: ip_match.id,
+                        netshot_name: ip_match.name.clone(),
+                        netbox_name: device.name.clone(),
+                    });
+                    continue;
+                }
+            }
+        }
+        let name_match = match (ip_match, options.match_on) {
+            (None, _) | (_, MatchOn::Name) => name_match
+                .filter(|name_match| canonical_ip(&name_match.management_address.ip) != device.ip),
+            _ => None,
+        };
+        if let Some(name_match) = name_match {
+            mapped_netshot_ids.insert(name_match.id);
+            log::warn!(
+                "{}({}) is matched by name only, it is registered on Netshot with the address {}, {}",
+                device.name,
+                device.ip,
+                name_match.management_address.ip,
+                address_fix
+            );
+            sync_plan.address_changes.push(AddressChange {
+                netbox_device: device.clone(),
+                netshot_id: name_match.id,
+                netshot_name: name_match.name.clone(),
+                netshot_ip: name_match.management_address.ip.clone(),
+            });
+            continue;
+        }
+        match ip_match {
+            Some(x) => log::debug!("{}({}) is present on both", x.name, device.ip),
+            None => {
+                log::debug!(
+                    action = "register",
+                    ip = device.ip.as_str(),
+                    hostname = device.name.as_str();
+                    "{}({}) missing from Netshot",
+                    device.name,
+                    device.ip
+                );
+                sync_plan.to_register.push(device.clone());
+            }
+        }
+    }
+    if options.update_changed_ips {
+        // Netshot keeps the configuration history of the devices moved to a new address
+        sync_plan.to_update_address = sync_plan
+            .address_changes
+            .iter()
+            .map(|change| change.netbox_device.clone())
+            .collect();
+    }
+
+    let mut seen_netshot_ips: HashSet<&str> = HashSet::new();
+    for (device, ip) in netshot_devices.iter().zip(&netshot_ips) {
+        let ip = ip.as_str();
+        if !seen_netshot_ips.insert(ip) {
+            continue;
+        }
+        if conflicting_ips.contains(ip) {
+            log::debug!("{}({}) has a conflict, not touching it", device.name, ip);
+        } else if netbox_ips.contains(ip) {
+            log::debug!("{}({}) is present on both", device.name, ip);
+            sync_plan.matched.push(MatchedDevice {
+                netbox_id: netbox_ids[ip],
+                netshot_id: device.id,
+                ip: ip.to_string(),
+                name: device.name.clone(),
+            });
+            let netbox_device = netbox_by_ip[ip];
+            if let (Some(netbox_prefix_length), Some(netshot_prefix_length)) = (
+                netbox_device.prefix_length,
+                device.management_address.prefix_length,
+            ) {
+                if netbox_prefix_length != netshot_prefix_length {
+                    log::warn!(
+                        "{}({}) has the prefix length /{} on Netshot but /{} on Netbox",
+                        device.name,
+                        ip,
+                        netshot_prefix_length,
+                        netbox_prefix_length
+                    );
+                    sync_plan.prefix_mismatches.push(PrefixMismatch {
+                        ip: ip.to_string(),
+                        netshot_id: device.id,
+                        netshot_name: device.name.clone(),
+                        netbox_prefix_length,
+                        netshot_prefix_length,
+                    });
+                    if options.fix_prefix_length {
+                        sync_plan.to_fix_prefix_length.push(PlannedDevice {
+                            name: device.name.clone(),
+                            ..netbox_device.clone()
+                        });
+                    }
+                }
+            }
+        } else if mapped_netshot_ids.contains(&device.id) {
+            log::debug!("{}({}) is mapped to a Netbox device", device.name, ip);
+        } else if ignored_ips.contains(ip) {
+            log::debug!("{}({}) has an ignored status on Netbox", device.name, ip);
+        } else if let Some(Presence::Absent | Presence::Ignore) =
+            options.netshot_status_policy.get(device.status.as_deref())
+        {
+            log::debug!(
+                "{}({}) has the status {:?} on Netshot, not disabling it",
+                device.name,
+                ip,
+                device.status
+            );
+        } else if options.missing == MissingPolicy::Ignore && !disabled_status_ips.contains_key(ip)
+        {
+            log::debug!("{}({}) is missing on Netbox, ignoring it", device.name, ip);
+        } else if disabled_status_ips.contains_key(ip)
+            && options.netshot_disabled_statuses.contains(device)
+        {
+            log::debug!(
+                "{}({}) has the status {} on Netbox and is already disabled on Netshot",
+                device.name,
+                ip,
+                disabled_status_ips[ip]
+            );
+        } else {
+            let facts = NetshotFacts::of(device);
+            let disabled_by = match (absent_ips.get(ip), excluded_tag_ips.get(ip)) {
+                _ if disabled_status_ips.contains_key(ip) => {
+                    format!("netbox-disable-statuses {}", disabled_status_ips[ip])
+                }
+                (Some(status), _) => format!("status-presence {}=absent", status),
+                (None, Some(tag)) => {
+                    log::warn!(
+                        "{}({}) is to be disabled on Netshot, its Netbox device having the excluded tag {} (unless --exclude-tag-keeps-netshot)",
+                        device.name,
+                        ip,
+                        tag
+                    );
+                    format!("excluded tag {}", tag)
+                }
+                (None, None) => "missing-means disable".to_string(),
+            };
+            log::debug!(
+                action = "disable",
+                ip = ip,
+                hostname = device.name.as_str();
+                "{}({}) to be disabled ({}): {}",
+                device.name,
+                ip,
+                disabled_by,
+                facts
+            );
+            sync_plan.to_disable.push(PlannedDevice {
+                ip: ip.to_string(),
+                name: device.name.clone(),
+                netshot: Some(facts),
+                disabled_by: Some(disabled_by),
+                prefix_length: None,
+                parent: None,
+                previous_failures: None,
+                reason: None,
+                comments: None,
+            });
+        }
+    }
+
+    let to_enable = |device: &netshot::Device| match options
+        .netshot_status_policy
+        .get(device.status.as_deref())
+    {
+        Some(presence) => presence == Presence::Absent,
+        None => options.netshot_disabled_statuses.contains(device),
+    };
+    for (device, ip) in netshot_devices
+        .iter()
+        .zip(&netshot_ips)
+        .filter(|(device, _)| to_enable(device))
+    {
+        let ip = ip.as_str();
+        if netbox_ips.contains(ip) && !conflicting_ips.contains(ip) {
+            // The IP may have been given to another device since this one was disabled, unless the mapping says otherwise
+            let netbox_device = netbox_by_ip[ip];
+            let mapped = options.mapping.get(&netbox_ids[ip]) == Some(&device.id);
+            if !mapped
+                && !nameless_ips.contains(ip)
+                && names_differ(&device.name, &netbox_device.name)
+            {
+                log::warn!(
+                    "{}({}) is disabled on Netshot but its IP now belongs to {} on Netbox, not enabling it, to be renamed or resolved manually",
+                    device.name,
+                    ip,
+                    netbox_device.name
+                );
+                sync_plan.name_conflicts.push(NameConflict {
+                    ip: ip.to_string(),
+                    netshot_id: device.id,
+                    netshot_name: device.name.clone(),
+                    netbox_name: netbox_device.name.clone(),
+                });
+                continue;
+            }
+            log::debug!(
+                action = "enable",
+                ip = ip,
+                hostname = device.name.as_str();
+                "{}({}) to be enabled (present on Netbox)",
+                device.name,
+                ip
+            );
+            sync_plan.to_enable.push(PlannedDevice {
+                ip: ip.to_string(),
+                name: device.name.clone(),
+                netshot: Some(NetshotFacts::of(device)),
+                disabled_by: None,
+                prefix_length: None,
+                parent: None,
+                previous_failures: None,
+                reason: None,
+                comments: None,
+            });
+        }
+    }
+
+    if options.sync_names {
+        let suffix = options.name_suffix.as_deref();
+        let matched_ids: HashSet<u32> = sync_plan
+            .matched
+            .iter()
+            .map(|matched| matched.netshot_id)
+            .collect();
+        let name_conflict_ips: HashSet<&str> = sync_plan
+            .name_conflicts
+            .iter()
+            .map(|conflict| conflict.ip.as_str())
+            .collect();
+        let mut to_rename = Vec::new();
+        for (device, ip) in netshot_devices.iter().zip(&netshot_ips) {
+            let ip = ip.as_str();
+            if !matched_ids.contains(&device.id)
+                || nameless_ips.contains(ip)
+                || name_conflict_ips.contains(ip)
+            {
+                continue;
+            }
+            let netbox_device = netbox_by_ip[ip];
+            let netshot_name = match rename_key(&device.name, suffix) {
+                Some(name) => name,
+                None => continue,
+            };
+            if rename_key(&netbox_device.name, suffix).is_some_and(|name| name != netshot_name) {
+                log::warn!(
+                    "{}({}) is named {} on Netbox, to be renamed",
+                    device.name,
+                    ip,
+                    netbox_device.name
+                );
+                to_rename.push(PlannedDevice {
+                    netshot: Some(NetshotFacts::of(device)),
+                    ..netbox_device.clone()
+                });
+            }
+        }
+        sync_plan.to_rename = to_rename;
+    }
+
+    sync_plan.conflicts = conflicts;
+    sync_plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn netbox_device(id: u32, name: Option<&str>, address: Option<&str>) -> netbox::Device {
+        let primary_ip4 = address.map(|address| json!({"id": id, "family": 4, "address": address}));
+        serde_json::from_value(json!({"id": id, "name": name, "primary_ip4": primary_ip4})).unwrap()
+    }
+
+    /// The devices of a Netbox list page fixture
+    fn netbox_page(path: &str) -> Vec<netbox::Device> {
+        let page: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        serde_json::from_value(page["results"].clone()).unwrap()
+    }
+
+    fn netshot_device(id: u32, name: &str, ip: &str, status: &str) -> netshot::Device {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": name,
+            "mgmtAddress": {"ip": ip},
+            "status": status,
+            "family": "Cisco Catalyst 2900",
+            "networkClass": "SWITCH"
+        }))
+        .unwrap()
+    }
+
+    fn netshot_facts() -> Option<NetshotFacts> {
+        Some(NetshotFacts {
+            family: Some("Cisco Catalyst 2900".to_string()),
+            network_class: Some("SWITCH".to_string()),
+            software_version: None,
+        })
+    }
+
+    #[test]
+    fn register_disable_and_enable() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("new-device"), Some("1.2.3.4/32")),
+            netbox_device(2, Some("known-device"), Some("1.2.3.5/32")),
+            netbox_device(3, Some("disabled-device"), Some("1.2.3.6/32")),
+        ];
+        let netshot_devices = vec![
+            netshot_device(1, "known-device", "1.2.3.5", "INPRODUCTION"),
+            netshot_device(2, "disabled-device", "1.2.3.6", "DISABLED"),
+            netshot_device(3, "old-device", "1.2.3.7", "INPRODUCTION"),
+        ];
+
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+
+        assert_eq!(
+            sync_plan,
+            SyncPlan {
+                to_register: vec![PlannedDevice {
+                    ip: "1.2.3.4".to_string(),
+                    name: "new-device".to_string(),
+                    netshot: None,
+                    disabled_by: None,
+                    prefix_length: Some(32),
+                    parent: None,
+                    previous_failures: None,
+                    reason: None,
+                    comments: None,
+                }],
+                to_disable: vec![PlannedDevice {
+                    ip: "1.2.3.7".to_string(),
+                    name: "old-device".to_string(),
+                    netshot: netshot_facts(),
+                    disabled_by: Some("missing-means disable".to_string()),
+                    prefix_length: None,
+                    parent: None,
+                    previous_failures: None,
+                    reason: None,
+                    comments: None,
+                }],
+                to_enable: vec![PlannedDevice {
+                    ip: "1.2.3.6".to_string(),
+                    name: "disabled-device".to_string(),
+                    netshot: netshot_facts(),
+                    disabled_by: None,
+                    prefix_length: None,
+                    parent: None,
+                    previous_failures: None,
+                    reason: None,
+                    comments: None,
+                }],
+                matched: vec![
+                    MatchedDevice {
+                        netbox_id: 2,
+                        netshot_id: 1,
+                        ip: "1.2.3.5".to_string(),
+                        name: "known-device".to_string(),
+                    },
+                    MatchedDevice {
+                        netbox_id: 3,
+                        netshot_id: 2,
+                        ip: "1.2.3.6".to_string(),
+                        name: "disabled-device".to_string(),
+                    },
+                ],
+                ..SyncPlan::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reassigned_ip_name_conflicts() {
+        // A was disabled at 10.0.0.5, which Netbox then gave to the new device B
+        let netbox_devices = vec![
+            netbox_device(1, Some("device-b"), Some("10.0.0.5/32")),
+            netbox_device(2, Some("Core-SW1"), Some("10.0.0.6/32")),
+            netbox_device(3, None, Some("10.0.0.7/32")),
+        ];
+        let netshot_devices = vec![
+            netshot_device(1, "device-a", "10.0.0.5", "DISABLED"),
+            netshot_device(2, "core_sw1.par1.example.net", "10.0.0.6", "DISABLED"),
+            netshot_device(3, "some-device", "10.0.0.7", "DISABLED"),
+        ];
+
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        assert_eq!(
+            sync_plan.name_conflicts,
+            vec![NameConflict {
+                ip: "10.0.0.5".to_string(),
+                netshot_id: 1,
+                netshot_name: "device-a".to_string(),
+                netbox_name: "device-b".to_string(),
+            }]
+        );
+        assert_eq!(
+            sync_plan
+                .to_enable
+                .iter()
+                .map(|device| device.ip.as_str())
+                .collect::<Vec<_>>(),
+            vec!["10.0.0.6", "10.0.0.7"]
+        );
+        assert!(sync_plan.to_register.is_empty());
+
+        // The mapping of the previous runs vouches for the device
+        let options = PlanOptions {
+            mapping: HashMap::from([(1, 1)]),
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert!(sync_plan.name_conflicts.is_empty());
+        assert_eq!(sync_plan.to_enable.len(), 3);
+
+        // Netshot names the devices it has not discovered yet after their IP
+        assert!(!names_differ("10.0.0.5", "device-b"));
+    }
+
+    #[test]
+    fn skipped_devices() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("no-ip"), None),
+            netbox_device(2, Some("bad-ip"), Some("not-an-ip/32")),
+            netbox_device(3, None, Some("1.2.3.4/32")),
+        ];
+
+        let sync_plan = plan(&netbox_devices, &[], &PlanOptions::default());
+
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(sync_plan.to_register[0].name, "netbox-id-3");
+        assert_eq!(
+            sync_plan
+                .skipped
+                .iter()
+                .map(|device| (device.id, device.reason))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, SkipReason::MissingPrimaryIp),
+                (2, SkipReason::InvalidPrimaryIp)
+            ]
+        );
+
+        let options = PlanOptions {
+            nameless: NamelessPolicy::Skip,
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &[], &options);
+
+        assert!(sync_plan.to_register.is_empty());
+        assert_eq!(sync_plan.skipped.len(), 3);
+        assert_eq!(sync_plan.skipped[2].reason, SkipReason::MissingName);
+        assert_eq!(sync_plan.skipped_by_reason()[&SkipReason::MissingName], 1);
+    }
+
+    #[test]
+    fn nameless_policy() {
+        assert_eq!(
+            "register".parse::<NamelessPolicy>().unwrap(),
+            NamelessPolicy::Register
+        );
+        assert_eq!(
+            "skip".parse::<NamelessPolicy>().unwrap(),
+            NamelessPolicy::Skip
+        );
+        assert!("rename".parse::<NamelessPolicy>().is_err());
+    }
+
+    #[test]
+    fn custom_field_ips() {
+        let body = std::fs::read_to_string("tests/data/netbox/custom_field_ips.json").unwrap();
+        let netbox_devices: Vec<netbox::Device> = serde_json::from_str(&body).unwrap();
+        let registered = |sync_plan: &SyncPlan| {
+            sync_plan
+                .to_register
+                .iter()
+                .map(|device| (device.name.clone(), device.ip.clone(), device.prefix_length))
+                .collect::<Vec<(String, String, Option<u8>)>>()
+        };
+        let device = |name: &str, ip: &str, prefix_length: Option<u8>| {
+            (name.to_string(), ip.to_string(), prefix_length)
+        };
+
+        let options = PlanOptions {
+            ip_custom_field: Some("oob_mgmt_ip".to_string()),
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &[], &options);
+        assert_eq!(
+            registered(&sync_plan),
+            vec![
+                device("edge-1", "10.99.0.1", Some(24)),
+                device("edge-2", "10.99.0.2", None),
+                // An IP address custom field
+                device("edge-3", "10.99.0.3", Some(32)),
+                // Empty or malformed, the primary IP is used
+                device("edge-4", "1.2.3.4", Some(32)),
+                device("edge-5", "1.2.3.5", Some(32)),
+                device("edge-6", "1.2.3.6", Some(32)),
+                // No primary IP is needed
+                device("edge-7", "10.99.0.7", Some(32)),
+            ]
+        );
+        assert_eq!(
+            sync_plan.skipped,
+            vec![SkippedDevice {
+                id: 8,
+                name: "edge-8".to_string(),
+                reason: SkipReason::MissingPrimaryIp,
+            }]
+        );
+
+        // The primary IPs only, without the option
+        let sync_plan = plan(&netbox_devices, &[], &PlanOptions::default());
+        assert_eq!(
+            registered(&sync_plan)[0],
+            device("edge-1", "1.2.3.1", Some(32))
+        );
+        assert_eq!(sync_plan.skipped.len(), 2);
+    }
+
+    #[test]
+    fn excluded_tags() {
+        let body = std::fs::read_to_string("tests/data/netbox/tagged_devices.json").unwrap();
+        let netbox_devices: Vec<netbox::Device> = serde_json::from_str(&body).unwrap();
+        let netshot_devices = vec![
+            netshot_device(10, "edge-1", "1.2.3.1", "INPRODUCTION"),
+            netshot_device(11, "lab-1", "1.2.3.2", "INPRODUCTION"),
+        ];
+        let ips = |devices: &[PlannedDevice]| {
+            devices
+                .iter()
+                .map(|device| device.ip.clone())
+                .collect::<Vec<String>>()
+        };
+
+        // The tagged devices are neither registered nor present, their Netshot devices disabled
+        let options = PlanOptions {
+            exclude_tags: vec!["netshot-ignore".to_string()],
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert!(sync_plan.to_register.is_empty());
+        assert_eq!(ips(&sync_plan.to_disable), vec!["1.2.3.2"]);
+        assert_eq!(
+            sync_plan.to_disable[0].disabled_by.as_deref(),
+            Some("excluded tag netshot-ignore")
+        );
+        assert!(sync_plan.skipped.is_empty());
+
+        // Or left alone
+        let options = PlanOptions {
+            exclude_tag_keeps_netshot: true,
+            ..options
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert!(sync_plan.to_register.is_empty());
+        assert!(sync_plan.to_disable.is_empty());
+
+        // Without the option, the tags make no difference
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        assert_eq!(ips(&sync_plan.to_register), vec!["1.2.3.3"]);
+        assert!(sync_plan.to_disable.is_empty());
+    }
+
+    #[test]
+    fn netbox_duplicate_ip() {
+        let body = std::fs::read_to_string("tests/data/netbox/duplicate_ip.json").unwrap();
+        let netbox_devices: Vec<netbox::Device> = serde_json::from_str(&body).unwrap();
+
+        let sync_plan = plan(&netbox_devices, &[], &PlanOptions::default());
+
+        assert_eq!(
+            sync_plan.netbox_conflicts,
+            vec![Conflict {
+                ip: "10.0.0.1".to_string(),
+                devices: vec![
+                    ConflictingDevice {
+                        id: 1,
+                        name: "edge-1".to_string(),
+                    },
+                    ConflictingDevice {
+                        id: 2,
+                        name: "edge-1-copy".to_string(),
+                    },
+                ],
+            }]
+        );
+        // The first device of the IP is kept, the other left out
+        let registered: Vec<(&str, &str)> = sync_plan
+            .to_register
+            .iter()
+            .map(|device| (device.ip.as_str(), device.name.as_str()))
+            .collect();
+        assert_eq!(
+            registered,
+            vec![("10.0.0.1", "edge-1"), ("10.0.0.3", "core-1")]
+        );
+        assert!(sync_plan.conflicts.is_empty());
+    }
+
+    #[test]
+    fn netshot_duplicate_ip() {
+        let body = std::fs::read_to_string("tests/data/netshot/duplicate_ip.json").unwrap();
+        let netshot_devices: Vec<netshot::Device> = serde_json::from_str(&body).unwrap();
+        let netbox_devices = vec![netbox_device(1, Some("test-device"), Some("1.2.3.4/32"))];
+
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+
+        let conflicting = |id: u32, name: &str| ConflictingDevice {
+            id,
+            name: name.to_string(),
+        };
+        assert_eq!(
+            sync_plan,
+            SyncPlan {
+                to_disable: vec![PlannedDevice {
+                    ip: "1.2.3.6".to_string(),
+                    name: "old-device".to_string(),
+                    netshot: Some(NetshotFacts {
+                        family: Some("Cisco Catalyst 2900".to_string()),
+                        network_class: None,
+                        software_version: None,
+                    }),
+                    disabled_by: Some("missing-means disable".to_string()),
+                    prefix_length: None,
+                    parent: None,
+                    previous_failures: None,
+                    reason: None,
+                    comments: None,
+                }],
+                conflicts: vec![
+                    Conflict {
+                        ip: "1.2.3.4".to_string(),
+                        devices: vec![
+                            conflicting(1, "test-device"),
+                            conflicting(2, "test-device-manual"),
+                        ],
+                    },
+                    Conflict {
+                        ip: "1.2.3.5".to_string(),
+                        devices: vec![
+                            conflicting(3, "orphan-device"),
+                            conflicting(4, "orphan-device-discovered"),
+                        ],
+                    },
+                ],
+                ..SyncPlan::default()
+            }
+        );
+    }
+
+    #[test]
+    fn prefix_mismatches() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("renumbered-device"), Some("1.2.3.4/24")),
+            netbox_device(2, Some("known-device"), Some("1.2.3.5/32")),
+            netbox_device(3, Some("unknown-prefix-device"), Some("1.2.3.6")),
+        ];
+        let mut netshot_devices = vec![
+            netshot_device(1, "renumbered-device", "1.2.3.4", "INPRODUCTION"),
+            netshot_device(2, "known-device", "1.2.3.5", "INPRODUCTION"),
+            netshot_device(3, "unknown-prefix-device", "1.2.3.6", "INPRODUCTION"),
+        ];
+        for device in &mut netshot_devices {
+            device.management_address.prefix_length = Some(32);
+        }
+
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        assert_eq!(
+            sync_plan.prefix_mismatches,
+            vec![PrefixMismatch {
+                ip: "1.2.3.4".to_string(),
+                netshot_id: 1,
+                netshot_name: "renumbered-device".to_string(),
+                netbox_prefix_length: 24,
+                netshot_prefix_length: 32,
+            }]
+        );
+        assert!(sync_plan.to_fix_prefix_length.is_empty());
+
+        let options = PlanOptions {
+            fix_prefix_length: true,
+            ..Default::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert_eq!(
+            sync_plan.to_fix_prefix_length,
+            vec![PlannedDevice {
+                ip: "1.2.3.4".to_string(),
+                name: "renumbered-device".to_string(),
+                netshot: None,
+                disabled_by: None,
+                prefix_length: Some(24),
+                parent: None,
+                previous_failures: None,
+                reason: None,
+                comments: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn powered_off_vms() {
+        let vms: Vec<netbox::Device> = netbox_page("tests/data/netbox/powered_off_vms.json")
+            .into_iter()
+            .map(|mut vm| {
+                vm.virtual_machine = true;
+                vm
+            })
+            .collect();
+
+        let sync_plan = plan(&vms, &[], &PlanOptions::default());
+        let registered: Vec<&str> = sync_plan
+            .to_register
+            .iter()
+            .map(|device| device.name.as_str())
+            .collect();
+        assert_eq!(registered, vec!["running-vm"]);
+        assert_eq!(
+            sync_plan.skipped_by_reason(),
+            BTreeMap::from([(SkipReason::PoweredOffVm, 2)])
+        );
+
+        // A status given a presence is handled as such
+        let options = PlanOptions {
+            status_presence: "paused=present".parse().unwrap(),
+            ..Default::default()
+        };
+        let sync_plan = plan(&vms, &[], &options);
+        let registered: Vec<&str> = sync_plan
+            .to_register
+            .iter()
+            .map(|device| device.name.as_str())
+            .collect();
+        assert_eq!(registered, vec!["running-vm", "paused-vm"]);
+        assert_eq!(sync_plan.skipped.len(), 1);
+    }
+
+    #[test]
+    fn child_devices() {
+        let devices = netbox_page("tests/data/netbox/chassis_with_line_cards.json");
+
+        let sync_plan = plan(&devices, &[], &PlanOptions::default());
+        assert_eq!(
+            sync_plan
+                .to_register
+                .iter()
+                .map(|device| (device.name.as_str(), device.parent.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("chassis-1", None),
+                ("chassis-1-lc1", Some("chassis-1")),
+                ("chassis-1-lc2", Some("netbox-id-1"))
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&sync_plan.to_register[1]).unwrap()["parent"],
+            "chassis-1"
+        );
+
+        let options = PlanOptions {
+            skip_child_devices: true,
+            ..Default::default()
+        };
+        let sync_plan = plan(&devices, &[], &options);
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(
+            sync_plan.skipped_by_reason(),
+            BTreeMap::from([(SkipReason::ChildDevice, 2)])
+        );
+    }
+
+    #[test]
+    fn virtual_chassis() {
+        let devices = netbox_page("tests/data/netbox/virtual_chassis.json");
+
+        // Only the master is synchronized, the other members are not warned about
+        let sync_plan = plan(&devices, &[], &PlanOptions::default());
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(sync_plan.to_register[0].name, "stack-1");
+        assert_eq!(sync_plan.to_register[0].ip, "10.0.1.1");
+        assert!(sync_plan.skipped.is_empty());
+
+        // Without an IP on the master, the member owning one stands for the stack
+        let mut without_master_ip = devices.clone();
+        without_master_ip[0].primary_ip4 = None;
+        without_master_ip[0].primary_ip = None;
+        let sync_plan = plan(&without_master_ip, &[], &PlanOptions::default());
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(sync_plan.to_register[0].name, "stack-1:3");
+
+        let options = PlanOptions {
+            keep_virtual_chassis_members: true,
+            ..Default::default()
+        };
+        let sync_plan = plan(&devices, &[], &options);
+        assert_eq!(sync_plan.to_register.len(), 2);
+        assert_eq!(
+            sync_plan.skipped_by_reason(),
+            BTreeMap::from([(SkipReason::MissingPrimaryIp, 1)])
+        );
+    }
+
+    #[test]
+    fn netbox_disable_statuses() {
+        let netbox_devices = netbox_page("tests/data/netbox/mixed_statuses.json");
+        let netshot_devices = vec![
+            netshot_device(1, "edge-1", "10.0.4.1", "INPRODUCTION"),
+            netshot_device(2, "edge-2", "10.0.4.2", "INPRODUCTION"),
+            netshot_device(4, "edge-4", "10.0.4.4", "DISABLED"),
+        ];
+        let ips = |devices: &[PlannedDevice]| -> Vec<(String, Option<String>)> {
+            devices
+                .iter()
+                .map(|device| (device.ip.clone(), device.disabled_by.clone()))
+                .collect()
+        };
+
+        // Without the option, the devices matching the filter are present whatever their status
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        assert_eq!(
+            ips(&sync_plan.to_register),
+            vec![("10.0.4.3".to_string(), None)]
+        );
+        assert_eq!(
+            ips(&sync_plan.to_enable),
+            vec![("10.0.4.4".to_string(), None)]
+        );
+        assert!(sync_plan.to_disable.is_empty());
+
+        for missing in [MissingPolicy::Disable, MissingPolicy::Ignore] {
+            let options = PlanOptions {
+                missing,
+                netbox_disable_statuses: vec!["offline".to_string(), "decommissioning".to_string()],
+                ..PlanOptions::default()
+            };
+            let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+            // The devices on Netshot are disabled unless they already are, and the one missing from Netshot is not
+            // registered
+            assert_eq!(
+                ips(&sync_plan.to_disable),
+                vec![(
+                    "10.0.4.2".to_string(),
+                    Some("netbox-disable-statuses offline".to_string())
+                )],
+                "{}",
+                missing
+            );
+            assert!(sync_plan.to_register.is_empty(), "{}", missing);
+            assert!(sync_plan.to_enable.is_empty(), "{}", missing);
+            assert_eq!(sync_plan.matched.len(), 1);
+        }
+
+        // The status presence wins
+        let options = PlanOptions {
+            status_presence: "offline=present".parse().unwrap(),
+            netbox_disable_statuses: vec!["offline".to_string()],
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert!(sync_plan.to_disable.is_empty());
+        assert_eq!(
+            ips(&sync_plan.to_enable),
+            vec![("10.0.4.4".to_string(), None)]
+        );
+    }
+
+    #[test]
+    fn netshot_disabled_statuses() {
+        let netshot_devices: Vec<netshot::Device> = serde_json::from_str(
+            &std::fs::read_to_string("tests/data/netshot/mixed_statuses.json").unwrap(),
+        )
+        .unwrap();
+        let netbox_devices: Vec<netbox::Device> = netshot_devices
+            .iter()
+            .take(3)
+            .map(|device| {
+                netbox_device(
+                    device.id,
+                    Some(&device.name),
+                    Some(&format!("{}/32", device.management_address.ip)),
+                )
+            })
+            .collect();
+        let enabled_and_disabled = |options: &PlanOptions| {
+            let sync_plan = plan(&netbox_devices, &netshot_devices, options);
+            let names = |devices: &[PlannedDevice]| -> Vec<String> {
+                devices.iter().map(|device| device.name.clone()).collect()
+            };
+            (names(&sync_plan.to_enable), names(&sync_plan.to_disable))
+        };
+
+        // The pre-production devices count as present unless told otherwise
+        assert_eq!(
+            enabled_and_disabled(&PlanOptions::default()),
+            (vec!["edge-2".to_string()], vec!["old-1".to_string()])
+        );
+        let options = PlanOptions {
+            netshot_disabled_statuses: "DISABLED, preproduction".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(
+            enabled_and_disabled(&options),
+            (
+                vec!["edge-1".to_string(), "edge-2".to_string()],
+                vec!["old-1".to_string()]
+            )
+        );
+        // The policy of a status comes first
+        let options = PlanOptions {
+            netshot_status_policy: "PREPRODUCTION=ignore".parse().unwrap(),
+            ..options
+        };
+        assert_eq!(
+            enabled_and_disabled(&options),
+            (vec!["edge-2".to_string()], Vec::new())
+        );
+
+        assert!("DISABLED,INVENTORIED".parse::<DisabledStatuses>().is_err());
+        assert!("DISABLED,INPRODUCTION".parse::<DisabledStatuses>().is_err());
+    }
+
+    #[test]
+    fn purged_disabled_devices() {
+        let now = Utc::now();
+        let netbox_devices = vec![netbox_device(1, Some("edge-1"), Some("10.0.0.1/32"))];
+        let mut netshot_devices = vec![
+            netshot_device(1, "edge-1", "10.0.0.1", "DISABLED"),
+            netshot_device(2, "old-1", "10.0.0.2", "DISABLED"),
+            netshot_device(3, "recent-1", "10.0.0.3", "DISABLED"),
+            netshot_device(4, "active-1", "10.0.0.4", "INPRODUCTION"),
+            netshot_device(5, "undated-1", "10.0.0.5", "DISABLED"),
+        ];
+        netshot_devices[0].change_date = Some(now - Duration::days(200));
+        netshot_devices[1].created_date = Some(now - Duration::days(120));
+        netshot_devices[2].change_date = Some(now - Duration::days(10));
+        netshot_devices[3].change_date = Some(now - Duration::days(200));
+
+        // Only the old disabled devices missing from Netbox, the others are still disabled
+        let mut sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        sync_plan.purge_disabled(&netshot_devices, Duration::days(90), now);
+        assert_eq!(
+            sync_plan
+                .to_purge_disabled
+                .iter()
+                .map(|device| (device.ip.as_str(), device.disabled_by.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![("10.0.0.2", Some("disabled on Netshot for 120 days"))]
+        );
+        assert_eq!(
+            sync_plan
+                .to_disable
+                .iter()
+                .map(|device| device.ip.as_str())
+                .collect::<Vec<_>>(),
+            vec!["10.0.0.3", "10.0.0.4", "10.0.0.5"]
+        );
+        assert_eq!(sync_plan.to_enable.len(), 1);
+    }
+
+    #[test]
+    fn netshot_status_policies() {
+        // On Netbox, or missing from it, with every status
+        let statuses = ["INPRODUCTION", "DISABLED", "INVENTORIED"];
+        let netbox_devices: Vec<netbox::Device> = (1..=3)
+            .map(|id| {
+                netbox_device(
+                    id,
+                    Some(&format!("device-{}", id)),
+                    Some(&format!("10.0.1.{}/32", id)),
+                )
+            })
+            .collect();
+        let mut netshot_devices = Vec::new();
+        for (index, status) in statuses.iter().enumerate() {
+            let id = index as u32 + 1;
+            netshot_devices.push(netshot_device(
+                id,
+                &format!("device-{}", id),
+                &format!("10.0.1.{}", id),
+                status,
+            ));
+            netshot_devices.push(netshot_device(
+                id + 10,
+                "gone",
+                &format!("10.0.2.{}", id),
+                status,
+            ));
+        }
+
+        let ips = |devices: &[PlannedDevice]| -> Vec<String> {
+            devices.iter().map(|device| device.ip.clone()).collect()
+        };
+
+        // INVENTORIED policy => (disables, enables), the default handling being kept for the others
+        let matrix = [
+            (
+                "",
+                vec!["10.0.2.1", "10.0.2.2", "10.0.2.3"],
+                vec!["10.0.1.2"],
+            ),
+            (
+                "INVENTORIED=present",
+                vec!["10.0.2.1", "10.0.2.2", "10.0.2.3"],
+                vec!["10.0.1.2"],
+            ),
+            (
+                "INVENTORIED=absent",
+                vec!["10.0.2.1", "10.0.2.2"],
+                vec!["10.0.1.2", "10.0.1.3"],
+            ),
+            (
+                "INVENTORIED=ignore",
+                vec!["10.0.2.1", "10.0.2.2"],
+                vec!["10.0.1.2"],
+            ),
+            (
+                "DISABLED=ignore,INPRODUCTION=absent",
+                vec!["10.0.2.3"],
+                vec!["10.0.1.1"],
+            ),
+        ];
+        for (policy, disables, enables) in matrix {
+            let options = PlanOptions {
+                netshot_status_policy: if policy.is_empty() {
+                    StatusPresence::default()
+                } else {
+                    policy.parse().unwrap()
+                },
+                ..Default::default()
+            };
+            let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+            assert_eq!(ips(&sync_plan.to_disable), disables, "{}", policy);
+            assert_eq!(ips(&sync_plan.to_enable), enables, "{}", policy);
+            assert!(sync_plan.to_register.is_empty(), "{}", policy);
+            assert_eq!(sync_plan.matched.len(), 3, "{}", policy);
+        }
+    }
+
+    #[test]
+    fn deferred_disables() {
+        let netshot_devices = vec![netshot_device(1, "old-device", "1.2.3.4", "INPRODUCTION")];
+
+        let mut sync_plan = plan(&[], &netshot_devices, &PlanOptions::default());
+        sync_plan.apply_stale_action(&StaleAction::Purge, &HashSet::new());
+        sync_plan.defer_disables();
+
+        assert!(sync_plan.to_purge.is_empty());
+        assert_eq!(sync_plan.deferred_disables[0].ip, "1.2.3.4");
+    }
+
+    #[test]
+    fn previous_failures() {
+        let netbox_devices = vec![netbox_device(1, Some("flaky"), Some("10.0.0.1/32"))];
+        let netshot_devices = vec![netshot_device(1, "old", "10.0.2.1", "INPRODUCTION")];
+
+        let mut sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        sync_plan.annotate_failures(|ip, action| match (ip, action) {
+            ("10.0.0.1", Action::Register) => 4,
+            ("10.0.2.1", Action::Register) => 2,
+            _ => 0,
+        });
+
+        assert_eq!(sync_plan.to_register[0].previous_failures, Some(4));
+        assert_eq!(sync_plan.to_disable[0].previous_failures, None);
+    }
+
+    #[test]
+    fn parallel_attribute_lookups() {
+        let mut sync_plan = SyncPlan {
+            matched: (1..=4)
+                .map(|id| MatchedDevice {
+                    netbox_id: id,
+                    netshot_id: id,
+                    ip: format!("10.0.0.{}", id),
+                    name: format!("edge-{}", id),
+                })
+                .collect(),
+            ..SyncPlan::default()
+        };
+        let in_flight = std::sync::atomic::AtomicUsize::new(0);
+        let most_in_flight = std::sync::atomic::AtomicUsize::new(0);
+        sync_plan
+            .plan_attributes(4, |device| {
+                let current = in_flight.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(current, std::sync::atomic::Ordering::SeqCst);
+                // The first devices are the slowest to answer
+                std::thread::sleep(std::time::Duration::from_millis(
+                    20 * u64::from(5 - device.netshot_id),
+                ));
+                in_flight.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                Ok((device.netshot_id != 3).then(|| format!("Netbox site: {}", device.name)))
+            })
+            .unwrap();
+
+        assert!(most_in_flight.into_inner() > 1);
+        assert_eq!(
+            sync_plan
+                .to_update_attributes
+                .iter()
+                .map(|device| device.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["edge-1", "edge-2", "edge-4"]
+        );
+    }
+
+    #[test]
+    fn held_registrations() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("no-driver"), Some("10.0.0.1/32")),
+            netbox_device(2, Some("new-router"), Some("10.0.0.2/32")),
+        ];
+        let mut sync_plan = plan(&netbox_devices, &[], &PlanOptions::default());
+        sync_plan.hold_registrations(|ip| {
+            (ip == "10.0.0.1").then(|| {
+                (
+                    netshot::DiscoveryFailure::Driver,
+                    Some("No driver could be found".to_string()),
+                )
+            })
+        });
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(sync_plan.to_register[0].ip, "10.0.0.2");
+        assert_eq!(sync_plan.held_registrations.len(), 1);
+        assert_eq!(sync_plan.held_registrations[0].device.ip, "10.0.0.1");
+        assert_eq!(
+            serde_json::to_value(&sync_plan.held_registrations[0]).unwrap(),
+            json!({"ip": "10.0.0.1", "name": "no-driver", "prefix_length": 32, "failure": "driver", "reason": "No driver could be found"})
+        );
+    }
+
+    #[test]
+    fn stale_actions() {
+        assert_eq!(
+            "group:Quarantine".parse::<StaleAction>().unwrap(),
+            StaleAction::Group("Quarantine".to_string())
+        );
+        assert_eq!("purge".parse::<StaleAction>().unwrap(), StaleAction::Purge);
+        assert!("group:".parse::<StaleAction>().is_err());
+        assert!("delete".parse::<StaleAction>().is_err());
+
+        let netbox_devices = vec![netbox_device(1, Some("back"), Some("10.0.0.1/32"))];
+        let netshot_devices = vec![
+            netshot_device(1, "back", "10.0.0.1", "INPRODUCTION"),
+            netshot_device(2, "stale", "10.0.2.1", "INPRODUCTION"),
+            netshot_device(3, "quarantined", "10.0.2.2", "INPRODUCTION"),
+        ];
+        let members: HashSet<String> = ["10.0.0.1", "10.0.2.2"]
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect();
+
+        let mut sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        sync_plan.apply_stale_action(&StaleAction::Group("Quarantine".to_string()), &members);
+        assert!(sync_plan.to_disable.is_empty());
+        assert_eq!(sync_plan.to_add_to_group.len(), 1);
+        assert_eq!(sync_plan.to_add_to_group[0].ip, "10.0.2.1");
+        assert_eq!(sync_plan.to_remove_from_group[0].ip, "10.0.0.1");
+
+        let mut sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        sync_plan.apply_stale_action(&StaleAction::Purge, &members);
+        assert!(sync_plan.to_disable.is_empty());
+        assert_eq!(sync_plan.to_purge.len(), 2);
+    }
+
+    #[test]
+    fn changes_budget() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("new-10"), Some("10.0.0.10/32")),
+            netbox_device(2, Some("new-9"), Some("10.0.0.9/32")),
+            netbox_device(3, Some("back"), Some("10.0.1.1/32")),
+        ];
+        let netshot_devices = vec![
+            netshot_device(1, "back", "10.0.1.1", "DISABLED"),
+            netshot_device(2, "old", "10.0.2.1", "INPRODUCTION"),
+        ];
+
+        let mut sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        apply_budget(&mut [&mut sync_plan], 2, &BudgetOrder::default());
+
+        assert_eq!(sync_plan.to_register.len(), 2);
+        assert_eq!(sync_plan.to_register[0].ip, "10.0.0.9");
+        assert!(sync_plan.to_enable.is_empty());
+        assert!(sync_plan.to_disable.is_empty());
+        assert_eq!(sync_plan.budget_deferred.to_enable[0].ip, "10.0.1.1");
+        assert_eq!(sync_plan.budget_deferred.to_disable[0].ip, "10.0.2.1");
+        assert_eq!(sync_plan.budget_deferred.len(), 2);
+
+        let mut sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        apply_budget(
+            &mut [&mut sync_plan],
+            2,
+            &"disable,enable,register".parse().unwrap(),
+        );
+
+        assert_eq!(sync_plan.to_disable.len(), 1);
+        assert_eq!(sync_plan.to_enable.len(), 1);
+        assert!(sync_plan.to_register.is_empty());
+        assert_eq!(sync_plan.budget_deferred.to_register.len(), 2);
+
+        // The budget is shared by the domains, the categories coming first
+        let mut first = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        let mut second = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        apply_budget(&mut [&mut first, &mut second], 5, &BudgetOrder::default());
+        assert_eq!((first.to_register.len(), second.to_register.len()), (2, 2));
+        assert_eq!((first.to_enable.len(), second.to_enable.len()), (1, 0));
+        assert_eq!(
+            first.budget_deferred.len() + second.budget_deferred.len(),
+            3
+        );
+    }
+
+    #[test]
+    fn credential_sets() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("up-to-date"), Some("1.2.3.4/32")),
+            netbox_device(2, Some("outdated"), Some("1.2.3.5/32")),
+        ];
+        let netshot_devices = vec![
+            netshot_device(1, "up-to-date", "1.2.3.4", "INPRODUCTION"),
+            netshot_device(2, "outdated", "1.2.3.5", "DISABLED"),
+            netshot_device(3, "removed", "1.2.3.6", "INPRODUCTION"),
+        ];
+
+        let mut sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        let mut fetched = Vec::new();
+        sync_plan
+            .plan_credential_sets(&[9, 4], |netshot_id| {
+                fetched.push(netshot_id);
+                Ok(match netshot_id {
+                    1 => vec![4, 9],
+                    _ => vec![3],
+                })
+            })
+            .unwrap();
+
+        assert_eq!(fetched, vec![1, 2]);
+        assert_eq!(sync_plan.to_update_credentials.len(), 1);
+        assert_eq!(sync_plan.to_update_credentials[0].ip, "1.2.3.5");
+    }
+
+    #[test]
+    fn verified_deletions() {
+        let netshot_devices = vec![
+            netshot_device(1, "deleted", "1.2.3.4", "INPRODUCTION"),
+            netshot_device(2, "re-roled", "1.2.3.5", "INPRODUCTION"),
+        ];
+
+        let mut sync_plan = plan(&[], &netshot_devices, &PlanOptions::default());
+        sync_plan
+            .verify_deletions(|device| Ok(device.ip == "1.2.3.4"))
+            .unwrap();
+
+        assert_eq!(sync_plan.to_disable.len(), 1);
+        assert_eq!(sync_plan.to_disable[0].ip, "1.2.3.4");
+        assert_eq!(sync_plan.out_of_scope[0].ip, "1.2.3.5");
+    }
+
+    #[test]
+    fn mapped_devices() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("readdressed"), Some("10.0.0.1/32")),
+            netbox_device(2, Some("same-address"), Some("10.0.0.2/32")),
+            netbox_device(3, Some("unknown-mapping"), Some("10.0.0.3/32")),
+        ];
+        let netshot_devices = vec![
+            netshot_device(2318, "readdressed", "192.168.0.1", "INPRODUCTION"),
+            netshot_device(2319, "same-address", "10.0.0.2", "INPRODUCTION"),
+        ];
+        let options = PlanOptions {
+            mapping: HashMap::from([(1, 2318), (2, 2319), (3, 9999)]),
+            ..PlanOptions::default()
+        };
+
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+
+        let netbox_planned = |ip: &str, name: &str| PlannedDevice {
+            ip: ip.to_string(),
+            name: name.to_string(),
+            netshot: None,
+            disabled_by: None,
+            prefix_length: Some(32),
+            parent: None,
+            previous_failures: None,
+            reason: None,
+            comments: None,
+        };
+        assert_eq!(
+            sync_plan,
+            SyncPlan {
+                to_register: vec![netbox_planned("10.0.0.3", "unknown-mapping")],
+                address_changes: vec![AddressChange {
+                    netbox_device: netbox_planned("10.0.0.1", "readdressed"),
+                    netshot_id: 2318,
+                    netshot_name: "readdressed".to_string(),
+                    netshot_ip: "192.168.0.1".to_string(),
+                }],
+                matched: vec![MatchedDevice {
+                    netbox_id: 2,
+                    netshot_id: 2319,
+                    ip: "10.0.0.2".to_string(),
+                    name: "same-address".to_string(),
+                }],
+                ..SyncPlan::default()
+            }
+        );
+    }
+
+    #[test]
+    fn name_matching() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("renumbered"), Some("10.0.0.1/32")),
+            netbox_device(2, Some("renamed"), Some("10.0.0.2/32")),
+            netbox_device(3, Some("twin"), Some("10.0.0.3/32")),
+            netbox_device(4, Some("twin"), Some("10.0.0.4/32")),
+            netbox_device(5, Some("Same"), Some("10.0.0.5/32")),
+        ];
+        let netshot_devices = vec![
+            netshot_device(2318, "renumbered", "192.168.0.1", "INPRODUCTION"),
+            netshot_device(2319, "old-name", "10.0.0.2", "INPRODUCTION"),
+            netshot_device(2320, "twin", "192.168.0.3", "INPRODUCTION"),
+            netshot_device(2321, "same", "10.0.0.5", "INPRODUCTION"),
+        ];
+        let plan_with = |match_on: &str| {
+            let options = PlanOptions {
+                match_on: match_on.parse().unwrap(),
+                ..PlanOptions::default()
+            };
+            plan(&netbox_devices, &netshot_devices, &options)
+        };
+        fn ips(devices: &[PlannedDevice]) -> Vec<&str> {
+            devices.iter().map(|device| device.ip.as_str()).collect()
+        }
+
+        // By IP, the renumbered device is registered again and its former address disabled
+        let sync_plan = plan_with("ip");
+        assert_eq!(
+            ips(&sync_plan.to_register),
+            vec!["10.0.0.1", "10.0.0.3", "10.0.0.4"]
+        );
+        assert_eq!(
+            ips(&sync_plan.to_disable),
+            vec!["192.168.0.1", "192.168.0.3"]
+        );
+
+        // By name, it is reported as an address change while the IP held under another name is a conflict
+        let sync_plan = plan_with("name");
+        assert_eq!(
+            sync_plan
+                .address_changes
+                .iter()
+                .map(|change| change.netshot_id)
+                .collect::<Vec<u32>>(),
+            vec![2318]
+        );
+        assert_eq!(
+            sync_plan
+                .name_conflicts
+                .iter()
+                .map(|conflict| (conflict.netshot_id, conflict.netbox_name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2319, "renamed")]
+        );
+        // The names shared by several Netbox devices match none of them
+        assert_eq!(ips(&sync_plan.to_register), vec!["10.0.0.3", "10.0.0.4"]);
+        assert_eq!(ips(&sync_plan.to_disable), vec!["192.168.0.3"]);
+
+        // By either, the IP alone is enough
+        let sync_plan = plan_with("both");
+        assert!(sync_plan.name_conflicts.is_empty());
+        assert_eq!(
+            sync_plan
+                .address_changes
+                .iter()
+                .map(|change| change.netshot_id)
+                .collect::<Vec<u32>>(),
+            vec![2318]
+        );
+        assert_eq!(ips(&sync_plan.to_register), vec!["10.0.0.3", "10.0.0.4"]);
+        assert!(sync_plan.to_update_address.is_empty());
+
+        // Updating the changed IPs pairs the devices by name even when matching on IP, the shared names aside
+        let options = PlanOptions {
+            update_changed_ips: true,
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert_eq!(ips(&sync_plan.to_update_address), vec!["10.0.0.1"]);
+        assert_eq!(sync_plan.address_changes[0].netshot_id, 2318);
+        assert_eq!(ips(&sync_plan.to_register), vec!["10.0.0.3", "10.0.0.4"]);
+        assert_eq!(ips(&sync_plan.to_disable), vec!["192.168.0.3"]);
+        assert!(sync_plan.name_conflicts.is_empty());
+
+        assert!("names".parse::<MatchOn>().is_err());
+    }
+
+    #[test]
+    fn renamed_devices() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("edge-1"), Some("10.0.0.1/32")),
+            netbox_device(2, Some("edge-2"), Some("10.0.0.2/32")),
+            netbox_device(3, Some("edge-3"), Some("10.0.0.3/32")),
+            netbox_device(4, None, Some("10.0.0.4/32")),
+            netbox_device(5, Some("edge-5"), Some("10.0.0.5/32")),
+        ];
+        let netshot_devices = vec![
+            netshot_device(1, "edge-1-old", "10.0.0.1", "INPRODUCTION"),
+            netshot_device(2, "EDGE-2.example.com", "10.0.0.2", "INPRODUCTION"),
+            netshot_device(3, "10.0.0.3", "10.0.0.3", "INPRODUCTION"),
+            netshot_device(4, "edge-4", "10.0.0.4", "INPRODUCTION"),
+            netshot_device(5, "edge-5-old", "10.0.0.5", "DISABLED"),
+        ];
+        fn ips(devices: &[PlannedDevice]) -> Vec<&str> {
+            devices.iter().map(|device| device.ip.as_str()).collect()
+        }
+        assert!(
+            plan(&netbox_devices, &netshot_devices, &PlanOptions::default())
+                .to_rename
+                .is_empty()
+        );
+
+        // The undiscovered devices named after their IP, the nameless Netbox devices and the name conflicts are left
+        // alone
+        let options = PlanOptions {
+            sync_names: true,
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert_eq!(ips(&sync_plan.to_rename), vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(sync_plan.to_rename[0].name, "edge-1");
+        assert!(sync_plan.to_rename[0].netshot.is_some());
+        assert!(sync_plan.to_enable.is_empty());
+
+        let options = PlanOptions {
+            name_suffix: Some(".Example.com".to_string()),
+            ..options
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert_eq!(ips(&sync_plan.to_rename), vec!["10.0.0.1"]);
+    }
+
+    #[test]
+    fn soft_matches() {
+        let netbox_devices = vec![
+            netbox_device(1, Some("mgmt-vrf-device"), Some("192.168.0.1/24")),
+            netbox_device(2, Some("new-device"), Some("1.2.3.4/32")),
+        ];
+        let netshot_devices = vec![
+            netshot_device(1, "loopback-device", "10.0.0.1", "INPRODUCTION"),
+            netshot_device(2, "old-device", "10.0.0.2", "INPRODUCTION"),
+        ];
+
+        let mut sync_plan = plan(&netbox_devices, &netshot_devices, &PlanOptions::default());
+        let candidates = sync_plan.soft_match_candidates(&netshot_devices);
+        assert_eq!(candidates.len(), 2);
+
+        let secondary_addresses: HashMap<String, &netshot::Device> =
+            vec![("192.168.0.1".to_string(), candidates[0])]
+                .into_iter()
+                .collect();
+        sync_plan.resolve_soft_matches(&secondary_addresses);
+
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(sync_plan.to_register[0].name, "new-device");
+        assert_eq!(sync_plan.to_disable.len(), 1);
+        assert_eq!(sync_plan.to_disable[0].name, "old-device");
+        assert_eq!(
+            sync_plan.soft_matches,
+            vec![SoftMatch {
+                netbox_device: PlannedDevice {
+                    ip: "192.168.0.1".to_string(),
+                    name: "mgmt-vrf-device".to_string(),
+                    netshot: None,
+                    disabled_by: None,
+                    prefix_length: Some(24),
+                    parent: None,
+                    previous_failures: None,
+                    reason: None,
+                    comments: None,
+                },
+                netshot_id: 1,
+                netshot_name: "loopback-device".to_string(),
+                netshot_ip: "10.0.0.1".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn generic_primary_ip_fallback() {
+        let netbox_devices: Vec<netbox::Device> = vec![
+            serde_json::from_value(json!({
+                "id": 1,
+                "name": "v4-vm",
+                "primary_ip": {"id": 1, "family": 4, "address": "1.2.3.4/24"}
+            }))
+            .unwrap(),
+            serde_json::from_value(json!({
+                "id": 2,
+                "name": "v6-vm",
+                "primary_ip": {"id": 2, "family": 6, "address": "2001:db8::1/64"}
+            }))
+            .unwrap(),
+            serde_json::from_value(json!({
+                "id": 3,
+                "name": "v6-device",
+                "primary_ip6": {"id": 3, "family": 6, "address": "2001:db8::2/64"},
+                "primary_ip": {"id": 3, "family": 6, "address": "2001:db8::2/64"}
+            }))
+            .unwrap(),
+        ];
+
+        let sync_plan = plan(&netbox_devices, &[], &PlanOptions::default());
+
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(sync_plan.to_register[0].ip, "1.2.3.4");
+        assert_eq!(sync_plan.skipped.len(), 2);
+        assert_eq!(sync_plan.skipped[0].reason, SkipReason::Ipv6NotAllowed);
+
+        let options = PlanOptions {
+            allow_ipv6: true,
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &[], &options);
+
+        assert_eq!(sync_plan.to_register.len(), 3);
+        assert_eq!(sync_plan.to_register[2].ip, "2001:db8::2");
+    }
+
+    #[test]
+    fn ipv6_primary_ips() {
+        let netbox_devices: Vec<netbox::Device> = vec![
+            serde_json::from_value(json!({
+                "id": 1,
+                "name": "dual-stack",
+                "primary_ip4": {"id": 1, "family": 4, "address": "1.2.3.4/24"},
+                "primary_ip6": {"id": 2, "family": 6, "address": "2001:DB8::1/64"}
+            }))
+            .unwrap(),
+            serde_json::from_value(json!({
+                "id": 2,
+                "name": "v6-only",
+                "primary_ip6": {"id": 3, "family": 6, "address": "2001:DB8:0:0::2/64"}
+            }))
+            .unwrap(),
+        ];
+        // Netshot neither compresses the addresses nor lowercases them the same way
+        let netshot_devices = vec![
+            netshot_device(1, "dual-stack", "2001:db8:0:0:0:0:0:1", "DISABLED"),
+            netshot_device(2, "v6-only", "2001:db8::2", "INPRODUCTION"),
+        ];
+
+        let ips = |devices: &[PlannedDevice]| -> Vec<String> {
+            devices.iter().map(|device| device.ip.clone()).collect()
+        };
+
+        let options = PlanOptions {
+            allow_ipv6: true,
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert_eq!(ips(&sync_plan.to_register), vec!["1.2.3.4"]);
+        assert_eq!(ips(&sync_plan.to_disable), vec!["2001:db8::1"]);
+        assert_eq!(sync_plan.matched.len(), 1);
+
+        let options = PlanOptions {
+            allow_ipv6: true,
+            prefer_ipv6: true,
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert!(sync_plan.to_register.is_empty());
+        assert!(sync_plan.to_disable.is_empty());
+        assert_eq!(ips(&sync_plan.to_enable), vec!["2001:db8::1"]);
+        assert_eq!(sync_plan.matched.len(), 2);
+    }
+
+    #[test]
+    fn special_ips() {
+        for ip in [
+            "127.0.0.1",
+            "169.254.10.1",
+            "0.0.0.0",
+            "224.0.0.5",
+            "::",
+            "::1",
+            "fe80::1",
+            "ff02::1",
+        ] {
+            assert!(is_special_ip(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["10.0.0.1", "192.0.2.1", "2001:db8::1", "fec0::1"] {
+            assert!(!is_special_ip(&ip.parse().unwrap()), "{}", ip);
+        }
+
+        let netbox_devices: Vec<netbox::Device> = vec![
+            serde_json::from_value(json!({
+                "id": 1,
+                "name": "loopback",
+                "primary_ip4": {"id": 1, "family": 4, "address": "127.0.0.1/8"}
+            }))
+            .unwrap(),
+            serde_json::from_value(json!({
+                "id": 2,
+                "name": "real",
+                "primary_ip4": {"id": 2, "family": 4, "address": "10.0.0.1/24"}
+            }))
+            .unwrap(),
+        ];
+
+        let sync_plan = plan(&netbox_devices, &[], &PlanOptions::default());
+        assert_eq!(sync_plan.to_register.len(), 1);
+        assert_eq!(sync_plan.skipped[0].reason, SkipReason::SpecialPrimaryIp);
+
+        let options = PlanOptions {
+            allow_special_ips: true,
+            ..PlanOptions::default()
+        };
+        assert_eq!(plan(&netbox_devices, &[], &options).to_register.len(), 2);
+    }
+
+    #[test]
+    fn presence_policies() {
+        let netbox_device = |id: u32, status: &str| -> netbox::Device {
+            serde_json::from_value(json!({
+                "id": id,
+                "name": format!("device-{}", id),
+                "primary_ip4": {"id": id, "family": 4, "address": format!("1.2.3.{}/32", id)},
+                "status": {"value": status, "label": status}
+            }))
+            .unwrap()
+        };
+        let netbox_devices = vec![
+            netbox_device(1, "active"),
+            netbox_device(2, "offline"),
+            netbox_device(3, "planned"),
+            netbox_device(4, "staged"),
+        ];
+        // Every Netbox device is on Netshot, disabled for the even ones, and another one is missing from Netbox.
+        // The devices already disabled are planned too, the apply skips them.
+        let mut netshot_devices: Vec<netshot::Device> = (1..=4)
+            .map(|id| {
+                let status = if id % 2 == 0 {
+                    "DISABLED"
+                } else {
+                    "INPRODUCTION"
+                };
+                netshot_device(
+                    id,
+                    &format!("device-{}", id),
+                    &format!("1.2.3.{}", id),
+                    status,
+                )
+            })
+            .collect();
+        netshot_devices.push(netshot_device(5, "gone", "1.2.3.5", "INPRODUCTION"));
+        let status_presence: StatusPresence = "active=present,offline=absent,planned=ignore"
+            .parse()
+            .unwrap();
+
+        let ips = |devices: &[PlannedDevice]| -> Vec<(String, Option<String>)> {
+            devices
+                .iter()
+                .map(|device| (device.ip.clone(), device.disabled_by.clone()))
+                .collect()
+        };
+        let disabled = |ip: &str, policy: &str| (ip.to_string(), Some(policy.to_string()));
+        let enabled = |ip: &str| (ip.to_string(), None);
+
+        // (missing policy, status presence) => (disables, enables)
+        let matrix = [
+            (
+                MissingPolicy::Disable,
+                StatusPresence::default(),
+                vec![disabled("1.2.3.5", "missing-means disable")],
+                vec![enabled("1.2.3.2"), enabled("1.2.3.4")],
+            ),
+            (
+                MissingPolicy::Disable,
+                status_presence.clone(),
+                vec![
+                    disabled("1.2.3.2", "status-presence offline=absent"),
+                    disabled("1.2.3.5", "missing-means disable"),
+                ],
+                vec![enabled("1.2.3.4")],
+            ),
+            (
+                MissingPolicy::Ignore,
+                StatusPresence::default(),
+                vec![],
+                vec![enabled("1.2.3.2"), enabled("1.2.3.4")],
+            ),
+            (
+                MissingPolicy::Ignore,
+                status_presence.clone(),
+                vec![],
+                vec![enabled("1.2.3.4")],
+            ),
+        ];
+        for (missing, status_presence, to_disable, to_enable) in matrix {
+            let options = PlanOptions {
+                missing,
+                status_presence: status_presence.clone(),
+                ..PlanOptions::default()
+            };
+            let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+            let case = format!("{} {:?}", missing, status_presence);
+            assert_eq!(ips(&sync_plan.to_disable), to_disable, "{}", case);
+            assert_eq!(ips(&sync_plan.to_enable), to_enable, "{}", case);
+            assert!(sync_plan.to_register.is_empty(), "{}", case);
+        }
+
+        // An absent device still enabled on Netshot is disabled by its status
+        let options = PlanOptions {
+            status_presence: "active=absent".parse().unwrap(),
+            ..PlanOptions::default()
+        };
+        let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+        assert_eq!(
+            ips(&sync_plan.to_disable),
+            vec![
+                disabled("1.2.3.1", "status-presence active=absent"),
+                disabled("1.2.3.5", "missing-means disable"),
+            ]
+        );
+
+        assert!("offline=gone".parse::<StatusPresence>().is_err());
+        assert!("offline".parse::<StatusPresence>().is_err());
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        /// The primary IPs of the Netbox devices: mostly valid, sharing a few hosts, or IPv6, special, invalid or missing
+        fn netbox_address() -> impl Strategy<Value = Option<String>> {
+            prop_oneof![
+                4 => (0u8..8, prop::sample::select(vec![24u8, 32]))
+                    .prop_map(|(host, length)| Some(format!("10.0.0.{}/{}", host, length))),
+                1 => (0u8..8).prop_map(|host| Some(format!("2001:db8::{}/64", host))),
+                1 => prop::sample::select(vec!["127.0.0.1/8", "10.0.0.300/32", "router/32", "", "10.0.0.1"])
+                    .prop_map(|address| Some(address.to_string())),
+                1 => Just(None),
+            ]
+        }
+
+        /// The management addresses of the Netshot devices, the IPv6 ones not compressed as Netshot does
+        fn netshot_address() -> impl Strategy<Value = String> {
+            prop_oneof![
+                4 => (0u8..8).prop_map(|host| format!("10.0.0.{}", host)),
+                1 => (0u8..8).prop_map(|host| format!("2001:db8:0:0:0:0:0:{}", host)),
+                1 => Just("router".to_string()),
+            ]
+        }
+
+        fn name() -> impl Strategy<Value = &'static str> {
+            prop::sample::select(vec!["core-1", "CORE-1.example.net", "edge-1", "東京-1"])
+        }
+
+        /// The Netbox and Netshot inventories, with a mapping from previous runs between some of their IDs
+        fn inventories(
+        ) -> impl Strategy<Value = (Vec<netbox::Device>, Vec<netshot::Device>, PlanOptions)>
+        {
+            (
+                prop::collection::vec((prop::option::of(name()), netbox_address()), 0..12),
+                prop::collection::vec(
+                    (
+                        name(),
+                        netshot_address(),
+                        prop::sample::select(vec!["INPRODUCTION", "DISABLED", "PREPRODUCTION"]),
+                    ),
+                    0..12,
+                ),
+                prop::collection::hash_map(1u32..13, 1u32..13, 0..4),
+                any::<bool>(),
+            )
+                .prop_map(|(netbox, netshot, mapping, allow_ipv6)| {
+                    let netbox_devices = netbox
+                        .iter()
+                        .zip(1..)
+                        .map(|((name, address), id)| netbox_device(id, *name, address.as_deref()))
+                        .collect();
+                    let netshot_devices = netshot
+                        .iter()
+                        .zip(1..)
+                        .map(|((name, ip, status), id)| netshot_device(id, name, ip, status))
+                        .collect();
+                    let options = PlanOptions {
+                        allow_ipv6,
+                        mapping,
+                        ..PlanOptions::default()
+                    };
+                    (netbox_devices, netshot_devices, options)
+                })
+        }
+
+        fn ips(devices: &[PlannedDevice]) -> Vec<&str> {
+            devices.iter().map(|device| device.ip.as_str()).collect()
+        }
+
+        proptest! {
+            #[test]
+            fn plan_invariants((netbox_devices, netshot_devices, options) in inventories()) {
+                let sync_plan = plan(&netbox_devices, &netshot_devices, &options);
+                prop_assert_eq!(&sync_plan, &plan(&netbox_devices, &netshot_devices, &options));
+
+                // No device appears in more than one action list
+                let actions: Vec<&str> = [&sync_plan.to_register, &sync_plan.to_disable, &sync_plan.to_enable]
+                    .iter()
+                    .flat_map(|devices| ips(devices))
+                    .collect();
+                let unique: HashSet<&str> = actions.iter().copied().collect();
+                prop_assert_eq!(unique.len(), actions.len(), "{:?}", sync_plan);
+
+                let valid_ips: HashSet<String> = netbox_devices
+                    .iter()
+                    .filter_map(|device| netbox_device_ip(device, &options).ok())
+                    .map(|(ip, _)| ip)
+                    .collect();
+                for ip in &valid_ips {
+                    // Every valid Netbox device is either registered, matched or held back for a manual resolution
+                    let covered = ips(&sync_plan.to_register).contains(&ip.as_str())
+                        || sync_plan.matched.iter().any(|matched| &matched.ip == ip)
+                        || sync_plan.conflicts.iter().any(|conflict| &conflict.ip == ip)
+                        || sync_plan.address_changes.iter().any(|change| &change.netbox_device.ip == ip);
+                    prop_assert!(covered, "{} in {:?}", ip, sync_plan);
+                }
+                // The devices of the Netbox inventory are never disabled, nor those of Netshot registered again
+                let parsed = |ip: &str| ip.parse::<IpAddr>().ok();
+                let netbox_ips: HashSet<IpAddr> = valid_ips.iter().filter_map(|ip| parsed(ip)).collect();
+                for device in &sync_plan.to_disable {
+                    prop_assert!(
+                        !parsed(&device.ip).is_some_and(|ip| netbox_ips.contains(&ip)),
+                        "{} in {:?}",
+                        device.ip,
+                        sync_plan
+                    );
+                }
+                let netshot_ips: HashSet<IpAddr> = netshot_devices
+                    .iter()
+                    .filter_map(|device| parsed(&device.management_address.ip))
+                    .collect();
+                for device in &sync_plan.to_register {
+                    prop_assert!(!netshot_ips.contains(&parsed(&device.ip).unwrap()), "{} in {:?}", device.ip, sync_plan);
+                }
+                prop_assert_eq!(
+                    sync_plan.skipped.len(),
+                    netbox_devices.len() - netbox_devices
+                        .iter()
+                        .filter(|device| netbox_device_ip(device, &options).is_ok())
+                        .count()
+                );
+            }
+
+            #[test]
+            fn normalization_inputs(input in "\\PC*") {
+                prop_assert_eq!(canonical_ip(&canonical_ip(&input)), canonical_ip(&input));
+                normalized_name(&input);
+                let device = netbox_device(1, Some(&input), Some(&input));
+                if let Ok((ip, _)) = netbox_device_ip(&device, &PlanOptions::default()) {
+                    prop_assert_eq!(canonical_ip(&ip), ip);
+                }
+            }
+        }
+    }
+}
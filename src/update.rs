@@ -0,0 +1,237 @@
+use crate::cli::APP_USER_AGENT;
+use anyhow::{anyhow, Error, Result};
+use reqwest::blocking::Client;
+use reqwest::Proxy;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use structopt::StructOpt;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/scaleway/netbox2netshot/releases/latest";
+
+/// The release asset listing the SHA-256 checksums of the binaries
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+/// The options of the self-update
+#[derive(Debug, StructOpt, Clone)]
+pub struct UpdateOpt {
+    #[structopt(
+        long,
+        help = "Only report whether a newer release exists, without installing it"
+    )]
+    pub check_only: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Result<&Asset, Error> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| anyhow!("Release {} has no {} asset", self.tag_name, name))
+    }
+}
+
+/// The name of the release asset built for the running target
+fn asset_name() -> String {
+    format!("netbox2netshot-{}", env!("TARGET"))
+}
+
+/// Parse a version such as `v0.1.13`, the missing minor and patch numbers being 0
+fn parse_version(version: &str) -> Result<semver::Version, Error> {
+    let trimmed = version.trim_start_matches('v');
+    let (numbers, suffix) = trimmed.split_at(trimmed.find(['-', '+']).unwrap_or(trimmed.len()));
+    let missing = 3usize.saturating_sub(numbers.split('.').count());
+    semver::Version::parse(&format!("{}{}{}", numbers, ".0".repeat(missing), suffix))
+        .map_err(|_| anyhow!("Invalid version `{}`", version))
+}
+
+/// The expected checksum of an asset, from the content of the checksums asset
+fn expected_checksum<'a>(checksums: &'a str, name: &str) -> Option<&'a str> {
+    checksums.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let checksum = fields.next()?;
+        (fields.next()?.trim_start_matches('*') == name).then_some(checksum)
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Check the downloaded binary against the checksum published with the release
+fn verify_checksum(binary: &[u8], checksums: &str, name: &str) -> Result<(), Error> {
+    let expected = expected_checksum(checksums, name)
+        .ok_or_else(|| anyhow!("No checksum published for {}", name))?;
+    let actual = hex(&openssl::sha::sha256(binary));
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(anyhow!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            name,
+            expected,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Replace the executable with the new binary, through a temporary file in the same directory
+fn replace_executable(executable: &Path, binary: &[u8]) -> Result<(), Error> {
+    let mut temporary_path = executable.as_os_str().to_owned();
+    temporary_path.push(".new");
+    fs::write(&temporary_path, binary)?;
+    fs::set_permissions(&temporary_path, fs::metadata(executable)?.permissions())?;
+    fs::rename(&temporary_path, executable)?;
+    Ok(())
+}
+
+fn get(client: &Client, url: &str) -> Result<reqwest::blocking::Response, Error> {
+    let response = client.get(url).send()?;
+    if !response.status().is_success() {
+        return Err(anyhow!("GET {} failed: {}", url, response.status()));
+    }
+    Ok(response)
+}
+
+fn update(opt: &UpdateOpt, client: &Client, releases_url: &str, executable: &Path) -> Result<()> {
+    let release: Release = get(client, releases_url)?.json()?;
+    let current = env!("CARGO_PKG_VERSION");
+    if parse_version(&release.tag_name)? <= parse_version(current)? {
+        println!("netbox2netshot {} is up to date", current);
+        return Ok(());
+    }
+    println!(
+        "netbox2netshot {} is available (running {})",
+        release.tag_name, current
+    );
+    if opt.check_only {
+        return Ok(());
+    }
+
+    let name = asset_name();
+    let binary = get(client, &release.asset(&name)?.browser_download_url)?.bytes()?;
+    let checksums = get(
+        client,
+        &release.asset(CHECKSUMS_ASSET)?.browser_download_url,
+    )?
+    .text()?;
+    verify_checksum(&binary, &checksums, &name)?;
+
+    replace_executable(executable, &binary)?;
+    println!("Updated {} to {}", executable.display(), release.tag_name);
+    Ok(())
+}
+
+/// Update the running executable to the latest GitHub release, going through the given proxy if any
+pub fn run(opt: &UpdateOpt, proxy: Option<String>) -> Result<(), Error> {
+    let mut client = Client::builder().user_agent(APP_USER_AGENT);
+    if let Some(proxy) = proxy {
+        client = client.proxy(Proxy::all(proxy)?);
+    }
+    update(
+        opt,
+        &client.build()?,
+        RELEASES_URL,
+        &std::env::current_exe()?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+
+    #[test]
+    fn versions() {
+        assert!(parse_version("v0.1.14").unwrap() > parse_version("0.1.13").unwrap());
+        assert!(parse_version("0.2.0-rc1").unwrap() > parse_version("0.1.13").unwrap());
+        assert!(parse_version("0.2.0-rc1").unwrap() < parse_version("0.2.0").unwrap());
+        assert!(parse_version("0.1.9").unwrap() < parse_version("0.1.13").unwrap());
+        assert_eq!(
+            parse_version("v0.2").unwrap(),
+            parse_version("0.2.0").unwrap()
+        );
+        assert!(parse_version("latest").is_err());
+    }
+
+    #[test]
+    fn self_update() {
+        let binary = b"new netbox2netshot";
+        let name = asset_name();
+        let checksums = format!(
+            "{}  {}\n{}  SHA256SUMS.sig\n",
+            hex(&openssl::sha::sha256(binary)),
+            name,
+            hex(&[0; 32])
+        );
+        let release = format!(
+            r#"{{"tag_name": "99.0.0", "assets": [
+                {{"name": "{name}", "browser_download_url": "{url}/download/{name}"}},
+                {{"name": "SHA256SUMS", "browser_download_url": "{url}/download/SHA256SUMS"}}
+            ]}}"#,
+            name = name,
+            url = mockito::server_url()
+        );
+        let _releases = mock("GET", "/update/releases/latest")
+            .with_body(release)
+            .create();
+        let _binary = mock("GET", format!("/download/{}", name).as_str())
+            .with_body(binary)
+            .create();
+        let _checksums = mock("GET", "/download/SHA256SUMS")
+            .with_body(checksums)
+            .create();
+
+        let executable =
+            std::env::temp_dir().join(format!("netbox2netshot-update-{}", std::process::id()));
+        fs::write(&executable, b"old netbox2netshot").unwrap();
+        let client = Client::new();
+        let releases_url = format!("{}/update/releases/latest", mockito::server_url());
+
+        update(
+            &UpdateOpt { check_only: true },
+            &client,
+            &releases_url,
+            &executable,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&executable).unwrap(), b"old netbox2netshot");
+
+        update(
+            &UpdateOpt { check_only: false },
+            &client,
+            &releases_url,
+            &executable,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&executable).unwrap(), binary);
+        fs::remove_file(&executable).unwrap();
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let checksums = format!(
+            "{}  netbox2netshot-x86_64-unknown-linux-gnu\n",
+            hex(&[0; 32])
+        );
+        let error = verify_checksum(
+            b"binary",
+            &checksums,
+            "netbox2netshot-x86_64-unknown-linux-gnu",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("Checksum mismatch"));
+        assert!(verify_checksum(b"binary", &checksums, "netbox2netshot-aarch64").is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Error, Result};
+use chrono::{Local, NaiveTime, Utc};
+use chrono_tz::Tz;
+use std::fmt;
+use std::str::FromStr;
+
+const TIME_FORMAT: &str = "%H:%M";
+
+/// A daily time window, which may wrap around midnight (e.g. 22:00-06:00)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl FromStr for TimeWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid time window `{}`, expected HH:MM-HH:MM", s))?;
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), TIME_FORMAT)
+                .map_err(|error| anyhow!("Invalid time `{}` in window `{}`: {}", time, s, error))
+        };
+        let window = Self {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+        if window.start == window.end {
+            return Err(anyhow!(
+                "Invalid time window `{}`, it starts and ends at the same time",
+                s
+            ));
+        }
+        Ok(window)
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format(TIME_FORMAT),
+            self.end.format(TIME_FORMAT)
+        )
+    }
+}
+
+impl TimeWindow {
+    /// Is the given time within the window (start included, end excluded)
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Is the current time within the window, in the given timezone or the local one
+    pub fn contains_now(&self, timezone: Option<Tz>) -> bool {
+        let now = match timezone {
+            Some(tz) => Utc::now().with_timezone(&tz).time(),
+            None => Local::now().time(),
+        };
+        self.contains(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn parse_window() {
+        let window: TimeWindow = "22:00-06:30".parse().unwrap();
+        assert_eq!(window.start, time(22, 0));
+        assert_eq!(window.end, time(6, 30));
+        assert_eq!(window.to_string(), "22:00-06:30");
+
+        assert!("22:00".parse::<TimeWindow>().is_err());
+        assert!("25:00-06:00".parse::<TimeWindow>().is_err());
+        assert!("06:00-06:00".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn same_day_window() {
+        let window: TimeWindow = "09:00-17:00".parse().unwrap();
+        assert!(window.contains(time(9, 0)));
+        assert!(window.contains(time(12, 0)));
+        assert!(!window.contains(time(17, 0)));
+        assert!(!window.contains(time(23, 0)));
+    }
+
+    #[test]
+    fn wrap_around_window() {
+        let window: TimeWindow = "22:00-06:00".parse().unwrap();
+        assert!(window.contains(time(22, 0)));
+        assert!(window.contains(time(23, 59)));
+        assert!(window.contains(time(0, 0)));
+        assert!(window.contains(time(5, 59)));
+        assert!(!window.contains(time(6, 0)));
+        assert!(!window.contains(time(12, 0)));
+    }
+}
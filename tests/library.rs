@@ -0,0 +1,68 @@
+//! The planner through the public API of the library, with no HTTP call
+
+use netbox2netshot::prelude::*;
+use netbox2netshot::sync::SkipReason;
+use serde_json::json;
+
+fn netbox_devices(value: serde_json::Value) -> Vec<NetboxDevice> {
+    serde_json::from_value(value).unwrap()
+}
+
+fn netshot_devices(value: serde_json::Value) -> Vec<NetshotDevice> {
+    serde_json::from_value(value).unwrap()
+}
+
+fn ips(devices: &[PlannedDevice]) -> Vec<&str> {
+    devices.iter().map(|device| device.ip.as_str()).collect()
+}
+
+#[test]
+fn missing_primary_ip() {
+    let netbox = netbox_devices(json!([
+        {"id": 1, "name": "edge-1", "primary_ip4": {"id": 1, "address": "10.0.0.1/32"}},
+        {"id": 2, "name": "edge-2"}
+    ]));
+    let plan = plan(&netbox, &[], &PlanOptions::default());
+    assert_eq!(ips(&plan.to_register), vec!["10.0.0.1"]);
+    assert_eq!(plan.skipped.len(), 1);
+    assert_eq!(plan.skipped[0].id, 2);
+    assert_eq!(plan.skipped[0].reason, SkipReason::MissingPrimaryIp);
+}
+
+#[test]
+fn disabled_devices() {
+    let netbox = netbox_devices(json!([
+        {"id": 1, "name": "edge-1", "primary_ip4": {"id": 1, "address": "10.0.0.1/32"}}
+    ]));
+    let netshot = netshot_devices(json!([
+        {"id": 10, "name": "edge-1", "mgmtAddress": {"ip": "10.0.0.1"}, "status": "DISABLED"},
+        {"id": 11, "name": "old-1", "mgmtAddress": {"ip": "10.0.0.2"}, "status": "INPRODUCTION"}
+    ]));
+    let plan = plan(&netbox, &netshot, &PlanOptions::default());
+    assert!(plan.to_register.is_empty());
+    assert_eq!(ips(&plan.to_enable), vec!["10.0.0.1"]);
+    assert_eq!(ips(&plan.to_disable), vec!["10.0.0.2"]);
+}
+
+#[test]
+fn duplicates() {
+    let netbox = netbox_devices(json!([
+        {"id": 1, "name": "edge-1", "primary_ip4": {"id": 1, "address": "10.0.0.1/32"}},
+        {"id": 2, "name": "edge-1-spare", "primary_ip4": {"id": 2, "address": "10.0.0.1/32"}},
+        {"id": 3, "name": "core-1", "primary_ip4": {"id": 3, "address": "10.0.0.4/32"}}
+    ]));
+    let netshot = netshot_devices(json!([
+        {"id": 10, "name": "core-1", "mgmtAddress": {"ip": "10.0.0.4"}, "status": "INPRODUCTION"},
+        {"id": 11, "name": "core-1-old", "mgmtAddress": {"ip": "10.0.0.4"}, "status": "INPRODUCTION"}
+    ]));
+    let plan = plan(&netbox, &netshot, &PlanOptions::default());
+    // The first Netbox device of an IP is synchronized
+    assert_eq!(plan.to_register.len(), 1);
+    assert_eq!(plan.to_register[0].name, "edge-1");
+    assert_eq!(plan.netbox_conflicts.len(), 1);
+    assert_eq!(plan.netbox_conflicts[0].ip, "10.0.0.1");
+    // The IPs shared on Netshot are resolved manually
+    assert_eq!(plan.conflicts.len(), 1);
+    assert_eq!(plan.conflicts[0].ip, "10.0.0.4");
+    assert!(plan.to_disable.is_empty());
+}
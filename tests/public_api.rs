@@ -0,0 +1,551 @@
+//! The public API of the library against its snapshot in tests/data/public_api.txt, so that
+//! changing it is a deliberate choice: run with `UPDATE_PUBLIC_API=1` to rewrite the snapshot.
+//!
+//! The API is the one rustdoc compiles, read from its JSON output: the items reachable from the
+//! crate root, re-exports included, with their fields, variants, methods and trait impls.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const SNAPSHOT: &str = "tests/data/public_api.txt";
+
+/// Document the library as JSON, in its own target directory as the tests hold the default one
+fn rustdoc_json() -> Value {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target_dir = manifest_dir.join("target").join("public-api");
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    // The JSON output is still unstable, RUSTC_BOOTSTRAP unlocks it on the stable toolchains
+    let status = Command::new(cargo)
+        .current_dir(manifest_dir)
+        .env("RUSTC_BOOTSTRAP", "1")
+        .args(["rustdoc", "--lib", "--quiet", "--target-dir"])
+        .arg(&target_dir)
+        .args(["--", "-Z", "unstable-options", "--output-format", "json"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "rustdoc failed to document the library");
+    let json = fs::read_to_string(target_dir.join("doc").join("netbox2netshot.json")).unwrap();
+    serde_json::from_str(&json).unwrap()
+}
+
+/// The public items of the crate, one per line, as rendered from the rustdoc JSON
+struct Api<'a> {
+    index: &'a Value,
+    /// The first path of the rendered items, by ID
+    paths: HashMap<String, String>,
+    lines: Vec<String>,
+}
+
+impl<'a> Api<'a> {
+    fn item(&self, id: &Value) -> Option<&'a Value> {
+        self.index.get(id.to_string())
+    }
+
+    /// List the items of a module, then of its public submodules, each item under its first path
+    fn module(&mut self, path: &str, items: &'a Value) {
+        for id in items.as_array().unwrap() {
+            let item = match self.item(id) {
+                Some(item) => item,
+                None => continue,
+            };
+            let (kind, inner) = item_kind(item);
+            if kind == "use" {
+                let name = inner["name"].as_str().unwrap();
+                let target = inner.get("id").and_then(|id| self.item(id));
+                match target {
+                    Some(target) if inner["is_glob"].as_bool().unwrap() => {
+                        self.module(path, &item_kind(target).1["items"])
+                    }
+                    Some(target) => self.named(&format!("{}::{}", path, name), target),
+                    None => self.lines.push(format!(
+                        "pub use {}::{} = {}",
+                        path,
+                        name,
+                        inner["source"].as_str().unwrap()
+                    )),
+                }
+                continue;
+            }
+            let name = item["name"].as_str().unwrap_or_default();
+            self.named(&format!("{}::{}", path, name), item);
+        }
+    }
+
+    /// Render an item under `path`, or the re-export of its first path when already rendered
+    fn named(&mut self, path: &str, item: &'a Value) {
+        if let Some(first_path) = self.paths.get(&item["id"].to_string()) {
+            self.lines
+                .push(format!("pub use {} = {}", path, first_path));
+            return;
+        }
+        self.paths.insert(item["id"].to_string(), path.to_string());
+        let attributes = match is_non_exhaustive(item) {
+            true => "#[non_exhaustive] ",
+            false => "",
+        };
+        let (kind, inner) = item_kind(item);
+        match kind {
+            "module" => {
+                self.lines.push(format!("pub mod {}", path));
+                self.module(path, &inner["items"]);
+            }
+            "struct" => {
+                let generics = generics(&inner["generics"]);
+                match &inner["kind"] {
+                    kind if kind.get("tuple").is_some() => {
+                        let fields: Vec<String> = kind["tuple"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|field| match self.item(field) {
+                                Some(field) => {
+                                    format!("pub {}", ty(&kind_of(field)["struct_field"]))
+                                }
+                                None => "_".to_string(),
+                            })
+                            .collect();
+                        self.lines.push(format!(
+                            "{}pub struct {}{}({})",
+                            attributes,
+                            path,
+                            generics,
+                            fields.join(", ")
+                        ));
+                    }
+                    kind => {
+                        self.lines
+                            .push(format!("{}pub struct {}{}", attributes, path, generics));
+                        if let Some(fields) = kind.pointer("/plain/fields") {
+                            self.fields(path, fields);
+                        }
+                    }
+                }
+                self.impls(path, &inner["impls"]);
+            }
+            "enum" => {
+                self.lines.push(format!(
+                    "{}pub enum {}{}",
+                    attributes,
+                    path,
+                    generics(&inner["generics"])
+                ));
+                for variant in inner["variants"].as_array().unwrap() {
+                    let variant = self.item(variant).unwrap();
+                    let variant_path = format!("{}::{}", path, variant["name"].as_str().unwrap());
+                    let attributes = match is_non_exhaustive(variant) {
+                        true => "#[non_exhaustive] ",
+                        false => "",
+                    };
+                    let variant_kind = &kind_of(variant)["variant"]["kind"];
+                    if let Some(fields) = variant_kind.get("tuple") {
+                        let fields: Vec<String> = fields
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|field| match self.item(field) {
+                                Some(field) => ty(&kind_of(field)["struct_field"]),
+                                None => "_".to_string(),
+                            })
+                            .collect();
+                        self.lines.push(format!(
+                            "{}{}({})",
+                            attributes,
+                            variant_path,
+                            fields.join(", ")
+                        ));
+                    } else {
+                        self.lines.push(format!("{}{}", attributes, variant_path));
+                        if let Some(fields) = variant_kind.pointer("/struct/fields") {
+                            self.fields(&variant_path, fields);
+                        }
+                    }
+                }
+                self.impls(path, &inner["impls"]);
+            }
+            "trait" => {
+                self.lines.push(format!(
+                    "pub trait {}{}{}",
+                    path,
+                    generics(&inner["generics"]),
+                    bounds(":", &inner["bounds"])
+                ));
+                for trait_item in inner["items"].as_array().unwrap() {
+                    let trait_item = self.item(trait_item).unwrap();
+                    let name = trait_item["name"].as_str().unwrap();
+                    self.lines
+                        .push(associated(&format!("{}::{}", path, name), trait_item));
+                }
+            }
+            "function" => self.lines.push(function(path, inner)),
+            "constant" => self
+                .lines
+                .push(format!("pub const {}: {}", path, ty(&inner["type"]))),
+            "static" => self
+                .lines
+                .push(format!("pub static {}: {}", path, ty(&inner["type"]))),
+            "type_alias" => self.lines.push(format!(
+                "pub type {}{} = {}",
+                path,
+                generics(&inner["generics"]),
+                ty(&inner["type"])
+            )),
+            kind => self.lines.push(format!("pub {} {}", kind, path)),
+        }
+    }
+
+    fn fields(&mut self, path: &str, fields: &Value) {
+        for field in fields.as_array().unwrap() {
+            let field = self.item(field).unwrap();
+            self.lines.push(format!(
+                "pub {}::{}: {}",
+                path,
+                field["name"].as_str().unwrap(),
+                ty(&kind_of(field)["struct_field"])
+            ));
+        }
+    }
+
+    /// The methods of the inherent impls and the trait impls written or derived for the type
+    fn impls(&mut self, path: &str, impls: &Value) {
+        for id in impls.as_array().unwrap() {
+            let inner = &kind_of(self.item(id).unwrap())["impl"];
+            if inner["is_synthetic"].as_bool().unwrap() || !inner["blanket_impl"].is_null() {
+                continue;
+            }
+            let trait_ = &inner["trait"];
+            if trait_.is_null() {
+                for method in inner["items"].as_array().unwrap() {
+                    let method = self.item(method).unwrap();
+                    if method["visibility"] == "public" {
+                        let name = method["name"].as_str().unwrap();
+                        self.lines
+                            .push(associated(&format!("{}::{}", path, name), method));
+                    }
+                }
+            } else {
+                // The type named by its path, with the generic arguments of the impl
+                let module = &path[..path.rfind("::").unwrap() + 2];
+                self.lines.push(format!(
+                    "impl{} {} for {}{}{}",
+                    generics(&inner["generics"]),
+                    resolved_path(trait_),
+                    module,
+                    ty(&inner["for"]),
+                    where_clause(&inner["generics"])
+                ));
+            }
+        }
+    }
+}
+
+/// The kind of an item with its details
+fn item_kind(item: &Value) -> (&str, &Value) {
+    let (kind, inner) = item["inner"].as_object().unwrap().iter().next().unwrap();
+    (kind.as_str(), inner)
+}
+
+fn kind_of(item: &Value) -> &Value {
+    &item["inner"]
+}
+
+fn is_non_exhaustive(item: &Value) -> bool {
+    item["attrs"].as_array().unwrap().iter().any(|attribute| {
+        attribute == "non_exhaustive"
+            || attribute.get("non_exhaustive").is_some()
+            || attribute.as_str() == Some("#[non_exhaustive]")
+    })
+}
+
+/// A method, associated type or associated constant
+fn associated(path: &str, item: &Value) -> String {
+    let (kind, inner) = item_kind(item);
+    match kind {
+        "function" => function(path, inner),
+        "assoc_type" => format!("type {}{}", path, bounds(":", &inner["bounds"])),
+        "assoc_const" => format!("const {}: {}", path, ty(&inner["type"])),
+        kind => format!("{} {}", kind, path),
+    }
+}
+
+fn function(path: &str, inner: &Value) -> String {
+    let header = &inner["header"];
+    let mut qualifiers = String::new();
+    for (flag, qualifier) in [
+        ("is_const", "const "),
+        ("is_async", "async "),
+        ("is_unsafe", "unsafe "),
+    ] {
+        if header[flag].as_bool().unwrap_or_default() {
+            qualifiers.push_str(qualifier);
+        }
+    }
+    let sig = &inner["sig"];
+    let inputs: Vec<String> = sig["inputs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|input| {
+            let name = input[0].as_str().unwrap();
+            let input_ty = &input[1];
+            match name {
+                "self" if input_ty.get("generic").is_some() => "self".to_string(),
+                "self"
+                    if input_ty.pointer("/borrowed_ref/type/generic") == Some(&"Self".into()) =>
+                {
+                    let reference = &input_ty["borrowed_ref"];
+                    format!(
+                        "&{}{}self",
+                        lifetime(&reference["lifetime"]),
+                        match reference["is_mutable"].as_bool().unwrap() {
+                            true => "mut ",
+                            false => "",
+                        }
+                    )
+                }
+                name => format!("{}: {}", name, ty(input_ty)),
+            }
+        })
+        .collect();
+    let output = match &sig["output"] {
+        Value::Null => String::new(),
+        output => format!(" -> {}", ty(output)),
+    };
+    format!(
+        "pub {}fn {}{}({}){}{}",
+        qualifiers,
+        path,
+        generics(&inner["generics"]),
+        inputs.join(", "),
+        output,
+        where_clause(&inner["generics"])
+    )
+}
+
+fn lifetime(lifetime: &Value) -> String {
+    match lifetime.as_str() {
+        Some(lifetime) => format!("{} ", lifetime),
+        None => String::new(),
+    }
+}
+
+/// The generic parameters, without the ones of the `impl Trait` arguments
+fn generics(generics: &Value) -> String {
+    let params: Vec<String> = generics["params"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|param| param.pointer("/kind/type/is_synthetic") != Some(&true.into()))
+        .map(|param| {
+            let name = param["name"].as_str().unwrap();
+            match &param["kind"] {
+                kind if kind.get("type").is_some() => {
+                    format!("{}{}", name, bounds(":", &kind["type"]["bounds"]))
+                }
+                kind if kind.get("const").is_some() => {
+                    format!("const {}: {}", name, ty(&kind["const"]["type"]))
+                }
+                _ => name.to_string(),
+            }
+        })
+        .collect();
+    match params.is_empty() {
+        true => String::new(),
+        false => format!("<{}>", params.join(", ")),
+    }
+}
+
+fn where_clause(generics: &Value) -> String {
+    let predicates: Vec<String> = generics["where_predicates"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|predicate| {
+            let bound = predicate.get("bound_predicate")?;
+            Some(format!(
+                "{}{}",
+                ty(&bound["type"]),
+                bounds(":", &bound["bounds"])
+            ))
+        })
+        .collect();
+    match predicates.is_empty() {
+        true => String::new(),
+        false => format!(" where {}", predicates.join(", ")),
+    }
+}
+
+fn bounds(separator: &str, bounds: &Value) -> String {
+    let bounds: Vec<String> = bounds
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|bound| match bound {
+            bound if bound.get("trait_bound").is_some() => {
+                let modifier = match bound["trait_bound"]["modifier"].as_str() {
+                    Some("maybe") => "?",
+                    _ => "",
+                };
+                format!(
+                    "{}{}",
+                    modifier,
+                    resolved_path(&bound["trait_bound"]["trait"])
+                )
+            }
+            bound => bound["outlives"].as_str().unwrap_or_default().to_string(),
+        })
+        .collect();
+    match bounds.is_empty() {
+        true => String::new(),
+        false => format!("{} {}", separator, bounds.join(" + ")),
+    }
+}
+
+/// A path named by its last segment, with its generic arguments
+fn resolved_path(path: &Value) -> String {
+    let name = path["path"].as_str().unwrap().rsplit("::").next().unwrap();
+    let args = &path["args"];
+    if let Some(args) = args.get("angle_bracketed") {
+        let mut rendered: Vec<String> = args["args"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|arg| match arg {
+                arg if arg.get("type").is_some() => ty(&arg["type"]),
+                arg if arg.get("lifetime").is_some() => {
+                    arg["lifetime"].as_str().unwrap().to_string()
+                }
+                arg => arg.to_string(),
+            })
+            .collect();
+        for constraint in args["constraints"].as_array().unwrap() {
+            rendered.push(format!(
+                "{} = {}",
+                constraint["name"].as_str().unwrap(),
+                ty(&constraint["binding"]["equality"]["type"])
+            ));
+        }
+        if !rendered.is_empty() {
+            return format!("{}<{}>", name, rendered.join(", "));
+        }
+    } else if let Some(args) = args.get("parenthesized") {
+        let inputs: Vec<String> = args["inputs"].as_array().unwrap().iter().map(ty).collect();
+        let output = match &args["output"] {
+            Value::Null => String::new(),
+            output => format!(" -> {}", ty(output)),
+        };
+        return format!("{}({}){}", name, inputs.join(", "), output);
+    }
+    name.to_string()
+}
+
+fn ty(ty_: &Value) -> String {
+    let (kind, inner) = ty_.as_object().unwrap().iter().next().unwrap();
+    match kind.as_str() {
+        "primitive" | "generic" => inner.as_str().unwrap().to_string(),
+        "resolved_path" => resolved_path(inner),
+        "borrowed_ref" => format!(
+            "&{}{}{}",
+            lifetime(&inner["lifetime"]),
+            match inner["is_mutable"].as_bool().unwrap() {
+                true => "mut ",
+                false => "",
+            },
+            ty(&inner["type"])
+        ),
+        "raw_pointer" => format!(
+            "*{} {}",
+            match inner["is_mutable"].as_bool().unwrap() {
+                true => "mut",
+                false => "const",
+            },
+            ty(&inner["type"])
+        ),
+        "slice" => format!("[{}]", ty(inner)),
+        "array" => format!(
+            "[{}; {}]",
+            ty(&inner["type"]),
+            inner["len"].as_str().unwrap()
+        ),
+        "tuple" => {
+            let types: Vec<String> = inner.as_array().unwrap().iter().map(ty).collect();
+            format!("({})", types.join(", "))
+        }
+        "impl_trait" => format!("impl{}", bounds("", inner).trim_end()),
+        "dyn_trait" => {
+            let mut traits: Vec<String> = inner["traits"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|bound| resolved_path(&bound["trait"]))
+                .collect();
+            if let Some(lifetime) = inner["lifetime"].as_str() {
+                traits.push(lifetime.to_string());
+            }
+            format!("dyn {}", traits.join(" + "))
+        }
+        "qualified_path" => format!(
+            "<{} as {}>::{}",
+            ty(&inner["self_type"]),
+            resolved_path(&inner["trait"]),
+            inner["name"].as_str().unwrap()
+        ),
+        "function_pointer" => {
+            let inputs: Vec<String> = inner["sig"]["inputs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|input| ty(&input[1]))
+                .collect();
+            let output = match &inner["sig"]["output"] {
+                Value::Null => String::new(),
+                output => format!(" -> {}", ty(output)),
+            };
+            format!("fn({}){}", inputs.join(", "), output)
+        }
+        "infer" => "_".to_string(),
+        kind => kind.to_string(),
+    }
+}
+
+#[test]
+fn public_api() {
+    let json = rustdoc_json();
+    let index = &json["index"];
+    let root = &index[json["root"].to_string()];
+    let mut api = Api {
+        index,
+        paths: HashMap::new(),
+        lines: Vec::new(),
+    };
+    api.module("netbox2netshot", &item_kind(root).1["items"]);
+    let api = api.lines.join("\n") + "\n";
+    if std::env::var_os("UPDATE_PUBLIC_API").is_some() {
+        fs::write(SNAPSHOT, &api).unwrap();
+        return;
+    }
+    let snapshot = fs::read_to_string(SNAPSHOT).unwrap_or_default();
+    assert!(
+        api == snapshot,
+        "The public API changed, run the tests with UPDATE_PUBLIC_API=1 to update {} if intended:\n{}",
+        SNAPSHOT,
+        difference(&snapshot, &api)
+    );
+}
+
+/// The lines removed from, then added to, the snapshot
+fn difference(snapshot: &str, api: &str) -> String {
+    let removed = snapshot
+        .lines()
+        .filter(|line| !api.lines().any(|item| item == *line));
+    let added = api
+        .lines()
+        .filter(|line| !snapshot.lines().any(|item| item == *line));
+    removed
+        .map(|line| format!("- {}", line))
+        .chain(added.map(|line| format!("+ {}", line)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}